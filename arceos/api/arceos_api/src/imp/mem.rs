@@ -3,6 +3,8 @@ use core::alloc::Layout;
 cfg_alloc! {
     use core::ptr::NonNull;
 
+    pub use axalloc::MemoryStats as AxMemoryStats;
+
     pub fn ax_alloc(layout: Layout) -> Option<NonNull<u8>> {
         axalloc::global_allocator().alloc(layout).ok()
     }
@@ -10,6 +12,10 @@ cfg_alloc! {
     pub fn ax_dealloc(ptr: NonNull<u8>, layout: Layout) {
         axalloc::global_allocator().dealloc(ptr, layout)
     }
+
+    pub fn ax_memory_stats() -> AxMemoryStats {
+        axalloc::global_allocator().memory_stats()
+    }
 }
 
 cfg_dma! {
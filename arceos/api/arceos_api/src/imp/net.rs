@@ -1,7 +1,7 @@
 use crate::io::AxPollState;
 use axerrno::AxResult;
 use axnet::{UdpSocket, TcpSocket};
-use core::net::{IpAddr, SocketAddr};
+use core::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 /// A handle to a TCP socket.
 pub struct AxTcpSocketHandle(TcpSocket);
@@ -64,6 +64,10 @@ pub fn ax_tcp_shutdown(socket: &AxTcpSocketHandle) -> AxResult {
     socket.0.shutdown()
 }
 
+pub fn ax_tcp_shutdown_write(socket: &AxTcpSocketHandle) -> AxResult {
+    socket.0.shutdown_write()
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // UDP socket
 ////////////////////////////////////////////////////////////////////////////////
@@ -85,6 +89,31 @@ pub fn ax_udp_set_nonblocking(socket: &AxUdpSocketHandle, nonblocking: bool) ->
     Ok(())
 }
 
+pub fn ax_udp_broadcast(socket: &AxUdpSocketHandle) -> AxResult<bool> {
+    Ok(socket.0.is_broadcast())
+}
+
+pub fn ax_udp_set_broadcast(socket: &AxUdpSocketHandle, broadcast: bool) -> AxResult {
+    socket.0.set_broadcast(broadcast);
+    Ok(())
+}
+
+pub fn ax_udp_ttl(socket: &AxUdpSocketHandle) -> AxResult<u32> {
+    Ok(socket.0.ttl())
+}
+
+pub fn ax_udp_set_ttl(socket: &AxUdpSocketHandle, ttl: u32) -> AxResult {
+    socket.0.set_ttl(ttl)
+}
+
+pub fn ax_udp_join_multicast_v4(socket: &AxUdpSocketHandle, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> AxResult {
+    socket.0.join_multicast_v4(multiaddr, interface)
+}
+
+pub fn ax_udp_leave_multicast_v4(socket: &AxUdpSocketHandle, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> AxResult {
+    socket.0.leave_multicast_v4(multiaddr, interface)
+}
+
 pub fn ax_udp_bind(socket: &AxUdpSocketHandle, addr: SocketAddr) -> AxResult {
     socket.0.bind(addr)
 }
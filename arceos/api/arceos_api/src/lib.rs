@@ -75,6 +75,18 @@ pub mod mem {
         pub unsafe fn ax_dealloc(ptr: NonNull<u8>, layout: Layout);
     }
 
+    define_api_type! {
+        @cfg "alloc";
+        pub type AxMemoryStats;
+    }
+
+    define_api! {
+        @cfg "alloc";
+        /// Returns the free memory and its fragmentation in the global
+        /// allocator.
+        pub fn ax_memory_stats() -> AxMemoryStats;
+    }
+
     define_api_type! {
         @cfg "dma";
         pub type DMAInfo;
@@ -124,7 +136,8 @@ pub mod task {
     }
 
     define_api! {
-        /// Current task is going to sleep, it will be woken up at the given deadline.
+        /// Current task is going to sleep, it will be woken up at the given deadline,
+        /// in monotonic time (see [`ax_monotonic_time`](crate::time::ax_monotonic_time)).
         ///
         /// If the feature `multitask` is not enabled, it uses busy-wait instead
         pub fn ax_sleep_until(deadline: crate::time::AxTimeValue);
@@ -144,6 +157,8 @@ pub mod task {
 
         /// Returns the current task's ID.
         pub fn ax_current_task_id() -> u64;
+        /// Returns the current task's name.
+        pub fn ax_current_task_name() -> alloc::string::String;
         /// Spawns a new task with the given entry point and other arguments.
         pub fn ax_spawn(
             f: impl FnOnce() + Send + 'static,
@@ -260,7 +275,7 @@ pub mod fs {
 /// Networking primitives for TCP/UDP communication.
 pub mod net {
     use crate::{io::AxPollState, AxResult};
-    use core::net::{IpAddr, SocketAddr};
+    use core::net::{IpAddr, Ipv4Addr, SocketAddr};
 
     define_api_type! {
         @cfg "net";
@@ -303,6 +318,9 @@ pub mod net {
         pub fn ax_tcp_poll(socket: &AxTcpSocketHandle) -> AxResult<AxPollState>;
         /// Closes the connection on the TCP socket.
         pub fn ax_tcp_shutdown(socket: &AxTcpSocketHandle) -> AxResult;
+        /// Closes the sending half of the connection on the TCP socket, which
+        /// stays readable.
+        pub fn ax_tcp_shutdown_write(socket: &AxTcpSocketHandle) -> AxResult;
 
         // UDP socket
 
@@ -314,6 +332,18 @@ pub mod net {
         pub fn ax_udp_peer_addr(socket: &AxUdpSocketHandle) -> AxResult<SocketAddr>;
         /// Moves this UDP socket into or out of nonblocking mode.
         pub fn ax_udp_set_nonblocking(socket: &AxUdpSocketHandle, nonblocking: bool) -> AxResult;
+        /// Returns whether the UDP socket may send to the broadcast address.
+        pub fn ax_udp_broadcast(socket: &AxUdpSocketHandle) -> AxResult<bool>;
+        /// Allows or forbids the UDP socket to send to the broadcast address.
+        pub fn ax_udp_set_broadcast(socket: &AxUdpSocketHandle, broadcast: bool) -> AxResult;
+        /// Returns the time-to-live of the datagrams sent by the UDP socket.
+        pub fn ax_udp_ttl(socket: &AxUdpSocketHandle) -> AxResult<u32>;
+        /// Sets the time-to-live of the datagrams sent by the UDP socket.
+        pub fn ax_udp_set_ttl(socket: &AxUdpSocketHandle, ttl: u32) -> AxResult;
+        /// Joins the IPv4 multicast group on the given interface.
+        pub fn ax_udp_join_multicast_v4(socket: &AxUdpSocketHandle, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> AxResult;
+        /// Leaves the IPv4 multicast group on the given interface.
+        pub fn ax_udp_leave_multicast_v4(socket: &AxUdpSocketHandle, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> AxResult;
 
         /// Binds the UDP socket to the given address and port.
         pub fn ax_udp_bind(socket: &AxUdpSocketHandle, addr: SocketAddr) -> AxResult;
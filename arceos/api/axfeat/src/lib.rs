@@ -12,6 +12,9 @@
 //!     - `alloc-tlsf`: Use the TLSF allocator.
 //!     - `alloc-slab`: Use the slab allocator.
 //!     - `alloc-buddy`: Use the buddy system allocator.
+//!     - `alloc-tlsf-native`: Use the TLSF allocator of the `tlsf_allocator` module.
+//!     - `alloc-early-heap`: Serve the allocations made before the global allocator
+//!       is initialized from a static early heap.
 //!     - `paging`: Enable page table manipulation.
 //!     - `tls`: Enable thread-local storage.
 //! - Task management
@@ -23,6 +26,7 @@
 //!     - `fs`: Enable file system support.
 //!     - `myfs`: Allow users to define their custom filesystems to override the default.
 //!     - `net`: Enable networking support.
+//!     - `sntp`: Synchronize the wall time with an NTP server after boot.
 //!     - `display`: Enable graphics support.
 //! - Device drivers
 //!     - `bus-mmio`: Use device tree to probe all MMIO devices.
@@ -0,0 +1,146 @@
+//! Emulation of the CSRs the guest cannot access directly.
+//!
+//! The guest runs in VS-mode, so reading M-mode CSRs such as `mhartid`, or
+//! counters not enabled in `hcounteren`, traps as an illegal instruction.
+//! [`CsrEmulator`] looks the CSR up in a table of handlers and completes the
+//! instruction for the guest.
+
+use axerrno::{ax_err, AxResult};
+
+use crate::insn::{CsrOp, CsrSrc, Insn, Trapped};
+use crate::vcpu::VmCpuRegisters;
+
+pub const CSR_CYCLE: u16 = 0xc00;
+pub const CSR_TIME: u16 = 0xc01;
+pub const CSR_INSTRET: u16 = 0xc02;
+pub const CSR_MVENDORID: u16 = 0xf11;
+pub const CSR_MARCHID: u16 = 0xf12;
+pub const CSR_MIMPID: u16 = 0xf13;
+pub const CSR_MHARTID: u16 = 0xf14;
+pub const CSR_MISA: u16 = 0x301;
+
+/// The hart id seen by the guest.
+pub const GUEST_HART_ID: usize = 0x1234;
+
+/// RV64 with the I, M, A, F, D, C, S and U extensions.
+const GUEST_MISA: usize = (2 << 62) | misa_bits(b"IMAFDCSU");
+
+const fn misa_bits(exts: &[u8]) -> usize {
+    let mut bits = 0;
+    let mut i = 0;
+    while i < exts.len() {
+        bits |= 1 << (exts[i] - b'A');
+        i += 1;
+    }
+    bits
+}
+
+/// Reads the emulated CSR.
+pub type CsrRead = fn(&VmCpuRegisters) -> usize;
+/// Writes the emulated CSR.
+pub type CsrWrite = fn(&mut VmCpuRegisters, usize);
+
+/// The handlers of an emulated CSR.
+pub struct CsrHandler {
+    pub csr: u16,
+    pub read: CsrRead,
+    /// `None` for a read-only CSR.
+    pub write: Option<CsrWrite>,
+}
+
+impl CsrHandler {
+    /// A read-only CSR.
+    pub const fn read_only(csr: u16, read: CsrRead) -> Self {
+        Self {
+            csr,
+            read,
+            write: None,
+        }
+    }
+
+    /// A CSR that can be read and written.
+    pub const fn read_write(csr: u16, read: CsrRead, write: CsrWrite) -> Self {
+        Self {
+            csr,
+            read,
+            write: Some(write),
+        }
+    }
+}
+
+/// The CSRs emulated for the guest by default.
+pub static DEFAULT_CSR_HANDLERS: &[CsrHandler] = &[
+    CsrHandler::read_only(CSR_MHARTID, |_| GUEST_HART_ID),
+    CsrHandler::read_only(CSR_MVENDORID, |_| 0),
+    CsrHandler::read_only(CSR_MARCHID, |_| 0),
+    CsrHandler::read_only(CSR_MIMPID, |_| 0),
+    // misa is WARL, writes are ignored as the extensions cannot be changed.
+    CsrHandler::read_write(CSR_MISA, |_| GUEST_MISA, |_, _| {}),
+    CsrHandler::read_only(CSR_CYCLE, |_| riscv::register::cycle::read()),
+    CsrHandler::read_only(CSR_TIME, |_| riscv::register::time::read()),
+    CsrHandler::read_only(CSR_INSTRET, |_| riscv::register::instret::read()),
+];
+
+/// Emulates the CSR instructions of the guest with a table of handlers.
+pub struct CsrEmulator {
+    handlers: &'static [CsrHandler],
+}
+
+impl CsrEmulator {
+    /// Creates a [`CsrEmulator`] with the given handlers.
+    pub const fn new(handlers: &'static [CsrHandler]) -> Self {
+        Self { handlers }
+    }
+
+    fn handler(&self, csr: u16) -> Option<&CsrHandler> {
+        self.handlers.iter().find(|h| h.csr == csr)
+    }
+
+    /// Whether `csr` is emulated.
+    pub fn handles(&self, csr: u16) -> bool {
+        self.handler(csr).is_some()
+    }
+
+    /// Emulates the CSR instruction trapped from the guest: the old value is
+    /// put in `rd`, the new one is written, and `sepc` is moved past the
+    /// instruction.
+    ///
+    /// Returns `Unsupported` if it is not a CSR instruction or the CSR is
+    /// not emulated, and `PermissionDenied` if it writes a read-only CSR.
+    pub fn emulate(&self, ctx: &mut VmCpuRegisters, trapped: &Trapped) -> AxResult {
+        let Some(Insn::Csr { op, csr, rd, src }) = trapped.insn else {
+            return ax_err!(Unsupported);
+        };
+        let Some(handler) = self.handler(csr) else {
+            return ax_err!(Unsupported, "CSR not emulated");
+        };
+        let (operand, writes) = match src {
+            CsrSrc::Reg(rs1) => (ctx.guest_regs.gprs.reg(rs1), rs1 as u32 != 0),
+            CsrSrc::Imm(imm) => (imm, imm != 0),
+        };
+        // csrrw always writes, csrrs and csrrc not with a zero operand.
+        let writes = writes || op == CsrOp::ReadWrite;
+
+        let old = (handler.read)(ctx);
+        if writes {
+            let Some(write) = handler.write else {
+                return ax_err!(PermissionDenied, "write to a read-only CSR");
+            };
+            let new = match op {
+                CsrOp::ReadWrite => operand,
+                CsrOp::ReadSet => old | operand,
+                CsrOp::ReadClear => old & !operand,
+            };
+            write(ctx, new);
+        }
+        ctx.guest_regs.gprs.set_reg(rd, old);
+        ctx.guest_regs.sepc += trapped.len;
+        Ok(())
+    }
+}
+
+impl Default for CsrEmulator {
+    fn default() -> Self {
+        Self::new(DEFAULT_CSR_HANDLERS)
+    }
+}
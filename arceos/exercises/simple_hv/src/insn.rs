@@ -0,0 +1,366 @@
+//! Decoding of the guest instructions that trap to the hypervisor.
+//!
+//! Only the instructions a hypervisor has to emulate are decoded: CSR
+//! accesses, loads and stores (for MMIO), and the system instructions.
+//! Compressed loads and stores are decoded to the same [`Insn`]s, so the
+//! instruction length must be taken from [`Trapped`] when moving `sepc`.
+
+#![allow(dead_code)]
+
+use crate::regs::GprIndex;
+
+const OPCODE_COMPRESSED_Q0: u16 = 0b00;
+const OPCODE_COMPRESSED_Q2: u16 = 0b10;
+const OPCODE_LOAD: u32 = 0b000_0011;
+const OPCODE_STORE: u32 = 0b010_0011;
+const OPCODE_SYSTEM: u32 = 0b111_0011;
+
+const FUNCT7_SFENCE_VMA: u32 = 0b000_1001;
+const FUNCT7_HFENCE_VVMA: u32 = 0b001_0001;
+const FUNCT7_HFENCE_GVMA: u32 = 0b011_0001;
+
+/// The operation of a CSR instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CsrOp {
+    /// `csrrw`: writes the source.
+    ReadWrite,
+    /// `csrrs`: sets the bits of the source.
+    ReadSet,
+    /// `csrrc`: clears the bits of the source.
+    ReadClear,
+}
+
+/// The source operand of a CSR instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CsrSrc {
+    /// A register, `rs1`.
+    Reg(GprIndex),
+    /// A 5-bit immediate, for the `csrr*i` forms.
+    Imm(usize),
+}
+
+/// The width of a memory access.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Width {
+    Byte,
+    Half,
+    Word,
+    Double,
+}
+
+impl Width {
+    /// Returns the number of bytes accessed.
+    pub const fn size(self) -> usize {
+        match self {
+            Width::Byte => 1,
+            Width::Half => 2,
+            Width::Word => 4,
+            Width::Double => 8,
+        }
+    }
+
+    fn from_funct3(funct3: u32) -> Self {
+        match funct3 & 0b11 {
+            0 => Width::Byte,
+            1 => Width::Half,
+            2 => Width::Word,
+            _ => Width::Double,
+        }
+    }
+}
+
+/// The system instructions without operands, and the fences.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SystemOp {
+    Ecall,
+    Ebreak,
+    Sret,
+    Mret,
+    Wfi,
+    SfenceVma { rs1: GprIndex, rs2: GprIndex },
+    HfenceVvma { rs1: GprIndex, rs2: GprIndex },
+    HfenceGvma { rs1: GprIndex, rs2: GprIndex },
+}
+
+/// A decoded instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Insn {
+    /// A CSR access, reading the old value into `rd`.
+    ///
+    /// `csrrs` and `csrrc` with `x0` or a zero immediate do not write the CSR.
+    Csr {
+        op: CsrOp,
+        csr: u16,
+        rd: GprIndex,
+        src: CsrSrc,
+    },
+    /// A load of `width` bytes from `rs1 + offset` into `rd`.
+    Load {
+        width: Width,
+        signed: bool,
+        rd: GprIndex,
+        rs1: GprIndex,
+        offset: isize,
+    },
+    /// A store of the low `width` bytes of `rs2` at `rs1 + offset`.
+    Store {
+        width: Width,
+        rs1: GprIndex,
+        rs2: GprIndex,
+        offset: isize,
+    },
+    /// A system instruction.
+    System(SystemOp),
+}
+
+impl Insn {
+    /// Decodes a 32-bit instruction. Returns `None` if it is not one of the
+    /// instructions handled here.
+    pub fn decode(raw: u32) -> Option<Self> {
+        let rd = gpr(raw >> 7);
+        let funct3 = (raw >> 12) & 0b111;
+        let rs1 = gpr(raw >> 15);
+        let rs2 = gpr(raw >> 20);
+        match raw & 0x7f {
+            OPCODE_LOAD if funct3 != 0b111 => Some(Insn::Load {
+                width: Width::from_funct3(funct3),
+                // `ld` has no unsigned form, so bit 2 is only set for
+                // `lbu`, `lhu` and `lwu`.
+                signed: funct3 & 0b100 == 0,
+                rd,
+                rs1,
+                offset: (raw as i32 >> 20) as isize,
+            }),
+            OPCODE_STORE if funct3 < 0b100 => Some(Insn::Store {
+                width: Width::from_funct3(funct3),
+                rs1,
+                rs2,
+                offset: store_offset(raw),
+            }),
+            OPCODE_SYSTEM => decode_system(raw, funct3, rd, rs1, rs2),
+            _ => None,
+        }
+    }
+
+    /// Decodes a 16-bit compressed instruction. Only the loads and stores
+    /// are handled, for MMIO emulation.
+    pub fn decode_compressed(raw: u16) -> Option<Self> {
+        let funct3 = raw >> 13;
+        // The registers x8-x15 of the 3-bit fields.
+        let rd_short = gpr(8 + ((raw as u32 >> 2) & 0b111));
+        let rs1_short = gpr(8 + ((raw as u32 >> 7) & 0b111));
+        let rd = gpr(raw as u32 >> 7);
+        let rs2 = gpr(raw as u32 >> 2);
+        let bits = |hi: u16, lo: u16| ((raw >> lo) & ((1 << (hi - lo + 1)) - 1)) as isize;
+        // c.lw, c.sw and c.ld, c.sd offsets.
+        let word_offset = bits(12, 10) << 3 | bits(6, 6) << 2 | bits(5, 5) << 6;
+        let double_offset = bits(12, 10) << 3 | bits(6, 5) << 6;
+        let insn = match (raw & 0b11, funct3) {
+            (OPCODE_COMPRESSED_Q0, 0b010) => Insn::Load {
+                width: Width::Word,
+                signed: true,
+                rd: rd_short,
+                rs1: rs1_short,
+                offset: word_offset,
+            },
+            (OPCODE_COMPRESSED_Q0, 0b011) => Insn::Load {
+                width: Width::Double,
+                signed: true,
+                rd: rd_short,
+                rs1: rs1_short,
+                offset: double_offset,
+            },
+            (OPCODE_COMPRESSED_Q0, 0b110) => Insn::Store {
+                width: Width::Word,
+                rs1: rs1_short,
+                rs2: rd_short,
+                offset: word_offset,
+            },
+            (OPCODE_COMPRESSED_Q0, 0b111) => Insn::Store {
+                width: Width::Double,
+                rs1: rs1_short,
+                rs2: rd_short,
+                offset: double_offset,
+            },
+            (OPCODE_COMPRESSED_Q2, 0b010) if rd != GprIndex::Zero => Insn::Load {
+                width: Width::Word,
+                signed: true,
+                rd,
+                rs1: GprIndex::SP,
+                offset: bits(12, 12) << 5 | bits(6, 4) << 2 | bits(3, 2) << 6,
+            },
+            (OPCODE_COMPRESSED_Q2, 0b011) if rd != GprIndex::Zero => Insn::Load {
+                width: Width::Double,
+                signed: true,
+                rd,
+                rs1: GprIndex::SP,
+                offset: bits(12, 12) << 5 | bits(6, 5) << 3 | bits(4, 2) << 6,
+            },
+            (OPCODE_COMPRESSED_Q2, 0b110) => Insn::Store {
+                width: Width::Word,
+                rs1: GprIndex::SP,
+                rs2,
+                offset: bits(12, 9) << 2 | bits(8, 7) << 6,
+            },
+            (OPCODE_COMPRESSED_Q2, 0b111) => Insn::Store {
+                width: Width::Double,
+                rs1: GprIndex::SP,
+                rs2,
+                offset: bits(12, 10) << 3 | bits(9, 7) << 6,
+            },
+            _ => return None,
+        };
+        Some(insn)
+    }
+}
+
+/// Returns the length in bytes of the instruction starting with `low`.
+pub const fn insn_len(low: u32) -> usize {
+    if low & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// A guest instruction that trapped to the hypervisor.
+#[derive(Clone, Copy, Debug)]
+pub struct Trapped {
+    /// The instruction bits, with the high half cleared if it is compressed.
+    pub raw: u32,
+    /// The length of the instruction, 2 if it is compressed, by which `sepc`
+    /// is moved past it.
+    pub len: usize,
+    /// The decoded instruction, if it is one handled here.
+    pub insn: Option<Insn>,
+}
+
+impl Trapped {
+    /// Returns the instruction of an illegal instruction trap, reported in
+    /// `stval`.
+    pub fn from_stval(stval: usize) -> Self {
+        Self::from_raw(stval as u32)
+    }
+
+    /// Returns the instruction of a guest page fault at `sepc`.
+    ///
+    /// It is taken from `htinst` if the hart wrote the transformed
+    /// instruction there, or fetched from the guest memory otherwise.
+    pub fn from_guest_fault(htinst: usize, sepc: usize) -> Self {
+        let htinst = htinst as u32;
+        // Bit 0 is set for a transformed instruction, bit 1 tells whether the
+        // trapping one was 32-bit or compressed.
+        if htinst & 1 != 0 {
+            return Self {
+                raw: htinst,
+                len: if htinst & 0b10 != 0 { 4 } else { 2 },
+                insn: Insn::decode(htinst | 0b10),
+            };
+        }
+        Self::from_raw(fetch_guest_insn(sepc))
+    }
+
+    fn from_raw(raw: u32) -> Self {
+        let len = insn_len(raw);
+        if len == 2 {
+            let raw = raw & 0xffff;
+            Self {
+                raw,
+                len,
+                insn: Insn::decode_compressed(raw as u16),
+            }
+        } else {
+            Self {
+                raw,
+                len,
+                insn: Insn::decode(raw),
+            }
+        }
+    }
+}
+
+/// Reads the instruction at the guest virtual address `gva`, one half-word
+/// at a time as a 32-bit instruction may cross a page boundary.
+///
+/// The guest has just executed from `gva`, so it is mapped and readable with
+/// `hlvx`.
+fn fetch_guest_insn(gva: usize) -> u32 {
+    let low = hlvx_hu(gva);
+    if insn_len(low) == 2 {
+        return low;
+    }
+    low | hlvx_hu(gva + 2) << 16
+}
+
+fn hlvx_hu(gva: usize) -> u32 {
+    let val: usize;
+    unsafe {
+        core::arch::asm!(
+            ".option push",
+            ".option arch, +h",
+            "hlvx.hu {val}, ({gva})",
+            ".option pop",
+            val = out(reg) val,
+            gva = in(reg) gva,
+        );
+    }
+    val as u32
+}
+
+fn decode_system(
+    raw: u32,
+    funct3: u32,
+    rd: GprIndex,
+    rs1: GprIndex,
+    rs2: GprIndex,
+) -> Option<Insn> {
+    let op = match funct3 & 0b11 {
+        0b01 => CsrOp::ReadWrite,
+        0b10 => CsrOp::ReadSet,
+        0b11 => CsrOp::ReadClear,
+        _ if funct3 == 0 && rd == GprIndex::Zero => {
+            return decode_priv(raw, rs1, rs2).map(Insn::System);
+        }
+        _ => return None,
+    };
+    let src = if funct3 & 0b100 != 0 {
+        CsrSrc::Imm(((raw >> 15) & 0x1f) as usize)
+    } else {
+        CsrSrc::Reg(rs1)
+    };
+    Some(Insn::Csr {
+        op,
+        csr: (raw >> 20) as u16,
+        rd,
+        src,
+    })
+}
+
+fn decode_priv(raw: u32, rs1: GprIndex, rs2: GprIndex) -> Option<SystemOp> {
+    match raw >> 25 {
+        FUNCT7_SFENCE_VMA => return Some(SystemOp::SfenceVma { rs1, rs2 }),
+        FUNCT7_HFENCE_VVMA => return Some(SystemOp::HfenceVvma { rs1, rs2 }),
+        FUNCT7_HFENCE_GVMA => return Some(SystemOp::HfenceGvma { rs1, rs2 }),
+        _ => {}
+    }
+    if rs1 != GprIndex::Zero {
+        return None;
+    }
+    match raw >> 20 {
+        0x000 => Some(SystemOp::Ecall),
+        0x001 => Some(SystemOp::Ebreak),
+        0x102 => Some(SystemOp::Sret),
+        0x302 => Some(SystemOp::Mret),
+        0x105 => Some(SystemOp::Wfi),
+        _ => None,
+    }
+}
+
+fn gpr(bits: u32) -> GprIndex {
+    GprIndex::from_raw(bits & 0x1f).unwrap()
+}
+
+fn store_offset(raw: u32) -> isize {
+    let imm = ((raw as i32 >> 25) << 5) | ((raw >> 7) & 0x1f) as i32;
+    imm as isize
+}
@@ -15,6 +15,9 @@ mod regs;
 mod csrs;
 mod sbi;
 mod loader;
+mod insn;
+mod csr_emu;
+mod sbi_proxy;
 
 use vcpu::VmCpuRegisters;
 use riscv::register::{scause, sstatus, stval};
@@ -26,9 +29,13 @@ use sbi::SbiMessage;
 use loader::load_vm_image;
 use axhal::mem::PhysAddr;
 use crate::regs::GprIndex::{A0, A1};
+use insn::Trapped;
+use csr_emu::{CsrEmulator, DEFAULT_CSR_HANDLERS};
 
 const VM_ENTRY: usize = 0x8020_0000;
 
+static CSR_EMULATOR: CsrEmulator = CsrEmulator::new(DEFAULT_CSR_HANDLERS);
+
 #[cfg_attr(feature = "axstd", no_mangle)]
 fn main() {
     ax_println!("Hypervisor ...");
@@ -83,34 +90,43 @@ fn vmexit_handler(ctx: &mut VmCpuRegisters) -> bool {
     match scause.cause() {
         Trap::Exception(Exception::VirtualSupervisorEnvCall) => {
             let sbi_msg = SbiMessage::from_regs(ctx.guest_regs.gprs.a_regs()).ok();
-            ax_println!("VmExit Reason: VSuperEcall: {:?}", sbi_msg);
-            if let Some(msg) = sbi_msg {
-                match msg {
-                    SbiMessage::Reset(_) => {
-                        let a0 = ctx.guest_regs.gprs.reg(A0);
-                        let a1 = ctx.guest_regs.gprs.reg(A1);
-                        ax_println!("a0 = {:#x}, a1 = {:#x}", a0, a1);
-                        assert_eq!(a0, 0x6688);
-                        assert_eq!(a1, 0x1234);
-                        ax_println!("Shutdown vm normally!");
-                        return true;
-                    },
-                    _ => todo!(),
-                }
-            } else {
-                panic!("bad sbi message! ");
+            debug!("VmExit Reason: VSuperEcall: {:?}", sbi_msg);
+            match sbi_msg {
+                Some(SbiMessage::Reset(_)) => {
+                    let a0 = ctx.guest_regs.gprs.reg(A0);
+                    let a1 = ctx.guest_regs.gprs.reg(A1);
+                    ax_println!("a0 = {:#x}, a1 = {:#x}", a0, a1);
+                    assert_eq!(a0, 0x6688);
+                    assert_eq!(a1, 0x1234);
+                    ax_println!("Shutdown vm normally!");
+                    return true;
+                },
+                Some(msg) => sbi_proxy::reply(ctx, sbi_proxy::handle(msg)),
+                // An extension the guest did not find with the probe.
+                None => sbi_proxy::reply(ctx, sbi_proxy::not_supported()),
             }
         },
         Trap::Exception(Exception::IllegalInstruction) => {
-            panic!("Bad instruction: {:#x} sepc: {:#x}",
-                stval::read(),
-                ctx.guest_regs.sepc
-            );
+            let trapped = Trapped::from_stval(stval::read());
+            if let Err(e) = CSR_EMULATOR.emulate(ctx, &trapped) {
+                panic!("Bad instruction: {:#x} ({:?}) sepc: {:#x}: {:?}",
+                    trapped.raw,
+                    trapped.insn,
+                    ctx.guest_regs.sepc,
+                    e
+                );
+            }
         },
         Trap::Exception(Exception::LoadGuestPageFault) => {
-            panic!("LoadGuestPageFault: stval{:#x} sepc: {:#x}",
+            let trapped = Trapped::from_guest_fault(
+                riscv::register::htinst::read(),
+                ctx.guest_regs.sepc,
+            );
+            panic!("LoadGuestPageFault: stval{:#x} sepc: {:#x} insn: {:?} ({} bytes)",
                 stval::read(),
-                ctx.guest_regs.sepc
+                ctx.guest_regs.sepc,
+                trapped.insn,
+                trapped.len
             );
         },
         _ => {
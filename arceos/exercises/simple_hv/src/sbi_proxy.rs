@@ -0,0 +1,79 @@
+//! Handling of the SBI calls of the guest other than reset.
+//!
+//! The console calls and the machine information are forwarded to the host
+//! SBI. The spec version and the extension probe are emulated, so the guest
+//! only sees the extensions handled here.
+
+use crate::regs::GprIndex::{A0, A1};
+use crate::sbi::{BaseFunction, SbiMessage, SbiReturn, SbiReturnTyoe, SBI_ERR_NOT_SUPPORTED};
+use crate::vcpu::VmCpuRegisters;
+
+/// The SBI spec version implemented for the guest, 0.2, the first one with
+/// the base extension.
+const GUEST_SPEC_VERSION: usize = 2;
+
+/// Whether the extension `eid` is handled for the guest.
+fn has_extension(eid: usize) -> bool {
+    matches!(
+        eid,
+        sbi_spec::base::EID_BASE
+            | sbi_spec::legacy::LEGACY_CONSOLE_PUTCHAR
+            | sbi_spec::legacy::LEGACY_CONSOLE_GETCHAR
+            | sbi_spec::srst::EID_SRST
+    )
+}
+
+fn success(value: usize) -> SbiReturnTyoe {
+    SbiReturnTyoe::Standard(SbiReturn {
+        error_code: 0,
+        return_value: value as i64,
+    })
+}
+
+/// The reply to a call the guest should not make, as its extension is not
+/// reported by the probe.
+pub fn not_supported() -> SbiReturnTyoe {
+    SbiReturnTyoe::Standard(SbiReturn {
+        error_code: SBI_ERR_NOT_SUPPORTED as i64,
+        return_value: 0,
+    })
+}
+
+fn handle_base(base: BaseFunction) -> SbiReturnTyoe {
+    match base {
+        BaseFunction::GetSepcificationVersion => success(GUEST_SPEC_VERSION),
+        BaseFunction::GetImplementationID => success(sbi_rt::get_sbi_impl_id()),
+        BaseFunction::GetImplementationVersion => success(sbi_rt::get_sbi_impl_version()),
+        BaseFunction::ProbeSbiExtension(eid) => success(has_extension(eid as usize) as usize),
+        BaseFunction::GetMachineVendorID => success(sbi_rt::get_mvendorid()),
+        BaseFunction::GetMachineArchitectureID => success(sbi_rt::get_marchid()),
+        BaseFunction::GetMachineImplementationID => success(sbi_rt::get_mimpid()),
+    }
+}
+
+/// Handles the SBI call `msg` of the guest, which must not be a reset.
+#[allow(deprecated)]
+pub fn handle(msg: SbiMessage) -> SbiReturnTyoe {
+    match msg {
+        SbiMessage::Base(base) => handle_base(base),
+        SbiMessage::PutChar(c) => SbiReturnTyoe::Legacy(sbi_rt::legacy::console_putchar(c) as u64),
+        SbiMessage::GetChar => SbiReturnTyoe::Legacy(sbi_rt::legacy::console_getchar() as u64),
+        _ => {
+            warn!("Unsupported SBI call: {:?}", msg);
+            not_supported()
+        }
+    }
+}
+
+/// Returns `ret` to the guest and moves `sepc` past its `ecall`.
+pub fn reply(ctx: &mut VmCpuRegisters, ret: SbiReturnTyoe) {
+    let gprs = &mut ctx.guest_regs.gprs;
+    match ret {
+        SbiReturnTyoe::Legacy(value) => gprs.set_reg(A0, value as usize),
+        SbiReturnTyoe::Standard(ret) => {
+            gprs.set_reg(A0, ret.error_code as usize);
+            gprs.set_reg(A1, ret.return_value as usize);
+        }
+    }
+    ctx.guest_regs.sepc += 4;
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![cfg(target_arch = "aarch64")]
+#![feature(asm_const)]
+#![doc = include_str!("../README.md")]
+
+#[macro_use]
+extern crate log;
+
+macro_rules! read_sysreg {
+    ($reg:literal) => {{
+        let val: u64;
+        unsafe { core::arch::asm!(concat!("mrs {0}, ", $reg), out(reg) val) };
+        val
+    }};
+}
+
+macro_rules! write_sysreg {
+    ($reg:literal, $val:expr) => {
+        unsafe { core::arch::asm!(concat!("msr ", $reg, ", {0}"), in(reg) $val as u64) }
+    };
+}
+
+mod mmio;
+pub mod psci;
+mod stage2;
+mod vcpu;
+
+pub use self::mmio::{MmioAccess, MmioOp};
+pub use self::stage2::Stage2PTE;
+pub use self::vcpu::{
+    AArch64VCpu, AccessWidth, AxVCpuExitReason, GuestPhysAddr, GuestRegs, GuestSysRegs,
+    HostPhysAddr, TrapRegs,
+};
+
+/// The `FPEN` field of `CPTR_EL2` with VHE, not trapping FP/SIMD accesses.
+const CPTR_FPEN: u64 = 0b11 << 20;
+/// The `EL1PCTEN` and `EL1PCEN` bits of `CNTHCTL_EL2` with VHE, letting the guest use the
+/// physical counter and timer.
+const CNTHCTL_EL1PCTEN: u64 = 1 << 10;
+const CNTHCTL_EL1PCEN: u64 = 1 << 11;
+/// The `E2H` bit of `HCR_EL2`, set when the host runs at EL2 with VHE.
+pub(crate) const HCR_E2H: u64 = 1 << 34;
+
+/// `VTCR_EL2` for 48-bit guest physical addresses with 4K pages, the walks starting at level 0
+/// as in the host page tables.
+const VTCR_T0SZ: u64 = 64 - 48;
+const VTCR_SL0_LEVEL0: u64 = 0b10 << 6;
+const VTCR_INNER_WB: u64 = 0b01 << 8;
+const VTCR_OUTER_WB: u64 = 0b01 << 10;
+const VTCR_INNER_SHAREABLE: u64 = 0b11 << 12;
+const VTCR_PS_SHIFT: u64 = 16;
+const VTCR_VS_16BIT: u64 = 1 << 19;
+const VTCR_RES1: u64 = 1 << 31;
+
+/// The `PARange` of 48 bits in `ID_AA64MMFR0_EL1`.
+const PARANGE_48BIT: u64 = 0b0101;
+
+/// Whether the current CPU can run guests: the host runs at EL2 with VHE, and the guest
+/// physical addresses may have 48 bits.
+pub fn has_hardware_support() -> bool {
+    let el = read_sysreg!("currentel") >> 2 & 0b11;
+    let parange = read_sysreg!("id_aa64mmfr0_el1") & 0xf;
+    el == 2 && read_sysreg!("hcr_el2") & HCR_E2H != 0 && parange >= PARANGE_48BIT
+}
+
+/// Initialize the EL2 registers of the current CPU to run guests.
+///
+/// # Safety
+///
+/// The host must run at EL2 with VHE, see [`has_hardware_support`].
+pub unsafe fn setup_el2() {
+    // Save and restore the FP/SIMD registers of the guest without trapping.
+    write_sysreg!("cptr_el2", read_sysreg!("cptr_el2") | CPTR_FPEN);
+    write_sysreg!(
+        "cnthctl_el2",
+        read_sysreg!("cnthctl_el2") | CNTHCTL_EL1PCTEN | CNTHCTL_EL1PCEN
+    );
+
+    let parange = read_sysreg!("id_aa64mmfr0_el1") & 0xf;
+    let mut vtcr = VTCR_T0SZ
+        | VTCR_SL0_LEVEL0
+        | VTCR_INNER_WB
+        | VTCR_OUTER_WB
+        | VTCR_INNER_SHAREABLE
+        | parange.min(PARANGE_48BIT) << VTCR_PS_SHIFT
+        | VTCR_RES1;
+    if vmid_bits() == 16 {
+        vtcr |= VTCR_VS_16BIT;
+    }
+    write_sysreg!("vtcr_el2", vtcr);
+    core::arch::asm!("isb");
+    debug!("vtcr_el2: {:#x}", vtcr);
+}
+
+/// Returns the number of VMID bits supported by the current CPU.
+///
+/// VMIDs that do not fit are truncated by the hardware, so VMs sharing a CPU would see each
+/// other's stage-2 translations.
+pub fn vmid_bits() -> usize {
+    // The `VMIDBits` field of `ID_AA64MMFR1_EL1`.
+    match read_sysreg!("id_aa64mmfr1_el1") >> 4 & 0xf {
+        0b0010 => 16,
+        _ => 8,
+    }
+}
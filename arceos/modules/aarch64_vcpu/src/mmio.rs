@@ -0,0 +1,130 @@
+//! Decoding of the guest loads and stores that fault on emulated devices.
+
+use axerrno::{ax_err, AxResult};
+
+use crate::vcpu::{AccessWidth, GuestPhysAddr};
+
+/// The fields of the ISS of a data abort, valid when `ISV` is set.
+const ESR_ISV: u64 = 1 << 24;
+const ESR_SAS_SHIFT: u64 = 22;
+const ESR_SSE: u64 = 1 << 21;
+const ESR_SRT_SHIFT: u64 = 16;
+const ESR_SF: u64 = 1 << 15;
+const ESR_WNR: u64 = 1 << 6;
+/// The `IL` bit of `ESR_EL2`, set for a 32-bit instruction.
+pub(crate) const ESR_IL: u64 = 1 << 25;
+
+/// The register number of `xzr` in the ISS.
+const REG_ZERO: usize = 31;
+
+/// What a faulting guest access does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioOp {
+    /// A load into `x<reg>`, sign-extended if `signed`, to 64 bits if `sixty_four`.
+    Read {
+        reg: usize,
+        signed: bool,
+        sixty_four: bool,
+    },
+    /// A store of `data`, truncated to the access width.
+    Write { data: u64 },
+}
+
+/// A guest MMIO access, decoded by [`AArch64VCpu::decode_mmio`] and completed
+/// by [`AArch64VCpu::complete_mmio`].
+///
+/// [`AArch64VCpu::decode_mmio`]: crate::AArch64VCpu::decode_mmio
+/// [`AArch64VCpu::complete_mmio`]: crate::AArch64VCpu::complete_mmio
+#[derive(Debug, Clone, Copy)]
+pub struct MmioAccess {
+    /// The guest physical address accessed.
+    pub addr: GuestPhysAddr,
+    /// The width of the access.
+    pub width: AccessWidth,
+    /// Whether it is a load or a store.
+    pub op: MmioOp,
+    /// The length of the instruction, to move `elr` past it.
+    pub(crate) insn_len: usize,
+}
+
+impl AccessWidth {
+    /// Returns the number of bytes accessed.
+    pub const fn size(self) -> usize {
+        match self {
+            AccessWidth::Byte => 1,
+            AccessWidth::Word => 2,
+            AccessWidth::Dword => 4,
+            AccessWidth::Qword => 8,
+        }
+    }
+
+    /// Truncates `val` to the width, sign-extending it if `signed`.
+    pub const fn extend(self, val: u64, signed: bool) -> u64 {
+        let shift = 64 - self.size() as u32 * 8;
+        if signed {
+            ((val << shift) as i64 >> shift) as u64
+        } else {
+            val << shift >> shift
+        }
+    }
+
+    fn from_sas(sas: u64) -> Self {
+        match sas & 0b11 {
+            0 => AccessWidth::Byte,
+            1 => AccessWidth::Word,
+            2 => AccessWidth::Dword,
+            _ => AccessWidth::Qword,
+        }
+    }
+}
+
+/// Decodes the load or store at `addr` from the syndrome `esr` of its data abort, with `read_reg`
+/// giving the guest registers for stores.
+///
+/// Only the single-register loads and stores have a valid syndrome, the others, e.g. load pairs
+/// or post-indexed accesses, are not supported.
+pub(crate) fn decode_abort(
+    esr: u64,
+    addr: GuestPhysAddr,
+    read_reg: impl Fn(usize) -> u64,
+) -> AxResult<MmioAccess> {
+    if esr & ESR_ISV == 0 {
+        return ax_err!(Unsupported, "MMIO access without a valid syndrome");
+    }
+    let width = AccessWidth::from_sas(esr >> ESR_SAS_SHIFT);
+    let reg = (esr >> ESR_SRT_SHIFT & 0x1f) as usize;
+    let op = if esr & ESR_WNR != 0 {
+        let data = if reg == REG_ZERO { 0 } else { read_reg(reg) };
+        MmioOp::Write {
+            data: width.extend(data, false),
+        }
+    } else {
+        MmioOp::Read {
+            reg,
+            signed: esr & ESR_SSE != 0,
+            sixty_four: esr & ESR_SF != 0,
+        }
+    };
+    Ok(MmioAccess {
+        addr,
+        width,
+        op,
+        insn_len: if esr & ESR_IL != 0 { 4 } else { 2 },
+    })
+}
+
+/// The value loaded into the register of `op`, a read of `width`.
+pub(crate) fn loaded_value(width: AccessWidth, op: MmioOp, val: u64) -> Option<(usize, u64)> {
+    match op {
+        MmioOp::Read {
+            reg,
+            signed,
+            sixty_four,
+        } if reg != REG_ZERO => {
+            let val = width.extend(val, signed);
+            // Writes to `w<reg>` clear the upper half.
+            Some((reg, if sixty_four { val } else { val & 0xffff_ffff }))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,46 @@
+//! The PSCI calls of the guest, with `hvc` or `smc`.
+
+pub const PSCI_VERSION: u64 = 0x8400_0000;
+pub const CPU_SUSPEND: u64 = 0xc400_0001;
+pub const CPU_OFF: u64 = 0x8400_0002;
+pub const CPU_ON: u64 = 0xc400_0003;
+pub const AFFINITY_INFO: u64 = 0xc400_0004;
+pub const MIGRATE_INFO_TYPE: u64 = 0x8400_0006;
+pub const SYSTEM_OFF: u64 = 0x8400_0008;
+pub const SYSTEM_RESET: u64 = 0x8400_0009;
+pub const PSCI_FEATURES: u64 = 0x8400_000a;
+
+/// PSCI 1.0.
+pub const VERSION_1_0: u64 = 0x0001_0000;
+/// `MIGRATE_INFO_TYPE`: no trusted OS to migrate.
+pub const MIGRATE_NOT_REQUIRED: u64 = 2;
+
+pub const PSCI_SUCCESS: i64 = 0;
+pub const PSCI_NOT_SUPPORTED: i64 = -1;
+pub const PSCI_INVALID_PARAMETERS: i64 = -2;
+pub const PSCI_ALREADY_ON: i64 = -4;
+
+/// Whether the call `func` is handled, for `PSCI_FEATURES`.
+pub fn is_supported(func: u64) -> bool {
+    matches!(
+        func,
+        PSCI_VERSION
+            | CPU_SUSPEND
+            | CPU_OFF
+            | CPU_ON
+            | AFFINITY_INFO
+            | MIGRATE_INFO_TYPE
+            | SYSTEM_OFF
+            | SYSTEM_RESET
+            | PSCI_FEATURES
+    )
+}
+
+/// Maps the SMC32 function IDs of the calls with an SMC64 variant to it.
+pub fn to_smc64(func: u64) -> u64 {
+    let func = func & 0xffff_ffff;
+    match func | 0x4000_0000 {
+        smc64 @ (CPU_SUSPEND | CPU_ON | AFFINITY_INFO) => smc64,
+        _ => func,
+    }
+}
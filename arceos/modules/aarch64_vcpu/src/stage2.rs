@@ -0,0 +1,132 @@
+//! The descriptors of the stage-2 page tables.
+//!
+//! They differ from the stage-1 ones in their attributes: the memory type is
+//! in the descriptor rather than an index in `MAIR`, and the access
+//! permissions are the ones of the guest, read and write, without privilege.
+
+use core::fmt;
+use memory_addr::PhysAddr;
+use page_table_entry::{GenericPTE, MappingFlags};
+
+const DESC_VALID: u64 = 1 << 0;
+/// A table or a page, otherwise a block.
+const DESC_NON_BLOCK: u64 = 1 << 1;
+const MEMATTR_SHIFT: u64 = 2;
+const MEMATTR_MASK: u64 = 0b1111 << MEMATTR_SHIFT;
+/// Normal memory, outer and inner write-back cacheable.
+const MEMATTR_NORMAL: u64 = 0b1111 << MEMATTR_SHIFT;
+/// Normal memory, outer and inner non-cacheable.
+const MEMATTR_NORMAL_NC: u64 = 0b0101 << MEMATTR_SHIFT;
+/// Device-nGnRE memory.
+const MEMATTR_DEVICE: u64 = 0b0001 << MEMATTR_SHIFT;
+const S2AP_READ: u64 = 1 << 6;
+const S2AP_WRITE: u64 = 1 << 7;
+const SH_INNER: u64 = 0b11 << 8;
+const AF: u64 = 1 << 10;
+/// Not executable at EL1 nor EL0.
+const XN: u64 = 0b10 << 53;
+const ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// A descriptor of the stage-2 page tables, with 4K pages.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct Stage2PTE(u64);
+
+impl Stage2PTE {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+}
+
+impl GenericPTE for Stage2PTE {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        let mut pte = Self(paddr.as_usize() as u64 & ADDR_MASK);
+        pte.set_flags(flags, is_huge);
+        pte
+    }
+
+    fn new_table(paddr: PhysAddr) -> Self {
+        Self(paddr.as_usize() as u64 & ADDR_MASK | DESC_VALID | DESC_NON_BLOCK)
+    }
+
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((self.0 & ADDR_MASK) as usize)
+    }
+
+    fn flags(&self) -> MappingFlags {
+        let mut flags = MappingFlags::empty();
+        if self.0 & S2AP_READ != 0 {
+            flags |= MappingFlags::READ;
+        }
+        if self.0 & S2AP_WRITE != 0 {
+            flags |= MappingFlags::WRITE;
+        }
+        if self.0 & XN == 0 {
+            flags |= MappingFlags::EXECUTE;
+        }
+        match self.0 & MEMATTR_MASK {
+            MEMATTR_DEVICE => flags |= MappingFlags::DEVICE,
+            MEMATTR_NORMAL_NC => flags |= MappingFlags::UNCACHED,
+            _ => {}
+        }
+        flags
+    }
+
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 = self.0 & !ADDR_MASK | paddr.as_usize() as u64 & ADDR_MASK;
+    }
+
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let mut attr = DESC_VALID | AF | SH_INNER;
+        if !is_huge {
+            attr |= DESC_NON_BLOCK;
+        }
+        if flags.contains(MappingFlags::READ) {
+            attr |= S2AP_READ;
+        }
+        if flags.contains(MappingFlags::WRITE) {
+            attr |= S2AP_WRITE;
+        }
+        if !flags.contains(MappingFlags::EXECUTE) {
+            attr |= XN;
+        }
+        attr |= if flags.contains(MappingFlags::DEVICE) {
+            MEMATTR_DEVICE
+        } else if flags.contains(MappingFlags::UNCACHED) {
+            MEMATTR_NORMAL_NC
+        } else {
+            MEMATTR_NORMAL
+        };
+        self.0 = self.0 & ADDR_MASK | attr;
+    }
+
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn is_present(&self) -> bool {
+        self.0 & DESC_VALID != 0
+    }
+
+    fn is_huge(&self) -> bool {
+        self.0 & DESC_NON_BLOCK == 0
+    }
+
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+impl fmt::Debug for Stage2PTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stage2PTE")
+            .field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
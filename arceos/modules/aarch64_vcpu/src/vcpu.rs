@@ -0,0 +1,519 @@
+use core::arch::global_asm;
+
+use memoffset::offset_of;
+
+use axerrno::{ax_err, AxResult};
+
+use super::mmio::{self, MmioAccess, ESR_IL};
+use super::psci;
+use super::HCR_E2H;
+use axhal::paging::MappingFlags;
+use memory_addr::{PhysAddr, VirtAddr};
+
+/// Guest physical address.
+pub type GuestPhysAddr = VirtAddr;
+/// Host physical address.
+pub type HostPhysAddr = PhysAddr;
+
+const HCR_VM: u64 = 1 << 0;
+const HCR_SWIO: u64 = 1 << 1;
+const HCR_FMO: u64 = 1 << 3;
+const HCR_IMO: u64 = 1 << 4;
+const HCR_AMO: u64 = 1 << 5;
+const HCR_VI: u64 = 1 << 7;
+const HCR_TWI: u64 = 1 << 13;
+const HCR_TSC: u64 = 1 << 19;
+const HCR_RW: u64 = 1 << 31;
+/// `HCR_EL2` while the guest runs: stage-2 translation, physical interrupts taken to EL2, `wfi`
+/// and `smc` trapped, and EL1 in AArch64. `E2H` stays set, the host registers being the EL2
+/// ones.
+const HCR_GUEST: u64 =
+    HCR_VM | HCR_SWIO | HCR_FMO | HCR_IMO | HCR_AMO | HCR_TWI | HCR_TSC | HCR_RW | HCR_E2H;
+
+const VTTBR_VMID_SHIFT: u64 = 48;
+const VTTBR_VMID_MASK: u64 = 0xffff;
+const VTTBR_BADDR_MASK: u64 = 0x0000_ffff_ffff_fffe;
+
+/// The guest starts at EL1h, with all the exceptions masked.
+const SPSR_EL1H_MASKED: u64 = 0x3c5;
+/// `SCTLR_EL1` at reset: the RES1 bits, with the MMU and the caches off.
+const SCTLR_EL1_RESET: u64 = 0x30d0_0800;
+/// The RES1 bit of `MPIDR_EL1`.
+const MPIDR_RES1: u64 = 1 << 31;
+
+const ESR_EC_SHIFT: u64 = 26;
+const EC_WFX: u64 = 0x01;
+const EC_HVC64: u64 = 0x16;
+const EC_SMC64: u64 = 0x17;
+const EC_IABT_LOWER: u64 = 0x20;
+const EC_DABT_LOWER: u64 = 0x24;
+/// The ISS bit of a trapped `wfe`, rather than `wfi`.
+const ISS_WFE: u64 = 1 << 0;
+/// The ISS bit of a data abort on a write.
+const ISS_WNR: u64 = 1 << 6;
+/// The bits of `HPFAR_EL2` holding the faulting guest physical page.
+const HPFAR_FIPA_MASK: u64 = 0x0000_0fff_ffff_fff0;
+
+/// The exception the guest exited with, set by `_guest_exit`.
+const EXIT_SYNC: u64 = 0;
+const EXIT_IRQ: u64 = 1;
+const EXIT_FIQ: u64 = 2;
+const EXIT_SERROR: u64 = 3;
+
+/// The host registers saved while the guest runs, the callee-saved ones.
+#[derive(Default)]
+#[repr(C)]
+struct HostRegs {
+    x19_x30: [u64; 12],
+    d8_d15: [u64; 8],
+}
+
+/// The guest registers saved while the host runs.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct GuestRegs {
+    pub x: [u64; 31],
+    pub sp_el0: u64,
+    /// The guest pc.
+    pub elr: u64,
+    /// The guest `PSTATE`.
+    pub spsr: u64,
+    pub q: [u128; 32],
+    pub fpcr: u64,
+    pub fpsr: u64,
+}
+
+#[derive(Default)]
+#[repr(C)]
+struct VmCpuRegisters {
+    host: HostRegs,
+    guest: GuestRegs,
+    exit_kind: u64,
+}
+
+macro_rules! guest_sysregs {
+    ($($field:ident: $reg:literal,)*) => {
+        /// The EL1 system registers of the guest.
+        ///
+        /// With VHE, the host accesses them with their `_EL12` and `_EL02` aliases, written with
+        /// their encodings for the assemblers without VHE.
+        #[derive(Debug, Default, Clone, Copy)]
+        pub struct GuestSysRegs {
+            $(pub $field: u64,)*
+        }
+
+        impl GuestSysRegs {
+            /// Loads the registers on the current CPU.
+            fn load(&self) {
+                $(write_sysreg!($reg, self.$field);)*
+            }
+
+            /// Saves the registers of the current CPU.
+            fn save(&mut self) {
+                $(self.$field = read_sysreg!($reg);)*
+            }
+        }
+    };
+}
+
+guest_sysregs! {
+    sctlr: "s3_5_c1_c0_0",      // SCTLR_EL12
+    cpacr: "s3_5_c1_c0_2",      // CPACR_EL12
+    ttbr0: "s3_5_c2_c0_0",      // TTBR0_EL12
+    ttbr1: "s3_5_c2_c0_1",      // TTBR1_EL12
+    tcr: "s3_5_c2_c0_2",        // TCR_EL12
+    spsr: "s3_5_c4_c0_0",       // SPSR_EL12
+    elr: "s3_5_c4_c0_1",        // ELR_EL12
+    afsr0: "s3_5_c5_c1_0",      // AFSR0_EL12
+    afsr1: "s3_5_c5_c1_1",      // AFSR1_EL12
+    esr: "s3_5_c5_c2_0",        // ESR_EL12
+    far: "s3_5_c6_c0_0",        // FAR_EL12
+    mair: "s3_5_c10_c2_0",      // MAIR_EL12
+    amair: "s3_5_c10_c3_0",     // AMAIR_EL12
+    vbar: "s3_5_c12_c0_0",      // VBAR_EL12
+    contextidr: "s3_5_c13_c0_1", // CONTEXTIDR_EL12
+    cntkctl: "s3_5_c14_c1_0",   // CNTKCTL_EL12
+    cntv_ctl: "s3_5_c14_c3_1",  // CNTV_CTL_EL02
+    cntv_cval: "s3_5_c14_c3_2", // CNTV_CVAL_EL02
+    sp_el1: "sp_el1",
+    tpidr_el1: "tpidr_el1",
+    tpidr_el0: "tpidr_el0",
+    tpidrro_el0: "tpidrro_el0",
+    par_el1: "par_el1",
+}
+
+/// The syndrome registers of the last exit.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TrapRegs {
+    pub esr: u64,
+    pub far: u64,
+    pub hpfar: u64,
+}
+
+extern "C" {
+    fn _run_guest(state: *mut VmCpuRegisters);
+    fn _guest_exit_vectors();
+}
+
+global_asm!(
+    include_str!("guest.S"),
+    host_x = const offset_of!(VmCpuRegisters, host) + offset_of!(HostRegs, x19_x30),
+    host_d = const offset_of!(VmCpuRegisters, host) + offset_of!(HostRegs, d8_d15),
+    guest_x = const offset_of!(VmCpuRegisters, guest) + offset_of!(GuestRegs, x),
+    guest_sp_el0 = const offset_of!(VmCpuRegisters, guest) + offset_of!(GuestRegs, sp_el0),
+    guest_elr = const offset_of!(VmCpuRegisters, guest) + offset_of!(GuestRegs, elr),
+    guest_spsr = const offset_of!(VmCpuRegisters, guest) + offset_of!(GuestRegs, spsr),
+    guest_q = const offset_of!(VmCpuRegisters, guest) + offset_of!(GuestRegs, q),
+    guest_fpcr = const offset_of!(VmCpuRegisters, guest) + offset_of!(GuestRegs, fpcr),
+    guest_fpsr = const offset_of!(VmCpuRegisters, guest) + offset_of!(GuestRegs, fpsr),
+    exit_kind = const offset_of!(VmCpuRegisters, exit_kind),
+    exit_sync = const EXIT_SYNC,
+    exit_irq = const EXIT_IRQ,
+    exit_fiq = const EXIT_FIQ,
+    exit_serror = const EXIT_SERROR,
+);
+
+/// A virtual CPU within a guest
+pub struct AArch64VCpu {
+    regs: VmCpuRegisters,
+    sys_regs: GuestSysRegs,
+    /// `HCR_EL2` while the guest runs.
+    hcr: u64,
+    vttbr: u64,
+    vmpidr: u64,
+    trap: TrapRegs,
+}
+
+impl AArch64VCpu {
+    pub fn init() -> Self {
+        let mut regs = VmCpuRegisters::default();
+        regs.guest.spsr = SPSR_EL1H_MASKED;
+        Self {
+            regs,
+            sys_regs: GuestSysRegs {
+                sctlr: SCTLR_EL1_RESET,
+                ..Default::default()
+            },
+            hcr: HCR_GUEST,
+            vttbr: 0,
+            vmpidr: MPIDR_RES1,
+            trap: TrapRegs::default(),
+        }
+    }
+
+    pub fn set_entry(&mut self, entry: GuestPhysAddr) -> AxResult {
+        self.regs.guest.elr = entry.as_usize() as u64;
+        Ok(())
+    }
+
+    /// Sets the root of the stage-2 page tables, made of [`Stage2PTE`](crate::Stage2PTE).
+    pub fn set_ept_root(&mut self, ept_root: HostPhysAddr) -> AxResult {
+        let vmid = self.vttbr & (VTTBR_VMID_MASK << VTTBR_VMID_SHIFT);
+        self.vttbr = vmid | ept_root.as_usize() as u64 & VTTBR_BADDR_MASK;
+        Ok(())
+    }
+
+    /// Sets the VMID tagging the stage-2 translations of this vCPU.
+    ///
+    /// All vCPUs of a VM should use the same VMID, and different VMs different ones. The VMID
+    /// must fit in the VMID bits of every CPU the vCPU runs on, see [`crate::vmid_bits`].
+    pub fn set_vmid(&mut self, vmid: usize) -> AxResult {
+        if vmid as u64 > VTTBR_VMID_MASK {
+            return ax_err!(InvalidInput);
+        }
+        self.vttbr =
+            self.vttbr & !(VTTBR_VMID_MASK << VTTBR_VMID_SHIFT) | (vmid as u64) << VTTBR_VMID_SHIFT;
+        Ok(())
+    }
+
+    /// Returns the VMID of this vCPU.
+    pub fn vmid(&self) -> usize {
+        (self.vttbr >> VTTBR_VMID_SHIFT & VTTBR_VMID_MASK) as usize
+    }
+
+    /// Sets the `Aff0` of the `MPIDR_EL1` the guest reads, its CPU ID, e.g. for PSCI `CPU_ON`.
+    pub fn set_cpu_id(&mut self, id: usize) {
+        self.vmpidr = MPIDR_RES1 | (id as u64 & 0xff);
+    }
+
+    /// Invalidates the stage-2 translations of this vCPU's VMID on the current CPU.
+    ///
+    /// Must be called after the stage-2 page tables are modified. Other CPUs running vCPUs of the
+    /// same VM have to do it themselves.
+    pub fn flush_ept(&self) {
+        use axhal::arch::{local_irq_restore, local_irq_save_and_disable};
+        // The invalidation is for the EL1&0 regime of the VMID in `VTTBR_EL2` only without
+        // `TGE`, so the guest `HCR_EL2` is loaded meanwhile.
+        let flags = local_irq_save_and_disable();
+        let host_hcr = read_sysreg!("hcr_el2");
+        write_sysreg!("vttbr_el2", self.vttbr);
+        write_sysreg!("hcr_el2", self.hcr);
+        unsafe {
+            core::arch::asm!("isb", "dsb ishst", "tlbi vmalls12e1", "dsb nsh");
+        }
+        write_sysreg!("hcr_el2", host_hcr);
+        unsafe { core::arch::asm!("isb") };
+        local_irq_restore(flags);
+    }
+
+    pub fn run(&mut self) -> AxResult<AxVCpuExitReason> {
+        // The EL1 registers are per-CPU state, load them in case the vCPU is run by another CPU.
+        self.sys_regs.load();
+        write_sysreg!("vttbr_el2", self.vttbr);
+        write_sysreg!("vmpidr_el2", self.vmpidr);
+        let host_hcr = read_sysreg!("hcr_el2");
+        let host_vbar = read_sysreg!("vbar_el2");
+        write_sysreg!("hcr_el2", self.hcr);
+        write_sysreg!("vbar_el2", _guest_exit_vectors as usize);
+        unsafe {
+            core::arch::asm!("isb");
+            // Safe to run the guest as it only touches memory assigned to it by being owned
+            // by its page table
+            _run_guest(&mut self.regs);
+        }
+        self.trap = TrapRegs {
+            esr: read_sysreg!("esr_el2"),
+            far: read_sysreg!("far_el2"),
+            hpfar: read_sysreg!("hpfar_el2"),
+        };
+        write_sysreg!("hcr_el2", host_hcr);
+        write_sysreg!("vbar_el2", host_vbar);
+        unsafe { core::arch::asm!("isb") };
+        self.sys_regs.save();
+        self.vmexit_handler()
+    }
+
+    /// Gets the register `x<index>` of the vCPU.
+    pub fn get_gpr(&self, index: usize) -> u64 {
+        self.regs.guest.x[index]
+    }
+
+    /// Sets the register `x<index>` of the vCPU.
+    pub fn set_gpr(&mut self, index: usize, val: u64) {
+        self.regs.guest.x[index] = val;
+    }
+
+    /// Gets the vCPU's registers.
+    pub fn regs(&mut self) -> &mut GuestRegs {
+        &mut self.regs.guest
+    }
+
+    /// Gets the vCPU's EL1 system registers.
+    pub fn sys_regs(&mut self) -> &mut GuestSysRegs {
+        &mut self.sys_regs
+    }
+
+    /// The syndrome registers of the last exit, e.g. to report an unhandled trap.
+    pub fn trap_regs(&self) -> &TrapRegs {
+        &self.trap
+    }
+
+    /// Asserts the virtual IRQ line of the vCPU, until [`clear_irq`](Self::clear_irq).
+    pub fn inject_irq(&mut self) {
+        self.hcr |= HCR_VI;
+    }
+
+    /// Deasserts the virtual IRQ line of the vCPU.
+    pub fn clear_irq(&mut self) {
+        self.hcr &= !HCR_VI;
+    }
+
+    /// Decodes the guest access to `addr`, the address of a [`NestedPageFault`] exit, to emulate
+    /// it.
+    ///
+    /// [`NestedPageFault`]: AxVCpuExitReason::NestedPageFault
+    pub fn decode_mmio(&self, addr: GuestPhysAddr) -> AxResult<MmioAccess> {
+        mmio::decode_abort(self.trap.esr, addr, |reg| self.get_gpr(reg))
+    }
+
+    /// Completes the emulated `access`: a load gets `val` in its destination
+    /// register, and the guest resumes after the instruction.
+    pub fn complete_mmio(&mut self, access: &MmioAccess, val: u64) {
+        if let Some((reg, val)) = mmio::loaded_value(access.width, access.op, val) {
+            self.set_gpr(reg, val);
+        }
+        self.advance_pc(access.insn_len);
+    }
+
+    /// Advance guest pc by `instr_len` bytes
+    pub fn advance_pc(&mut self, instr_len: usize) {
+        self.regs.guest.elr += instr_len as u64
+    }
+}
+
+impl AArch64VCpu {
+    fn vmexit_handler(&mut self) -> AxResult<AxVCpuExitReason> {
+        let esr = self.trap.esr;
+        match self.regs.exit_kind {
+            // Taken by the host once it unmasks the interrupts.
+            EXIT_IRQ | EXIT_FIQ => return Ok(AxVCpuExitReason::Nothing),
+            EXIT_SERROR => {
+                warn!("SError in the guest, elr {:#x}", self.regs.guest.elr);
+                return Ok(AxVCpuExitReason::UnhandledTrap { esr });
+            }
+            _ => {}
+        }
+        let insn_len = if esr & ESR_IL != 0 { 4 } else { 2 };
+        match esr >> ESR_EC_SHIFT & 0x3f {
+            EC_WFX => {
+                self.advance_pc(insn_len);
+                if esr & ISS_WFE != 0 {
+                    Ok(AxVCpuExitReason::Nothing)
+                } else {
+                    Ok(AxVCpuExitReason::Halt)
+                }
+            }
+            // `elr` is past the `hvc` already.
+            EC_HVC64 => Ok(self.handle_psci(true)),
+            EC_SMC64 => {
+                self.advance_pc(insn_len);
+                Ok(self.handle_psci(false))
+            }
+            ec @ (EC_IABT_LOWER | EC_DABT_LOWER) => {
+                let ipa = (self.trap.hpfar & HPFAR_FIPA_MASK) << 8 | self.trap.far & 0xfff;
+                let access_flags = if ec == EC_IABT_LOWER {
+                    MappingFlags::EXECUTE
+                } else if esr & ISS_WNR != 0 {
+                    MappingFlags::WRITE
+                } else {
+                    MappingFlags::READ
+                };
+                Ok(AxVCpuExitReason::NestedPageFault {
+                    addr: GuestPhysAddr::from(ipa as usize),
+                    access_flags,
+                })
+            }
+            ec => {
+                warn!(
+                    "Unhandled trap: EC {:#x}, esr {:#x}, far {:#x}, elr {:#x}",
+                    ec, esr, self.trap.far, self.regs.guest.elr
+                );
+                Ok(AxVCpuExitReason::UnhandledTrap { esr })
+            }
+        }
+    }
+
+    /// Handles the PSCI call of the guest, or forwards it to the hypervisor. Other `hvc` calls
+    /// are hypercalls, other `smc` calls are not supported.
+    fn handle_psci(&mut self, hvc: bool) -> AxVCpuExitReason {
+        let x = self.regs.guest.x;
+        let func = psci::to_smc64(x[0]);
+        let ret = match func {
+            psci::PSCI_VERSION => psci::VERSION_1_0 as i64,
+            psci::PSCI_FEATURES => {
+                if psci::is_supported(psci::to_smc64(x[1])) {
+                    psci::PSCI_SUCCESS
+                } else {
+                    psci::PSCI_NOT_SUPPORTED
+                }
+            }
+            psci::MIGRATE_INFO_TYPE => psci::MIGRATE_NOT_REQUIRED as i64,
+            _ => {
+                // The hypervisor may overwrite `x0` with an error code.
+                self.regs.guest.x[0] = psci::PSCI_SUCCESS as u64;
+                return match func {
+                    // A standby, as `wfi`.
+                    psci::CPU_SUSPEND => AxVCpuExitReason::Halt,
+                    psci::CPU_OFF => AxVCpuExitReason::CpuDown,
+                    psci::CPU_ON => AxVCpuExitReason::CpuUp {
+                        target_cpu: (x[1] & 0xff) as usize,
+                        entry_point: GuestPhysAddr::from(x[2] as usize),
+                        arg: x[3] as usize,
+                    },
+                    psci::AFFINITY_INFO => AxVCpuExitReason::CpuStatus {
+                        target_cpu: (x[1] & 0xff) as usize,
+                    },
+                    psci::SYSTEM_OFF => AxVCpuExitReason::SystemDown,
+                    psci::SYSTEM_RESET => AxVCpuExitReason::SystemReset,
+                    _ if hvc => AxVCpuExitReason::Hypercall {
+                        nr: x[0],
+                        args: [x[1], x[2], x[3], x[4], x[5], x[6]],
+                    },
+                    _ => {
+                        self.regs.guest.x[0] = psci::PSCI_NOT_SUPPORTED as u64;
+                        AxVCpuExitReason::Nothing
+                    }
+                };
+            }
+        };
+        self.regs.guest.x[0] = ret as u64;
+        AxVCpuExitReason::Nothing
+    }
+}
+
+/// The width of an access.
+///
+/// Note that the term "word" here refers to 16-bit data, as in the x86 architecture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessWidth {
+    /// 8-bit access.
+    Byte,
+    /// 16-bit access.
+    Word,
+    /// 32-bit access.
+    Dword,
+    /// 64-bit access.
+    Qword,
+}
+
+/// The result of [`AArch64VCpu::run`].
+#[derive(Debug)]
+pub enum AxVCpuExitReason {
+    /// The vcpu made a hypercall with `hvc`, other than a PSCI call.
+    Hypercall {
+        /// The hypercall number, in `x0`.
+        nr: u64,
+        /// The arguments for the hypercall, in `x1` to `x6`.
+        args: [u64; 6],
+    },
+    /// A nested page fault happened: the stage-2 translation of the guest access faulted.
+    ///
+    /// Note that fields may be added in the future, use `..` to handle them.
+    NestedPageFault {
+        /// The guest physical address of the fault.
+        addr: GuestPhysAddr,
+        /// The access flags of the fault.
+        access_flags: MappingFlags,
+    },
+    /// The vcpu asks to start another vcpu of the same VM (PSCI `CPU_ON`).
+    ///
+    /// The call returns success unless the hypervisor overwrites `x0` with a PSCI error code.
+    CpuUp {
+        /// The `Aff0` of the vcpu to start.
+        target_cpu: usize,
+        /// The guest physical address the vcpu starts at.
+        entry_point: GuestPhysAddr,
+        /// The opaque argument passed to the vcpu in `x0`.
+        arg: usize,
+    },
+    /// The vcpu asks for the state of a vcpu of the same VM (PSCI `AFFINITY_INFO`).
+    ///
+    /// The hypervisor puts the state of the vcpu in `x0`, or a PSCI error code.
+    CpuStatus {
+        /// The `Aff0` of the vcpu.
+        target_cpu: usize,
+    },
+    /// The vcpu executed `wfi`, or a PSCI `CPU_SUSPEND` standby: it waits for an interrupt, and
+    /// may be descheduled until it gets one. The guest resumes after the instruction.
+    Halt,
+    /// The vcpu is powered off (PSCI `CPU_OFF`).
+    ///
+    /// This vcpu may be resumed later.
+    CpuDown,
+    /// The system should be powered off (PSCI `SYSTEM_OFF`). The guest does not resume.
+    SystemDown,
+    /// The system should be rebooted (PSCI `SYSTEM_RESET`).
+    ///
+    /// The guest does not resume: the hypervisor restarts it from its kernel image.
+    SystemReset,
+    /// The vcpu trapped with a cause the hypervisor cannot handle, see
+    /// [`AArch64VCpu::trap_regs`] for the details. The guest cannot resume.
+    UnhandledTrap {
+        /// The `ESR_EL2` of the trap.
+        esr: u64,
+    },
+    /// Nothing special happened, the vcpu has handled the exit itself.
+    ///
+    /// This exists to allow the caller to have a chance to check virtual devices/physical devices/virtual interrupts.
+    Nothing,
+}
@@ -0,0 +1,106 @@
+//! Free memory, fragmentation and memory map reporting, shared by the
+//! allocator modules.
+
+#![no_std]
+
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+/// A snapshot of the free memory of an allocator.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// The number of free bytes.
+    pub free_bytes: usize,
+    /// The number of free blocks, i.e. disjoint ranges the allocator can hand
+    /// out. It is 0 if the allocator does not track them.
+    pub free_blocks: usize,
+    /// The size of the largest free block, in bytes.
+    pub largest_free_block: usize,
+}
+
+impl MemoryStats {
+    /// Counts a free block of `size` bytes.
+    pub fn add_block(&mut self, size: usize) {
+        if size > 0 {
+            self.free_bytes += size;
+            self.free_blocks += 1;
+            self.largest_free_block = self.largest_free_block.max(size);
+        }
+    }
+
+    /// Combines the stats of two allocators.
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            free_bytes: self.free_bytes + other.free_bytes,
+            free_blocks: self.free_blocks + other.free_blocks,
+            largest_free_block: self.largest_free_block.max(other.largest_free_block),
+        }
+    }
+
+    /// Returns the external fragmentation in percent, i.e. the part of the
+    /// free memory that is outside of the largest free block.
+    ///
+    /// It is 0 if there is no free memory, or if the free blocks are not
+    /// tracked.
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.free_bytes == 0 || self.free_blocks == 0 {
+            return 0;
+        }
+        (self.free_bytes - self.largest_free_block) * 100 / self.free_bytes
+    }
+}
+
+/// An allocator that can report how its free memory is fragmented.
+pub trait AllocatorStats {
+    /// Returns the stats of the free memory.
+    ///
+    /// It may walk the free lists, so it is not meant for hot paths.
+    fn memory_stats(&self) -> MemoryStats;
+
+    /// Returns the size of the largest free block, in bytes.
+    fn largest_free_block(&self) -> usize {
+        self.memory_stats().largest_free_block
+    }
+
+    /// Returns the number of free blocks.
+    fn free_block_count(&self) -> usize {
+        self.memory_stats().free_blocks
+    }
+
+    /// Returns the external fragmentation in percent, see
+    /// [`MemoryStats::fragmentation_percent`].
+    fn fragmentation_percent(&self) -> usize {
+        self.memory_stats().fragmentation_percent()
+    }
+}
+
+/// An allocation event reported to the trace hook of an allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocEvent {
+    /// `size` bytes aligned to `align` were allocated at `pos`.
+    Alloc {
+        pos: usize,
+        size: usize,
+        align: usize,
+    },
+    /// `size` bytes at `pos` were freed.
+    Dealloc { pos: usize, size: usize },
+    /// `num_pages` pages were allocated at `pos`.
+    AllocPages { pos: usize, num_pages: usize },
+    /// `num_pages` pages at `pos` were freed.
+    DeallocPages { pos: usize, num_pages: usize },
+    /// An allocation of `size` bytes aligned to `align` failed.
+    Failed { size: usize, align: usize },
+}
+
+/// What a memory range reported by the `iter_regions` of an allocator is
+/// used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Used by byte allocations, including their padding.
+    BytesUsed,
+    /// Free.
+    Free,
+    /// Used by page allocations.
+    PagesUsed,
+}
@@ -0,0 +1,40 @@
+//! Backing memory for the tests of the allocator modules.
+
+extern crate std;
+
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use std::alloc::{alloc, dealloc};
+
+/// Memory from the host allocator that a tested allocator can manage and
+/// write to, freed on drop.
+pub struct TestMemory {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl TestMemory {
+    /// Allocates `size` bytes aligned to `align`, e.g. to the size for a
+    /// buddy allocator to see a single block.
+    pub fn new(size: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = NonNull::new(unsafe { alloc(layout) }).expect("out of host memory");
+        Self { ptr, layout }
+    }
+
+    /// Returns the start address of the memory.
+    pub fn start(&self) -> usize {
+        self.ptr.as_ptr() as usize
+    }
+
+    /// Returns the size of the memory in bytes.
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+}
+
+impl Drop for TestMemory {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
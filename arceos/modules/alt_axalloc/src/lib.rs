@@ -4,24 +4,29 @@
 extern crate log;
 extern crate alloc;
 
-use allocator::{AllocResult, BaseAllocator, ByteAllocator, PageAllocator};
-use bump_allocator::EarlyAllocator;
+use alloc_stats::AllocatorStats;
+use allocator::AllocResult;
+use bump_allocator::GlobalEarlyAllocator;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::NonNull;
-use kspin::SpinNoIrq;
+
+pub use alloc_stats::MemoryStats;
+#[cfg(feature = "fail-alloc")]
+pub use bump_allocator::FailPolicy;
+pub use bump_allocator::{AllocEvent, UnusedMemory};
 
 const PAGE_SIZE: usize = 0x1000;
 
 /// The global allocator used by ArceOS.
 pub struct GlobalAllocator {
-    inner: SpinNoIrq<EarlyAllocator<PAGE_SIZE>>,
+    inner: GlobalEarlyAllocator<PAGE_SIZE>,
 }
 
 impl GlobalAllocator {
     /// Creates an empty [`GlobalAllocator`].
     pub const fn new() -> Self {
         Self {
-            inner: SpinNoIrq::new(EarlyAllocator::new()),
+            inner: GlobalEarlyAllocator::new(),
         }
     }
 
@@ -32,54 +37,116 @@ impl GlobalAllocator {
 
     /// Initializes the allocator with the given region.
     pub fn init(&self, start_vaddr: usize, size: usize) {
-        self.inner.lock().init(start_vaddr, size);
+        self.inner.init(start_vaddr, size);
+    }
+
+    /// Sets the function called on every allocation and deallocation. `None`
+    /// removes it.
+    ///
+    /// The hook is called with the allocator locked, so it must not allocate.
+    pub fn set_trace_hook(&self, hook: Option<fn(AllocEvent)>) {
+        self.inner.set_trace_hook(hook)
+    }
+
+    /// Sets when allocations fail, to test out-of-memory error paths.
+    #[cfg(feature = "fail-alloc")]
+    pub fn set_fail_policy(&self, policy: FailPolicy) {
+        self.inner.set_fail_policy(policy)
     }
 
     /// Add the given region to the allocator.
-    pub fn add_memory(&self, _start_vaddr: usize, _size: usize) -> AllocResult {
-        unimplemented!()
+    pub fn add_memory(&self, start_vaddr: usize, size: usize) -> AllocResult {
+        self.inner.add_memory(start_vaddr, size)
+    }
+
+    /// Seals the allocator and returns the memory it did not use, as
+    /// `(start, size)` ranges.
+    ///
+    /// The ranges can be given to the formal allocator, e.g. the first one to
+    /// `axalloc::global_init` and the others to `axalloc::global_add_memory`.
+    /// Allocations fail after that, while the early ones can still be freed.
+    pub fn finalize(&self) -> UnusedMemory {
+        self.inner.finalize()
+    }
+
+    /// Gives back the page-aligned middle of the memory not used yet, as
+    /// `(start, size)` ranges, while allocations keep working with the rest.
+    pub fn release_unused(&self) -> UnusedMemory {
+        self.inner.release_unused()
     }
 
     /// Allocate arbitrary number of bytes. Returns the left bound of the
     /// allocated region.
     pub fn alloc(&self, layout: Layout) -> AllocResult<NonNull<u8>> {
-        self.inner.lock().alloc(layout)
+        self.inner.alloc(layout)
+    }
+
+    /// Allocates zero-filled bytes. Memory never handed out before is known to
+    /// be zero and is not cleared again.
+    pub fn alloc_zeroed(&self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        self.inner.alloc_zeroed(layout)
     }
 
     /// Gives back the allocated region to the byte allocator.
     pub fn dealloc(&self, pos: NonNull<u8>, layout: Layout) {
-        self.inner.lock().dealloc(pos, layout)
+        self.inner.dealloc(pos, layout)
+    }
+
+    /// Resizes the region at `pos` to `new_size` bytes, in place if it is the
+    /// last allocation.
+    ///
+    /// # Safety
+    ///
+    /// `pos` must be allocated by this allocator with `layout`.
+    pub unsafe fn realloc(
+        &self,
+        pos: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> AllocResult<NonNull<u8>> {
+        self.inner.realloc(pos, layout, new_size)
     }
 
     /// Allocates contiguous pages.
     pub fn alloc_pages(&self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
-        self.inner.lock().alloc_pages(num_pages, align_pow2)
+        self.inner.alloc_pages(num_pages, align_pow2)
+    }
+
+    /// Allocates contiguous pages at the fixed address `base`, if they are
+    /// free.
+    pub fn alloc_pages_at(&self, base: usize, num_pages: usize) -> AllocResult<usize> {
+        self.inner.alloc_pages_at(base, num_pages)
     }
 
     /// Gives back the allocated pages starts from `pos` to the page allocator.
     /// [`alloc_pages`]: GlobalAllocator::alloc_pages
     pub fn dealloc_pages(&self, pos: usize, num_pages: usize) {
-        self.inner.lock().dealloc_pages(pos, num_pages)
+        self.inner.dealloc_pages(pos, num_pages)
     }
 
     /// Returns the number of allocated bytes in the byte allocator.
     pub fn used_bytes(&self) -> usize {
-        self.inner.lock().used_bytes()
+        self.inner.used_bytes()
     }
 
     /// Returns the number of available bytes in the byte allocator.
     pub fn available_bytes(&self) -> usize {
-        self.inner.lock().available_bytes()
+        self.inner.available_bytes()
     }
 
     /// Returns the number of allocated pages in the page allocator.
     pub fn used_pages(&self) -> usize {
-        self.inner.lock().used_pages()
+        self.inner.used_pages()
     }
 
     /// Returns the number of available pages in the page allocator.
     pub fn available_pages(&self) -> usize {
-        self.inner.lock().available_pages()
+        self.inner.available_pages()
+    }
+
+    /// Returns the stats of the free memory.
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.inner.with(|a| a.memory_stats())
     }
 }
 
@@ -92,9 +159,25 @@ unsafe impl GlobalAlloc for GlobalAllocator {
         }
     }
 
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = GlobalAllocator::alloc_zeroed(self, layout) {
+            ptr.as_ptr()
+        } else {
+            alloc::alloc::handle_alloc_error(layout)
+        }
+    }
+
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         GlobalAllocator::dealloc(self, NonNull::new(ptr).expect("dealloc null ptr"), layout)
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let pos = NonNull::new(ptr).expect("realloc null ptr");
+        match GlobalAllocator::realloc(self, pos, layout, new_size) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => core::ptr::null_mut(),
+        }
+    }
 }
 
 #[cfg_attr(all(target_os = "none", not(test)), global_allocator)]
@@ -116,6 +199,11 @@ pub fn global_init(start_vaddr: usize, size: usize) {
 }
 
 /// Add the given memory region to the global allocator.
-pub fn global_add_memory(_start_vaddr: usize, _size: usize) -> AllocResult {
-    unimplemented!()
+pub fn global_add_memory(start_vaddr: usize, size: usize) -> AllocResult {
+    debug!(
+        "add a memory region to global allocator: [{:#x}, {:#x})",
+        start_vaddr,
+        start_vaddr + size
+    );
+    GLOBAL_ALLOCATOR.add_memory(start_vaddr, size)
 }
@@ -4,6 +4,10 @@
 //! [`core::alloc::GlobalAlloc`]. A static global variable of type
 //! [`GlobalAllocator`] is defined with the `#[global_allocator]` attribute, to
 //! be registered as the standard library’s default allocator.
+//!
+//! With the `early-heap` feature, the registered allocator serves the heap
+//! from a region given to [`early_init`] until [`global_init`] is called, so
+//! that boot code can allocate before the memory regions are known.
 
 #![no_std]
 
@@ -12,8 +16,9 @@ extern crate log;
 extern crate alloc;
 
 mod page;
+mod trace;
 
-use allocator::{AllocResult, BaseAllocator, BitmapPageAllocator, ByteAllocator, PageAllocator};
+use allocator::{AllocResult, BaseAllocator, ByteAllocator, PageAllocator};
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::NonNull;
 use kspin::SpinNoIrq;
@@ -21,10 +26,16 @@ use kspin::SpinNoIrq;
 const PAGE_SIZE: usize = 0x1000;
 const MIN_HEAP_SIZE: usize = 0x8000; // 32 K
 
+pub use alloc_stats::{AllocEvent, AllocatorStats, MemoryStats};
 pub use page::GlobalPage;
 
+use trace::TraceHook;
+
 cfg_if::cfg_if! {
-    if #[cfg(feature = "slab")] {
+    if #[cfg(feature = "tlsf-native")] {
+        /// The default byte allocator.
+        pub type DefaultByteAllocator = tlsf_allocator::TlsfByteAllocator;
+    } else if #[cfg(feature = "slab")] {
         /// The default byte allocator.
         pub type DefaultByteAllocator = allocator::SlabByteAllocator;
     } else if #[cfg(feature = "buddy")] {
@@ -36,6 +47,16 @@ cfg_if::cfg_if! {
     }
 }
 
+cfg_if::cfg_if! {
+    if #[cfg(feature = "page-buddy")] {
+        /// The default page allocator.
+        pub type DefaultPageAllocator = buddy_allocator::BuddyPageAllocator<PAGE_SIZE>;
+    } else {
+        /// The default page allocator.
+        pub type DefaultPageAllocator = bitmap_page_allocator::BitmapPageAllocator<PAGE_SIZE>;
+    }
+}
+
 /// The global allocator used by ArceOS.
 ///
 /// It combines a [`ByteAllocator`] and a [`PageAllocator`] into a simple
@@ -43,13 +64,17 @@ cfg_if::cfg_if! {
 /// there is no memory, asks the page allocator for more memory and adds it to
 /// the byte allocator.
 ///
-/// Currently, [`TlsfByteAllocator`] is used as the byte allocator, while
-/// [`BitmapPageAllocator`] is used as the page allocator.
+/// Currently, [`TlsfByteAllocator`] is used as the byte allocator, or
+/// `tlsf_allocator::TlsfByteAllocator` with the `tlsf-native` feature, while
+/// [`BitmapPageAllocator`] is used as the page allocator, or
+/// `buddy_allocator::BuddyPageAllocator` with the `page-buddy` feature.
 ///
 /// [`TlsfByteAllocator`]: allocator::TlsfByteAllocator
+/// [`BitmapPageAllocator`]: bitmap_page_allocator::BitmapPageAllocator
 pub struct GlobalAllocator {
     balloc: SpinNoIrq<DefaultByteAllocator>,
-    palloc: SpinNoIrq<BitmapPageAllocator<PAGE_SIZE>>,
+    palloc: SpinNoIrq<DefaultPageAllocator>,
+    trace_hook: TraceHook,
 }
 
 impl GlobalAllocator {
@@ -57,14 +82,26 @@ impl GlobalAllocator {
     pub const fn new() -> Self {
         Self {
             balloc: SpinNoIrq::new(DefaultByteAllocator::new()),
-            palloc: SpinNoIrq::new(BitmapPageAllocator::new()),
+            palloc: SpinNoIrq::new(DefaultPageAllocator::new()),
+            trace_hook: TraceHook::new(),
         }
     }
 
+    /// Sets the function called on every allocation and deallocation. `None`
+    /// removes it.
+    ///
+    /// The hook may be called with the allocator locked, so it must not
+    /// allocate.
+    pub fn set_trace_hook(&self, hook: Option<fn(AllocEvent)>) {
+        self.trace_hook.set(hook);
+    }
+
     /// Returns the name of the allocator.
     pub const fn name(&self) -> &'static str {
         cfg_if::cfg_if! {
-            if #[cfg(feature = "slab")] {
+            if #[cfg(feature = "tlsf-native")] {
+                "TLSF (native)"
+            } else if #[cfg(feature = "slab")] {
                 "slab"
             } else if #[cfg(feature = "buddy")] {
                 "buddy"
@@ -107,6 +144,11 @@ impl GlobalAllocator {
         let mut balloc = self.balloc.lock();
         loop {
             if let Ok(ptr) = balloc.alloc(layout) {
+                self.trace_hook.call(AllocEvent::Alloc {
+                    pos: ptr.as_ptr() as usize,
+                    size: layout.size(),
+                    align: layout.align(),
+                });
                 return Ok(ptr);
             } else {
                 let old_size = balloc.total_bytes();
@@ -114,7 +156,14 @@ impl GlobalAllocator {
                     .max(layout.size())
                     .next_power_of_two()
                     .max(PAGE_SIZE);
-                let heap_ptr = self.alloc_pages(expand_size / PAGE_SIZE, PAGE_SIZE)?;
+                let heap_ptr = self
+                    .alloc_pages(expand_size / PAGE_SIZE, PAGE_SIZE)
+                    .inspect_err(|_| {
+                        self.trace_hook.call(AllocEvent::Failed {
+                            size: layout.size(),
+                            align: layout.align(),
+                        })
+                    })?;
                 debug!(
                     "expand heap memory: [{:#x}, {:#x})",
                     heap_ptr,
@@ -133,6 +182,10 @@ impl GlobalAllocator {
     ///
     /// [`alloc`]: GlobalAllocator::alloc
     pub fn dealloc(&self, pos: NonNull<u8>, layout: Layout) {
+        self.trace_hook.call(AllocEvent::Dealloc {
+            pos: pos.as_ptr() as usize,
+            size: layout.size(),
+        });
         self.balloc.lock().dealloc(pos, layout)
     }
 
@@ -143,7 +196,15 @@ impl GlobalAllocator {
     /// `align_pow2` must be a power of 2, and the returned region bound will be
     /// aligned to it.
     pub fn alloc_pages(&self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
-        self.palloc.lock().alloc_pages(num_pages, align_pow2)
+        let res = self.palloc.lock().alloc_pages(num_pages, align_pow2);
+        self.trace_hook.call(match res {
+            Ok(pos) => AllocEvent::AllocPages { pos, num_pages },
+            Err(_) => AllocEvent::Failed {
+                size: num_pages.saturating_mul(PAGE_SIZE),
+                align: align_pow2,
+            },
+        });
+        res
     }
 
     /// Gives back the allocated pages starts from `pos` to the page allocator.
@@ -154,6 +215,8 @@ impl GlobalAllocator {
     ///
     /// [`alloc_pages`]: GlobalAllocator::alloc_pages
     pub fn dealloc_pages(&self, pos: usize, num_pages: usize) {
+        self.trace_hook
+            .call(AllocEvent::DeallocPages { pos, num_pages });
         self.palloc.lock().dealloc_pages(pos, num_pages)
     }
 
@@ -176,6 +239,12 @@ impl GlobalAllocator {
     pub fn available_pages(&self) -> usize {
         self.palloc.lock().available_pages()
     }
+
+    /// Returns the stats of the free memory of the page allocator, which the
+    /// heap grows from.
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.palloc.lock().memory_stats()
+    }
 }
 
 unsafe impl GlobalAlloc for GlobalAllocator {
@@ -192,9 +261,32 @@ unsafe impl GlobalAlloc for GlobalAllocator {
     }
 }
 
-#[cfg_attr(all(target_os = "none", not(test)), global_allocator)]
+#[cfg_attr(
+    all(target_os = "none", not(test), not(feature = "early-heap")),
+    global_allocator
+)]
 static GLOBAL_ALLOCATOR: GlobalAllocator = GlobalAllocator::new();
 
+#[cfg(feature = "early-heap")]
+#[cfg_attr(all(target_os = "none", not(test)), global_allocator)]
+static EARLY_HEAP: bump_allocator::EarlyGlobalAlloc<PAGE_SIZE, GlobalAllocator> =
+    bump_allocator::EarlyGlobalAlloc::new(&GLOBAL_ALLOCATOR);
+
+/// Initializes the early heap with the given memory region, e.g. a static
+/// buffer, to serve allocations until [`global_init`] is called.
+///
+/// Allocations made from it stay valid after [`global_init`], and the part
+/// of the region left unused is then added to the global allocator.
+#[cfg(feature = "early-heap")]
+pub fn early_init(start_vaddr: usize, size: usize) {
+    debug!(
+        "initialize early heap at: [{:#x}, {:#x})",
+        start_vaddr,
+        start_vaddr + size
+    );
+    EARLY_HEAP.init(start_vaddr, size);
+}
+
 /// Returns the reference to the global allocator.
 pub fn global_allocator() -> &'static GlobalAllocator {
     &GLOBAL_ALLOCATOR
@@ -215,6 +307,17 @@ pub fn global_init(start_vaddr: usize, size: usize) {
         start_vaddr + size
     );
     GLOBAL_ALLOCATOR.init(start_vaddr, size);
+    #[cfg(feature = "early-heap")]
+    for (start, size) in EARLY_HEAP.switch_over() {
+        if let Err(e) = GLOBAL_ALLOCATOR.add_memory(start, size) {
+            warn!(
+                "failed to reuse early heap [{:#x}, {:#x}): {:?}",
+                start,
+                start + size,
+                e
+            );
+        }
+    }
 }
 
 /// Add the given memory region to the global allocator.
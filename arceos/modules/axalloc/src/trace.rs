@@ -0,0 +1,28 @@
+//! Tracing of allocations, e.g. to log the allocation patterns for tuning.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc_stats::AllocEvent;
+
+/// The trace hook, stored as a function address, `0` if not set.
+pub(crate) struct TraceHook(AtomicUsize);
+
+impl TraceHook {
+    pub const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+
+    pub fn set(&self, hook: Option<fn(AllocEvent)>) {
+        self.0
+            .store(hook.map_or(0, |f| f as usize), Ordering::Release);
+    }
+
+    pub fn call(&self, event: AllocEvent) {
+        let hook = self.0.load(Ordering::Acquire);
+        if hook != 0 {
+            // SAFETY: only `fn(AllocEvent)` are stored by `set`.
+            let hook: fn(AllocEvent) = unsafe { core::mem::transmute(hook) };
+            hook(event);
+        }
+    }
+}
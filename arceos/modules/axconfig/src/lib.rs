@@ -3,6 +3,9 @@
 //! Currently supported platforms can be found in the [platforms] directory of
 //! the [ArceOS] root.
 //!
+//! Boot-time options given on the kernel command line can be read with
+//! [`option()`], see mod [`option`](mod@option) for details.
+//!
 //! [ArceOS]: https://github.com/arceos-org/arceos
 //! [platforms]: https://github.com/arceos-org/arceos/tree/main/platforms
 
@@ -15,5 +18,9 @@ mod config {
 
 pub use config::*;
 
+pub mod option;
+
+pub use self::option::{option, option_or};
+
 /// End address of the whole physical memory.
 pub const PHYS_MEMORY_END: usize = PHYS_MEMORY_BASE + PHYS_MEMORY_SIZE;
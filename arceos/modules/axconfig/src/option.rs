@@ -0,0 +1,226 @@
+//! Boot-time options parsed from the kernel command line.
+//!
+//! The command line is a list of whitespace-separated `key=value` or `key`
+//! items, as passed by the bootloader (e.g., `/chosen/bootargs` in the device
+//! tree). Values containing spaces can be quoted with `"`.
+//!
+//! Subsystems read typed values with [`option`], and may [`register_option`]
+//! the keys they understand so that unknown keys can be reported.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Maximum length of the saved command line in bytes. Longer command lines
+/// are truncated.
+pub const MAX_CMDLINE_LEN: usize = 1024;
+
+/// Maximum number of options that can be registered.
+pub const MAX_REGISTERED_OPTIONS: usize = 64;
+
+struct CmdlineBuf(UnsafeCell<[u8; MAX_CMDLINE_LEN]>);
+
+unsafe impl Sync for CmdlineBuf {}
+
+static CMDLINE_BUF: CmdlineBuf = CmdlineBuf(UnsafeCell::new([0; MAX_CMDLINE_LEN]));
+static CMDLINE_LEN: AtomicUsize = AtomicUsize::new(0);
+static CMDLINE_INITED: AtomicBool = AtomicBool::new(false);
+
+/// Saves the kernel command line.
+///
+/// It copies `cmdline` into a static buffer, so the original memory (e.g.,
+/// the device tree blob) can be reused afterwards. Only the first call takes
+/// effect, later calls return `false`.
+pub fn init_cmdline(cmdline: &str) -> bool {
+    if CMDLINE_INITED
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return false;
+    }
+    let mut len = cmdline.len().min(MAX_CMDLINE_LEN);
+    while !cmdline.is_char_boundary(len) {
+        len -= 1;
+    }
+    // SAFETY: the buffer is written only once, guarded by `CMDLINE_INITED`,
+    // and is not read before `CMDLINE_LEN` is published.
+    unsafe { (*CMDLINE_BUF.0.get())[..len].copy_from_slice(&cmdline.as_bytes()[..len]) };
+    CMDLINE_LEN.store(len, Ordering::Release);
+    true
+}
+
+/// Returns the saved kernel command line, or an empty string if
+/// [`init_cmdline`] has not been called.
+pub fn cmdline() -> &'static str {
+    let len = CMDLINE_LEN.load(Ordering::Acquire);
+    // SAFETY: the first `len` bytes are never modified once published, and
+    // were copied from a `str` truncated at a char boundary.
+    unsafe { core::str::from_utf8_unchecked(&(*CMDLINE_BUF.0.get())[..len]) }
+}
+
+/// An iterator over the `(key, value)` items of the command line.
+///
+/// Items without `=` have no value.
+pub struct Options {
+    rest: &'static str,
+}
+
+impl Iterator for Options {
+    type Item = (&'static str, Option<&'static str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.rest.trim_start();
+        if s.is_empty() {
+            self.rest = s;
+            return None;
+        }
+        let mut in_quote = false;
+        let end = s
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quote = !in_quote;
+                }
+                c.is_whitespace() && !in_quote
+            })
+            .map_or(s.len(), |(i, _)| i);
+        let (item, rest) = s.split_at(end);
+        self.rest = rest;
+        Some(match item.split_once('=') {
+            Some((key, value)) => (key, Some(value.trim_matches('"'))),
+            None => (item, None),
+        })
+    }
+}
+
+/// Returns an iterator over all items of the command line.
+pub fn options() -> Options {
+    Options { rest: cmdline() }
+}
+
+/// A type that can be parsed from the value of a command line option.
+pub trait FromOption: Sized {
+    /// Parses the value. `value` is [`None`] if the key is given without
+    /// `=`.
+    fn from_option(value: Option<&'static str>) -> Option<Self>;
+}
+
+impl FromOption for bool {
+    fn from_option(value: Option<&'static str>) -> Option<Self> {
+        match value {
+            None | Some("1" | "y" | "yes" | "on" | "true") => Some(true),
+            Some("0" | "n" | "no" | "off" | "false") => Some(false),
+            Some(_) => None,
+        }
+    }
+}
+
+impl FromOption for &'static str {
+    fn from_option(value: Option<&'static str>) -> Option<Self> {
+        value
+    }
+}
+
+macro_rules! impl_from_option_for_int {
+    ($($t:ty),*) => {$(
+        impl FromOption for $t {
+            fn from_option(value: Option<&'static str>) -> Option<Self> {
+                let s = value?;
+                let (s, neg) = match s.strip_prefix('-') {
+                    Some(s) => (s, true),
+                    None => (s, false),
+                };
+                let (digits, radix) = if let Some(s) = s.strip_prefix("0x") {
+                    (s, 16)
+                } else if let Some(s) = s.strip_prefix("0o") {
+                    (s, 8)
+                } else if let Some(s) = s.strip_prefix("0b") {
+                    (s, 2)
+                } else {
+                    (s, 10)
+                };
+                let v = <$t>::from_str_radix(digits, radix).ok()?;
+                if neg { <$t>::checked_neg(v) } else { Some(v) }
+            }
+        }
+    )*};
+}
+
+impl_from_option_for_int!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// Returns the value of the option `key` parsed as `T`.
+///
+/// If the key appears more than once, the last one wins. Returns [`None`] if
+/// the key is absent or its value can not be parsed.
+///
+/// # Examples
+///
+/// ```ignore
+/// let level = axconfig::option::<&str>("log_level");
+/// let smp = axconfig::option::<bool>("smp").unwrap_or(true);
+/// ```
+pub fn option<T: FromOption>(key: &str) -> Option<T> {
+    let (_, value) = options().filter(|(k, _)| *k == key).last()?;
+    T::from_option(value)
+}
+
+/// Returns the value of the option `key` parsed as `T`, or `default` if it
+/// is absent or invalid.
+pub fn option_or<T: FromOption>(key: &str, default: T) -> T {
+    option(key).unwrap_or(default)
+}
+
+/// Information about a registered option.
+#[derive(Debug, Clone, Copy)]
+pub struct OptionInfo {
+    /// The key of the option.
+    pub name: &'static str,
+    /// A short description of the option.
+    pub help: &'static str,
+}
+
+struct Registry(UnsafeCell<[OptionInfo; MAX_REGISTERED_OPTIONS]>);
+
+unsafe impl Sync for Registry {}
+
+static REGISTRY: Registry = Registry(UnsafeCell::new(
+    [OptionInfo { name: "", help: "" }; MAX_REGISTERED_OPTIONS],
+));
+static REGISTRY_LEN: AtomicUsize = AtomicUsize::new(0);
+static REGISTRY_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Registers an option that some subsystem understands.
+///
+/// Returns `false` if the registry is full.
+pub fn register_option(name: &'static str, help: &'static str) -> bool {
+    while REGISTRY_LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    let len = REGISTRY_LEN.load(Ordering::Relaxed);
+    let ok = len < MAX_REGISTERED_OPTIONS;
+    if ok {
+        // SAFETY: slots beyond `REGISTRY_LEN` are only written with the lock
+        // held, and are not visible to readers until `REGISTRY_LEN` is bumped.
+        unsafe { (*REGISTRY.0.get())[len] = OptionInfo { name, help } };
+        REGISTRY_LEN.store(len + 1, Ordering::Release);
+    }
+    REGISTRY_LOCK.store(false, Ordering::Release);
+    ok
+}
+
+/// Returns all registered options.
+pub fn registered_options() -> &'static [OptionInfo] {
+    let len = REGISTRY_LEN.load(Ordering::Acquire);
+    // SAFETY: the first `len` slots are never modified once published.
+    unsafe { &(*REGISTRY.0.get())[..len] }
+}
+
+/// Returns an iterator over the command line keys that no subsystem has
+/// registered.
+pub fn unknown_options() -> impl Iterator<Item = &'static str> {
+    options()
+        .map(|(key, _)| key)
+        .filter(|key| !registered_options().iter().any(|o| o.name == *key))
+}
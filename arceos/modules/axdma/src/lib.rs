@@ -5,6 +5,7 @@
 extern crate alloc;
 
 mod dma;
+mod pool;
 
 use core::{alloc::Layout, ptr::NonNull};
 
@@ -13,6 +14,8 @@ use memory_addr::PhysAddr;
 
 use self::dma::ALLOCATOR;
 
+pub use self::pool::{DmaBuffer, DmaBufferPool, DmaDirection, DmaMapping};
+
 /// Converts a physical address to a bus address.
 ///
 /// It assumes that there is a linear mapping with the offset
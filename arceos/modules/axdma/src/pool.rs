@@ -0,0 +1,255 @@
+//! A pool of pre-allocated DMA buffers with bounce buffering.
+
+use alloc::vec::Vec;
+use core::{alloc::Layout, ptr::NonNull, sync::atomic::fence, sync::atomic::Ordering};
+
+use allocator::{AllocError, AllocResult};
+use kspin::SpinNoIrq;
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::{alloc_coherent, dealloc_coherent, BusAddr, DMAInfo};
+
+/// The direction of a DMA transfer, seen from the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The device reads from memory.
+    ToDevice,
+    /// The device writes to memory.
+    FromDevice,
+    /// The device may both read and write the buffer.
+    Bidirectional,
+}
+
+impl DmaDirection {
+    const fn to_device(self) -> bool {
+        matches!(self, Self::ToDevice | Self::Bidirectional)
+    }
+
+    const fn from_device(self) -> bool {
+        matches!(self, Self::FromDevice | Self::Bidirectional)
+    }
+}
+
+/// A pool of fixed-size, physically contiguous and device-visible buffers.
+///
+/// All buffers are carved out of one coherent allocation made when the pool
+/// is created, so drivers can hand out DMA buffers on the I/O path without
+/// going through [`alloc_coherent`] for every request.
+pub struct DmaBufferPool {
+    region: DMAInfo,
+    layout: Layout,
+    buf_size: usize,
+    num_bufs: usize,
+    free: SpinNoIrq<Vec<usize>>,
+}
+
+unsafe impl Send for DmaBufferPool {}
+unsafe impl Sync for DmaBufferPool {}
+
+impl DmaBufferPool {
+    /// Creates a pool of `num_bufs` buffers, each `buf_size` bytes long.
+    ///
+    /// Every buffer is aligned to `buf_size` rounded up to a power of two (at
+    /// most 4K), which is enough for virtqueue descriptors and most device
+    /// DMA engines.
+    pub fn new(buf_size: usize, num_bufs: usize) -> AllocResult<Self> {
+        if buf_size == 0 || num_bufs == 0 {
+            return Err(AllocError::InvalidParam);
+        }
+        let align = buf_size.next_power_of_two().min(PAGE_SIZE_4K);
+        let buf_size = memory_addr::align_up(buf_size, align);
+        let total = buf_size
+            .checked_mul(num_bufs)
+            .ok_or(AllocError::InvalidParam)?;
+        let layout = Layout::from_size_align(total, align).map_err(|_| AllocError::InvalidParam)?;
+        let region = unsafe { alloc_coherent(layout)? };
+        Ok(Self {
+            region,
+            layout,
+            buf_size,
+            num_bufs,
+            free: SpinNoIrq::new((0..num_bufs).rev().collect()),
+        })
+    }
+
+    /// Returns the size in bytes of each buffer.
+    pub const fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+
+    /// Returns the total number of buffers in the pool.
+    pub const fn capacity(&self) -> usize {
+        self.num_bufs
+    }
+
+    /// Returns the number of buffers currently available.
+    pub fn available(&self) -> usize {
+        self.free.lock().len()
+    }
+
+    /// Takes a buffer from the pool, or returns [`None`] if all buffers are
+    /// in use.
+    ///
+    /// The buffer goes back to the pool when the returned [`DmaBuffer`] is
+    /// dropped.
+    pub fn alloc(&self) -> Option<DmaBuffer<'_>> {
+        let index = self.free.lock().pop()?;
+        let offset = index * self.buf_size;
+        Some(DmaBuffer {
+            pool: self,
+            index,
+            cpu_addr: unsafe { NonNull::new_unchecked(self.region.cpu_addr.as_ptr().add(offset)) },
+            bus_addr: BusAddr::new(self.region.bus_addr.as_u64() + offset as u64),
+        })
+    }
+
+    /// Returns whether `buf` can be used by a device directly, i.e., it lies
+    /// entirely in the coherent region owned by this pool.
+    pub fn is_dma_safe(&self, buf: &[u8]) -> bool {
+        let start = self.region.cpu_addr.as_ptr() as usize;
+        let end = start + self.layout.size();
+        let addr = buf.as_ptr() as usize;
+        addr >= start && addr + buf.len() <= end
+    }
+
+    /// Makes `buf` visible to the device for a transfer in direction `dir`.
+    ///
+    /// If `buf` is already DMA-safe, it is used in place. Otherwise a buffer
+    /// is taken from the pool as a bounce buffer: data is copied into it
+    /// before the transfer if the device reads it, and copied back to `buf`
+    /// when the returned [`DmaMapping`] is dropped if the device writes it.
+    ///
+    /// Returns [`AllocError::InvalidParam`] if `buf` needs bouncing but is
+    /// larger than [`buf_size`](Self::buf_size), or [`AllocError::NoMemory`]
+    /// if no buffer is available.
+    pub fn map<'a>(&'a self, buf: &'a mut [u8], dir: DmaDirection) -> AllocResult<DmaMapping<'a>> {
+        if self.is_dma_safe(buf) {
+            let offset = buf.as_ptr() as usize - self.region.cpu_addr.as_ptr() as usize;
+            let bus_addr = BusAddr::new(self.region.bus_addr.as_u64() + offset as u64);
+            return Ok(DmaMapping {
+                buf,
+                bounce: None,
+                bus_addr,
+                dir,
+            });
+        }
+        if buf.len() > self.buf_size {
+            return Err(AllocError::InvalidParam);
+        }
+        let mut bounce = self.alloc().ok_or(AllocError::NoMemory)?;
+        if dir.to_device() {
+            bounce.as_mut_slice()[..buf.len()].copy_from_slice(buf);
+        }
+        // Make sure the copied data reaches memory before the device is
+        // told to start the transfer.
+        fence(Ordering::SeqCst);
+        let bus_addr = bounce.bus_addr();
+        Ok(DmaMapping {
+            buf,
+            bounce: Some(bounce),
+            bus_addr,
+            dir,
+        })
+    }
+
+    fn free(&self, index: usize) {
+        debug_assert!(index < self.num_bufs);
+        self.free.lock().push(index);
+    }
+}
+
+impl Drop for DmaBufferPool {
+    fn drop(&mut self) {
+        debug_assert_eq!(self.available(), self.num_bufs);
+        unsafe { dealloc_coherent(self.region, self.layout) }
+    }
+}
+
+/// A buffer taken from a [`DmaBufferPool`].
+///
+/// It is returned to the pool when dropped.
+pub struct DmaBuffer<'a> {
+    pool: &'a DmaBufferPool,
+    index: usize,
+    cpu_addr: NonNull<u8>,
+    bus_addr: BusAddr,
+}
+
+impl DmaBuffer<'_> {
+    /// Returns the address the device uses to access the buffer.
+    pub const fn bus_addr(&self) -> BusAddr {
+        self.bus_addr
+    }
+
+    /// Returns the length of the buffer in bytes.
+    pub const fn len(&self) -> usize {
+        self.pool.buf_size
+    }
+
+    /// Returns whether the buffer is empty, which is never the case.
+    pub const fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns the buffer as a byte slice.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.cpu_addr.as_ptr(), self.len()) }
+    }
+
+    /// Returns the buffer as a mutable byte slice.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.cpu_addr.as_ptr(), self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.free(self.index);
+    }
+}
+
+/// A caller's buffer made visible to a device by [`DmaBufferPool::map`].
+///
+/// Dropping it finishes the transfer, copying data back from the bounce
+/// buffer if there is one.
+pub struct DmaMapping<'a> {
+    buf: &'a mut [u8],
+    bounce: Option<DmaBuffer<'a>>,
+    bus_addr: BusAddr,
+    dir: DmaDirection,
+}
+
+impl DmaMapping<'_> {
+    /// Returns the address the device should use for the transfer.
+    pub const fn bus_addr(&self) -> BusAddr {
+        self.bus_addr
+    }
+
+    /// Returns the length of the transfer in bytes.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns whether the transfer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Returns whether a bounce buffer is used for this mapping.
+    pub const fn is_bounced(&self) -> bool {
+        self.bounce.is_some()
+    }
+}
+
+impl Drop for DmaMapping<'_> {
+    fn drop(&mut self) {
+        if let Some(bounce) = &self.bounce {
+            // Do not read the bounce buffer before the device is done.
+            fence(Ordering::SeqCst);
+            if self.dir.from_device() {
+                let len = self.buf.len();
+                self.buf.copy_from_slice(&bounce.as_slice()[..len]);
+            }
+        }
+    }
+}
@@ -1,11 +1,31 @@
 #[allow(unused_imports)]
 use crate::{prelude::*, AllDevices};
 
+/// The virtio-mmio regions not probed, left e.g. to be passed through to
+/// guests, as a comma-separated list of base addresses.
+#[cfg(feature = "virtio")]
+const RESERVED_VIRTIO_MMIO: Option<&str> = option_env!("AX_VIRTIO_MMIO_RESERVED");
+
+#[cfg(feature = "virtio")]
+fn is_reserved(base: usize) -> bool {
+    RESERVED_VIRTIO_MMIO.is_some_and(|list| {
+        list.split(',').any(|addr| {
+            let addr = addr.trim().replace('_', "");
+            let addr = addr.strip_prefix("0x").unwrap_or(&addr);
+            usize::from_str_radix(addr, 16) == Ok(base)
+        })
+    })
+}
+
 impl AllDevices {
     pub(crate) fn probe_bus_devices(&mut self) {
         // TODO: parse device tree
         #[cfg(feature = "virtio")]
         for reg in axconfig::VIRTIO_MMIO_REGIONS {
+            if is_reserved(reg.0) {
+                info!("skipped reserved virtio-mmio region at [PA:{:#x}]", reg.0);
+                continue;
+            }
             for_each_drivers!(type Driver, {
                 if let Some(dev) = Driver::probe_mmio(reg.0, reg.1) {
                     info!(
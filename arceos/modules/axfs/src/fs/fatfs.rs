@@ -87,8 +87,20 @@ impl VfsNodeOps for FileWrapper<'static> {
 
     fn truncate(&self, size: u64) -> VfsResult {
         let mut file = self.0.lock();
-        file.seek(SeekFrom::Start(size)).map_err(as_vfs_err)?; // TODO: more efficient
-        file.truncate().map_err(as_vfs_err)
+        let len = file.seek(SeekFrom::End(0)).map_err(as_vfs_err)?;
+        if size <= len {
+            file.seek(SeekFrom::Start(size)).map_err(as_vfs_err)?; // TODO: more efficient
+            return file.truncate().map_err(as_vfs_err);
+        }
+        // Seeking past the end stops at it, so the file is extended with zeros.
+        let zeros = [0; BLOCK_SIZE];
+        let mut left = size - len;
+        while left > 0 {
+            let n = left.min(BLOCK_SIZE as u64) as usize;
+            file.write_all(&zeros[..n]).map_err(as_vfs_err)?;
+            left -= n as u64;
+        }
+        Ok(())
     }
 }
 
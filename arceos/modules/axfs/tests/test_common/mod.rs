@@ -131,6 +131,37 @@ fn test_create_file_dir() -> Result<()> {
     Ok(())
 }
 
+fn test_set_len() -> Result<()> {
+    let fname = "./set-len.txt";
+    println!("test set_len {:?}:", fname);
+    fs::write(fname, "1234")?;
+
+    // extend with zeros, then shrink
+    let file = File::options().write(true).open(fname)?;
+    file.set_len(1000)?;
+    assert_eq!(file.metadata()?.len(), 1000);
+    let contents = fs::read(fname)?;
+    assert_eq!(&contents[..4], b"1234");
+    assert!(contents[4..].iter().all(|&b| b == 0));
+    file.set_len(2)?;
+    assert_eq!(file.metadata()?.len(), 2);
+    drop(file);
+    assert_eq!(fs::read_to_string(fname)?, "12");
+
+    // append after truncating on open
+    let mut file = OpenOptions::new().write(true).truncate(true).open(fname)?;
+    file.write_all(b"abc")?;
+    drop(file);
+    let mut file = OpenOptions::new().append(true).open(fname)?;
+    file.write_all(b"def")?;
+    drop(file);
+    assert_eq!(fs::read_to_string(fname)?, "abcdef");
+    fs::remove_file(fname)?;
+
+    println!("test_set_len() OK!");
+    Ok(())
+}
+
 fn test_remove_file_dir() -> Result<()> {
     // remove a file and test existence
     let fname = "//very-long-dir-name/..///new-file.txt";
@@ -257,6 +288,7 @@ pub fn test_all() {
     test_read_dir().expect("test_read_dir() failed");
     test_file_permission().expect("test_file_permission() failed");
     test_create_file_dir().expect("test_create_file_dir() failed");
+    test_set_len().expect("test_set_len() failed");
     test_remove_file_dir().expect("test_remove_file_dir() failed");
     test_devfs_ramfs().expect("test_devfs_ramfs() failed");
 }
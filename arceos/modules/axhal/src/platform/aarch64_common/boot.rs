@@ -18,6 +18,22 @@ unsafe fn switch_to_el1() {
     SPSel.write(SPSel::SP::ELx);
     SP_EL0.set(0);
     let current_el = CurrentEL.read(CurrentEL::EL);
+    // Stay at EL2 to run guests, with VHE: the accesses to the EL1 registers
+    // are redirected to the EL2 ones, so the kernel runs there unchanged.
+    #[cfg(feature = "hv")]
+    if current_el == 2 {
+        const HCR_TGE: u64 = 1 << 27;
+        const HCR_RW: u64 = 1 << 31;
+        const HCR_E2H: u64 = 1 << 34;
+        let mmfr1: u64;
+        core::arch::asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1);
+        // The `VH` field.
+        if mmfr1 >> 8 & 0xf != 0 {
+            HCR_EL2.set(HCR_E2H | HCR_TGE | HCR_RW);
+            barrier::isb(barrier::SY);
+            return;
+        }
+    }
     if current_el >= 2 {
         if current_el == 3 {
             // Set EL2 to 64bit and enable the HVC instruction.
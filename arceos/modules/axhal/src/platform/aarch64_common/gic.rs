@@ -7,8 +7,14 @@ use memory_addr::PhysAddr;
 pub const MAX_IRQ_COUNT: usize = 1024;
 
 /// The timer IRQ number.
+#[cfg(not(feature = "hv"))]
 pub const TIMER_IRQ_NUM: usize = translate_irq(14, InterruptType::PPI).unwrap();
 
+/// The timer IRQ number, the one of the EL2 physical timer, which the EL1 one
+/// is redirected to at EL2 with VHE.
+#[cfg(feature = "hv")]
+pub const TIMER_IRQ_NUM: usize = translate_irq(10, InterruptType::PPI).unwrap();
+
 /// The UART IRQ number.
 pub const UART_IRQ_NUM: usize = translate_irq(axconfig::UART_IRQ, InterruptType::SPI).unwrap();
 
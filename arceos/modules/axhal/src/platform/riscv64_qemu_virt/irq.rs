@@ -8,7 +8,6 @@ use riscv::register::sie;
 pub(super) const INTC_IRQ_BASE: usize = 1 << (usize::BITS - 1);
 
 /// Supervisor software interrupt in `scause`
-#[allow(unused)]
 pub(super) const S_SOFT: usize = INTC_IRQ_BASE + 1;
 
 /// Supervisor timer interrupt in `scause`
@@ -65,13 +64,19 @@ pub fn register_handler(scause: usize, handler: IrqHandler) -> bool {
 /// up in the IRQ handler table and calls the corresponding handler. If
 /// necessary, it also acknowledges the interrupt controller after handling.
 pub fn dispatch_irq(scause: usize) {
+    if scause == S_SOFT {
+        // IPIs only interrupt the hart, e.g. to make it exit a guest, there is no handler.
+        unsafe { core::arch::asm!("csrc sip, {0}", in(reg) 1 << 1) };
+        return;
+    }
     with_cause!(
         scause,
         @TIMER => {
             trace!("IRQ: timer");
             TIMER_HANDLER();
         },
-        @EXT => crate::irq::dispatch_irq_common(0), // TODO: get IRQ number from PLIC
+        // TODO: get IRQ number from PLIC, all external IRQs go to the handler of `S_EXT` for now
+        @EXT => crate::irq::dispatch_irq_common(S_EXT & !INTC_IRQ_BASE),
     );
 }
 
@@ -1,5 +1,7 @@
 //! Time-related operations.
 
+use core::sync::atomic::{AtomicI64, Ordering};
+
 pub use core::time::Duration;
 
 /// A measurement of the system clock.
@@ -25,6 +27,10 @@ pub const NANOS_PER_MILLIS: u64 = 1_000_000;
 /// Number of nanoseconds in a microsecond.
 pub const NANOS_PER_MICROS: u64 = 1_000;
 
+/// Correction applied on top of [`epochoffset_nanos`] to get the wall time,
+/// maintained by clock synchronization services (e.g., SNTP).
+static WALL_TIME_ADJUST_NANOS: AtomicI64 = AtomicI64::new(0);
+
 /// Returns nanoseconds elapsed since system boot.
 pub fn monotonic_time_nanos() -> u64 {
     ticks_to_nanos(current_ticks())
@@ -37,22 +43,39 @@ pub fn monotonic_time() -> TimeValue {
 
 /// Returns nanoseconds elapsed since epoch (also known as realtime).
 pub fn wall_time_nanos() -> u64 {
-    monotonic_time_nanos() + epochoffset_nanos()
+    (monotonic_time_nanos() + epochoffset_nanos())
+        .wrapping_add_signed(WALL_TIME_ADJUST_NANOS.load(Ordering::Relaxed))
 }
 
 /// Returns the time elapsed since epoch (also known as realtime) in [`TimeValue`].
+///
+/// It reports the time of day and may be stepped by [`adjust_wall_time`], so
+/// deadlines and timeouts should use [`monotonic_time`] instead.
 pub fn wall_time() -> TimeValue {
-    TimeValue::from_nanos(monotonic_time_nanos() + epochoffset_nanos())
+    TimeValue::from_nanos(wall_time_nanos())
+}
+
+/// Returns the total correction in nanoseconds applied to the wall time by
+/// [`adjust_wall_time`].
+pub fn wall_time_adjust_nanos() -> i64 {
+    WALL_TIME_ADJUST_NANOS.load(Ordering::Relaxed)
+}
+
+/// Moves the wall time forward (or backward if negative) by `delta_nanos`.
+///
+/// The monotonic time is not affected.
+pub fn adjust_wall_time(delta_nanos: i64) {
+    WALL_TIME_ADJUST_NANOS.fetch_add(delta_nanos, Ordering::Relaxed);
 }
 
 /// Busy waiting for the given duration.
 pub fn busy_wait(dur: Duration) {
-    busy_wait_until(wall_time() + dur);
+    busy_wait_until(monotonic_time() + dur);
 }
 
-/// Busy waiting until reaching the given deadline.
+/// Busy waiting until reaching the given deadline, in [`monotonic_time`].
 pub fn busy_wait_until(deadline: TimeValue) {
-    while wall_time() < deadline {
+    while monotonic_time() < deadline {
         core::hint::spin_loop();
     }
 }
@@ -127,6 +127,25 @@ pub trait LogIf {
     fn current_task_id() -> Option<u64>;
 }
 
+/// Writes the context of the current task before its log messages, e.g.
+/// `[vm0.cpu1] ` for a task of a hypervisor running a vCPU.
+pub type ContextFn = fn(&mut fmt::Formatter) -> fmt::Result;
+
+static CONTEXT_FN: kspin::SpinNoIrq<Option<ContextFn>> = kspin::SpinNoIrq::new(None);
+
+/// The context of the current task, displayed before its log messages.
+struct Context;
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let context_fn = *CONTEXT_FN.lock();
+        match context_fn {
+            Some(write_context) => write_context(f),
+            None => Ok(()),
+        }
+    }
+}
+
 struct Logger;
 
 impl Write for Logger {
@@ -168,10 +187,11 @@ impl Log for Logger {
             if #[cfg(feature = "std")] {
                 __print_impl(with_color!(
                     ColorCode::White,
-                    "[{time} {path}:{line}] {args}\n",
+                    "[{time} {path}:{line}] {ctx}{args}\n",
                     time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.6f"),
                     path = path,
                     line = line,
+                    ctx = Context,
                     args = with_color!(args_color, "{}", record.args()),
                 ));
             } else {
@@ -183,25 +203,27 @@ impl Log for Logger {
                         // show CPU ID and task ID
                         __print_impl(with_color!(
                             ColorCode::White,
-                            "[{:>3}.{:06} {cpu_id}:{tid} {path}:{line}] {args}\n",
+                            "[{:>3}.{:06} {cpu_id}:{tid} {path}:{line}] {ctx}{args}\n",
                             now.as_secs(),
                             now.subsec_micros(),
                             cpu_id = cpu_id,
                             tid = tid,
                             path = path,
                             line = line,
+                            ctx = Context,
                             args = with_color!(args_color, "{}", record.args()),
                         ));
                     } else {
                         // show CPU ID only
                         __print_impl(with_color!(
                             ColorCode::White,
-                            "[{:>3}.{:06} {cpu_id} {path}:{line}] {args}\n",
+                            "[{:>3}.{:06} {cpu_id} {path}:{line}] {ctx}{args}\n",
                             now.as_secs(),
                             now.subsec_micros(),
                             cpu_id = cpu_id,
                             path = path,
                             line = line,
+                            ctx = Context,
                             args = with_color!(args_color, "{}", record.args()),
                         ));
                     }
@@ -209,11 +231,12 @@ impl Log for Logger {
                     // neither CPU ID nor task ID is shown
                     __print_impl(with_color!(
                         ColorCode::White,
-                        "[{:>3}.{:06} {path}:{line}] {args}\n",
+                        "[{:>3}.{:06} {path}:{line}] {ctx}{args}\n",
                         now.as_secs(),
                         now.subsec_micros(),
                         path = path,
                         line = line,
+                        ctx = Context,
                         args = with_color!(args_color, "{}", record.args()),
                     ));
                 }
@@ -247,6 +270,12 @@ pub fn init() {
     log::set_max_level(LevelFilter::Warn);
 }
 
+/// Sets the function writing the context of the current task before its log
+/// messages, e.g. the VM it runs.
+pub fn set_context_fn(context_fn: ContextFn) {
+    *CONTEXT_FN.lock() = Some(context_fn);
+}
+
 /// Set the maximum log level.
 ///
 /// Unlike the features such as `log-level-error`, setting the logging level in
@@ -177,6 +177,12 @@ impl AddrSpace {
         Ok(())
     }
 
+    /// Removes all mappings in the address space, and frees the frames of
+    /// the allocated ones.
+    pub fn clear(&mut self) {
+        self.areas.clear(&mut self.pt).unwrap();
+    }
+
     /// To process data in this area with the given function.
     ///
     /// Now it supports reading and writing data in the given interval.
@@ -0,0 +1,55 @@
+//! The kernel symbol table exported to loadable modules.
+
+/// A kernel symbol that loadable modules can link against.
+#[derive(Debug)]
+pub struct KernelSymbol {
+    /// The name the module refers to the symbol with.
+    pub name: &'static str,
+    /// The address of the symbol.
+    pub addr: *const (),
+}
+
+unsafe impl Sync for KernelSymbol {}
+
+/// All symbols exported by [`export_symbol!`].
+#[linkme::distributed_slice]
+pub static KSYMTAB: [KernelSymbol];
+
+/// Exports a kernel function or static to loadable modules.
+///
+/// The symbol is exported under its own name, or under the given name if
+/// one is provided. Exported items should be `extern "C"` and
+/// `#[no_mangle]` so that modules written in any language can call them.
+///
+/// # Examples
+///
+/// ```ignore
+/// #[no_mangle]
+/// pub extern "C" fn ax_console_write(buf: *const u8, len: usize) { /* ... */ }
+///
+/// axmodule::export_symbol!(ax_console_write);
+/// ```
+#[macro_export]
+macro_rules! export_symbol {
+    ($sym:ident) => {
+        $crate::export_symbol!($sym, stringify!($sym));
+    };
+    ($sym:path, $name:expr) => {
+        const _: () = {
+            #[$crate::linkme::distributed_slice($crate::KSYMTAB)]
+            #[linkme(crate = $crate::linkme)]
+            static KSYM: $crate::KernelSymbol = $crate::KernelSymbol {
+                name: $name,
+                addr: $sym as *const (),
+            };
+        };
+    };
+}
+
+/// Looks up the address of the exported kernel symbol `name`.
+pub fn lookup_symbol(name: &str) -> Option<usize> {
+    KSYMTAB
+        .iter()
+        .find(|sym| sym.name == name)
+        .map(|sym| sym.addr as usize)
+}
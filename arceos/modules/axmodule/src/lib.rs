@@ -0,0 +1,131 @@
+//! [ArceOS](https://github.com/arceos-org/arceos) loadable kernel module
+//! support.
+//!
+//! A module is a relocatable object file (`.o`, built with `-mno-relax` on
+//! RISC-V and `-fno-common`), e.g., a driver or a device-emulation backend.
+//! Loading it places its allocated sections in kernel memory, resolves its
+//! undefined symbols against the kernel symbol table, applies relocations and
+//! calls its `module_init` function. Unloading calls `module_exit` and frees
+//! the memory.
+//!
+//! Kernel functions are made available to modules with [`export_symbol!`].
+//!
+//! # Cargo Features
+//!
+//! - `fs`: Enable [`load_module_file`] to load modules from the filesystem.
+
+#![no_std]
+
+#[macro_use]
+extern crate log;
+extern crate alloc;
+
+mod ksym;
+mod loader;
+mod reloc;
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use axerrno::{ax_err, AxResult};
+use kspin::SpinNoIrq;
+
+use self::loader::ModuleImage;
+
+#[doc(hidden)]
+pub use linkme;
+
+pub use self::ksym::{lookup_symbol, KernelSymbol, KSYMTAB};
+pub use self::loader::{MODULE_EXIT_SYMBOL, MODULE_INIT_SYMBOL};
+
+/// Information about a loaded module.
+#[derive(Debug, Clone)]
+pub struct ModuleInfo {
+    /// The name of the module.
+    pub name: String,
+    /// The start address of the module in kernel memory.
+    pub base: usize,
+    /// The size in bytes of the module in kernel memory.
+    pub size: usize,
+}
+
+static MODULES: SpinNoIrq<BTreeMap<String, ModuleImage>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Loads the relocatable object `image` as a module called `name`, and runs
+/// its init function.
+///
+/// Returns [`AlreadyExists`](axerrno::AxError::AlreadyExists) if a module
+/// with the same name is loaded, or [`BadState`](axerrno::AxError::BadState)
+/// if the init function fails.
+pub fn load_module(name: &str, image: &[u8]) -> AxResult {
+    if MODULES.lock().contains_key(name) {
+        return ax_err!(AlreadyExists, "module already loaded");
+    }
+    let module = loader::load(image)?;
+    info!(
+        "module {}: loaded at [{:#x}, {:#x})",
+        name,
+        module.base,
+        module.base + module.size()
+    );
+
+    if let Some(init) = module.init {
+        let ret = init();
+        if ret != 0 {
+            warn!("module {}: init failed with {}", name, ret);
+            module.free();
+            return ax_err!(BadState, "module init failed");
+        }
+    }
+
+    let mut modules = MODULES.lock();
+    if modules.contains_key(name) {
+        // Loaded concurrently by someone else.
+        drop(modules);
+        if let Some(exit) = module.exit {
+            exit();
+        }
+        module.free();
+        return ax_err!(AlreadyExists, "module already loaded");
+    }
+    modules.insert(name.to_string(), module);
+    Ok(())
+}
+
+/// Loads the module at `path` on the filesystem. The module is named after
+/// the file name without extension.
+#[cfg(feature = "fs")]
+pub fn load_module_file(path: &str) -> AxResult {
+    let image = axfs::api::read(path)?;
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let name = file_name.split('.').next().unwrap_or(file_name);
+    load_module(name, &image)
+}
+
+/// Runs the exit function of the module `name` and unloads it.
+pub fn unload_module(name: &str) -> AxResult {
+    let module = match MODULES.lock().remove(name) {
+        Some(module) => module,
+        None => return ax_err!(NotFound, "module not loaded"),
+    };
+    if let Some(exit) = module.exit {
+        exit();
+    }
+    module.free();
+    info!("module {}: unloaded", name);
+    Ok(())
+}
+
+/// Returns information about all loaded modules.
+pub fn list_modules() -> Vec<ModuleInfo> {
+    MODULES
+        .lock()
+        .iter()
+        .map(|(name, module)| ModuleInfo {
+            name: name.clone(),
+            base: module.base,
+            size: module.size(),
+        })
+        .collect()
+}
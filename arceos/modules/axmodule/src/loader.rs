@@ -0,0 +1,238 @@
+//! Loading relocatable objects into kernel memory.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use axalloc::global_allocator;
+use axerrno::{ax_err, ax_err_type, AxError, AxResult};
+use axhal::paging::MappingFlags;
+use elf::abi::{
+    ET_REL, SHF_ALLOC, SHN_ABS, SHN_COMMON, SHN_UNDEF, SHT_NOBITS, SHT_REL, SHT_RELA, STB_GLOBAL,
+};
+use elf::endian::AnyEndian;
+use elf::section::SectionHeader;
+use elf::ElfBytes;
+use memory_addr::{align_up, align_up_4k, va, PAGE_SIZE_4K};
+
+use crate::ksym::lookup_symbol;
+use crate::reloc::{self, RelocState, EM_CURRENT};
+
+/// The function a module must export to be initialized. It returns `0` on
+/// success.
+pub const MODULE_INIT_SYMBOL: &str = "module_init";
+/// The function a module may export to be cleaned up before unloading.
+pub const MODULE_EXIT_SYMBOL: &str = "module_exit";
+
+/// The memory of a loaded module, with its entry points resolved.
+pub(crate) struct ModuleImage {
+    pub base: usize,
+    pub num_pages: usize,
+    pub init: Option<extern "C" fn() -> i32>,
+    pub exit: Option<extern "C" fn()>,
+}
+
+impl ModuleImage {
+    pub fn size(&self) -> usize {
+        self.num_pages * PAGE_SIZE_4K
+    }
+
+    /// Gives the memory back to the kernel page allocator.
+    pub fn free(self) {
+        let _ = axmm::kernel_aspace().lock().protect(
+            va!(self.base),
+            self.size(),
+            MappingFlags::READ | MappingFlags::WRITE,
+        );
+        global_allocator().dealloc_pages(self.base, self.num_pages);
+    }
+}
+
+fn parse_err(e: elf::ParseError) -> AxError {
+    warn!("failed to parse module: {:?}", e);
+    AxError::InvalidData
+}
+
+fn flush_icache() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!("fence.i")
+    };
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::arch::asm!("dsb ish", "ic iallu", "dsb ish", "isb")
+    };
+}
+
+/// Lays out, relocates and links the relocatable object `image`.
+pub(crate) fn load(image: &[u8]) -> AxResult<ModuleImage> {
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(image).map_err(parse_err)?;
+    if elf.ehdr.e_type != ET_REL {
+        return ax_err!(InvalidData, "module is not a relocatable object");
+    }
+    if elf.ehdr.e_machine != EM_CURRENT {
+        return ax_err!(InvalidData, "module is built for another architecture");
+    }
+    let shdrs: Vec<SectionHeader> = elf
+        .section_headers()
+        .ok_or_else(|| ax_err_type!(InvalidData, "module has no sections"))?
+        .iter()
+        .collect();
+
+    // Place all allocated sections in one contiguous region.
+    let mut offsets = vec![None; shdrs.len()];
+    let mut total = 0;
+    for (i, shdr) in shdrs.iter().enumerate() {
+        if shdr.sh_flags & SHF_ALLOC as u64 == 0 || shdr.sh_size == 0 {
+            continue;
+        }
+        let off = align_up(total, (shdr.sh_addralign as usize).max(1));
+        offsets[i] = Some(off);
+        total = off + shdr.sh_size as usize;
+    }
+    if total == 0 {
+        return ax_err!(InvalidData, "module has no allocated sections");
+    }
+
+    let num_pages = align_up_4k(total) / PAGE_SIZE_4K;
+    let base = global_allocator()
+        .alloc_pages(num_pages, PAGE_SIZE_4K)
+        .map_err(|_| AxError::NoMemory)?;
+    let module = ModuleImage {
+        base,
+        num_pages,
+        init: None,
+        exit: None,
+    };
+    match link(&elf, &shdrs, &offsets, module) {
+        Ok(module) => Ok(module),
+        Err((module, e)) => {
+            module.free();
+            Err(e)
+        }
+    }
+}
+
+fn link(
+    elf: &ElfBytes<AnyEndian>,
+    shdrs: &[SectionHeader],
+    offsets: &[Option<usize>],
+    mut module: ModuleImage,
+) -> Result<ModuleImage, (ModuleImage, AxError)> {
+    macro_rules! tri {
+        ($e:expr) => {
+            match $e {
+                Ok(v) => v,
+                Err(e) => return Err((module, e)),
+            }
+        };
+    }
+
+    let base = module.base;
+    let mem = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, module.size()) };
+    mem.fill(0);
+    for (shdr, off) in shdrs.iter().zip(offsets) {
+        if let Some(off) = off {
+            if shdr.sh_type != SHT_NOBITS {
+                let (data, _) = tri!(elf.section_data(shdr).map_err(parse_err));
+                mem[*off..*off + data.len()].copy_from_slice(data);
+            }
+        }
+    }
+
+    // Resolve all symbols to absolute addresses.
+    let (symtab, strtab) = match tri!(elf.symbol_table().map_err(parse_err)) {
+        Some(tab) => tab,
+        None => {
+            return Err((
+                module,
+                ax_err_type!(InvalidData, "module has no symbol table"),
+            ))
+        }
+    };
+    let mut values = Vec::with_capacity(symtab.len());
+    for sym in symtab.iter() {
+        let name = tri!(strtab.get(sym.st_name as usize).map_err(parse_err));
+        let value = match sym.st_shndx {
+            SHN_UNDEF if name.is_empty() => 0,
+            SHN_UNDEF => match lookup_symbol(name) {
+                Some(addr) => addr,
+                None => {
+                    error!("module references unknown symbol {:?}", name);
+                    return Err((module, AxError::NotFound));
+                }
+            },
+            SHN_ABS => sym.st_value as usize,
+            SHN_COMMON => {
+                error!(
+                    "common symbol {:?} is not supported, build with -fno-common",
+                    name
+                );
+                return Err((module, AxError::Unsupported));
+            }
+            shndx => match offsets.get(shndx as usize).copied().flatten() {
+                Some(off) => base + off + sym.st_value as usize,
+                None => 0,
+            },
+        };
+        if sym.st_bind() == STB_GLOBAL && !sym.is_undefined() {
+            let entry = value as *const ();
+            match name {
+                MODULE_INIT_SYMBOL => module.init = Some(unsafe { core::mem::transmute(entry) }),
+                MODULE_EXIT_SYMBOL => module.exit = Some(unsafe { core::mem::transmute(entry) }),
+                _ => {}
+            }
+        }
+        values.push(value);
+    }
+
+    // Apply relocations to allocated sections.
+    for shdr in shdrs {
+        if shdr.sh_type == SHT_REL {
+            return Err((
+                module,
+                ax_err_type!(Unsupported, "REL relocations are not supported"),
+            ));
+        }
+        if shdr.sh_type != SHT_RELA {
+            continue;
+        }
+        let Some(target) = offsets.get(shdr.sh_info as usize).copied().flatten() else {
+            continue;
+        };
+        let relas: Vec<_> = tri!(elf.section_data_as_relas(shdr).map_err(parse_err)).collect();
+        let mut state = RelocState::default();
+        let resolve = |r_sym: u32| values.get(r_sym as usize).copied().unwrap_or(0);
+        for rela in &relas {
+            let place = base + target + rela.r_offset as usize;
+            reloc::prepare(
+                &mut state,
+                rela.r_type,
+                place,
+                resolve(rela.r_sym),
+                rela.r_addend,
+            );
+        }
+        for rela in &relas {
+            let place = base + target + rela.r_offset as usize;
+            tri!(unsafe {
+                reloc::apply(
+                    &state,
+                    rela.r_type,
+                    place,
+                    resolve(rela.r_sym),
+                    rela.r_addend,
+                )
+            });
+        }
+    }
+
+    // Loaded code must be executable. The region stays writable since
+    // sections are not page-aligned.
+    tri!(axmm::kernel_aspace().lock().protect(
+        va!(base),
+        module.size(),
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+    ));
+    flush_icache();
+    Ok(module)
+}
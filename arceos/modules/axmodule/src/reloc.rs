@@ -0,0 +1,296 @@
+//! Architecture-specific relocation processing.
+
+use alloc::collections::BTreeMap;
+
+use axerrno::{ax_err, AxResult};
+
+/// State shared by all relocations of one relocation section.
+#[derive(Default)]
+pub(crate) struct RelocState {
+    /// Values of `R_RISCV_PCREL_HI20` relocations by the address of their
+    /// `auipc`, needed by the paired `R_RISCV_PCREL_LO12_*` relocations.
+    #[allow(dead_code)]
+    pub pcrel_hi: BTreeMap<usize, i64>,
+}
+
+unsafe fn read32(place: usize) -> u32 {
+    (place as *const u32).read_unaligned()
+}
+
+unsafe fn write32(place: usize, val: u32) {
+    (place as *mut u32).write_unaligned(val)
+}
+
+#[allow(dead_code)]
+unsafe fn read16(place: usize) -> u16 {
+    (place as *const u16).read_unaligned()
+}
+
+#[allow(dead_code)]
+unsafe fn write16(place: usize, val: u16) {
+    (place as *mut u16).write_unaligned(val)
+}
+
+unsafe fn write64(place: usize, val: u64) {
+    (place as *mut u64).write_unaligned(val)
+}
+
+#[allow(dead_code)]
+fn check_range(value: i64, bits: u32, r_type: u32) -> AxResult {
+    let limit = 1i64 << (bits - 1);
+    if value < -limit || value >= limit {
+        error!("relocation {} out of range: {:#x}", r_type, value);
+        return ax_err!(InvalidData, "relocation out of range");
+    }
+    Ok(())
+}
+
+/// The ELF machine type of modules loadable on this architecture.
+#[cfg(target_arch = "riscv64")]
+pub(crate) const EM_CURRENT: u16 = elf::abi::EM_RISCV;
+#[cfg(target_arch = "x86_64")]
+pub(crate) const EM_CURRENT: u16 = elf::abi::EM_X86_64;
+#[cfg(target_arch = "aarch64")]
+pub(crate) const EM_CURRENT: u16 = elf::abi::EM_AARCH64;
+
+/// Records what the first pass over a relocation section must remember.
+#[cfg(target_arch = "riscv64")]
+pub(crate) fn prepare(state: &mut RelocState, r_type: u32, place: usize, s: usize, a: i64) {
+    if r_type == elf::abi::R_RISCV_PCREL_HI20 {
+        let offset = (s as i64).wrapping_add(a).wrapping_sub(place as i64);
+        state.pcrel_hi.insert(place, offset);
+    }
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+pub(crate) fn prepare(_state: &mut RelocState, _r_type: u32, _place: usize, _s: usize, _a: i64) {}
+
+/// Applies one relocation of type `r_type` at address `place`, where `s` is
+/// the symbol value and `a` is the addend.
+#[cfg(target_arch = "riscv64")]
+pub(crate) unsafe fn apply(
+    state: &RelocState,
+    r_type: u32,
+    place: usize,
+    s: usize,
+    a: i64,
+) -> AxResult {
+    use elf::abi::*;
+
+    let value = (s as i64).wrapping_add(a);
+    let pcrel = value.wrapping_sub(place as i64);
+    let hi20 = |off: i64| ((off + 0x800) as u32) & 0xffff_f000;
+    let lo12 = |off: i64| (off - (((off + 0x800) >> 12) << 12)) as u32;
+
+    match r_type {
+        R_RISCV_NONE | R_RISCV_RELAX | R_RISCV_ALIGN => {}
+        R_RISCV_32 => write32(place, value as u32),
+        R_RISCV_64 => write64(place, value as u64),
+        R_RISCV_32_PCREL => {
+            check_range(pcrel, 32, r_type)?;
+            write32(place, pcrel as u32);
+        }
+        R_RISCV_BRANCH => {
+            check_range(pcrel, 13, r_type)?;
+            let off = pcrel as u32;
+            let insn = read32(place) & 0x01ff_f07f;
+            write32(
+                place,
+                insn | ((off & 0x1000) << 19)
+                    | ((off & 0x7e0) << 20)
+                    | ((off & 0x1e) << 7)
+                    | ((off & 0x800) >> 4),
+            );
+        }
+        R_RISCV_JAL => {
+            check_range(pcrel, 21, r_type)?;
+            let off = pcrel as u32;
+            let insn = read32(place) & 0xfff;
+            write32(
+                place,
+                insn | ((off & 0x10_0000) << 11)
+                    | ((off & 0x7fe) << 20)
+                    | ((off & 0x800) << 9)
+                    | (off & 0xf_f000),
+            );
+        }
+        R_RISCV_CALL | R_RISCV_CALL_PLT => {
+            check_range(pcrel, 32, r_type)?;
+            let auipc = read32(place) & 0xfff;
+            let jalr = read32(place + 4) & 0x000f_ffff;
+            write32(place, auipc | hi20(pcrel));
+            write32(place + 4, jalr | (lo12(pcrel) << 20));
+        }
+        R_RISCV_PCREL_HI20 => {
+            check_range(pcrel, 32, r_type)?;
+            write32(place, (read32(place) & 0xfff) | hi20(pcrel));
+        }
+        R_RISCV_PCREL_LO12_I | R_RISCV_PCREL_LO12_S => {
+            // The symbol is the `auipc` carrying the high part.
+            let Some(&off) = state.pcrel_hi.get(&s) else {
+                error!("no PCREL_HI20 relocation found at {:#x}", s);
+                return ax_err!(InvalidData, "dangling PCREL_LO12 relocation");
+            };
+            let lo = lo12(off);
+            let insn = read32(place);
+            if r_type == R_RISCV_PCREL_LO12_I {
+                write32(place, (insn & 0x000f_ffff) | (lo << 20));
+            } else {
+                write32(
+                    place,
+                    (insn & 0x01ff_f07f) | ((lo & 0xfe0) << 20) | ((lo & 0x1f) << 7),
+                );
+            }
+        }
+        R_RISCV_HI20 => {
+            check_range(value, 32, r_type)?;
+            write32(place, (read32(place) & 0xfff) | hi20(value));
+        }
+        R_RISCV_LO12_I => {
+            let insn = read32(place) & 0x000f_ffff;
+            write32(place, insn | (lo12(value) << 20));
+        }
+        R_RISCV_LO12_S => {
+            let lo = lo12(value);
+            let insn = read32(place) & 0x01ff_f07f;
+            write32(place, insn | ((lo & 0xfe0) << 20) | ((lo & 0x1f) << 7));
+        }
+        R_RISCV_RVC_BRANCH => {
+            check_range(pcrel, 9, r_type)?;
+            let off = pcrel as u16;
+            let insn = read16(place) & 0xe383;
+            write16(
+                place,
+                insn | ((off & 0x100) << 4)
+                    | ((off & 0x18) << 7)
+                    | ((off & 0xc0) >> 1)
+                    | ((off & 0x6) << 2)
+                    | ((off & 0x20) >> 3),
+            );
+        }
+        R_RISCV_RVC_JUMP => {
+            check_range(pcrel, 12, r_type)?;
+            let off = pcrel as u16;
+            let insn = read16(place) & 0xe003;
+            write16(
+                place,
+                insn | ((off & 0x800) << 1)
+                    | ((off & 0x10) << 7)
+                    | ((off & 0x300) << 1)
+                    | ((off & 0x400) >> 2)
+                    | ((off & 0x40) << 1)
+                    | ((off & 0x80) >> 1)
+                    | ((off & 0xe) << 2)
+                    | ((off & 0x20) >> 3),
+            );
+        }
+        R_RISCV_ADD32 => write32(place, read32(place).wrapping_add(value as u32)),
+        R_RISCV_SUB32 => write32(place, read32(place).wrapping_sub(value as u32)),
+        R_RISCV_ADD64 => {
+            let old = (place as *const u64).read_unaligned();
+            write64(place, old.wrapping_add(value as u64));
+        }
+        R_RISCV_SUB64 => {
+            let old = (place as *const u64).read_unaligned();
+            write64(place, old.wrapping_sub(value as u64));
+        }
+        _ => {
+            error!("unsupported relocation type {}", r_type);
+            return ax_err!(Unsupported, "unsupported relocation type");
+        }
+    }
+    Ok(())
+}
+
+/// Applies one relocation of type `r_type` at address `place`, where `s` is
+/// the symbol value and `a` is the addend.
+#[cfg(target_arch = "x86_64")]
+pub(crate) unsafe fn apply(
+    _state: &RelocState,
+    r_type: u32,
+    place: usize,
+    s: usize,
+    a: i64,
+) -> AxResult {
+    use elf::abi::*;
+
+    let value = (s as i64).wrapping_add(a);
+    match r_type {
+        R_X86_64_NONE => {}
+        R_X86_64_64 => write64(place, value as u64),
+        R_X86_64_PC32 | R_X86_64_PLT32 => {
+            let pcrel = value.wrapping_sub(place as i64);
+            check_range(pcrel, 32, r_type)?;
+            write32(place, pcrel as u32);
+        }
+        R_X86_64_32 => {
+            if value as u64 > u32::MAX as u64 {
+                return ax_err!(InvalidData, "relocation out of range");
+            }
+            write32(place, value as u32);
+        }
+        R_X86_64_32S => {
+            check_range(value, 32, r_type)?;
+            write32(place, value as u32);
+        }
+        _ => {
+            error!("unsupported relocation type {}", r_type);
+            return ax_err!(Unsupported, "unsupported relocation type");
+        }
+    }
+    Ok(())
+}
+
+/// Applies one relocation of type `r_type` at address `place`, where `s` is
+/// the symbol value and `a` is the addend.
+#[cfg(target_arch = "aarch64")]
+pub(crate) unsafe fn apply(
+    _state: &RelocState,
+    r_type: u32,
+    place: usize,
+    s: usize,
+    a: i64,
+) -> AxResult {
+    use elf::abi::*;
+
+    let value = (s as i64).wrapping_add(a);
+    let pcrel = value.wrapping_sub(place as i64);
+    // Patches the 12-bit immediate of an `add`/`ldr`/`str`, scaled by the
+    // access size.
+    let set_lo12 = |shift: u32| {
+        let imm = ((value as u32) & 0xfff) >> shift;
+        write32(place, (read32(place) & !(0xfff << 10)) | (imm << 10));
+    };
+    match r_type {
+        R_AARCH64_NONE => {}
+        R_AARCH64_ABS64 => write64(place, value as u64),
+        R_AARCH64_PREL64 => write64(place, pcrel as u64),
+        R_AARCH64_PREL32 => {
+            check_range(pcrel, 32, r_type)?;
+            write32(place, pcrel as u32);
+        }
+        R_AARCH64_CALL26 | R_AARCH64_JUMP26 => {
+            check_range(pcrel, 28, r_type)?;
+            let imm = ((pcrel >> 2) as u32) & 0x03ff_ffff;
+            write32(place, (read32(place) & 0xfc00_0000) | imm);
+        }
+        R_AARCH64_ADR_PREL_PG_HI21 => {
+            let pages = (value & !0xfff) - (place as i64 & !0xfff);
+            check_range(pages, 33, r_type)?;
+            let imm = (pages >> 12) as u32;
+            let immlo = (imm & 0x3) << 29;
+            let immhi = ((imm >> 2) & 0x7ffff) << 5;
+            write32(place, (read32(place) & 0x9f00_001f) | immlo | immhi);
+        }
+        R_AARCH64_ADD_ABS_LO12_NC | R_AARCH64_LDST8_ABS_LO12_NC => set_lo12(0),
+        R_AARCH64_LDST16_ABS_LO12_NC => set_lo12(1),
+        R_AARCH64_LDST32_ABS_LO12_NC => set_lo12(2),
+        R_AARCH64_LDST64_ABS_LO12_NC => set_lo12(3),
+        R_AARCH64_LDST128_ABS_LO12_NC => set_lo12(4),
+        _ => {
+            error!("unsupported relocation type {}", r_type);
+            return ax_err!(Unsupported, "unsupported relocation type");
+        }
+    }
+    Ok(())
+}
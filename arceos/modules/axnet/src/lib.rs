@@ -9,11 +9,13 @@
 //! - [`TcpSocket`]: A TCP socket that provides POSIX-like APIs.
 //! - [`UdpSocket`]: A UDP socket that provides POSIX-like APIs.
 //! - [`dns_query`]: Function for DNS query.
+//! - mod [`sntp`]: An SNTP client to synchronize the wall time.
 //!
 //! # Cargo Features
 //!
 //! - `smoltcp`: Use [smoltcp] as the underlying network stack. This is enabled
 //!   by default.
+//! - `sntp`: Enable the SNTP client. It requires multitasking.
 //!
 //! [smoltcp]: https://github.com/smoltcp-rs/smoltcp
 
@@ -36,6 +38,9 @@ pub use self::net_impl::UdpSocket;
 pub use self::net_impl::{bench_receive, bench_transmit};
 pub use self::net_impl::{dns_query, poll_interfaces};
 
+#[cfg(feature = "sntp")]
+pub mod sntp;
+
 use axdriver::{prelude::*, AxDeviceContainer};
 
 /// Initializes the network subsystem by NIC devices.
@@ -11,7 +11,8 @@ use core::ops::DerefMut;
 
 use axdriver::prelude::*;
 use axdriver_net::{DevError, NetBufPtr};
-use axhal::time::{wall_time_nanos, NANOS_PER_MICROS};
+use axerrno::{ax_err_type, AxResult};
+use axhal::time::{monotonic_time_nanos, NANOS_PER_MICROS};
 use axsync::Mutex;
 use lazyinit::LazyInit;
 use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
@@ -145,7 +146,7 @@ impl InterfaceWrapper {
     }
 
     fn current_time() -> Instant {
-        Instant::from_micros_const((wall_time_nanos() / NANOS_PER_MICROS) as i64)
+        Instant::from_micros_const((monotonic_time_nanos() / NANOS_PER_MICROS) as i64)
     }
 
     pub fn name(&self) -> &str {
@@ -170,6 +171,26 @@ impl InterfaceWrapper {
         };
     }
 
+    pub fn join_multicast_group(&self, addr: IpAddress) -> AxResult {
+        let mut dev = self.dev.lock();
+        let mut iface = self.iface.lock();
+        let timestamp = Self::current_time();
+        iface
+            .join_multicast_group(dev.deref_mut(), addr, timestamp)
+            .map_err(|_| ax_err_type!(InvalidInput, "cannot join the multicast group"))?;
+        Ok(())
+    }
+
+    pub fn leave_multicast_group(&self, addr: IpAddress) -> AxResult {
+        let mut dev = self.dev.lock();
+        let mut iface = self.iface.lock();
+        let timestamp = Self::current_time();
+        iface
+            .leave_multicast_group(dev.deref_mut(), addr, timestamp)
+            .map_err(|_| ax_err_type!(InvalidInput, "cannot leave the multicast group"))?;
+        Ok(())
+    }
+
     pub fn poll(&self, sockets: &Mutex<SocketSet>) {
         let mut dev = self.dev.lock();
         let mut iface = self.iface.lock();
@@ -269,6 +269,26 @@ impl TcpSocket {
         Ok(())
     }
 
+    /// Closes the sending half of the connection, keeping it readable.
+    ///
+    /// A FIN is sent once the data already queued is transmitted, and
+    /// [`recv`](Self::recv) still returns what the peer sends until it closes
+    /// its half too. It is ignored if the socket is not connected.
+    pub fn shutdown_write(&self) -> AxResult {
+        self.update_state(STATE_CONNECTED, STATE_CONNECTED, || {
+            // SAFETY: `self.handle` should be initialized in a connected socket, and
+            // no other threads can read or write it.
+            let handle = unsafe { self.handle.get().read().unwrap() };
+            SOCKET_SET.with_socket_mut::<tcp::Socket, _, _>(handle, |socket| {
+                debug!("TCP socket {}: shutting down the write half", handle);
+                socket.close();
+            });
+            SOCKET_SET.poll_interfaces();
+            Ok(())
+        })
+        .unwrap_or(Ok(()))
+    }
+
     /// Receives data from the socket, stores it in the given buffer.
     pub fn recv(&self, buf: &mut [u8]) -> AxResult<usize> {
         if self.is_connecting() {
@@ -1,4 +1,4 @@
-use core::net::SocketAddr;
+use core::net::{Ipv4Addr, SocketAddr};
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use axerrno::{ax_err, ax_err_type, AxError, AxResult};
@@ -10,8 +10,13 @@ use smoltcp::iface::SocketHandle;
 use smoltcp::socket::udp::{self, BindError, SendError};
 use smoltcp::wire::{IpEndpoint, IpListenEndpoint};
 
-use super::addr::{from_core_sockaddr, into_core_sockaddr, is_unspecified, UNSPECIFIED_ENDPOINT};
-use super::{SocketSetWrapper, SOCKET_SET};
+use super::addr::{from_core_ipaddr, from_core_sockaddr, into_core_sockaddr, is_unspecified};
+use super::addr::{UNSPECIFIED_ENDPOINT, UNSPECIFIED_IP};
+use super::{SocketSetWrapper, ETH0, SOCKET_SET};
+
+/// The TTL of the datagrams sent, unless set with
+/// [`UdpSocket::set_ttl`], the default of smoltcp.
+const DEFAULT_TTL: u8 = 64;
 
 /// A UDP socket that provides POSIX-like APIs.
 pub struct UdpSocket {
@@ -19,6 +24,7 @@ pub struct UdpSocket {
     local_addr: RwLock<Option<IpEndpoint>>,
     peer_addr: RwLock<Option<IpEndpoint>>,
     nonblock: AtomicBool,
+    broadcast: AtomicBool,
 }
 
 impl UdpSocket {
@@ -32,6 +38,7 @@ impl UdpSocket {
             local_addr: RwLock::new(None),
             peer_addr: RwLock::new(None),
             nonblock: AtomicBool::new(false),
+            broadcast: AtomicBool::new(false),
         }
     }
 
@@ -69,6 +76,56 @@ impl UdpSocket {
         self.nonblock.store(nonblocking, Ordering::Release);
     }
 
+    /// Returns whether this socket may send to the broadcast address.
+    #[inline]
+    pub fn is_broadcast(&self) -> bool {
+        self.broadcast.load(Ordering::Acquire)
+    }
+
+    /// Allows or forbids this socket to send to the broadcast address
+    /// (`SO_BROADCAST`). It is forbidden by default.
+    #[inline]
+    pub fn set_broadcast(&self, broadcast: bool) {
+        self.broadcast.store(broadcast, Ordering::Release);
+    }
+
+    /// Returns the time-to-live of the datagrams sent by this socket.
+    pub fn ttl(&self) -> u32 {
+        SOCKET_SET.with_socket::<udp::Socket, _, _>(self.handle, |socket| {
+            socket.hop_limit().unwrap_or(DEFAULT_TTL) as u32
+        })
+    }
+
+    /// Sets the time-to-live of the datagrams sent by this socket, from 1 to
+    /// 255.
+    pub fn set_ttl(&self, ttl: u32) -> AxResult {
+        let ttl = u8::try_from(ttl)
+            .ok()
+            .filter(|&ttl| ttl != 0)
+            .ok_or_else(|| ax_err_type!(InvalidInput, "socket set_ttl() failed: invalid TTL"))?;
+        SOCKET_SET.with_socket_mut::<udp::Socket, _, _>(self.handle, |socket| {
+            socket.set_hop_limit(Some(ttl))
+        });
+        Ok(())
+    }
+
+    /// Joins the IPv4 multicast group `multiaddr`, so the datagrams sent to it
+    /// are received.
+    ///
+    /// Only the default interface is supported, so `interface` must be
+    /// unspecified.
+    pub fn join_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> AxResult {
+        Self::check_multicast(multiaddr, interface)?;
+        ETH0.join_multicast_group(from_core_ipaddr(multiaddr.into()))
+    }
+
+    /// Leaves the IPv4 multicast group `multiaddr`, see
+    /// [`join_multicast_v4`](Self::join_multicast_v4).
+    pub fn leave_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> AxResult {
+        Self::check_multicast(multiaddr, interface)?;
+        ETH0.leave_multicast_group(from_core_ipaddr(multiaddr.into()))
+    }
+
     /// Binds an unbound socket to the given address and port.
     ///
     /// It's must be called before [`send_to`](Self::send_to) and
@@ -210,6 +267,14 @@ impl UdpSocket {
         if self.local_addr.read().is_none() {
             return ax_err!(NotConnected, "socket send() failed");
         }
+        if remote_endpoint.addr == from_core_ipaddr(Ipv4Addr::BROADCAST.into())
+            && !self.is_broadcast()
+        {
+            return ax_err!(
+                PermissionDenied,
+                "socket send() failed: broadcast not allowed"
+            );
+        }
 
         self.block_on(|| {
             SOCKET_SET.with_socket_mut::<udp::Socket, _, _>(self.handle, |socket| {
@@ -252,6 +317,16 @@ impl UdpSocket {
         })
     }
 
+    fn check_multicast(multiaddr: Ipv4Addr, interface: Ipv4Addr) -> AxResult {
+        if !multiaddr.is_multicast() {
+            return ax_err!(InvalidInput, "not a multicast address");
+        }
+        if from_core_ipaddr(interface.into()) != UNSPECIFIED_IP {
+            return ax_err!(Unsupported, "only the default interface is supported");
+        }
+        Ok(())
+    }
+
     fn block_on<F, T>(&self, mut f: F) -> AxResult<T>
     where
         F: FnMut() -> AxResult<T>,
@@ -0,0 +1,162 @@
+//! A Simple Network Time Protocol (SNTP) client, see [RFC 4330].
+//!
+//! The client asks an NTP server for the current time and corrects the wall
+//! clock of [`axhal::time`] accordingly. Large errors (e.g., right after boot
+//! on a platform without RTC) are stepped at once, small ones are slewed
+//! gradually so that the wall time never jumps during normal operation.
+//!
+//! [RFC 4330]: https://datatracker.ietf.org/doc/html/rfc4330
+
+use core::net::{IpAddr, SocketAddr};
+use core::sync::atomic::{AtomicI64, Ordering};
+use core::time::Duration;
+
+use axerrno::{ax_err, ax_err_type, AxError, AxResult};
+use axhal::time::{adjust_wall_time, monotonic_time, wall_time_nanos, NANOS_PER_SEC};
+
+use crate::{dns_query, poll_interfaces, UdpSocket};
+
+/// The NTP server used by the client started at boot.
+///
+/// It can be overridden by the `AX_NTP_SERVER` environment variable at build
+/// time.
+pub const DEFAULT_SNTP_SERVER: &str = match option_env!("AX_NTP_SERVER") {
+    Some(server) => server,
+    None => "pool.ntp.org",
+};
+
+/// The interval between two queries of the client started at boot.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1024);
+
+const NTP_PORT: u16 = 123;
+const NTP_PACKET_SIZE: usize = 48;
+/// Seconds from 1900-01-01 (NTP era 0) to 1970-01-01 (UNIX epoch).
+const NTP_UNIX_EPOCH_DIFF: u64 = 2_208_988_800;
+/// Leap indicator 0, version 4, client mode.
+const NTP_CLIENT_HEADER: u8 = (4 << 3) | 3;
+const NTP_MODE_SERVER: u8 = 4;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// Offsets larger than this are stepped instead of slewed.
+const STEP_THRESHOLD_NANOS: i64 = 128_000_000;
+/// Maximum slew rate (500 PPM, the same as `adjtime(3)`).
+const MAX_SLEW_NANOS_PER_SEC: i64 = 500_000;
+
+/// The part of the last measured offset that has not been slewed yet.
+static SLEW_REMAINING_NANOS: AtomicI64 = AtomicI64::new(0);
+
+fn ntp_to_unix_nanos(ts: &[u8]) -> i64 {
+    let secs = u32::from_be_bytes(ts[0..4].try_into().unwrap()) as u64;
+    let frac = u32::from_be_bytes(ts[4..8].try_into().unwrap()) as u64;
+    let nanos = (frac * NANOS_PER_SEC) >> 32;
+    (secs as i64 - NTP_UNIX_EPOCH_DIFF as i64) * NANOS_PER_SEC as i64 + nanos as i64
+}
+
+fn unix_nanos_to_ntp(nanos: u64) -> [u8; 8] {
+    let secs = (nanos / NANOS_PER_SEC + NTP_UNIX_EPOCH_DIFF) as u32;
+    let frac = (((nanos % NANOS_PER_SEC) << 32) / NANOS_PER_SEC) as u32;
+    let mut ts = [0; 8];
+    ts[0..4].copy_from_slice(&secs.to_be_bytes());
+    ts[4..8].copy_from_slice(&frac.to_be_bytes());
+    ts
+}
+
+fn resolve(server: &str) -> AxResult<SocketAddr> {
+    let ip = match server.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => *dns_query(server)?
+            .first()
+            .ok_or_else(|| ax_err_type!(NotFound, "SNTP server not found"))?,
+    };
+    Ok(SocketAddr::new(ip, NTP_PORT))
+}
+
+/// Queries the NTP server at `server` and returns the offset in nanoseconds
+/// that should be added to the local wall time.
+pub fn sntp_query(server: SocketAddr) -> AxResult<i64> {
+    let socket = UdpSocket::new();
+    socket.connect(server)?;
+    socket.set_nonblocking(true);
+
+    let mut req = [0u8; NTP_PACKET_SIZE];
+    req[0] = NTP_CLIENT_HEADER;
+    let t1 = wall_time_nanos();
+    let t1_ntp = unix_nanos_to_ntp(t1);
+    req[40..48].copy_from_slice(&t1_ntp);
+    socket.send(&req)?;
+
+    let deadline = monotonic_time() + QUERY_TIMEOUT;
+    let mut resp = [0u8; NTP_PACKET_SIZE];
+    loop {
+        poll_interfaces();
+        match socket.recv(&mut resp) {
+            Ok(len) if len >= NTP_PACKET_SIZE => break,
+            Ok(_) => return ax_err!(InvalidData, "SNTP response too short"),
+            Err(AxError::WouldBlock) if monotonic_time() < deadline => axtask::yield_now(),
+            Err(AxError::WouldBlock) => return ax_err!(WouldBlock, "SNTP request timed out"),
+            Err(e) => return Err(e),
+        }
+    }
+    let t4 = wall_time_nanos();
+
+    if resp[0] & 0x7 != NTP_MODE_SERVER || resp[1] == 0 {
+        // stratum 0 is a "kiss-o'-death" packet
+        return ax_err!(InvalidData, "SNTP server refused the request");
+    }
+    if resp[24..32] != t1_ntp {
+        return ax_err!(InvalidData, "SNTP response does not match the request");
+    }
+    let t2 = ntp_to_unix_nanos(&resp[32..40]);
+    let t3 = ntp_to_unix_nanos(&resp[40..48]);
+    let (t1, t4) = (t1 as i64, t4 as i64);
+    Ok(((t2 - t1) + (t3 - t4)) / 2)
+}
+
+/// Synchronizes the wall time with the NTP server `server`, which can be a
+/// host name or an IP address.
+///
+/// If the offset is small, it is not applied at once but slewed by
+/// [`slew_wall_time`]. Returns the measured offset in nanoseconds.
+pub fn sntp_sync(server: &str) -> AxResult<i64> {
+    let offset = sntp_query(resolve(server)?)?;
+    if offset.abs() >= STEP_THRESHOLD_NANOS {
+        info!("SNTP: step wall time by {} ms", offset / 1_000_000);
+        SLEW_REMAINING_NANOS.store(0, Ordering::Relaxed);
+        adjust_wall_time(offset);
+    } else {
+        debug!("SNTP: slew wall time by {} us", offset / 1_000);
+        SLEW_REMAINING_NANOS.store(offset, Ordering::Relaxed);
+    }
+    Ok(offset)
+}
+
+/// Applies the pending slew for an elapsed period of `dt`, at most 500 PPM
+/// of it.
+pub fn slew_wall_time(dt: Duration) {
+    let max = (MAX_SLEW_NANOS_PER_SEC as u128 * dt.as_nanos() / NANOS_PER_SEC as u128) as i64;
+    let remaining = SLEW_REMAINING_NANOS.load(Ordering::Relaxed);
+    let delta = remaining.clamp(-max, max);
+    if delta != 0 {
+        SLEW_REMAINING_NANOS.fetch_sub(delta, Ordering::Relaxed);
+        adjust_wall_time(delta);
+    }
+}
+
+/// Spawns a task that synchronizes the wall time with `server` immediately
+/// and then every `poll_interval`, slewing the wall time in between.
+pub fn start_sntp_client(server: &'static str, poll_interval: Duration) {
+    const SLEW_TICK: Duration = Duration::from_secs(1);
+    axtask::spawn(move || {
+        let mut next_poll = monotonic_time();
+        loop {
+            if monotonic_time() >= next_poll {
+                if let Err(e) = sntp_sync(server) {
+                    warn!("SNTP: failed to sync with {}: {:?}", server, e);
+                }
+                next_poll = monotonic_time() + poll_interval;
+            }
+            axtask::sleep(SLEW_TICK);
+            slew_wall_time(SLEW_TICK);
+        }
+    });
+}
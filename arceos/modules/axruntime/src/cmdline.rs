@@ -0,0 +1,80 @@
+//! Extracts the kernel command line from the device tree blob.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+fn be32(blob: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(blob.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn cstr(blob: &[u8], off: usize) -> Option<&[u8]> {
+    let s = blob.get(off..)?;
+    Some(&s[..s.iter().position(|&b| b == 0)?])
+}
+
+/// Returns the `bootargs` property of the `/chosen` node of the flattened
+/// device tree `blob`.
+fn find_bootargs(blob: &[u8]) -> Option<&str> {
+    let off_struct = be32(blob, 8)? as usize;
+    let off_strings = be32(blob, 12)? as usize;
+
+    let mut off = off_struct;
+    let mut depth = 0;
+    let mut in_chosen = false;
+    loop {
+        let token = be32(blob, off)?;
+        off += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = cstr(blob, off)?;
+                off = (off + name.len() + 1 + 3) & !3;
+                depth += 1;
+                in_chosen = depth == 2 && name == b"chosen";
+            }
+            FDT_END_NODE => {
+                if in_chosen {
+                    return None;
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = be32(blob, off)? as usize;
+                let name_off = be32(blob, off + 4)? as usize;
+                let value = blob.get(off + 8..off + 8 + len)?;
+                off = (off + 8 + len + 3) & !3;
+                if in_chosen && cstr(blob, off_strings + name_off)? == b"bootargs" {
+                    let value = value.split(|&b| b == 0).next()?;
+                    return core::str::from_utf8(value).ok();
+                }
+            }
+            FDT_NOP => {}
+            _ => return None,
+        }
+    }
+}
+
+/// Reads the command line from the device tree blob at physical address
+/// `dtb` and saves it with [`axconfig::option::init_cmdline`].
+pub(crate) fn init(dtb: usize) {
+    if dtb == 0 {
+        return;
+    }
+    let header = axhal::mem::phys_to_virt(dtb.into()).as_usize() as *const u8;
+    // SAFETY: the bootloader passes a valid device tree blob, which lies in
+    // the linearly mapped physical memory.
+    let blob = unsafe {
+        let header = core::slice::from_raw_parts(header, 8);
+        if be32(header, 0) != Some(FDT_MAGIC) {
+            warn!("Invalid device tree blob at {:#x}", dtb);
+            return;
+        }
+        let total_size = be32(header, 4).unwrap() as usize;
+        core::slice::from_raw_parts(header.as_ptr(), total_size)
+    };
+    if let Some(args) = find_bootargs(blob) {
+        axconfig::option::init_cmdline(args);
+    }
+}
@@ -6,12 +6,15 @@
 //! # Cargo Features
 //!
 //! - `alloc`: Enable global memory allocator.
+//! - `early-heap`: Serve the allocations made before the global allocator is
+//!   initialized from a static early heap.
 //! - `paging`: Enable page table manipulation support.
 //! - `irq`: Enable interrupt handling support.
 //! - `multitask`: Enable multi-threading support.
 //! - `smp`: Enable SMP (symmetric multiprocessing) support.
 //! - `fs`: Enable filesystem support.
 //! - `net`: Enable networking support.
+//! - `sntp`: Synchronize the wall time with an NTP server after boot.
 //! - `display`: Enable graphics support.
 //!
 //! All the features are optional and disabled by default.
@@ -25,6 +28,8 @@ extern crate axlog;
 #[cfg(all(target_os = "none", not(test)))]
 mod lang_items;
 
+mod cmdline;
+
 #[cfg(feature = "smp")]
 mod mp;
 
@@ -102,6 +107,9 @@ fn is_init_ok() -> bool {
 /// and the secondary CPUs call [`rust_main_secondary`].
 #[cfg_attr(not(test), no_mangle)]
 pub extern "C" fn rust_main(cpu_id: usize, dtb: usize) -> ! {
+    #[cfg(feature = "early-heap")]
+    init_early_heap();
+
     ax_println!("{}", LOGO);
     ax_println!(
         "\
@@ -130,6 +138,15 @@ pub extern "C" fn rust_main(cpu_id: usize, dtb: usize) -> ! {
     info!("Logging is enabled.");
     info!("Primary CPU {} started, dtb = {:#x}.", cpu_id, dtb);
 
+    self::cmdline::init(dtb);
+    axconfig::option::register_option("log_level", "Override the build-time log level");
+    if !axconfig::option::cmdline().is_empty() {
+        info!("Kernel command line: {}", axconfig::option::cmdline());
+    }
+    if let Some(level) = axconfig::option::<&str>("log_level") {
+        axlog::set_max_level(level);
+    }
+
     info!("Found physcial memory regions:");
     for r in axhal::mem::memory_regions() {
         info!(
@@ -164,6 +181,12 @@ pub extern "C" fn rust_main(cpu_id: usize, dtb: usize) -> ! {
         #[cfg(feature = "net")]
         axnet::init_network(all_devices.net);
 
+        #[cfg(feature = "sntp")]
+        axnet::sntp::start_sntp_client(
+            axnet::sntp::DEFAULT_SNTP_SERVER,
+            axnet::sntp::DEFAULT_POLL_INTERVAL,
+        );
+
         #[cfg(feature = "display")]
         axdisplay::init_display(all_devices.display);
     }
@@ -190,6 +213,10 @@ pub extern "C" fn rust_main(cpu_id: usize, dtb: usize) -> ! {
         core::hint::spin_loop();
     }
 
+    for key in axconfig::option::unknown_options() {
+        warn!("Unknown kernel command line option: {}", key);
+    }
+
     unsafe { main() };
 
     #[cfg(feature = "multitask")]
@@ -201,6 +228,21 @@ pub extern "C" fn rust_main(cpu_id: usize, dtb: usize) -> ! {
     }
 }
 
+#[cfg(feature = "early-heap")]
+fn init_early_heap() {
+    /// The size of the early heap, in the kernel image.
+    const EARLY_HEAP_SIZE: usize = 0x10000; // 64 K
+
+    #[repr(align(4096))]
+    struct EarlyHeap([u8; EARLY_HEAP_SIZE]);
+
+    static mut EARLY_HEAP: EarlyHeap = EarlyHeap([0; EARLY_HEAP_SIZE]);
+
+    // SAFETY: only used by the allocator, which is initialized once here.
+    let start = unsafe { core::ptr::addr_of_mut!(EARLY_HEAP) } as usize;
+    axalloc::early_init(start, EARLY_HEAP_SIZE);
+}
+
 #[cfg(feature = "alloc")]
 fn init_allocator() {
     use axhal::mem::{memory_regions, phys_to_virt, MemRegionFlags};
@@ -3,6 +3,8 @@
 //! Currently supported primitives:
 //!
 //! - [`Mutex`]: A mutual exclusion primitive.
+//! - [`RwLock`]: A reader-writer lock with a selectable [`RwLockPolicy`].
+//! - [`Semaphore`]: A counting semaphore.
 //! - mod [`spin`]: spinlocks imported from the [`kspin`] crate.
 //!
 //! # Cargo Features
@@ -10,19 +12,54 @@
 //! - `multitask`: For use in the multi-threaded environments. If the feature is
 //!   not enabled, [`Mutex`] will be an alias of [`spin::SpinNoIrq`]. This
 //!   feature is enabled by default.
+//! - `irq`: Interrupts are enabled. If this feature is enabled, timed
+//!   operations such as [`Semaphore::acquire_timeout`] can be used.
 
 #![cfg_attr(not(test), no_std)]
 #![feature(doc_cfg)]
 
+#[cfg(feature = "multitask")]
+extern crate alloc;
+
 pub use kspin as spin;
 
 #[cfg(feature = "multitask")]
 mod mutex;
+#[cfg(feature = "multitask")]
+mod rwlock;
+#[cfg(feature = "multitask")]
+mod semaphore;
 
 #[cfg(feature = "multitask")]
 #[doc(cfg(feature = "multitask"))]
 pub use self::mutex::{Mutex, MutexGuard};
 
+#[cfg(feature = "multitask")]
+#[doc(cfg(feature = "multitask"))]
+pub use self::rwlock::{
+    RwLock, RwLockPolicy, RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard,
+};
+
+#[cfg(feature = "multitask")]
+#[doc(cfg(feature = "multitask"))]
+pub use self::semaphore::{Semaphore, SemaphoreGuard};
+
 #[cfg(not(feature = "multitask"))]
 #[doc(cfg(not(feature = "multitask")))]
 pub use kspin::{SpinNoIrq as Mutex, SpinNoIrqGuard as MutexGuard};
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, MutexGuard, Once};
+
+    static INIT: Once = Once::new();
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    /// Initializes the scheduler shared by the tests, which are run one at a
+    /// time.
+    pub(crate) fn init() -> MutexGuard<'static, ()> {
+        let lock = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+        INIT.call_once(axtask::init_scheduler);
+        lock
+    }
+}
@@ -201,9 +201,6 @@ impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
 mod tests {
     use crate::Mutex;
     use axtask as thread;
-    use std::sync::Once;
-
-    static INIT: Once = Once::new();
 
     fn may_interrupt() {
         // simulate interrupts
@@ -214,7 +211,7 @@ mod tests {
 
     #[test]
     fn lots_and_lots() {
-        INIT.call_once(thread::init_scheduler);
+        let _lock = crate::tests::init();
 
         const NUM_TASKS: u32 = 10;
         const NUM_ITERS: u32 = 10_000;
@@ -0,0 +1,524 @@
+//! A sleeping reader-writer lock with a selectable fairness policy.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axtask::WaitQueue;
+
+const WRITER: usize = 1;
+const UPGRADABLE: usize = 1 << 1;
+const READER: usize = 1 << 2;
+
+/// Which side of a [`RwLock`] is favored when both readers and writers are
+/// waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RwLockPolicy {
+    /// New readers are refused while any writer is waiting, so writers can
+    /// not be starved by a continuous stream of readers.
+    WriterPreferred,
+    /// Readers are admitted whenever no writer holds the lock. This gives
+    /// the best read throughput but writers may starve.
+    ReaderPreferred,
+}
+
+/// A reader-writer lock, similar to
+/// [`std::sync::RwLock`](https://doc.rust-lang.org/std/sync/struct.RwLock.html).
+///
+/// Besides the usual read and write locks, it also provides an upgradable
+/// read lock: it can coexist with plain readers, but only one upgradable
+/// reader (and no writer) may hold the lock at a time, and it can later be
+/// upgraded into a write lock without releasing it first.
+///
+/// Tasks that can not get the lock are blocked and put into the wait queue.
+/// Which kind of waiter wins is determined by the [`RwLockPolicy`].
+pub struct RwLock<T: ?Sized> {
+    wq: WaitQueue,
+    state: AtomicUsize,
+    waiting_writers: AtomicUsize,
+    policy: RwLockPolicy,
+    data: UnsafeCell<T>,
+}
+
+/// A guard that provides immutable data access.
+///
+/// When the guard falls out of scope it will release the read lock.
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    data: *const T,
+}
+
+/// A guard that provides mutable data access.
+///
+/// When the guard falls out of scope it will release the write lock.
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    data: *mut T,
+}
+
+/// A guard that provides immutable data access and can be upgraded into a
+/// [`RwLockWriteGuard`].
+///
+/// When the guard falls out of scope it will release the upgradable lock.
+pub struct RwLockUpgradableGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    data: *const T,
+}
+
+// Same unsafe impls as `std::sync::RwLock`
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Creates a new writer-preferred [`RwLock`] wrapping the supplied data.
+    #[inline(always)]
+    pub const fn new(data: T) -> Self {
+        Self::with_policy(data, RwLockPolicy::WriterPreferred)
+    }
+
+    /// Creates a new [`RwLock`] wrapping the supplied data, using the given
+    /// fairness policy.
+    #[inline(always)]
+    pub const fn with_policy(data: T, policy: RwLockPolicy) -> Self {
+        Self {
+            wq: WaitQueue::new(),
+            state: AtomicUsize::new(0),
+            waiting_writers: AtomicUsize::new(0),
+            policy,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this [`RwLock`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        let RwLock { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Returns the fairness policy of this lock.
+    #[inline(always)]
+    pub fn policy(&self) -> RwLockPolicy {
+        self.policy
+    }
+
+    /// Returns the number of readers currently holding the lock, including
+    /// the upgradable reader.
+    ///
+    /// The result may be out of date the instant it is returned, so it should
+    /// only be used as a heuristic.
+    pub fn reader_count(&self) -> usize {
+        let state = self.state.load(Ordering::Relaxed);
+        state / READER + (state & UPGRADABLE != 0) as usize
+    }
+
+    /// Returns `true` if the lock is currently held by a writer.
+    ///
+    /// The result may be out of date the instant it is returned, so it should
+    /// only be used as a heuristic.
+    #[inline(always)]
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0
+    }
+
+    fn readers_blocked(&self) -> bool {
+        self.policy == RwLockPolicy::WriterPreferred
+            && self.waiting_writers.load(Ordering::Relaxed) != 0
+    }
+
+    fn try_lock_shared(&self, extra: usize) -> bool {
+        if self.readers_blocked() {
+            return false;
+        }
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & WRITER != 0 || state & extra != 0 {
+                return false;
+            }
+            let new = if extra == 0 {
+                state + READER
+            } else {
+                state | extra
+            };
+            match self
+                .state
+                .compare_exchange_weak(state, new, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(s) => state = s,
+            }
+        }
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn try_upgrade_inner(&self) -> bool {
+        self.state
+            .compare_exchange(UPGRADABLE, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Blocks until `acquire` succeeds, registering the current task as a
+    /// waiting writer meanwhile.
+    fn wait_as_writer(&self, acquire: impl Fn() -> bool) {
+        if acquire() {
+            return;
+        }
+        self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+        self.wq.wait_until(acquire);
+        self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Locks this [`RwLock`] with shared read access, blocking the current
+    /// task until it can be acquired.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        if !self.try_lock_shared(0) {
+            self.wq.wait_until(|| self.try_lock_shared(0));
+        }
+        RwLockReadGuard {
+            lock: self,
+            data: self.data.get(),
+        }
+    }
+
+    /// Attempts to lock this [`RwLock`] with shared read access, returning a
+    /// guard if successful.
+    #[inline(always)]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        if self.try_lock_shared(0) {
+            Some(RwLockReadGuard {
+                lock: self,
+                data: self.data.get(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Locks this [`RwLock`] with upgradable read access, blocking the
+    /// current task until it can be acquired.
+    pub fn upgradable_read(&self) -> RwLockUpgradableGuard<T> {
+        if !self.try_lock_shared(UPGRADABLE) {
+            self.wq.wait_until(|| self.try_lock_shared(UPGRADABLE));
+        }
+        RwLockUpgradableGuard {
+            lock: self,
+            data: self.data.get(),
+        }
+    }
+
+    /// Attempts to lock this [`RwLock`] with upgradable read access,
+    /// returning a guard if successful.
+    #[inline(always)]
+    pub fn try_upgradable_read(&self) -> Option<RwLockUpgradableGuard<T>> {
+        if self.try_lock_shared(UPGRADABLE) {
+            Some(RwLockUpgradableGuard {
+                lock: self,
+                data: self.data.get(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Locks this [`RwLock`] with exclusive write access, blocking the
+    /// current task until it can be acquired.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.wait_as_writer(|| self.try_lock_exclusive());
+        RwLockWriteGuard {
+            lock: self,
+            data: self.data.get(),
+        }
+    }
+
+    /// Attempts to lock this [`RwLock`] with exclusive write access,
+    /// returning a guard if successful.
+    #[inline(always)]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if self.try_lock_exclusive() {
+            Some(RwLockWriteGuard {
+                lock: self,
+                data: self.data.get(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`RwLock`] mutably, no actual locking
+    /// needs to take place.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn unlock_shared(&self) {
+        let prev = self.state.fetch_sub(READER, Ordering::Release);
+        if prev / READER == 1 {
+            // The last reader is gone, a writer or an upgrader may proceed.
+            self.wq.notify_all(true);
+        }
+    }
+
+    fn unlock_upgradable(&self) {
+        self.state.fetch_and(!UPGRADABLE, Ordering::Release);
+        self.wq.notify_all(true);
+    }
+
+    fn unlock_exclusive(&self) {
+        self.state.fetch_and(!WRITER, Ordering::Release);
+        self.wq.notify_all(true);
+    }
+}
+
+impl<T: ?Sized + Default> Default for RwLock<T> {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => write!(f, "RwLock {{ data: ")
+                .and_then(|()| (*guard).fmt(f))
+                .and_then(|()| write!(f, "}}")),
+            None => write!(f, "RwLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> RwLockUpgradableGuard<'a, T> {
+    /// Upgrades into a write lock, blocking the current task until all other
+    /// readers have released the lock.
+    pub fn upgrade(self) -> RwLockWriteGuard<'a, T> {
+        let this = ManuallyDrop::new(self);
+        let lock = this.lock;
+        lock.wait_as_writer(|| lock.try_upgrade_inner());
+        RwLockWriteGuard {
+            lock,
+            data: lock.data.get(),
+        }
+    }
+
+    /// Tries to upgrade into a write lock without blocking.
+    ///
+    /// Returns the original guard on failure.
+    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'a, T>, Self> {
+        if self.lock.try_upgrade_inner() {
+            let this = ManuallyDrop::new(self);
+            Ok(RwLockWriteGuard {
+                lock: this.lock,
+                data: this.lock.data.get(),
+            })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Downgrades into a plain read lock, allowing another task to take the
+    /// upgradable lock.
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
+        let this = ManuallyDrop::new(self);
+        let lock = this.lock;
+        lock.state.fetch_add(READER, Ordering::Acquire);
+        lock.unlock_upgradable();
+        RwLockReadGuard {
+            lock,
+            data: lock.data.get(),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
+    /// Atomically downgrades into a read lock, without letting any writer in
+    /// between.
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
+        let this = ManuallyDrop::new(self);
+        let lock = this.lock;
+        lock.state.store(READER, Ordering::Release);
+        lock.wq.notify_all(true);
+        RwLockReadGuard {
+            lock,
+            data: lock.data.get(),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockUpgradableGuard<'a, T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for RwLockUpgradableGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_shared();
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockUpgradableGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_upgradable();
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_exclusive();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{RwLock, RwLockPolicy};
+    use axtask as thread;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[test]
+    fn writer_preferred() {
+        let _lock = crate::tests::init();
+
+        static L: RwLock<u32> = RwLock::new(0);
+        static SEEN: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+        let guard = L.read();
+        thread::spawn(|| *L.write() = 1);
+        while L.waiting_writers.load(Ordering::Relaxed) == 0 {
+            thread::yield_now();
+        }
+        // A new reader has to wait behind the waiting writer.
+        assert!(L.try_read().is_none());
+        thread::spawn(|| SEEN.store(*L.read() as usize, Ordering::Relaxed));
+        thread::yield_now();
+        assert_eq!(SEEN.load(Ordering::Relaxed), usize::MAX);
+
+        drop(guard);
+        while SEEN.load(Ordering::Relaxed) == usize::MAX {
+            thread::yield_now();
+        }
+        assert_eq!(SEEN.load(Ordering::Relaxed), 1);
+        println!("RwLock writer-preferred test OK");
+    }
+
+    #[test]
+    fn reader_preferred() {
+        let _lock = crate::tests::init();
+
+        static L: RwLock<u32> = RwLock::with_policy(0, RwLockPolicy::ReaderPreferred);
+        static WROTE: AtomicBool = AtomicBool::new(false);
+
+        let guard = L.read();
+        thread::spawn(|| {
+            *L.write() = 1;
+            WROTE.store(true, Ordering::Relaxed);
+        });
+        while L.waiting_writers.load(Ordering::Relaxed) == 0 {
+            thread::yield_now();
+        }
+        // Readers still get in while the writer waits.
+        assert_eq!(*L.try_read().unwrap(), 0);
+
+        drop(guard);
+        while !WROTE.load(Ordering::Relaxed) {
+            thread::yield_now();
+        }
+        assert_eq!(*L.read(), 1);
+        println!("RwLock reader-preferred test OK");
+    }
+
+    #[test]
+    fn upgrade_with_readers() {
+        let _lock = crate::tests::init();
+
+        static L: RwLock<u32> = RwLock::new(0);
+        static UPGRADED: AtomicBool = AtomicBool::new(false);
+
+        let guard = L.read();
+        thread::spawn(|| {
+            let upgradable = L.upgradable_read();
+            let upgradable = match upgradable.try_upgrade() {
+                Ok(_) => panic!("upgraded while a reader holds the lock"),
+                Err(upgradable) => upgradable,
+            };
+            let mut writer = upgradable.upgrade();
+            *writer += 1;
+            UPGRADED.store(true, Ordering::Relaxed);
+        });
+        while L.waiting_writers.load(Ordering::Relaxed) == 0 {
+            thread::yield_now();
+        }
+        // Both the reader and the upgrader hold the lock.
+        assert_eq!(L.reader_count(), 2);
+        assert!(!UPGRADED.load(Ordering::Relaxed));
+        assert!(L.try_upgradable_read().is_none());
+
+        drop(guard);
+        while !UPGRADED.load(Ordering::Relaxed) {
+            thread::yield_now();
+        }
+        assert_eq!(*L.read(), 1);
+
+        let writer = L.write();
+        let reader = writer.downgrade();
+        assert_eq!(L.reader_count(), 1);
+        assert!(L.try_write().is_none());
+        drop(reader);
+        assert!(L.try_write().is_some());
+        println!("RwLock upgrade test OK");
+    }
+}
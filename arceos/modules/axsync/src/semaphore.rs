@@ -0,0 +1,218 @@
+//! A counting semaphore built on wait queues.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use axtask::WaitQueue;
+use kspin::SpinNoIrq;
+
+/// A counting semaphore.
+///
+/// A semaphore maintains a set of permits. [`acquire`] blocks the current
+/// task until a permit is available and then takes it, [`release`] gives one
+/// back and wakes up a waiting task.
+///
+/// Permits are served in FIFO order: a released permit is handed off directly
+/// to the task that has been waiting longest, so new arrivals can not take it
+/// ahead of the waiting tasks.
+///
+/// [`acquire`]: Semaphore::acquire
+/// [`release`]: Semaphore::release
+pub struct Semaphore {
+    wq: WaitQueue,
+    state: SpinNoIrq<State>,
+}
+
+struct State {
+    /// The free permits, only non-zero when no task is waiting.
+    permits: usize,
+    /// The flags of the waiting tasks, set when a permit is handed off to
+    /// them, in FIFO order.
+    waiters: VecDeque<Arc<AtomicBool>>,
+}
+
+impl Semaphore {
+    /// Creates a new semaphore with the given number of permits.
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            wq: WaitQueue::new(),
+            state: SpinNoIrq::new(State {
+                permits,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Returns the number of permits currently available.
+    ///
+    /// The result may be out of date the instant it is returned, so it should
+    /// only be used as a heuristic.
+    #[inline(always)]
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Tries to take a permit without blocking.
+    ///
+    /// Returns `true` if a permit was acquired.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.permits > 0 {
+            state.permits -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes a free permit, or queues the current task for the next released
+    /// one, returning the flag set when it is handed off.
+    fn acquire_or_enqueue(&self) -> Option<Arc<AtomicBool>> {
+        let mut state = self.state.lock();
+        if state.permits > 0 {
+            state.permits -= 1;
+            return None;
+        }
+        let granted = Arc::new(AtomicBool::new(false));
+        state.waiters.push_back(granted.clone());
+        Some(granted)
+    }
+
+    /// Takes a permit, blocking the current task until one is available.
+    pub fn acquire(&self) {
+        if let Some(granted) = self.acquire_or_enqueue() {
+            self.wq.wait_until(|| granted.load(Ordering::Acquire));
+        }
+    }
+
+    /// Takes a permit, blocking the current task until one is available or
+    /// the given duration has elapsed.
+    ///
+    /// Returns `true` if a permit was acquired, `false` on timeout.
+    #[cfg(feature = "irq")]
+    pub fn acquire_timeout(&self, dur: core::time::Duration) -> bool {
+        let Some(granted) = self.acquire_or_enqueue() else {
+            return true;
+        };
+        if !self
+            .wq
+            .wait_timeout_until(dur, || granted.load(Ordering::Acquire))
+        {
+            return true;
+        }
+        let mut state = self.state.lock();
+        // The permit may have been handed off since the timeout.
+        if granted.load(Ordering::Acquire) {
+            return true;
+        }
+        state.waiters.retain(|w| !Arc::ptr_eq(w, &granted));
+        false
+    }
+
+    /// Takes a permit and returns a guard that releases it when dropped.
+    pub fn access(&self) -> SemaphoreGuard<'_> {
+        self.acquire();
+        SemaphoreGuard { sem: self }
+    }
+
+    /// Gives a permit back, handing it off to the longest-waiting task, if
+    /// any.
+    pub fn release(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop_front() {
+            Some(granted) => {
+                granted.store(true, Ordering::Release);
+                drop(state);
+                // The wait queue does not know which task the permit is for,
+                // the others go back to sleep.
+                self.wq.notify_all(true);
+            }
+            None => state.permits += 1,
+        }
+    }
+}
+
+/// A guard holding one permit of a [`Semaphore`].
+///
+/// The permit is released when the guard falls out of scope.
+pub struct SemaphoreGuard<'a> {
+    sem: &'a Semaphore,
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        self.sem.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Semaphore;
+    use axtask as thread;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[test]
+    fn fifo_handoff() {
+        let _lock = crate::tests::init();
+
+        const NUM_TASKS: usize = 5;
+        static SEM: Semaphore = Semaphore::new(0);
+        static ORDER: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+        for i in 0..NUM_TASKS {
+            thread::spawn(move || {
+                SEM.acquire();
+                ORDER.lock().unwrap().push(i);
+            });
+            // Let the task queue up before spawning the next one.
+            while SEM.state.lock().waiters.len() <= i {
+                thread::yield_now();
+            }
+        }
+
+        for _ in 0..NUM_TASKS {
+            SEM.release();
+            // The permit went to a waiter, not to the releasing task.
+            assert!(!SEM.try_acquire());
+        }
+        while ORDER.lock().unwrap().len() < NUM_TASKS {
+            thread::yield_now();
+        }
+        assert_eq!(*ORDER.lock().unwrap(), (0..NUM_TASKS).collect::<Vec<_>>());
+        assert_eq!(SEM.available_permits(), 0);
+        println!("Semaphore FIFO test OK");
+    }
+
+    #[test]
+    fn bounded_holders() {
+        let _lock = crate::tests::init();
+
+        const PERMITS: usize = 3;
+        const NUM_TASKS: usize = 10;
+        const NUM_ITERS: usize = 1_000;
+        static SEM: Semaphore = Semaphore::new(PERMITS);
+        static HOLDERS: AtomicUsize = AtomicUsize::new(0);
+        static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+        for _ in 0..NUM_TASKS {
+            thread::spawn(|| {
+                for _ in 0..NUM_ITERS {
+                    let _guard = SEM.access();
+                    let holders = HOLDERS.fetch_add(1, Ordering::Relaxed) + 1;
+                    assert!(holders <= PERMITS);
+                    thread::yield_now();
+                    HOLDERS.fetch_sub(1, Ordering::Relaxed);
+                }
+                FINISHED.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+
+        while FINISHED.load(Ordering::Relaxed) < NUM_TASKS {
+            thread::yield_now();
+        }
+        assert_eq!(SEM.available_permits(), PERMITS);
+        println!("Semaphore holders test OK");
+    }
+}
@@ -142,10 +142,11 @@ pub fn yield_now() {
 ///
 /// If the feature `irq` is not enabled, it uses busy-wait instead.
 pub fn sleep(dur: core::time::Duration) {
-    sleep_until(axhal::time::wall_time() + dur);
+    sleep_until(axhal::time::monotonic_time() + dur);
 }
 
-/// Current task is going to sleep, it will be woken up at the given deadline.
+/// Current task is going to sleep, it will be woken up at the given deadline,
+/// in [`monotonic_time`](axhal::time::monotonic_time).
 ///
 /// If the feature `irq` is not enabled, it uses busy-wait instead.
 pub fn sleep_until(deadline: axhal::time::TimeValue) {
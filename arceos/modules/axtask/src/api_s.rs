@@ -16,7 +16,7 @@ pub fn sleep(dur: core::time::Duration) {
 }
 
 /// For single-task situation, we just busy wait until reaching the given
-/// deadline.
+/// deadline, in [`monotonic_time`](axhal::time::monotonic_time).
 pub fn sleep_until(deadline: axhal::time::TimeValue) {
     axhal::time::busy_wait_until(deadline);
 }
@@ -0,0 +1,290 @@
+//! Bitmap page allocator.
+//!
+//! Each page of the managed range has a bit telling whether it is free. Any
+//! range of pages can be allocated with any alignment, and freed in any
+//! order. A cached index of the lowest page that may be free makes most
+//! single-page allocations take the first word it looks at.
+//!
+//! The bitmap is stored in the allocator itself, so the managed memory is
+//! never accessed.
+
+#![no_std]
+
+use alloc_stats::{AllocatorStats, MemoryStats};
+use allocator::{AllocError, AllocResult, BaseAllocator, PageAllocator};
+
+#[cfg(test)]
+mod tests;
+
+pub use alloc_stats::RegionKind;
+
+/// The default number of bitmap words, for 4 GiB of 4 KiB pages.
+pub const DEFAULT_WORDS: usize = 1 << 14;
+
+/// Max number of memory regions, including the one given to `init`.
+pub const MAX_MEMORY_REGIONS: usize = 8;
+
+const BITS: usize = u64::BITS as usize;
+
+/// A page allocator with one bit per page.
+///
+/// It manages at most `WORDS * 64` pages, starting from the first region
+/// given to `init`. Regions added later must lie in that range.
+pub struct BitmapPageAllocator<const PAGE_SIZE: usize, const WORDS: usize = DEFAULT_WORDS> {
+    base: usize,
+    /// Set bits are free pages.
+    bits: [u64; WORDS],
+    /// The `(first, last)` pages of the regions.
+    regions: [(usize, usize); MAX_MEMORY_REGIONS],
+    num_regions: usize,
+    /// No page below is free.
+    next_free: usize,
+    total_pages: usize,
+    used_pages: usize,
+}
+
+impl<const PAGE_SIZE: usize, const WORDS: usize> BitmapPageAllocator<PAGE_SIZE, WORDS> {
+    /// The max number of pages.
+    pub const CAPACITY: usize = WORDS * BITS;
+
+    /// Creates an empty [`BitmapPageAllocator`].
+    pub const fn new() -> Self {
+        Self {
+            base: 0,
+            bits: [0; WORDS],
+            regions: [(0, 0); MAX_MEMORY_REGIONS],
+            num_regions: 0,
+            next_free: Self::CAPACITY,
+            total_pages: 0,
+            used_pages: 0,
+        }
+    }
+
+    /// Returns the memory map, e.g. for a debug shell to print it.
+    pub fn iter_regions(&self) -> RegionIter<'_, PAGE_SIZE, WORDS> {
+        RegionIter {
+            alloc: self,
+            region: 0,
+            page: 0,
+        }
+    }
+
+    /// Marks the pages `[start, end)` as free or used.
+    fn set_range(&mut self, start: usize, end: usize, free: bool) {
+        let mut page = start;
+        while page < end {
+            let (word, bit) = (page / BITS, page % BITS);
+            let n = (BITS - bit).min(end - page);
+            let mask = (u64::MAX >> (BITS - n)) << bit;
+            if free {
+                self.bits[word] |= mask;
+            } else {
+                self.bits[word] &= !mask;
+            }
+            page += n;
+        }
+    }
+
+    /// Returns the first page in `[start, end)` that is used.
+    fn first_used(&self, start: usize, end: usize) -> Option<usize> {
+        let mut page = start;
+        while page < end {
+            let (word, bit) = (page / BITS, page % BITS);
+            let used = !self.bits[word] >> bit;
+            if used != 0 {
+                let pos = page + used.trailing_zeros() as usize;
+                return (pos < end).then_some(pos);
+            }
+            page += BITS - bit;
+        }
+        None
+    }
+
+    /// Returns the first free page from `start`.
+    fn first_free(&self, start: usize) -> Option<usize> {
+        let mut word = start / BITS;
+        let mut bits = self.bits.get(word)? & (u64::MAX << (start % BITS));
+        while bits == 0 {
+            word += 1;
+            bits = *self.bits.get(word)?;
+        }
+        Some(word * BITS + bits.trailing_zeros() as usize)
+    }
+
+    /// Returns the first page from `page` whose address is aligned to
+    /// `align`.
+    fn align_page(&self, page: usize, align: usize) -> Option<usize> {
+        let addr = self.base.checked_add(page * PAGE_SIZE)?;
+        let aligned = addr.checked_add(align - 1)? & !(align - 1);
+        Some((aligned - self.base) / PAGE_SIZE)
+    }
+
+    fn page_of(&self, addr: usize) -> usize {
+        (addr - self.base) / PAGE_SIZE
+    }
+}
+
+impl<const PAGE_SIZE: usize, const WORDS: usize> Default for BitmapPageAllocator<PAGE_SIZE, WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const PAGE_SIZE: usize, const WORDS: usize> BaseAllocator
+    for BitmapPageAllocator<PAGE_SIZE, WORDS>
+{
+    fn init(&mut self, start: usize, size: usize) {
+        // Reset in place, the bitmap may be too large for the stack.
+        self.bits.fill(0);
+        self.num_regions = 0;
+        self.next_free = Self::CAPACITY;
+        self.total_pages = 0;
+        self.used_pages = 0;
+        self.base = start & !(PAGE_SIZE - 1);
+        self.add_memory(start, size).unwrap();
+    }
+
+    fn add_memory(&mut self, start: usize, size: usize) -> AllocResult {
+        let end = start.checked_add(size).ok_or(AllocError::InvalidParam)? & !(PAGE_SIZE - 1);
+        let start = start.next_multiple_of(PAGE_SIZE);
+        if start < self.base || start >= end {
+            return Err(AllocError::InvalidParam);
+        }
+        let (first, last) = (self.page_of(start), self.page_of(end));
+        if last > Self::CAPACITY {
+            return Err(AllocError::InvalidParam);
+        }
+        // The pages of a region may all be used, so the bitmap cannot tell.
+        if self.regions[..self.num_regions]
+            .iter()
+            .any(|&(start, end)| first < end && start < last)
+        {
+            return Err(AllocError::MemoryOverlap);
+        }
+        if self.num_regions == MAX_MEMORY_REGIONS {
+            return Err(AllocError::NoMemory);
+        }
+        self.regions[self.num_regions] = (first, last);
+        self.num_regions += 1;
+        self.set_range(first, last, true);
+        self.next_free = self.next_free.min(first);
+        self.total_pages += last - first;
+        Ok(())
+    }
+}
+
+impl<const PAGE_SIZE: usize, const WORDS: usize> PageAllocator
+    for BitmapPageAllocator<PAGE_SIZE, WORDS>
+{
+    const PAGE_SIZE: usize = PAGE_SIZE;
+
+    fn alloc_pages(&mut self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
+        if num_pages == 0 || !align_pow2.is_power_of_two() {
+            return Err(AllocError::InvalidParam);
+        }
+        let align = align_pow2.max(PAGE_SIZE);
+        let mut page = self
+            .first_free(self.next_free)
+            .ok_or(AllocError::NoMemory)?;
+        if num_pages == 1 && align == PAGE_SIZE {
+            self.set_range(page, page + 1, false);
+            self.next_free = page + 1;
+            self.used_pages += 1;
+            return Ok(self.base + page * PAGE_SIZE);
+        }
+        self.next_free = page;
+        loop {
+            page = self.align_page(page, align).ok_or(AllocError::NoMemory)?;
+            let end = page
+                .checked_add(num_pages)
+                .filter(|&end| end <= Self::CAPACITY)
+                .ok_or(AllocError::NoMemory)?;
+            match self.first_used(page, end) {
+                Some(used) => {
+                    page = self.first_free(used).ok_or(AllocError::NoMemory)?;
+                }
+                None => {
+                    self.set_range(page, end, false);
+                    self.used_pages += num_pages;
+                    return Ok(self.base + page * PAGE_SIZE);
+                }
+            }
+        }
+    }
+
+    fn dealloc_pages(&mut self, pos: usize, num_pages: usize) {
+        let page = self.page_of(pos);
+        self.set_range(page, page + num_pages, true);
+        self.next_free = self.next_free.min(page);
+        self.used_pages -= num_pages;
+    }
+
+    fn total_pages(&self) -> usize {
+        self.total_pages
+    }
+
+    fn used_pages(&self) -> usize {
+        self.used_pages
+    }
+
+    fn available_pages(&self) -> usize {
+        self.total_pages - self.used_pages
+    }
+}
+
+impl<const PAGE_SIZE: usize, const WORDS: usize> AllocatorStats
+    for BitmapPageAllocator<PAGE_SIZE, WORDS>
+{
+    /// Each run of free pages counts as a free block.
+    fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        let mut page = self.next_free;
+        while let Some(start) = self.first_free(page) {
+            let end = self
+                .first_used(start, Self::CAPACITY)
+                .unwrap_or(Self::CAPACITY);
+            stats.add_block((end - start) * PAGE_SIZE);
+            page = end;
+        }
+        stats
+    }
+}
+
+/// The memory map of a [`BitmapPageAllocator`], returned by
+/// [`BitmapPageAllocator::iter_regions`].
+///
+/// It iterates over `(start, len, kind)` ranges, region by region, in address
+/// order within each region.
+pub struct RegionIter<'a, const PAGE_SIZE: usize, const WORDS: usize> {
+    alloc: &'a BitmapPageAllocator<PAGE_SIZE, WORDS>,
+    region: usize,
+    page: usize,
+}
+
+impl<const PAGE_SIZE: usize, const WORDS: usize> Iterator for RegionIter<'_, PAGE_SIZE, WORDS> {
+    type Item = (usize, usize, RegionKind);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let alloc = self.alloc;
+        loop {
+            let &(first, last) = alloc.regions[..alloc.num_regions].get(self.region)?;
+            let page = self.page.max(first);
+            if page >= last {
+                self.region += 1;
+                self.page = 0;
+                continue;
+            }
+            let (kind, end) = match alloc.first_used(page, last) {
+                Some(used) if used > page => (RegionKind::Free, used),
+                Some(_) => {
+                    let free = alloc.first_free(page).unwrap_or(last);
+                    (RegionKind::PagesUsed, free.min(last))
+                }
+                None => (RegionKind::Free, last),
+            };
+            self.page = end;
+            let start = alloc.base + page * PAGE_SIZE;
+            return Some((start, (end - page) * PAGE_SIZE, kind));
+        }
+    }
+}
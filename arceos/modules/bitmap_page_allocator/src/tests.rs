@@ -0,0 +1,117 @@
+extern crate std;
+
+use super::*;
+use std::boxed::Box;
+
+const PAGE_SIZE: usize = 0x1000;
+/// A fake base address, the memory is never accessed.
+const BASE: usize = 0x8000_0000;
+
+type Allocator = BitmapPageAllocator<PAGE_SIZE, 4>;
+
+fn new_allocator(num_pages: usize) -> Box<Allocator> {
+    let mut a = Box::new(Allocator::new());
+    a.init(BASE, num_pages * PAGE_SIZE);
+    a
+}
+
+#[test]
+fn test_single_pages() {
+    let mut a = new_allocator(256);
+    assert_eq!(a.total_pages(), 256);
+    for i in 0..256 {
+        assert_eq!(a.alloc_pages(1, PAGE_SIZE), Ok(BASE + i * PAGE_SIZE));
+    }
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Err(AllocError::NoMemory));
+
+    // Freed pages are found again, the lowest first.
+    a.dealloc_pages(BASE + 200 * PAGE_SIZE, 1);
+    a.dealloc_pages(BASE + 70 * PAGE_SIZE, 1);
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Ok(BASE + 70 * PAGE_SIZE));
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Ok(BASE + 200 * PAGE_SIZE));
+    assert_eq!(a.available_pages(), 0);
+}
+
+#[test]
+fn test_ranges_align() {
+    let mut a = new_allocator(256);
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Ok(BASE));
+    // Skips the used page and crosses a word boundary.
+    assert_eq!(a.alloc_pages(100, PAGE_SIZE), Ok(BASE + PAGE_SIZE));
+    assert_eq!(a.alloc_pages(4, 16 * PAGE_SIZE), Ok(BASE + 112 * PAGE_SIZE));
+    // The gap below the aligned range is still free.
+    assert_eq!(a.alloc_pages(11, PAGE_SIZE), Ok(BASE + 101 * PAGE_SIZE));
+    assert_eq!(a.used_pages(), 116);
+
+    a.dealloc_pages(BASE + PAGE_SIZE, 100);
+    assert_eq!(
+        a.alloc_pages(64, 64 * PAGE_SIZE),
+        Ok(BASE + 128 * PAGE_SIZE)
+    );
+    assert_eq!(a.alloc_pages(100, PAGE_SIZE), Ok(BASE + PAGE_SIZE));
+    assert_eq!(a.alloc_pages(65, PAGE_SIZE), Err(AllocError::NoMemory));
+    assert_eq!(a.alloc_pages(0, PAGE_SIZE), Err(AllocError::InvalidParam));
+    assert_eq!(a.alloc_pages(1, 3), Err(AllocError::InvalidParam));
+}
+
+#[test]
+fn test_add_memory() {
+    let mut a = new_allocator(16);
+    a.add_memory(BASE + 64 * PAGE_SIZE + 1, 16 * PAGE_SIZE)
+        .unwrap();
+    assert_eq!(a.total_pages(), 31);
+    assert_eq!(
+        a.add_memory(BASE + 8 * PAGE_SIZE, 16 * PAGE_SIZE),
+        Err(AllocError::MemoryOverlap)
+    );
+    assert_eq!(
+        a.add_memory(BASE + 250 * PAGE_SIZE, 16 * PAGE_SIZE),
+        Err(AllocError::InvalidParam)
+    );
+    assert_eq!(a.alloc_pages(8, PAGE_SIZE), Ok(BASE));
+    assert_eq!(a.alloc_pages(9, PAGE_SIZE), Ok(BASE + 65 * PAGE_SIZE));
+
+    let stats = a.memory_stats();
+    assert_eq!(stats.free_blocks, 2);
+    assert_eq!(stats.free_bytes, 14 * PAGE_SIZE);
+    assert_eq!(stats.largest_free_block, 8 * PAGE_SIZE);
+}
+
+#[test]
+fn test_add_memory_over_used_pages() {
+    let mut a = new_allocator(16);
+    assert_eq!(a.alloc_pages(16, PAGE_SIZE), Ok(BASE));
+    assert_eq!(
+        a.add_memory(BASE + 4 * PAGE_SIZE, 4 * PAGE_SIZE),
+        Err(AllocError::MemoryOverlap)
+    );
+    assert_eq!(
+        a.add_memory(BASE + 8 * PAGE_SIZE, 16 * PAGE_SIZE),
+        Err(AllocError::MemoryOverlap)
+    );
+    // The used pages are not handed out again.
+    assert_eq!(a.total_pages(), 16);
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Err(AllocError::NoMemory));
+}
+
+#[test]
+fn test_iter_regions() {
+    let mut a = new_allocator(16);
+    a.add_memory(BASE + 64 * PAGE_SIZE, 4 * PAGE_SIZE).unwrap();
+    a.alloc_pages(3, PAGE_SIZE).unwrap();
+    a.alloc_pages(2, 8 * PAGE_SIZE).unwrap();
+    a.dealloc_pages(BASE + PAGE_SIZE, 1);
+    let map: std::vec::Vec<_> = a.iter_regions().collect();
+    assert_eq!(
+        map,
+        [
+            (BASE, PAGE_SIZE, RegionKind::PagesUsed),
+            (BASE + PAGE_SIZE, PAGE_SIZE, RegionKind::Free),
+            (BASE + 2 * PAGE_SIZE, PAGE_SIZE, RegionKind::PagesUsed),
+            (BASE + 3 * PAGE_SIZE, 5 * PAGE_SIZE, RegionKind::Free),
+            (BASE + 8 * PAGE_SIZE, 2 * PAGE_SIZE, RegionKind::PagesUsed),
+            (BASE + 10 * PAGE_SIZE, 6 * PAGE_SIZE, RegionKind::Free),
+            (BASE + 64 * PAGE_SIZE, 4 * PAGE_SIZE, RegionKind::Free),
+        ]
+    );
+}
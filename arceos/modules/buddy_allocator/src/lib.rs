@@ -0,0 +1,312 @@
+//! Buddy page allocator.
+//!
+//! Free memory is kept as blocks of `2^order` pages, aligned to their size,
+//! in a free list per order. An allocation splits a larger block if needed,
+//! and freeing merges a block with its buddy as long as the buddy is free, so
+//! memory freed after boot becomes available for large allocations again.
+//!
+//! The free lists are linked through the free pages themselves, so the
+//! memory given to the allocator must be accessible.
+
+#![no_std]
+
+use alloc_stats::{AllocatorStats, MemoryStats};
+use allocator::{AllocError, AllocResult, BaseAllocator, PageAllocator};
+
+#[cfg(test)]
+mod tests;
+
+pub use alloc_stats::RegionKind;
+
+/// The max order of blocks, i.e. blocks have at most `2^MAX_ORDER` pages.
+pub const MAX_ORDER: usize = 20;
+
+/// Max number of memory regions, including the one given to `init`.
+pub const MAX_MEMORY_REGIONS: usize = 8;
+
+/// Marks the end of a free list.
+const NIL: usize = 0;
+
+/// A page allocator with order-based free lists.
+///
+/// `alloc_pages` takes a block of the smallest order that fits, and gives
+/// the pages past `num_pages` back at once. `dealloc_pages` can free any
+/// number of pages, which are merged with their free buddies.
+pub struct BuddyPageAllocator<const PAGE_SIZE: usize> {
+    free_lists: [usize; MAX_ORDER + 1],
+    /// The page-aligned `(start, end)` of the regions.
+    regions: [(usize, usize); MAX_MEMORY_REGIONS],
+    num_regions: usize,
+    total_pages: usize,
+    used_pages: usize,
+}
+
+impl<const PAGE_SIZE: usize> BuddyPageAllocator<PAGE_SIZE> {
+    /// Creates an empty [`BuddyPageAllocator`].
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [NIL; MAX_ORDER + 1],
+            regions: [(0, 0); MAX_MEMORY_REGIONS],
+            num_regions: 0,
+            total_pages: 0,
+            used_pages: 0,
+        }
+    }
+
+    /// Returns the number of free blocks of `order`.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut block = self.free_lists[order];
+        while block != NIL {
+            count += 1;
+            // SAFETY: blocks in the free lists are free memory.
+            block = unsafe { next_of(block) };
+        }
+        count
+    }
+
+    /// Returns the memory map, e.g. for a debug shell to print it.
+    ///
+    /// It walks the free lists for each range, so it is slow with many free
+    /// blocks.
+    pub fn iter_regions(&self) -> RegionIter<'_, PAGE_SIZE> {
+        RegionIter {
+            alloc: self,
+            region: 0,
+            pos: 0,
+        }
+    }
+
+    /// Returns the lowest free block in `[start, end)`, with its size.
+    fn next_free_block(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+        let mut next: Option<(usize, usize)> = None;
+        for order in 0..=MAX_ORDER {
+            let mut block = self.free_lists[order];
+            while block != NIL {
+                if start <= block && block < end && next.map_or(true, |(b, _)| block < b) {
+                    next = Some((block, Self::block_size(order)));
+                }
+                // SAFETY: blocks in the free lists are free memory.
+                block = unsafe { next_of(block) };
+            }
+        }
+        next
+    }
+
+    const fn block_size(order: usize) -> usize {
+        PAGE_SIZE << order
+    }
+
+    fn push(&mut self, block: usize, order: usize) {
+        // SAFETY: `block` is free memory of at least one page.
+        unsafe { set_next(block, self.free_lists[order]) };
+        self.free_lists[order] = block;
+    }
+
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        let block = self.free_lists[order];
+        if block == NIL {
+            return None;
+        }
+        // SAFETY: blocks in the free lists are free memory.
+        self.free_lists[order] = unsafe { next_of(block) };
+        Some(block)
+    }
+
+    /// Removes `block` from the free list of `order`, returns whether it was
+    /// there.
+    fn remove(&mut self, block: usize, order: usize) -> bool {
+        let mut prev = NIL;
+        let mut cur = self.free_lists[order];
+        while cur != NIL {
+            // SAFETY: blocks in the free lists are free memory.
+            let next = unsafe { next_of(cur) };
+            if cur == block {
+                if prev == NIL {
+                    self.free_lists[order] = next;
+                } else {
+                    unsafe { set_next(prev, next) };
+                }
+                return true;
+            }
+            prev = cur;
+            cur = next;
+        }
+        false
+    }
+
+    /// Frees the block of `order` at `block`, merging it with its buddies.
+    fn free_block(&mut self, mut block: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = block ^ Self::block_size(order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    /// Frees `[start, end)`, cut into the largest aligned blocks.
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let order = max_order_at(start / PAGE_SIZE, (end - start) / PAGE_SIZE);
+            self.free_block(start, order);
+            start += Self::block_size(order);
+        }
+    }
+}
+
+impl<const PAGE_SIZE: usize> Default for BuddyPageAllocator<PAGE_SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const PAGE_SIZE: usize> BaseAllocator for BuddyPageAllocator<PAGE_SIZE> {
+    fn init(&mut self, start: usize, size: usize) {
+        self.free_lists = [NIL; MAX_ORDER + 1];
+        self.num_regions = 0;
+        self.total_pages = 0;
+        self.used_pages = 0;
+        self.add_memory(start, size).unwrap();
+    }
+
+    fn add_memory(&mut self, start: usize, size: usize) -> AllocResult {
+        let end = start.checked_add(size).ok_or(AllocError::InvalidParam)? & !(PAGE_SIZE - 1);
+        // Address 0 marks the end of the free lists.
+        let start = start.max(PAGE_SIZE).next_multiple_of(PAGE_SIZE);
+        if start < end {
+            if self.num_regions == MAX_MEMORY_REGIONS {
+                return Err(AllocError::NoMemory);
+            }
+            self.regions[self.num_regions] = (start, end);
+            self.num_regions += 1;
+            self.free_range(start, end);
+            self.total_pages += (end - start) / PAGE_SIZE;
+        }
+        Ok(())
+    }
+}
+
+impl<const PAGE_SIZE: usize> PageAllocator for BuddyPageAllocator<PAGE_SIZE> {
+    const PAGE_SIZE: usize = PAGE_SIZE;
+
+    fn alloc_pages(&mut self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
+        if num_pages == 0 || !align_pow2.is_power_of_two() {
+            return Err(AllocError::InvalidParam);
+        }
+        // Blocks are aligned to their size.
+        let align_pages = align_pow2.div_ceil(PAGE_SIZE);
+        let want = num_pages.max(align_pages).next_power_of_two();
+        let order = want.trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return Err(AllocError::NoMemory);
+        }
+        let (mut block_order, block) = (order..=MAX_ORDER)
+            .find_map(|o| self.pop(o).map(|b| (o, b)))
+            .ok_or(AllocError::NoMemory)?;
+        // Split, keeping the lower half.
+        while block_order > order {
+            block_order -= 1;
+            self.push(block + Self::block_size(block_order), block_order);
+        }
+        // Give back the pages not asked for.
+        let end = block + num_pages * PAGE_SIZE;
+        self.free_range(end, block + Self::block_size(order));
+        self.used_pages += num_pages;
+        Ok(block)
+    }
+
+    fn dealloc_pages(&mut self, pos: usize, num_pages: usize) {
+        self.free_range(pos, pos + num_pages * PAGE_SIZE);
+        self.used_pages -= num_pages;
+    }
+
+    fn total_pages(&self) -> usize {
+        self.total_pages
+    }
+
+    fn used_pages(&self) -> usize {
+        self.used_pages
+    }
+
+    fn available_pages(&self) -> usize {
+        self.total_pages - self.used_pages
+    }
+}
+
+impl<const PAGE_SIZE: usize> AllocatorStats for BuddyPageAllocator<PAGE_SIZE> {
+    /// Each block in the free lists counts as a free block, even if it is
+    /// next to another one that is not its buddy.
+    fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        for order in 0..=MAX_ORDER {
+            for _ in 0..self.free_blocks(order) {
+                stats.add_block(Self::block_size(order));
+            }
+        }
+        stats
+    }
+}
+
+/// The memory map of a [`BuddyPageAllocator`], returned by
+/// [`BuddyPageAllocator::iter_regions`].
+///
+/// It iterates over `(start, len, kind)` ranges, region by region, in address
+/// order within each region.
+pub struct RegionIter<'a, const PAGE_SIZE: usize> {
+    alloc: &'a BuddyPageAllocator<PAGE_SIZE>,
+    region: usize,
+    pos: usize,
+}
+
+impl<const PAGE_SIZE: usize> Iterator for RegionIter<'_, PAGE_SIZE> {
+    type Item = (usize, usize, RegionKind);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let &(start, end) = self.alloc.regions[..self.alloc.num_regions].get(self.region)?;
+            let pos = self.pos.max(start);
+            if pos >= end {
+                self.region += 1;
+                self.pos = 0;
+                continue;
+            }
+            let (kind, run_end) = match self.alloc.next_free_block(pos, end) {
+                Some((block, size)) if block == pos => {
+                    // Merge the free blocks that follow.
+                    let mut run_end = block + size;
+                    while let Some((next, size)) = self.alloc.next_free_block(run_end, end) {
+                        if next != run_end {
+                            break;
+                        }
+                        run_end += size;
+                    }
+                    (RegionKind::Free, run_end)
+                }
+                Some((block, _)) => (RegionKind::PagesUsed, block),
+                None => (RegionKind::PagesUsed, end),
+            };
+            self.pos = run_end;
+            return Some((pos, run_end - pos, kind));
+        }
+    }
+}
+
+/// Returns the order of the largest block at page number `page`, aligned to
+/// its size and not longer than `max_pages`.
+fn max_order_at(page: usize, max_pages: usize) -> usize {
+    let align_order = page.trailing_zeros() as usize;
+    let size_order = (usize::BITS - 1 - max_pages.leading_zeros()) as usize;
+    align_order.min(size_order).min(MAX_ORDER)
+}
+
+unsafe fn next_of(block: usize) -> usize {
+    *(block as *const usize)
+}
+
+unsafe fn set_next(block: usize, next: usize) {
+    *(block as *mut usize) = next;
+}
@@ -0,0 +1,133 @@
+extern crate std;
+
+use super::*;
+use alloc_stats::test_util::TestMemory;
+use core::alloc::Layout;
+
+const PAGE_SIZE: usize = 0x1000;
+const SIZE: usize = 64 * PAGE_SIZE;
+
+/// Creates an allocator on memory aligned to its size, so that it is a single
+/// block.
+fn new_allocator() -> (TestMemory, BuddyPageAllocator<PAGE_SIZE>) {
+    let mem = TestMemory::new(SIZE, SIZE);
+    let mut a = BuddyPageAllocator::new();
+    a.init(mem.start(), SIZE);
+    (mem, a)
+}
+
+#[test]
+fn test_split_merge() {
+    let (mem, mut a) = new_allocator();
+    assert_eq!(a.total_pages(), 64);
+    assert_eq!(a.free_blocks(6), 1);
+
+    let p1 = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    assert_eq!(p1, mem.start());
+    for order in 0..6 {
+        assert_eq!(a.free_blocks(order), 1);
+    }
+    let p2 = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    assert_eq!(p2, mem.start() + PAGE_SIZE);
+    assert_eq!(a.used_pages(), 2);
+
+    a.dealloc_pages(p1, 1);
+    a.dealloc_pages(p2, 1);
+    assert_eq!(a.free_blocks(6), 1);
+    assert_eq!(a.available_pages(), 64);
+}
+
+#[test]
+fn test_odd_sizes() {
+    let (mem, mut a) = new_allocator();
+    // The 8-page block is cut, the 3 last pages are given back.
+    let p = a.alloc_pages(5, PAGE_SIZE).unwrap();
+    assert_eq!(p, mem.start());
+    assert_eq!(a.used_pages(), 5);
+    assert_eq!(a.alloc_pages(2, PAGE_SIZE), Ok(mem.start() + 6 * PAGE_SIZE));
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Ok(mem.start() + 5 * PAGE_SIZE));
+
+    // Pages can be freed in parts.
+    a.dealloc_pages(p + PAGE_SIZE, 4);
+    a.dealloc_pages(p, 1);
+    a.dealloc_pages(mem.start() + 5 * PAGE_SIZE, 3);
+    assert_eq!(a.free_blocks(6), 1);
+}
+
+#[test]
+fn test_align() {
+    let (mem, mut a) = new_allocator();
+    a.alloc_pages(1, PAGE_SIZE).unwrap();
+    let p = a.alloc_pages(1, 16 * PAGE_SIZE).unwrap();
+    assert_eq!(p, mem.start() + 16 * PAGE_SIZE);
+    assert_eq!(a.used_pages(), 2);
+    assert_eq!(a.alloc_pages(0, PAGE_SIZE), Err(AllocError::InvalidParam));
+    assert_eq!(a.alloc_pages(1, 3), Err(AllocError::InvalidParam));
+    assert_eq!(a.alloc_pages(64, PAGE_SIZE), Err(AllocError::NoMemory));
+}
+
+#[test]
+fn test_add_memory() {
+    let (mem, mut a) = new_allocator();
+    let more = TestMemory::new(SIZE, SIZE);
+    // Unaligned bounds are trimmed to pages.
+    a.add_memory(more.start() + 1, SIZE - 1).unwrap();
+    assert_eq!(a.total_pages(), 64 + 63);
+    let p = a.alloc_pages(64, PAGE_SIZE).unwrap();
+    assert_eq!(p, mem.start());
+    assert_eq!(
+        a.alloc_pages(32, PAGE_SIZE),
+        Ok(more.start() + 32 * PAGE_SIZE)
+    );
+}
+
+#[test]
+fn test_memory_stats() {
+    let (_mem, mut a) = new_allocator();
+    assert_eq!(a.memory_stats().largest_free_block, SIZE);
+    assert_eq!(a.fragmentation_percent(), 0);
+
+    let p = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    a.alloc_pages(1, 32 * PAGE_SIZE).unwrap();
+    a.dealloc_pages(p, 1);
+    // The first half is merged back, the 31 free pages of the second one
+    // are in blocks of 1 to 16 pages.
+    let stats = a.memory_stats();
+    assert_eq!(stats.free_bytes, 63 * PAGE_SIZE);
+    assert_eq!(stats.largest_free_block, 32 * PAGE_SIZE);
+    assert_eq!(stats.free_blocks, 6);
+    assert_eq!(stats.fragmentation_percent(), 31 * 100 / 63);
+    assert_eq!(a.free_block_count(), 6);
+}
+
+#[test]
+fn test_iter_regions() {
+    let (mem, mut a) = new_allocator();
+    let p = a.alloc_pages(3, PAGE_SIZE).unwrap();
+    a.alloc_pages(2, PAGE_SIZE).unwrap();
+    a.dealloc_pages(p + PAGE_SIZE, 1);
+    let map: std::vec::Vec<_> = a.iter_regions().collect();
+    assert_eq!(
+        map,
+        [
+            (mem.start(), PAGE_SIZE, RegionKind::PagesUsed),
+            (mem.start() + PAGE_SIZE, PAGE_SIZE, RegionKind::Free),
+            (
+                mem.start() + 2 * PAGE_SIZE,
+                PAGE_SIZE,
+                RegionKind::PagesUsed
+            ),
+            (mem.start() + 3 * PAGE_SIZE, PAGE_SIZE, RegionKind::Free),
+            (
+                mem.start() + 4 * PAGE_SIZE,
+                2 * PAGE_SIZE,
+                RegionKind::PagesUsed
+            ),
+            (
+                mem.start() + 6 * PAGE_SIZE,
+                SIZE - 6 * PAGE_SIZE,
+                RegionKind::Free
+            ),
+        ]
+    );
+}
@@ -0,0 +1,165 @@
+//! Deterministic allocation failures, to test out-of-memory error paths.
+
+use allocator::{AllocError, AllocResult, BaseAllocator, ByteAllocator, PageAllocator};
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+/// When allocations are made to fail.
+///
+/// An allocation fails if it is the `nth` one counted since the policy was
+/// set, or if it would make the used memory exceed `max_used` bytes. Byte
+/// and page allocations are counted together, pages by their size in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FailPolicy {
+    nth: Option<usize>,
+    max_used: Option<usize>,
+    count: usize,
+}
+
+impl FailPolicy {
+    /// A policy that never fails.
+    pub const fn never() -> Self {
+        Self {
+            nth: None,
+            max_used: None,
+            count: 0,
+        }
+    }
+
+    /// Fails the `n`th allocation, counting from 1, and only that one.
+    pub const fn fail_nth(mut self, n: usize) -> Self {
+        self.nth = Some(n);
+        self
+    }
+
+    /// Fails the allocations that would make the used memory exceed
+    /// `max_used` bytes.
+    pub const fn fail_above(mut self, max_used: usize) -> Self {
+        self.max_used = Some(max_used);
+        self
+    }
+
+    /// Returns the number of allocations counted so far.
+    pub const fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Counts an allocation of `size` bytes while `used` bytes are in use,
+    /// and returns whether it must fail.
+    pub fn should_fail(&mut self, used: usize, size: usize) -> bool {
+        self.count += 1;
+        self.nth == Some(self.count)
+            || self
+                .max_used
+                .is_some_and(|max| used.saturating_add(size) > max)
+    }
+}
+
+/// Wraps an allocator to make its allocations fail following a
+/// [`FailPolicy`]. Failed allocations return [`AllocError::NoMemory`] and
+/// do not reach the inner allocator.
+pub struct FailInjector<A> {
+    inner: A,
+    policy: FailPolicy,
+}
+
+impl<A> FailInjector<A> {
+    /// Wraps `inner` with `policy`.
+    pub const fn new(inner: A, policy: FailPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    /// Replaces the policy, restarting the count of allocations.
+    pub fn set_policy(&mut self, policy: FailPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the current policy.
+    pub const fn policy(&self) -> &FailPolicy {
+        &self.policy
+    }
+
+    /// Returns the inner allocator.
+    pub const fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Returns the inner allocator mutably.
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    /// Returns the inner allocator, dropping the policy.
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+}
+
+impl<A: BaseAllocator> BaseAllocator for FailInjector<A> {
+    fn init(&mut self, start: usize, size: usize) {
+        self.inner.init(start, size)
+    }
+
+    fn add_memory(&mut self, start: usize, size: usize) -> AllocResult {
+        self.inner.add_memory(start, size)
+    }
+}
+
+impl<A: ByteAllocator> ByteAllocator for FailInjector<A> {
+    fn alloc(&mut self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        if self
+            .policy
+            .should_fail(self.inner.used_bytes(), layout.size())
+        {
+            return Err(AllocError::NoMemory);
+        }
+        self.inner.alloc(layout)
+    }
+
+    fn dealloc(&mut self, pos: NonNull<u8>, layout: Layout) {
+        self.inner.dealloc(pos, layout)
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.inner.total_bytes()
+    }
+
+    fn used_bytes(&self) -> usize {
+        self.inner.used_bytes()
+    }
+
+    fn available_bytes(&self) -> usize {
+        self.inner.available_bytes()
+    }
+}
+
+impl<A: PageAllocator> PageAllocator for FailInjector<A> {
+    const PAGE_SIZE: usize = A::PAGE_SIZE;
+
+    fn alloc_pages(&mut self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
+        let used = self.inner.used_pages().saturating_mul(A::PAGE_SIZE);
+        if self
+            .policy
+            .should_fail(used, num_pages.saturating_mul(A::PAGE_SIZE))
+        {
+            return Err(AllocError::NoMemory);
+        }
+        self.inner.alloc_pages(num_pages, align_pow2)
+    }
+
+    fn dealloc_pages(&mut self, pos: usize, num_pages: usize) {
+        self.inner.dealloc_pages(pos, num_pages)
+    }
+
+    fn total_pages(&self) -> usize {
+        self.inner.total_pages()
+    }
+
+    fn used_pages(&self) -> usize {
+        self.inner.used_pages()
+    }
+
+    fn available_pages(&self) -> usize {
+        self.inner.available_pages()
+    }
+}
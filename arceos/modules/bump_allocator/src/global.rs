@@ -0,0 +1,221 @@
+//! A lock-protected [`EarlyAllocator`] shared by all CPUs.
+
+use allocator::{AllocResult, BaseAllocator, ByteAllocator, PageAllocator};
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use kspin::SpinNoIrq;
+
+#[cfg(feature = "fail-alloc")]
+use crate::FailPolicy;
+use crate::{AllocEvent, EarlyAllocator, EarlyAllocatorMark, EarlyAllocatorStats, UnusedMemory};
+
+/// An [`EarlyAllocator`] behind a [`SpinNoIrq`] lock, so that secondary CPUs
+/// brought up before the formal allocators can allocate safely.
+///
+/// The methods take `&self`. The allocator traits are implemented for
+/// `&GlobalEarlyAllocator`, so a shared reference can be used where an
+/// allocator is expected.
+pub struct GlobalEarlyAllocator<const SIZE: usize> {
+    inner: SpinNoIrq<EarlyAllocator<SIZE>>,
+}
+
+impl<const SIZE: usize> GlobalEarlyAllocator<SIZE> {
+    /// Creates an empty [`GlobalEarlyAllocator`].
+    pub const fn new() -> Self {
+        Self {
+            inner: SpinNoIrq::new(EarlyAllocator::new()),
+        }
+    }
+
+    /// Runs `f` with the inner allocator locked, e.g. to make several calls
+    /// atomically.
+    pub fn with<R>(&self, f: impl FnOnce(&mut EarlyAllocator<SIZE>) -> R) -> R {
+        f(&mut self.inner.lock())
+    }
+
+    /// Initializes the allocator with the given region.
+    pub fn init(&self, start: usize, size: usize) {
+        self.inner.lock().init(start, size)
+    }
+
+    /// Initializes the allocator with the given region and page size.
+    pub fn init_with_page_size(&self, start: usize, size: usize, page_size: usize) {
+        self.inner
+            .lock()
+            .init_with_page_size(start, size, page_size)
+    }
+
+    /// Sets the function called on every allocation and deallocation. It is
+    /// called with the lock held, so it must not allocate.
+    pub fn set_trace_hook(&self, hook: Option<fn(AllocEvent)>) {
+        self.inner.lock().set_trace_hook(hook)
+    }
+
+    /// Sets when allocations fail, to test out-of-memory error paths.
+    #[cfg(feature = "fail-alloc")]
+    pub fn set_fail_policy(&self, policy: FailPolicy) {
+        self.inner.lock().set_fail_policy(policy)
+    }
+
+    /// Adds the given region to the allocator.
+    pub fn add_memory(&self, start: usize, size: usize) -> AllocResult {
+        self.inner.lock().add_memory(start, size)
+    }
+
+    /// Allocates bytes with the given layout.
+    pub fn alloc(&self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        self.inner.lock().alloc(layout)
+    }
+
+    /// Allocates zero-filled bytes with the given layout.
+    pub fn alloc_zeroed(&self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        self.inner.lock().alloc_zeroed(layout)
+    }
+
+    /// Gives back the bytes allocated at `pos`.
+    pub fn dealloc(&self, pos: NonNull<u8>, layout: Layout) {
+        self.inner.lock().dealloc(pos, layout)
+    }
+
+    /// Resizes the byte allocation at `pos` to `new_size` bytes.
+    ///
+    /// # Safety
+    ///
+    /// `pos` must be allocated by this allocator with `layout`.
+    pub unsafe fn realloc(
+        &self,
+        pos: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> AllocResult<NonNull<u8>> {
+        self.inner.lock().realloc(pos, layout, new_size)
+    }
+
+    /// Allocates contiguous pages.
+    pub fn alloc_pages(&self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
+        self.inner.lock().alloc_pages(num_pages, align_pow2)
+    }
+
+    /// Allocates contiguous pages at the fixed address `base`.
+    pub fn alloc_pages_at(&self, base: usize, num_pages: usize) -> AllocResult<usize> {
+        self.inner.lock().alloc_pages_at(base, num_pages)
+    }
+
+    /// Gives back the pages allocated at `pos`.
+    pub fn dealloc_pages(&self, pos: usize, num_pages: usize) {
+        self.inner.lock().dealloc_pages(pos, num_pages)
+    }
+
+    /// Takes a checkpoint of the bytes areas.
+    pub fn mark(&self) -> EarlyAllocatorMark {
+        self.inner.lock().mark()
+    }
+
+    /// Releases all byte allocations made after `mark`.
+    pub fn reset_to(&self, mark: &EarlyAllocatorMark) {
+        self.inner.lock().reset_to(mark)
+    }
+
+    /// Seals the allocator and returns the memory it did not use.
+    pub fn finalize(&self) -> UnusedMemory {
+        self.inner.lock().finalize()
+    }
+
+    /// Whether `addr` may be a live byte allocation of this allocator.
+    pub fn owns_bytes(&self, addr: usize) -> bool {
+        self.inner.lock().owns_bytes(addr)
+    }
+
+    /// Gives back the page-aligned middle of the available area of each
+    /// region, see [`EarlyAllocator::release_unused`].
+    pub fn release_unused(&self) -> UnusedMemory {
+        self.inner.lock().release_unused()
+    }
+
+    /// Returns the usage statistics.
+    pub fn stats(&self) -> EarlyAllocatorStats {
+        self.inner.lock().stats()
+    }
+
+    /// Returns the number of allocated bytes.
+    pub fn used_bytes(&self) -> usize {
+        self.inner.lock().used_bytes()
+    }
+
+    /// Returns the number of available bytes.
+    pub fn available_bytes(&self) -> usize {
+        self.inner.lock().available_bytes()
+    }
+
+    /// Returns the number of allocated pages.
+    pub fn used_pages(&self) -> usize {
+        self.inner.lock().used_pages()
+    }
+
+    /// Returns the number of available pages.
+    pub fn available_pages(&self) -> usize {
+        self.inner.lock().available_pages()
+    }
+}
+
+impl<const SIZE: usize> Default for GlobalEarlyAllocator<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize> BaseAllocator for &GlobalEarlyAllocator<SIZE> {
+    fn init(&mut self, start: usize, size: usize) {
+        GlobalEarlyAllocator::init(self, start, size)
+    }
+
+    fn add_memory(&mut self, start: usize, size: usize) -> AllocResult {
+        GlobalEarlyAllocator::add_memory(self, start, size)
+    }
+}
+
+impl<const SIZE: usize> ByteAllocator for &GlobalEarlyAllocator<SIZE> {
+    fn alloc(&mut self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        GlobalEarlyAllocator::alloc(self, layout)
+    }
+
+    fn dealloc(&mut self, pos: NonNull<u8>, layout: Layout) {
+        GlobalEarlyAllocator::dealloc(self, pos, layout)
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.inner.lock().total_bytes()
+    }
+
+    fn used_bytes(&self) -> usize {
+        GlobalEarlyAllocator::used_bytes(self)
+    }
+
+    fn available_bytes(&self) -> usize {
+        GlobalEarlyAllocator::available_bytes(self)
+    }
+}
+
+impl<const SIZE: usize> PageAllocator for &GlobalEarlyAllocator<SIZE> {
+    const PAGE_SIZE: usize = SIZE;
+
+    fn alloc_pages(&mut self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
+        GlobalEarlyAllocator::alloc_pages(self, num_pages, align_pow2)
+    }
+
+    fn dealloc_pages(&mut self, pos: usize, num_pages: usize) {
+        GlobalEarlyAllocator::dealloc_pages(self, pos, num_pages)
+    }
+
+    fn total_pages(&self) -> usize {
+        self.inner.lock().total_pages()
+    }
+
+    fn used_pages(&self) -> usize {
+        GlobalEarlyAllocator::used_pages(self)
+    }
+
+    fn available_pages(&self) -> usize {
+        GlobalEarlyAllocator::available_pages(self)
+    }
+}
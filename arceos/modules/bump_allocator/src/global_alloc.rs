@@ -0,0 +1,86 @@
+//! A [`GlobalAlloc`] served by an [`EarlyAllocator`] until the formal
+//! allocator is up.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{GlobalEarlyAllocator, UnusedMemory};
+
+/// A [`GlobalAlloc`] that allocates from a [`GlobalEarlyAllocator`] at boot,
+/// then from the formal allocator `F` once [`switch_over`] is called.
+///
+/// It can be registered with `#[global_allocator]`, so that `Vec` or
+/// `String` can be used before the formal allocator is initialized.
+/// Allocations made before the switch-over stay valid, and are given back to
+/// the early allocator when freed.
+///
+/// [`switch_over`]: EarlyGlobalAlloc::switch_over
+pub struct EarlyGlobalAlloc<const SIZE: usize, F: 'static> {
+    early: GlobalEarlyAllocator<SIZE>,
+    formal: &'static F,
+    switched: AtomicBool,
+}
+
+impl<const SIZE: usize, F: GlobalAlloc> EarlyGlobalAlloc<SIZE, F> {
+    /// Creates an [`EarlyGlobalAlloc`] switching over to `formal`.
+    pub const fn new(formal: &'static F) -> Self {
+        Self {
+            early: GlobalEarlyAllocator::new(),
+            formal,
+            switched: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the early allocator, e.g. to add memory to it.
+    pub const fn early(&self) -> &GlobalEarlyAllocator<SIZE> {
+        &self.early
+    }
+
+    /// Initializes the early allocator with the given region.
+    pub fn init(&self, start: usize, size: usize) {
+        self.early.init(start, size)
+    }
+
+    /// Sends all later allocations to the formal allocator, which must be
+    /// initialized, and returns the memory the early allocator did not use,
+    /// to be added to it.
+    pub fn switch_over(&self) -> UnusedMemory {
+        self.switched.store(true, Ordering::Release);
+        self.early.finalize()
+    }
+
+    /// Whether [`switch_over`](Self::switch_over) has been called.
+    pub fn is_switched(&self) -> bool {
+        self.switched.load(Ordering::Acquire)
+    }
+}
+
+unsafe impl<const SIZE: usize, F: GlobalAlloc> GlobalAlloc for EarlyGlobalAlloc<SIZE, F> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.is_switched() {
+            return self.formal.alloc(layout);
+        }
+        self.early
+            .alloc(layout)
+            .map_or(null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if self.is_switched() {
+            return self.formal.alloc_zeroed(layout);
+        }
+        self.early
+            .alloc_zeroed(layout)
+            .map_or(null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // The unused memory given to the formal allocator is outside of the
+        // bytes areas in use.
+        match NonNull::new(ptr) {
+            Some(pos) if self.early.owns_bytes(ptr as usize) => self.early.dealloc(pos, layout),
+            _ => self.formal.dealloc(ptr, layout),
+        }
+    }
+}
@@ -1,6 +1,238 @@
+//! Early memory allocator used before the formal allocators can work.
+//!
+//! # Cargo Features
+//!
+//! - `debug-poison`: Fill newly allocated bytes with `0xAA`, and the bytes
+//!   area with `0xDD` when it is reclaimed, to catch the use of uninitialized
+//!   or freed memory.
+//! - `red-zone`: Surround each byte allocation with 8-byte canaries, checked
+//!   when it is freed and by `finalize`. A corrupted canary panics with the
+//!   address of the allocation.
+//! - `fail-alloc`: Make allocations fail on demand following a [`FailPolicy`],
+//!   set with `EarlyAllocator::set_fail_policy`, or for any allocator with
+//!   [`FailInjector`], to test out-of-memory error paths.
+
 #![no_std]
 
-use allocator::{BaseAllocator, ByteAllocator, PageAllocator};
+use alloc_stats::{AllocatorStats, MemoryStats};
+use allocator::{AllocError, AllocResult, BaseAllocator, ByteAllocator, PageAllocator};
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+#[cfg(feature = "fail-alloc")]
+mod fail;
+mod global;
+mod global_alloc;
+mod percpu;
+#[cfg(feature = "red-zone")]
+mod redzone;
+// The tests check exact addresses, which are moved by the red zones.
+#[cfg(all(test, not(feature = "red-zone")))]
+mod tests;
+
+#[cfg(feature = "fail-alloc")]
+pub use self::fail::{FailInjector, FailPolicy};
+pub use self::global::GlobalEarlyAllocator;
+pub use self::global_alloc::EarlyGlobalAlloc;
+pub use self::percpu::PerCpuEarlyAllocator;
+#[cfg(feature = "red-zone")]
+pub use self::redzone::CANARY;
+pub use alloc_stats::{AllocEvent, RegionKind};
+
+/// The byte newly allocated memory is filled with.
+#[cfg(feature = "debug-poison")]
+pub const POISON_ALLOC: u8 = 0xAA;
+/// The byte reclaimed memory is filled with.
+#[cfg(feature = "debug-poison")]
+pub const POISON_FREE: u8 = 0xDD;
+
+/// Max number of freed page ranges remembered for reuse.
+pub const MAX_FREE_PAGE_RANGES: usize = 8;
+
+/// Max number of memory regions, including the one given to `init`.
+pub const MAX_MEMORY_REGIONS: usize = 8;
+
+#[derive(Clone, Copy)]
+struct FreeRange {
+    start: usize,
+    size: usize,
+}
+
+impl FreeRange {
+    const EMPTY: Self = Self { start: 0, size: 0 };
+
+    const fn end(&self) -> usize {
+        self.start + self.size
+    }
+}
+
+/// A snapshot of the usage of an [`EarlyAllocator`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EarlyAllocatorStats {
+    /// Bytes used by byte allocations, including alignment padding.
+    pub used_bytes: usize,
+    /// The maximum of `used_bytes` since `init`.
+    pub peak_bytes: usize,
+    /// Pages used by page allocations.
+    pub used_pages: usize,
+    /// The maximum of `used_pages` since `init`.
+    pub peak_pages: usize,
+    /// Number of live byte allocations.
+    pub byte_allocs: usize,
+    /// Number of failed byte and page allocations.
+    pub failed_allocs: usize,
+}
+
+/// A checkpoint of the bytes areas of an [`EarlyAllocator`], taken by
+/// [`EarlyAllocator::mark`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EarlyAllocatorMark {
+    b_pos: [usize; MAX_MEMORY_REGIONS],
+    count: [usize; MAX_MEMORY_REGIONS],
+    num_regions: usize,
+}
+
+/// The memory left unused by an [`EarlyAllocator`], returned by
+/// [`EarlyAllocator::finalize`].
+///
+/// It iterates over disjoint `(start, size)` ranges: the available area of
+/// each region, followed by the freed page ranges.
+#[derive(Clone)]
+pub struct UnusedMemory {
+    ranges: [FreeRange; MAX_MEMORY_REGIONS + MAX_FREE_PAGE_RANGES],
+    len: usize,
+    next: usize,
+}
+
+impl UnusedMemory {
+    const fn empty() -> Self {
+        Self {
+            ranges: [FreeRange::EMPTY; MAX_MEMORY_REGIONS + MAX_FREE_PAGE_RANGES],
+            len: 0,
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, start: usize, size: usize) {
+        if size > 0 {
+            self.ranges[self.len] = FreeRange { start, size };
+            self.len += 1;
+        }
+    }
+}
+
+impl Iterator for UnusedMemory {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = self.ranges[self.next..self.len].first()?;
+        self.next += 1;
+        Some((range.start, range.size))
+    }
+}
+
+/// The memory map of an [`EarlyAllocator`], returned by
+/// [`EarlyAllocator::iter_regions`].
+///
+/// It iterates over `(start, len, kind)` ranges, region by region, in address
+/// order within each region.
+pub struct RegionIter<'a, const SIZE: usize> {
+    alloc: &'a EarlyAllocator<SIZE>,
+    region: usize,
+    pos: usize,
+}
+
+impl<const SIZE: usize> Iterator for RegionIter<'_, SIZE> {
+    type Item = (usize, usize, RegionKind);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let r = self.alloc.regions().get(self.region)?;
+            let pos = self.pos.max(r.start);
+            let (kind, end) = if pos < r.b_pos {
+                (RegionKind::BytesUsed, r.b_pos)
+            } else if pos < r.p_pos {
+                (RegionKind::Free, r.p_pos)
+            } else if pos < r.end {
+                // The pages area, with holes from the free list.
+                let free = self
+                    .alloc
+                    .free_list()
+                    .iter()
+                    .filter(|f| r.contains(f.start));
+                match free.clone().find(|f| f.start <= pos && pos < f.end()) {
+                    Some(f) => (RegionKind::Free, f.end()),
+                    None => {
+                        let next = free.map(|f| f.start).filter(|&s| s > pos).min();
+                        (RegionKind::PagesUsed, next.unwrap_or(r.end))
+                    }
+                }
+            } else {
+                self.region += 1;
+                self.pos = 0;
+                continue;
+            };
+            self.pos = end;
+            return Some((pos, end - pos, kind));
+        }
+    }
+}
+
+/// A memory region, allocated from both ends.
+#[derive(Clone, Copy)]
+struct Region {
+    start: usize,
+    end: usize,
+    b_pos: usize,
+    p_pos: usize,
+    count: usize,
+    /// The highest `b_pos` so far, the bytes above have never been handed out.
+    b_max: usize,
+    /// The lowest `p_pos` so far, the bytes below have never been handed out.
+    p_min: usize,
+}
+
+impl Region {
+    const EMPTY: Self = Self::new(0, 0);
+
+    const fn new(start: usize, end: usize) -> Self {
+        Self {
+            start,
+            end,
+            b_pos: start,
+            p_pos: end,
+            count: 0,
+            b_max: start,
+            p_min: end,
+        }
+    }
+
+    const fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn alloc_bytes(&mut self, layout: Layout) -> Option<usize> {
+        let start = align_up(self.b_pos, layout.align())?;
+        let next = start.checked_add(layout.size())?;
+        if next > self.p_pos {
+            return None;
+        }
+        self.b_pos = next;
+        self.b_max = self.b_max.max(next);
+        self.count += 1;
+        Some(start)
+    }
+
+    fn alloc_pages(&mut self, size: usize, align: usize) -> Option<usize> {
+        let next = align_down(self.p_pos.checked_sub(size)?, align);
+        if next < self.b_pos {
+            return None;
+        }
+        self.p_pos = next;
+        self.p_min = self.p_min.min(next);
+        Some(next)
+    }
+}
 
 /// Early memory allocator
 /// Use it before formal bytes-allocator and pages-allocator can work!
@@ -14,75 +246,826 @@ use allocator::{BaseAllocator, ByteAllocator, PageAllocator};
 ///
 /// For bytes area, 'count' records number of allocations.
 /// When it goes down to ZERO, free bytes-used area.
-/// For pages area, it will never be freed!
+/// Freeing the last allocation also moves b_pos back at once, so stack-like
+/// alloc/free patterns reuse the memory.
+/// For pages area, freed pages next to p_pos give the space back at once.
+/// Other freed ranges are kept in a small free list for later page
+/// allocations, and are leaked when the list is full.
+///
+/// More disjoint regions can be added by `add_memory`, each of them is laid
+/// out as above. Allocations are served by the first region with enough
+/// space, so they fall over to the next region when one is exhausted.
+///
+/// Once the formal allocators are ready, `finalize` hands the unused memory
+/// over to them and seals the allocator. Memory already allocated stays
+/// valid and can be freed, but nothing is allocated any more.
+///
+/// The page size is `SIZE` by default. Use `init_with_page_size` to select it
+/// at runtime, e.g. from the device tree, or [`DynPageEarlyAllocator`] if
+/// there is no sensible default.
 ///
-pub struct EarlyAllocator<const SIZE: usize> {}
+pub struct EarlyAllocator<const SIZE: usize> {
+    regions: [Region; MAX_MEMORY_REGIONS],
+    num_regions: usize,
+    free_pages: [FreeRange; MAX_FREE_PAGE_RANGES],
+    num_free: usize,
+    peak_bytes: usize,
+    peak_pages: usize,
+    failed_allocs: usize,
+    sealed: bool,
+    page_size: usize,
+    trace_hook: Option<fn(AllocEvent)>,
+    #[cfg(feature = "fail-alloc")]
+    fail_policy: FailPolicy,
+}
+
+/// An [`EarlyAllocator`] whose page size can only be set at runtime, by
+/// [`EarlyAllocator::init_with_page_size`]. Page allocations fail before.
+pub type DynPageEarlyAllocator = EarlyAllocator<0>;
 
 impl<const SIZE: usize> EarlyAllocator<SIZE> {
     pub const fn new() -> Self {
-        Self {}
+        Self {
+            regions: [Region::EMPTY; MAX_MEMORY_REGIONS],
+            num_regions: 0,
+            free_pages: [FreeRange::EMPTY; MAX_FREE_PAGE_RANGES],
+            num_free: 0,
+            peak_bytes: 0,
+            peak_pages: 0,
+            failed_allocs: 0,
+            sealed: false,
+            page_size: SIZE,
+            trace_hook: None,
+            #[cfg(feature = "fail-alloc")]
+            fail_policy: FailPolicy::never(),
+        }
+    }
+
+    /// Sets the function called on every allocation and deallocation, e.g.
+    /// to log the allocation patterns for tuning. `None` removes it.
+    ///
+    /// The hook may be called with the allocator locked, so it must not
+    /// allocate.
+    pub fn set_trace_hook(&mut self, hook: Option<fn(AllocEvent)>) {
+        self.trace_hook = hook;
+    }
+
+    /// Sets when allocations fail, counting allocations from now. It is kept
+    /// across `init`.
+    #[cfg(feature = "fail-alloc")]
+    pub fn set_fail_policy(&mut self, policy: FailPolicy) {
+        self.fail_policy = policy;
+    }
+
+    /// Initializes the allocator with the given region, like `init`, but with
+    /// a page size selected at runtime instead of `SIZE`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `page_size` is not a power of two.
+    pub fn init_with_page_size(&mut self, start: usize, size: usize, page_size: usize) {
+        assert!(page_size.is_power_of_two(), "invalid page size");
+        self.init(start, size);
+        self.page_size = page_size;
+    }
+
+    /// Takes a checkpoint of the bytes areas, to release everything allocated
+    /// after it at once with [`reset_to`](Self::reset_to).
+    pub fn mark(&self) -> EarlyAllocatorMark {
+        let mut mark = EarlyAllocatorMark {
+            b_pos: [0; MAX_MEMORY_REGIONS],
+            count: [0; MAX_MEMORY_REGIONS],
+            num_regions: self.num_regions,
+        };
+        for (i, r) in self.regions().iter().enumerate() {
+            mark.b_pos[i] = r.b_pos;
+            mark.count[i] = r.count;
+        }
+        mark
+    }
+
+    /// Releases all byte allocations made after `mark`, which must be taken
+    /// since the last `init`. Page allocations are not affected.
+    ///
+    /// The allocations made before `mark` must not be freed until the reset,
+    /// or their bytes area is not reclaimed when all of them are freed.
+    pub fn reset_to(&mut self, mark: &EarlyAllocatorMark) {
+        for (i, r) in self.regions_mut().iter_mut().enumerate() {
+            let (b_pos, count) = if i < mark.num_regions {
+                (mark.b_pos[i], mark.count[i])
+            } else {
+                (r.start, 0)
+            };
+            // Everything may have been freed since the mark.
+            if b_pos < r.b_pos {
+                poison(b_pos, r.b_pos - b_pos, POISON_FREE);
+                r.b_pos = b_pos;
+                r.count = count;
+            }
+        }
+    }
+
+    /// Allocates `num_pages` pages at the fixed address `base`, e.g. for an
+    /// identity-mapped DMA region.
+    ///
+    /// It succeeds only if the whole range is free, i.e. it lies in the
+    /// available area of a region or in a freed page range. `base` must be
+    /// aligned to the page size.
+    pub fn alloc_pages_at(&mut self, base: usize, num_pages: usize) -> AllocResult<usize> {
+        let res = self.alloc_pages_at_inner(base, num_pages);
+        self.record_pages(&res, num_pages, self.page_size);
+        res
+    }
+
+    /// Returns the page size used by page allocations.
+    pub const fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Seals the allocator and returns the memory it did not use, i.e. the
+    /// `[b_pos, p_pos)` area of each region and the freed page ranges, to be
+    /// given to the formal allocators.
+    ///
+    /// After that, allocations fail with [`AllocError::NotAllocated`], and
+    /// the returned memory is no longer counted as available. Memory freed
+    /// later is not reused. Calling it again returns nothing.
+    ///
+    /// With the `red-zone` feature, it panics if a canary is corrupted.
+    pub fn finalize(&mut self) -> UnusedMemory {
+        let mut unused = UnusedMemory::empty();
+        if self.sealed {
+            return unused;
+        }
+        #[cfg(feature = "red-zone")]
+        for r in self.regions() {
+            // SAFETY: the bytes area only contains blocks and gaps.
+            unsafe { redzone::check_all(r.start, r.b_pos) };
+        }
+        for r in self.regions() {
+            unused.push(r.b_pos, r.p_pos - r.b_pos);
+        }
+        for r in self.free_list() {
+            unused.push(r.start, r.size);
+        }
+        self.sealed = true;
+        unused
+    }
+
+    /// Whether `addr` is in the bytes area in use of a region, i.e. it may be
+    /// a live byte allocation. The memory returned by `finalize` is not.
+    pub fn owns_bytes(&self, addr: usize) -> bool {
+        self.regions()
+            .iter()
+            .any(|r| r.start <= addr && addr < r.b_pos)
+    }
+
+    /// Gives back the page-aligned middle of the available area of each
+    /// region, e.g. for axmm to add it to the frame pool, while the allocator
+    /// keeps working with the memory around it.
+    ///
+    /// A region is split in two around its released part: the lower one
+    /// keeps the bytes area, the upper one the pages area. A region is left
+    /// as is if there is no slot for its upper part.
+    pub fn release_unused(&mut self) -> UnusedMemory {
+        let mut released = UnusedMemory::empty();
+        let page_size = self.page_size;
+        if self.sealed || !page_size.is_power_of_two() {
+            return released;
+        }
+        for i in 0..self.num_regions {
+            let r = self.regions[i];
+            let (Some(lo), hi) = (align_up(r.b_pos, page_size), align_down(r.p_pos, page_size))
+            else {
+                continue;
+            };
+            if lo >= hi || (hi < r.end && self.num_regions == MAX_MEMORY_REGIONS) {
+                continue;
+            }
+            self.regions[i] = Region {
+                end: lo,
+                p_pos: lo,
+                b_max: r.b_max.min(lo),
+                p_min: lo,
+                ..r
+            };
+            if hi < r.end {
+                self.regions[self.num_regions] = Region {
+                    start: hi,
+                    b_pos: hi,
+                    count: 0,
+                    b_max: hi,
+                    p_min: r.p_min.max(hi),
+                    ..r
+                };
+                self.num_regions += 1;
+            }
+            released.push(lo, hi - lo);
+        }
+        released
+    }
+
+    /// Returns the memory map, e.g. for a debug shell to print it.
+    ///
+    /// Adjacent free ranges may be reported separately, e.g. the available
+    /// area and a freed page range next to it.
+    pub fn iter_regions(&self) -> RegionIter<'_, SIZE> {
+        RegionIter {
+            alloc: self,
+            region: 0,
+            pos: 0,
+        }
+    }
+
+    /// Whether [`finalize`](Self::finalize) has been called.
+    pub const fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    /// Allocates zero-filled bytes.
+    ///
+    /// Memory given to `init` and `add_memory` is assumed to be zero-filled,
+    /// as the RAM at boot. So only the part that has been handed out before
+    /// is cleared, and a fresh allocation costs no `memset`.
+    pub fn alloc_zeroed(&mut self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        let (start, b_max, p_min) = self.alloc_data(layout)?;
+        let end = start + layout.size();
+        // SAFETY: the range has just been allocated.
+        unsafe {
+            if start < b_max {
+                core::ptr::write_bytes(start as *mut u8, 0, b_max.min(end) - start);
+            }
+            if end > p_min {
+                let from = p_min.max(start);
+                core::ptr::write_bytes(from as *mut u8, 0, end - from);
+            }
+        }
+        NonNull::new(start as *mut u8).ok_or(AllocError::NoMemory)
+    }
+
+    /// Returns the usage statistics, to help sizing the early memory.
+    pub fn stats(&self) -> EarlyAllocatorStats {
+        EarlyAllocatorStats {
+            used_bytes: self.used_bytes(),
+            peak_bytes: self.peak_bytes,
+            used_pages: self.used_pages(),
+            peak_pages: self.peak_pages,
+            byte_allocs: self.regions().iter().map(|r| r.count).sum(),
+            failed_allocs: self.failed_allocs,
+        }
+    }
+
+    /// Resizes the byte allocation at `pos` to `new_size` bytes.
+    ///
+    /// If it is the last allocation of its region, i.e. it ends at `b_pos`,
+    /// it is resized in place. Otherwise a new allocation is made and the
+    /// data is copied, unless the allocation shrinks. With the `red-zone`
+    /// feature, it is always moved.
+    ///
+    /// # Safety
+    ///
+    /// `pos` must be allocated by this allocator with `layout`.
+    pub unsafe fn realloc(
+        &mut self,
+        pos: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> AllocResult<NonNull<u8>> {
+        if self.sealed {
+            return Err(AllocError::NotAllocated);
+        }
+        if new_size == 0 {
+            return Err(AllocError::InvalidParam);
+        }
+        let start = pos.as_ptr() as usize;
+        let end = start + layout.size();
+        // The canaries are at fixed places, so the allocation always moves
+        // with red zones.
+        let in_place = !cfg!(feature = "red-zone");
+        if let Some(region) = self
+            .regions_mut()
+            .iter_mut()
+            .find(|r| in_place && r.contains(start) && r.b_pos == end)
+        {
+            match start.checked_add(new_size) {
+                Some(new_end) if new_end <= region.p_pos => {
+                    if new_end > end {
+                        poison(end, new_end - end, POISON_ALLOC);
+                    }
+                    region.b_pos = new_end;
+                    region.b_max = region.b_max.max(new_end);
+                    self.peak_bytes = self.peak_bytes.max(self.used_bytes());
+                    self.trace(AllocEvent::Dealloc {
+                        pos: start,
+                        size: layout.size(),
+                    });
+                    self.trace(AllocEvent::Alloc {
+                        pos: start,
+                        size: new_size,
+                        align: layout.align(),
+                    });
+                    return Ok(pos);
+                }
+                _ => {}
+            }
+        }
+        if in_place && new_size <= layout.size() {
+            return Ok(pos);
+        }
+        let new_layout = Layout::from_size_align(new_size, layout.align())
+            .map_err(|_| AllocError::InvalidParam)?;
+        let new_pos = self.alloc(new_layout)?;
+        let len = layout.size().min(new_size);
+        core::ptr::copy_nonoverlapping(pos.as_ptr(), new_pos.as_ptr(), len);
+        self.dealloc(pos, layout);
+        Ok(new_pos)
+    }
+
+    fn regions(&self) -> &[Region] {
+        &self.regions[..self.num_regions]
+    }
+
+    fn regions_mut(&mut self) -> &mut [Region] {
+        &mut self.regions[..self.num_regions]
+    }
+
+    fn region_of(&self, addr: usize) -> Option<usize> {
+        self.regions().iter().position(|r| r.contains(addr))
+    }
+
+    /// Returns the number of bytes in the free list.
+    fn free_list_bytes(&self) -> usize {
+        self.free_list().iter().map(|r| r.size).sum()
+    }
+
+    fn free_list(&self) -> &[FreeRange] {
+        &self.free_pages[..self.num_free]
+    }
+
+    fn remove_free_range(&mut self, idx: usize) -> FreeRange {
+        let range = self.free_pages[idx];
+        self.num_free -= 1;
+        self.free_pages[idx] = self.free_pages[self.num_free];
+        range
+    }
+
+    /// Adds a range to the free list, merging it with its neighbours in the
+    /// same region. Returns `false` if the list is full.
+    fn insert_free_range(&mut self, mut range: FreeRange) -> bool {
+        let region = self.region_of(range.start);
+        let mut i = 0;
+        while i < self.num_free {
+            let r = self.free_pages[i];
+            if (r.end() == range.start || range.end() == r.start)
+                && self.region_of(r.start) == region
+            {
+                range.start = range.start.min(r.start);
+                range.size += r.size;
+                self.remove_free_range(i);
+            } else {
+                i += 1;
+            }
+        }
+        if self.num_free == MAX_FREE_PAGE_RANGES {
+            return false;
+        }
+        self.free_pages[self.num_free] = range;
+        self.num_free += 1;
+        true
+    }
+
+    /// Gives the free ranges at the bottom of the pages areas back to the
+    /// available areas.
+    fn shrink_pages_area(&mut self) {
+        let mut i = 0;
+        while i < self.num_free {
+            let range = self.free_pages[i];
+            let region = self
+                .regions_mut()
+                .iter_mut()
+                .find(|r| r.p_pos == range.start && r.contains(range.start));
+            if let Some(region) = region {
+                region.p_pos = range.end();
+                self.remove_free_range(i);
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Checks that a byte allocation can be made with `layout`.
+    fn check_layout(&self, layout: Layout) -> AllocResult {
+        if self.sealed {
+            return Err(AllocError::NotAllocated);
+        }
+        // The alignment of a `Layout` is always a power of two.
+        if layout.size() == 0 {
+            return Err(AllocError::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Allocates bytes for `layout` from the first region with enough space.
+    /// Returns the address, and the `b_max` and `p_min` of the region before.
+    fn alloc_data(&mut self, layout: Layout) -> AllocResult<(usize, usize, usize)> {
+        self.check_layout(layout)?;
+        #[cfg(feature = "red-zone")]
+        let (block, _) = redzone::block_layout(layout).ok_or(AllocError::NoMemory)?;
+        #[cfg(not(feature = "red-zone"))]
+        let block = layout;
+        let res = if self.inject_failure(block.size()) {
+            Err(AllocError::NoMemory)
+        } else {
+            self.regions_mut()
+                .iter_mut()
+                .find_map(|r| {
+                    let (b_pos, b_max, p_min) = (r.b_pos, r.b_max, r.p_min);
+                    r.alloc_bytes(block)
+                        .map(|start| (b_pos, start, b_max, p_min))
+                })
+                .ok_or(AllocError::NoMemory)
+        };
+        self.record_alloc(&res);
+        #[cfg(feature = "red-zone")]
+        // SAFETY: the block and the gap before it have just been allocated.
+        let res = res.map(|(b_pos, start, b_max, p_min)| {
+            (
+                b_pos,
+                unsafe { redzone::arm(b_pos, start, layout) },
+                b_max,
+                p_min,
+            )
+        });
+        self.trace_bytes(res.as_ref().ok().map(|&(_, data, ..)| data), layout);
+        res.map(|(_, data, b_max, p_min)| (data, b_max, p_min))
+    }
+
+    /// Counts an allocation of `size` bytes for the fail policy, and returns
+    /// whether it must fail.
+    #[cfg(feature = "fail-alloc")]
+    fn inject_failure(&mut self, size: usize) -> bool {
+        let used = self.used_bytes() + self.used_pages() * self.page_size;
+        self.fail_policy.should_fail(used, size)
+    }
+
+    #[cfg(not(feature = "fail-alloc"))]
+    #[inline(always)]
+    fn inject_failure(&mut self, _size: usize) -> bool {
+        false
+    }
+
+    fn trace(&self, event: AllocEvent) {
+        if let Some(hook) = self.trace_hook {
+            hook(event);
+        }
+    }
+
+    /// Traces a byte allocation at `pos`, or a failed one if it is `None`.
+    fn trace_bytes(&self, pos: Option<usize>, layout: Layout) {
+        self.trace(match pos {
+            Some(pos) => AllocEvent::Alloc {
+                pos,
+                size: layout.size(),
+                align: layout.align(),
+            },
+            None => AllocEvent::Failed {
+                size: layout.size(),
+                align: layout.align(),
+            },
+        });
+    }
+
+    fn record_pages(&mut self, res: &AllocResult<usize>, num_pages: usize, align: usize) {
+        self.record_alloc(res);
+        self.trace(match *res {
+            Ok(pos) => AllocEvent::AllocPages { pos, num_pages },
+            Err(_) => AllocEvent::Failed {
+                size: num_pages.saturating_mul(self.page_size),
+                align,
+            },
+        });
+    }
+
+    fn record_alloc<T>(&mut self, res: &AllocResult<T>) {
+        if res.is_ok() {
+            self.peak_bytes = self.peak_bytes.max(self.used_bytes());
+            self.peak_pages = self.peak_pages.max(self.used_pages());
+        } else {
+            self.failed_allocs += 1;
+        }
+    }
+
+    /// Takes `size` bytes aligned to `align` from the free list.
+    fn alloc_from_free_list(&mut self, size: usize, align: usize) -> Option<usize> {
+        for i in 0..self.num_free {
+            let range = self.free_pages[i];
+            if range.size < size {
+                continue;
+            }
+            // Take the top of the range, so that only the bottom part is left
+            // unless the alignment requires a gap above.
+            let pos = align_down(range.end() - size, align);
+            if pos >= range.start && self.take_from_free_range(i, pos, size) {
+                return Some(pos);
+            }
+        }
+        None
+    }
+
+    /// Takes `[pos, pos + size)` from the free range `idx`, which contains
+    /// it. Returns `false` if there is no slot to keep both leftovers.
+    fn take_from_free_range(&mut self, idx: usize, pos: usize, size: usize) -> bool {
+        let range = self.free_pages[idx];
+        let above = FreeRange {
+            start: pos + size,
+            size: range.end() - (pos + size),
+        };
+        if above.size > 0 && self.num_free == MAX_FREE_PAGE_RANGES && pos > range.start {
+            return false;
+        }
+        self.free_pages[idx].size = pos - range.start;
+        if pos == range.start {
+            self.remove_free_range(idx);
+        }
+        if above.size > 0 {
+            self.insert_free_range(above);
+        }
+        true
+    }
+
+    fn alloc_pages_at_inner(&mut self, base: usize, num_pages: usize) -> AllocResult<usize> {
+        if self.sealed {
+            return Err(AllocError::NotAllocated);
+        }
+        let page_size = self.page_size;
+        if num_pages == 0 || !page_size.is_power_of_two() || base & (page_size - 1) != 0 {
+            return Err(AllocError::InvalidParam);
+        }
+        let end = num_pages
+            .checked_mul(page_size)
+            .and_then(|size| base.checked_add(size))
+            .ok_or(AllocError::InvalidParam)?;
+        if self.inject_failure(end - base) {
+            return Err(AllocError::NoMemory);
+        }
+        if let Some(i) = self
+            .free_list()
+            .iter()
+            .position(|r| r.start <= base && end <= r.end())
+        {
+            return if self.take_from_free_range(i, base, end - base) {
+                Ok(base)
+            } else {
+                Err(AllocError::NoMemory)
+            };
+        }
+        let Some(i) = self
+            .regions()
+            .iter()
+            .position(|r| r.b_pos <= base && end <= r.p_pos)
+        else {
+            return Err(AllocError::NoMemory);
+        };
+        let old_p_pos = self.regions[i].p_pos;
+        if end < old_p_pos {
+            // Keep the pages above for later allocations, or fail rather than
+            // leaking them.
+            let above = FreeRange {
+                start: end,
+                size: old_p_pos - end,
+            };
+            if !self.insert_free_range(above) {
+                return Err(AllocError::NoMemory);
+            }
+        }
+        let region = &mut self.regions[i];
+        region.p_pos = base;
+        region.p_min = region.p_min.min(base);
+        Ok(base)
+    }
+
+    fn alloc_pages_inner(&mut self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
+        if self.sealed {
+            return Err(AllocError::NotAllocated);
+        }
+        // `align_up` and `align_down` only work with powers of two.
+        let page_size = self.page_size;
+        if num_pages == 0 || !align_pow2.is_power_of_two() || !page_size.is_power_of_two() {
+            return Err(AllocError::InvalidParam);
+        }
+        let size = num_pages
+            .checked_mul(page_size)
+            .ok_or(AllocError::NoMemory)?;
+        if self.inject_failure(size) {
+            return Err(AllocError::NoMemory);
+        }
+        let align = align_pow2.max(page_size);
+        if let Some(pos) = self.alloc_from_free_list(size, align) {
+            return Ok(pos);
+        }
+        for i in 0..self.num_regions {
+            let old_p_pos = self.regions[i].p_pos;
+            if let Some(next) = self.regions[i].alloc_pages(size, align) {
+                if next + size < old_p_pos {
+                    // Keep the gap left by the alignment for later allocations.
+                    self.insert_free_range(FreeRange {
+                        start: next + size,
+                        size: old_p_pos - (next + size),
+                    });
+                }
+                return Ok(next);
+            }
+        }
+        Err(AllocError::NoMemory)
+    }
+}
+
+impl<const SIZE: usize> Default for EarlyAllocator<SIZE> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl<const SIZE: usize> BaseAllocator for EarlyAllocator<SIZE> {
     fn init(&mut self, start: usize, size: usize) {
-        todo!()
+        self.regions[0] = Region::new(start, start + size);
+        self.num_regions = 1;
+        self.num_free = 0;
+        self.peak_bytes = 0;
+        self.peak_pages = 0;
+        self.failed_allocs = 0;
+        self.sealed = false;
+        self.page_size = SIZE;
     }
 
-    fn add_memory(&mut self, start: usize, size: usize) -> allocator::AllocResult {
-        todo!()
+    fn add_memory(&mut self, start: usize, size: usize) -> AllocResult {
+        let end = start.checked_add(size).ok_or(AllocError::InvalidParam)?;
+        if self
+            .regions()
+            .iter()
+            .any(|r| start < r.end && r.start < end)
+        {
+            return Err(AllocError::MemoryOverlap);
+        }
+        if self.num_regions == MAX_MEMORY_REGIONS {
+            return Err(AllocError::NoMemory);
+        }
+        self.regions[self.num_regions] = Region::new(start, end);
+        self.num_regions += 1;
+        Ok(())
     }
 }
 
 impl<const SIZE: usize> ByteAllocator for EarlyAllocator<SIZE> {
-    fn alloc(
-        &mut self,
-        layout: core::alloc::Layout,
-    ) -> allocator::AllocResult<core::ptr::NonNull<u8>> {
-        todo!()
+    fn alloc(&mut self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        let (start, ..) = self.alloc_data(layout)?;
+        poison(start, layout.size(), POISON_ALLOC);
+        NonNull::new(start as *mut u8).ok_or(AllocError::NoMemory)
     }
 
-    fn dealloc(&mut self, pos: core::ptr::NonNull<u8>, layout: core::alloc::Layout) {
-        todo!()
+    fn dealloc(&mut self, pos: NonNull<u8>, layout: Layout) {
+        let start = pos.as_ptr() as usize;
+        let Some(idx) = self.region_of(start) else {
+            return;
+        };
+        self.trace(AllocEvent::Dealloc {
+            pos: start,
+            size: layout.size(),
+        });
+        #[cfg(feature = "red-zone")]
+        // SAFETY: `pos` is allocated with `layout`.
+        let (start, layout) = unsafe {
+            (
+                redzone::check(start, layout.size()),
+                redzone::block_layout(layout).unwrap().0,
+            )
+        };
+        let region = &mut self.regions[idx];
+        region.count -= 1;
+        let new_b_pos = if region.count == 0 {
+            region.start
+        } else if start + layout.size() == region.b_pos {
+            // The last allocation, freed in LIFO order.
+            start
+        } else {
+            return;
+        };
+        poison(new_b_pos, region.b_pos - new_b_pos, POISON_FREE);
+        region.b_pos = new_b_pos;
     }
 
     fn total_bytes(&self) -> usize {
-        todo!()
+        self.regions().iter().map(|r| r.end - r.start).sum()
     }
 
     fn used_bytes(&self) -> usize {
-        todo!()
+        self.regions().iter().map(|r| r.b_pos - r.start).sum()
     }
 
     fn available_bytes(&self) -> usize {
-        todo!()
+        if self.sealed {
+            return 0;
+        }
+        self.regions().iter().map(|r| r.p_pos - r.b_pos).sum()
     }
 }
 
 impl<const SIZE: usize> PageAllocator for EarlyAllocator<SIZE> {
     const PAGE_SIZE: usize = SIZE;
 
-    fn alloc_pages(
-        &mut self,
-        num_pages: usize,
-        align_pow2: usize,
-    ) -> allocator::AllocResult<usize> {
-        todo!()
+    fn alloc_pages(&mut self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
+        let res = self.alloc_pages_inner(num_pages, align_pow2);
+        self.record_pages(&res, num_pages, align_pow2);
+        res
     }
 
     fn dealloc_pages(&mut self, pos: usize, num_pages: usize) {
-        todo!()
+        self.trace(AllocEvent::DeallocPages { pos, num_pages });
+        let range = FreeRange {
+            start: pos,
+            size: num_pages * self.page_size,
+        };
+        if !self.insert_free_range(range) {
+            // The free list is full, give the pages back only if they are
+            // next to `p_pos`, or leak them.
+            if let Some(region) = self.regions_mut().iter_mut().find(|r| r.p_pos == pos) {
+                region.p_pos = range.end();
+            } else {
+                return;
+            }
+        }
+        self.shrink_pages_area();
     }
 
     fn total_pages(&self) -> usize {
-        todo!()
+        self.regions()
+            .iter()
+            .map(|r| (r.end - r.start).checked_div(self.page_size).unwrap_or(0))
+            .sum()
     }
 
     fn used_pages(&self) -> usize {
-        todo!()
+        let used: usize = self.regions().iter().map(|r| r.end - r.p_pos).sum();
+        (used - self.free_list_bytes())
+            .checked_div(self.page_size)
+            .unwrap_or(0)
     }
 
     fn available_pages(&self) -> usize {
-        todo!()
+        if self.sealed {
+            return 0;
+        }
+        let avail: usize = self.regions().iter().map(|r| r.p_pos - r.b_pos).sum();
+        (avail + self.free_list_bytes())
+            .checked_div(self.page_size)
+            .unwrap_or(0)
     }
-}
\ No newline at end of file
+}
+
+impl<const SIZE: usize> AllocatorStats for EarlyAllocator<SIZE> {
+    /// The free blocks are the available area of each region and the freed
+    /// page ranges. There are none once the allocator is sealed.
+    fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        if self.sealed {
+            return stats;
+        }
+        for r in self.regions() {
+            stats.add_block(r.p_pos - r.b_pos);
+        }
+        for r in self.free_list() {
+            stats.add_block(r.size);
+        }
+        stats
+    }
+}
+
+#[cfg(feature = "debug-poison")]
+fn poison(start: usize, len: usize, byte: u8) {
+    // SAFETY: the range is in the memory managed by the allocator, and is not
+    // used by anyone else.
+    unsafe { core::ptr::write_bytes(start as *mut u8, byte, len) };
+}
+
+#[cfg(not(feature = "debug-poison"))]
+#[inline(always)]
+fn poison(_start: usize, _len: usize, _byte: u8) {}
+
+#[cfg(not(feature = "debug-poison"))]
+const POISON_ALLOC: u8 = 0;
+#[cfg(not(feature = "debug-poison"))]
+const POISON_FREE: u8 = 0;
+
+/// Aligns `pos` up to `align`, which must be a power of two. Returns `None`
+/// on overflow.
+const fn align_up(pos: usize, align: usize) -> Option<usize> {
+    match pos.checked_add(align - 1) {
+        Some(pos) => Some(pos & !(align - 1)),
+        None => None,
+    }
+}
+
+/// Aligns `pos` down to `align`, which must be a power of two.
+const fn align_down(pos: usize, align: usize) -> usize {
+    pos & !(align - 1)
+}
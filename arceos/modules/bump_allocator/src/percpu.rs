@@ -0,0 +1,143 @@
+//! Per-CPU early arenas, with a shared one as fallback.
+
+use allocator::AllocResult;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::GlobalEarlyAllocator;
+
+/// An early allocator with an arena for each of the `CPUS` harts, so that
+/// they do not contend on a single lock during SMP early boot.
+///
+/// `init` cuts a slice for each hart from the start of the memory, the rest
+/// forms a shared arena. A hart allocates from its own slice, and from the
+/// shared arena when its slice is exhausted. Memory can be freed from any
+/// hart.
+pub struct PerCpuEarlyAllocator<const SIZE: usize, const CPUS: usize> {
+    arenas: [GlobalEarlyAllocator<SIZE>; CPUS],
+    shared: GlobalEarlyAllocator<SIZE>,
+    base: AtomicUsize,
+    slice_size: AtomicUsize,
+}
+
+impl<const SIZE: usize, const CPUS: usize> PerCpuEarlyAllocator<SIZE, CPUS> {
+    /// Creates an empty [`PerCpuEarlyAllocator`].
+    pub const fn new() -> Self {
+        Self {
+            arenas: [const { GlobalEarlyAllocator::new() }; CPUS],
+            shared: GlobalEarlyAllocator::new(),
+            base: AtomicUsize::new(0),
+            slice_size: AtomicUsize::new(0),
+        }
+    }
+
+    /// Initializes the allocator with the given region, giving `slice_size`
+    /// bytes (rounded down to pages) to each hart.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region is too small for all the slices.
+    pub fn init(&self, start: usize, size: usize, slice_size: usize) {
+        let slice_size = slice_size & !(SIZE - 1);
+        let per_cpu = slice_size * CPUS;
+        assert!(per_cpu <= size, "early memory too small for per-CPU arenas");
+        for (i, arena) in self.arenas.iter().enumerate() {
+            arena.init(start + i * slice_size, slice_size);
+        }
+        self.shared.init(start + per_cpu, size - per_cpu);
+        self.slice_size.store(slice_size, Ordering::Relaxed);
+        self.base.store(start, Ordering::Release);
+    }
+
+    /// Adds the given region to the shared arena.
+    pub fn add_memory(&self, start: usize, size: usize) -> AllocResult {
+        self.shared.add_memory(start, size)
+    }
+
+    /// Returns the arena of hart `cpu_id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cpu_id` is not less than `CPUS`.
+    pub fn arena(&self, cpu_id: usize) -> &GlobalEarlyAllocator<SIZE> {
+        &self.arenas[cpu_id]
+    }
+
+    /// Returns the shared arena.
+    pub fn shared(&self) -> &GlobalEarlyAllocator<SIZE> {
+        &self.shared
+    }
+
+    /// Returns the arena which `addr` belongs to.
+    fn owner(&self, addr: usize) -> &GlobalEarlyAllocator<SIZE> {
+        let base = self.base.load(Ordering::Acquire);
+        let slice_size = self.slice_size.load(Ordering::Relaxed);
+        match addr
+            .checked_sub(base)
+            .and_then(|off| off.checked_div(slice_size))
+        {
+            Some(i) if i < CPUS => &self.arenas[i],
+            _ => &self.shared,
+        }
+    }
+
+    /// Allocates bytes on hart `cpu_id`.
+    pub fn alloc(&self, cpu_id: usize, layout: Layout) -> AllocResult<NonNull<u8>> {
+        self.arena(cpu_id)
+            .alloc(layout)
+            .or_else(|_| self.shared.alloc(layout))
+    }
+
+    /// Gives back the bytes allocated at `pos`, on any hart.
+    pub fn dealloc(&self, pos: NonNull<u8>, layout: Layout) {
+        self.owner(pos.as_ptr() as usize).dealloc(pos, layout)
+    }
+
+    /// Allocates contiguous pages on hart `cpu_id`.
+    pub fn alloc_pages(
+        &self,
+        cpu_id: usize,
+        num_pages: usize,
+        align_pow2: usize,
+    ) -> AllocResult<usize> {
+        self.arena(cpu_id)
+            .alloc_pages(num_pages, align_pow2)
+            .or_else(|_| self.shared.alloc_pages(num_pages, align_pow2))
+    }
+
+    /// Gives back the pages allocated at `pos`, on any hart.
+    pub fn dealloc_pages(&self, pos: usize, num_pages: usize) {
+        self.owner(pos).dealloc_pages(pos, num_pages)
+    }
+
+    fn all(&self) -> impl Iterator<Item = &GlobalEarlyAllocator<SIZE>> {
+        self.arenas.iter().chain(core::iter::once(&self.shared))
+    }
+
+    /// Returns the number of allocated bytes in all arenas.
+    pub fn used_bytes(&self) -> usize {
+        self.all().map(|a| a.used_bytes()).sum()
+    }
+
+    /// Returns the number of available bytes in all arenas.
+    pub fn available_bytes(&self) -> usize {
+        self.all().map(|a| a.available_bytes()).sum()
+    }
+
+    /// Returns the number of allocated pages in all arenas.
+    pub fn used_pages(&self) -> usize {
+        self.all().map(|a| a.used_pages()).sum()
+    }
+
+    /// Returns the number of available pages in all arenas.
+    pub fn available_pages(&self) -> usize {
+        self.all().map(|a| a.available_pages()).sum()
+    }
+}
+
+impl<const SIZE: usize, const CPUS: usize> Default for PerCpuEarlyAllocator<SIZE, CPUS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
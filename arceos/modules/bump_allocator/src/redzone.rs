@@ -0,0 +1,170 @@
+//! Red zones around byte allocations, to catch buffer overflows.
+//!
+//! Each allocation is placed in a block laid out as:
+//!
+//! ```text
+//! [ size | data_off | .. | data_off | canary | data | canary | .. ]
+//! ^ start                                    ^ start + data_off
+//! ```
+//!
+//! The blocks are 8-byte aligned and sized, and the gaps between them are
+//! filled with [`GAP`] words, so that the bytes area can be walked from its
+//! start to check all canaries.
+
+use core::alloc::Layout;
+use core::ptr::{read_unaligned, write_unaligned};
+
+use crate::align_up;
+
+/// The value of the 8-byte canaries around each allocation.
+pub const CANARY: u64 = 0x5afe_c0de_5afe_c0de;
+
+/// Fills the gaps between blocks.
+const GAP: usize = usize::MAX;
+
+const SLOT: usize = 8;
+
+/// Returns the layout of the block of an allocation with `layout`, and the
+/// offset of the data in the block.
+pub(crate) fn block_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(SLOT);
+    let data_off = align_up(3 * SLOT, align)?;
+    let size = align_up(
+        data_off.checked_add(layout.size())?.checked_add(SLOT)?,
+        SLOT,
+    )?;
+    Some((Layout::from_size_align(size, align).ok()?, data_off))
+}
+
+unsafe fn read(addr: usize) -> usize {
+    read_unaligned(addr as *const usize)
+}
+
+unsafe fn write(addr: usize, val: usize) {
+    write_unaligned(addr as *mut usize, val)
+}
+
+unsafe fn canary_ok(addr: usize) -> bool {
+    read_unaligned(addr as *const u64) == CANARY
+}
+
+/// Writes the header and canaries of the block at `start`, and fills the gap
+/// from the end of the previous block at `prev_end`. Returns the address of
+/// the data.
+///
+/// # Safety
+///
+/// `[prev_end, start + block size)` must be allocated for `layout`.
+pub(crate) unsafe fn arm(prev_end: usize, start: usize, layout: Layout) -> usize {
+    let (_, data_off) = block_layout(layout).unwrap();
+    for gap in (prev_end..start).step_by(SLOT) {
+        write(gap, GAP);
+    }
+    let data = start + data_off;
+    write(start, layout.size());
+    write(start + SLOT, data_off);
+    write(data - 2 * SLOT, data_off);
+    write_unaligned((data - SLOT) as *mut u64, CANARY);
+    write_unaligned((data + layout.size()) as *mut u64, CANARY);
+    data
+}
+
+/// Checks the canaries around the allocation of `size` bytes at `data`, and
+/// returns the start of its block.
+///
+/// # Panics
+///
+/// Panics if a canary is corrupted.
+///
+/// # Safety
+///
+/// `data` must be allocated by [`arm`] with `size` bytes.
+pub(crate) unsafe fn check(data: usize, size: usize) -> usize {
+    if !canary_ok(data - SLOT) || !canary_ok(data + size) {
+        panic!("red zone corrupted around allocation at {:#x}", data);
+    }
+    data - read(data - 2 * SLOT)
+}
+
+/// Checks the canaries of all blocks in the bytes area `[start, end)`.
+///
+/// # Panics
+///
+/// Panics if a canary or a block header is corrupted.
+///
+/// # Safety
+///
+/// The area must only contain blocks made by [`arm`] and gaps.
+pub(crate) unsafe fn check_all(start: usize, end: usize) {
+    let mut pos = start;
+    while pos < end {
+        let size = read(pos);
+        if size == GAP {
+            pos += SLOT;
+            continue;
+        }
+        let data_off = read(pos + SLOT);
+        let len = data_off
+            .checked_add(size)
+            .and_then(|n| align_up(n.checked_add(SLOT)?, SLOT));
+        match len {
+            Some(len) if data_off >= 3 * SLOT && len <= end - pos => {
+                check(pos + data_off, size);
+                pos += len;
+            }
+            _ => panic!("red zone corrupted at {:#x}", pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use crate::EarlyAllocator;
+    use allocator::{BaseAllocator, ByteAllocator};
+    use core::alloc::Layout;
+
+    #[test]
+    fn test_red_zone() {
+        let mut mem = [0u64; 256];
+        let mut a = EarlyAllocator::<0x1000>::new();
+        a.init(mem.as_mut_ptr() as usize, core::mem::size_of_val(&mem));
+
+        let small = Layout::from_size_align(10, 2).unwrap();
+        let big = Layout::from_size_align(100, 64).unwrap();
+        let p1 = a.alloc(small).unwrap();
+        let p2 = a.alloc(big).unwrap();
+        let p3 = a.alloc(small).unwrap();
+        assert_eq!(p2.as_ptr() as usize % 64, 0);
+        unsafe {
+            p1.as_ptr().write_bytes(1, 10);
+            p2.as_ptr().write_bytes(2, 100);
+        }
+        a.dealloc(p3, small);
+        let p2 = unsafe { a.realloc(p2, big, 200) }.unwrap();
+        assert_eq!(unsafe { *p2.as_ptr().add(99) }, 2);
+        assert_eq!(a.finalize().count(), 1);
+        a.dealloc(p1, small);
+
+        // One byte past the end.
+        unsafe { *p2.as_ptr().add(200) = 0 };
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            a.dealloc(p2, Layout::from_size_align(200, 64).unwrap())
+        }));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "red zone corrupted")]
+    fn test_red_zone_finalize() {
+        let mut mem = [0u64; 64];
+        let mut a = EarlyAllocator::<0x1000>::new();
+        a.init(mem.as_mut_ptr() as usize, core::mem::size_of_val(&mem));
+
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let p = a.alloc(layout).unwrap();
+        unsafe { *p.as_ptr().sub(1) = 0 };
+        a.finalize();
+    }
+}
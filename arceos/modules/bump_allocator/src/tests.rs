@@ -0,0 +1,611 @@
+extern crate std;
+
+use super::*;
+use alloc_stats::test_util::TestMemory;
+
+const PAGE_SIZE: usize = 0x1000;
+const SIZE: usize = 16 * PAGE_SIZE;
+
+/// Creates an allocator on backing memory of three `SIZE` chunks, so that it
+/// can write to what it hands out.
+fn new_allocator() -> (TestMemory, EarlyAllocator<PAGE_SIZE>) {
+    let mem = TestMemory::new(3 * SIZE, SIZE);
+    let mut a = EarlyAllocator::new();
+    a.init(mem.start(), SIZE);
+    (mem, a)
+}
+
+#[test]
+fn test_bytes() {
+    let (mem, mut a) = new_allocator();
+    let start = mem.start();
+    let layout = Layout::from_size_align(10, 8).unwrap();
+    let p1 = a.alloc(layout).unwrap();
+    let p2 = a.alloc(layout).unwrap();
+    assert_eq!(p1.as_ptr() as usize, start);
+    assert_eq!(p2.as_ptr() as usize, start + 16);
+    assert_eq!(a.used_bytes(), 26);
+
+    a.dealloc(p1, layout);
+    assert_eq!(a.used_bytes(), 26);
+    a.dealloc(p2, layout);
+    assert_eq!(a.used_bytes(), 0);
+}
+
+#[test]
+fn test_bytes_lifo() {
+    let (mem, mut a) = new_allocator();
+    let start = mem.start();
+    let small = Layout::from_size_align(8, 8).unwrap();
+    let big = Layout::from_size_align(100, 4).unwrap();
+    let p1 = a.alloc(small).unwrap();
+    let p2 = a.alloc(big).unwrap();
+    let p3 = a.alloc(big).unwrap();
+
+    // Freeing the last one gives its bytes back at once.
+    a.dealloc(p3, big);
+    assert_eq!(a.used_bytes(), 108);
+    let p4 = a.alloc(small).unwrap();
+    assert_eq!(p4.as_ptr() as usize, start + 112);
+
+    // Others are kept until everything is freed.
+    a.dealloc(p2, big);
+    a.dealloc(p1, small);
+    assert_eq!(a.used_bytes(), 120);
+    a.dealloc(p4, small);
+    assert_eq!(a.used_bytes(), 0);
+}
+
+#[test]
+fn test_pages() {
+    let (mem, mut a) = new_allocator();
+    let start = mem.start();
+    let p1 = a.alloc_pages(2, PAGE_SIZE).unwrap();
+    assert_eq!(p1, start + SIZE - 2 * PAGE_SIZE);
+    let p2 = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    assert_eq!(p2, p1 - PAGE_SIZE);
+    assert_eq!(a.used_pages(), 3);
+    assert_eq!(a.available_pages(), 13);
+
+    let big = Layout::from_size_align(13 * PAGE_SIZE + 1, 1).unwrap();
+    assert_eq!(a.alloc(big), Err(AllocError::NoMemory));
+}
+
+#[test]
+fn test_pages_free_list() {
+    let (_mem, mut a) = new_allocator();
+    let p1 = a.alloc_pages(2, PAGE_SIZE).unwrap();
+    let p2 = a.alloc_pages(1, PAGE_SIZE).unwrap();
+
+    // Not next to `p_pos`, kept in the free list and reused.
+    a.dealloc_pages(p1, 2);
+    assert_eq!(a.used_pages(), 1);
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Ok(p1 + PAGE_SIZE));
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Ok(p1));
+
+    // Freeing the lowest pages gives back all adjacent free ones.
+    a.dealloc_pages(p1, 1);
+    a.dealloc_pages(p1 + PAGE_SIZE, 1);
+    a.dealloc_pages(p2, 1);
+    assert_eq!(a.used_pages(), 0);
+    assert_eq!(a.available_pages(), 16);
+}
+
+#[test]
+fn test_pages_align() {
+    let (_mem, mut a) = new_allocator();
+    let p1 = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    let p2 = a.alloc_pages(1, 4 * PAGE_SIZE).unwrap();
+    assert_eq!(p2 % (4 * PAGE_SIZE), 0);
+    // The gap left by the alignment can be used later.
+    assert_eq!(a.alloc_pages(2, PAGE_SIZE), Ok(p2 + PAGE_SIZE));
+    assert_eq!(a.used_pages(), 4);
+    assert!(p1 > p2);
+}
+
+#[test]
+fn test_regions() {
+    let (mem, mut a) = new_allocator();
+    let start = mem.start();
+    let second = start + 2 * SIZE;
+    assert_eq!(
+        a.add_memory(start + SIZE / 2, SIZE),
+        Err(AllocError::MemoryOverlap)
+    );
+    assert_eq!(a.add_memory(second, SIZE), Ok(()));
+    assert_eq!(a.total_pages(), 32);
+
+    // Exhaust the first region, then fall over to the second one.
+    let p1 = a.alloc_pages(16, PAGE_SIZE).unwrap();
+    assert_eq!(p1, start);
+    let p2 = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    assert_eq!(p2, second + SIZE - PAGE_SIZE);
+    let layout = Layout::from_size_align(8, 8).unwrap();
+    let b = a.alloc(layout).unwrap();
+    assert_eq!(b.as_ptr() as usize, second);
+
+    // Each region reclaims its own bytes and pages.
+    a.dealloc(b, layout);
+    a.dealloc_pages(p1, 16);
+    assert_eq!(a.alloc(layout).unwrap().as_ptr() as usize, start);
+    assert_eq!(a.used_pages(), 1);
+}
+
+#[test]
+fn test_realloc() {
+    let mut mem = [0u64; 64];
+    let start = mem.as_mut_ptr() as usize;
+    let mut a = EarlyAllocator::<PAGE_SIZE>::new();
+    a.init(start, core::mem::size_of_val(&mem));
+
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    let p1 = a.alloc(layout).unwrap();
+    unsafe { p1.as_ptr().write_bytes(0x5a, 16) };
+
+    // The last allocation grows in place.
+    let p1 = unsafe { a.realloc(p1, layout, 32) }.unwrap();
+    assert_eq!(p1.as_ptr() as usize, start);
+    assert_eq!(a.used_bytes(), 32);
+
+    // Others are moved.
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let p2 = a.alloc(layout).unwrap();
+    let p1 = unsafe { a.realloc(p1, layout, 48) }.unwrap();
+    assert_eq!(p1.as_ptr() as usize, p2.as_ptr() as usize + 32);
+    assert_eq!(unsafe { *p1.as_ptr().add(15) }, 0x5a);
+}
+
+#[test]
+fn test_stats() {
+    let (_mem, mut a) = new_allocator();
+    let layout = Layout::from_size_align(100, 4).unwrap();
+    let p = a.alloc(layout).unwrap();
+    let pages = a.alloc_pages(4, PAGE_SIZE).unwrap();
+    assert!(a.alloc_pages(16, PAGE_SIZE).is_err());
+    a.dealloc(p, layout);
+    a.dealloc_pages(pages, 4);
+
+    let stats = a.stats();
+    assert_eq!(stats.used_bytes, 0);
+    assert_eq!(stats.peak_bytes, 100);
+    assert_eq!(stats.used_pages, 0);
+    assert_eq!(stats.peak_pages, 4);
+    assert_eq!(stats.byte_allocs, 0);
+    assert_eq!(stats.failed_allocs, 1);
+}
+
+#[cfg(feature = "debug-poison")]
+#[test]
+fn test_poison() {
+    let mut mem = [0u8; 256];
+    let start = mem.as_mut_ptr() as usize;
+    let mut a = EarlyAllocator::<PAGE_SIZE>::new();
+    a.init(start, mem.len());
+
+    let layout = Layout::from_size_align(16, 1).unwrap();
+    let p1 = a.alloc(layout).unwrap();
+    let p2 = a.alloc(layout).unwrap();
+    a.dealloc(p1, layout);
+    a.dealloc(p2, layout);
+    assert!(mem[..32].iter().all(|&b| b == POISON_FREE));
+    assert_eq!(mem[32], 0);
+
+    let p = a.alloc(layout).unwrap();
+    let bytes = unsafe { core::slice::from_raw_parts(p.as_ptr(), 16) };
+    assert!(bytes.iter().all(|&b| b == POISON_ALLOC));
+}
+
+#[test]
+fn test_finalize() {
+    let (mem, mut a) = new_allocator();
+    let start = mem.start();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let b = a.alloc(layout).unwrap();
+    let p1 = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    let p2 = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    a.dealloc_pages(p1, 1);
+
+    let unused: std::vec::Vec<_> = a.finalize().collect();
+    assert_eq!(unused, [(start + 64, p2 - start - 64), (p1, PAGE_SIZE)]);
+    assert!(a.is_sealed());
+    assert_eq!(a.available_bytes(), 0);
+    assert_eq!(a.available_pages(), 0);
+    assert_eq!(a.used_pages(), 1);
+    assert_eq!(a.alloc(layout), Err(AllocError::NotAllocated));
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Err(AllocError::NotAllocated));
+    assert_eq!(a.finalize().count(), 0);
+
+    // Early allocations can still be freed.
+    a.dealloc(b, layout);
+    a.dealloc_pages(p2, 1);
+    assert_eq!(a.used_bytes(), 0);
+    assert_eq!(a.used_pages(), 0);
+}
+
+#[test]
+fn test_alloc_zeroed() {
+    let mut mem = [0u8; 256];
+    let start = mem.as_mut_ptr() as usize;
+    let mut a = EarlyAllocator::<PAGE_SIZE>::new();
+    a.init(start, mem.len());
+
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let p = a.alloc(layout).unwrap();
+    unsafe { p.as_ptr().write_bytes(0xff, 32) };
+    a.dealloc(p, layout);
+
+    // Only the bytes handed out before are cleared, the fresh ones are
+    // assumed to be zero already.
+    mem[40] = 0x11;
+    let layout = Layout::from_size_align(48, 8).unwrap();
+    let p = a.alloc_zeroed(layout).unwrap();
+    assert_eq!(p.as_ptr() as usize, start);
+    assert!(mem[..32].iter().all(|&b| b == 0));
+    assert_eq!(mem[40], 0x11);
+}
+
+#[test]
+fn test_invalid_param() {
+    let (_mem, mut a) = new_allocator();
+    let empty = Layout::from_size_align(0, 8).unwrap();
+    assert_eq!(a.alloc(empty), Err(AllocError::InvalidParam));
+    assert_eq!(a.alloc_zeroed(empty), Err(AllocError::InvalidParam));
+    assert_eq!(
+        a.alloc_pages(1, 3 * PAGE_SIZE),
+        Err(AllocError::InvalidParam)
+    );
+    assert_eq!(a.alloc_pages(0, PAGE_SIZE), Err(AllocError::InvalidParam));
+    assert_eq!(
+        a.alloc_pages(usize::MAX, PAGE_SIZE),
+        Err(AllocError::NoMemory)
+    );
+
+    let huge = Layout::from_size_align(8, 1 << (usize::BITS - 2)).unwrap();
+    assert_eq!(a.alloc(huge), Err(AllocError::NoMemory));
+    assert_eq!(a.used_bytes(), 0);
+    assert_eq!(a.used_pages(), 0);
+}
+
+#[test]
+fn test_runtime_page_size() {
+    const PAGE_16K: usize = 4 * PAGE_SIZE;
+    let mem = TestMemory::new(3 * SIZE, SIZE);
+    let start = mem.start();
+    let mut a = DynPageEarlyAllocator::new();
+    a.init(start, SIZE);
+    assert_eq!(a.alloc_pages(1, PAGE_16K), Err(AllocError::InvalidParam));
+
+    a.init_with_page_size(start, SIZE, PAGE_16K);
+    assert_eq!(a.page_size(), PAGE_16K);
+    assert_eq!(a.total_pages(), 4);
+    let p = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    assert_eq!(p, start + SIZE - PAGE_16K);
+    assert_eq!(a.available_pages(), 3);
+    a.dealloc_pages(p, 1);
+    assert_eq!(a.used_pages(), 0);
+}
+
+#[test]
+fn test_mark_reset() {
+    let (mem, mut a) = new_allocator();
+    let start = mem.start();
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let keep = a.alloc(layout).unwrap();
+    let mark = a.mark();
+    for _ in 0..4 {
+        a.alloc(layout).unwrap();
+    }
+    let pages = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    assert_eq!(a.used_bytes(), 5 * 32);
+
+    a.reset_to(&mark);
+    assert_eq!(a.used_bytes(), 32);
+    assert_eq!(a.used_pages(), 1);
+    assert_eq!(a.alloc(layout).unwrap().as_ptr() as usize, start + 32);
+
+    // Resetting again after everything is freed does nothing.
+    a.reset_to(&mark);
+    a.dealloc(keep, layout);
+    a.dealloc_pages(pages, 1);
+    a.reset_to(&mark);
+    assert_eq!(a.used_bytes(), 0);
+    assert_eq!(a.used_pages(), 0);
+}
+
+#[test]
+fn test_release_unused() {
+    let (mem, mut a) = new_allocator();
+    let start = mem.start();
+    let p = a.alloc(Layout::from_size_align(100, 8).unwrap()).unwrap();
+    let pages = a.alloc_pages(2, PAGE_SIZE).unwrap();
+    let released: std::vec::Vec<_> = a.release_unused().collect();
+    assert_eq!(released, [(start + PAGE_SIZE, SIZE - 3 * PAGE_SIZE)]);
+    assert_eq!(a.total_bytes(), 3 * PAGE_SIZE);
+    assert_eq!(a.available_bytes(), PAGE_SIZE - 100);
+    assert_eq!(a.release_unused().count(), 0);
+
+    // Both parts still work, without reaching into the released range.
+    let layout = Layout::from_size_align(PAGE_SIZE - 100, 1).unwrap();
+    assert!(a.alloc(layout).is_ok());
+    assert_eq!(a.alloc(layout), Err(AllocError::NoMemory));
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Err(AllocError::NoMemory));
+    a.dealloc_pages(pages, 2);
+    assert_eq!(a.alloc_pages(2, PAGE_SIZE), Ok(pages));
+    a.dealloc(p, Layout::from_size_align(100, 8).unwrap());
+    assert_eq!(a.used_pages(), 2);
+}
+
+#[test]
+fn test_iter_regions() {
+    let (mem, mut a) = new_allocator();
+    let start = mem.start();
+    let more = TestMemory::new(3 * SIZE, SIZE);
+    a.add_memory(more.start(), PAGE_SIZE).unwrap();
+    a.alloc(Layout::from_size_align(100, 8).unwrap()).unwrap();
+    let p = a.alloc_pages(1, PAGE_SIZE).unwrap();
+    a.alloc_pages(2, PAGE_SIZE).unwrap();
+    a.alloc_pages(1, PAGE_SIZE).unwrap();
+    a.dealloc_pages(p, 1);
+    a.dealloc_pages(p - 2 * PAGE_SIZE, 1);
+
+    let end = start + SIZE;
+    let map: std::vec::Vec<_> = a.iter_regions().collect();
+    assert_eq!(
+        map,
+        [
+            (start, 100, RegionKind::BytesUsed),
+            (start + 100, SIZE - 4 * PAGE_SIZE - 100, RegionKind::Free),
+            (end - 4 * PAGE_SIZE, PAGE_SIZE, RegionKind::PagesUsed),
+            (end - 3 * PAGE_SIZE, PAGE_SIZE, RegionKind::Free),
+            (end - 2 * PAGE_SIZE, PAGE_SIZE, RegionKind::PagesUsed),
+            (end - PAGE_SIZE, PAGE_SIZE, RegionKind::Free),
+            (more.start(), PAGE_SIZE, RegionKind::Free),
+        ]
+    );
+}
+
+#[test]
+fn test_memory_stats() {
+    let (_mem, mut a) = new_allocator();
+    let more = TestMemory::new(3 * SIZE, SIZE);
+    a.add_memory(more.start(), PAGE_SIZE).unwrap();
+    assert_eq!(a.free_block_count(), 2);
+    assert_eq!(a.largest_free_block(), SIZE);
+
+    a.alloc(Layout::from_size_align(PAGE_SIZE, 8).unwrap())
+        .unwrap();
+    let p = a.alloc_pages(2, PAGE_SIZE).unwrap();
+    a.alloc_pages(1, PAGE_SIZE).unwrap();
+    a.dealloc_pages(p, 2);
+    let stats = a.memory_stats();
+    assert_eq!(stats.free_blocks, 3);
+    assert_eq!(stats.free_bytes, SIZE - PAGE_SIZE);
+    assert_eq!(stats.largest_free_block, SIZE - 4 * PAGE_SIZE);
+    assert_eq!(stats.fragmentation_percent(), 20);
+
+    a.finalize();
+    assert_eq!(a.memory_stats(), MemoryStats::default());
+}
+
+#[test]
+fn test_global() {
+    static GLOBAL: GlobalEarlyAllocator<PAGE_SIZE> = GlobalEarlyAllocator::new();
+
+    fn churn(mut a: impl ByteAllocator + PageAllocator) {
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        for _ in 0..100 {
+            let p = a.alloc(layout).unwrap();
+            let pages = a.alloc_pages(1, PAGE_SIZE).unwrap();
+            a.dealloc_pages(pages, 1);
+            a.dealloc(p, layout);
+        }
+    }
+
+    let mem = TestMemory::new(3 * SIZE, SIZE);
+    GLOBAL.init(mem.start(), SIZE);
+    let threads: std::vec::Vec<_> = (0..4)
+        .map(|_| std::thread::spawn(|| churn(&GLOBAL)))
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(GLOBAL.used_bytes(), 0);
+    assert_eq!(GLOBAL.used_pages(), 0);
+}
+
+#[test]
+fn test_early_global_alloc() {
+    use core::alloc::GlobalAlloc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::alloc::System;
+
+    /// The formal allocator, counting its live allocations.
+    struct Formal(AtomicUsize);
+
+    unsafe impl GlobalAlloc for Formal {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    static FORMAL: Formal = Formal(AtomicUsize::new(0));
+    static HEAP: EarlyGlobalAlloc<PAGE_SIZE, Formal> = EarlyGlobalAlloc::new(&FORMAL);
+
+    let mem = TestMemory::new(3 * SIZE, SIZE);
+    HEAP.init(mem.start(), SIZE);
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let early = unsafe { HEAP.alloc(layout) };
+    assert_eq!(early as usize, mem.start());
+    assert_eq!(FORMAL.0.load(Ordering::Relaxed), 0);
+
+    let unused: std::vec::Vec<_> = HEAP.switch_over().collect();
+    assert!(HEAP.is_switched());
+    assert_eq!(unused, [(mem.start() + 32, SIZE - 32)]);
+    let formal = unsafe { HEAP.alloc(layout) };
+    assert_eq!(FORMAL.0.load(Ordering::Relaxed), 1);
+
+    // Each pointer goes back to the allocator it came from.
+    unsafe {
+        HEAP.dealloc(formal, layout);
+        HEAP.dealloc(early, layout);
+    }
+    assert_eq!(FORMAL.0.load(Ordering::Relaxed), 0);
+    assert_eq!(HEAP.early().used_bytes(), 0);
+}
+
+#[test]
+fn test_percpu() {
+    let mem = TestMemory::new(3 * SIZE, SIZE);
+    let start = mem.start();
+    let a = PerCpuEarlyAllocator::<PAGE_SIZE, 2>::new();
+    a.init(start, SIZE, 4 * PAGE_SIZE);
+    assert_eq!(a.available_pages(), 16);
+
+    // Each hart allocates from its own slice.
+    let p0 = a.alloc_pages(0, 4, PAGE_SIZE).unwrap();
+    let p1 = a.alloc_pages(1, 1, PAGE_SIZE).unwrap();
+    assert_eq!(p0, start);
+    assert_eq!(p1, start + 7 * PAGE_SIZE);
+
+    // Falls over to the shared arena when the slice is exhausted.
+    let layout = Layout::from_size_align(8, 8).unwrap();
+    let b = a.alloc(0, layout).unwrap();
+    assert_eq!(b.as_ptr() as usize, start + 8 * PAGE_SIZE);
+    assert_eq!(a.shared().used_bytes(), 8);
+
+    // Freed on another hart.
+    a.dealloc(b, layout);
+    a.dealloc_pages(p0, 4);
+    a.dealloc_pages(p1, 1);
+    assert_eq!(a.used_bytes(), 0);
+    assert_eq!(a.used_pages(), 0);
+}
+
+#[test]
+fn test_trace_hook() {
+    use std::sync::Mutex;
+    static EVENTS: Mutex<std::vec::Vec<AllocEvent>> = Mutex::new(std::vec::Vec::new());
+    fn hook(event: AllocEvent) {
+        EVENTS.lock().unwrap().push(event);
+    }
+
+    let (mem, mut a) = new_allocator();
+    let start = mem.start();
+    a.set_trace_hook(Some(hook));
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let p = a.alloc(layout).unwrap();
+    a.dealloc(p, layout);
+    let pages = a.alloc_pages(2, PAGE_SIZE).unwrap();
+    a.dealloc_pages(pages, 2);
+    assert!(a.alloc_pages(32, PAGE_SIZE).is_err());
+    a.set_trace_hook(None);
+    a.alloc(layout).unwrap();
+
+    assert_eq!(
+        *EVENTS.lock().unwrap(),
+        [
+            AllocEvent::Alloc {
+                pos: start,
+                size: 24,
+                align: 8
+            },
+            AllocEvent::Dealloc {
+                pos: start,
+                size: 24
+            },
+            AllocEvent::AllocPages {
+                pos: pages,
+                num_pages: 2
+            },
+            AllocEvent::DeallocPages {
+                pos: pages,
+                num_pages: 2
+            },
+            AllocEvent::Failed {
+                size: 32 * PAGE_SIZE,
+                align: PAGE_SIZE
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_pages_at() {
+    let (mem, mut a) = new_allocator();
+    let start = mem.start();
+    let layout = Layout::from_size_align(8, 8).unwrap();
+    a.alloc(layout).unwrap();
+    assert_eq!(a.alloc_pages_at(start, 1), Err(AllocError::NoMemory));
+    assert_eq!(
+        a.alloc_pages_at(start + 1, 1),
+        Err(AllocError::InvalidParam)
+    );
+
+    // In the middle of the available area, the pages above are kept.
+    let base = start + 8 * PAGE_SIZE;
+    assert_eq!(a.alloc_pages_at(base, 2), Ok(base));
+    assert_eq!(
+        a.alloc_pages_at(base + PAGE_SIZE, 1),
+        Err(AllocError::NoMemory)
+    );
+    assert_eq!(a.used_pages(), 2);
+    assert_eq!(
+        a.alloc_pages_at(base + 3 * PAGE_SIZE, 1),
+        Ok(base + 3 * PAGE_SIZE)
+    );
+    assert_eq!(a.used_pages(), 3);
+
+    a.dealloc_pages(base, 2);
+    a.dealloc_pages(base + 3 * PAGE_SIZE, 1);
+    assert_eq!(a.used_pages(), 0);
+    assert_eq!(a.available_pages(), 15);
+}
+
+#[cfg(feature = "fail-alloc")]
+#[test]
+fn test_fail_policy() {
+    let (mem, mut a) = new_allocator();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    a.set_fail_policy(FailPolicy::never().fail_nth(2));
+    assert!(a.alloc(layout).is_ok());
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Err(AllocError::NoMemory));
+    assert!(a.alloc(layout).is_ok());
+    assert_eq!(a.stats().failed_allocs, 1);
+
+    a.set_fail_policy(FailPolicy::never().fail_above(2 * PAGE_SIZE));
+    assert!(a.alloc_pages(1, PAGE_SIZE).is_ok());
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Err(AllocError::NoMemory));
+    assert_eq!(
+        a.alloc_pages_at(mem.start() + 4 * PAGE_SIZE, 1),
+        Err(AllocError::NoMemory)
+    );
+    assert!(a.alloc(layout).is_ok());
+
+    a.set_fail_policy(FailPolicy::never());
+    assert!(a.alloc_pages(1, PAGE_SIZE).is_ok());
+}
+
+#[cfg(feature = "fail-alloc")]
+#[test]
+fn test_fail_injector() {
+    let (_mem, a) = new_allocator();
+    let mut a = FailInjector::new(a, FailPolicy::never().fail_nth(3));
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    let p = a.alloc(layout).unwrap();
+    a.dealloc(p, layout);
+    assert!(a.alloc_pages(1, PAGE_SIZE).is_ok());
+    assert_eq!(a.alloc(layout), Err(AllocError::NoMemory));
+    assert_eq!(a.inner().stats().failed_allocs, 0);
+    assert_eq!(a.policy().count(), 3);
+
+    a.set_policy(FailPolicy::never().fail_above(PAGE_SIZE));
+    assert_eq!(a.alloc_pages(1, PAGE_SIZE), Err(AllocError::NoMemory));
+    assert_eq!(a.into_inner().used_pages(), 1);
+}
@@ -0,0 +1,79 @@
+//! Decoding of the guest accesses to its IMSIC interrupt file.
+//!
+//! With the AIA of the host, the guest accesses its IMSIC through `vsireg`
+//! and `vstopei`. No guest interrupt file of the hart is assigned to it
+//! (`hstatus.VGEIN` is 0), so these accesses trap as virtual instructions,
+//! and the interrupt file is emulated by the hypervisor.
+
+use crate::regs::GprIndex;
+
+/// The first register of the IMSIC interrupt file selected by `vsiselect`,
+/// `eidelivery`.
+pub const IMSIC_ISELECT_FIRST: usize = 0x70;
+/// The last register of the IMSIC interrupt file selected by `vsiselect`,
+/// the last `eie` one.
+pub const IMSIC_ISELECT_LAST: usize = 0xff;
+
+/// The register of the IMSIC interrupt file accessed by the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImsicReg {
+    /// The register selected by `vsiselect`, accessed through `vsireg`, e.g.
+    /// `eidelivery` at 0x70 or `eip0` at 0x80.
+    Indirect(usize),
+    /// `vstopei`, the top pending and enabled interrupt, claimed by a write.
+    Topei,
+}
+
+/// A guest access to its IMSIC interrupt file, returned with
+/// [`AxVCpuExitReason::ImsicAccess`] and completed by
+/// [`RISCVVCpu::complete_imsic_access`].
+///
+/// [`AxVCpuExitReason::ImsicAccess`]: crate::AxVCpuExitReason::ImsicAccess
+/// [`RISCVVCpu::complete_imsic_access`]: crate::RISCVVCpu::complete_imsic_access
+#[derive(Debug, Clone, Copy)]
+pub struct ImsicAccess {
+    /// The register accessed.
+    pub reg: ImsicReg,
+    /// `Some((mask, bits))` if the CSR instruction writes the register: it
+    /// becomes `old & !mask | bits & mask`, see [`new_value`](Self::new_value).
+    pub write: Option<(usize, usize)>,
+    /// The register getting the old value.
+    pub(crate) rd: GprIndex,
+}
+
+impl ImsicAccess {
+    /// Returns the value of the register after the access, from its value
+    /// `old` before.
+    pub fn new_value(&self, old: usize) -> usize {
+        match self.write {
+            Some((mask, bits)) => old & !mask | bits & mask,
+            None => old,
+        }
+    }
+}
+
+/// Decodes `inst`, a CSR instruction accessing `reg`, with `gpr` returning
+/// the value of its source register.
+pub(crate) fn decode(
+    inst: usize,
+    reg: ImsicReg,
+    gpr: impl FnOnce(GprIndex) -> usize,
+) -> ImsicAccess {
+    let funct3 = (inst >> 12) & 0x7;
+    let rs1 = ((inst >> 15) & 0x1f) as u32;
+    let rd = GprIndex::from_raw(((inst >> 7) & 0x1f) as u32).unwrap();
+    // `csrrwi`, `csrrsi` and `csrrci` take `rs1` as an immediate.
+    let src = if funct3 & 0x4 != 0 {
+        rs1 as usize
+    } else {
+        gpr(GprIndex::from_raw(rs1).unwrap())
+    };
+    // `csrrs` and `csrrc` do not write with `x0` or a zero immediate.
+    let write = match funct3 & 0x3 {
+        1 => Some((usize::MAX, src)),
+        2 if rs1 != 0 => Some((src, usize::MAX)),
+        3 if rs1 != 0 => Some((src, 0)),
+        _ => None,
+    };
+    ImsicAccess { reg, write, rd }
+}
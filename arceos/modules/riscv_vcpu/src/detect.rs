@@ -2,7 +2,9 @@
 //!
 //! First, it disables all S-level interrupts. Remaining traps in RISC-V core
 //! are all exceptions.
-//! Then, it filters out illegal instruction from exceptions.
+//! Then, it filters out illegal instruction from exceptions. The faults of
+//! the guest memory accesses, see the `guest_mem` module, are filtered out
+//! too.
 //! ref: https://github.com/luojia65/zihai/blob/main/zihai/src/detect.rs
 
 use core::arch::asm;
@@ -24,12 +26,42 @@ pub fn detect_h_extension() -> bool {
     ans != 2
 }
 
+// Detect if the Sstc extension exists and is enabled for S-mode by the firmware
+//
+// This function tries to read stimecmp and returns false if the read operation failed.
+pub fn detect_sstc_extension() -> bool {
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0x14d", out(reg) _, options(nomem, nostack)); // 0x14d => stimecmp
+    });
+    ans != 2
+}
+
+// Detect if the Ssaia extension exists on current hart environment
+//
+// This function tries to read stopi and returns false if the read operation failed.
+pub fn detect_ssaia_extension() -> bool {
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0xdb0", out(reg) _, options(nomem, nostack)); // 0xdb0 => stopi
+    });
+    ans != 2
+}
+
+// Detect if the Smstateen extension exists and is enabled for S-mode by the firmware
+//
+// This function tries to read hstateen0 and returns false if the read operation failed.
+pub fn detect_smstateen_extension() -> bool {
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0x60c", out(reg) _, options(nomem, nostack)); // 0x60c => hstateen0
+    });
+    ans != 2
+}
+
 // Tries to execute all instructions defined in clojure `f`.
 // If resulted in an exception, this function returns its exception id.
 //
 // This function is useful to detect if an instruction exists on current environment.
 #[inline]
-fn with_detect_trap(param: usize, f: impl FnOnce()) -> usize {
+pub(crate) fn with_detect_trap(param: usize, f: impl FnOnce()) -> usize {
     // disable interrupts and handle exceptions only
     let (sie, stvec, tp) = unsafe { init_detect_trap(param) };
     // run detection inner
@@ -54,6 +86,15 @@ extern "C" fn rust_detect_trap(trap_frame: &mut TrapFrame) {
             // skip current instruction
             trap_frame.sepc = trap_frame.sepc.wrapping_add(insn_bits);
         }
+        // a faulting `hlv`/`hsv`, skip it
+        Trap::Exception(
+            Exception::LoadFault
+            | Exception::StoreFault
+            | Exception::LoadPageFault
+            | Exception::StorePageFault
+            | Exception::LoadGuestPageFault
+            | Exception::StoreGuestPageFault,
+        ) => trap_frame.sepc = trap_frame.sepc.wrapping_add(4),
         Trap::Exception(_) => unreachable!(), // FIXME: unexpected instruction errors
         Trap::Interrupt(_) => unreachable!(), // filtered out for sie == false
     }
@@ -0,0 +1,121 @@
+//! Byte accesses to the guest memory at guest virtual addresses, through both
+//! stages of the address translation of the vCPU run on the current hart.
+//!
+//! A faulting access does not trap to the host: its `scause` is returned, so
+//! the caller can map the page or forward the fault to the guest.
+
+use core::arch::asm;
+
+use crate::csrs::defs::CSR_VSATP;
+use crate::detect::with_detect_trap;
+
+/// The exception codes of the faults returned.
+pub(crate) const LOAD_PAGE_FAULT: usize = 13;
+pub(crate) const STORE_PAGE_FAULT: usize = 15;
+pub(crate) const LOAD_GUEST_PAGE_FAULT: usize = 21;
+pub(crate) const STORE_GUEST_PAGE_FAULT: usize = 23;
+
+/// The fields of `vsatp`, and the walk of the VS-stage page table.
+const SATP_MODE_SHIFT: usize = 60;
+const SATP_MODE_BARE: usize = 0;
+const SATP_MODE_SV39: usize = 8;
+const SATP_MODE_SV57: usize = 10;
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+const PTE_V: usize = 1 << 0;
+const PTE_RWX: usize = 0b1110;
+const PTE_PPN_SHIFT: usize = 10;
+const LEVEL_BITS: usize = 9;
+
+/// Loads the byte at `gva`, or returns the `scause` of the fault.
+pub(crate) fn load_byte(gva: usize) -> Result<u8, usize> {
+    let mut val: usize = 0;
+    let scause = with_detect_trap(0, || unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "hlv.bu {val}, ({gva})",
+            ".option pop",
+            val = inout(reg) val,
+            gva = in(reg) gva,
+            options(nostack),
+        );
+    });
+    match scause {
+        0 => Ok(val as u8),
+        scause => Err(scause),
+    }
+}
+
+/// Stores `val` at `gva`, or returns the `scause` of the fault.
+pub(crate) fn store_byte(gva: usize, val: u8) -> Result<(), usize> {
+    let scause = with_detect_trap(0, || unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "hsv.b {val}, ({gva})",
+            ".option pop",
+            val = in(reg) val as usize,
+            gva = in(reg) gva,
+            options(nostack),
+        );
+    });
+    match scause {
+        0 => Ok(()),
+        scause => Err(scause),
+    }
+}
+
+/// Runs `f` with the VS-stage translation off, so the guest virtual addresses it accesses are
+/// guest physical ones.
+fn with_bare_vsatp<T>(f: impl FnOnce() -> T) -> T {
+    let vsatp: usize;
+    unsafe { asm!("csrrw {0}, {csr}, zero", out(reg) vsatp, csr = const CSR_VSATP) };
+    let res = f();
+    unsafe { asm!("csrw {csr}, {0}", in(reg) vsatp, csr = const CSR_VSATP) };
+    res
+}
+
+/// Translates `gva` with the VS-stage page table of the guest, or returns the `scause` of
+/// the fault, `LOAD_PAGE_FAULT` if `gva` is not mapped.
+pub(crate) fn translate(gva: usize) -> Result<usize, usize> {
+    let vsatp: usize;
+    unsafe { asm!("csrr {0}, {csr}", out(reg) vsatp, csr = const CSR_VSATP) };
+    let levels = match vsatp >> SATP_MODE_SHIFT {
+        SATP_MODE_BARE => return Ok(gva),
+        // Sv39, Sv48 and Sv57 have 3, 4 and 5 levels.
+        mode @ SATP_MODE_SV39..=SATP_MODE_SV57 => mode - 5,
+        _ => return Err(LOAD_PAGE_FAULT),
+    };
+    let mut table = (vsatp & SATP_PPN_MASK) << 12;
+    with_bare_vsatp(|| {
+        for level in (0..levels).rev() {
+            let shift = 12 + level * LEVEL_BITS;
+            let pte_addr = table + ((gva >> shift) & ((1 << LEVEL_BITS) - 1)) * 8;
+            let pte = (0..8).try_fold(0, |pte, i| {
+                load_byte(pte_addr + i).map(|byte| pte | (byte as usize) << (i * 8))
+            })?;
+            if pte & PTE_V == 0 {
+                break;
+            }
+            let addr = (pte >> PTE_PPN_SHIFT & SATP_PPN_MASK) << 12;
+            // A leaf, maybe a superpage.
+            if pte & PTE_RWX != 0 {
+                let offset_mask = (1 << shift) - 1;
+                return Ok(addr & !offset_mask | gva & offset_mask);
+            }
+            table = addr;
+        }
+        Err(LOAD_PAGE_FAULT)
+    })
+}
+
+/// Loads the byte at the guest physical address `gpa`, or returns the `scause` of the fault.
+pub(crate) fn load_phys_byte(gpa: usize) -> Result<u8, usize> {
+    with_bare_vsatp(|| load_byte(gpa))
+}
+
+/// Stores `val` at the guest physical address `gpa`, whatever the permissions of the guest
+/// page table, or returns the `scause` of the fault.
+pub(crate) fn store_phys_byte(gpa: usize, val: u8) -> Result<(), usize> {
+    with_bare_vsatp(|| store_byte(gpa, val))
+}
@@ -8,17 +8,43 @@
 #[macro_use]
 extern crate log;
 
+mod aia;
 pub mod csrs;
 mod detect;
+mod guest_mem;
+mod mmio;
 mod regs;
 pub mod sbi;
+mod step;
 mod vcpu;
+mod vpmu;
 
+pub use self::aia::{ImsicAccess, ImsicReg, IMSIC_ISELECT_FIRST, IMSIC_ISELECT_LAST};
+pub use self::mmio::{MmioAccess, MmioOp};
+pub use self::regs::GprIndex;
 pub use self::vcpu::RISCVVCpu;
 pub use detect::detect_h_extension as has_hardware_support;
-pub use vcpu::AxVCpuExitReason;
+pub use detect::detect_ssaia_extension as has_aia_support;
+pub use vcpu::{AccessWidth, AxVCpuExitReason, IrqKind, VCpuState, VmCpuTrapState};
+pub use vpmu::PmuEvents;
+use core::sync::atomic::{AtomicBool, Ordering};
 use csrs::{traps, CSR, RiscvCsrTrait};
 
+/// The `STCE` bit of `henvcfg`, letting the guest use `vstimecmp`.
+const HENVCFG_STCE: usize = 1 << 63;
+/// The `CY` and `IR` bits of `hcounteren`, letting the guest read `cycle` and `instret`.
+const HCOUNTEREN_CY: usize = 1 << 0;
+const HCOUNTEREN_IR: usize = 1 << 2;
+/// The `SPVP` bit of `hstatus`, the privilege of the hypervisor accesses to the guest memory.
+pub(crate) const HSTATUS_SPVP: usize = 1 << 8;
+/// The `AIA` and `CSRIND` bits of `hstateen0`, letting the guest access the AIA CSRs but the
+/// ones of the IMSIC.
+const HSTATEEN0_AIA: usize = 1 << 59;
+const HSTATEEN0_CSRIND: usize = 1 << 60;
+
+static HAS_SSTC: AtomicBool = AtomicBool::new(false);
+static HAS_AIA: AtomicBool = AtomicBool::new(false);
+
 pub struct RISCVPerCpu {}
 
 /// Initialize (H)S-level CSRs to a reasonable state.
@@ -48,8 +74,35 @@ pub unsafe fn setup_csrs() {
             | traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
     );
 
-    // clear all interrupts.
-    CSR.hcounteren.write_value(0xffff_ffff);
+    // Let the guest read the counters, but `cycle` and `instret`, which are emulated.
+    CSR.hcounteren.write_value(0xffff_ffff & !(HCOUNTEREN_CY | HCOUNTEREN_IR));
+
+    // Access the guest memory with the supervisor privilege, e.g. to fetch trapped instructions.
+    CSR.hstatus.read_and_set_bits(HSTATUS_SPVP);
+
+    // With Sstc, the guest programs its timer in `vstimecmp`, and VSTIP is raised by the
+    // hardware. Otherwise the timer is emulated with SBI `set_timer` and `hvip`.
+    let sstc = detect::detect_sstc_extension();
+    HAS_SSTC.store(sstc, Ordering::Relaxed);
+    if sstc {
+        core::arch::asm!(
+            "csrs 0x60a, {stce}", // henvcfg
+            "csrw 0x24d, {max}",  // vstimecmp
+            stce = in(reg) HENVCFG_STCE,
+            max = in(reg) usize::MAX,
+        );
+    }
+
+    // With Ssaia, the guest may use the AIA CSRs. No guest interrupt file is assigned to it,
+    // so its accesses to the IMSIC trap, to be emulated.
+    let aia = detect::detect_ssaia_extension();
+    HAS_AIA.store(aia, Ordering::Relaxed);
+    if aia && detect::detect_smstateen_extension() {
+        core::arch::asm!(
+            "csrs 0x60c, {bits}", // hstateen0
+            bits = in(reg) HSTATEEN0_AIA | HSTATEEN0_CSRIND,
+        );
+    }
 
     // enable interrupt
     CSR.sie.write_value(
@@ -59,3 +112,33 @@ pub unsafe fn setup_csrs() {
     );
     debug!("sie: {:#x}", CSR.sie.get_value());
 }
+
+/// Whether guest timers use the Sstc extension, as detected by [`setup_csrs`].
+pub fn has_sstc() -> bool {
+    HAS_SSTC.load(Ordering::Relaxed)
+}
+
+/// Whether the guests may use the AIA, the host having Ssaia, as detected by [`setup_csrs`].
+/// Their IMSIC interrupt files are then emulated, see [`AxVCpuExitReason::ImsicAccess`].
+pub fn has_aia() -> bool {
+    HAS_AIA.load(Ordering::Relaxed)
+}
+
+/// Returns the number of VMID bits supported by the current hart.
+///
+/// VMIDs that do not fit are truncated by the hardware, so VMs sharing a hart would see each
+/// other's G-stage translations.
+pub fn vmid_bits() -> usize {
+    let vmid: usize;
+    unsafe {
+        // Write all ones to the VMID field of a bare `hgatp`, and see which bits stick.
+        core::arch::asm!(
+            "csrrw {old}, hgatp, {probe}",
+            "csrr {probe}, hgatp",
+            "csrw hgatp, {old}",
+            old = out(reg) _,
+            probe = inout(reg) 0x3fffusize << 44 => vmid,
+        );
+    }
+    (vmid >> 44).count_ones() as usize
+}
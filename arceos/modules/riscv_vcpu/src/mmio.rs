@@ -0,0 +1,158 @@
+//! Decoding of the guest loads and stores that fault on emulated devices.
+
+use axerrno::{ax_err, AxResult};
+
+use crate::regs::GprIndex;
+use crate::vcpu::{AccessWidth, GuestPhysAddr};
+
+const OPCODE_LOAD: u32 = 0b000_0011;
+const OPCODE_STORE: u32 = 0b010_0011;
+
+/// What a faulting guest access does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioOp {
+    /// A load into `reg`, sign-extended if `signed`.
+    Read { reg: GprIndex, signed: bool },
+    /// A store of `data`, truncated to the access width.
+    Write { data: u64 },
+}
+
+/// A guest MMIO access, decoded by [`RISCVVCpu::decode_mmio`] and completed
+/// by [`RISCVVCpu::complete_mmio`].
+///
+/// [`RISCVVCpu::decode_mmio`]: crate::RISCVVCpu::decode_mmio
+/// [`RISCVVCpu::complete_mmio`]: crate::RISCVVCpu::complete_mmio
+#[derive(Debug, Clone, Copy)]
+pub struct MmioAccess {
+    /// The guest physical address accessed.
+    pub addr: GuestPhysAddr,
+    /// The width of the access.
+    pub width: AccessWidth,
+    /// Whether it is a load or a store.
+    pub op: MmioOp,
+    /// The length of the instruction, to move `sepc` past it.
+    pub(crate) insn_len: usize,
+}
+
+/// A decoded load or store, before the store data is read.
+pub(crate) struct LoadStore {
+    pub width: AccessWidth,
+    /// `Ok((rd, signed))` for a load, `Err(rs2)` for a store.
+    pub reg: Result<(GprIndex, bool), GprIndex>,
+    pub insn_len: usize,
+}
+
+impl AccessWidth {
+    /// Returns the number of bytes accessed.
+    pub const fn size(self) -> usize {
+        match self {
+            AccessWidth::Byte => 1,
+            AccessWidth::Word => 2,
+            AccessWidth::Dword => 4,
+            AccessWidth::Qword => 8,
+        }
+    }
+
+    /// Truncates `val` to the width, sign-extending it if `signed`.
+    pub const fn extend(self, val: u64, signed: bool) -> u64 {
+        let shift = 64 - self.size() as u32 * 8;
+        if signed {
+            ((val << shift) as i64 >> shift) as u64
+        } else {
+            val << shift >> shift
+        }
+    }
+
+    fn from_funct3(funct3: u32) -> Self {
+        match funct3 & 0b11 {
+            0 => AccessWidth::Byte,
+            1 => AccessWidth::Word,
+            2 => AccessWidth::Dword,
+            _ => AccessWidth::Qword,
+        }
+    }
+}
+
+/// Decodes the load or store that trapped with the transformed instruction
+/// `htinst`, or at the guest virtual address `sepc` if the hart did not
+/// provide it.
+pub(crate) fn decode_trapped(htinst: usize, sepc: usize) -> AxResult<LoadStore> {
+    let htinst = htinst as u32;
+    // Bit 0 is set for a transformed instruction, bit 1 tells whether the
+    // trapping one was 32-bit or compressed. Pseudo-instructions for the
+    // guest page table walks have bit 0 clear and are not MMIO.
+    if htinst & 1 != 0 {
+        let insn_len = if htinst & 0b10 != 0 { 4 } else { 2 };
+        return decode(htinst | 0b10, insn_len);
+    }
+    if htinst != 0 {
+        return ax_err!(Unsupported, "fault during a guest page table walk");
+    }
+    let low = hlvx_hu(sepc);
+    if low & 0b11 != 0b11 {
+        return decode_compressed(low as u16);
+    }
+    decode(low | hlvx_hu(sepc + 2) << 16, 4)
+}
+
+fn decode(raw: u32, insn_len: usize) -> AxResult<LoadStore> {
+    let funct3 = (raw >> 12) & 0b111;
+    let width = AccessWidth::from_funct3(funct3);
+    let reg = match raw & 0x7f {
+        // `ld` has no unsigned form, so bit 2 is only set for `lbu`, `lhu`
+        // and `lwu`.
+        OPCODE_LOAD if funct3 != 0b111 => Ok((gpr(raw >> 7), funct3 & 0b100 == 0)),
+        OPCODE_STORE if funct3 < 0b100 => Err(gpr(raw >> 20)),
+        _ => return ax_err!(Unsupported, "not a load or a store"),
+    };
+    Ok(LoadStore {
+        width,
+        reg,
+        insn_len,
+    })
+}
+
+fn decode_compressed(raw: u16) -> AxResult<LoadStore> {
+    // The registers x8-x15 of the 3-bit fields.
+    let short = gpr(8 + ((raw as u32 >> 2) & 0b111));
+    let (width, reg) = match (raw & 0b11, raw >> 13) {
+        // c.lw, c.ld, c.sw, c.sd
+        (0b00, 0b010) => (AccessWidth::Dword, Ok((short, true))),
+        (0b00, 0b011) => (AccessWidth::Qword, Ok((short, true))),
+        (0b00, 0b110) => (AccessWidth::Dword, Err(short)),
+        (0b00, 0b111) => (AccessWidth::Qword, Err(short)),
+        // c.lwsp, c.ldsp, c.swsp, c.sdsp
+        (0b10, 0b010) => (AccessWidth::Dword, Ok((gpr(raw as u32 >> 7), true))),
+        (0b10, 0b011) => (AccessWidth::Qword, Ok((gpr(raw as u32 >> 7), true))),
+        (0b10, 0b110) => (AccessWidth::Dword, Err(gpr(raw as u32 >> 2))),
+        (0b10, 0b111) => (AccessWidth::Qword, Err(gpr(raw as u32 >> 2))),
+        _ => return ax_err!(Unsupported, "not a load or a store"),
+    };
+    Ok(LoadStore {
+        width,
+        reg,
+        insn_len: 2,
+    })
+}
+
+fn gpr(bits: u32) -> GprIndex {
+    GprIndex::from_raw(bits & 0x1f).unwrap()
+}
+
+/// Reads a half-word of guest instruction at the guest virtual address `gva`.
+///
+/// The guest has just executed from `gva`, so it is mapped and executable.
+fn hlvx_hu(gva: usize) -> u32 {
+    let val: usize;
+    unsafe {
+        core::arch::asm!(
+            ".option push",
+            ".option arch, +h",
+            "hlvx.hu {val}, ({gva})",
+            ".option pop",
+            val = out(reg) val,
+            gva = in(reg) gva,
+        );
+    }
+    val as u32
+}
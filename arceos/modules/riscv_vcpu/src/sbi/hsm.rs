@@ -0,0 +1,33 @@
+use sbi_spec::hsm::{HART_GET_STATUS, HART_START, HART_STOP};
+
+use axerrno::{AxError, AxResult};
+
+/// Functions for the Hart State Management extension.
+#[derive(Clone, Copy, Debug)]
+pub enum HsmFunction {
+    /// Starts the given hart at `start_addr` in supervisor mode.
+    HartStart {
+        hartid: usize,
+        start_addr: usize,
+        opaque: usize,
+    },
+    /// Stops the calling hart.
+    HartStop,
+    /// Returns the current status of the given hart.
+    HartGetStatus { hartid: usize },
+}
+
+impl HsmFunction {
+    pub(crate) fn from_regs(args: &[usize]) -> AxResult<Self> {
+        match args[6] {
+            HART_START => Ok(Self::HartStart {
+                hartid: args[0],
+                start_addr: args[1],
+                opaque: args[2],
+            }),
+            HART_STOP => Ok(Self::HartStop),
+            HART_GET_STATUS => Ok(Self::HartGetStatus { hartid: args[0] }),
+            _ => Err(AxError::NotFound),
+        }
+    }
+}
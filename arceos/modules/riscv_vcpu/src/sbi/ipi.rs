@@ -0,0 +1,25 @@
+use sbi_spec::spi::SEND_IPI;
+
+use axerrno::{AxError, AxResult};
+
+/// Functions for the IPI extension.
+#[derive(Clone, Copy, Debug)]
+pub enum IpiFunction {
+    /// Sends a supervisor software interrupt to all harts in the mask.
+    SendIpi {
+        hart_mask: usize,
+        hart_mask_base: usize,
+    },
+}
+
+impl IpiFunction {
+    pub(crate) fn from_regs(args: &[usize]) -> AxResult<Self> {
+        match args[6] {
+            SEND_IPI => Ok(Self::SendIpi {
+                hart_mask: args[0],
+                hart_mask_base: args[1],
+            }),
+            _ => Err(AxError::NotFound),
+        }
+    }
+}
@@ -6,6 +6,33 @@ pub enum PmuFunction {
     GetNumCounters,
     /// Returns information about hardware counter specified by the inner value.
     GetCounterInfo(u64),
+    /// Finds and configures a counter among the ones selected by counter_index and
+    /// counter_mask to monitor the event.
+    /// See the sbi_pmu_counter_config_matching documentation for details.
+    ConfigMatching {
+        /// Countert index base.
+        counter_index: u64,
+        /// Counter index mask.
+        counter_mask: u64,
+        /// Counter configuration flags.
+        config_flags: u64,
+        /// The event type and code.
+        event_index: u64,
+        /// The extra event data, for raw events.
+        event_data: u64,
+    },
+    /// Starts the couters selected by counter_index and counter_mask.
+    /// See the sbi_pmu_counter_start documentation for details.
+    StartCounter {
+        /// Countert index base.
+        counter_index: u64,
+        /// Counter index mask.
+        counter_mask: u64,
+        /// Counter start flags.
+        start_flags: u64,
+        /// The initial value of the counters, with `SBI_PMU_START_SET_INIT_VALUE`.
+        initial_value: u64,
+    },
     /// Stops the couters selected by counter_index and counter_mask.
     /// See the sbi_pmu_counter_stop documentation for details.
     StopCounter {
@@ -16,6 +43,13 @@ pub enum PmuFunction {
         /// Counter stop flags.
         stop_flags: u64,
     },
+    /// Returns the value of the firmware counter specified by the inner value.
+    ReadFirmwareCounter(u64),
+    /// Returns the high bits of the value of the firmware counter specified by the inner
+    /// value, on RV32 only.
+    ReadFirmwareCounterHigh(u64),
+    /// Another function, not supported, e.g. the snapshot shared memory.
+    Unsupported(u64),
 }
 
 impl PmuFunction {
@@ -23,12 +57,27 @@ impl PmuFunction {
         match args[6] {
             0 => Ok(Self::GetNumCounters),
             1 => Ok(Self::GetCounterInfo(args[0] as u64)),
+            2 => Ok(Self::ConfigMatching {
+                counter_index: args[0] as u64,
+                counter_mask: args[1] as u64,
+                config_flags: args[2] as u64,
+                event_index: args[3] as u64,
+                event_data: args[4] as u64,
+            }),
+            3 => Ok(Self::StartCounter {
+                counter_index: args[0] as u64,
+                counter_mask: args[1] as u64,
+                start_flags: args[2] as u64,
+                initial_value: args[3] as u64,
+            }),
             4 => Ok(Self::StopCounter {
                 counter_index: args[0] as u64,
                 counter_mask: args[1] as u64,
                 stop_flags: args[2] as u64,
             }),
-            _ => panic!("Unsupported yet!"),
+            5 => Ok(Self::ReadFirmwareCounter(args[0] as u64)),
+            6 => Ok(Self::ReadFirmwareCounterHigh(args[0] as u64)),
+            fid => Ok(Self::Unsupported(fid as u64)),
         }
     }
 }
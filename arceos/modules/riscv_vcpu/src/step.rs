@@ -0,0 +1,191 @@
+//! Single-stepping the guest with `ebreak`s.
+//!
+//! The hart has no step trap for VS-mode, so before a step `ebreak`s are written
+//! where the guest may go next: after the instruction, at the targets of a branch
+//! or a jump, and at `vstvec` for the traps the guest takes meanwhile. They are
+//! written through the guest physical addresses, as the guest page table may not
+//! allow writing its code, and removed once the vCPU exits.
+
+use core::arch::asm;
+
+use crate::guest_mem;
+use crate::regs::GprIndex;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+const SRET: u32 = 0x1020_0073;
+
+const OPCODE_BRANCH: u32 = 0x63;
+const OPCODE_JALR: u32 = 0x67;
+const OPCODE_JAL: u32 = 0x6f;
+
+/// The VS-level interrupts, whose handlers are at `vstvec` plus 4 times their code
+/// in vectored mode.
+const VS_IRQ_CODES: [usize; 3] = [1, 5, 9];
+const STVEC_MODE_VECTORED: usize = 1;
+
+/// The next instruction, 2 branch or jump targets, `vstvec` and the vectors.
+const MAX_BREAKPOINTS: usize = 3 + VS_IRQ_CODES.len();
+
+/// The guest state a step starts from.
+pub(crate) struct StepStart<'a> {
+    pub pc: usize,
+    pub vstvec: usize,
+    pub vsepc: usize,
+    pub gpr: &'a dyn Fn(GprIndex) -> usize,
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    gva: usize,
+    gpa: usize,
+    /// The instruction under the `ebreak`, 2 or 4 bytes.
+    orig: [u8; 4],
+    len: usize,
+}
+
+/// The `ebreak`s of a step in the guest memory.
+pub(crate) struct StepBreakpoints {
+    bps: [Option<Breakpoint>; MAX_BREAKPOINTS],
+}
+
+impl StepBreakpoints {
+    /// Writes the `ebreak`s for a step from `start`. The targets not mapped yet
+    /// are left out: the guest faults there, which is the end of the step too.
+    pub fn insert(start: &StepStart) -> Self {
+        let mut bps = [None; MAX_BREAKPOINTS];
+        let mut targets = [None; MAX_BREAKPOINTS];
+        next_pcs(start, &mut targets);
+        for (slot, gva) in bps.iter_mut().zip(targets) {
+            // An `ebreak` at the instruction itself would replace it.
+            let Some(gva) = gva.filter(|&gva| gva != start.pc) else {
+                continue;
+            };
+            *slot = insert_one(gva);
+        }
+        unsafe { asm!("fence.i") };
+        Self { bps }
+    }
+
+    /// Whether the step ended at the `ebreak` at `pc`.
+    pub fn contains(&self, pc: usize) -> bool {
+        self.bps.iter().flatten().any(|bp| bp.gva == pc)
+    }
+
+    /// Restores the instructions under the `ebreak`s.
+    pub fn remove(self) {
+        for bp in self.bps.iter().rev().flatten() {
+            for i in 0..bp.len {
+                let _ = guest_mem::store_phys_byte(bp.gpa + i, bp.orig[i]);
+            }
+        }
+        unsafe { asm!("fence.i") };
+    }
+}
+
+/// Reads the instruction at the guest physical address `gpa`, and returns it
+/// with its length.
+fn read_inst(gpa: usize) -> Option<(u32, usize)> {
+    let byte = |i| guest_mem::load_phys_byte(gpa + i).ok();
+    let low = byte(0)? as u32 | (byte(1)? as u32) << 8;
+    if low & 0x3 != 0x3 {
+        return Some((low, 2));
+    }
+    let high = byte(2)? as u32 | (byte(3)? as u32) << 8;
+    Some((low | high << 16, 4))
+}
+
+/// Writes an `ebreak` of the length of the instruction at `gva`.
+fn insert_one(gva: usize) -> Option<Breakpoint> {
+    let gpa = guest_mem::translate(gva).ok()?;
+    let (inst, len) = read_inst(gpa)?;
+    let ebreak = match len {
+        2 => C_EBREAK as u32,
+        _ => EBREAK,
+    };
+    for i in 0..len {
+        guest_mem::store_phys_byte(gpa + i, (ebreak >> (i * 8)) as u8).ok()?;
+    }
+    Some(Breakpoint {
+        gva,
+        gpa,
+        orig: inst.to_le_bytes(),
+        len,
+    })
+}
+
+fn sign_extend(val: u32, bits: u32) -> usize {
+    (((val << (32 - bits)) as i32) >> (32 - bits)) as isize as usize
+}
+
+/// Fills `targets` with the guest virtual addresses the guest may reach from
+/// `start` in an instruction.
+fn next_pcs(start: &StepStart, targets: &mut [Option<usize>; MAX_BREAKPOINTS]) {
+    let pc = start.pc;
+    targets[0] = Some(start.vstvec & !0x3);
+    if start.vstvec & 0x3 == STVEC_MODE_VECTORED {
+        for (target, code) in targets[1..].iter_mut().zip(VS_IRQ_CODES) {
+            *target = Some((start.vstvec & !0x3) + 4 * code);
+        }
+    }
+    let Some((inst, len)) = guest_mem::translate(pc).ok().and_then(read_inst) else {
+        return;
+    };
+    let next = &mut targets[1 + VS_IRQ_CODES.len()..];
+    next[0] = Some(pc + len);
+    let gpr = |raw: u32| (start.gpr)(GprIndex::from_raw(raw & 0x1f).unwrap());
+    if len == 4 {
+        match inst & 0x7f {
+            OPCODE_BRANCH => {
+                let imm = (inst >> 31) << 12
+                    | (inst >> 7 & 0x1) << 11
+                    | (inst >> 25 & 0x3f) << 5
+                    | (inst >> 8 & 0xf) << 1;
+                next[1] = Some(pc.wrapping_add(sign_extend(imm, 13)));
+            }
+            OPCODE_JAL => {
+                let imm = (inst >> 31) << 20
+                    | (inst >> 12 & 0xff) << 12
+                    | (inst >> 20 & 0x1) << 11
+                    | (inst >> 21 & 0x3ff) << 1;
+                next[0] = Some(pc.wrapping_add(sign_extend(imm, 21)));
+            }
+            OPCODE_JALR => {
+                let target = gpr(inst >> 15).wrapping_add(sign_extend(inst >> 20, 12));
+                next[0] = Some(target & !1);
+            }
+            _ if inst == SRET => next[0] = Some(start.vsepc),
+            _ => {}
+        }
+        return;
+    }
+    let (op, funct3) = (inst & 0x3, inst >> 13 & 0x7);
+    match (op, funct3) {
+        // `c.j`
+        (1, 0b101) => {
+            let imm = (inst >> 12 & 0x1) << 11
+                | (inst >> 11 & 0x1) << 4
+                | (inst >> 9 & 0x3) << 8
+                | (inst >> 8 & 0x1) << 10
+                | (inst >> 7 & 0x1) << 6
+                | (inst >> 6 & 0x1) << 7
+                | (inst >> 3 & 0x7) << 1
+                | (inst >> 2 & 0x1) << 5;
+            next[0] = Some(pc.wrapping_add(sign_extend(imm, 12)));
+        }
+        // `c.beqz` and `c.bnez`
+        (1, 0b110 | 0b111) => {
+            let imm = (inst >> 12 & 0x1) << 8
+                | (inst >> 10 & 0x3) << 3
+                | (inst >> 5 & 0x3) << 6
+                | (inst >> 3 & 0x3) << 1
+                | (inst >> 2 & 0x1) << 5;
+            next[1] = Some(pc.wrapping_add(sign_extend(imm, 9)));
+        }
+        // `c.jr` and `c.jalr`
+        (2, 0b100) if inst >> 2 & 0x1f == 0 && inst >> 7 & 0x1f != 0 => {
+            next[0] = Some(gpr(inst >> 7));
+        }
+        _ => {}
+    }
+}
@@ -3,16 +3,28 @@ use core::mem::size_of;
 
 use memoffset::offset_of;
 use riscv::register::{htinst, htval, scause, sstatus, stval};
-use sbi_rt::{pmu_counter_get_info, pmu_counter_stop};
 use tock_registers::LocalRegisterCopy;
 
 use axerrno::AxResult;
 
 use super::csrs::defs::hstatus;
+use super::csrs::defs::{
+    CSR_HIE, CSR_HIP, CSR_HTIMEDELTA, CSR_VSATP, CSR_VSCAUSE, CSR_VSEPC, CSR_VSIE, CSR_VSISELECT,
+    CSR_VSSCRATCH, CSR_VSSTATUS, CSR_VSTVAL, CSR_VSTVEC,
+};
 use super::csrs::{traps, RiscvCsrTrait, CSR};
-use super::sbi::{BaseFunction, PmuFunction, RemoteFenceFunction, SbiMessage};
+use super::sbi::{
+    BaseFunction, HsmFunction, IpiFunction, PmuFunction, RemoteFenceFunction, ResetFunction,
+    ResetType, SbiMessage, SBI_ERR_NOT_SUPPORTED,
+};
 
+use super::aia::{self, ImsicAccess, ImsicReg};
+use super::guest_mem;
+use super::HSTATUS_SPVP;
+use super::mmio::{self, MmioAccess, MmioOp};
 use super::regs::{GeneralPurposeRegisters, GprIndex};
+use super::step::{StepBreakpoints, StepStart};
+use super::vpmu::{self, PmuEvents, VirtPmu};
 use memory_addr::{VirtAddr, PhysAddr};
 use axhal::paging::MappingFlags;
 
@@ -21,6 +33,49 @@ pub type GuestPhysAddr = VirtAddr;
 /// Host physical address.
 pub type HostPhysAddr = PhysAddr;
 
+macro_rules! read_csr {
+    ($csr:ident) => {{
+        let val: usize;
+        unsafe { core::arch::asm!("csrr {0}, {csr}", out(reg) val, csr = const $csr) };
+        val
+    }};
+}
+
+macro_rules! write_csr {
+    ($csr:ident, $val:expr) => {
+        unsafe { core::arch::asm!("csrw {csr}, {0}", in(reg) $val, csr = const $csr) }
+    };
+}
+
+/// The bit position of the VMID field in `hgatp`.
+const HGATP_VMID_SHIFT: usize = 44;
+/// The mask of the VMID field in `hgatp` (14 bits on RV64).
+const HGATP_VMID_MASK: usize = 0x3fff;
+
+/// The `scause` of a virtual instruction exception.
+const SCAUSE_VIRTUAL_INSTRUCTION: usize = 22;
+/// The encoding of `wfi`.
+const INST_WFI: usize = 0x1050_0073;
+/// The opcode of `wfi` and the CSR instructions.
+const OPCODE_SYSTEM: usize = 0x73;
+
+/// The bits of `sstatus` and `vsstatus` used to enter a trap.
+const SSTATUS_SIE: usize = 1 << 1;
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// The unprivileged counter CSRs, and their high halves on RV32.
+const CSR_CYCLE: u16 = 0xc00;
+const CSR_TIME: u16 = 0xc01;
+const CSR_INSTRET: u16 = 0xc02;
+const CSR_CYCLEH: u16 = 0xc80;
+const CSR_TIMEH: u16 = 0xc81;
+const CSR_INSTRETH: u16 = 0xc82;
+
+/// The AIA CSRs of the IMSIC interrupt file, as accessed by the guest.
+const CSR_SIREG: u16 = 0x151;
+const CSR_STOPEI: u16 = 0x15c;
+
 /// Hypervisor GPR and CSR state which must be saved/restored when entering/exiting virtualization.
 #[derive(Default)]
 #[repr(C)]
@@ -211,6 +266,12 @@ pub struct VCpuConfig {}
 /// A virtual CPU within a guest
 pub struct RISCVVCpu {
     regs: VmCpuRegisters,
+    /// Subtracted from the host time to get the guest `cycle` and `instret`.
+    counter_offset: usize,
+    /// The counters of the SBI PMU extension.
+    pmu: VirtPmu,
+    /// [`run`](Self::run) returns after each guest instruction.
+    single_step: bool,
 }
 
 impl RISCVVCpu {
@@ -221,25 +282,79 @@ impl RISCVVCpu {
     }
 
     pub fn set_ept_root(&mut self, ept_root: HostPhysAddr) -> AxResult {
-        self.regs.virtual_hs_csrs.hgatp = 8usize << 60 | usize::from(ept_root) >> 12;
-        unsafe {
-            core::arch::asm!(
-                "csrw hgatp, {hgatp}",
-                hgatp = in(reg) self.regs.virtual_hs_csrs.hgatp,
-            );
-            core::arch::riscv64::hfence_gvma_all();
+        let vmid = self.regs.virtual_hs_csrs.hgatp & (HGATP_VMID_MASK << HGATP_VMID_SHIFT);
+        self.regs.virtual_hs_csrs.hgatp = 8usize << 60 | vmid | usize::from(ept_root) >> 12;
+        self.load_hgatp();
+        Ok(())
+    }
+
+    /// Sets the VMID tagging the G-stage translations of this vCPU.
+    ///
+    /// All vCPUs of a VM should use the same VMID, and different VMs different ones. The VMID
+    /// must fit in the VMID bits of every hart the vCPU runs on, see [`crate::vmid_bits`].
+    pub fn set_vmid(&mut self, vmid: usize) -> AxResult {
+        if vmid > HGATP_VMID_MASK {
+            return Err(axerrno::AxError::InvalidInput);
         }
+        let hgatp = &mut self.regs.virtual_hs_csrs.hgatp;
+        *hgatp = *hgatp & !(HGATP_VMID_MASK << HGATP_VMID_SHIFT) | vmid << HGATP_VMID_SHIFT;
         Ok(())
     }
 
+    /// Returns the VMID of this vCPU.
+    pub fn vmid(&self) -> usize {
+        self.regs.virtual_hs_csrs.hgatp >> HGATP_VMID_SHIFT & HGATP_VMID_MASK
+    }
+
+    /// Invalidates the G-stage translations of this vCPU's VMID on the current hart.
+    ///
+    /// Must be called after the EPT is modified. Other harts running vCPUs of the same VM have
+    /// to do it themselves.
+    pub fn flush_ept(&self) {
+        unsafe { core::arch::riscv64::hfence_gvma_vmid(self.vmid()) };
+    }
+
     pub fn run(&mut self) -> AxResult<AxVCpuExitReason> {
+        // `hgatp` and `htimedelta` are per-hart state, load them in case the vCPU is run by
+        // another hart.
+        self.load_hgatp();
+        write_csr!(CSR_HTIMEDELTA, self.regs.vs_csrs.htimedelta);
+        let start_pc = self.regs.guest_regs.sepc;
+        let step = self.single_step.then(|| {
+            let delegated = CSR.hedeleg.read_and_clear_bits(traps::exception::BREAKPOINT)
+                & traps::exception::BREAKPOINT;
+            let bps = StepBreakpoints::insert(&StepStart {
+                pc: start_pc,
+                vstvec: read_csr!(CSR_VSTVEC),
+                vsepc: read_csr!(CSR_VSEPC),
+                gpr: &|reg| self.get_gpr(reg),
+            });
+            (bps, delegated)
+        });
         let regs = &mut self.regs;
         unsafe {
             // Safe to run the guest as it only touches memory assigned to it by being owned
             // by its page table
             _run_guest(regs);
         }
-        self.vmexit_handler()
+        let exit_reason = self.vmexit_handler();
+        let Some((bps, delegated)) = step else {
+            return exit_reason;
+        };
+        let at_step_end = match exit_reason {
+            Ok(AxVCpuExitReason::Breakpoint { pc }) => bps.contains(pc),
+            // E.g. an SBI call handled here, but not a host interrupt.
+            Ok(AxVCpuExitReason::Nothing) => self.regs.guest_regs.sepc != start_pc,
+            _ => false,
+        };
+        bps.remove();
+        CSR.hedeleg.read_and_set_bits(delegated);
+        if at_step_end {
+            return Ok(AxVCpuExitReason::SingleStep {
+                pc: self.regs.guest_regs.sepc,
+            });
+        }
+        exit_reason
     }
 }
 
@@ -254,6 +369,8 @@ impl RISCVVCpu {
         // Set SPVP bit in order to accessing VS-mode memory from HS-mode.
         hstatus.modify(hstatus::spvp::Supervisor);
         CSR.hstatus.write_value(hstatus.get());
+        // Trap `wfi` in the guest, so the hart can run something else while the vCPU waits.
+        hstatus.modify(hstatus::vtw::SET);
         regs.guest_regs.hstatus = hstatus.get();
 
         // Set sstatus
@@ -263,7 +380,12 @@ impl RISCVVCpu {
 
         CSR.sie
             .read_and_clear_bits(traps::interrupt::SUPERVISOR_TIMER);
-        Self { regs }
+        Self {
+            regs,
+            counter_offset: 0,
+            pmu: VirtPmu::new(),
+            single_step: false,
+        }
     }
 
     /// Gets one of the vCPU's general purpose registers.
@@ -276,6 +398,102 @@ impl RISCVVCpu {
         self.regs.guest_regs.gprs.set_reg(index, val);
     }
 
+    /// Loads `hgatp` of this vCPU on the current hart if it is not loaded yet, and invalidates
+    /// the stale G-stage translations.
+    fn load_hgatp(&self) {
+        let hgatp = self.regs.virtual_hs_csrs.hgatp;
+        let current: usize;
+        unsafe {
+            core::arch::asm!("csrr {0}, hgatp", out(reg) current);
+            if current != hgatp {
+                core::arch::asm!("csrw hgatp, {0}", in(reg) hgatp);
+                core::arch::riscv64::hfence_gvma_all();
+            }
+        }
+    }
+
+    /// Injects `irq` into the vCPU, which must be the one run on the current hart.
+    ///
+    /// The interrupt stays pending until the guest handles it (software and timer) or it is
+    /// cleared with [`clear_irq`](Self::clear_irq) (external). A vCPU waiting in `wfi` resumes
+    /// as soon as the interrupt is pending and enabled by the guest, so a vCPU on another hart
+    /// only has to be kicked out of the guest to have its task inject the interrupt.
+    pub fn inject_irq(&mut self, irq: IrqKind) {
+        if irq == IrqKind::Software {
+            self.pmu.fw_event(vpmu::FW_IPI_RECEIVED);
+        }
+        CSR.hvip.read_and_set_bits(irq.hvip_bit());
+    }
+
+    /// Clears `irq` injected into the vCPU, which must be the one run on the current hart.
+    pub fn clear_irq(&mut self, irq: IrqKind) {
+        CSR.hvip.read_and_clear_bits(irq.hvip_bit());
+    }
+
+    /// Whether an interrupt is pending in the vCPU and enabled by the guest, so a `wfi` would
+    /// resume. The vCPU must be the one run on the current hart.
+    pub fn irq_pending(&self) -> bool {
+        const VS_IRQS: usize = traps::interrupt::VIRTUAL_SUPERVISOR_SOFT
+            | traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
+            | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL;
+        read_csr!(CSR_HIP) & read_csr!(CSR_HIE) & VS_IRQS != 0
+    }
+
+    /// Programs the guest timer to fire at `deadline`, in `time` ticks, and clears the pending
+    /// guest timer interrupt.
+    ///
+    /// With Sstc, the deadline goes to `vstimecmp` and the hardware raises the interrupt.
+    /// Otherwise a host timer is programmed, and the interrupt is injected through `hvip` when
+    /// it fires.
+    pub fn set_guest_timer(&mut self, deadline: usize) {
+        self.regs.vs_csrs.vstimecmp = deadline;
+        if crate::has_sstc() {
+            unsafe { core::arch::asm!("csrw 0x24d, {0}", in(reg) deadline) }; // vstimecmp
+            return;
+        }
+        sbi_rt::set_timer(deadline as u64);
+        self.clear_irq(IrqKind::Timer);
+        //  Enable host timer interrupt
+        CSR.sie
+            .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
+    }
+
+    /// Decodes the guest load or store to `addr` that caused the last
+    /// [`AxVCpuExitReason::NestedPageFault`], to emulate it as an MMIO access.
+    pub fn decode_mmio(&self, addr: GuestPhysAddr) -> AxResult<MmioAccess> {
+        let insn = mmio::decode_trapped(self.regs.trap_csrs.htinst, self.regs.guest_regs.sepc)?;
+        let op = match insn.reg {
+            Ok((reg, signed)) => MmioOp::Read { reg, signed },
+            Err(rs2) => MmioOp::Write {
+                data: insn.width.extend(self.get_gpr(rs2) as u64, false),
+            },
+        };
+        Ok(MmioAccess {
+            addr,
+            width: insn.width,
+            op,
+            insn_len: insn.insn_len,
+        })
+    }
+
+    /// Completes the emulated `access`: a load gets `val` in its destination
+    /// register, and the guest resumes after the instruction.
+    pub fn complete_mmio(&mut self, access: &MmioAccess, val: u64) {
+        if let MmioOp::Read { reg, signed } = access.op {
+            let val = access.width.extend(val, signed);
+            self.set_gpr_from_gpr_index(reg, val as usize);
+        }
+        self.advance_pc(access.insn_len);
+    }
+
+    /// Completes the emulated `access` to the IMSIC interrupt file: the destination register
+    /// gets `old`, the value of the register before the access, and the guest resumes after
+    /// the instruction.
+    pub fn complete_imsic_access(&mut self, access: &ImsicAccess, old: usize) {
+        self.set_gpr_from_gpr_index(access.rd, old);
+        self.advance_pc(4);
+    }
+
     /// Advance guest pc by `instr_len` bytes
     pub fn advance_pc(&mut self, instr_len: usize) {
         self.regs.guest_regs.sepc += instr_len
@@ -285,6 +503,144 @@ impl RISCVVCpu {
     pub fn regs(&mut self) -> &mut VmCpuRegisters {
         &mut self.regs
     }
+
+    /// Makes `ebreak` in the guest exit to the hypervisor, e.g. for a debugger, instead of
+    /// trapping to the guest itself. The vCPU must be the one run on the current hart.
+    pub fn set_breakpoint_exits(&mut self, enable: bool) {
+        if enable {
+            CSR.hedeleg.read_and_clear_bits(traps::exception::BREAKPOINT);
+        } else {
+            CSR.hedeleg.read_and_set_bits(traps::exception::BREAKPOINT);
+        }
+    }
+
+    /// Makes [`run`](Self::run) return after each guest instruction, with
+    /// [`AxVCpuExitReason::SingleStep`] once the guest gets to the next one, e.g. for a debugger.
+    /// An instruction exiting for another reason, e.g. an MMIO access, ends the step with this
+    /// exit instead.
+    ///
+    /// The steps write `ebreak`s to the guest memory, which the other vCPUs of the VM should
+    /// not execute meanwhile: they better be paused, or have breakpoint exits enabled.
+    pub fn set_single_step(&mut self, enable: bool) {
+        self.single_step = enable;
+    }
+
+    /// Whether [`run`](Self::run) returns after each guest instruction, see
+    /// [`set_single_step`](Self::set_single_step).
+    pub fn single_step(&self) -> bool {
+        self.single_step
+    }
+
+    /// Sets the host time, in `time` ticks, at which the guest `cycle` and `instret` counters
+    /// are 0, usually the same for all the vCPUs of a VM.
+    ///
+    /// Those counters are virtual: they count the time ticks, as the host counters are per
+    /// hart and the vCPU may move between harts. The guest `time` is the host one plus
+    /// `htimedelta`, see [`set_time_delta`](Self::set_time_delta).
+    pub fn set_counter_offset(&mut self, offset: usize) {
+        self.counter_offset = offset;
+    }
+
+    /// Sets the events of the virtual PMU shared with the guest through the SBI PMU extension,
+    /// all of them by default. The extension is missing if none is.
+    pub fn set_pmu_events(&mut self, events: PmuEvents) {
+        self.pmu.set_events(events);
+    }
+
+    /// Sets `htimedelta`, the offset of the guest `time` from the host one, usually the same
+    /// for all the vCPUs of a VM. It is loaded on the hart each time the vCPU is run.
+    ///
+    /// The hypervisor changes it to hide from the guest the time it was not running, e.g.
+    /// while paused.
+    pub fn set_time_delta(&mut self, delta: usize) {
+        self.regs.vs_csrs.htimedelta = delta;
+        write_csr!(CSR_HTIMEDELTA, delta);
+    }
+
+    /// Returns `htimedelta`, see [`set_time_delta`](Self::set_time_delta).
+    pub fn time_delta(&self) -> usize {
+        self.regs.vs_csrs.htimedelta
+    }
+
+    /// Returns the trap CSRs of the last VM exit.
+    pub fn trap_csrs(&self) -> &VmCpuTrapState {
+        &self.regs.trap_csrs
+    }
+
+    /// Returns the CSR accessed by the instruction which caused the last VM exit, if it was a
+    /// CSR instruction trapped as a virtual instruction.
+    pub fn trapped_csr(&self) -> Option<u16> {
+        if self.regs.trap_csrs.scause != SCAUSE_VIRTUAL_INSTRUCTION {
+            return None;
+        }
+        // `stval` holds the faulting instruction.
+        let inst = self.regs.trap_csrs.stval;
+        let funct3 = (inst >> 12) & 0x7;
+        if inst & 0x7f != OPCODE_SYSTEM || funct3 == 0 || funct3 == 4 {
+            return None;
+        }
+        Some(((inst >> 20) & 0xfff) as u16)
+    }
+
+    /// Saves the architectural state of the vCPU, which must be the one run on the current
+    /// hart, outside the guest.
+    pub fn save_state(&self) -> VCpuState {
+        let mut gprs = [0; 32];
+        for (i, reg) in gprs.iter_mut().enumerate() {
+            *reg = self.regs.guest_regs.gprs.reg(GprIndex::from_raw(i as u32).unwrap());
+        }
+        let htimedelta = self.regs.vs_csrs.htimedelta;
+        VCpuState {
+            gprs,
+            sepc: self.regs.guest_regs.sepc,
+            sstatus: self.regs.guest_regs.sstatus,
+            vsstatus: read_csr!(CSR_VSSTATUS),
+            vsie: read_csr!(CSR_VSIE),
+            vstvec: read_csr!(CSR_VSTVEC),
+            vsscratch: read_csr!(CSR_VSSCRATCH),
+            vsepc: read_csr!(CSR_VSEPC),
+            vscause: read_csr!(CSR_VSCAUSE),
+            vstval: read_csr!(CSR_VSTVAL),
+            vsatp: read_csr!(CSR_VSATP),
+            vsiselect: if crate::has_aia() {
+                read_csr!(CSR_VSISELECT)
+            } else {
+                0
+            },
+            vstimecmp: self.regs.vs_csrs.vstimecmp,
+            hvip: CSR.hvip.get_value(),
+            time: riscv::register::time::read().wrapping_add(htimedelta),
+        }
+    }
+
+    /// Restores the architectural state `state` saved by [`save_state`](Self::save_state),
+    /// maybe by another boot of the host, into the vCPU, which must be the one run on the
+    /// current hart.
+    ///
+    /// The guest time goes on from the saved one, `htimedelta` being set accordingly.
+    pub fn restore_state(&mut self, state: &VCpuState) {
+        for (i, &val) in state.gprs.iter().enumerate() {
+            self.set_gpr_from_gpr_index(GprIndex::from_raw(i as u32).unwrap(), val);
+        }
+        self.regs.guest_regs.sepc = state.sepc;
+        self.regs.guest_regs.sstatus = state.sstatus;
+        write_csr!(CSR_VSSTATUS, state.vsstatus);
+        write_csr!(CSR_VSIE, state.vsie);
+        write_csr!(CSR_VSTVEC, state.vstvec);
+        write_csr!(CSR_VSSCRATCH, state.vsscratch);
+        write_csr!(CSR_VSEPC, state.vsepc);
+        write_csr!(CSR_VSCAUSE, state.vscause);
+        write_csr!(CSR_VSTVAL, state.vstval);
+        write_csr!(CSR_VSATP, state.vsatp);
+        if crate::has_aia() {
+            write_csr!(CSR_VSISELECT, state.vsiselect);
+        }
+        self.set_time_delta(state.time.wrapping_sub(riscv::register::time::read()));
+        CSR.hvip.write_value(state.hvip);
+        if state.vstimecmp != 0 {
+            self.set_guest_timer(state.vstimecmp);
+        }
+    }
 }
 
 impl RISCVVCpu {
@@ -298,81 +654,292 @@ impl RISCVVCpu {
         use scause::{Exception, Interrupt, Trap};
         match scause.cause() {
             Trap::Exception(Exception::VirtualSupervisorEnvCall) => {
-                let sbi_msg = SbiMessage::from_regs(self.regs.guest_regs.gprs.a_regs()).ok();
+                let a_regs = self.regs.guest_regs.gprs.a_regs();
+                let (eid, fid) = (a_regs[7], a_regs[6]);
+                let sbi_msg = match SbiMessage::from_regs(a_regs) {
+                    Ok(sbi_msg) => sbi_msg,
+                    Err(_) => {
+                        warn!("Unsupported SBI call: eid {:#x}, fid {:#x}", eid, fid);
+                        self.set_gpr_from_gpr_index(GprIndex::A0, SBI_ERR_NOT_SUPPORTED as usize);
+                        self.advance_pc(4);
+                        return Ok(AxVCpuExitReason::Nothing);
+                    }
+                };
                 debug!("VSuperEcall: {:?}", sbi_msg);
-                if let Some(sbi_msg) = sbi_msg {
-                    match sbi_msg {
-                        SbiMessage::Base(base) => {
-                            self.handle_base_function(base).unwrap();
-                        }
-                        SbiMessage::GetChar => {
-                            #[allow(deprecated)]
-                            let c = sbi_rt::legacy::console_getchar();
-                            self.set_gpr_from_gpr_index(GprIndex::A0, c);
-                        }
-                        SbiMessage::PutChar(c) => {
-                            #[allow(deprecated)]
-                            sbi_rt::legacy::console_putchar(c);
-                        }
-                        SbiMessage::SetTimer(timer) => {
-                            info!("Set timer... ");
-                            sbi_rt::set_timer(timer as u64);
-                            // Clear guest timer interrupt
-                            CSR.hvip
-                                .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
-                            //  Enable host timer interrupt
-                            CSR.sie
-                                .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
-                        }
-                        SbiMessage::Reset(_) => {
-                            sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
-                        }
-                        SbiMessage::RemoteFence(rfnc) => {
-                            self.handle_rfnc_function(rfnc).unwrap();
-                        }
-                        SbiMessage::PMU(pmu) => {
-                            self.handle_pmu_function(pmu).unwrap();
-                        }
-                        _ => todo!(),
+                let res = match sbi_msg {
+                    SbiMessage::Base(base) => self.handle_base_function(base),
+                    SbiMessage::GetChar => {
+                        #[allow(deprecated)]
+                        let c = sbi_rt::legacy::console_getchar();
+                        self.set_gpr_from_gpr_index(GprIndex::A0, c);
+                        Ok(())
                     }
-                    self.advance_pc(4);
-                    Ok(AxVCpuExitReason::Nothing)
-                } else {
-                    panic!()
+                    SbiMessage::PutChar(c) => {
+                        #[allow(deprecated)]
+                        sbi_rt::legacy::console_putchar(c);
+                        Ok(())
+                    }
+                    SbiMessage::ClearIpi => {
+                        self.clear_irq(IrqKind::Software);
+                        self.set_gpr_from_gpr_index(GprIndex::A0, 0);
+                        Ok(())
+                    }
+                    SbiMessage::SetTimer(timer) => {
+                        debug!("Set timer: {:#x}", timer);
+                        self.pmu.fw_event(vpmu::FW_SET_TIMER);
+                        self.set_guest_timer(timer as usize);
+                        Ok(())
+                    }
+                    SbiMessage::Reset(ResetFunction::Reset { reset_type, reason }) => {
+                        // The call does not return: the guest stops, or starts over.
+                        info!("Guest system reset: {:?}, reason: {:?}", reset_type, reason);
+                        return Ok(match reset_type {
+                            ResetType::Shutdown => AxVCpuExitReason::SystemDown,
+                            ResetType::ColdReset | ResetType::WarmReset => {
+                                AxVCpuExitReason::SystemReset
+                            }
+                        });
+                    }
+                    SbiMessage::RemoteFence(rfnc) => self.handle_rfnc_function(rfnc),
+                    SbiMessage::PMU(pmu) => self.handle_pmu_function(pmu),
+                    SbiMessage::Hsm(hsm) => {
+                        self.advance_pc(4);
+                        return self.handle_hsm_function(hsm);
+                    }
+                    SbiMessage::Ipi(IpiFunction::SendIpi {
+                        hart_mask,
+                        hart_mask_base,
+                    }) => {
+                        // Success, unless the hypervisor finds an invalid hart in the mask.
+                        self.pmu.fw_event(vpmu::FW_IPI_SENT);
+                        self.set_gpr_from_gpr_index(GprIndex::A0, 0);
+                        self.advance_pc(4);
+                        return Ok(AxVCpuExitReason::SendIpi {
+                            hart_mask,
+                            hart_mask_base,
+                        });
+                    }
+                    // Not reported by the probe, so the guest should not call it.
+                    SbiMessage::DebugConsole(_) => {
+                        self.set_gpr_from_gpr_index(GprIndex::A0, SBI_ERR_NOT_SUPPORTED as usize);
+                        Ok(())
+                    }
+                };
+                if let Err(err) = res {
+                    // The guest cannot go on without the result of its call.
+                    warn!("Failed SBI call {:?}: {:?}", sbi_msg, err);
+                    return Ok(AxVCpuExitReason::UnhandledTrap {
+                        scause: self.regs.trap_csrs.scause,
+                    });
                 }
+                self.advance_pc(4);
+                Ok(AxVCpuExitReason::Nothing)
             }
             Trap::Interrupt(Interrupt::SupervisorTimer) => {
                 info!("timer irq emulation");
                 // Enable guest timer interrupt
-                CSR.hvip
-                    .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
+                self.inject_irq(IrqKind::Timer);
                 // Clear host timer interrupt
                 CSR.sie
                     .read_and_clear_bits(traps::interrupt::SUPERVISOR_TIMER);
                 Ok(AxVCpuExitReason::Nothing)
             }
+            Trap::Interrupt(Interrupt::SupervisorSoft) => {
+                // Kicked by another hart, e.g., to deliver a virtual IPI. The caller checks what
+                // to inject before running the vCPU again.
+                unsafe {
+                    core::arch::asm!("csrc sip, {0}", in(reg) traps::interrupt::SUPERVISOR_SOFT)
+                };
+                Ok(AxVCpuExitReason::Nothing)
+            }
             Trap::Interrupt(Interrupt::SupervisorExternal) => {
                 Ok(AxVCpuExitReason::ExternalInterrupt { vector: 0 })
             }
-            Trap::Exception(Exception::LoadGuestPageFault)
-            | Trap::Exception(Exception::StoreGuestPageFault) => {
+            Trap::Exception(Exception::Breakpoint) => Ok(AxVCpuExitReason::Breakpoint {
+                pc: self.regs.guest_regs.sepc,
+            }),
+            Trap::Exception(Exception::LoadMisaligned | Exception::StoreMisaligned) => {
+                self.emulate_misaligned()
+            }
+            Trap::Exception(_)
+                if self.regs.trap_csrs.scause == SCAUSE_VIRTUAL_INSTRUCTION
+                    && matches!(self.trapped_csr(), Some(CSR_SIREG | CSR_STOPEI)) =>
+            {
+                let reg = if self.trapped_csr() == Some(CSR_STOPEI) {
+                    ImsicReg::Topei
+                } else {
+                    ImsicReg::Indirect(read_csr!(CSR_VSISELECT))
+                };
+                let access = aia::decode(self.regs.trap_csrs.stval, reg, |reg| self.get_gpr(reg));
+                Ok(AxVCpuExitReason::ImsicAccess { access })
+            }
+            // `stval` holds the faulting instruction.
+            Trap::Exception(_)
+                if self.regs.trap_csrs.scause == SCAUSE_VIRTUAL_INSTRUCTION
+                    && self.trapped_csr().is_some_and(is_counter_csr) =>
+            {
+                self.emulate_counter_read(self.regs.trap_csrs.stval);
+                self.advance_pc(4);
+                Ok(AxVCpuExitReason::Nothing)
+            }
+            Trap::Exception(_)
+                if self.regs.trap_csrs.scause == SCAUSE_VIRTUAL_INSTRUCTION
+                    && self.regs.trap_csrs.stval == INST_WFI =>
+            {
+                self.advance_pc(4);
+                // `wfi` may trap with an interrupt pending, which the guest takes right away.
+                if self.irq_pending() {
+                    Ok(AxVCpuExitReason::Nothing)
+                } else {
+                    Ok(AxVCpuExitReason::Halt)
+                }
+            }
+            Trap::Exception(
+                e @ (Exception::LoadGuestPageFault
+                | Exception::StoreGuestPageFault
+                | Exception::InstructionGuestPageFault),
+            ) => {
                 let fault_addr = self.regs.trap_csrs.htval << 2 | self.regs.trap_csrs.stval & 0x3;
+                let access_flags = match e {
+                    Exception::LoadGuestPageFault => MappingFlags::READ,
+                    Exception::StoreGuestPageFault => MappingFlags::WRITE,
+                    _ => MappingFlags::EXECUTE,
+                };
                 Ok(AxVCpuExitReason::NestedPageFault {
                     addr: GuestPhysAddr::from(fault_addr),
-                    access_flags: MappingFlags::empty(),
+                    access_flags,
                 })
             }
             _ => {
-                panic!(
+                warn!(
                     "Unhandled trap: {:?}, sepc: {:#x}, stval: {:#x}",
                     scause.cause(),
                     self.regs.guest_regs.sepc,
                     self.regs.trap_csrs.stval
                 );
+                Ok(AxVCpuExitReason::UnhandledTrap {
+                    scause: self.regs.trap_csrs.scause,
+                })
+            }
+        }
+    }
+
+    /// Emulates the misaligned load or store which trapped, with byte accesses at the guest
+    /// virtual address in `stval`.
+    ///
+    /// If a byte of the guest RAM is not mapped yet, returns the nested page fault to let the
+    /// hypervisor map it, and the guest executes the instruction again. Other faults go to the
+    /// guest.
+    fn emulate_misaligned(&mut self) -> AxResult<AxVCpuExitReason> {
+        let insn = mmio::decode_trapped(self.regs.trap_csrs.htinst, self.regs.guest_regs.sepc)?;
+        let gva = self.regs.trap_csrs.stval;
+        let size = insn.width.size();
+        // The accesses have the privilege of the guest when it trapped.
+        let spvp = self.regs.guest_regs.hstatus & HSTATUS_SPVP;
+        let host_spvp = CSR.hstatus.read_and_clear_bits(HSTATUS_SPVP) & HSTATUS_SPVP;
+        CSR.hstatus.read_and_set_bits(spvp);
+        let res = match insn.reg {
+            Ok((rd, signed)) => (0..size)
+                .try_fold(0u64, |val, i| {
+                    guest_mem::load_byte(gva + i).map(|byte| val | (byte as u64) << (i * 8))
+                })
+                .map(|val| {
+                    let val = insn.width.extend(val, signed);
+                    self.set_gpr_from_gpr_index(rd, val as usize);
+                }),
+            Err(rs2) => {
+                let data = self.get_gpr(rs2);
+                (0..size).try_for_each(|i| guest_mem::store_byte(gva + i, (data >> (i * 8)) as u8))
+            }
+        };
+        CSR.hstatus.read_and_clear_bits(HSTATUS_SPVP);
+        CSR.hstatus.read_and_set_bits(host_spvp);
+
+        match res {
+            Ok(()) => {
+                self.pmu.fw_event(match insn.reg {
+                    Ok(_) => vpmu::FW_MISALIGNED_LOAD,
+                    Err(_) => vpmu::FW_MISALIGNED_STORE,
+                });
+                self.advance_pc(insn.insn_len);
+                Ok(AxVCpuExitReason::Nothing)
+            }
+            Err(
+                scause @ (guest_mem::LOAD_GUEST_PAGE_FAULT | guest_mem::STORE_GUEST_PAGE_FAULT),
+            ) => {
+                let fault_addr = htval::read() << 2 | stval::read() & 0x3;
+                let access_flags = if scause == guest_mem::LOAD_GUEST_PAGE_FAULT {
+                    MappingFlags::READ
+                } else {
+                    MappingFlags::WRITE
+                };
+                Ok(AxVCpuExitReason::NestedPageFault {
+                    addr: GuestPhysAddr::from(fault_addr),
+                    access_flags,
+                })
+            }
+            Err(scause) => {
+                // A store faulting as a load still is a store for the guest.
+                let scause = match (scause, insn.reg) {
+                    (guest_mem::LOAD_PAGE_FAULT, Err(_)) => guest_mem::STORE_PAGE_FAULT,
+                    _ => scause,
+                };
+                self.inject_exception(scause, stval::read());
+                Ok(AxVCpuExitReason::Nothing)
             }
         }
     }
 
+    /// Makes the guest take the exception `cause`, with `tval` in `vstval`, as if it was
+    /// delegated to VS-mode.
+    fn inject_exception(&mut self, cause: usize, tval: usize) {
+        let vsstatus = read_csr!(CSR_VSSTATUS);
+        let mut new_vsstatus = vsstatus & !(SSTATUS_SPP | SSTATUS_SPIE | SSTATUS_SIE);
+        // `sstatus.SPP` tells whether the guest trapped from VS-mode or VU-mode.
+        new_vsstatus |= self.regs.guest_regs.sstatus & SSTATUS_SPP;
+        if vsstatus & SSTATUS_SIE != 0 {
+            new_vsstatus |= SSTATUS_SPIE;
+        }
+        write_csr!(CSR_VSSTATUS, new_vsstatus);
+        write_csr!(CSR_VSEPC, self.regs.guest_regs.sepc);
+        write_csr!(CSR_VSCAUSE, cause);
+        write_csr!(CSR_VSTVAL, tval);
+        self.regs.guest_regs.sepc = read_csr!(CSR_VSTVEC) & !0b11;
+        self.regs.guest_regs.sstatus |= SSTATUS_SPP;
+    }
+
+    /// Emulates `inst`, a CSR instruction reading a counter CSR. The counters are read-only,
+    /// writes are ignored.
+    fn emulate_counter_read(&mut self, inst: usize) {
+        let csr = ((inst >> 20) & 0xfff) as u16;
+        let rd = ((inst >> 7) & 0x1f) as u32;
+        let rs1 = (inst >> 15) & 0x1f;
+        let funct3 = (inst >> 12) & 0x7;
+        // `csrrw`, or `csrrs`/`csrrc` with a non-zero source.
+        if funct3 & 0x3 == 1 || rs1 != 0 {
+            warn!("Guest write to read-only counter CSR {:#x} ignored", csr);
+        }
+        let time = riscv::register::time::read();
+        let val = match csr {
+            CSR_TIME | CSR_TIMEH => time.wrapping_add(self.regs.vs_csrs.htimedelta),
+            // `cycle` and `instret` are the counters 0 and 2 of the virtual PMU.
+            _ => self
+                .pmu
+                .read_hw((csr & 0x7f) as usize, self.counter_ticks()) as usize,
+        };
+        let val = match csr {
+            CSR_CYCLEH | CSR_TIMEH | CSR_INSTRETH => val >> 32,
+            _ => val,
+        };
+        self.set_gpr_from_gpr_index(GprIndex::from_raw(rd).unwrap(), val);
+    }
+
+    /// Returns the time ticks counted by the guest `cycle` and `instret`, see
+    /// [`set_counter_offset`](Self::set_counter_offset).
+    fn counter_ticks(&self) -> u64 {
+        riscv::register::time::read().wrapping_sub(self.counter_offset) as u64
+    }
+
     fn handle_base_function(&mut self, base: BaseFunction) -> AxResult<()> {
         match base {
             BaseFunction::GetSepcificationVersion => {
@@ -391,9 +958,16 @@ impl RISCVVCpu {
                 let impl_version = sbi_rt::get_sbi_impl_version();
                 self.set_gpr_from_gpr_index(GprIndex::A1, impl_version);
             }
+            // The PMU is virtual, present if the guest gets any event.
+            BaseFunction::ProbeSbiExtension(extension)
+                if extension as usize == sbi_spec::pmu::EID_PMU =>
+            {
+                self.set_gpr_from_gpr_index(GprIndex::A1, !self.pmu.events().is_empty() as usize);
+            }
+            // Only the extensions handled here, whatever the host SBI has.
             BaseFunction::ProbeSbiExtension(extension) => {
-                let extension = sbi_rt::probe_extension(extension as usize).raw;
-                self.set_gpr_from_gpr_index(GprIndex::A1, extension);
+                let present = SbiMessage::has_extension(extension as usize);
+                self.set_gpr_from_gpr_index(GprIndex::A1, present as usize);
             }
             BaseFunction::GetMachineVendorID => {
                 let mvendorid = sbi_rt::get_mvendorid();
@@ -419,6 +993,7 @@ impl RISCVVCpu {
                 hart_mask,
                 hart_mask_base,
             } => {
+                self.pmu.fw_event(vpmu::FW_FENCE_I_SENT);
                 let sbi_ret = sbi_rt::remote_fence_i(hart_mask as usize, hart_mask_base as usize);
                 self.set_gpr_from_gpr_index(GprIndex::A0, sbi_ret.error);
                 self.set_gpr_from_gpr_index(GprIndex::A1, sbi_ret.value);
@@ -429,6 +1004,7 @@ impl RISCVVCpu {
                 start_addr,
                 size,
             } => {
+                self.pmu.fw_event(vpmu::FW_SFENCE_VMA_SENT);
                 let sbi_ret = sbi_rt::remote_sfence_vma(
                     hart_mask as usize,
                     hart_mask_base as usize,
@@ -442,35 +1018,110 @@ impl RISCVVCpu {
         Ok(())
     }
 
-    fn handle_pmu_function(&mut self, pmu: PmuFunction) -> AxResult<()> {
+    fn handle_hsm_function(&mut self, hsm: HsmFunction) -> AxResult<AxVCpuExitReason> {
         self.set_gpr_from_gpr_index(GprIndex::A0, 0);
-        match pmu {
-            PmuFunction::GetNumCounters => {
-                self.set_gpr_from_gpr_index(GprIndex::A1, sbi_rt::pmu_num_counters())
-            }
-            PmuFunction::GetCounterInfo(counter_index) => {
-                let sbi_ret = pmu_counter_get_info(counter_index as usize);
-                self.set_gpr_from_gpr_index(GprIndex::A0, sbi_ret.error);
-                self.set_gpr_from_gpr_index(GprIndex::A1, sbi_ret.value);
-            }
-            PmuFunction::StopCounter {
-                counter_index,
-                counter_mask,
-                stop_flags,
-            } => {
-                let sbi_ret = pmu_counter_stop(
-                    counter_index as usize,
-                    counter_mask as usize,
-                    stop_flags as usize,
-                );
-                self.set_gpr_from_gpr_index(GprIndex::A0, sbi_ret.error);
-                self.set_gpr_from_gpr_index(GprIndex::A1, sbi_ret.value);
-            }
+        match hsm {
+            HsmFunction::HartStart {
+                hartid,
+                start_addr,
+                opaque,
+            } => Ok(AxVCpuExitReason::CpuUp {
+                target_cpu: hartid,
+                entry_point: GuestPhysAddr::from(start_addr),
+                arg: opaque,
+            }),
+            HsmFunction::HartStop => Ok(AxVCpuExitReason::CpuDown),
+            HsmFunction::HartGetStatus { hartid } => Ok(AxVCpuExitReason::CpuStatus {
+                target_cpu: hartid,
+            }),
         }
+    }
+
+    fn handle_pmu_function(&mut self, pmu: PmuFunction) -> AxResult<()> {
+        let (error, value) = self.pmu.handle(pmu, self.counter_ticks());
+        self.set_gpr_from_gpr_index(GprIndex::A0, error as usize);
+        self.set_gpr_from_gpr_index(GprIndex::A1, value);
         Ok(())
     }
 }
 
+/// An interrupt injected into a vCPU, at the virtual supervisor level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqKind {
+    /// A software interrupt, i.e., an IPI.
+    Software,
+    /// A timer interrupt.
+    Timer,
+    /// An external interrupt, from a device.
+    External,
+}
+
+fn is_counter_csr(csr: u16) -> bool {
+    matches!(
+        csr,
+        CSR_CYCLE | CSR_TIME | CSR_INSTRET | CSR_CYCLEH | CSR_TIMEH | CSR_INSTRETH
+    )
+}
+
+impl IrqKind {
+    const fn hvip_bit(self) -> usize {
+        match self {
+            IrqKind::Software => traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
+            IrqKind::Timer => traps::interrupt::VIRTUAL_SUPERVISOR_TIMER,
+            IrqKind::External => traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL,
+        }
+    }
+}
+
+/// The architectural state of a vCPU, as saved by [`RISCVVCpu::save_state`].
+///
+/// All the fields are words, so the state can be stored as an array of words, see
+/// [`VCpuState::as_words`].
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct VCpuState {
+    pub gprs: [usize; 32],
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub vsstatus: usize,
+    pub vsie: usize,
+    pub vstvec: usize,
+    pub vsscratch: usize,
+    pub vsepc: usize,
+    pub vscause: usize,
+    pub vstval: usize,
+    pub vsatp: usize,
+    /// The register of the AIA selected by the guest, 0 without the AIA.
+    pub vsiselect: usize,
+    pub vstimecmp: usize,
+    /// The injected interrupts.
+    pub hvip: usize,
+    /// The guest time.
+    pub time: usize,
+}
+
+impl VCpuState {
+    /// The number of words of the state.
+    pub const NUM_WORDS: usize = size_of::<Self>() / size_of::<usize>();
+
+    /// Returns the state as an array of words.
+    pub fn as_words(&self) -> &[usize] {
+        // SAFETY: `Self` is `repr(C)` with only `usize` fields.
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const usize, Self::NUM_WORDS) }
+    }
+
+    /// Returns the state from the array of words returned by [`as_words`](Self::as_words).
+    pub fn from_words(words: &[usize]) -> Option<Self> {
+        let mut state = Self::default();
+        // SAFETY: `Self` is `repr(C)` with only `usize` fields.
+        let dst = unsafe {
+            core::slice::from_raw_parts_mut(&mut state as *mut Self as *mut usize, Self::NUM_WORDS)
+        };
+        dst.copy_from_slice(words.get(..Self::NUM_WORDS)?);
+        Some(state)
+    }
+}
+
 /// The width of an access.
 ///
 /// Note that the term "word" here refers to 16-bit data, as in the x86 architecture.
@@ -557,7 +1208,55 @@ pub enum AxVCpuExitReason {
         /// The access flags of the fault.
         access_flags: MappingFlags,
     },
-    /// The vcpu is halted.
+    /// The vcpu asks to start another vcpu of the same VM (SBI HSM `hart_start`).
+    ///
+    /// The SBI call returns success unless the hypervisor overwrites `a0` with an error code.
+    CpuUp {
+        /// The hart ID of the vcpu to start.
+        target_cpu: usize,
+        /// The guest physical address the vcpu starts at.
+        entry_point: GuestPhysAddr,
+        /// The opaque argument passed to the vcpu in `a1`.
+        arg: usize,
+    },
+    /// The vcpu asks for the state of a vcpu of the same VM (SBI HSM `hart_get_status`).
+    ///
+    /// The hypervisor puts the HSM state of the vcpu in `a1`, or an error code in `a0`.
+    CpuStatus {
+        /// The hart ID of the vcpu.
+        target_cpu: usize,
+    },
+    /// The vcpu sends a software interrupt to other vcpus of the same VM (SBI IPI `send_ipi`).
+    ///
+    /// The SBI call returns success unless the hypervisor overwrites `a0` with an error code.
+    SendIpi {
+        /// The bitmask of target hart IDs, relative to `hart_mask_base`.
+        hart_mask: usize,
+        /// The hart ID the mask starts from, or `usize::MAX` for all harts.
+        hart_mask_base: usize,
+    },
+    /// The vcpu executed `ebreak` at `pc`, with breakpoint exits enabled, see
+    /// [`RISCVVCpu::set_breakpoint_exits`]. The guest resumes at the `ebreak` again.
+    Breakpoint {
+        /// The guest virtual address of the `ebreak`.
+        pc: usize,
+    },
+    /// The vcpu executed an instruction with single-stepping enabled, see
+    /// [`RISCVVCpu::set_single_step`], or took a trap to `vstvec`.
+    SingleStep {
+        /// The guest virtual address of the next instruction.
+        pc: usize,
+    },
+    /// The vcpu accessed its IMSIC interrupt file, emulated by the hypervisor with the AIA of
+    /// the host, see [`has_aia`](crate::has_aia). The hypervisor completes the access with
+    /// [`RISCVVCpu::complete_imsic_access`].
+    ImsicAccess {
+        /// The decoded access.
+        access: ImsicAccess,
+    },
+    /// The vcpu executed `wfi` with no interrupt pending: it waits for one, and may be
+    /// descheduled until its timer fires or an interrupt is injected. The guest resumes after
+    /// the `wfi`.
     Halt,
     /// The vcpu is powered off.
     ///
@@ -565,8 +1264,19 @@ pub enum AxVCpuExitReason {
     CpuDown,
     /// The system should be powered off.
     ///
-    /// This is used to notify the hypervisor that the whole system should be powered off.
+    /// This is used to notify the hypervisor that the whole system should be powered off, with
+    /// SBI SRST `SHUTDOWN` or the legacy shutdown call. The guest does not resume.
     SystemDown,
+    /// The system should be rebooted, with SBI SRST `COLD_REBOOT` or `WARM_REBOOT`.
+    ///
+    /// The guest does not resume: the hypervisor restarts it from its kernel image.
+    SystemReset,
+    /// The vcpu trapped with a cause the hypervisor cannot handle, see
+    /// [`RISCVVCpu::trap_csrs`] for the details. The guest cannot resume.
+    UnhandledTrap {
+        /// The `scause` of the trap.
+        scause: usize,
+    },
     /// Nothing special happened, the vcpu has handled the exit itself.
     ///
     /// This exists to allow the caller to have a chance to check virtual devices/physical devices/virtual interrupts.
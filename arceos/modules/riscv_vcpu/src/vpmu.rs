@@ -0,0 +1,319 @@
+//! The virtual PMU of a vCPU, behind the SBI PMU extension.
+//!
+//! The counters are emulated, the host ones being per hart while the vCPU may move between
+//! harts. Counter 0 is `cycle` and counter 2 `instret`, both counting the time ticks, see
+//! [`RISCVVCpu::set_counter_offset`], and counter 1 is `time`, which monitors no event. The
+//! other counters are firmware ones, counting the SBI calls and the traps handled for the
+//! guest, e.g. the misaligned accesses emulated. The guest gets the events the hypervisor
+//! shares only, see [`PmuEvents`].
+//!
+//! [`RISCVVCpu::set_counter_offset`]: crate::RISCVVCpu::set_counter_offset
+
+use crate::sbi::{
+    PmuFunction, SBI_ERR_ALREADY_STARTED, SBI_ERR_ALREADY_STOPPED, SBI_ERR_INAVLID_PARAM,
+    SBI_ERR_NOT_SUPPORTED,
+};
+
+/// The events of the virtual PMU shared with the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmuEvents {
+    /// The CPU cycles, counted by `cycle`.
+    pub cycles: bool,
+    /// The retired instructions, counted by `instret`.
+    pub instructions: bool,
+    /// The firmware events, e.g. the SBI `set_timer` calls.
+    pub firmware: bool,
+}
+
+impl PmuEvents {
+    /// All the events.
+    pub const ALL: Self = Self {
+        cycles: true,
+        instructions: true,
+        firmware: true,
+    };
+    /// No event: the guest does not get the SBI PMU extension.
+    pub const NONE: Self = Self {
+        cycles: false,
+        instructions: false,
+        firmware: false,
+    };
+
+    /// Whether no event is shared.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::NONE
+    }
+}
+
+/// The firmware events counted, by their event codes.
+pub(crate) const FW_MISALIGNED_LOAD: usize = 0;
+pub(crate) const FW_MISALIGNED_STORE: usize = 1;
+pub(crate) const FW_SET_TIMER: usize = 5;
+pub(crate) const FW_IPI_SENT: usize = 6;
+pub(crate) const FW_IPI_RECEIVED: usize = 7;
+pub(crate) const FW_FENCE_I_SENT: usize = 8;
+pub(crate) const FW_SFENCE_VMA_SENT: usize = 10;
+/// The number of firmware event codes of the SBI specification.
+const NUM_FW_EVENTS: usize = 22;
+
+/// The event types, in bits 19:16 of the event index.
+const EVENT_TYPE_HW: usize = 0;
+const EVENT_TYPE_FW: usize = 0xf;
+/// The codes of the hardware events.
+const HW_CPU_CYCLES: usize = 1;
+const HW_INSTRUCTIONS: usize = 2;
+
+/// The hardware counters, at the indexes of their CSRs from `cycle`.
+const COUNTER_CYCLE: usize = 0;
+const COUNTER_TIME: usize = 1;
+const COUNTER_INSTRET: usize = 2;
+const NUM_HW_COUNTERS: usize = 3;
+const NUM_FW_COUNTERS: usize = 8;
+const NUM_COUNTERS: usize = NUM_HW_COUNTERS + NUM_FW_COUNTERS;
+
+/// The `counter_info` of a 64-bit hardware counter, with its CSR.
+const INFO_HW_WIDTH_64: usize = 63 << 12;
+const CSR_CYCLE: usize = 0xc00;
+/// The `counter_info` of a firmware counter.
+const INFO_FW: usize = 1 << 63;
+
+const CFG_FLAG_SKIP_MATCH: usize = 1 << 0;
+const CFG_FLAG_CLEAR_VALUE: usize = 1 << 1;
+const CFG_FLAG_AUTO_START: usize = 1 << 2;
+const START_SET_INIT_VALUE: usize = 1 << 0;
+const STOP_FLAG_RESET: usize = 1 << 0;
+
+#[derive(Clone, Copy)]
+struct Counter {
+    /// The event index the guest configured the counter for, if any.
+    event: Option<usize>,
+    running: bool,
+    /// Subtracted from the ticks counted for a running hardware counter.
+    offset: u64,
+    /// The value of a stopped hardware counter, or of a firmware counter.
+    value: u64,
+}
+
+/// The virtual PMU of a vCPU.
+pub(crate) struct VirtPmu {
+    events: PmuEvents,
+    counters: [Counter; NUM_COUNTERS],
+}
+
+impl VirtPmu {
+    pub fn new() -> Self {
+        let counter = Counter {
+            event: None,
+            running: false,
+            offset: 0,
+            value: 0,
+        };
+        let mut counters = [counter; NUM_COUNTERS];
+        // The hardware counters run until configured, as the guest may read `cycle` and
+        // `instret` without the PMU.
+        counters[COUNTER_CYCLE].running = true;
+        counters[COUNTER_INSTRET].running = true;
+        Self {
+            events: PmuEvents::ALL,
+            counters,
+        }
+    }
+
+    pub fn events(&self) -> PmuEvents {
+        self.events
+    }
+
+    pub fn set_events(&mut self, events: PmuEvents) {
+        self.events = events;
+    }
+
+    /// Returns the value of the hardware counter `idx`, with `ticks` counted so far.
+    pub fn read_hw(&self, idx: usize, ticks: u64) -> u64 {
+        let counter = &self.counters[idx];
+        if counter.running {
+            ticks.wrapping_sub(counter.offset)
+        } else {
+            counter.value
+        }
+    }
+
+    /// Counts an occurrence of the firmware event `code`.
+    pub fn fw_event(&mut self, code: usize) {
+        let event = EVENT_TYPE_FW << 16 | code;
+        for counter in &mut self.counters[NUM_HW_COUNTERS..] {
+            if counter.running && counter.event == Some(event) {
+                counter.value = counter.value.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Handles the SBI PMU call `func`, with `ticks` counted so far by the hardware counters.
+    /// Returns the SBI error and value.
+    pub fn handle(&mut self, func: PmuFunction, ticks: u64) -> (isize, usize) {
+        match func {
+            PmuFunction::GetNumCounters => (0, NUM_COUNTERS),
+            PmuFunction::GetCounterInfo(idx) => match idx as usize {
+                idx if idx < NUM_HW_COUNTERS => (0, INFO_HW_WIDTH_64 | (CSR_CYCLE + idx)),
+                idx if idx < NUM_COUNTERS => (0, INFO_FW),
+                _ => (SBI_ERR_INAVLID_PARAM, 0),
+            },
+            PmuFunction::ConfigMatching {
+                counter_index,
+                counter_mask,
+                config_flags,
+                event_index,
+                event_data: _,
+            } => self.config_matching(
+                counter_index as usize,
+                counter_mask as usize,
+                config_flags as usize,
+                event_index as usize,
+                ticks,
+            ),
+            PmuFunction::StartCounter {
+                counter_index,
+                counter_mask,
+                start_flags,
+                initial_value,
+            } => {
+                let init =
+                    (start_flags as usize & START_SET_INIT_VALUE != 0).then_some(initial_value);
+                self.for_each_counter(counter_index as usize, counter_mask as usize, |pmu, idx| {
+                    pmu.start(idx, init, ticks)
+                })
+            }
+            PmuFunction::StopCounter {
+                counter_index,
+                counter_mask,
+                stop_flags,
+            } => {
+                let reset = stop_flags as usize & STOP_FLAG_RESET != 0;
+                self.for_each_counter(counter_index as usize, counter_mask as usize, |pmu, idx| {
+                    pmu.stop(idx, reset, ticks)
+                })
+            }
+            PmuFunction::ReadFirmwareCounter(idx) => match self.counters.get(idx as usize) {
+                Some(counter) if idx as usize >= NUM_HW_COUNTERS && counter.event.is_some() => {
+                    (0, counter.value as usize)
+                }
+                _ => (SBI_ERR_INAVLID_PARAM, 0),
+            },
+            // The counters fit in the registers on RV64.
+            PmuFunction::ReadFirmwareCounterHigh(_) => (0, 0),
+            PmuFunction::Unsupported(_) => (SBI_ERR_NOT_SUPPORTED, 0),
+        }
+    }
+
+    /// Whether the guest may monitor `event` with the counter `idx`.
+    fn can_monitor(&self, idx: usize, event: usize) -> bool {
+        let (kind, code) = ((event >> 16) & 0xf, event & 0xffff);
+        match (kind, code) {
+            (EVENT_TYPE_HW, HW_CPU_CYCLES) => self.events.cycles && idx == COUNTER_CYCLE,
+            (EVENT_TYPE_HW, HW_INSTRUCTIONS) => self.events.instructions && idx == COUNTER_INSTRET,
+            (EVENT_TYPE_FW, code) if code < NUM_FW_EVENTS => {
+                self.events.firmware && idx >= NUM_HW_COUNTERS
+            }
+            _ => false,
+        }
+    }
+
+    fn config_matching(
+        &mut self,
+        base: usize,
+        mask: usize,
+        flags: usize,
+        event: usize,
+        ticks: u64,
+    ) -> (isize, usize) {
+        let mut selected = (0..usize::BITS as usize)
+            .filter(|bit| mask & 1 << bit != 0)
+            .map(|bit| base.wrapping_add(bit))
+            .filter(|&idx| idx < NUM_COUNTERS && idx != COUNTER_TIME);
+        // With `SKIP_MATCH`, the guest picks the counter it configured before.
+        let idx = if flags & CFG_FLAG_SKIP_MATCH != 0 {
+            selected.next()
+        } else {
+            selected.find(|&idx| self.counters[idx].event.is_none())
+        };
+        let Some(idx) = idx.filter(|&idx| self.can_monitor(idx, event)) else {
+            return (SBI_ERR_NOT_SUPPORTED, 0);
+        };
+        let value = if flags & CFG_FLAG_CLEAR_VALUE != 0 {
+            0
+        } else {
+            self.read(idx, ticks)
+        };
+        let counter = &mut self.counters[idx];
+        counter.event = Some(event);
+        counter.running = false;
+        counter.value = value;
+        if flags & CFG_FLAG_AUTO_START != 0 {
+            self.start(idx, None, ticks);
+        }
+        (0, idx)
+    }
+
+    /// Applies `f` to the counters selected by `base` and `mask`, returning the first error.
+    fn for_each_counter(
+        &mut self,
+        base: usize,
+        mask: usize,
+        mut f: impl FnMut(&mut Self, usize) -> isize,
+    ) -> (isize, usize) {
+        let mut error = 0;
+        for bit in (0..usize::BITS as usize).filter(|bit| mask & 1 << bit != 0) {
+            let idx = base.wrapping_add(bit);
+            let res = match self.counters.get(idx) {
+                Some(counter) if counter.event.is_some() => f(self, idx),
+                _ => SBI_ERR_INAVLID_PARAM,
+            };
+            if error == 0 {
+                error = res;
+            }
+        }
+        (error, 0)
+    }
+
+    fn read(&self, idx: usize, ticks: u64) -> u64 {
+        if idx < NUM_HW_COUNTERS {
+            self.read_hw(idx, ticks)
+        } else {
+            self.counters[idx].value
+        }
+    }
+
+    fn start(&mut self, idx: usize, init: Option<u64>, ticks: u64) -> isize {
+        let counter = &mut self.counters[idx];
+        if counter.running {
+            return SBI_ERR_ALREADY_STARTED;
+        }
+        if let Some(init) = init {
+            counter.value = init;
+        }
+        counter.running = true;
+        counter.offset = ticks.wrapping_sub(counter.value);
+        0
+    }
+
+    fn stop(&mut self, idx: usize, reset: bool, ticks: u64) -> isize {
+        let running = self.counters[idx].running;
+        if running && idx < NUM_HW_COUNTERS {
+            self.counters[idx].value = self.read_hw(idx, ticks);
+        }
+        let counter = &mut self.counters[idx];
+        counter.running = false;
+        // Released hardware counters run freely again, as before being configured.
+        if reset {
+            counter.event = None;
+            if idx < NUM_HW_COUNTERS {
+                counter.running = true;
+                counter.offset = ticks.wrapping_sub(counter.value);
+            }
+        }
+        if running {
+            0
+        } else {
+            SBI_ERR_ALREADY_STOPPED
+        }
+    }
+}
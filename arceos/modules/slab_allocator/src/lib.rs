@@ -0,0 +1,202 @@
+//! Slab byte allocator for small, fixed-size kernel objects.
+//!
+//! Allocations are rounded up to one of the size classes from 16 to 4096
+//! bytes. Each class carves pages from a page allocator into objects of its
+//! size, so objects of the same kind are packed together. Larger allocations
+//! take pages from the page allocator directly.
+//!
+//! The free lists are linked through the free objects themselves, so the
+//! memory given to the allocator must be accessible.
+
+#![no_std]
+
+use alloc_stats::{AllocatorStats, MemoryStats};
+use allocator::{AllocError, AllocResult, BaseAllocator, ByteAllocator, PageAllocator};
+use buddy_allocator::BuddyPageAllocator;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+#[cfg(test)]
+mod tests;
+
+/// The size of the smallest class.
+pub const MIN_OBJECT_SIZE: usize = 16;
+/// The size of the largest class, larger allocations are served by pages.
+pub const MAX_OBJECT_SIZE: usize = 4096;
+
+const NUM_CLASSES: usize =
+    (MAX_OBJECT_SIZE.trailing_zeros() - MIN_OBJECT_SIZE.trailing_zeros()) as usize + 1;
+
+/// Marks the end of a free list.
+const NIL: usize = 0;
+
+/// The default page backend.
+pub type DefaultPageAllocator = BuddyPageAllocator<0x1000>;
+
+/// A byte allocator with per-size-class slabs over the page allocator `P`.
+///
+/// Pages taken by a slab are not given back to `P`, freed objects are kept
+/// for later allocations of the same class.
+pub struct SlabByteAllocator<P = DefaultPageAllocator> {
+    pages: P,
+    free_lists: [usize; NUM_CLASSES],
+    used_bytes: usize,
+}
+
+impl SlabByteAllocator {
+    /// Creates an empty [`SlabByteAllocator`] over a buddy page allocator.
+    pub const fn new() -> Self {
+        Self::with_backend(BuddyPageAllocator::new())
+    }
+}
+
+impl Default for SlabByteAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: PageAllocator> SlabByteAllocator<P> {
+    /// Creates an empty [`SlabByteAllocator`] over the page allocator `pages`.
+    pub const fn with_backend(pages: P) -> Self {
+        assert!(P::PAGE_SIZE >= MAX_OBJECT_SIZE);
+        Self {
+            pages,
+            free_lists: [NIL; NUM_CLASSES],
+            used_bytes: 0,
+        }
+    }
+
+    /// Returns the page allocator.
+    pub fn backend(&self) -> &P {
+        &self.pages
+    }
+
+    /// Returns the size class of `layout`, or `None` if it is served by pages.
+    fn class_of(layout: Layout) -> Option<usize> {
+        let size = layout
+            .size()
+            .max(layout.align())
+            .max(MIN_OBJECT_SIZE)
+            .next_power_of_two();
+        if size > MAX_OBJECT_SIZE {
+            return None;
+        }
+        Some((size.trailing_zeros() - MIN_OBJECT_SIZE.trailing_zeros()) as usize)
+    }
+
+    const fn class_size(class: usize) -> usize {
+        MIN_OBJECT_SIZE << class
+    }
+
+    fn num_pages(layout: Layout) -> usize {
+        layout.size().div_ceil(P::PAGE_SIZE)
+    }
+
+    /// Fills the free list of `class` with the objects of a new slab.
+    fn grow(&mut self, class: usize) -> AllocResult {
+        let slab = self.pages.alloc_pages(1, P::PAGE_SIZE)?;
+        let size = Self::class_size(class);
+        for obj in (slab..slab + P::PAGE_SIZE).step_by(size).rev() {
+            // SAFETY: the object is in the slab just allocated.
+            unsafe { set_next(obj, self.free_lists[class]) };
+            self.free_lists[class] = obj;
+        }
+        Ok(())
+    }
+}
+
+impl<P: PageAllocator> BaseAllocator for SlabByteAllocator<P> {
+    fn init(&mut self, start: usize, size: usize) {
+        self.free_lists = [NIL; NUM_CLASSES];
+        self.used_bytes = 0;
+        self.pages.init(start, size);
+    }
+
+    fn add_memory(&mut self, start: usize, size: usize) -> AllocResult {
+        self.pages.add_memory(start, size)
+    }
+}
+
+impl<P: PageAllocator> ByteAllocator for SlabByteAllocator<P> {
+    fn alloc(&mut self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        if layout.size() == 0 {
+            return Err(AllocError::InvalidParam);
+        }
+        let pos = match Self::class_of(layout) {
+            Some(class) => {
+                if self.free_lists[class] == NIL {
+                    self.grow(class)?;
+                }
+                let obj = self.free_lists[class];
+                // SAFETY: objects in the free lists are free memory.
+                self.free_lists[class] = unsafe { next_of(obj) };
+                self.used_bytes += Self::class_size(class);
+                obj
+            }
+            None => {
+                let num_pages = Self::num_pages(layout);
+                let pos = self
+                    .pages
+                    .alloc_pages(num_pages, layout.align().max(P::PAGE_SIZE))?;
+                self.used_bytes += num_pages * P::PAGE_SIZE;
+                pos
+            }
+        };
+        NonNull::new(pos as *mut u8).ok_or(AllocError::NoMemory)
+    }
+
+    fn dealloc(&mut self, pos: NonNull<u8>, layout: Layout) {
+        let pos = pos.as_ptr() as usize;
+        match Self::class_of(layout) {
+            Some(class) => {
+                // SAFETY: the object is freed.
+                unsafe { set_next(pos, self.free_lists[class]) };
+                self.free_lists[class] = pos;
+                self.used_bytes -= Self::class_size(class);
+            }
+            None => {
+                let num_pages = Self::num_pages(layout);
+                self.pages.dealloc_pages(pos, num_pages);
+                self.used_bytes -= num_pages * P::PAGE_SIZE;
+            }
+        }
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.pages.total_pages() * P::PAGE_SIZE
+    }
+
+    fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    fn available_bytes(&self) -> usize {
+        self.total_bytes() - self.used_bytes
+    }
+}
+
+impl<P: PageAllocator + AllocatorStats> AllocatorStats for SlabByteAllocator<P> {
+    /// Free objects in the slabs count as free blocks of their class size,
+    /// along with the free blocks of the page allocator.
+    fn memory_stats(&self) -> MemoryStats {
+        let mut stats = self.pages.memory_stats();
+        for (class, &head) in self.free_lists.iter().enumerate() {
+            let mut obj = head;
+            while obj != NIL {
+                stats.add_block(Self::class_size(class));
+                // SAFETY: objects in the free lists are free memory.
+                obj = unsafe { next_of(obj) };
+            }
+        }
+        stats
+    }
+}
+
+unsafe fn next_of(obj: usize) -> usize {
+    *(obj as *const usize)
+}
+
+unsafe fn set_next(obj: usize, next: usize) {
+    *(obj as *mut usize) = next;
+}
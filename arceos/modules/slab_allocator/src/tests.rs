@@ -0,0 +1,86 @@
+extern crate std;
+
+use super::*;
+use alloc_stats::test_util::TestMemory;
+
+const PAGE_SIZE: usize = 0x1000;
+const SIZE: usize = 16 * PAGE_SIZE;
+
+fn new_allocator() -> (TestMemory, SlabByteAllocator) {
+    let mem = TestMemory::new(SIZE, SIZE);
+    let mut a = SlabByteAllocator::new();
+    a.init(mem.start(), SIZE);
+    (mem, a)
+}
+
+#[test]
+fn test_size_classes() {
+    let (_mem, mut a) = new_allocator();
+    let l24 = Layout::from_size_align(24, 8).unwrap();
+    let p1 = a.alloc(l24).unwrap().as_ptr() as usize;
+    let p2 = a.alloc(l24).unwrap().as_ptr() as usize;
+    // Objects of the same class are packed in one slab.
+    assert_eq!(p2, p1 + 32);
+    assert_eq!(a.used_bytes(), 64);
+    assert_eq!(a.backend().used_pages(), 1);
+
+    // Another class takes another slab.
+    let l100 = Layout::from_size_align(100, 4).unwrap();
+    let p3 = a.alloc(l100).unwrap().as_ptr() as usize;
+    assert_eq!(p3 % 128, 0);
+    assert_eq!(a.backend().used_pages(), 2);
+
+    // Alignment selects a larger class.
+    let aligned = Layout::from_size_align(8, 256).unwrap();
+    assert_eq!(a.alloc(aligned).unwrap().as_ptr() as usize % 256, 0);
+}
+
+#[test]
+fn test_reuse() {
+    let (_mem, mut a) = new_allocator();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let p = a.alloc(layout).unwrap();
+    a.dealloc(p, layout);
+    assert_eq!(a.used_bytes(), 0);
+    assert_eq!(a.alloc(layout), Ok(p));
+
+    // A full slab grows by another page.
+    for _ in 1..PAGE_SIZE / 64 {
+        a.alloc(layout).unwrap();
+    }
+    assert_eq!(a.backend().used_pages(), 1);
+    a.alloc(layout).unwrap();
+    assert_eq!(a.backend().used_pages(), 2);
+}
+
+#[test]
+fn test_large() {
+    let (_mem, mut a) = new_allocator();
+    let layout = Layout::from_size_align(3 * PAGE_SIZE + 1, 8).unwrap();
+    let p = a.alloc(layout).unwrap();
+    assert_eq!(p.as_ptr() as usize % PAGE_SIZE, 0);
+    assert_eq!(a.used_bytes(), 4 * PAGE_SIZE);
+    a.dealloc(p, layout);
+    assert_eq!(a.used_bytes(), 0);
+    assert_eq!(a.backend().used_pages(), 0);
+
+    let empty = Layout::from_size_align(0, 1).unwrap();
+    assert_eq!(a.alloc(empty), Err(AllocError::InvalidParam));
+    let huge = Layout::from_size_align(SIZE + 1, 8).unwrap();
+    assert_eq!(a.alloc(huge), Err(AllocError::NoMemory));
+}
+
+#[test]
+fn test_memory_stats() {
+    let (_mem, mut a) = new_allocator();
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let p = a.alloc(layout).unwrap();
+    // Three free objects in the slab, and the 15 other pages.
+    let stats = a.memory_stats();
+    assert_eq!(stats.free_bytes, 3 * 1024 + 15 * PAGE_SIZE);
+    assert_eq!(stats.largest_free_block, 8 * PAGE_SIZE);
+    assert_eq!(stats.free_blocks, 3 + 4);
+
+    a.dealloc(p, layout);
+    assert_eq!(a.free_block_count(), 4 + 4);
+}
@@ -0,0 +1,360 @@
+//! Two-level segregated fit (TLSF) byte allocator.
+//!
+//! Free blocks are kept in lists indexed by two levels of size classes: the
+//! first level by powers of two, the second level splits each of them into
+//! [`SL_COUNT`] linear ranges. Two bitmaps tell which lists are not empty, so
+//! a fitting block is found, and a freed block is merged with its free
+//! neighbours, in constant time. The bounded size classes also bound the
+//! fragmentation.
+//!
+//! Block headers and free list links are stored in the managed memory, so it
+//! must be accessible.
+
+#![no_std]
+
+use alloc_stats::{AllocatorStats, MemoryStats};
+use allocator::{AllocError, AllocResult, BaseAllocator, ByteAllocator};
+use core::alloc::Layout;
+use core::ptr::{null_mut, NonNull};
+
+#[cfg(test)]
+mod tests;
+
+/// The log2 of the number of second-level lists per first-level class.
+pub const SL_LOG2: u32 = 4;
+/// The number of second-level lists per first-level class.
+pub const SL_COUNT: usize = 1 << SL_LOG2;
+
+const ALIGN: usize = core::mem::size_of::<usize>();
+/// Sizes below are served by the first first-level class, split linearly.
+const SMALL_SIZE: usize = SL_COUNT * ALIGN;
+const FL_SHIFT: u32 = SMALL_SIZE.trailing_zeros();
+const FL_COUNT: usize = (usize::BITS - FL_SHIFT + 1) as usize;
+
+/// The header before the payload of each block: `prev_phys` and `size`.
+const HEADER_SIZE: usize = 2 * ALIGN;
+/// A free block stores its list links in the payload.
+const MIN_PAYLOAD: usize = 2 * ALIGN;
+
+const BLOCK_FREE: usize = 1;
+const BLOCK_PREV_FREE: usize = 2;
+const BLOCK_FLAGS: usize = BLOCK_FREE | BLOCK_PREV_FREE;
+
+#[repr(C)]
+struct Block {
+    /// The previous block in memory, valid only if it is free.
+    prev_phys: *mut Block,
+    /// The payload size, with the flags in the low bits.
+    size: usize,
+    /// Links of the free list, valid only if the block is free.
+    next_free: *mut Block,
+    prev_free: *mut Block,
+}
+
+impl Block {
+    unsafe fn size(b: *mut Self) -> usize {
+        (*b).size & !BLOCK_FLAGS
+    }
+
+    unsafe fn set_size(b: *mut Self, size: usize) {
+        (*b).size = size | ((*b).size & BLOCK_FLAGS);
+    }
+
+    unsafe fn is_free(b: *mut Self) -> bool {
+        (*b).size & BLOCK_FREE != 0
+    }
+
+    unsafe fn is_prev_free(b: *mut Self) -> bool {
+        (*b).size & BLOCK_PREV_FREE != 0
+    }
+
+    unsafe fn set_flag(b: *mut Self, flag: usize, on: bool) {
+        if on {
+            (*b).size |= flag;
+        } else {
+            (*b).size &= !flag;
+        }
+    }
+
+    unsafe fn payload(b: *mut Self) -> usize {
+        b as usize + HEADER_SIZE
+    }
+
+    unsafe fn from_payload(ptr: usize) -> *mut Self {
+        (ptr - HEADER_SIZE) as *mut Self
+    }
+
+    unsafe fn next_phys(b: *mut Self) -> *mut Self {
+        (Self::payload(b) + Self::size(b)) as *mut Self
+    }
+
+    /// Marks `b` as free or used, in itself and in the next block.
+    unsafe fn mark_free(b: *mut Self, free: bool) {
+        let next = Self::next_phys(b);
+        Self::set_flag(b, BLOCK_FREE, free);
+        Self::set_flag(next, BLOCK_PREV_FREE, free);
+        if free {
+            (*next).prev_phys = b;
+        }
+    }
+}
+
+/// A TLSF byte allocator.
+///
+/// Each memory region given to `init` or `add_memory` becomes a pool ending
+/// with a used sentinel block, so that blocks of different pools are never
+/// merged.
+pub struct TlsfByteAllocator {
+    fl_bitmap: usize,
+    sl_bitmaps: [usize; FL_COUNT],
+    blocks: [[*mut Block; SL_COUNT]; FL_COUNT],
+    total_bytes: usize,
+    used_bytes: usize,
+}
+
+// SAFETY: the blocks are only accessed through `&mut self`.
+unsafe impl Send for TlsfByteAllocator {}
+
+/// Returns the list of the blocks of `size`.
+const fn mapping(size: usize) -> (usize, usize) {
+    if size < SMALL_SIZE {
+        (0, size / ALIGN)
+    } else {
+        let fl = usize::BITS - 1 - size.leading_zeros();
+        let sl = (size >> (fl - SL_LOG2)) ^ SL_COUNT;
+        ((fl - FL_SHIFT + 1) as usize, sl)
+    }
+}
+
+/// Returns the first list whose blocks are all at least `size` bytes.
+const fn mapping_search(size: usize) -> (usize, usize) {
+    if size < SMALL_SIZE {
+        return mapping(size);
+    }
+    let fl = usize::BITS - 1 - size.leading_zeros();
+    let round = (1 << (fl - SL_LOG2)) - 1;
+    match size.checked_add(round) {
+        Some(size) => mapping(size),
+        None => (FL_COUNT, 0),
+    }
+}
+
+const fn align_up(pos: usize, align: usize) -> usize {
+    (pos + align - 1) & !(align - 1)
+}
+
+impl TlsfByteAllocator {
+    /// Creates an empty [`TlsfByteAllocator`].
+    pub const fn new() -> Self {
+        Self {
+            fl_bitmap: 0,
+            sl_bitmaps: [0; FL_COUNT],
+            blocks: [[null_mut(); SL_COUNT]; FL_COUNT],
+            total_bytes: 0,
+            used_bytes: 0,
+        }
+    }
+
+    unsafe fn insert(&mut self, b: *mut Block) {
+        let (fl, sl) = mapping(Block::size(b));
+        let head = self.blocks[fl][sl];
+        (*b).next_free = head;
+        (*b).prev_free = null_mut();
+        if !head.is_null() {
+            (*head).prev_free = b;
+        }
+        self.blocks[fl][sl] = b;
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmaps[fl] |= 1 << sl;
+    }
+
+    unsafe fn remove(&mut self, b: *mut Block) {
+        let (fl, sl) = mapping(Block::size(b));
+        let (next, prev) = ((*b).next_free, (*b).prev_free);
+        if !next.is_null() {
+            (*next).prev_free = prev;
+        }
+        if !prev.is_null() {
+            (*prev).next_free = next;
+        } else {
+            self.blocks[fl][sl] = next;
+            if next.is_null() {
+                self.sl_bitmaps[fl] &= !(1 << sl);
+                if self.sl_bitmaps[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+    }
+
+    /// Finds and removes a free block of at least `size` bytes.
+    unsafe fn take_block(&mut self, size: usize) -> Option<*mut Block> {
+        let (mut fl, sl) = mapping_search(size);
+        if fl >= FL_COUNT {
+            return None;
+        }
+        let mut sl_map = self.sl_bitmaps[fl] & (!0 << sl);
+        if sl_map == 0 {
+            let fl_map = self.fl_bitmap & (!0usize).checked_shl(fl as u32 + 1).unwrap_or(0);
+            if fl_map == 0 {
+                return None;
+            }
+            fl = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmaps[fl];
+        }
+        let b = self.blocks[fl][sl_map.trailing_zeros() as usize];
+        self.remove(b);
+        Some(b)
+    }
+
+    /// Splits `b` so that its payload is `size` bytes, and returns the rest
+    /// to the free lists if it can hold a block.
+    unsafe fn trim(&mut self, b: *mut Block, size: usize) {
+        let block_size = Block::size(b);
+        if block_size < size + HEADER_SIZE + MIN_PAYLOAD {
+            return;
+        }
+        let rest = (Block::payload(b) + size) as *mut Block;
+        (*rest).size = block_size - size - HEADER_SIZE;
+        Block::set_size(b, size);
+        Block::mark_free(rest, true);
+        self.insert(rest);
+    }
+
+    /// Splits `b` so that its payload starts `gap` bytes later, returns the
+    /// front part to the free lists and the back part.
+    unsafe fn trim_front(&mut self, b: *mut Block, gap: usize) -> *mut Block {
+        let back = (b as usize + gap) as *mut Block;
+        (*back).size = Block::size(b) - gap;
+        Block::set_size(b, gap - HEADER_SIZE);
+        Block::mark_free(b, true);
+        self.insert(b);
+        back
+    }
+
+    /// Frees `b`, merging it with its free neighbours.
+    unsafe fn free_block(&mut self, mut b: *mut Block) {
+        if Block::is_prev_free(b) {
+            let prev = (*b).prev_phys;
+            self.remove(prev);
+            Block::set_size(prev, Block::size(prev) + HEADER_SIZE + Block::size(b));
+            b = prev;
+        }
+        let next = Block::next_phys(b);
+        if Block::is_free(next) {
+            self.remove(next);
+            Block::set_size(b, Block::size(b) + HEADER_SIZE + Block::size(next));
+        }
+        Block::mark_free(b, true);
+        self.insert(b);
+    }
+}
+
+impl Default for TlsfByteAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BaseAllocator for TlsfByteAllocator {
+    fn init(&mut self, start: usize, size: usize) {
+        *self = Self::new();
+        self.add_memory(start, size).unwrap();
+    }
+
+    fn add_memory(&mut self, start: usize, size: usize) -> AllocResult {
+        let end = start.checked_add(size).ok_or(AllocError::InvalidParam)? & !(ALIGN - 1);
+        // Null marks the end of the free lists.
+        let start = align_up(start.max(ALIGN), ALIGN);
+        if start >= end || end - start < 2 * HEADER_SIZE + MIN_PAYLOAD {
+            return Err(AllocError::InvalidParam);
+        }
+        let b = start as *mut Block;
+        let sentinel = (end - HEADER_SIZE) as *mut Block;
+        // SAFETY: the region is given to the allocator.
+        unsafe {
+            (*b).prev_phys = null_mut();
+            (*b).size = end - start - 2 * HEADER_SIZE;
+            (*sentinel).size = 0;
+            Block::mark_free(b, true);
+            self.insert(b);
+        }
+        self.total_bytes += end - start;
+        Ok(())
+    }
+}
+
+impl ByteAllocator for TlsfByteAllocator {
+    fn alloc(&mut self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        if layout.size() == 0 {
+            return Err(AllocError::InvalidParam);
+        }
+        let size = align_up(layout.size(), ALIGN).max(MIN_PAYLOAD);
+        let align = layout.align();
+        // Room for a free block before the aligned payload.
+        let search = if align > ALIGN {
+            size.checked_add(align + HEADER_SIZE + MIN_PAYLOAD)
+        } else {
+            Some(size)
+        };
+        // SAFETY: the blocks in the free lists are free memory.
+        unsafe {
+            let mut b = search
+                .and_then(|search| self.take_block(search))
+                .ok_or(AllocError::NoMemory)?;
+            if align > ALIGN {
+                let payload = Block::payload(b);
+                let mut aligned = align_up(payload, align);
+                if aligned != payload && aligned - payload < HEADER_SIZE + MIN_PAYLOAD {
+                    aligned = align_up(payload + HEADER_SIZE + MIN_PAYLOAD, align);
+                }
+                if aligned != payload {
+                    b = self.trim_front(b, aligned - payload);
+                }
+            }
+            self.trim(b, size);
+            Block::mark_free(b, false);
+            self.used_bytes += Block::size(b);
+            NonNull::new(Block::payload(b) as *mut u8).ok_or(AllocError::NoMemory)
+        }
+    }
+
+    fn dealloc(&mut self, pos: NonNull<u8>, _layout: Layout) {
+        // SAFETY: `pos` is allocated by `alloc`.
+        unsafe {
+            let b = Block::from_payload(pos.as_ptr() as usize);
+            self.used_bytes -= Block::size(b);
+            self.free_block(b);
+        }
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    fn available_bytes(&self) -> usize {
+        self.total_bytes - self.used_bytes
+    }
+}
+
+impl AllocatorStats for TlsfByteAllocator {
+    /// The free blocks are counted by their payload size.
+    fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        for head in self.blocks.iter().flatten() {
+            let mut b = *head;
+            while !b.is_null() {
+                // SAFETY: the blocks in the free lists are free memory.
+                unsafe {
+                    stats.add_block(Block::size(b));
+                    b = (*b).next_free;
+                }
+            }
+        }
+        stats
+    }
+}
@@ -0,0 +1,115 @@
+extern crate std;
+
+use super::*;
+use alloc_stats::test_util::TestMemory;
+use core::alloc::Layout;
+use std::vec::Vec;
+
+const SIZE: usize = 0x10000;
+
+fn new_allocator() -> (TestMemory, TlsfByteAllocator) {
+    let mem = TestMemory::new(SIZE, 16);
+    let mut a = TlsfByteAllocator::new();
+    a.init(mem.start(), SIZE);
+    (mem, a)
+}
+
+/// The largest allocation that fits in a pool of `size` bytes.
+const fn max_alloc(size: usize) -> usize {
+    let max = size - 2 * HEADER_SIZE;
+    let fl = usize::BITS - 1 - max.leading_zeros();
+    // Round down to the start of the second-level list.
+    max & !((1 << (fl - SL_LOG2)) - 1)
+}
+
+#[test]
+fn test_mapping() {
+    assert_eq!(mapping(0), (0, 0));
+    assert_eq!(mapping(SMALL_SIZE - 1), (0, SL_COUNT - 1));
+    assert_eq!(mapping(SMALL_SIZE), (1, 0));
+    assert_eq!(mapping(SMALL_SIZE * 2 - 1), (1, SL_COUNT - 1));
+    assert_eq!(mapping(usize::MAX), (FL_COUNT - 1, SL_COUNT - 1));
+    // A search never returns a list with smaller blocks.
+    for size in [SMALL_SIZE + 1, 1000, 4097, (1 << 20) + 1] {
+        assert!(mapping_search(size) > mapping(size));
+    }
+    assert_eq!(mapping_search(1 << 20), mapping(1 << 20));
+    assert_eq!(mapping_search(usize::MAX).0, FL_COUNT);
+}
+
+#[test]
+fn test_alloc_merge() {
+    let (_mem, mut a) = new_allocator();
+    assert_eq!(a.total_bytes(), SIZE);
+
+    let layouts: Vec<_> = (1..64)
+        .map(|i| Layout::from_size_align(i * 13, 1 << (i % 7)).unwrap())
+        .collect();
+    let ptrs: Vec<_> = layouts.iter().map(|&l| a.alloc(l).unwrap()).collect();
+    for (p, l) in ptrs.iter().zip(&layouts) {
+        assert_eq!(p.as_ptr() as usize % l.align(), 0);
+        unsafe { p.as_ptr().write_bytes(0xaa, l.size()) };
+    }
+    assert!(a.used_bytes() >= layouts.iter().map(|l| l.size()).sum());
+
+    // Free every other block, then the rest, so that all merges happen.
+    for (p, l) in ptrs.iter().zip(&layouts).step_by(2) {
+        a.dealloc(*p, *l);
+    }
+    for (p, l) in ptrs.iter().zip(&layouts).skip(1).step_by(2) {
+        a.dealloc(*p, *l);
+    }
+    assert_eq!(a.used_bytes(), 0);
+
+    // The pool is a single block again.
+    let big = Layout::from_size_align(max_alloc(SIZE), 8).unwrap();
+    let p = a.alloc(big).unwrap();
+    assert_eq!(a.alloc(big), Err(AllocError::NoMemory));
+    a.dealloc(p, big);
+}
+
+#[test]
+fn test_pools() {
+    let (mem, mut a) = new_allocator();
+    let half = SIZE / 2;
+    a.init(mem.start(), half);
+    a.add_memory(mem.start() + half, half).unwrap();
+    assert_eq!(a.total_bytes(), SIZE);
+    assert_eq!(a.add_memory(mem.start(), 8), Err(AllocError::InvalidParam));
+
+    // Pools are never merged, so no block spans both halves.
+    let big = Layout::from_size_align(max_alloc(half), 8).unwrap();
+    let p1 = a.alloc(big).unwrap();
+    let p2 = a.alloc(big).unwrap();
+    assert_eq!(a.alloc(big), Err(AllocError::NoMemory));
+    a.dealloc(p1, big);
+    a.dealloc(p2, big);
+    let too_big = Layout::from_size_align(half, 8).unwrap();
+    assert_eq!(a.alloc(too_big), Err(AllocError::NoMemory));
+    assert_eq!(
+        a.alloc(Layout::from_size_align(0, 1).unwrap()),
+        Err(AllocError::InvalidParam)
+    );
+}
+
+#[test]
+fn test_memory_stats() {
+    let (_mem, mut a) = new_allocator();
+    let whole = SIZE - 2 * HEADER_SIZE;
+    assert_eq!(a.memory_stats().largest_free_block, whole);
+
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let ptrs: Vec<_> = (0..4).map(|_| a.alloc(layout).unwrap()).collect();
+    a.dealloc(ptrs[1], layout);
+    let stats = a.memory_stats();
+    assert_eq!(stats.free_blocks, 2);
+    assert_eq!(stats.free_bytes, 1024 + whole - 4 * (1024 + HEADER_SIZE));
+    assert!(stats.fragmentation_percent() > 0);
+
+    for &p in &ptrs[2..] {
+        a.dealloc(p, layout);
+    }
+    a.dealloc(ptrs[0], layout);
+    assert_eq!(a.free_block_count(), 1);
+    assert_eq!(a.fragmentation_percent(), 0);
+}
@@ -0,0 +1,125 @@
+//! The entries of the EPT page tables.
+//!
+//! They differ from the host ones in their attributes: an entry is present if
+//! any of its read, write and execute permissions is set, and the memory type
+//! of the guest physical pages is in the leaf entries.
+
+use core::fmt;
+use memory_addr::PhysAddr;
+use page_table_entry::{GenericPTE, MappingFlags};
+
+const EPT_READ: u64 = 1 << 0;
+const EPT_WRITE: u64 = 1 << 1;
+const EPT_EXECUTE: u64 = 1 << 2;
+const EPT_RWX: u64 = EPT_READ | EPT_WRITE | EPT_EXECUTE;
+const MEM_TYPE_SHIFT: u64 = 3;
+const MEM_TYPE_MASK: u64 = 0b111 << MEM_TYPE_SHIFT;
+/// Uncacheable memory.
+const MEM_TYPE_UC: u64 = 0 << MEM_TYPE_SHIFT;
+/// Write-back memory.
+const MEM_TYPE_WB: u64 = 6 << MEM_TYPE_SHIFT;
+/// The memory type of the entry applies, not combined with the guest `PAT`.
+const IGNORE_PAT: u64 = 1 << 6;
+/// A 2M or 1G page in a PDE or PDPTE, otherwise a table.
+const HUGE_PAGE: u64 = 1 << 7;
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// An entry of the EPT page tables, with 4K pages and 4 levels.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct EPTEntry(u64);
+
+impl EPTEntry {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+}
+
+impl GenericPTE for EPTEntry {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        let mut pte = Self(paddr.as_usize() as u64 & ADDR_MASK);
+        pte.set_flags(flags, is_huge);
+        pte
+    }
+
+    fn new_table(paddr: PhysAddr) -> Self {
+        Self(paddr.as_usize() as u64 & ADDR_MASK | EPT_RWX)
+    }
+
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((self.0 & ADDR_MASK) as usize)
+    }
+
+    fn flags(&self) -> MappingFlags {
+        let mut flags = MappingFlags::empty();
+        if self.0 & EPT_READ != 0 {
+            flags |= MappingFlags::READ;
+        }
+        if self.0 & EPT_WRITE != 0 {
+            flags |= MappingFlags::WRITE;
+        }
+        if self.0 & EPT_EXECUTE != 0 {
+            flags |= MappingFlags::EXECUTE;
+        }
+        if self.0 & MEM_TYPE_MASK == MEM_TYPE_UC {
+            flags |= MappingFlags::DEVICE;
+        }
+        flags
+    }
+
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 = self.0 & !ADDR_MASK | paddr.as_usize() as u64 & ADDR_MASK;
+    }
+
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let mut attr = IGNORE_PAT;
+        if is_huge {
+            attr |= HUGE_PAGE;
+        }
+        if flags.contains(MappingFlags::READ) {
+            attr |= EPT_READ;
+        }
+        if flags.contains(MappingFlags::WRITE) {
+            attr |= EPT_WRITE;
+        }
+        if flags.contains(MappingFlags::EXECUTE) {
+            attr |= EPT_EXECUTE;
+        }
+        attr |= if flags.intersects(MappingFlags::DEVICE | MappingFlags::UNCACHED) {
+            MEM_TYPE_UC
+        } else {
+            MEM_TYPE_WB
+        };
+        self.0 = self.0 & ADDR_MASK | attr;
+    }
+
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn is_present(&self) -> bool {
+        self.0 & EPT_RWX != 0
+    }
+
+    fn is_huge(&self) -> bool {
+        self.0 & HUGE_PAGE != 0
+    }
+
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+impl fmt::Debug for EPTEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EPTEntry")
+            .field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
@@ -0,0 +1,169 @@
+#![no_std]
+#![cfg(target_arch = "x86_64")]
+#![feature(asm_const)]
+#![doc = include_str!("../README.md")]
+
+#[macro_use]
+extern crate log;
+
+mod ept;
+mod vcpu;
+mod vmcs;
+
+use axerrno::{ax_err, ax_err_type, AxResult};
+use axhal::mem::{virt_to_phys, PAGE_SIZE_4K};
+use x86::controlregs::{cr0, cr0_write, cr4, cr4_write, Cr0, Cr4};
+use x86::msr::{rdmsr, wrmsr};
+
+pub use self::ept::EPTEntry;
+pub use self::vcpu::{
+    AccessWidth, AxVCpuExitReason, ExitInfo, GuestPhysAddr, GuestRegs, HostPhysAddr, X86VCpu,
+};
+
+pub(crate) const IA32_FEATURE_CONTROL: u32 = 0x3a;
+pub(crate) const IA32_VMX_BASIC: u32 = 0x480;
+pub(crate) const IA32_VMX_PINBASED_CTLS: u32 = 0x481;
+pub(crate) const IA32_VMX_PROCBASED_CTLS: u32 = 0x482;
+pub(crate) const IA32_VMX_EXIT_CTLS: u32 = 0x483;
+pub(crate) const IA32_VMX_ENTRY_CTLS: u32 = 0x484;
+pub(crate) const IA32_VMX_CR0_FIXED0: u32 = 0x486;
+pub(crate) const IA32_VMX_CR0_FIXED1: u32 = 0x487;
+pub(crate) const IA32_VMX_CR4_FIXED0: u32 = 0x488;
+pub(crate) const IA32_VMX_CR4_FIXED1: u32 = 0x489;
+pub(crate) const IA32_VMX_PROCBASED_CTLS2: u32 = 0x48b;
+pub(crate) const IA32_VMX_EPT_VPID_CAP: u32 = 0x48c;
+/// The `IA32_VMX_TRUE_*` capabilities, read instead of the ones above for the default-1
+/// controls that may be cleared.
+pub(crate) const IA32_VMX_TRUE_PINBASED_CTLS: u32 = 0x48d;
+pub(crate) const IA32_VMX_TRUE_PROCBASED_CTLS: u32 = 0x48e;
+pub(crate) const IA32_VMX_TRUE_EXIT_CTLS: u32 = 0x48f;
+pub(crate) const IA32_VMX_TRUE_ENTRY_CTLS: u32 = 0x490;
+pub(crate) const IA32_EFER: u32 = 0xc000_0080;
+pub(crate) const IA32_FS_BASE: u32 = 0xc000_0100;
+pub(crate) const IA32_GS_BASE: u32 = 0xc000_0101;
+pub(crate) const IA32_SYSENTER_CS: u32 = 0x174;
+pub(crate) const IA32_SYSENTER_ESP: u32 = 0x175;
+pub(crate) const IA32_SYSENTER_EIP: u32 = 0x176;
+
+const FEATURE_CONTROL_LOCKED: u64 = 1 << 0;
+const FEATURE_CONTROL_VMX_OUTSIDE_SMX: u64 = 1 << 2;
+/// The `IA32_VMX_BASIC` bit telling the `IA32_VMX_TRUE_*` capabilities exist.
+const VMX_BASIC_TRUE_CTLS: u64 = 1 << 55;
+/// The `IA32_VMX_PROCBASED_CTLS2` bits of the EPT and of the unrestricted guest mode, in the
+/// allowed 1-settings.
+const CTLS2_ENABLE_EPT: u64 = 1 << (32 + 1);
+const CTLS2_UNRESTRICTED_GUEST: u64 = 1 << (32 + 7);
+/// The `IA32_VMX_EPT_VPID_CAP` bits of the 4-level walks, of the write-back EPT structures, and
+/// of the single-context `invept`.
+const EPT_CAP_WALK_4: u64 = 1 << 6;
+const EPT_CAP_WB: u64 = 1 << 14;
+const EPT_CAP_INVEPT: u64 = 1 << 20;
+const EPT_CAP_INVEPT_SINGLE: u64 = 1 << 25;
+
+/// Whether the current CPU can run guests: it has VT-x, not disabled by the firmware, with the
+/// EPT and the unrestricted guest mode.
+pub fn has_hardware_support() -> bool {
+    let vmx = unsafe { core::arch::x86_64::__cpuid(1) }.ecx & (1 << 5) != 0;
+    if !vmx {
+        return false;
+    }
+    let feature_control = unsafe { rdmsr(IA32_FEATURE_CONTROL) };
+    if feature_control & FEATURE_CONTROL_LOCKED != 0
+        && feature_control & FEATURE_CONTROL_VMX_OUTSIDE_SMX == 0
+    {
+        return false;
+    }
+    let ctls2 = unsafe { rdmsr(IA32_VMX_PROCBASED_CTLS2) };
+    let ept_cap = unsafe { rdmsr(IA32_VMX_EPT_VPID_CAP) };
+    let ept_cap_needed = EPT_CAP_WALK_4 | EPT_CAP_WB | EPT_CAP_INVEPT | EPT_CAP_INVEPT_SINGLE;
+    ctls2 & CTLS2_ENABLE_EPT != 0
+        && ctls2 & CTLS2_UNRESTRICTED_GUEST != 0
+        && ept_cap & ept_cap_needed == ept_cap_needed
+}
+
+/// Returns the VMCS revision identifier of the CPU, at the start of the VMXON region and of the
+/// VMCSs.
+pub(crate) fn vmcs_revision_id() -> u32 {
+    unsafe { rdmsr(IA32_VMX_BASIC) as u32 & 0x7fff_ffff }
+}
+
+/// Allocates a zeroed 4K frame, for the VMXON region or a VMCS, returning its virtual address.
+pub(crate) fn alloc_frame() -> AxResult<usize> {
+    let vaddr = axalloc::global_allocator()
+        .alloc_pages(1, PAGE_SIZE_4K)
+        .map_err(|_| ax_err_type!(NoMemory))?;
+    unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, PAGE_SIZE_4K) };
+    Ok(vaddr)
+}
+
+/// Adjusts the VM-execution, VM-exit or VM-entry `controls` to the settings allowed by the
+/// capability MSR `msr`, or its `IA32_VMX_TRUE_*` variant `true_msr` if any.
+///
+/// Fails if one of the `controls` cannot be set.
+pub(crate) fn adjust_controls(msr: u32, true_msr: Option<u32>, controls: u32) -> AxResult<u32> {
+    let msr = match true_msr {
+        Some(true_msr) if unsafe { rdmsr(IA32_VMX_BASIC) } & VMX_BASIC_TRUE_CTLS != 0 => true_msr,
+        _ => msr,
+    };
+    let cap = unsafe { rdmsr(msr) };
+    let (allowed0, allowed1) = (cap as u32, (cap >> 32) as u32);
+    if controls & !allowed1 != 0 {
+        return ax_err!(
+            Unsupported,
+            format_args!("VMX controls {:#x} not allowed by MSR {:#x}", controls, msr)
+        );
+    }
+    Ok(controls | allowed0)
+}
+
+/// Enables VMX operation on the current CPU with `vmxon`.
+///
+/// It must be called once on every CPU running vCPUs, before they are run. The VMXON region is
+/// never freed.
+///
+/// # Safety
+///
+/// The current CPU must support running guests, see [`has_hardware_support`].
+pub unsafe fn setup_vmx() -> AxResult {
+    let feature_control = rdmsr(IA32_FEATURE_CONTROL);
+    if feature_control & FEATURE_CONTROL_LOCKED == 0 {
+        wrmsr(
+            IA32_FEATURE_CONTROL,
+            feature_control | FEATURE_CONTROL_LOCKED | FEATURE_CONTROL_VMX_OUTSIDE_SMX,
+        );
+    }
+
+    // The fixed bits of CR0 and CR4 in VMX operation.
+    let fixed0 = rdmsr(IA32_VMX_CR0_FIXED0) as usize;
+    let fixed1 = rdmsr(IA32_VMX_CR0_FIXED1) as usize;
+    cr0_write(Cr0::from_bits_truncate((cr0().bits() | fixed0) & fixed1));
+    let fixed0 = rdmsr(IA32_VMX_CR4_FIXED0) as usize;
+    let fixed1 = rdmsr(IA32_VMX_CR4_FIXED1) as usize;
+    cr4_write(Cr4::from_bits_truncate(
+        (cr4().bits() | Cr4::CR4_ENABLE_VMX.bits() | fixed0) & fixed1,
+    ));
+
+    let vmxon_region = alloc_frame()?;
+    *(vmxon_region as *mut u32) = vmcs_revision_id();
+    let paddr = virt_to_phys(vmxon_region.into()).as_usize() as u64;
+    if let Err(e) = x86::bits64::vmx::vmxon(paddr) {
+        axalloc::global_allocator().dealloc_pages(vmxon_region, 1);
+        return ax_err!(BadState, format_args!("vmxon: {:?}", e));
+    }
+    debug!("VMX enabled, VMXON region at {:#x}", paddr);
+    Ok(())
+}
+
+/// Invalidates the guest physical translations of the EPT `eptp` on the current CPU, with a
+/// single-context `invept`.
+pub(crate) fn invept_single(eptp: u64) {
+    let descriptor = [eptp, 0u64];
+    unsafe {
+        core::arch::asm!(
+            "invept {0}, [{1}]",
+            in(reg) 1u64,
+            in(reg) &descriptor,
+            options(nostack),
+        );
+    }
+}
@@ -0,0 +1,839 @@
+use core::arch::global_asm;
+use core::arch::x86_64::__cpuid_count;
+
+use axerrno::{ax_err, AxResult};
+use x86::bits64::vmx;
+use x86::controlregs::{cr0, cr3, cr4};
+use x86::dtables::{sgdt, sidt, DescriptorTablePointer};
+use x86::msr::rdmsr;
+
+use super::vmcs::{self, control, guest, host, ro};
+use super::*;
+use axhal::paging::MappingFlags;
+use memory_addr::{PhysAddr, VirtAddr};
+
+/// Guest physical address.
+pub type GuestPhysAddr = VirtAddr;
+/// Host physical address.
+pub type HostPhysAddr = PhysAddr;
+
+/// The port number of an I/O operation.
+type Port = u16;
+
+const PIN_EXTERNAL_INTERRUPT_EXITING: u32 = 1 << 0;
+const PROC_INTERRUPT_WINDOW_EXITING: u32 = 1 << 2;
+const PROC_HLT_EXITING: u32 = 1 << 7;
+const PROC_UNCOND_IO_EXITING: u32 = 1 << 24;
+const PROC_SECONDARY_CONTROLS: u32 = 1 << 31;
+const PROC2_ENABLE_EPT: u32 = 1 << 1;
+const PROC2_UNRESTRICTED_GUEST: u32 = 1 << 7;
+const EXIT_HOST_ADDRESS_SPACE_SIZE: u32 = 1 << 9;
+const EXIT_SAVE_IA32_EFER: u32 = 1 << 20;
+const EXIT_LOAD_IA32_EFER: u32 = 1 << 21;
+const ENTRY_LOAD_IA32_EFER: u32 = 1 << 15;
+
+/// The low bits of the EPTP: write-back paging structures, walked in 4 levels.
+const EPTP_WB_WALK_4: u64 = 6 | (4 - 1) << 3;
+const EPTP_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_ET: u64 = 1 << 4;
+const CR0_NE: u64 = 1 << 5;
+const CR0_PG: u64 = 1 << 31;
+const RFLAGS_RESERVED: u64 = 1 << 1;
+const RFLAGS_IF: u64 = 1 << 9;
+/// `DR7` at reset.
+const DR7_RESET: u64 = 0x400;
+
+/// The index of the segments in the guest segment fields, see [`guest::ES_SELECTOR`].
+const SEG_CS: u32 = 1;
+const SEG_LDTR: u32 = 6;
+const SEG_TR: u32 = 7;
+/// The access rights of the flat segments the guest starts with: 32-bit code and data, and a
+/// busy 32-bit TSS.
+const AR_CODE32: u64 = 0xc09b;
+const AR_DATA32: u64 = 0xc093;
+const AR_TSS32_BUSY: u64 = 0x8b;
+const AR_UNUSABLE: u64 = 1 << 16;
+const SELECTOR_CODE32: u64 = 0x08;
+const SELECTOR_DATA32: u64 = 0x10;
+
+const INTR_INFO_VALID: u32 = 1 << 31;
+const INTR_INFO_ERR_CODE: u32 = 1 << 11;
+/// The bits of the IDT-vectoring information that are valid in the VM-entry interruption
+/// information: the vector, the type, the error code bit and the valid bit.
+const INTR_INFO_ENTRY_MASK: u32 = 0x8000_0fff;
+/// The blocking by `sti` and by `mov ss` in the guest interruptibility state.
+const INTERRUPTIBILITY_STI_MOV_SS: u64 = 0b11;
+
+const EXIT_REASON_EXTERNAL_INTERRUPT: u32 = 1;
+const EXIT_REASON_TRIPLE_FAULT: u32 = 2;
+const EXIT_REASON_INTERRUPT_WINDOW: u32 = 7;
+const EXIT_REASON_CPUID: u32 = 10;
+const EXIT_REASON_HLT: u32 = 12;
+const EXIT_REASON_VMCALL: u32 = 18;
+const EXIT_REASON_IO_INSTRUCTION: u32 = 30;
+const EXIT_REASON_MSR_READ: u32 = 31;
+const EXIT_REASON_MSR_WRITE: u32 = 32;
+const EXIT_REASON_EPT_VIOLATION: u32 = 48;
+/// The bit of the exit reason telling the VM entry failed.
+const EXIT_REASON_ENTRY_FAILURE: u32 = 1 << 31;
+
+/// The exit qualification of the I/O instructions.
+const IO_SIZE_MASK: u64 = 0b111;
+const IO_IN: u64 = 1 << 3;
+const IO_STRING: u64 = 1 << 4;
+const IO_PORT_SHIFT: u64 = 16;
+/// The exit qualification of the EPT violations.
+const EPT_VIOLATION_READ: u64 = 1 << 0;
+const EPT_VIOLATION_WRITE: u64 = 1 << 1;
+const EPT_VIOLATION_FETCH: u64 = 1 << 2;
+
+/// The `CPUID.01H:ECX` features hidden from the guest: VMX, and the x2APIC and the `XSAVE`
+/// state that would need `MSR` and `xsetbv` emulation.
+const CPUID_1_ECX_HIDDEN: u32 = 1 << 5 | 1 << 21 | 1 << 26 | 1 << 27 | 1 << 28;
+const CPUID_1_ECX_HYPERVISOR: u32 = 1 << 31;
+
+/// `IA32_APIC_BASE` as read by the guest: the default base, enabled, for the bootstrap CPU.
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_DEFAULT: u64 = 0xfee0_0000 | 1 << 11 | 1 << 8;
+
+const RAX: usize = 0;
+const RCX: usize = 1;
+const RDX: usize = 2;
+const RBX: usize = 3;
+const RSP: usize = 4;
+const RSI: usize = 6;
+const RDI: usize = 7;
+const R8: usize = 8;
+
+/// The guest registers saved while the host runs.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct GuestRegs {
+    /// The GPRs, indexed by their encoding: `rax`, `rcx`, `rdx`, `rbx`, `rsp`, `rbp`, `rsi`,
+    /// `rdi` and `r8` to `r15`. The `rsp` slot is unused, see `rsp`.
+    pub gprs: [u64; 16],
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+/// The x87 and SSE state of `fxsave`.
+#[repr(C, align(16))]
+struct FxsaveArea([u8; 512]);
+
+impl FxsaveArea {
+    /// The state after `fninit`, with the SSE exceptions masked.
+    fn reset() -> Self {
+        let mut area = Self([0; 512]);
+        area.0[0..2].copy_from_slice(&0x37fu16.to_le_bytes()); // FCW
+        area.0[24..28].copy_from_slice(&0x1f80u32.to_le_bytes()); // MXCSR
+        area
+    }
+
+    fn save(&mut self) {
+        unsafe { core::arch::asm!("fxsave64 [{0}]", in(reg) self.0.as_mut_ptr()) };
+    }
+
+    fn restore(&self) {
+        unsafe { core::arch::asm!("fxrstor64 [{0}]", in(reg) self.0.as_ptr()) };
+    }
+}
+
+/// The fields of the VMCS describing the last exit.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExitInfo {
+    pub exit_reason: u32,
+    pub qualification: u64,
+    pub guest_phys_addr: u64,
+    pub instruction_len: u32,
+    pub interruption_info: u32,
+}
+
+extern "C" {
+    fn _run_guest(gprs: *mut [u64; 16], launched: bool) -> u64;
+    fn _vmx_exit();
+}
+
+global_asm!(include_str!("guest.S"), host_rsp = const host::RSP);
+
+/// A virtual CPU within a guest
+pub struct X86VCpu {
+    regs: GuestRegs,
+    guest_fxsave: FxsaveArea,
+    /// The virtual address of the VMCS.
+    vmcs: usize,
+    /// The CPU the VMCS is active on, if any.
+    loaded_cpu: Option<usize>,
+    /// Whether the VMCS is launched, resumed with `vmresume` rather than `vmlaunch`.
+    launched: bool,
+    /// Whether the controls and the initial guest state are written to the VMCS.
+    vmcs_ready: bool,
+    proc_controls: u32,
+    eptp: u64,
+    interruptibility: u64,
+    pending_irq: Option<u8>,
+    /// An event whose delivery was interrupted by the last exit, with its error code and
+    /// instruction length, delivered again on the next entry.
+    pending_event: Option<(u32, u64, u64)>,
+    exit_info: ExitInfo,
+}
+
+impl X86VCpu {
+    pub fn init() -> AxResult<Self> {
+        let vmcs = alloc_frame()?;
+        unsafe { *(vmcs as *mut u32) = vmcs_revision_id() };
+        Ok(Self {
+            regs: GuestRegs {
+                rflags: RFLAGS_RESERVED,
+                ..Default::default()
+            },
+            guest_fxsave: FxsaveArea::reset(),
+            vmcs,
+            loaded_cpu: None,
+            launched: false,
+            vmcs_ready: false,
+            proc_controls: 0,
+            eptp: 0,
+            interruptibility: 0,
+            pending_irq: None,
+            pending_event: None,
+            exit_info: ExitInfo::default(),
+        })
+    }
+
+    pub fn set_entry(&mut self, entry: GuestPhysAddr) -> AxResult {
+        self.regs.rip = entry.as_usize() as u64;
+        Ok(())
+    }
+
+    /// Sets the root of the EPT page tables, made of [`EPTEntry`](crate::EPTEntry).
+    pub fn set_ept_root(&mut self, ept_root: HostPhysAddr) -> AxResult {
+        self.eptp = ept_root.as_usize() as u64 & EPTP_ADDR_MASK | EPTP_WB_WALK_4;
+        Ok(())
+    }
+
+    /// Invalidates the guest physical translations of this vCPU's EPT on the current CPU, which
+    /// must be in VMX operation, see [`crate::setup_vmx`].
+    ///
+    /// Must be called after the EPT page tables are modified. Other CPUs running vCPUs of the
+    /// same VM have to do it themselves.
+    pub fn flush_ept(&self) {
+        invept_single(self.eptp);
+    }
+
+    /// Runs the vCPU until its next exit.
+    ///
+    /// The interrupts of the host must be disabled: an external interrupt makes the vCPU exit,
+    /// and is taken by the host once it enables them.
+    pub fn run(&mut self) -> AxResult<AxVCpuExitReason> {
+        self.load()?;
+        self.write_guest_state()?;
+
+        let mut host_fxsave = FxsaveArea([0; 512]);
+        host_fxsave.save();
+        self.guest_fxsave.restore();
+        // Safe to run the guest as it only touches memory assigned to it by being owned by its
+        // page table
+        let failed = unsafe { _run_guest(&mut self.regs.gprs, self.launched) } != 0;
+        self.guest_fxsave.save();
+        host_fxsave.restore();
+
+        if failed {
+            let error = vmcs::read(ro::VM_INSTRUCTION_ERROR)?;
+            warn!(
+                "VM entry failed with VM-instruction error {}, rip {:#x}",
+                error, self.regs.rip
+            );
+            return Ok(AxVCpuExitReason::FailEntry {
+                hardware_entry_failure_reason: error,
+            });
+        }
+        self.launched = true;
+        self.read_guest_state()?;
+        self.vmexit_handler()
+    }
+
+    /// Makes the VMCS inactive on the current CPU, with `vmclear`, for the vCPU to run on
+    /// another one.
+    ///
+    /// A vCPU runs on the CPU it last ran on until it is unloaded.
+    pub fn unload(&mut self) -> AxResult {
+        match self.loaded_cpu {
+            None => Ok(()),
+            Some(cpu) if cpu == axhal::cpu::this_cpu_id() => {
+                if let Err(e) = unsafe { vmx::vmclear(self.vmcs_paddr()) } {
+                    return ax_err!(BadState, format_args!("vmclear: {:?}", e));
+                }
+                self.loaded_cpu = None;
+                self.launched = false;
+                Ok(())
+            }
+            Some(cpu) => ax_err!(BadState, format_args!("the VMCS is active on CPU {}", cpu)),
+        }
+    }
+
+    /// Gets the register of encoding `index` of the vCPU, `rax` to `r15`.
+    pub fn get_gpr(&self, index: usize) -> u64 {
+        match index {
+            RSP => self.regs.rsp,
+            _ => self.regs.gprs[index],
+        }
+    }
+
+    /// Sets the register of encoding `index` of the vCPU, `rax` to `r15`.
+    pub fn set_gpr(&mut self, index: usize, val: u64) {
+        match index {
+            RSP => self.regs.rsp = val,
+            _ => self.regs.gprs[index] = val,
+        }
+    }
+
+    /// Gets the vCPU's registers.
+    pub fn regs(&mut self) -> &mut GuestRegs {
+        &mut self.regs
+    }
+
+    /// The VMCS fields of the last exit, e.g. to report an unhandled one.
+    pub fn exit_info(&self) -> &ExitInfo {
+        &self.exit_info
+    }
+
+    /// Injects the external interrupt `vector` into the vCPU, once the guest can take it.
+    ///
+    /// Only the last injected interrupt is kept until then.
+    pub fn inject_irq(&mut self, vector: u8) {
+        self.pending_irq = Some(vector);
+    }
+
+    /// Withdraws the interrupt injected with [`inject_irq`](Self::inject_irq), if the guest has
+    /// not taken it yet.
+    pub fn clear_irq(&mut self) {
+        self.pending_irq = None;
+    }
+
+    /// Completes the emulated `in` of an [`IoRead`] exit: `al`, `ax` or `eax` gets `val`.
+    ///
+    /// [`IoRead`]: AxVCpuExitReason::IoRead
+    pub fn complete_io_read(&mut self, width: AccessWidth, val: u64) {
+        let rax = &mut self.regs.gprs[RAX];
+        *rax = match width {
+            AccessWidth::Byte => *rax & !0xff | val & 0xff,
+            AccessWidth::Word => *rax & !0xffff | val & 0xffff,
+            // A 32-bit destination is zero-extended.
+            _ => val & 0xffff_ffff,
+        };
+    }
+
+    /// Advance guest pc by `instr_len` bytes
+    pub fn advance_pc(&mut self, instr_len: usize) {
+        self.regs.rip += instr_len as u64;
+        // The blocking by `sti` or `mov ss` ends after the next instruction.
+        self.interruptibility &= !INTERRUPTIBILITY_STI_MOV_SS;
+    }
+}
+
+impl X86VCpu {
+    fn vmcs_paddr(&self) -> u64 {
+        axhal::mem::virt_to_phys(self.vmcs.into()).as_usize() as u64
+    }
+
+    /// Makes the VMCS current on this CPU, setting up its controls and the initial guest state on
+    /// its first load.
+    fn load(&mut self) -> AxResult {
+        let cpu = axhal::cpu::this_cpu_id();
+        let paddr = self.vmcs_paddr();
+        match self.loaded_cpu {
+            Some(loaded) if loaded != cpu => {
+                return ax_err!(
+                    BadState,
+                    format_args!("the VMCS is active on CPU {}, unload it first", loaded)
+                );
+            }
+            Some(_) => {}
+            // Clear the launch state before the first `vmptrld` on this CPU.
+            None => {
+                if let Err(e) = unsafe { vmx::vmclear(paddr) } {
+                    return ax_err!(BadState, format_args!("vmclear: {:?}", e));
+                }
+            }
+        }
+        // Other vCPUs may have been run by this CPU meanwhile.
+        if let Err(e) = unsafe { vmx::vmptrld(paddr) } {
+            return ax_err!(BadState, format_args!("vmptrld: {:?}", e));
+        }
+        if !self.vmcs_ready {
+            self.setup_controls()?;
+            self.setup_guest_state()?;
+            self.vmcs_ready = true;
+        }
+        self.loaded_cpu = Some(cpu);
+        // The FS base of the running task may differ from the last run.
+        self.setup_host_state()
+    }
+
+    fn setup_controls(&mut self) -> AxResult {
+        let pin = adjust_controls(
+            IA32_VMX_PINBASED_CTLS,
+            Some(IA32_VMX_TRUE_PINBASED_CTLS),
+            PIN_EXTERNAL_INTERRUPT_EXITING,
+        )?;
+        self.proc_controls = adjust_controls(
+            IA32_VMX_PROCBASED_CTLS,
+            Some(IA32_VMX_TRUE_PROCBASED_CTLS),
+            PROC_HLT_EXITING | PROC_UNCOND_IO_EXITING | PROC_SECONDARY_CONTROLS,
+        )?;
+        let proc2 = adjust_controls(
+            IA32_VMX_PROCBASED_CTLS2,
+            None,
+            PROC2_ENABLE_EPT | PROC2_UNRESTRICTED_GUEST,
+        )?;
+        let exit = adjust_controls(
+            IA32_VMX_EXIT_CTLS,
+            Some(IA32_VMX_TRUE_EXIT_CTLS),
+            EXIT_HOST_ADDRESS_SPACE_SIZE | EXIT_SAVE_IA32_EFER | EXIT_LOAD_IA32_EFER,
+        )?;
+        let entry = adjust_controls(
+            IA32_VMX_ENTRY_CTLS,
+            Some(IA32_VMX_TRUE_ENTRY_CTLS),
+            ENTRY_LOAD_IA32_EFER,
+        )?;
+        vmcs::write(control::PINBASED_EXEC_CONTROLS, pin as u64)?;
+        vmcs::write(
+            control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+            self.proc_controls as u64,
+        )?;
+        vmcs::write(control::SECONDARY_PROCBASED_EXEC_CONTROLS, proc2 as u64)?;
+        vmcs::write(control::VMEXIT_CONTROLS, exit as u64)?;
+        vmcs::write(control::VMENTRY_CONTROLS, entry as u64)?;
+        // No MSR bitmap: all `rdmsr` and `wrmsr` exit.
+        vmcs::write(control::EXCEPTION_BITMAP, 0)?;
+        vmcs::write(control::CR3_TARGET_COUNT, 0)?;
+        vmcs::write(control::VMEXIT_MSR_STORE_COUNT, 0)?;
+        vmcs::write(control::VMEXIT_MSR_LOAD_COUNT, 0)?;
+        vmcs::write(control::VMENTRY_MSR_LOAD_COUNT, 0)?;
+        Ok(())
+    }
+
+    /// Sets up the guest state of a multiboot entry: 32-bit protected mode with flat segments
+    /// and paging off.
+    fn setup_guest_state(&mut self) -> AxResult {
+        let set_segment = |index: u32, selector: u64, limit: u64, access_rights: u64| {
+            vmcs::write(guest::ES_SELECTOR + index * 2, selector)?;
+            vmcs::write(guest::ES_BASE + index * 2, 0)?;
+            vmcs::write(guest::ES_LIMIT + index * 2, limit)?;
+            vmcs::write(guest::ES_ACCESS_RIGHTS + index * 2, access_rights)
+        };
+        for index in 0..SEG_LDTR {
+            if index == SEG_CS {
+                set_segment(index, SELECTOR_CODE32, 0xffff_ffff, AR_CODE32)?;
+            } else {
+                set_segment(index, SELECTOR_DATA32, 0xffff_ffff, AR_DATA32)?;
+            }
+        }
+        set_segment(SEG_LDTR, 0, 0, AR_UNUSABLE)?;
+        set_segment(SEG_TR, 0, 0xff, AR_TSS32_BUSY)?;
+        vmcs::write(guest::GDTR_BASE, 0)?;
+        vmcs::write(guest::GDTR_LIMIT, 0xffff)?;
+        vmcs::write(guest::IDTR_BASE, 0)?;
+        vmcs::write(guest::IDTR_LIMIT, 0xffff)?;
+
+        // The unrestricted guest may clear `PE` and `PG` despite the fixed bits.
+        let (cr0_fixed0, cr0_fixed1) =
+            unsafe { (rdmsr(IA32_VMX_CR0_FIXED0), rdmsr(IA32_VMX_CR0_FIXED1)) };
+        let cr0 = (CR0_PE | CR0_ET | CR0_NE | cr0_fixed0 & !(CR0_PE | CR0_PG)) & cr0_fixed1;
+        vmcs::write(guest::CR0, cr0)?;
+        vmcs::write(control::CR0_GUEST_HOST_MASK, 0)?;
+        vmcs::write(control::CR0_READ_SHADOW, cr0)?;
+        vmcs::write(guest::CR3, 0)?;
+        // The fixed bits of CR4, e.g. `VMXE`, read as clear by the guest.
+        let cr4_fixed0 = unsafe { rdmsr(IA32_VMX_CR4_FIXED0) };
+        vmcs::write(guest::CR4, cr4_fixed0)?;
+        vmcs::write(control::CR4_GUEST_HOST_MASK, cr4_fixed0)?;
+        vmcs::write(control::CR4_READ_SHADOW, 0)?;
+
+        vmcs::write(guest::DR7, DR7_RESET)?;
+        vmcs::write(guest::IA32_EFER, 0)?;
+        vmcs::write(guest::IA32_SYSENTER_CS, 0)?;
+        vmcs::write(guest::IA32_SYSENTER_ESP, 0)?;
+        vmcs::write(guest::IA32_SYSENTER_EIP, 0)?;
+        vmcs::write(guest::LINK_PTR, u64::MAX)?;
+        vmcs::write(guest::ACTIVITY_STATE, 0)?;
+        vmcs::write(guest::PENDING_DBG_EXCEPTIONS, 0)?;
+        Ok(())
+    }
+
+    /// Sets up the host state of the current CPU and task, restored on VM exits.
+    fn setup_host_state(&self) -> AxResult {
+        use x86::segmentation::{cs, ds, es, fs, gs, ss};
+
+        let mut gdtr = DescriptorTablePointer::<u64>::default();
+        let mut idtr = DescriptorTablePointer::<u64>::default();
+        unsafe {
+            sgdt(&mut gdtr);
+            sidt(&mut idtr);
+        }
+        // The 16-byte TSS descriptor in the GDT holds the base of the TSS.
+        let tr = unsafe { x86::task::tr() }.bits();
+        let tss_desc = unsafe { gdtr.base.add(tr as usize >> 3) };
+        let (low, high) = unsafe { (*tss_desc, *tss_desc.add(1)) };
+        let tr_base = low >> 16 & 0xff_ffff | (low >> 56 & 0xff) << 24 | (high & 0xffff_ffff) << 32;
+
+        // The host selectors have their RPL and TI bits clear.
+        vmcs::write(host::ES_SELECTOR, (es().bits() & !0x7) as u64)?;
+        vmcs::write(host::CS_SELECTOR, (cs().bits() & !0x7) as u64)?;
+        vmcs::write(host::SS_SELECTOR, (ss().bits() & !0x7) as u64)?;
+        vmcs::write(host::DS_SELECTOR, (ds().bits() & !0x7) as u64)?;
+        vmcs::write(host::FS_SELECTOR, (fs().bits() & !0x7) as u64)?;
+        vmcs::write(host::GS_SELECTOR, (gs().bits() & !0x7) as u64)?;
+        vmcs::write(host::TR_SELECTOR, (tr & !0x7) as u64)?;
+        vmcs::write(host::TR_BASE, tr_base)?;
+        vmcs::write(host::GDTR_BASE, gdtr.base as u64)?;
+        vmcs::write(host::IDTR_BASE, idtr.base as u64)?;
+        unsafe {
+            vmcs::write(host::CR0, cr0().bits() as u64)?;
+            vmcs::write(host::CR3, cr3())?;
+            vmcs::write(host::CR4, cr4().bits() as u64)?;
+            vmcs::write(host::FS_BASE, rdmsr(IA32_FS_BASE))?;
+            vmcs::write(host::GS_BASE, rdmsr(IA32_GS_BASE))?;
+            vmcs::write(host::IA32_EFER, rdmsr(IA32_EFER))?;
+            vmcs::write(host::IA32_SYSENTER_CS, rdmsr(IA32_SYSENTER_CS))?;
+            vmcs::write(host::IA32_SYSENTER_ESP, rdmsr(IA32_SYSENTER_ESP))?;
+            vmcs::write(host::IA32_SYSENTER_EIP, rdmsr(IA32_SYSENTER_EIP))?;
+        }
+        vmcs::write(host::RIP, _vmx_exit as usize as u64)?;
+        Ok(())
+    }
+
+    fn write_guest_state(&mut self) -> AxResult {
+        vmcs::write(guest::RIP, self.regs.rip)?;
+        vmcs::write(guest::RSP, self.regs.rsp)?;
+        vmcs::write(guest::RFLAGS, self.regs.rflags)?;
+        vmcs::write(guest::INTERRUPTIBILITY_STATE, self.interruptibility)?;
+        vmcs::write(control::EPTP, self.eptp)?;
+
+        if let Some((info, err_code, insn_len)) = self.pending_event.take() {
+            vmcs::write(control::VMENTRY_INTERRUPTION_INFO, info as u64)?;
+            if info & INTR_INFO_ERR_CODE != 0 {
+                vmcs::write(control::VMENTRY_EXCEPTION_ERR_CODE, err_code)?;
+            }
+            vmcs::write(control::VMENTRY_INSTRUCTION_LEN, insn_len)?;
+            // The interrupt is injected once this event is delivered.
+            return self.set_interrupt_window_exiting(self.pending_irq.is_some());
+        }
+        if let Some(vector) = self.pending_irq {
+            let can_inject = self.regs.rflags & RFLAGS_IF != 0
+                && self.interruptibility & INTERRUPTIBILITY_STI_MOV_SS == 0;
+            if can_inject {
+                // An external interrupt, of type 0.
+                vmcs::write(
+                    control::VMENTRY_INTERRUPTION_INFO,
+                    (INTR_INFO_VALID | vector as u32) as u64,
+                )?;
+                self.pending_irq = None;
+            }
+            // Otherwise exit once the guest can take it.
+            return self.set_interrupt_window_exiting(!can_inject);
+        }
+        self.set_interrupt_window_exiting(false)
+    }
+
+    fn set_interrupt_window_exiting(&mut self, enable: bool) -> AxResult {
+        let controls = if enable {
+            self.proc_controls | PROC_INTERRUPT_WINDOW_EXITING
+        } else {
+            self.proc_controls & !PROC_INTERRUPT_WINDOW_EXITING
+        };
+        if controls != self.proc_controls {
+            self.proc_controls = controls;
+            vmcs::write(control::PRIMARY_PROCBASED_EXEC_CONTROLS, controls as u64)?;
+        }
+        Ok(())
+    }
+
+    fn read_guest_state(&mut self) -> AxResult {
+        self.regs.rip = vmcs::read(guest::RIP)?;
+        self.regs.rsp = vmcs::read(guest::RSP)?;
+        self.regs.rflags = vmcs::read(guest::RFLAGS)?;
+        self.interruptibility = vmcs::read(guest::INTERRUPTIBILITY_STATE)?;
+        self.exit_info = ExitInfo {
+            exit_reason: vmcs::read(ro::EXIT_REASON)? as u32,
+            qualification: vmcs::read(ro::EXIT_QUALIFICATION)?,
+            guest_phys_addr: vmcs::read(ro::GUEST_PHYSICAL_ADDR)?,
+            instruction_len: vmcs::read(ro::VMEXIT_INSTRUCTION_LEN)? as u32,
+            interruption_info: vmcs::read(ro::VMEXIT_INTERRUPTION_INFO)? as u32,
+        };
+        let idt_vectoring = vmcs::read(ro::IDT_VECTORING_INFO)? as u32;
+        if idt_vectoring & INTR_INFO_VALID != 0 {
+            self.pending_event = Some((
+                idt_vectoring & INTR_INFO_ENTRY_MASK,
+                vmcs::read(ro::IDT_VECTORING_ERR_CODE)?,
+                self.exit_info.instruction_len as u64,
+            ));
+        }
+        Ok(())
+    }
+
+    fn vmexit_handler(&mut self) -> AxResult<AxVCpuExitReason> {
+        let info = self.exit_info;
+        if info.exit_reason & EXIT_REASON_ENTRY_FAILURE != 0 {
+            warn!(
+                "VM entry failed: exit reason {:#x}, qualification {:#x}",
+                info.exit_reason, info.qualification
+            );
+            return Ok(AxVCpuExitReason::FailEntry {
+                hardware_entry_failure_reason: (info.exit_reason & 0xffff) as u64,
+            });
+        }
+        let insn_len = info.instruction_len as usize;
+        let gprs = self.regs.gprs;
+        match info.exit_reason & 0xffff {
+            // Taken by the host once it enables the interrupts.
+            EXIT_REASON_EXTERNAL_INTERRUPT => Ok(AxVCpuExitReason::Nothing),
+            // The pending interrupt is injected on the next entry.
+            EXIT_REASON_INTERRUPT_WINDOW => Ok(AxVCpuExitReason::Nothing),
+            EXIT_REASON_TRIPLE_FAULT => {
+                warn!("Triple fault in the guest, rip {:#x}", self.regs.rip);
+                Ok(AxVCpuExitReason::SystemReset)
+            }
+            EXIT_REASON_CPUID => {
+                self.handle_cpuid();
+                self.advance_pc(insn_len);
+                Ok(AxVCpuExitReason::Nothing)
+            }
+            EXIT_REASON_HLT => {
+                self.advance_pc(insn_len);
+                Ok(AxVCpuExitReason::Halt)
+            }
+            EXIT_REASON_VMCALL => {
+                self.advance_pc(insn_len);
+                Ok(AxVCpuExitReason::Hypercall {
+                    nr: gprs[RAX],
+                    args: [
+                        gprs[RBX], gprs[RCX], gprs[RDX], gprs[RSI], gprs[RDI], gprs[R8],
+                    ],
+                })
+            }
+            EXIT_REASON_IO_INSTRUCTION => Ok(self.handle_io()),
+            EXIT_REASON_MSR_READ => {
+                self.handle_rdmsr()?;
+                self.advance_pc(insn_len);
+                Ok(AxVCpuExitReason::Nothing)
+            }
+            EXIT_REASON_MSR_WRITE => {
+                self.handle_wrmsr()?;
+                self.advance_pc(insn_len);
+                Ok(AxVCpuExitReason::Nothing)
+            }
+            EXIT_REASON_EPT_VIOLATION => {
+                let mut access_flags = MappingFlags::empty();
+                if info.qualification & EPT_VIOLATION_READ != 0 {
+                    access_flags |= MappingFlags::READ;
+                }
+                if info.qualification & EPT_VIOLATION_WRITE != 0 {
+                    access_flags |= MappingFlags::WRITE;
+                }
+                if info.qualification & EPT_VIOLATION_FETCH != 0 {
+                    access_flags |= MappingFlags::EXECUTE;
+                }
+                Ok(AxVCpuExitReason::NestedPageFault {
+                    addr: GuestPhysAddr::from(info.guest_phys_addr as usize),
+                    access_flags,
+                })
+            }
+            reason => {
+                warn!(
+                    "Unhandled VM exit: reason {}, qualification {:#x}, rip {:#x}",
+                    reason, info.qualification, self.regs.rip
+                );
+                Ok(AxVCpuExitReason::UnhandledTrap {
+                    exit_reason: reason,
+                })
+            }
+        }
+    }
+
+    /// Executes the `cpuid` of the guest on the host, hiding the features it cannot use.
+    fn handle_cpuid(&mut self) {
+        let gprs = &mut self.regs.gprs;
+        let (leaf, subleaf) = (gprs[RAX] as u32, gprs[RCX] as u32);
+        let mut res = unsafe { __cpuid_count(leaf, subleaf) };
+        if leaf == 1 {
+            res.ecx = res.ecx & !CPUID_1_ECX_HIDDEN | CPUID_1_ECX_HYPERVISOR;
+        }
+        gprs[RAX] = res.eax as u64;
+        gprs[RBX] = res.ebx as u64;
+        gprs[RCX] = res.ecx as u64;
+        gprs[RDX] = res.edx as u64;
+    }
+
+    /// Handles the `in` and `out` of the guest. The string ones are not supported.
+    fn handle_io(&mut self) -> AxVCpuExitReason {
+        let qualification = self.exit_info.qualification;
+        let width = match qualification & IO_SIZE_MASK {
+            0 => AccessWidth::Byte,
+            1 => AccessWidth::Word,
+            _ => AccessWidth::Dword,
+        };
+        if qualification & IO_STRING != 0 {
+            warn!(
+                "Unsupported string I/O, qualification {:#x}, rip {:#x}",
+                qualification, self.regs.rip
+            );
+            return AxVCpuExitReason::UnhandledTrap {
+                exit_reason: EXIT_REASON_IO_INSTRUCTION,
+            };
+        }
+        let port = (qualification >> IO_PORT_SHIFT) as Port;
+        self.advance_pc(self.exit_info.instruction_len as usize);
+        if qualification & IO_IN != 0 {
+            AxVCpuExitReason::IoRead { port, width }
+        } else {
+            let data = self.regs.gprs[RAX] & width.mask();
+            AxVCpuExitReason::IoWrite { port, width, data }
+        }
+    }
+
+    /// Emulates the `rdmsr` of the guest. The MSRs in the VMCS are read from it, the others
+    /// read as 0 but `IA32_APIC_BASE`.
+    fn handle_rdmsr(&mut self) -> AxResult {
+        let msr = self.regs.gprs[RCX] as u32;
+        let val = match msr {
+            IA32_EFER => vmcs::read(guest::IA32_EFER)?,
+            IA32_FS_BASE => vmcs::read(guest::FS_BASE)?,
+            IA32_GS_BASE => vmcs::read(guest::GS_BASE)?,
+            IA32_SYSENTER_CS => vmcs::read(guest::IA32_SYSENTER_CS)?,
+            IA32_SYSENTER_ESP => vmcs::read(guest::IA32_SYSENTER_ESP)?,
+            IA32_SYSENTER_EIP => vmcs::read(guest::IA32_SYSENTER_EIP)?,
+            IA32_APIC_BASE => APIC_BASE_DEFAULT,
+            _ => {
+                debug!("Guest rdmsr {:#x}, read as 0", msr);
+                0
+            }
+        };
+        self.regs.gprs[RAX] = val & 0xffff_ffff;
+        self.regs.gprs[RDX] = val >> 32;
+        Ok(())
+    }
+
+    /// Emulates the `wrmsr` of the guest. The MSRs in the VMCS are written to it, the others
+    /// ignored.
+    fn handle_wrmsr(&mut self) -> AxResult {
+        let gprs = &self.regs.gprs;
+        let msr = gprs[RCX] as u32;
+        let val = (gprs[RDX] & 0xffff_ffff) << 32 | gprs[RAX] & 0xffff_ffff;
+        match msr {
+            // The processor sets `LMA` itself once the guest enables paging.
+            IA32_EFER => vmcs::write(guest::IA32_EFER, val),
+            IA32_FS_BASE => vmcs::write(guest::FS_BASE, val),
+            IA32_GS_BASE => vmcs::write(guest::GS_BASE, val),
+            IA32_SYSENTER_CS => vmcs::write(guest::IA32_SYSENTER_CS, val),
+            IA32_SYSENTER_ESP => vmcs::write(guest::IA32_SYSENTER_ESP, val),
+            IA32_SYSENTER_EIP => vmcs::write(guest::IA32_SYSENTER_EIP, val),
+            _ => {
+                debug!("Guest wrmsr {:#x} = {:#x}, ignored", msr, val);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for X86VCpu {
+    fn drop(&mut self) {
+        // The CPU the VMCS is active on may still write it back.
+        if self.unload().is_err() {
+            warn!("vCPU dropped with its VMCS active on another CPU, leaking it");
+            return;
+        }
+        axalloc::global_allocator().dealloc_pages(self.vmcs, 1);
+    }
+}
+
+/// The width of an access.
+///
+/// Note that the term "word" here refers to 16-bit data, as in the x86 architecture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessWidth {
+    /// 8-bit access.
+    Byte,
+    /// 16-bit access.
+    Word,
+    /// 32-bit access.
+    Dword,
+    /// 64-bit access.
+    Qword,
+}
+
+impl AccessWidth {
+    fn mask(self) -> u64 {
+        match self {
+            Self::Byte => 0xff,
+            Self::Word => 0xffff,
+            Self::Dword => 0xffff_ffff,
+            Self::Qword => u64::MAX,
+        }
+    }
+}
+
+/// The result of [`X86VCpu::run`].
+#[derive(Debug)]
+pub enum AxVCpuExitReason {
+    /// The vcpu made a hypercall with `vmcall`.
+    Hypercall {
+        /// The hypercall number, in `rax`.
+        nr: u64,
+        /// The arguments for the hypercall, in `rbx`, `rcx`, `rdx`, `rsi`, `rdi` and `r8`.
+        args: [u64; 6],
+    },
+    /// The vcpu executed `in`. The guest resumes after the instruction, with the value of
+    /// [`X86VCpu::complete_io_read`].
+    IoRead {
+        /// The port number of the I/O read.
+        port: Port,
+        /// The width of the I/O read.
+        width: AccessWidth,
+    },
+    /// The vcpu executed `out`. The guest resumes after the instruction.
+    IoWrite {
+        /// The port number of the I/O write.
+        port: Port,
+        /// The width of the I/O write.
+        width: AccessWidth,
+        /// The data to be written, from `al`, `ax` or `eax`.
+        data: u64,
+    },
+    /// A nested page fault happened: an EPT violation.
+    ///
+    /// Note that fields may be added in the future, use `..` to handle them.
+    NestedPageFault {
+        /// The guest physical address of the fault.
+        addr: GuestPhysAddr,
+        /// The access flags of the fault.
+        access_flags: MappingFlags,
+    },
+    /// The vcpu executed `hlt`: it waits for an interrupt, and may be descheduled until it gets
+    /// one. The guest resumes after the instruction.
+    Halt,
+    /// The guest triple faulted, which resets the system.
+    ///
+    /// The guest does not resume: the hypervisor restarts it from its kernel image.
+    SystemReset,
+    /// The vcpu exited with a reason the hypervisor cannot handle, see
+    /// [`X86VCpu::exit_info`] for the details. The guest cannot resume.
+    UnhandledTrap {
+        /// The basic exit reason.
+        exit_reason: u32,
+    },
+    /// Nothing special happened, the vcpu has handled the exit itself.
+    ///
+    /// This exists to allow the caller to have a chance to check virtual devices/physical devices/virtual interrupts.
+    Nothing,
+    /// Something bad happened during VM entry, the vcpu could not be run.
+    /// Corresponds to `KVM_EXIT_FAIL_ENTRY`.
+    FailEntry {
+        /// The VM-instruction error of a failed `vmlaunch` or `vmresume`, or the basic exit
+        /// reason of a VM entry failing on the guest state.
+        hardware_entry_failure_reason: u64,
+    },
+}
@@ -0,0 +1,109 @@
+//! The encodings of the VMCS fields used by the vCPUs, and their accessors.
+//!
+//! The current VMCS of the CPU is the one accessed, loaded with `vmptrld`.
+
+use axerrno::{ax_err, AxResult};
+use x86::bits64::vmx;
+
+pub mod control {
+    pub const MSR_BITMAPS_ADDR: u32 = 0x2004;
+    pub const EPTP: u32 = 0x201a;
+    pub const PINBASED_EXEC_CONTROLS: u32 = 0x4000;
+    pub const PRIMARY_PROCBASED_EXEC_CONTROLS: u32 = 0x4002;
+    pub const EXCEPTION_BITMAP: u32 = 0x4004;
+    pub const CR3_TARGET_COUNT: u32 = 0x400a;
+    pub const VMEXIT_CONTROLS: u32 = 0x400c;
+    pub const VMEXIT_MSR_STORE_COUNT: u32 = 0x400e;
+    pub const VMEXIT_MSR_LOAD_COUNT: u32 = 0x4010;
+    pub const VMENTRY_CONTROLS: u32 = 0x4012;
+    pub const VMENTRY_MSR_LOAD_COUNT: u32 = 0x4014;
+    pub const VMENTRY_INTERRUPTION_INFO: u32 = 0x4016;
+    pub const VMENTRY_EXCEPTION_ERR_CODE: u32 = 0x4018;
+    pub const VMENTRY_INSTRUCTION_LEN: u32 = 0x401a;
+    pub const SECONDARY_PROCBASED_EXEC_CONTROLS: u32 = 0x401e;
+    pub const CR0_GUEST_HOST_MASK: u32 = 0x6000;
+    pub const CR4_GUEST_HOST_MASK: u32 = 0x6002;
+    pub const CR0_READ_SHADOW: u32 = 0x6004;
+    pub const CR4_READ_SHADOW: u32 = 0x6006;
+}
+
+pub mod guest {
+    /// The segment fields are at these encodings plus twice the index of the segment in `ES`,
+    /// `CS`, `SS`, `DS`, `FS`, `GS`, `LDTR` and `TR`.
+    pub const ES_SELECTOR: u32 = 0x0800;
+    pub const ES_LIMIT: u32 = 0x4800;
+    pub const ES_ACCESS_RIGHTS: u32 = 0x4814;
+    pub const ES_BASE: u32 = 0x6806;
+
+    pub const LINK_PTR: u32 = 0x2800;
+    pub const IA32_EFER: u32 = 0x2806;
+    pub const GDTR_LIMIT: u32 = 0x4810;
+    pub const IDTR_LIMIT: u32 = 0x4812;
+    pub const INTERRUPTIBILITY_STATE: u32 = 0x4824;
+    pub const ACTIVITY_STATE: u32 = 0x4826;
+    pub const IA32_SYSENTER_CS: u32 = 0x482a;
+    pub const CR0: u32 = 0x6800;
+    pub const CR3: u32 = 0x6802;
+    pub const CR4: u32 = 0x6804;
+    pub const FS_BASE: u32 = 0x680e;
+    pub const GS_BASE: u32 = 0x6810;
+    pub const GDTR_BASE: u32 = 0x6816;
+    pub const IDTR_BASE: u32 = 0x6818;
+    pub const DR7: u32 = 0x681a;
+    pub const RSP: u32 = 0x681c;
+    pub const RIP: u32 = 0x681e;
+    pub const RFLAGS: u32 = 0x6820;
+    pub const PENDING_DBG_EXCEPTIONS: u32 = 0x6822;
+    pub const IA32_SYSENTER_ESP: u32 = 0x6824;
+    pub const IA32_SYSENTER_EIP: u32 = 0x6826;
+}
+
+pub mod host {
+    pub const ES_SELECTOR: u32 = 0x0c00;
+    pub const CS_SELECTOR: u32 = 0x0c02;
+    pub const SS_SELECTOR: u32 = 0x0c04;
+    pub const DS_SELECTOR: u32 = 0x0c06;
+    pub const FS_SELECTOR: u32 = 0x0c08;
+    pub const GS_SELECTOR: u32 = 0x0c0a;
+    pub const TR_SELECTOR: u32 = 0x0c0c;
+    pub const IA32_EFER: u32 = 0x2c02;
+    pub const IA32_SYSENTER_CS: u32 = 0x4c00;
+    pub const CR0: u32 = 0x6c00;
+    pub const CR3: u32 = 0x6c02;
+    pub const CR4: u32 = 0x6c04;
+    pub const FS_BASE: u32 = 0x6c06;
+    pub const GS_BASE: u32 = 0x6c08;
+    pub const TR_BASE: u32 = 0x6c0a;
+    pub const GDTR_BASE: u32 = 0x6c0c;
+    pub const IDTR_BASE: u32 = 0x6c0e;
+    pub const IA32_SYSENTER_ESP: u32 = 0x6c10;
+    pub const IA32_SYSENTER_EIP: u32 = 0x6c12;
+    pub const RSP: u32 = 0x6c14;
+    pub const RIP: u32 = 0x6c16;
+}
+
+/// The read-only fields describing the last VM exit.
+pub mod ro {
+    pub const GUEST_PHYSICAL_ADDR: u32 = 0x2400;
+    pub const VM_INSTRUCTION_ERROR: u32 = 0x4400;
+    pub const EXIT_REASON: u32 = 0x4402;
+    pub const VMEXIT_INTERRUPTION_INFO: u32 = 0x4404;
+    pub const IDT_VECTORING_INFO: u32 = 0x4408;
+    pub const IDT_VECTORING_ERR_CODE: u32 = 0x440a;
+    pub const VMEXIT_INSTRUCTION_LEN: u32 = 0x440c;
+    pub const EXIT_QUALIFICATION: u32 = 0x6400;
+}
+
+pub fn read(field: u32) -> AxResult<u64> {
+    match unsafe { vmx::vmread(field) } {
+        Ok(val) => Ok(val),
+        Err(e) => ax_err!(BadState, format_args!("vmread {:#x}: {:?}", field, e)),
+    }
+}
+
+pub fn write(field: u32, val: u64) -> AxResult {
+    match unsafe { vmx::vmwrite(field, val) } {
+        Ok(()) => Ok(()),
+        Err(e) => ax_err!(BadState, format_args!("vmwrite {:#x}: {:?}", field, e)),
+    }
+}
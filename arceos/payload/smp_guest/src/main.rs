@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+/// Boot hart: starts all other harts with SBI HSM, then waits until every
+/// started hart has checked in (an increment of the counter followed by an
+/// IPI) and shuts down. Other harts check in and stop themselves.
+#[no_mangle]
+unsafe extern "C" fn _start() -> ! {
+    core::arch::asm!(
+        "mv     s0, a0",
+        "bnez   s0, 5f",
+
+        // Start harts 1, 2, ... until one fails to start.
+        "li     s1, 1",
+        "li     s2, 0",
+        "1:",
+        "li     a7, 0x48534d",      // HSM
+        "li     a6, 0",             // hart_start
+        "mv     a0, s1",
+        "lla    a1, {entry}",
+        "li     a2, 0",
+        "ecall",
+        "bnez   a0, 2f",
+        "addi   s2, s2, 1",
+        "addi   s1, s1, 1",
+        "li     t0, {max_harts}",
+        "bltu   s1, t0, 1b",
+        "2:",
+        "lla    a0, 10f",
+        "jal    ra, 8f",
+        "mv     a0, s2",
+        "jal    ra, 9f",
+        "lla    a0, 11f",
+        "jal    ra, 8f",
+
+        // Wait for all started harts to check in. IPIs may coalesce, so
+        // re-read the counter after each of them.
+        "3:",
+        "lla    t1, 13f",
+        "ld     t0, 0(t1)",
+        "beq    t0, s2, 4f",
+        "csrr   t0, sip",
+        "andi   t0, t0, 2",
+        "beqz   t0, 3b",
+        "csrci  sip, 2",
+        "j      3b",
+        "4:",
+        "lla    a0, 12f",
+        "jal    ra, 8f",
+        "li     a7, 0x53525354",    // SRST
+        "li     a6, 0",             // system_reset
+        "li     a0, 0",             // shutdown
+        "li     a1, 0",             // no reason
+        "ecall",
+        "j      6f",
+
+        // Secondary harts: check in, send an IPI to hart 0 and stop.
+        "5:",
+        "lla    t1, 13f",
+        "li     t0, 1",
+        "amoadd.d zero, t0, (t1)",
+        "li     a7, 0x735049",      // sPI
+        "li     a6, 0",             // send_ipi
+        "li     a0, 1",             // hart_mask
+        "li     a1, 0",             // hart_mask_base
+        "ecall",
+        "li     a7, 0x48534d",      // HSM
+        "li     a6, 1",             // hart_stop
+        "ecall",
+        "6:",
+        "wfi",
+        "j      6b",
+
+        // puts(a0)
+        "8:",
+        "mv     t1, a0",
+        "14:",
+        "lbu    a0, 0(t1)",
+        "beqz   a0, 15f",
+        "li     a7, 1",             // legacy console_putchar
+        "ecall",
+        "addi   t1, t1, 1",
+        "j      14b",
+        "15:",
+        "ret",
+
+        // putdigit(a0)
+        "9:",
+        "addi   a0, a0, '0'",
+        "li     a7, 1",
+        "ecall",
+        "ret",
+
+        // Keep the strings in `.text`, so that `_start` is at the beginning of
+        // the flat binary.
+        "10: .asciz \"hart 0: started \"",
+        "11: .asciz \" secondary harts\\n\"",
+        "12: .asciz \"hart 0: all harts checked in, SMP guest ok!\\n\"",
+        ".pushsection .data",
+        ".balign 8",
+        "13: .dword 0",
+        ".popsection",
+        entry = sym _start,
+        max_harts = const 8,
+        options(noreturn)
+    )
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,216 @@
+//! VM configuration files, in a subset of TOML.
+//!
+//! ```toml
+//! image = "/sbin/u_3_0_riscv64-qemu-virt.bin"
+//! initrd = "/initramfs.cpio"  # optional
+//! mem_base = 0x8000_0000  # optional, 0x8000_0000 by default
+//! mem_size = 0x100_0000
+//! entry = 0x8020_0000
+//! vcpus = 2               # optional, 1 by default
+//! max_vcpus = 4           # optional, `vcpus` by default
+//!
+//! [devices]               # optional
+//! blk = "/vm_disk.img"
+//! pflash = "/pflash.img"
+//! pflash_base = 0x2200_0000  # optional, 0x2200_0000 by default
+//! balloon = 0x400         # the pages the virtio-balloon asks for
+//! blk_passthrough = 0x1000_8000  # a virtio-mmio slot of the host
+//! net = "10.0.2.2:5555"   # with the `vnet` feature
+//! irqchip = "aia"         # optional, "plic" by default
+//! pmu_events = "cycles,firmware"  # optional, all of them by default
+//! ```
+//!
+//! `crash_dump = "/vm1.crash"` in the top section writes the crash dump of
+//! the guest to this file, if it crashes. `record = "/vm1.exits"` records
+//! the VM exits to this file, and `replay = "/vm1.exits"` replays them.
+//! `cpu_quota_ms = 20` lets each vCPU run the guest 20 ms per period,
+//! `cpu_period_ms`, 100 ms by default.
+//!
+//! With `irqchip = "aia"`, the interrupts of the devices go through a
+//! virtual APLIC and IMSIC instead of the PLIC, which needs the AIA on the
+//! host, and no passthrough device.
+//!
+//! `pmu_events` lists the events the guest may count with the SBI PMU
+//! extension, among `cycles`, `instructions` and `firmware`. With `""`, the
+//! guest gets no PMU.
+//!
+//! The vCPUs beyond `vcpus`, up to `max_vcpus`, are absent at boot and can be
+//! added by the host later, see `Vm::add_vcpu`.
+//!
+//! Values are strings or integers, in decimal or hexadecimal with `0x`, with
+//! optional `_` separators.
+//!
+//! The image is either a flat binary, loaded at `entry`, or an ELF file. ELF
+//! images are loaded at the physical addresses of their segments and start at
+//! their own entry point, `entry` is still required but unused.
+
+use alloc::string::{String, ToString};
+use axerrno::{ax_err_type, AxResult};
+use riscv_vcpu::PmuEvents;
+
+use crate::cpu_quota::{CpuQuota, DEFAULT_PERIOD};
+use crate::vdev::pflash::PFLASH_BASE;
+use crate::vm::VmConfig;
+
+const DEFAULT_MEM_BASE: usize = 0x8000_0000;
+
+enum Value {
+    Str(String),
+    Int(usize),
+}
+
+/// Parses `key = value`, without the comment.
+fn parse_line(line: &str) -> Option<(&str, Value)> {
+    let (key, value) = line.split_once('=')?;
+    let (key, value) = (key.trim(), value.trim());
+    let value = if let Some(s) = value.strip_prefix('"') {
+        Value::Str(s.strip_suffix('"')?.to_string())
+    } else {
+        let digits = value.replace('_', "");
+        let int = match digits.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => digits.parse(),
+        };
+        Value::Int(int.ok()?)
+    };
+    Some((key, value))
+}
+
+/// Parses a comma-separated list of PMU events, e.g. `cycles,firmware`.
+pub fn parse_pmu_events(list: &str) -> Option<PmuEvents> {
+    let mut events = PmuEvents::NONE;
+    for name in list
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        match name {
+            "cycles" => events.cycles = true,
+            "instructions" => events.instructions = true,
+            "firmware" => events.firmware = true,
+            _ => return None,
+        }
+    }
+    Some(events)
+}
+
+/// Removes the comment of `line`, if not in a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_str = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_str = !in_str,
+            '#' if !in_str => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+impl VmConfig {
+    /// Parses the configuration file `text`.
+    pub fn from_toml(text: &str) -> AxResult<Self> {
+        let mut image = None;
+        let mut initrd = None;
+        let mut mem_base = DEFAULT_MEM_BASE;
+        let mut mem_size = None;
+        let mut entry = None;
+        let mut num_vcpus = 1;
+        let mut max_vcpus = None;
+        let mut disk = None;
+        let mut pflash = None;
+        let mut pflash_base = PFLASH_BASE;
+        let mut balloon = None;
+        let mut blk_passthrough = None;
+        let mut net_peer: Option<String> = None;
+        let mut aia = false;
+        let mut pmu_events = PmuEvents::ALL;
+        let mut crash_dump = None;
+        let mut record = None;
+        let mut replay = None;
+        let mut cpu_quota_ms = None;
+        let mut cpu_period_ms = DEFAULT_PERIOD.as_millis() as usize;
+
+        let mut section = String::new();
+        for (n, line) in text.lines().enumerate() {
+            let bad_line = || ax_err_type!(InvalidData, format!("bad VM config at line {}", n + 1));
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[') {
+                section = name
+                    .strip_suffix(']')
+                    .ok_or_else(bad_line)?
+                    .trim()
+                    .to_string();
+                continue;
+            }
+            let (key, value) = parse_line(line).ok_or_else(bad_line)?;
+            match (section.as_str(), key, value) {
+                ("", "image", Value::Str(s)) => image = Some(s),
+                ("", "initrd", Value::Str(s)) => initrd = Some(s),
+                ("", "mem_base", Value::Int(i)) => mem_base = i,
+                ("", "mem_size", Value::Int(i)) => mem_size = Some(i),
+                ("", "entry", Value::Int(i)) => entry = Some(i),
+                ("", "vcpus", Value::Int(i)) => num_vcpus = i,
+                ("", "max_vcpus", Value::Int(i)) => max_vcpus = Some(i),
+                ("", "crash_dump", Value::Str(s)) => crash_dump = Some(s),
+                ("", "record", Value::Str(s)) => record = Some(s),
+                ("", "replay", Value::Str(s)) => replay = Some(s),
+                ("", "cpu_quota_ms", Value::Int(i)) => cpu_quota_ms = Some(i),
+                ("", "cpu_period_ms", Value::Int(i)) => cpu_period_ms = i,
+                ("devices", "blk", Value::Str(s)) => disk = Some(s),
+                ("devices", "pflash", Value::Str(s)) => pflash = Some(s),
+                ("devices", "pflash_base", Value::Int(i)) => pflash_base = i,
+                ("devices", "balloon", Value::Int(i)) => balloon = Some(i),
+                ("devices", "blk_passthrough", Value::Int(i)) => blk_passthrough = Some(i),
+                ("devices", "net", Value::Str(s)) => net_peer = Some(s),
+                ("devices", "irqchip", Value::Str(s)) if s == "plic" || s == "aia" => {
+                    aia = s == "aia"
+                }
+                ("devices", "pmu_events", Value::Str(s)) => {
+                    pmu_events = parse_pmu_events(&s).ok_or_else(bad_line)?
+                }
+                _ => return Err(bad_line()),
+            }
+        }
+
+        let missing = |key| ax_err_type!(InvalidData, format!("{} missing in VM config", key));
+        #[cfg(not(feature = "vnet"))]
+        if net_peer.is_some() {
+            warn!("virtio-net needs the `vnet` feature, ignored");
+        }
+        Ok(Self {
+            mem_base,
+            mem_size: mem_size.ok_or_else(|| missing("mem_size"))?,
+            image: image.ok_or_else(|| missing("image"))?,
+            initrd,
+            entry: entry.ok_or_else(|| missing("entry"))?,
+            num_vcpus,
+            max_vcpus: max_vcpus.unwrap_or(num_vcpus),
+            disk,
+            pflash,
+            pflash_base,
+            balloon,
+            blk_passthrough,
+            #[cfg(feature = "vnet")]
+            net_peer,
+            aia,
+            pmu_events,
+            record,
+            replay,
+            crash_dump,
+            cpu_quota: cpu_quota_ms
+                .and_then(|budget| CpuQuota::from_millis(budget as u64, cpu_period_ms as u64)),
+        })
+    }
+
+    /// Loads the configuration file at `path`.
+    pub fn load(path: &str) -> AxResult<Self> {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            ax_err_type!(NotFound, format!("Failed to read {}, err {:?}", path, err))
+        })?;
+        Self::from_toml(&text)
+    }
+}
@@ -0,0 +1,63 @@
+//! CPU time quotas of the vCPUs, so a guest spinning on its harts leaves
+//! some time to the host tasks.
+//!
+//! Each vCPU of a VM with a quota may run the guest for `budget` in each
+//! `period`. The host timer makes a spinning vCPU exit at each tick, so the
+//! time it ran is charged then. Once it used its budget, its task sleeps
+//! until the next period, leaving its hart to the other tasks.
+
+use core::time::Duration;
+
+/// The period of a quota given without one.
+pub const DEFAULT_PERIOD: Duration = Duration::from_millis(100);
+
+/// The CPU time a vCPU may run the guest for in each period.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuQuota {
+    pub budget: Duration,
+    pub period: Duration,
+}
+
+impl CpuQuota {
+    /// A quota of `budget_ms` milliseconds per `period_ms` milliseconds, if
+    /// it restricts anything.
+    pub fn from_millis(budget_ms: u64, period_ms: u64) -> Option<Self> {
+        (budget_ms < period_ms).then(|| Self {
+            budget: Duration::from_millis(budget_ms),
+            period: Duration::from_millis(period_ms),
+        })
+    }
+}
+
+/// The CPU time a vCPU used in the current period of its quota.
+pub struct QuotaAccount {
+    quota: CpuQuota,
+    period_start_ns: u64,
+    used_ns: u64,
+}
+
+impl QuotaAccount {
+    pub fn new(quota: CpuQuota, now_ns: u64) -> Self {
+        Self {
+            quota,
+            period_start_ns: now_ns,
+            used_ns: 0,
+        }
+    }
+
+    /// Charges `guest_ns` of guest time at `now_ns`, and returns how long the
+    /// vCPU is descheduled for, if it used its budget.
+    pub fn charge(&mut self, now_ns: u64, guest_ns: u64) -> Option<Duration> {
+        let period_ns = self.quota.period.as_nanos() as u64;
+        let elapsed_ns = now_ns.saturating_sub(self.period_start_ns);
+        if elapsed_ns >= period_ns {
+            // The time of the previous periods is not carried over.
+            self.period_start_ns = now_ns - elapsed_ns % period_ns;
+            self.used_ns = guest_ns.min(now_ns - self.period_start_ns);
+        } else {
+            self.used_ns += guest_ns;
+        }
+        (self.used_ns >= self.quota.budget.as_nanos() as u64)
+            .then(|| Duration::from_nanos(self.period_start_ns + period_ns - now_ns))
+    }
+}
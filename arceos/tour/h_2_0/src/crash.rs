@@ -0,0 +1,133 @@
+//! Crash dumps of the guests the hypervisor cannot run any further, e.g.
+//! trapping with a cause it does not handle, instead of panicking the host.
+//!
+//! The dump holds the registers of the vCPU, its trap CSRs, and the guest
+//! memory around the faulting instruction and the faulting address. It is
+//! printed on the console, and written to the `crash_dump` file of the VM
+//! config, if any, whose directory is created if missing. The VM is shut
+//! down afterwards.
+
+use alloc::string::String;
+use core::fmt::Write as _;
+use riscv_vcpu::RISCVVCpu;
+use std::fs::File;
+use std::io::Write as _;
+
+use crate::guest_mem::GuestMemory;
+use crate::monitor::GPR_NAMES;
+use crate::translate::translate_guest_addr;
+use crate::vm::Vm;
+
+/// The bytes dumped before and after an address.
+const DUMP_AROUND: usize = 32;
+const DUMP_ROW_SIZE: usize = 16;
+
+/// The exceptions whose `stval` is the faulting guest virtual address:
+/// misaligned, access faults and page faults.
+const ADDR_EXCEPTIONS: [usize; 9] = [0, 1, 4, 5, 6, 7, 12, 13, 15];
+
+/// Dumps the state of vCPU `vcpu_id` of `vm`, which crashed for `reason`,
+/// then shuts the VM down. `fault_gpa` is the guest physical address the
+/// vCPU faulted at, if known, otherwise it is found from the trap CSRs.
+pub fn crash_vm(
+    vm: &Vm,
+    vcpu_id: usize,
+    arch_vcpu: &RISCVVCpu,
+    reason: &str,
+    fault_gpa: Option<usize>,
+) {
+    let dump = crash_dump(vm, vcpu_id, arch_vcpu, reason, fault_gpa);
+    std::println!("{}", dump);
+    if let Some(path) = &vm.config.crash_dump {
+        if let Some((dir, _)) = path.rsplit_once('/').filter(|(dir, _)| !dir.is_empty()) {
+            // Fails below if it cannot be created.
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let written = File::create(path).and_then(|mut file| file.write_all(dump.as_bytes()));
+        match written {
+            Ok(()) => info!("[VM {}] crash dump written to {}", vm.id, path),
+            Err(err) => warn!(
+                "[VM {}] failed to write the crash dump to {}: {:?}",
+                vm.id, path, err
+            ),
+        }
+    }
+    vm.guest_crash();
+}
+
+fn crash_dump(
+    vm: &Vm,
+    vcpu_id: usize,
+    arch_vcpu: &RISCVVCpu,
+    reason: &str,
+    fault_gpa: Option<usize>,
+) -> String {
+    let state = arch_vcpu.save_state();
+    let trap = arch_vcpu.trap_csrs();
+    let mut dump = String::new();
+    let _ = writeln!(dump, "[VM {}] vCPU {} crashed: {}", vm.id, vcpu_id, reason);
+    let _ = writeln!(
+        dump,
+        "sepc {:#018x} sstatus {:#x} scause {:#x} stval {:#x} htval {:#x} htinst {:#x}",
+        state.sepc, state.sstatus, trap.scause, trap.stval, trap.htval, trap.htinst
+    );
+    let _ = writeln!(
+        dump,
+        "vsatp {:#x} vstvec {:#x} vsepc {:#x} vscause {:#x} vstval {:#x} vsstatus {:#x}",
+        state.vsatp, state.vstvec, state.vsepc, state.vscause, state.vstval, state.vsstatus
+    );
+    for (names, regs) in GPR_NAMES.chunks(4).zip(state.gprs.chunks(4)) {
+        for (name, reg) in names.iter().zip(regs) {
+            let _ = write!(dump, "  {:>4} {:#018x}", name, reg);
+        }
+        let _ = writeln!(dump);
+    }
+
+    match translate_guest_addr(vm, state.vsatp, state.sepc) {
+        Ok((gpa, _)) => {
+            let _ = writeln!(dump, "memory around sepc, at gpa {:#x}:", gpa);
+            hexdump(&mut dump, vm, gpa);
+        }
+        Err(err) => {
+            let _ = writeln!(dump, "sepc not translated: {:?}", err);
+        }
+    }
+    let fault_gpa = fault_gpa.or_else(|| {
+        if trap.htval != 0 {
+            // A guest page fault, `htval` holds the address shifted by 2.
+            Some(trap.htval << 2 | trap.stval & 0x3)
+        } else if ADDR_EXCEPTIONS.contains(&trap.scause) {
+            translate_guest_addr(vm, state.vsatp, trap.stval)
+                .ok()
+                .map(|(gpa, _)| gpa)
+        } else {
+            None
+        }
+    });
+    if let Some(gpa) = fault_gpa {
+        let _ = writeln!(dump, "memory around the fault address, gpa {:#x}:", gpa);
+        hexdump(&mut dump, vm, gpa);
+    }
+    dump
+}
+
+/// Dumps the guest memory of `vm` around `gpa`, by rows, the ones not mapped
+/// being marked as such.
+fn hexdump(dump: &mut String, vm: &Vm, gpa: usize) {
+    let start = gpa.saturating_sub(DUMP_AROUND) / DUMP_ROW_SIZE * DUMP_ROW_SIZE;
+    let end = gpa.saturating_add(DUMP_AROUND);
+    let aspace = vm.aspace.lock();
+    let mem = GuestMemory::new(&aspace);
+    for row in (start..end).step_by(DUMP_ROW_SIZE) {
+        let mut buf = [0u8; DUMP_ROW_SIZE];
+        let _ = write!(dump, "  {:#x}:", row);
+        if mem.read(row.into(), &mut buf).is_ok() {
+            for byte in buf {
+                let _ = write!(dump, " {:02x}", byte);
+            }
+        } else {
+            let _ = write!(dump, " not mapped");
+        }
+        let _ = writeln!(dump);
+    }
+}
@@ -0,0 +1,170 @@
+//! Tracking of the pages of the guest RAM written by the guest.
+//!
+//! While logging, the writable pages of the guest RAM are mapped read-only,
+//! so the first write to each faults. The fault records the page in the dirty
+//! bitmap and makes it writable again, so later writes run at full speed
+//! until the log is taken.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use axerrno::{ax_err, AxResult};
+use axhal::paging::MappingFlags;
+use axmm::AddrSpace;
+use memory_addr::{VirtAddr, PAGE_SIZE_4K};
+use std::sync::Mutex;
+
+struct Bitmaps {
+    /// The pages write-protected for logging.
+    protected: Vec<u64>,
+    /// The pages written since the log was last taken.
+    dirty: Vec<u64>,
+}
+
+fn test_bit(bitmap: &[u64], n: usize) -> bool {
+    bitmap[n / 64] & (1 << (n % 64)) != 0
+}
+
+fn set_bit(bitmap: &mut [u64], n: usize, val: bool) {
+    if val {
+        bitmap[n / 64] |= 1 << (n % 64);
+    } else {
+        bitmap[n / 64] &= !(1 << (n % 64));
+    }
+}
+
+/// The dirty log of the guest RAM of a VM.
+///
+/// The caller holds the lock of the address space while calling the methods
+/// taking it, and flushes the G-stage TLBs of all the vCPUs afterwards.
+pub struct DirtyLog {
+    ram_base: usize,
+    num_pages: usize,
+    /// `None` while not logging.
+    bitmaps: Mutex<Option<Bitmaps>>,
+}
+
+impl DirtyLog {
+    pub fn new(ram_base: usize, ram_size: usize) -> Self {
+        Self {
+            ram_base,
+            num_pages: ram_size / PAGE_SIZE_4K,
+            bitmaps: Mutex::new(None),
+        }
+    }
+
+    fn page_addr(&self, n: usize) -> VirtAddr {
+        (self.ram_base + n * PAGE_SIZE_4K).into()
+    }
+
+    fn page_index(&self, gpa: VirtAddr) -> Option<usize> {
+        let n = gpa.as_usize().checked_sub(self.ram_base)? / PAGE_SIZE_4K;
+        (n < self.num_pages).then_some(n)
+    }
+
+    /// Write-protects page `n` if it is mapped writable, and returns whether
+    /// it was.
+    fn write_protect(&self, aspace: &mut AddrSpace, n: usize) -> AxResult<bool> {
+        let page = self.page_addr(n);
+        match aspace.page_table().query(page) {
+            Ok((_, flags, _)) if flags.contains(MappingFlags::WRITE) => {
+                aspace.protect(page, PAGE_SIZE_4K, flags - MappingFlags::WRITE)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn write_enable(&self, aspace: &mut AddrSpace, n: usize) -> AxResult {
+        let page = self.page_addr(n);
+        match aspace.page_table().query(page) {
+            Ok((_, flags, _)) if !flags.is_empty() => {
+                aspace.protect(page, PAGE_SIZE_4K, flags | MappingFlags::WRITE)
+            }
+            // Reclaimed by the balloon meanwhile.
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts logging: write-protects the writable pages of the guest RAM,
+    /// with all the pages clean.
+    pub fn start(&self, aspace: &mut AddrSpace) -> AxResult {
+        let mut bitmaps = self.bitmaps.lock();
+        if bitmaps.is_some() {
+            return ax_err!(AlreadyExists, "dirty log already started");
+        }
+        let words = self.num_pages.div_ceil(64);
+        let mut protected = vec![0; words];
+        for n in 0..self.num_pages {
+            let wp = self.write_protect(aspace, n)?;
+            set_bit(&mut protected, n, wp);
+        }
+        *bitmaps = Some(Bitmaps {
+            protected,
+            dirty: vec![0; words],
+        });
+        Ok(())
+    }
+
+    /// Stops logging, and makes the write-protected pages writable again.
+    pub fn stop(&self, aspace: &mut AddrSpace) -> AxResult {
+        let Some(bitmaps) = self.bitmaps.lock().take() else {
+            return ax_err!(BadState, "dirty log not started");
+        };
+        for n in (0..self.num_pages).filter(|&n| test_bit(&bitmaps.protected, n)) {
+            self.write_enable(aspace, n)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a write fault at `gpa`: if the page was write-protected for
+    /// logging, records it as dirty and makes it writable again. Returns
+    /// whether it was.
+    pub fn handle_write_fault(&self, aspace: &mut AddrSpace, gpa: VirtAddr) -> AxResult<bool> {
+        let mut bitmaps = self.bitmaps.lock();
+        let (Some(bitmaps), Some(n)) = (bitmaps.as_mut(), self.page_index(gpa)) else {
+            return Ok(false);
+        };
+        if !test_bit(&bitmaps.protected, n) {
+            return Ok(false);
+        }
+        self.write_enable(aspace, n)?;
+        set_bit(&mut bitmaps.protected, n, false);
+        set_bit(&mut bitmaps.dirty, n, true);
+        Ok(true)
+    }
+
+    /// Records the page of `gpa`, allocated on demand, as dirty if logging.
+    pub fn mark_dirty(&self, gpa: VirtAddr) {
+        if let (Some(bitmaps), Some(n)) = (self.bitmaps.lock().as_mut(), self.page_index(gpa)) {
+            set_bit(&mut bitmaps.dirty, n, true);
+        }
+    }
+
+    /// Write-protects the dirty pages again, and returns them.
+    ///
+    /// The caller flushes the TLBs, then calls [`DirtyLog::clear`] with the
+    /// returned pages, so writes through stale TLB entries are not missed.
+    pub fn collect(&self, aspace: &mut AddrSpace) -> AxResult<Vec<u64>> {
+        let mut bitmaps = self.bitmaps.lock();
+        let Some(bitmaps) = bitmaps.as_mut() else {
+            return ax_err!(BadState, "dirty log not started");
+        };
+        for n in (0..self.num_pages).filter(|&n| test_bit(&bitmaps.dirty, n)) {
+            let wp = self.write_protect(aspace, n)?;
+            set_bit(&mut bitmaps.protected, n, wp);
+        }
+        Ok(bitmaps.dirty.clone())
+    }
+
+    /// Cleans the pages of `collected`, except the ones written again since,
+    /// which stay dirty for the next log.
+    pub fn clear(&self, collected: &[u64]) {
+        if let Some(bitmaps) = self.bitmaps.lock().as_mut() {
+            for n in (0..self.num_pages).filter(|&n| test_bit(collected, n)) {
+                if test_bit(&bitmaps.protected, n) {
+                    set_bit(&mut bitmaps.dirty, n, false);
+                }
+            }
+        }
+    }
+}
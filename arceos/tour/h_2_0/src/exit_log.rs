@@ -0,0 +1,241 @@
+//! Recording and replay of the VM exits of a guest, to reproduce its bugs
+//! offline.
+//!
+//! When recording, each exit of the vCPUs is logged to a file: the vCPU, the
+//! kind of exit, the guest registers, and the value the guest read for MMIO
+//! reads, e.g. the input of the console. When replaying, the log is read
+//! back: each exit is checked against the logged one, by its kind and the
+//! guest pc, and the values the guest reads come from the log rather than
+//! from the devices. The first exit differing is reported, and the guest
+//! runs live from there. The registers are logged to compare them offline,
+//! as they differ with the guest time.
+//!
+//! The exits of each vCPU are replayed in order, but neither the
+//! interleaving of the vCPUs nor the instructions asynchronous interrupts
+//! come at, so the guest may still diverge with them.
+//!
+//! The file is a sequence of little-endian 64-bit words: the magic, the
+//! version and the number of vCPUs. Then for each exit, the vCPU, the length
+//! of the name of the exit kind followed by the name, padded to a word,
+//! `sepc`, the 32 GPRs and the value read.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use axerrno::{ax_err, ax_err_type, AxError, AxResult};
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv_vcpu::RISCVVCpu;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+const EXIT_LOG_MAGIC: u64 = u64::from_le_bytes(*b"AXVMEXIT");
+const EXIT_LOG_VERSION: u64 = 1;
+
+/// The size of the recorded exits buffered before being written.
+const RECORD_BUF_SIZE: usize = 0x1_0000;
+
+fn io_err(err: std::io::Error) -> AxError {
+    ax_err_type!(Io, format!("Failed to access the exit log, err {:?}", err))
+}
+
+/// The guest registers at a VM exit.
+pub struct ExitRegs {
+    pub sepc: usize,
+    pub gprs: [usize; 32],
+}
+
+impl ExitRegs {
+    pub fn of(arch_vcpu: &RISCVVCpu) -> Self {
+        let state = arch_vcpu.save_state();
+        Self {
+            sepc: state.sepc,
+            gprs: state.gprs,
+        }
+    }
+}
+
+struct Exit {
+    reason: String,
+    regs: ExitRegs,
+    data: usize,
+}
+
+struct Recorder {
+    file: File,
+    buf: Vec<u8>,
+}
+
+impl Recorder {
+    fn push_word(&mut self, word: usize) {
+        self.buf.extend_from_slice(&(word as u64).to_le_bytes());
+    }
+
+    fn flush(&mut self) -> AxResult {
+        self.file.write_all(&self.buf).map_err(io_err)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+enum Mode {
+    Record(Mutex<Recorder>),
+    Replay {
+        /// The exits left to replay, per vCPU.
+        exits: Vec<Mutex<VecDeque<Exit>>>,
+        diverged: AtomicBool,
+    },
+}
+
+/// The exit log of a VM, recorded or replayed.
+pub struct ExitLog {
+    mode: Mode,
+}
+
+/// Reads the words of `bytes` from `*pos`.
+fn take_words<'a>(bytes: &'a [u8], pos: &mut usize, num: usize) -> AxResult<&'a [u8]> {
+    let words = bytes
+        .get(*pos..*pos + num * 8)
+        .ok_or_else(|| ax_err_type!(InvalidData, "truncated exit log"))?;
+    *pos += num * 8;
+    Ok(words)
+}
+
+fn take_word(bytes: &[u8], pos: &mut usize) -> AxResult<usize> {
+    let word = take_words(bytes, pos, 1)?;
+    Ok(u64::from_le_bytes(word.try_into().unwrap()) as usize)
+}
+
+impl ExitLog {
+    /// Starts recording the exits of the `num_vcpus` vCPUs of a VM to the
+    /// file at `path`.
+    pub fn record(path: &str, num_vcpus: usize) -> AxResult<Self> {
+        let mut recorder = Recorder {
+            file: File::create(path).map_err(io_err)?,
+            buf: Vec::with_capacity(RECORD_BUF_SIZE),
+        };
+        for word in [
+            EXIT_LOG_MAGIC as usize,
+            EXIT_LOG_VERSION as usize,
+            num_vcpus,
+        ] {
+            recorder.push_word(word);
+        }
+        Ok(Self {
+            mode: Mode::Record(Mutex::new(recorder)),
+        })
+    }
+
+    /// Loads the exits recorded in the file at `path`, to replay them for a
+    /// VM with `num_vcpus` vCPUs.
+    pub fn replay(path: &str, num_vcpus: usize) -> AxResult<Self> {
+        let mut bytes = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(io_err)?;
+        let mut pos = 0;
+        if take_word(&bytes, &mut pos)? != EXIT_LOG_MAGIC as usize
+            || take_word(&bytes, &mut pos)? != EXIT_LOG_VERSION as usize
+        {
+            return ax_err!(InvalidData, "not an exit log");
+        }
+        if take_word(&bytes, &mut pos)? != num_vcpus {
+            return ax_err!(InvalidData, "exit log of another number of vCPUs");
+        }
+        let mut exits: Vec<_> = (0..num_vcpus).map(|_| VecDeque::new()).collect();
+        while pos < bytes.len() {
+            let vcpu = take_word(&bytes, &mut pos)?;
+            let len = take_word(&bytes, &mut pos)?;
+            let name = take_words(&bytes, &mut pos, len.div_ceil(8))?;
+            let reason = String::from_utf8_lossy(&name[..len]).into_owned();
+            let sepc = take_word(&bytes, &mut pos)?;
+            let mut gprs = [0; 32];
+            for gpr in &mut gprs {
+                *gpr = take_word(&bytes, &mut pos)?;
+            }
+            let data = take_word(&bytes, &mut pos)?;
+            exits
+                .get_mut(vcpu)
+                .ok_or_else(|| ax_err_type!(InvalidData, "bad vCPU in the exit log"))?
+                .push_back(Exit {
+                    reason,
+                    regs: ExitRegs { sepc, gprs },
+                    data,
+                });
+        }
+        Ok(Self {
+            mode: Mode::Replay {
+                exits: exits.into_iter().map(Mutex::new).collect(),
+                diverged: AtomicBool::new(false),
+            },
+        })
+    }
+
+    /// Called on the exit `reason` of vCPU `vcpu` of VM `vm_id`, with the
+    /// guest registers `regs`, before it is handled. When replaying, returns
+    /// the value the guest reads, if the exit is the logged one.
+    pub fn replay_exit(
+        &self,
+        vm_id: usize,
+        vcpu: usize,
+        reason: &str,
+        regs: &ExitRegs,
+    ) -> Option<usize> {
+        let Mode::Replay { exits, diverged } = &self.mode else {
+            return None;
+        };
+        if diverged.load(Ordering::Acquire) {
+            return None;
+        }
+        let exit = exits[vcpu].lock().pop_front();
+        match exit {
+            Some(exit) if exit.reason == reason && exit.regs.sepc == regs.sepc => Some(exit.data),
+            exit => {
+                if !diverged.swap(true, Ordering::AcqRel) {
+                    match exit {
+                        Some(exit) => warn!(
+                            "[VM {}] vCPU {} diverged from the exit log: {} at {:#x}, logged {} at {:#x}",
+                            vm_id, vcpu, reason, regs.sepc, exit.reason, exit.regs.sepc
+                        ),
+                        None => warn!("[VM {}] vCPU {} ran past the exit log", vm_id, vcpu),
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// Called once the exit `reason` of vCPU `vcpu`, with the guest
+    /// registers `regs`, is handled, the guest having read `data`, if any.
+    /// When recording, logs the exit.
+    pub fn record_exit(&self, vcpu: usize, reason: &str, regs: &ExitRegs, data: usize) -> AxResult {
+        let Mode::Record(recorder) = &self.mode else {
+            return Ok(());
+        };
+        let mut recorder = recorder.lock();
+        recorder.push_word(vcpu);
+        recorder.push_word(reason.len());
+        recorder.buf.extend_from_slice(reason.as_bytes());
+        recorder.buf.resize(
+            recorder.buf.len() + reason.len().next_multiple_of(8) - reason.len(),
+            0,
+        );
+        recorder.push_word(regs.sepc);
+        for &gpr in &regs.gprs {
+            recorder.push_word(gpr);
+        }
+        recorder.push_word(data);
+        if recorder.buf.len() >= RECORD_BUF_SIZE {
+            recorder.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the exits recorded so far to the file.
+    pub fn flush(&self) -> AxResult {
+        match &self.mode {
+            Mode::Record(recorder) => recorder.lock().flush(),
+            Mode::Replay { .. } => Ok(()),
+        }
+    }
+}
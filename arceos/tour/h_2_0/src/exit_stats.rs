@@ -0,0 +1,105 @@
+//! Statistics of the VM exits, to see where the guest time goes.
+//!
+//! Each vCPU counts its exits by reason, and by CSR for the trapped CSR
+//! accesses, with the time the hypervisor spent handling them.
+
+use alloc::collections::BTreeMap;
+use core::fmt;
+
+/// The number of exits of a kind, and the time spent handling them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExitCounter {
+    pub count: u64,
+    pub time_ns: u64,
+}
+
+impl ExitCounter {
+    fn add(&mut self, other: &ExitCounter) {
+        self.count += other.count;
+        self.time_ns += other.time_ns;
+    }
+}
+
+/// The exit statistics of a vCPU, or of a whole VM.
+#[derive(Debug, Clone, Default)]
+pub struct ExitStats {
+    /// The time spent running the guest.
+    pub guest_time_ns: u64,
+    /// By exit reason.
+    pub reasons: BTreeMap<&'static str, ExitCounter>,
+    /// By trapped CSR.
+    pub csrs: BTreeMap<u16, ExitCounter>,
+}
+
+impl ExitStats {
+    /// Records an exit for `reason`, and `csr` if it was a trapped CSR
+    /// access, after `guest_time_ns` in the guest and taking `time_ns` to
+    /// handle.
+    pub fn record(
+        &mut self,
+        reason: &'static str,
+        csr: Option<u16>,
+        guest_time_ns: u64,
+        time_ns: u64,
+    ) {
+        let counter = ExitCounter { count: 1, time_ns };
+        self.guest_time_ns += guest_time_ns;
+        self.reasons.entry(reason).or_default().add(&counter);
+        if let Some(csr) = csr {
+            self.csrs.entry(csr).or_default().add(&counter);
+        }
+    }
+
+    /// Adds the statistics of `other`, e.g. to sum the ones of all vCPUs.
+    pub fn merge(&mut self, other: &ExitStats) {
+        self.guest_time_ns += other.guest_time_ns;
+        for (reason, counter) in &other.reasons {
+            self.reasons.entry(reason).or_default().add(counter);
+        }
+        for (csr, counter) in &other.csrs {
+            self.csrs.entry(*csr).or_default().add(counter);
+        }
+    }
+
+    /// All the exits.
+    pub fn total(&self) -> ExitCounter {
+        let mut total = ExitCounter::default();
+        for counter in self.reasons.values() {
+            total.add(counter);
+        }
+        total
+    }
+}
+
+impl fmt::Display for ExitStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total();
+        write!(
+            f,
+            "{} exits, {} us handling, {} us in guest",
+            total.count,
+            total.time_ns / 1000,
+            self.guest_time_ns / 1000
+        )?;
+        for (reason, counter) in &self.reasons {
+            write!(
+                f,
+                "\n  {:<20} {:>10} {:>10} us",
+                reason,
+                counter.count,
+                counter.time_ns / 1000
+            )?;
+        }
+        for (csr, counter) in &self.csrs {
+            let name = format!("csr {:#05x}", csr);
+            write!(
+                f,
+                "\n  {:<20} {:>10} {:>10} us",
+                name,
+                counter.count,
+                counter.time_ns / 1000
+            )?;
+        }
+        Ok(())
+    }
+}
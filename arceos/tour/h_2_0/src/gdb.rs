@@ -0,0 +1,435 @@
+//! A GDB remote stub, to debug the guest of a VM over TCP.
+//!
+//! The vCPUs are the threads of the target, and the VM is paused while the
+//! debugger has control. Breakpoints are `ebreak`s written to the guest
+//! memory, which exit to the hypervisor while a debugger is attached, so the
+//! guest can not handle `ebreak` itself meanwhile. A step runs the vCPU for an
+//! instruction with the other vCPUs paused, even for `vCont;s:N;c`.
+//!
+//! Addresses are guest physical ones, as seen by a guest with its MMU off.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use axerrno::{AxError, AxResult};
+use core::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use crate::guest_mem::GuestMemory;
+use crate::vm::{Vm, VmState};
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The interrupt request of the debugger, Ctrl-C.
+const INTERRUPT: u8 = 0x03;
+
+/// The max size of a packet, and of the replies to memory reads.
+const PACKET_SIZE: usize = 0x1000;
+
+/// The general purpose registers, followed by `pc`.
+const GPR_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+const PC: usize = 32;
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(out: &mut String, bytes: &[u8]) {
+    for b in bytes {
+        let _ = write!(out, "{:02x}", b);
+    }
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><architecture>riscv:rv64</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (i, name) in GPR_NAMES.iter().enumerate() {
+        let ty = match *name {
+            "sp" | "fp" => "data_ptr",
+            _ => "int",
+        };
+        let _ = write!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>",
+            name, ty, i
+        );
+    }
+    xml.push_str("<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"32\"/>");
+    xml.push_str("</feature></target>");
+    xml
+}
+
+/// Serves the debuggers of `vm` on the TCP port `port`, one at a time.
+pub fn serve(vm: Arc<Vm>, port: u16) {
+    let listener = match TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))) {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!(
+                "[VM {}] gdb stub failed to listen on {}: {:?}",
+                vm.id, port, err
+            );
+            return;
+        }
+    };
+    info!("[VM {}] gdb stub listening on port {}", vm.id, port);
+    while let Ok((stream, peer)) = listener.accept() {
+        info!("[VM {}] debugger attached from {}", vm.id, peer);
+        let mut stub = GdbStub {
+            vm: vm.clone(),
+            stream,
+            vcpu: 0,
+            breakpoints: BTreeMap::new(),
+        };
+        if let Err(err) = stub.run() {
+            warn!("[VM {}] debugger connection failed: {:?}", vm.id, err);
+        }
+        stub.detach();
+        info!("[VM {}] debugger detached", vm.id);
+        if vm.state() == VmState::Shutdown {
+            break;
+        }
+    }
+}
+
+/// The connection to a debugger.
+struct GdbStub {
+    vm: Arc<Vm>,
+    stream: TcpStream,
+    /// The vCPU whose registers are accessed.
+    vcpu: usize,
+    /// The original instructions under the breakpoints.
+    breakpoints: BTreeMap<usize, Vec<u8>>,
+}
+
+impl GdbStub {
+    fn read_byte(&mut self) -> AxResult<u8> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Err(AxError::ConnectionReset),
+            _ => Ok(byte[0]),
+        }
+    }
+
+    /// Reads the next packet, acknowledging it.
+    fn read_packet(&mut self) -> AxResult<String> {
+        loop {
+            // Interrupt requests while stopped are ignored.
+            while self.read_byte()? != b'$' {}
+            let mut data = Vec::new();
+            let mut sum = 0u8;
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b => {
+                        sum = sum.wrapping_add(b);
+                        data.push(b);
+                    }
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let valid = core::str::from_utf8(&checksum)
+                .ok()
+                .and_then(parse_hex)
+                .is_some_and(|checksum| checksum == sum as usize);
+            match String::from_utf8(data) {
+                Ok(data) if valid => {
+                    self.stream.write_all(b"+")?;
+                    return Ok(data);
+                }
+                _ => self.stream.write_all(b"-")?,
+            }
+        }
+    }
+
+    /// Sends a packet, until the debugger acknowledges it.
+    fn send_packet(&mut self, data: &str) -> AxResult {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, sum);
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn run(&mut self) -> AxResult {
+        self.vm.set_debugging(true);
+        // The debugger takes control of the running guest.
+        let _ = self.vm.pause();
+        loop {
+            let packet = self.read_packet()?;
+            let reply = match packet.split_at(packet.len().min(1)) {
+                ("c", _) | ("C", _) => self.resume()?,
+                ("s", _) | ("S", _) => self.step()?,
+                _ if packet.starts_with("vCont;") => self.vcont(&packet["vCont;".len()..])?,
+                ("D", _) => {
+                    self.send_packet("OK")?;
+                    return Ok(());
+                }
+                ("k", _) => {
+                    let _ = self.vm.shutdown();
+                    return Ok(());
+                }
+                _ => self.handle(&packet).unwrap_or_else(|| "E01".into()),
+            };
+            self.send_packet(&reply)?;
+        }
+    }
+
+    /// Handles the packets other than the ones resuming or leaving, and
+    /// returns the reply, or `None` on errors.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let num_vcpus = self.vm.num_vcpus();
+        let reply = if packet == "?" {
+            self.stop_reply(SIGTRAP, false)
+        } else if packet.starts_with("qSupported") {
+            format!("PacketSize={:x};qXfer:features:read+;swbreak+", PACKET_SIZE)
+        } else if packet == "qAttached" {
+            "1".into()
+        } else if packet == "qC" {
+            format!("QC{:x}", self.vcpu + 1)
+        } else if packet == "qfThreadInfo" {
+            let ids: Vec<String> = (1..=num_vcpus).map(|id| format!("{:x}", id)).collect();
+            format!("m{}", ids.join(","))
+        } else if packet == "qsThreadInfo" {
+            "l".into()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = args.split_once(',')?;
+            let (offset, len) = (parse_hex(offset)?, parse_hex(len)?);
+            let xml = target_xml();
+            let chunk = xml.get(offset.min(xml.len())..)?;
+            if chunk.len() > len {
+                format!("m{}", &chunk[..len])
+            } else {
+                format!("l{}", chunk)
+            }
+        } else if packet == "vCont?" {
+            "vCont;c;C;s;S".into()
+        } else if let Some(thread) = packet.strip_prefix('H') {
+            // Any thread for `-1` or `0`, thread IDs start from 1.
+            match parse_hex(thread.get(1..)?) {
+                Some(id) if id > 0 => {
+                    if id > num_vcpus {
+                        return None;
+                    }
+                    self.vcpu = id - 1;
+                }
+                _ => {}
+            }
+            "OK".into()
+        } else if let Some(thread) = packet.strip_prefix('T') {
+            let id = parse_hex(thread)?;
+            if id == 0 || id > num_vcpus {
+                return None;
+            }
+            "OK".into()
+        } else if packet == "g" {
+            let state = self.vm.paused_vcpu_state(self.vcpu)?;
+            let mut reply = String::new();
+            for reg in state.gprs.iter().chain([&state.sepc]) {
+                encode_hex(&mut reply, &reg.to_le_bytes());
+            }
+            reply
+        } else if let Some(regs) = packet.strip_prefix('G') {
+            let regs = decode_hex(regs)?;
+            let mut state = self.vm.paused_vcpu_state(self.vcpu)?;
+            for (n, reg) in regs.chunks_exact(8).enumerate().take(PC + 1) {
+                self.set_reg(&mut state, n, u64::from_le_bytes(reg.try_into().unwrap()));
+            }
+            self.vm.set_paused_vcpu_state(self.vcpu, state).ok()?;
+            "OK".into()
+        } else if let Some(n) = packet.strip_prefix('p') {
+            let n = parse_hex(n)?;
+            let state = self.vm.paused_vcpu_state(self.vcpu)?;
+            let reg = match n {
+                PC => state.sepc,
+                _ => *state.gprs.get(n)?,
+            };
+            let mut reply = String::new();
+            encode_hex(&mut reply, &reg.to_le_bytes());
+            reply
+        } else if let Some(args) = packet.strip_prefix('P') {
+            let (n, val) = args.split_once('=')?;
+            let n = parse_hex(n)?;
+            let val = decode_hex(val)?;
+            if n > PC || val.len() != 8 {
+                return None;
+            }
+            let mut state = self.vm.paused_vcpu_state(self.vcpu)?;
+            self.set_reg(&mut state, n, u64::from_le_bytes(val.try_into().unwrap()));
+            self.vm.set_paused_vcpu_state(self.vcpu, state).ok()?;
+            "OK".into()
+        } else if let Some(args) = packet.strip_prefix('m') {
+            let (addr, len) = args.split_once(',')?;
+            let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+            let mut buf = vec![0; len.min(PACKET_SIZE / 2)];
+            let aspace = self.vm.aspace.lock();
+            GuestMemory::new(&aspace).read(addr.into(), &mut buf).ok()?;
+            let mut reply = String::new();
+            encode_hex(&mut reply, &buf);
+            reply
+        } else if let Some(args) = packet.strip_prefix('M') {
+            let (range, data) = args.split_once(':')?;
+            let (addr, len) = range.split_once(',')?;
+            let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+            let data = decode_hex(data)?;
+            if data.len() != len {
+                return None;
+            }
+            let aspace = self.vm.aspace.lock();
+            GuestMemory::new(&aspace).write(addr.into(), &data).ok()?;
+            "OK".into()
+        } else if let Some(args) = packet.strip_prefix("Z0,") {
+            let (addr, kind) = args.split_once(',')?;
+            self.insert_breakpoint(parse_hex(addr)?, parse_hex(kind)?)?;
+            "OK".into()
+        } else if let Some(args) = packet.strip_prefix("z0,") {
+            let (addr, _) = args.split_once(',')?;
+            self.remove_breakpoint(parse_hex(addr)?)?;
+            "OK".into()
+        } else {
+            // Not supported.
+            String::new()
+        };
+        Some(reply)
+    }
+
+    fn set_reg(&self, state: &mut riscv_vcpu::VCpuState, n: usize, val: u64) {
+        match n {
+            PC => state.sepc = val as usize,
+            // `zero` is hardwired.
+            0 => {}
+            _ => state.gprs[n] = val as usize,
+        }
+    }
+
+    fn stop_reply(&self, signal: u8, breakpoint: bool) -> String {
+        let swbreak = if breakpoint { "swbreak:;" } else { "" };
+        format!("T{:02x}{}thread:{:x};", signal, swbreak, self.vcpu + 1)
+    }
+
+    /// Resumes the guest until it hits a breakpoint, or the debugger
+    /// interrupts it, and returns the stop reply.
+    fn resume(&mut self) -> AxResult<String> {
+        self.vm.take_breakpoint_stop();
+        self.vm.resume()?;
+        self.stream.set_nonblocking(true)?;
+        let res = self.wait_for_stop();
+        self.stream.set_nonblocking(false)?;
+        res
+    }
+
+    /// Steps the current vCPU by an instruction, and returns the stop reply.
+    fn step(&mut self) -> AxResult<String> {
+        self.vm.step_vcpu(self.vcpu)?;
+        if self.vm.state() == VmState::Shutdown {
+            return Ok("W00".into());
+        }
+        Ok(self.stop_reply(SIGTRAP, false))
+    }
+
+    /// Handles the `vCont` actions, of which the first one only is done, on
+    /// its thread if any.
+    fn vcont(&mut self, actions: &str) -> AxResult<String> {
+        let action = actions.split(';').next().unwrap_or_default();
+        let (action, thread) = match action.split_once(':') {
+            Some((action, thread)) => (action, parse_hex(thread)),
+            None => (action, None),
+        };
+        match action.get(..1) {
+            Some("s" | "S") => {
+                if let Some(id) = thread.filter(|&id| id > 0 && id <= self.vm.num_vcpus()) {
+                    self.vcpu = id - 1;
+                }
+                self.step()
+            }
+            Some("c" | "C") => self.resume(),
+            _ => Ok("E01".into()),
+        }
+    }
+
+    fn wait_for_stop(&mut self) -> AxResult<String> {
+        let mut byte = [0];
+        loop {
+            if let Some(id) = self.vm.take_breakpoint_stop() {
+                self.vcpu = id;
+                return Ok(self.stop_reply(SIGTRAP, true));
+            }
+            if self.vm.state() == VmState::Shutdown {
+                return Ok("W00".into());
+            }
+            match self.stream.read(&mut byte) {
+                Ok(0) => return Err(AxError::ConnectionReset),
+                Ok(_) if byte[0] == INTERRUPT => {
+                    self.vm.pause()?;
+                    return Ok(self.stop_reply(SIGINT, false));
+                }
+                Ok(_) | Err(AxError::WouldBlock) => thread::yield_now(),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn insert_breakpoint(&mut self, addr: usize, kind: usize) -> Option<()> {
+        if self.breakpoints.contains_key(&addr) {
+            return Some(());
+        }
+        let ebreak = match kind {
+            2 => C_EBREAK.to_le_bytes().to_vec(),
+            4 => EBREAK.to_le_bytes().to_vec(),
+            _ => return None,
+        };
+        let aspace = self.vm.aspace.lock();
+        let mem = GuestMemory::new(&aspace);
+        let mut orig = vec![0; kind];
+        mem.read(addr.into(), &mut orig).ok()?;
+        mem.write(addr.into(), &ebreak).ok()?;
+        self.breakpoints.insert(addr, orig);
+        Some(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: usize) -> Option<()> {
+        let orig = self.breakpoints.remove(&addr)?;
+        let aspace = self.vm.aspace.lock();
+        GuestMemory::new(&aspace).write(addr.into(), &orig).ok()
+    }
+
+    /// Removes the breakpoints, and lets the guest run on its own.
+    fn detach(&mut self) {
+        let addrs: Vec<usize> = self.breakpoints.keys().copied().collect();
+        for addr in addrs {
+            self.remove_breakpoint(addr);
+        }
+        self.vm.set_debugging(false);
+        let _ = self.vm.resume();
+    }
+}
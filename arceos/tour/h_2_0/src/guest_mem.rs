@@ -0,0 +1,100 @@
+//! Typed accesses to the guest memory of a VM, for the loader and the device
+//! models.
+//!
+//! The accesses go through the G-stage mappings of the VM. They fail if a
+//! page of the range is not mapped, or not accessible by the guest, e.g.
+//! being reclaimed by the balloon.
+
+use alloc::string::String;
+use axerrno::{ax_err, AxResult};
+use axmm::AddrSpace;
+use core::mem::{size_of, MaybeUninit};
+use memory_addr::{MemoryAddr, VirtAddr, PAGE_SIZE_4K};
+
+/// A type made of plain data, for which any bytes are a valid value.
+///
+/// # Safety
+///
+/// The type must have no padding, and no invalid bit patterns. The values
+/// are in the byte order of the host, little-endian like virtio.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for usize {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// The guest memory of a VM, borrowed from its address space.
+pub struct GuestMemory<'a> {
+    aspace: &'a AddrSpace,
+}
+
+impl<'a> GuestMemory<'a> {
+    pub fn new(aspace: &'a AddrSpace) -> Self {
+        Self { aspace }
+    }
+
+    /// Checks that the `size` bytes at `gpa` are mapped to the guest.
+    fn check(&self, gpa: VirtAddr, size: usize) -> AxResult {
+        let Some(end) = gpa.as_usize().checked_add(size) else {
+            return ax_err!(BadAddress, "guest range overflows");
+        };
+        if !self.aspace.contains_range(gpa, size) {
+            return ax_err!(BadAddress, "out of the guest memory");
+        }
+        let mut page = gpa.align_down_4k();
+        while page.as_usize() < end {
+            match self.aspace.page_table().query(page) {
+                Ok((_, flags, _)) if !flags.is_empty() => {}
+                _ => return ax_err!(BadAddress, "guest page not accessible"),
+            }
+            page += PAGE_SIZE_4K;
+        }
+        Ok(())
+    }
+
+    /// Reads the bytes at `gpa` into `buf`.
+    pub fn read(&self, gpa: VirtAddr, buf: &mut [u8]) -> AxResult {
+        self.check(gpa, buf.len())?;
+        self.aspace.read(gpa, buf)
+    }
+
+    /// Writes `buf` at `gpa`.
+    pub fn write(&self, gpa: VirtAddr, buf: &[u8]) -> AxResult {
+        self.check(gpa, buf.len())?;
+        self.aspace.write(gpa, buf)
+    }
+
+    /// Reads the value of type `T` at `gpa`.
+    pub fn read_obj<T: Pod>(&self, gpa: VirtAddr) -> AxResult<T> {
+        let mut val = MaybeUninit::<T>::zeroed();
+        // SAFETY: zeroed, and any bytes are a valid `T`.
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        self.read(gpa, bytes)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// Writes `val` at `gpa`.
+    pub fn write_obj<T: Pod>(&self, gpa: VirtAddr, val: &T) -> AxResult {
+        // SAFETY: `T` has no padding.
+        let bytes =
+            unsafe { core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+        self.write(gpa, bytes)
+    }
+
+    /// Reads the NUL-terminated string at `gpa`, of at most `max_len` bytes
+    /// without the NUL. Bytes which are not UTF-8 are replaced.
+    pub fn read_cstr(&self, gpa: VirtAddr, max_len: usize) -> AxResult<String> {
+        let mut bytes = vec![];
+        for i in 0..max_len {
+            match self.read_obj::<u8>(gpa + i)? {
+                0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+                byte => bytes.push(byte),
+            }
+        }
+        ax_err!(InvalidData, "guest string too long")
+    }
+}
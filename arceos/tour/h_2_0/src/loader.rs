@@ -0,0 +1,304 @@
+//! Loading of the guest images from the host filesystem.
+//!
+//! An image is either an ELF file, whose segments are loaded at their
+//! physical addresses, or a flat binary loaded at the entry of the VM. A flat
+//! binary may be gzip-compressed, e.g. an `Image.gz` of Linux, and is
+//! decompressed on the fly.
+//!
+//! The initrd, if any, is loaded as is in the middle of the guest RAM, like
+//! QEMU does, so the kernel does not overwrite it when it decompresses.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use axerrno::{ax_err, ax_err_type, AxError, AxResult};
+use axhal::paging::MappingFlags;
+use axmm::AddrSpace;
+use core::ops::Range;
+use elf::abi::{PF_R, PF_W, PF_X, PT_LOAD};
+use elf::endian::AnyEndian;
+use elf::segment::ProgramHeader;
+use elf::ElfBytes;
+use memory_addr::{MemoryAddr, VirtAddr, PAGE_SIZE_4K};
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+
+use crate::guest_mem::GuestMemory;
+use crate::vm::VmConfig;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+/// The compression method of gzip, deflate.
+const GZIP_CM_DEFLATE: u8 = 8;
+const GZIP_FHCRC: u8 = 1 << 1;
+const GZIP_FEXTRA: u8 = 1 << 2;
+const GZIP_FNAME: u8 = 1 << 3;
+const GZIP_FCOMMENT: u8 = 1 << 4;
+
+/// The size of the chunks decompressed at once.
+const INFLATE_CHUNK_SIZE: usize = 0x1_0000;
+
+/// The initrd is loaded at the middle of the guest RAM, but at most this far
+/// from its start.
+const INITRD_MAX_OFFSET: usize = 0x2000_0000;
+
+/// Loads the image of the VM described by `config` into its guest memory,
+/// whose RAM is mapped on demand, and returns the entry point.
+pub fn load_vm_image(config: &VmConfig, aspace: &mut AddrSpace) -> AxResult<usize> {
+    let (mut image_file, image_size) = open_image_file(&config.image)?;
+    let mut magic = [0u8; 4];
+    let magic = match image_file.read_exact(&mut magic) {
+        Ok(()) => &magic[..],
+        Err(_) => &[],
+    };
+    let is_elf = magic == ELF_MAGIC;
+    let is_gzip = magic.starts_with(GZIP_MAGIC);
+    image_file.seek(SeekFrom::Start(0)).map_err(io_err)?;
+    if is_elf {
+        load_elf(image_file, config, aspace)
+    } else if is_gzip {
+        load_gzip(image_file, config.entry.into(), aspace)?;
+        Ok(config.entry)
+    } else {
+        populate_ram(aspace, config.entry.into(), image_size)?;
+        load_flat(image_file, image_size, config.entry.into(), aspace)?;
+        Ok(config.entry)
+    }
+}
+
+/// Loads the initrd of the VM described by `config`, if any, into its guest
+/// memory, and returns its guest physical address range.
+pub fn load_initrd(config: &VmConfig, aspace: &mut AddrSpace) -> AxResult<Option<Range<usize>>> {
+    let Some(path) = &config.initrd else {
+        return Ok(None);
+    };
+    let (file, size) = open_image_file(path)?;
+    let start = (config.mem_base + (config.mem_size / 2).min(INITRD_MAX_OFFSET)).align_down_4k();
+    if start + size > config.mem_base + config.mem_size {
+        return ax_err!(InvalidInput, "initrd does not fit in the guest RAM");
+    }
+    populate_ram(aspace, start.into(), size)?;
+    load_flat(file, size, start.into(), aspace)?;
+    Ok(Some(start..start + size))
+}
+
+/// Allocates the pages of the guest RAM from `start` to `start + size` which
+/// are not yet, as if the guest wrote them.
+pub fn populate_ram(aspace: &mut AddrSpace, start: VirtAddr, size: usize) -> AxResult {
+    let end = (start + size).align_up_4k();
+    let mut page = start.align_down_4k();
+    while page < end {
+        if aspace.page_table().query(page).is_err()
+            && !aspace.handle_page_fault(page, MappingFlags::WRITE)
+        {
+            return ax_err!(InvalidInput, "not in the guest RAM");
+        }
+        page += PAGE_SIZE_4K;
+    }
+    Ok(())
+}
+
+fn io_err(err: std::io::Error) -> AxError {
+    ax_err_type!(Io, format!("Failed in reading the VM image, err {:?}", err))
+}
+
+fn load_flat(
+    image_file: File,
+    image_size: usize,
+    image_load_gpa: VirtAddr,
+    aspace: &AddrSpace,
+) -> AxResult {
+    let image_load_regions = aspace
+        .translated_byte_buffer(image_load_gpa, image_size)
+        .ok_or_else(|| ax_err_type!(InvalidInput, "image does not fit in the guest RAM"))?;
+    let mut file = BufReader::new(image_file);
+
+    for buffer in image_load_regions {
+        file.read_exact(buffer).map_err(io_err)?;
+    }
+
+    Ok(())
+}
+
+/// Skips the gzip header at the start of `file`, up to the deflate stream.
+fn skip_gzip_header(file: &mut impl Read) -> AxResult {
+    let bad_gzip = || ax_err_type!(InvalidData, "bad gzip image");
+    let mut read_bytes = |len: usize| -> AxResult<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        file.read_exact(&mut buf).map_err(io_err)?;
+        Ok(buf)
+    };
+    // The magic, the method, the flags, then the time, XFL and OS.
+    let header = read_bytes(10)?;
+    if &header[..2] != GZIP_MAGIC || header[2] != GZIP_CM_DEFLATE {
+        return Err(bad_gzip());
+    }
+    let flags = header[3];
+    if flags & GZIP_FEXTRA != 0 {
+        let len = read_bytes(2)?;
+        read_bytes(u16::from_le_bytes([len[0], len[1]]) as usize)?;
+    }
+    // The file name and the comment are NUL-terminated.
+    for flag in [GZIP_FNAME, GZIP_FCOMMENT] {
+        if flags & flag != 0 {
+            while read_bytes(1)?[0] != 0 {}
+        }
+    }
+    if flags & GZIP_FHCRC != 0 {
+        read_bytes(2)?;
+    }
+    Ok(())
+}
+
+/// Decompresses the gzip-compressed flat image `image_file` into the guest
+/// memory at `load_gpa`.
+fn load_gzip(mut image_file: File, load_gpa: VirtAddr, aspace: &mut AddrSpace) -> AxResult {
+    let bad_gzip = || ax_err_type!(InvalidData, "bad gzip image");
+    // The size of the decompressed image is at the end, modulo 4G.
+    let mut size = [0u8; 4];
+    image_file.seek(SeekFrom::End(-4)).map_err(io_err)?;
+    image_file.read_exact(&mut size).map_err(io_err)?;
+    image_file.seek(SeekFrom::Start(0)).map_err(io_err)?;
+    let size = u32::from_le_bytes(size) as usize;
+    populate_ram(aspace, load_gpa, size)?;
+
+    let mem = GuestMemory::new(aspace);
+    let mut file = BufReader::new(image_file);
+    skip_gzip_header(&mut file)?;
+    let mut state = InflateState::new_boxed(DataFormat::Raw);
+    let mut output = vec![0u8; INFLATE_CHUNK_SIZE];
+    let mut written = 0;
+    loop {
+        let input = file.fill_buf().map_err(io_err)?;
+        let eof = input.is_empty();
+        let res = inflate(&mut state, input, &mut output, MZFlush::None);
+        file.consume(res.bytes_consumed);
+        let out = &output[..res.bytes_written];
+        if written == 0 && out.starts_with(ELF_MAGIC) {
+            return ax_err!(Unsupported, "gzip-compressed ELF image");
+        }
+        if written + out.len() > size {
+            return Err(bad_gzip());
+        }
+        mem.write(load_gpa + written, out)?;
+        written += out.len();
+        match res.status {
+            Ok(MZStatus::StreamEnd) => break,
+            // More input is needed, or more output is pending.
+            Ok(_) | Err(MZError::Buf) if !eof || !out.is_empty() => {}
+            _ => return Err(bad_gzip()),
+        }
+    }
+    if written != size {
+        return Err(bad_gzip());
+    }
+    Ok(())
+}
+
+/// Loads the `PT_LOAD` segments of the ELF image at their physical addresses,
+/// with the permissions of the segments. The memory past the file content of
+/// a segment, i.e., its BSS, is zeroed.
+fn load_elf(file: File, config: &VmConfig, aspace: &mut AddrSpace) -> AxResult<usize> {
+    let bad_elf = |_| ax_err_type!(InvalidData, "bad ELF image");
+    // The headers are read in small pieces.
+    let mut file = BufReader::new(file);
+
+    let mut buf = [0u8; 64];
+    file.read_exact(&mut buf).map_err(io_err)?;
+    let ehdr = ElfBytes::<AnyEndian>::parse_elf_header(&buf[..]).map_err(bad_elf)?;
+    let entsize =
+        ProgramHeader::validate_entsize(ehdr.class, ehdr.e_phentsize as usize).map_err(bad_elf)?;
+    let mut buf = vec![0u8; entsize * ehdr.e_phnum as usize];
+    file.seek(SeekFrom::Start(ehdr.e_phoff)).map_err(io_err)?;
+    file.read_exact(&mut buf).map_err(io_err)?;
+    let phdrs: Vec<ProgramHeader> =
+        elf::segment::SegmentTable::new(ehdr.endianness, ehdr.class, &buf[..])
+            .iter()
+            .filter(|phdr| phdr.p_type == PT_LOAD && phdr.p_memsz > 0)
+            .collect();
+    if phdrs.is_empty() {
+        return ax_err!(InvalidData, "no segment in the ELF image");
+    }
+
+    // The permissions of each page, the union of the ones of the segments
+    // sharing it. G-stage mappings are user mappings.
+    let mut pages = BTreeMap::new();
+    for phdr in &phdrs {
+        let mut flags = MappingFlags::USER;
+        if phdr.p_flags & PF_R != 0 {
+            flags |= MappingFlags::READ;
+        }
+        if phdr.p_flags & PF_W != 0 {
+            flags |= MappingFlags::WRITE;
+        }
+        if phdr.p_flags & PF_X != 0 {
+            flags |= MappingFlags::EXECUTE;
+        }
+        let start = (phdr.p_paddr as usize).align_down_4k();
+        let end = (phdr.p_paddr as usize)
+            .checked_add(phdr.p_memsz as usize)
+            .ok_or_else(|| ax_err_type!(InvalidData, "bad ELF segment"))?
+            .align_up_4k();
+        for page in (start..end).step_by(PAGE_SIZE_4K) {
+            *pages.entry(page).or_insert(MappingFlags::empty()) |= flags;
+        }
+    }
+    let ram = config.mem_base..config.mem_base + config.mem_size;
+    let mut pages = pages.into_iter().peekable();
+    while let Some((start, flags)) = pages.next() {
+        // Runs of contiguous pages with the same permissions.
+        let mut end = start + PAGE_SIZE_4K;
+        while pages
+            .next_if(|&(page, f)| page == end && f == flags)
+            .is_some()
+        {
+            end += PAGE_SIZE_4K;
+        }
+        if ram.contains(&start) && ram.contains(&(end - 1)) {
+            populate_ram(aspace, start.into(), end - start)?;
+            aspace.protect(start.into(), end - start, flags)?;
+        } else {
+            aspace.map_alloc(start.into(), end - start, flags, true)?;
+        }
+    }
+
+    for phdr in &phdrs {
+        if phdr.p_filesz > phdr.p_memsz {
+            return ax_err!(InvalidData, "bad ELF segment");
+        }
+        let mut data = vec![0u8; phdr.p_memsz as usize];
+        file.seek(SeekFrom::Start(phdr.p_offset)).map_err(io_err)?;
+        file.read_exact(&mut data[..phdr.p_filesz as usize])
+            .map_err(io_err)?;
+        GuestMemory::new(aspace).write(VirtAddr::from(phdr.p_paddr as usize), &data)?;
+    }
+    Ok(ehdr.e_entry as usize)
+}
+
+fn open_image_file(file_name: &str) -> AxResult<(File, usize)> {
+    let file = File::open(file_name).map_err(|err| {
+        ax_err_type!(
+            NotFound,
+            format!(
+                "Failed to open {}, err {:?}, please check your disk.img",
+                file_name, err
+            )
+        )
+    })?;
+    let file_size = file
+        .metadata()
+        .map_err(|err| {
+            ax_err_type!(
+                Io,
+                format!(
+                    "Failed to get metadate of file {}, err {:?}",
+                    file_name, err
+                )
+            )
+        })?
+        .size() as usize;
+    Ok((file, file_size))
+}
@@ -0,0 +1,42 @@
+//! The VM and vCPU of the hypervisor tasks in the log.
+//!
+//! The tasks working for a VM are named after it, e.g. `vm1.cpu0` for vCPU 0
+//! of VM 1, or `vm1.balloon`, and their log lines start with their name, e.g.
+//! `[vm1.cpu0]`. The exits of a VM can be traced too, see
+//! `Vm::set_trace_exits`: each is logged at the trace level, whatever the log
+//! level of the build, so debugging a guest does not flood the console with
+//! the exits of the others.
+
+use alloc::string::String;
+use core::fmt;
+
+/// Logs at the trace level, even if the build leaves this level out.
+macro_rules! force_trace {
+    ($($arg:tt)+) => {
+        log::logger().log(
+            &log::Record::builder()
+                .args(format_args!($($arg)+))
+                .level(log::Level::Trace)
+                .target(module_path!())
+                .line(Some(line!()))
+                .build(),
+        )
+    };
+}
+
+/// Tags the log lines of the tasks working for a VM.
+pub fn init() {
+    axlog::set_context_fn(write_context);
+}
+
+/// The name of a task working for VM `vm_id`, e.g. `vm1.cpu0` for `cpu0`.
+pub fn task_name(vm_id: usize, what: fmt::Arguments) -> String {
+    format!("vm{}.{}", vm_id, what)
+}
+
+fn write_context(f: &mut fmt::Formatter) -> fmt::Result {
+    match axtask::current_may_uninit() {
+        Some(curr) if curr.name().starts_with("vm") => write!(f, "[{}] ", curr.name()),
+        _ => Ok(()),
+    }
+}
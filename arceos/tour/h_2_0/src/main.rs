@@ -1,3 +1,109 @@
+//! A hypervisor running VMs with a vCPU per physical hart.
+//!
+//! Each vCPU is run by its own task. Once started, the task never yields, so
+//! it stays on the hart it was started on. Secondary vCPUs are started by the
+//! guest with SBI HSM `hart_start`, and IPIs between them are delivered with
+//! SBI `send_ipi`.
+//!
+//! VM `n` is described by the configuration file `/etc/vm<n>.toml` of the
+//! disk image, see the `config` module for its format. Without one, the VM
+//! runs the default guest image, or `AX_VM_IMAGE` if it is set at build time,
+//! e.g. to run the SMP demo guest:
+//!
+//! ```sh
+//! make payload && ./update_disk.sh payload/smp_guest/smp_guest
+//! AX_VM_IMAGE=/sbin/smp_guest make run A=tour/h_2_0 BLK=y SMP=4
+//! ```
+//!
+//! `AX_VM_COUNT` VMs are run at once, 1 by default, each with its own guest
+//! memory and vCPU tasks. The harts are shared out between them: each VM has
+//! at most its share of the harts as vCPUs. Without a configuration file, it
+//! has all of them, or `AX_VM_VCPUS` if it is set to fewer. A vCPU waiting
+//! in `wfi` leaves its hart to the other tasks until it gets an interrupt.
+//! If `AX_VM_MAX_VCPUS` is set to more vCPUs, within the share of the VM,
+//! the extra ones are absent at boot, and can be added from the monitor.
+//!
+//! The guest console is a NS16550 UART at `0x1000_0000`, connected to the
+//! host console, which all VMs share. Its interrupt, and the ones of the
+//! virtio devices below, go through a virtual PLIC at `0x0c00_0000`.
+//!
+//! If `AX_VM_AIA=y`, the VMs get the AIA instead of the PLIC: an APLIC at
+//! `0x0d00_0000` forwarding the interrupts of the devices as MSIs to an
+//! IMSIC at `0x2800_0000`, as QEMU virt with `aia=aplic-imsic`. The host
+//! needs the AIA too, the guest accesses to the IMSIC being emulated.
+//!
+//! The guest counts the cycles, the instructions and the firmware events with
+//! the SBI PMU extension, or only the ones listed in `AX_VM_PMU_EVENTS`, e.g.
+//! `cycles,firmware`, and gets no PMU if it is empty.
+//!
+//! The guest reads the wall time of the host from a goldfish RTC at
+//! `0x10_1000`, as on QEMU virt.
+//!
+//! Without a configuration file, if `AX_VM_INITRD` is set to a file of the
+//! disk image, the VMs get it as initrd, whose location is given in the
+//! `/chosen` node of their device tree.
+//!
+//! Without a configuration file, if `AX_VM_DISK` is set to a file of the
+//! disk image, e.g. `/vm_disk.img`, the first VM gets it as a virtio-blk
+//! device at `0x1000_1000`.
+//!
+//! If `AX_VM_PFLASH` is set to a file of the disk image, the first VM gets a
+//! pflash backed by it at `0x2200_0000`, the pflash#2 of QEMU virt.
+//! Otherwise, the pflash#2 of the host is passed through.
+//!
+//! If `AX_VM_BALLOON` is set to a number of pages, the first VM gets a
+//! virtio-balloon device at `0x1000_3000`, asking the guest to give them
+//! back to the host.
+//!
+//! If `AX_VM_BLK_PASSTHROUGH` is set to the base of a virtio-mmio slot of the
+//! host holding a virtio-blk device, e.g. `0x1000_8000`, the first VM gets
+//! the device itself at the same address, its DMA going through bounce
+//! buffers of the host. The slot must be listed in `AX_VIRTIO_MMIO_RESERVED`
+//! too, so the host does not use it.
+//!
+//! Likewise, with the `vnet` feature and `AX_VM_NET_PEER` set to a UDP
+//! address, e.g. `10.0.2.2:5555`, the first VM gets a virtio-net device at
+//! `0x1000_2000`. Its frames are exchanged with the peer in UDP datagrams,
+//! received on the same port.
+//!
+//! The boot vCPU gets the address of a device tree describing the VM in `a1`.
+//!
+//! A guest powering off with SBI SRST frees its VM, and one rebooting starts
+//! over from its image.
+//!
+//! If `AX_VM_EXIT_STATS` is set to a number of seconds, the VM exit
+//! statistics of each VM are logged with this period, and when it shuts down.
+//!
+//! The log lines of the tasks working for a VM are tagged with it, and its
+//! vCPU if any, e.g. `[vm1.cpu0]`. The monitor can log each exit of a VM at
+//! runtime, see the `log_ctx` module.
+//!
+//! A guest the hypervisor cannot run any further, e.g. trapping with an
+//! unexpected cause, gets its state dumped on the console and its VM shut
+//! down. Without a configuration file, if `AX_VM_CRASH_DUMP` is set to a
+//! directory of the disk image, created if missing, the dump of VM `n` is
+//! written to `vm<n>.crash` in it too.
+//!
+//! Without a configuration file, if `AX_VM_CPU_QUOTA` is set to a number of
+//! milliseconds, optionally followed by `/` and a period in milliseconds,
+//! e.g. `20/100`, each vCPU runs the guest at most this long per period, 100
+//! ms by default, then leaves its hart to the host tasks until the next one.
+//!
+//! If `AX_VM_RECORD` is set to a file of the disk image, the VM exits of the
+//! first VM are recorded to it, and if `AX_VM_REPLAY` is, they are replayed
+//! from it, to reproduce a guest bug, see the `exit_log` module.
+//!
+//! Typing `Ctrl-A c` on the host console switches to a monitor, to inspect
+//! and control the VMs, see the `monitor` module.
+//!
+//! With the `gdb` feature and `AX_VM_GDB_PORT` set to a TCP port, e.g. `1234`,
+//! a GDB remote stub for the first VM listens on it once the VMs booted.
+//!
+//! With the `migrate` feature and `AX_VM_MIGRATE_PORT` set to a TCP port, the
+//! first VM is not booted from its image but waits on the port for a VM
+//! migrated from another host, with the same configuration, which goes on
+//! running here. The `migrate` command of the monitor migrates one.
+
 #![no_std]
 #![no_main]
 
@@ -7,130 +113,192 @@ extern crate log;
 extern crate alloc;
 extern crate axstd as std;
 use alloc::string::ToString;
-use riscv_vcpu::AxVCpuExitReason;
-use axerrno::{ax_err_type, AxResult};
-use memory_addr::VirtAddr;
-use alloc::string::String;
-use std::fs::File;
-use riscv_vcpu::RISCVVCpu;
-use riscv_vcpu::AxVCpuExitReason::NestedPageFault;
+use alloc::vec::Vec;
+
+mod config;
+mod cpu_quota;
+mod crash;
+mod dirty_log;
+mod exit_log;
+mod exit_stats;
+#[cfg(feature = "gdb")]
+mod gdb;
+mod guest_mem;
+mod loader;
+#[macro_use]
+mod log_ctx;
+mod monitor;
+mod translate;
+mod vcpu;
+mod vdev;
+mod vm;
+mod vm_fdt;
+use vm::{Vm, VmConfig, VmExit, MAX_VCPUS};
 
-const VM_ASPACE_BASE: usize = 0x0;
-const VM_ASPACE_SIZE: usize = 0x7fff_ffff_f000;
 const PHY_MEM_START: usize = 0x8000_0000;
 const PHY_MEM_SIZE: usize = 0x100_0000;
 const KERNEL_BASE: usize = 0x8020_0000;
-
-use axmm::AddrSpace;
-use axhal::paging::MappingFlags;
+const VM_IMAGE: &str = match option_env!("AX_VM_IMAGE") {
+    Some(image) => image,
+    None => "/sbin/u_3_0_riscv64-qemu-virt.bin",
+};
+const VM_COUNT: Option<&str> = option_env!("AX_VM_COUNT");
+const VM_VCPUS: Option<&str> = option_env!("AX_VM_VCPUS");
+const VM_MAX_VCPUS: Option<&str> = option_env!("AX_VM_MAX_VCPUS");
+const VM_INITRD: Option<&str> = option_env!("AX_VM_INITRD");
+const VM_DISK: Option<&str> = option_env!("AX_VM_DISK");
+const VM_PFLASH: Option<&str> = option_env!("AX_VM_PFLASH");
+const VM_BALLOON: Option<&str> = option_env!("AX_VM_BALLOON");
+const VM_BLK_PASSTHROUGH: Option<&str> = option_env!("AX_VM_BLK_PASSTHROUGH");
+const VM_EXIT_STATS: Option<&str> = option_env!("AX_VM_EXIT_STATS");
+const VM_CRASH_DUMP: Option<&str> = option_env!("AX_VM_CRASH_DUMP");
+const VM_CPU_QUOTA: Option<&str> = option_env!("AX_VM_CPU_QUOTA");
+const VM_RECORD: Option<&str> = option_env!("AX_VM_RECORD");
+const VM_REPLAY: Option<&str> = option_env!("AX_VM_REPLAY");
+const VM_AIA: Option<&str> = option_env!("AX_VM_AIA");
+const VM_PMU_EVENTS: Option<&str> = option_env!("AX_VM_PMU_EVENTS");
+#[cfg(feature = "vnet")]
+const VM_NET_PEER: Option<&str> = option_env!("AX_VM_NET_PEER");
+#[cfg(feature = "gdb")]
+const VM_GDB_PORT: Option<&str> = option_env!("AX_VM_GDB_PORT");
+#[cfg(feature = "migrate")]
+const VM_MIGRATE_PORT: Option<&str> = option_env!("AX_VM_MIGRATE_PORT");
 
 #[no_mangle]
 fn main() {
+    log_ctx::init();
     info!("Starting virtualization...");
-    unsafe {
-        riscv_vcpu::setup_csrs();
-    }
-
-    // Setup AddressSpace and regions.
-    let mut aspace = AddrSpace::new_empty(VirtAddr::from(VM_ASPACE_BASE), VM_ASPACE_SIZE).unwrap();
 
-    // Physical memory region. Full access flags.
-    let mapping_flags = MappingFlags::from_bits(0xf).unwrap();
-    aspace.map_alloc(PHY_MEM_START.into(), PHY_MEM_SIZE, mapping_flags, true).unwrap();
+    let num_vms = VM_COUNT
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(1)
+        .min(axconfig::SMP);
+    // One vCPU per physical hart, as each running vCPU occupies its hart.
+    let max_vcpus = (axconfig::SMP / num_vms).min(MAX_VCPUS);
+    let num_vcpus = VM_VCPUS
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(max_vcpus);
 
-    // Load corresponding images for VM.
-    info!("VM created success, loading images...");
-    let image_fname = "/sbin/u_3_0_riscv64-qemu-virt.bin";
-    load_vm_image(image_fname.to_string(), KERNEL_BASE.into(), &aspace).expect("Failed to load VM images");
+    let vms: Vec<_> = (1..=num_vms)
+        .map(|id| {
+            let path = format!("/etc/vm{}.toml", id);
+            let mut config = if std::fs::metadata(&path).is_ok() {
+                info!("[VM {}] config {}", id, path);
+                VmConfig::load(&path).expect("Bad VM config")
+            } else {
+                default_config(id, num_vcpus)
+            };
+            config.num_vcpus = config.num_vcpus.min(max_vcpus);
+            config.max_vcpus = config.max_vcpus.clamp(config.num_vcpus, max_vcpus);
+            let vm = Vm::create(id, config).expect("Failed to create the VM");
+            info!("[VM {}] bsp_entry: {:#x}; ept: {:#x}; vmid: {}; vcpus: {}", id, vm.entry, vm.aspace.lock().page_table_root(), vm.vmid, vm.num_vcpus());
+            vm
+        })
+        .collect();
 
-    // Create VCpus.
-    let mut arch_vcpu = RISCVVCpu::init();
-
-    // Setup VCpus.
-    info!("bsp_entry: {:#x}; ept: {:#x}", KERNEL_BASE, aspace.page_table_root());
-    arch_vcpu.set_entry(KERNEL_BASE.into()).unwrap();
-    arch_vcpu.set_ept_root(aspace.page_table_root()).unwrap();
-
-    loop {
-        match vcpu_run(&mut arch_vcpu) {
-            Ok(exit_reason) => match exit_reason {
-                AxVCpuExitReason::Nothing => {},
-                NestedPageFault{addr, access_flags} => {
-                    debug!("addr {:#x} access {:#x}", addr, access_flags);
-                    assert_eq!(addr, 0x2200_0000.into(), "Now we ONLY handle pflash#2.");
-                    let mapping_flags = MappingFlags::from_bits(0xf).unwrap();
-                    // Passthrough-Mode
-                    let _ = aspace.map_linear(addr, addr.as_usize().into(), 4096, mapping_flags);
-
-                    /*
-                    // Emulator-Mode
-                    // Pretend to load file to fill buffer.
-                    let buf = "pfld";
-                    aspace.map_alloc(addr, 4096, mapping_flags, true);
-                    aspace.write(addr, buf.as_bytes());
-                    */
-                },
-                _ => {
-                    panic!("Unhandled VM-Exit: {:?}", exit_reason);
-                }
-            },
-            Err(err) => {
-                panic!("run VCpu get error {:?}", err);
+    #[cfg(feature = "migrate")]
+    if let Some(port) = VM_MIGRATE_PORT.and_then(|port| port.parse::<u16>().ok()) {
+        vms[0]
+            .receive_migration(port)
+            .expect("Failed to receive the migrated VM");
+    }
+    for vm in &vms {
+        vm.boot().expect("Failed to boot the VM");
+    }
+    {
+        let vms = vms.clone();
+        std::thread::spawn(move || monitor::serve(vms));
+    }
+    #[cfg(feature = "gdb")]
+    if let Some(port) = VM_GDB_PORT.and_then(|port| port.parse::<u16>().ok()) {
+        let vm = vms[0].clone();
+        std::thread::Builder::new()
+            .name(log_ctx::task_name(vm.id, format_args!("gdb")))
+            .spawn(move || gdb::serve(vm, port))
+            .expect("Failed to spawn the gdb stub");
+    }
+    let stats_period = VM_EXIT_STATS
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .filter(|&secs| secs > 0)
+        .map(core::time::Duration::from_secs);
+    if let Some(period) = stats_period {
+        let vms = vms.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(period);
+            let running: Vec<_> = vms
+                .iter()
+                .filter(|vm| vm.state() != vm::VmState::Shutdown)
+                .collect();
+            if running.is_empty() {
+                break;
+            }
+            for vm in running {
+                log_exit_stats(vm);
             }
+        });
+    }
+    for vm in &vms {
+        match vm.wait().unwrap() {
+            VmExit::Shutdown => info!("[VM {}] powered off by the guest", vm.id),
+            VmExit::Stopped => info!("[VM {}] shut down", vm.id),
+            VmExit::Crashed => warn!("[VM {}] crashed", vm.id),
+        }
+        if stats_period.is_some() {
+            log_exit_stats(vm);
         }
     }
 }
 
-fn load_vm_image(image_path: String, image_load_gpa: VirtAddr, aspace: &AddrSpace) -> AxResult {
-    use std::io::{BufReader, Read};
-    let (image_file, image_size) = open_image_file(image_path.as_str())?;
-
-    let image_load_regions = aspace
-        .translated_byte_buffer(image_load_gpa, image_size)
-        .expect("Failed to translate kernel image load address");
-    let mut file = BufReader::new(image_file);
-
-    for buffer in image_load_regions {
-        file.read_exact(buffer).map_err(|err| {
-            ax_err_type!(
-                Io,
-                format!("Failed in reading from file {}, err {:?}", image_path, err)
-            )
-        })?
+/// Logs the exit statistics of `vm`, summed over its vCPUs.
+fn log_exit_stats(vm: &Vm) {
+    let mut stats = exit_stats::ExitStats::default();
+    for vcpu_stats in vm.exit_stats() {
+        stats.merge(&vcpu_stats);
     }
-
-    Ok(())
+    info!("[VM {}] {}", vm.id, stats);
 }
 
-fn vcpu_run(arch_vcpu: &mut RISCVVCpu) -> AxResult<AxVCpuExitReason> {
-    use axhal::arch::{local_irq_save_and_disable, local_irq_restore};
-    let flags = local_irq_save_and_disable();
-    let ret = arch_vcpu.run();
-    local_irq_restore(flags);
-    ret
-}
-
-fn open_image_file(file_name: &str) -> AxResult<(File, usize)> {
-    let file = File::open(file_name).map_err(|err| {
-        ax_err_type!(
-            NotFound,
-            format!(
-                "Failed to open {}, err {:?}, please check your disk.img",
-                file_name, err
-            )
-        )
-    })?;
-    let file_size = file
-        .metadata()
-        .map_err(|err| {
-            ax_err_type!(
-                Io,
-                format!(
-                    "Failed to get metadate of file {}, err {:?}",
-                    file_name, err
-                )
-            )
-        })?
-        .size() as usize;
-    Ok((file, file_size))
+/// The configuration of VM `id` without configuration file.
+fn default_config(id: usize, num_vcpus: usize) -> VmConfig {
+    // The host resources of the devices go to the first VM only.
+    let first = id == 1;
+    VmConfig {
+        mem_base: PHY_MEM_START,
+        mem_size: PHY_MEM_SIZE,
+        image: VM_IMAGE.to_string(),
+        initrd: VM_INITRD.map(|path| path.to_string()),
+        entry: KERNEL_BASE,
+        num_vcpus,
+        max_vcpus: VM_MAX_VCPUS
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(num_vcpus),
+        disk: VM_DISK.filter(|_| first).map(|path| path.to_string()),
+        pflash: VM_PFLASH.filter(|_| first).map(|path| path.to_string()),
+        pflash_base: vdev::pflash::PFLASH_BASE,
+        balloon: VM_BALLOON
+            .filter(|_| first)
+            .and_then(|pages| pages.parse().ok()),
+        blk_passthrough: VM_BLK_PASSTHROUGH.filter(|_| first).and_then(|base| {
+            let base = base.replace('_', "");
+            usize::from_str_radix(base.trim_start_matches("0x"), 16).ok()
+        }),
+        #[cfg(feature = "vnet")]
+        net_peer: VM_NET_PEER.filter(|_| first).map(|peer| peer.to_string()),
+        aia: VM_AIA.is_some_and(|aia| aia == "y"),
+        pmu_events: VM_PMU_EVENTS
+            .and_then(config::parse_pmu_events)
+            .unwrap_or(riscv_vcpu::PmuEvents::ALL),
+        record: VM_RECORD.filter(|_| first).map(|path| path.to_string()),
+        replay: VM_REPLAY.filter(|_| first).map(|path| path.to_string()),
+        crash_dump: VM_CRASH_DUMP.map(|dir| format!("{}/vm{}.crash", dir, id)),
+        cpu_quota: VM_CPU_QUOTA.and_then(|quota| {
+            let (budget, period) = match quota.split_once('/') {
+                Some((budget, period)) => (budget, period.parse().ok()?),
+                None => (quota, cpu_quota::DEFAULT_PERIOD.as_millis() as u64),
+            };
+            cpu_quota::CpuQuota::from_millis(budget.parse().ok()?, period)
+        }),
+    }
 }
@@ -0,0 +1,313 @@
+//! A monitor over the host console, for interactive debugging of the guests.
+//!
+//! Typing `Ctrl-A c` switches the host console input from the guests to the
+//! monitor, and back. `Ctrl-A Ctrl-A` sends a `Ctrl-A` to the guest. The
+//! commands act on the selected VM, the first one at first:
+//!
+//! - `help`: lists the commands.
+//! - `vm N`: selects VM `N`.
+//! - `info registers`: the registers of the started vCPUs.
+//! - `info mem`: the guest RAM, and how much of it is allocated.
+//! - `x GPA`, `xs GPA`: the bytes, or the string, at a guest physical
+//!   address.
+//! - `pause`, `resume`: pauses or resumes the VM.
+//! - `step N`: runs vCPU `N` of the paused VM for an instruction, and prints
+//!   its registers.
+//! - `trace-exits on|off`: logs each VM exit, at the trace level.
+//! - `inject-irq N`: raises the source `N` of the virtual PLIC, or APLIC.
+//! - `migrate IP:PORT`: migrates the running VM to the host receiving it
+//!   there, with the `migrate` feature.
+//! - `vcpu-add N`, `vcpu-remove N`: plugs vCPU `N` in, or out once the guest
+//!   stopped it.
+//! - `quit`: shuts all the VMs down.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use axerrno::{ax_err, ax_err_type, AxResult};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use memory_addr::{VirtAddr, PAGE_SIZE_4K};
+use riscv_vcpu::VCpuState;
+use std::thread;
+
+use crate::guest_mem::GuestMemory;
+use crate::vdev::plic::NUM_SOURCES;
+use crate::vm::{Vm, VmState};
+
+const CTRL_A: u8 = 0x01;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// The number of bytes `x` dumps.
+const DUMP_SIZE: usize = 64;
+/// The longest string `xs` reads.
+const MAX_STR_LEN: usize = 256;
+
+/// The period the monitor polls the host console.
+const POLL_PERIOD: Duration = Duration::from_millis(10);
+
+/// The host console input goes to the monitor.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// `Ctrl-A` was typed for a guest, the next byte is the escaped one.
+static ESCAPE: AtomicBool = AtomicBool::new(false);
+
+/// The ABI names of the guest GPRs.
+pub const GPR_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const HELP: &str = "\
+help               this list
+vm N               select VM N
+info registers     the registers of the vCPUs
+info mem           the guest RAM
+x GPA              the bytes at GPA
+xs GPA             the string at GPA
+pause              pause the VM
+resume             resume the VM
+step N             step the vCPU N of the paused VM
+trace-exits on|off log each VM exit
+inject-irq N       raise the source N of the PLIC
+migrate IP:PORT    migrate the VM to another host
+vcpu-add N         add the vCPU N
+vcpu-remove N      remove the stopped vCPU N
+quit               shut all the VMs down
+Ctrl-A c           back to the guest console";
+
+/// Whether the host console input goes to the monitor, rather than to the
+/// guests.
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Filters `byte`, typed on the host console for a guest: returns the byte
+/// the guest receives, if any.
+pub fn filter_input(byte: u8) -> Option<u8> {
+    if !ESCAPE.swap(false, Ordering::AcqRel) {
+        if byte == CTRL_A {
+            ESCAPE.store(true, Ordering::Release);
+            return None;
+        }
+        return Some(byte);
+    }
+    match byte {
+        b'c' => {
+            ACTIVE.store(true, Ordering::Release);
+            None
+        }
+        CTRL_A => Some(CTRL_A),
+        _ => None,
+    }
+}
+
+/// Runs the monitor of `vms`, reading the host console while it is active.
+pub fn serve(vms: Vec<Arc<Vm>>) {
+    let mut selected = 0;
+    let mut line = String::new();
+    let mut prompted = false;
+    let mut escape = false;
+    loop {
+        if !active() {
+            thread::sleep(POLL_PERIOD);
+            continue;
+        }
+        if !prompted {
+            std::print!("(monitor VM {}) ", vms[selected].id);
+            prompted = true;
+        }
+        let Some(byte) = axhal::console::getchar() else {
+            thread::sleep(POLL_PERIOD);
+            continue;
+        };
+        if escape {
+            escape = false;
+            if byte == b'c' {
+                std::println!();
+                line.clear();
+                prompted = false;
+                ACTIVE.store(false, Ordering::Release);
+            }
+            continue;
+        }
+        match byte {
+            CTRL_A => escape = true,
+            b'\r' | b'\n' => {
+                std::println!();
+                let cmd = core::mem::take(&mut line);
+                if let Err(err) = run_command(&vms, &mut selected, cmd.trim()) {
+                    std::println!("error: {}", err);
+                }
+                prompted = false;
+            }
+            BACKSPACE | DELETE => {
+                if line.pop().is_some() {
+                    std::print!("\x08 \x08");
+                }
+            }
+            b' '..=b'~' => {
+                line.push(byte as char);
+                std::print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_number(arg: Option<&str>) -> AxResult<usize> {
+    let arg = arg.ok_or_else(|| ax_err_type!(InvalidInput, "missing number"))?;
+    let num = match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    num.map_err(|_| ax_err_type!(InvalidInput, "bad number"))
+}
+
+fn run_command(vms: &[Arc<Vm>], selected: &mut usize, cmd: &str) -> AxResult {
+    let vm = &vms[*selected];
+    let mut args = cmd.split_whitespace();
+    match args.next() {
+        None => {}
+        Some("help") => std::println!("{}", HELP),
+        Some("vm") => {
+            let id = parse_number(args.next())?;
+            *selected = vms
+                .iter()
+                .position(|vm| vm.id == id)
+                .ok_or_else(|| ax_err_type!(NotFound, "no such VM"))?;
+        }
+        Some("info") => match args.next() {
+            Some("registers") => info_registers(vm)?,
+            Some("mem") => info_mem(vm),
+            _ => return ax_err!(InvalidInput, "info registers or info mem"),
+        },
+        Some("x") => {
+            let gpa = parse_number(args.next())?;
+            let mut buf = [0u8; DUMP_SIZE];
+            GuestMemory::new(&vm.aspace.lock()).read(gpa.into(), &mut buf)?;
+            for (i, row) in buf.chunks(16).enumerate() {
+                let mut line = format!("{:#x}:", gpa + i * 16);
+                for byte in row {
+                    line += &format!(" {:02x}", byte);
+                }
+                std::println!("{}", line);
+            }
+        }
+        Some("xs") => {
+            let gpa = parse_number(args.next())?;
+            let s = GuestMemory::new(&vm.aspace.lock()).read_cstr(gpa.into(), MAX_STR_LEN)?;
+            std::println!("{:?}", s);
+        }
+        Some("pause") => vm.pause()?,
+        Some("resume") => vm.resume()?,
+        Some("step") => {
+            let id = parse_number(args.next())?;
+            vm.step_vcpu(id)?;
+            match vm.paused_vcpu_state(id) {
+                Some(state) => print_vcpu_state(id, &state),
+                None => std::println!("vCPU {}: stopped", id),
+            }
+        }
+        Some("trace-exits") => match args.next() {
+            Some("on") => vm.set_trace_exits(true),
+            Some("off") => vm.set_trace_exits(false),
+            _ => return ax_err!(InvalidInput, "trace-exits on or off"),
+        },
+        Some("inject-irq") => {
+            let irq = parse_number(args.next())?;
+            if !(1..NUM_SOURCES).contains(&irq) {
+                return ax_err!(InvalidInput, "no such PLIC source");
+            }
+            vm.devices().raise_irq(irq);
+        }
+        #[cfg(feature = "migrate")]
+        Some("migrate") => {
+            let addr = args
+                .next()
+                .and_then(|addr| addr.parse().ok())
+                .ok_or_else(|| ax_err_type!(InvalidInput, "bad address"))?;
+            vm.migrate_to(addr)?;
+        }
+        Some("vcpu-add") => vm.add_vcpu(parse_number(args.next())?)?,
+        Some("vcpu-remove") => vm.remove_vcpu(parse_number(args.next())?)?,
+        Some("quit") => {
+            for vm in vms {
+                let _ = vm.shutdown();
+            }
+            ACTIVE.store(false, Ordering::Release);
+        }
+        Some(_) => return ax_err!(InvalidInput, "unknown command, see help"),
+    }
+    Ok(())
+}
+
+/// Prints the registers of the vCPUs of `vm`, pausing it meanwhile if it
+/// runs.
+fn info_registers(vm: &Vm) -> AxResult {
+    let running = vm.state() == VmState::Running;
+    if running {
+        vm.pause()?;
+    }
+    for id in 0..vm.num_vcpus() {
+        match vm.paused_vcpu_state(id) {
+            Some(state) => print_vcpu_state(id, &state),
+            None if vm.vcpu_present(id) => std::println!("vCPU {}: stopped", id),
+            None => std::println!("vCPU {}: absent", id),
+        }
+    }
+    if running {
+        vm.resume()?;
+    }
+    Ok(())
+}
+
+fn print_vcpu_state(id: usize, state: &VCpuState) {
+    std::println!(
+        "vCPU {}: pc {:#018x} sstatus {:#x} vsstatus {:#x} vsatp {:#x}",
+        id,
+        state.sepc,
+        state.sstatus,
+        state.vsstatus,
+        state.vsatp
+    );
+    std::println!(
+        "  vstvec {:#x} vsepc {:#x} vscause {:#x} vstval {:#x} hvip {:#x} time {:#x}",
+        state.vstvec,
+        state.vsepc,
+        state.vscause,
+        state.vstval,
+        state.hvip,
+        state.time
+    );
+    for (names, regs) in GPR_NAMES.chunks(4).zip(state.gprs.chunks(4)) {
+        let mut row = String::new();
+        for (name, reg) in names.iter().zip(regs) {
+            row += &format!("  {:>4} {:#018x}", name, reg);
+        }
+        std::println!("{}", row);
+    }
+}
+
+fn info_mem(vm: &Vm) {
+    let base = vm.config.mem_base;
+    let size = vm.config.mem_size;
+    let aspace = vm.aspace.lock();
+    let allocated = (base..base + size)
+        .step_by(PAGE_SIZE_4K)
+        .filter(|&gpa| {
+            matches!(aspace.page_table().query(VirtAddr::from(gpa)),
+                Ok((_, flags, _)) if !flags.is_empty())
+        })
+        .count();
+    std::println!(
+        "RAM {:#x}-{:#x}: {} of {} pages allocated",
+        base,
+        base + size,
+        allocated,
+        size / PAGE_SIZE_4K
+    );
+    std::println!("G-stage root: {:#x}", aspace.page_table_root());
+    std::println!("device tree: {:#x}", vm.fdt_addr);
+}
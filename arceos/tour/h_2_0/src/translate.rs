@@ -0,0 +1,126 @@
+//! The translation of guest virtual addresses through both stages, to debug
+//! guests faulting with paging enabled.
+//!
+//! The VS-stage page table of the guest is walked first, reading its PTEs
+//! through the G-stage page table of the VM, then the G-stage page table
+//! itself. Each level of both walks is logged.
+
+use axerrno::{ax_err, AxResult};
+use axhal::mem::phys_to_virt;
+use memory_addr::{PhysAddr, VirtAddr, PAGE_SIZE_4K};
+
+use crate::vm::Vm;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_X: u64 = 1 << 3;
+const PTE_PPN_SHIFT: usize = 10;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+
+const SATP_MODE_SHIFT: usize = 60;
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+const SATP_MODE_BARE: usize = 0;
+const SATP_MODE_SV39: usize = 8;
+const SATP_MODE_SV48: usize = 9;
+const SATP_MODE_SV57: usize = 10;
+
+/// The bits of the virtual page number each level translates.
+const LEVEL_BITS: usize = 9;
+
+/// The G-stage is Sv39x4: Sv39 with 2 more address bits, indexing a 16K
+/// root table.
+const GSTAGE_LEVELS: usize = 3;
+const GSTAGE_EXTRA_BITS: usize = 2;
+const GSTAGE_ADDR_BITS: usize = 12 + GSTAGE_LEVELS * LEVEL_BITS + GSTAGE_EXTRA_BITS;
+
+/// Walks the page table at `root` for `addr`, with `levels` levels, the root
+/// one indexed with `extra_bits` more bits, and returns the translated
+/// address. `pte_addr` gives the host physical address of a PTE from its
+/// address in the page table.
+fn walk(
+    stage: &str,
+    root: usize,
+    addr: usize,
+    levels: usize,
+    extra_bits: usize,
+    pte_addr: impl Fn(usize) -> AxResult<PhysAddr>,
+) -> AxResult<usize> {
+    let mut table = root;
+    for level in (0..levels).rev() {
+        let shift = 12 + level * LEVEL_BITS;
+        let index_bits = if level == levels - 1 {
+            LEVEL_BITS + extra_bits
+        } else {
+            LEVEL_BITS
+        };
+        let index = (addr >> shift) & ((1 << index_bits) - 1);
+        let hpa = pte_addr(table + index * 8)?;
+        let pte = unsafe { (phys_to_virt(hpa).as_ptr() as *const u64).read_volatile() };
+        info!(
+            "  {} level {}: table {:#x}[{:#x}] at {:#x}: pte {:#x}",
+            stage, level, table, index, hpa, pte
+        );
+        if pte & PTE_V == 0 {
+            return ax_err!(BadAddress, "invalid PTE");
+        }
+        let ppn = ((pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) as usize;
+        if pte & (PTE_R | PTE_X) != 0 {
+            // A leaf, maybe a superpage.
+            return Ok(ppn * PAGE_SIZE_4K + (addr & ((1 << shift) - 1)));
+        }
+        table = ppn * PAGE_SIZE_4K;
+    }
+    ax_err!(BadAddress, "no leaf PTE")
+}
+
+/// Translates `gva` with the VS-stage page table of `vsatp`, then the
+/// G-stage page table of `vm`, logging each level of both walks. Returns the
+/// guest physical and host physical addresses.
+///
+/// `vsatp` is the one of the vCPU, e.g. in its [`riscv_vcpu::VCpuState`].
+pub fn translate_guest_addr(vm: &Vm, vsatp: usize, gva: usize) -> AxResult<(usize, PhysAddr)> {
+    // The page tables do not change meanwhile.
+    let aspace = vm.aspace.lock();
+    info!(
+        "[VM {}] translating {:#x} with vsatp {:#x}",
+        vm.id, gva, vsatp
+    );
+    let vs_levels = match vsatp >> SATP_MODE_SHIFT {
+        SATP_MODE_BARE => None,
+        SATP_MODE_SV39 => Some(3),
+        SATP_MODE_SV48 => Some(4),
+        SATP_MODE_SV57 => Some(5),
+        _ => return ax_err!(InvalidInput, "bad vsatp mode"),
+    };
+    let gpa = match vs_levels {
+        Some(levels) => {
+            let root = (vsatp & SATP_PPN_MASK) * PAGE_SIZE_4K;
+            walk("VS-stage", root, gva, levels, 0, |gpa| {
+                // The PTE pages are mapped once the guest touched them.
+                match aspace.page_table().query(VirtAddr::from(gpa)) {
+                    Ok((hpa, _, _)) => Ok(hpa),
+                    Err(_) => ax_err!(BadAddress, "VS-stage page table not mapped"),
+                }
+            })?
+        }
+        None => {
+            info!("  VS-stage: bare");
+            gva
+        }
+    };
+    info!("  gpa: {:#x}", gpa);
+    if gpa >> GSTAGE_ADDR_BITS != 0 {
+        return ax_err!(BadAddress, "gpa out of the G-stage");
+    }
+    let root = aspace.page_table_root().as_usize();
+    let hpa = walk(
+        "G-stage",
+        root,
+        gpa,
+        GSTAGE_LEVELS,
+        GSTAGE_EXTRA_BITS,
+        |hpa| Ok(PhysAddr::from(hpa)),
+    )?;
+    info!("  hpa: {:#x}", hpa);
+    Ok((gpa, PhysAddr::from(hpa)))
+}
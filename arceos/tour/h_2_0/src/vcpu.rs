@@ -0,0 +1,392 @@
+//! The tasks running the vCPUs of a VM.
+//!
+//! A vCPU executing `wfi` blocks its task until it gets an interrupt, so its
+//! hart runs other tasks meanwhile. The task may resume on another hart, the
+//! vCPU then moves with it. Likewise when the vCPU used its CPU quota, see
+//! the `cpu_quota` module.
+
+use alloc::sync::Arc;
+use axerrno::AxResult;
+use axhal::paging::MappingFlags;
+use axhal::time::{current_ticks, monotonic_time_nanos, ticks_to_nanos};
+use core::time::Duration;
+use memory_addr::VirtAddr;
+use riscv_vcpu::sbi::{SBI_ERR_ALREADY_AVAILABLE, SBI_ERR_INAVLID_PARAM};
+use riscv_vcpu::AxVCpuExitReason::{self, NestedPageFault};
+use riscv_vcpu::{GprIndex, IrqKind, MmioOp, RISCVVCpu, VCpuState};
+use std::thread;
+
+use crate::cpu_quota::QuotaAccount;
+use crate::crash::crash_vm;
+use crate::exit_log::ExitRegs;
+use crate::vdev::{self, Devices, MmioDevice};
+use crate::vm::Vm;
+
+/// The longest vCPU 0 waits in `wfi`, as it polls the host console input and
+/// the host interrupts of passthrough devices.
+const INPUT_POLL_PERIOD: Duration = Duration::from_millis(10);
+
+/// Runs vCPU `vcpu_id` of `vm` each time it is started, until the VM is shut
+/// down.
+pub fn vcpu_task(vm: Arc<Vm>, vcpu_id: usize) {
+    let devs = vm.devices().clone();
+    while let Some((entry, arg)) = vm.wait_for_start(vcpu_id) {
+        // The task keeps the current hart, whose CSRs are setup for this
+        // vCPU, unless it waits for an interrupt, see `wait_for_irq`.
+        let hart = axhal::cpu::this_cpu_id();
+        unsafe {
+            riscv_vcpu::setup_csrs();
+        }
+        if vm.vmid >> riscv_vcpu::vmid_bits() != 0 {
+            warn!(
+                "[VM {}] hart {} does not support VMID {}",
+                vm.id, hart, vm.vmid
+            );
+        }
+
+        // Create VCpus.
+        let mut arch_vcpu = RISCVVCpu::init();
+
+        // Setup VCpus.
+        arch_vcpu.set_entry(entry.into()).unwrap();
+        arch_vcpu.set_gpr_from_gpr_index(GprIndex::A0, vcpu_id);
+        arch_vcpu.set_gpr_from_gpr_index(GprIndex::A1, arg);
+        arch_vcpu.set_vmid(vm.vmid).unwrap();
+        arch_vcpu.set_counter_offset(vm.counter_base);
+        arch_vcpu.set_pmu_events(vm.config.pmu_events);
+        arch_vcpu
+            .set_ept_root(vm.aspace.lock().page_table_root())
+            .unwrap();
+        vm.set_running(vcpu_id, hart);
+        if let Some(passthrough) = devs.passthrough.as_ref().filter(|_| vcpu_id == 0) {
+            passthrough.bind_hart(hart);
+        }
+        if let Some(state) = vm.take_restored_state(vcpu_id) {
+            arch_vcpu.restore_state(&state);
+        }
+        // The same guest time for all the vCPUs.
+        arch_vcpu.set_time_delta(vm.time_delta());
+        info!(
+            "[VM {}] vCPU {} runs on hart {}, entry: {:#x}",
+            vm.id, vcpu_id, hart, entry
+        );
+
+        run_vcpu(&vm, &devs, vcpu_id, &mut arch_vcpu);
+
+        let hart = axhal::cpu::this_cpu_id();
+        info!("[VM {}] vCPU {} stopped on hart {}", vm.id, vcpu_id, hart);
+        arch_vcpu.clear_irq(IrqKind::Software);
+        vm.set_stopped(vcpu_id);
+    }
+}
+
+/// Runs the vCPU until it stops itself, or the VM is shut down.
+fn run_vcpu(vm: &Vm, devs: &Devices, vcpu_id: usize, arch_vcpu: &mut RISCVVCpu) {
+    let mut breakpoint_exits = false;
+    let mut quota = vm
+        .config
+        .cpu_quota
+        .map(|quota| QuotaAccount::new(quota, monotonic_time_nanos()));
+    while vm.vcpu_may_run(vcpu_id, arch_vcpu) {
+        if vm.debugging() != breakpoint_exits {
+            breakpoint_exits = !breakpoint_exits;
+            arch_vcpu.set_breakpoint_exits(breakpoint_exits);
+        }
+        vm.sync_ept(vcpu_id, || arch_vcpu.flush_ept());
+        if vm.take_ipi(vcpu_id) {
+            arch_vcpu.inject_irq(IrqKind::Software);
+        }
+        if let Some(deadline) = devs.clint.take_timer(vcpu_id) {
+            arch_vcpu.set_guest_timer(deadline as usize);
+        }
+        // The devices are kicking vCPU 0 when their interrupts change, so it
+        // updates the PLIC, which kicks the vCPUs it sends them to.
+        if vcpu_id == 0 {
+            devs.uart.poll_input();
+        }
+        devs.sync_irqs();
+        if devs.irq_pending(vcpu_id) {
+            arch_vcpu.inject_irq(IrqKind::External);
+        } else {
+            arch_vcpu.clear_irq(IrqKind::External);
+        }
+        let entered = monotonic_time_nanos();
+        let exit_reason = match vcpu_run(arch_vcpu) {
+            Ok(exit_reason) => exit_reason,
+            Err(err) => {
+                crash_vm(
+                    vm,
+                    vcpu_id,
+                    arch_vcpu,
+                    &format!("run error {:?}", err),
+                    None,
+                );
+                return;
+            }
+        };
+        let exited = monotonic_time_nanos();
+        let reason = exit_reason_name(&exit_reason);
+        let pc = arch_vcpu.regs().guest_regs.sepc;
+        let csr = arch_vcpu.trapped_csr();
+        let stopped = matches!(exit_reason, AxVCpuExitReason::CpuDown);
+        // A step ends with the first exit the guest made progress, or not, for.
+        let stepped = arch_vcpu.single_step() && !matches!(exit_reason, AxVCpuExitReason::Nothing);
+        // The exit is checked against the log before being handled.
+        let log = vm.exit_log().map(|log| (log, ExitRegs::of(arch_vcpu)));
+        let replayed = log
+            .as_ref()
+            .and_then(|(log, regs)| log.replay_exit(vm.id, vcpu_id, reason, regs));
+        let mut read = None;
+        match exit_reason {
+            AxVCpuExitReason::Nothing => {}
+            NestedPageFault { addr, access_flags }
+                if !vm.is_ram(addr) && devs.find(addr.as_usize()).is_some() =>
+            {
+                let (dev, base) = devs.find(addr.as_usize()).unwrap();
+                let offset = addr.as_usize() - base;
+                let mapped = if access_flags.contains(MappingFlags::WRITE) {
+                    Ok(false)
+                } else {
+                    dev.map_page(&mut vm.aspace.lock(), offset)
+                };
+                let res = mapped.and_then(|mapped| {
+                    if mapped {
+                        arch_vcpu.flush_ept();
+                        Ok(None)
+                    } else {
+                        emulate_mmio(arch_vcpu, dev, addr, base, replayed)
+                    }
+                });
+                match res {
+                    Ok(val) => read = val,
+                    Err(err) => {
+                        let reason = format!("bad device access at {:#x}: {:?}", addr, err);
+                        crash_vm(vm, vcpu_id, arch_vcpu, &reason, Some(addr.as_usize()));
+                        return;
+                    }
+                }
+            }
+            NestedPageFault { addr, access_flags } => {
+                debug!("[VM {}] addr {:#x} access {:#x}", vm.id, addr, access_flags);
+                if let Err(err) = vm.map_on_fault(addr, access_flags) {
+                    let reason = format!("bad access at {:#x}: {:?}", addr, err);
+                    crash_vm(vm, vcpu_id, arch_vcpu, &reason, Some(addr.as_usize()));
+                    return;
+                }
+                arch_vcpu.flush_ept();
+            }
+            AxVCpuExitReason::CpuUp {
+                target_cpu,
+                entry_point,
+                arg,
+            } => {
+                debug!(
+                    "[VM {}] vCPU {} starts vCPU {} at {:#x}",
+                    vm.id, vcpu_id, target_cpu, entry_point
+                );
+                if let Err(err) = vm.start_vcpu(target_cpu, entry_point.as_usize(), arg) {
+                    let sbi_err = match err {
+                        axerrno::AxError::AlreadyExists => SBI_ERR_ALREADY_AVAILABLE,
+                        _ => SBI_ERR_INAVLID_PARAM,
+                    };
+                    arch_vcpu.set_gpr_from_gpr_index(GprIndex::A0, sbi_err as usize);
+                }
+            }
+            AxVCpuExitReason::CpuStatus { target_cpu } => match vm.vcpu_status(target_cpu) {
+                Ok(status) => arch_vcpu.set_gpr_from_gpr_index(GprIndex::A1, status),
+                Err(_) => {
+                    arch_vcpu.set_gpr_from_gpr_index(GprIndex::A0, SBI_ERR_INAVLID_PARAM as usize)
+                }
+            },
+            AxVCpuExitReason::CpuDown => {}
+            AxVCpuExitReason::Halt => {
+                wait_for_irq(vm, devs, vcpu_id, arch_vcpu);
+                // The CSRs of the hart are setup again.
+                breakpoint_exits = false;
+            }
+            AxVCpuExitReason::SystemDown => {
+                info!("[VM {}] vCPU {} powers the VM off", vm.id, vcpu_id);
+                vm.guest_reset(false);
+            }
+            AxVCpuExitReason::SystemReset => {
+                info!("[VM {}] vCPU {} reboots the VM", vm.id, vcpu_id);
+                vm.guest_reset(true);
+            }
+            // The interrupt of a passthrough device, raised in the PLIC above.
+            AxVCpuExitReason::ExternalInterrupt { .. } => vdev::passthrough::handle_host_irq(),
+            AxVCpuExitReason::Breakpoint { pc } => {
+                debug!("[VM {}] vCPU {} at breakpoint {:#x}", vm.id, vcpu_id, pc);
+                vm.stop_at_breakpoint(vcpu_id);
+            }
+            AxVCpuExitReason::SingleStep { pc } => {
+                debug!("[VM {}] vCPU {} stepped to {:#x}", vm.id, vcpu_id, pc);
+            }
+            AxVCpuExitReason::ImsicAccess { access } => {
+                let old = match &devs.imsic {
+                    Some(imsic) => imsic.access(vcpu_id, &access),
+                    None => 0,
+                };
+                arch_vcpu.complete_imsic_access(&access, old);
+            }
+            AxVCpuExitReason::SendIpi {
+                hart_mask,
+                hart_mask_base,
+            } => {
+                if vm.send_ipi(hart_mask, hart_mask_base).is_err() {
+                    arch_vcpu.set_gpr_from_gpr_index(GprIndex::A0, SBI_ERR_INAVLID_PARAM as usize);
+                }
+            }
+            _ => {
+                let reason = format!("unhandled VM exit {:?}", exit_reason);
+                crash_vm(vm, vcpu_id, arch_vcpu, &reason, None);
+                return;
+            }
+        }
+        let handled = monotonic_time_nanos();
+        if let Some((log, regs)) = &log {
+            if let Err(err) = log.record_exit(vcpu_id, reason, regs, read.unwrap_or(0) as usize) {
+                warn!("[VM {}] failed to record the exit: {:?}", vm.id, err);
+            }
+        }
+        vm.record_exit(vcpu_id, reason, csr, exited - entered, handled - exited);
+        if vm.trace_exits() {
+            force_trace!(
+                "{} exit at {:#x}, {} ns in the guest, {} ns to handle",
+                reason,
+                pc,
+                exited - entered,
+                handled - exited
+            );
+        }
+        if stepped {
+            arch_vcpu.set_single_step(false);
+            vm.end_step(vcpu_id);
+        }
+        if stopped {
+            return;
+        }
+        if let Some(throttled) = quota
+            .as_mut()
+            .and_then(|quota| quota.charge(handled, exited - entered))
+        {
+            debug!(
+                "[VM {}] vCPU {} throttled for {:?}",
+                vm.id, vcpu_id, throttled
+            );
+            leave_hart(vm, devs, vcpu_id, arch_vcpu, |_| thread::sleep(throttled));
+            breakpoint_exits = false;
+        }
+    }
+}
+
+/// Blocks the task of the vCPU, which executed `wfi`, until the vCPU is
+/// kicked or its timer fires.
+fn wait_for_irq(vm: &Vm, devs: &Devices, vcpu_id: usize, arch_vcpu: &mut RISCVVCpu) {
+    leave_hart(vm, devs, vcpu_id, arch_vcpu, |state| {
+        let mut timeout = (state.vstimecmp != 0 && state.vstimecmp != usize::MAX).then(|| {
+            let ticks = state.vstimecmp.saturating_sub(state.time);
+            Duration::from_nanos(ticks_to_nanos(ticks as u64))
+        });
+        if vcpu_id == 0 {
+            timeout = Some(timeout.map_or(INPUT_POLL_PERIOD, |t| t.min(INPUT_POLL_PERIOD)));
+        }
+        if timeout != Some(Duration::ZERO) {
+            vm.wait_for_kick(vcpu_id, timeout);
+        }
+    });
+}
+
+/// Blocks the task of the vCPU with `block`, then moves the vCPU to the hart
+/// the task resumes on.
+///
+/// Meanwhile other vCPUs may run on the hart, so the state of the vCPU is
+/// saved and restored, with the guest time going on.
+fn leave_hart(
+    vm: &Vm,
+    devs: &Devices,
+    vcpu_id: usize,
+    arch_vcpu: &mut RISCVVCpu,
+    block: impl FnOnce(&VCpuState),
+) {
+    let mut state = arch_vcpu.save_state();
+    let saved_at = current_ticks() as usize;
+    block(&state);
+
+    let hart = axhal::cpu::this_cpu_id();
+    unsafe {
+        riscv_vcpu::setup_csrs();
+    }
+    state.time = state.time.wrapping_add(current_ticks() as usize - saved_at);
+    arch_vcpu.restore_state(&state);
+    // Without Sstc the timer is a host one, which may have fired for the
+    // host meanwhile.
+    if !riscv_vcpu::has_sstc() && state.vstimecmp != 0 && state.time >= state.vstimecmp {
+        arch_vcpu.inject_irq(IrqKind::Timer);
+    }
+    arch_vcpu.flush_ept();
+    vm.set_running(vcpu_id, hart);
+    if let Some(passthrough) = devs.passthrough.as_ref().filter(|_| vcpu_id == 0) {
+        passthrough.bind_hart(hart);
+    }
+}
+
+/// The name of the kind of `exit_reason`, for the exit statistics.
+fn exit_reason_name(exit_reason: &AxVCpuExitReason) -> &'static str {
+    match exit_reason {
+        AxVCpuExitReason::Hypercall { .. } => "Hypercall",
+        AxVCpuExitReason::MmioRead { .. } => "MmioRead",
+        AxVCpuExitReason::MmioWrite { .. } => "MmioWrite",
+        AxVCpuExitReason::IoRead { .. } => "IoRead",
+        AxVCpuExitReason::IoWrite { .. } => "IoWrite",
+        AxVCpuExitReason::ExternalInterrupt { .. } => "ExternalInterrupt",
+        NestedPageFault { .. } => "NestedPageFault",
+        AxVCpuExitReason::CpuUp { .. } => "CpuUp",
+        AxVCpuExitReason::CpuStatus { .. } => "CpuStatus",
+        AxVCpuExitReason::SendIpi { .. } => "SendIpi",
+        AxVCpuExitReason::Breakpoint { .. } => "Breakpoint",
+        AxVCpuExitReason::SingleStep { .. } => "SingleStep",
+        AxVCpuExitReason::ImsicAccess { .. } => "ImsicAccess",
+        AxVCpuExitReason::Halt => "Halt",
+        AxVCpuExitReason::CpuDown => "CpuDown",
+        AxVCpuExitReason::SystemDown => "SystemDown",
+        AxVCpuExitReason::SystemReset => "SystemReset",
+        AxVCpuExitReason::UnhandledTrap { .. } => "UnhandledTrap",
+        AxVCpuExitReason::Nothing => "Nothing",
+        AxVCpuExitReason::FailEntry { .. } => "FailEntry",
+        _ => "Other",
+    }
+}
+
+/// Emulates the access of the guest that faulted at `addr` in `dev`, mapped at `base`, and
+/// returns the value read, if a read. A read gets `replayed`, if any, without reaching the
+/// device.
+fn emulate_mmio(
+    arch_vcpu: &mut RISCVVCpu,
+    dev: &dyn MmioDevice,
+    addr: VirtAddr,
+    base: usize,
+    replayed: Option<usize>,
+) -> AxResult<Option<u64>> {
+    let access = arch_vcpu.decode_mmio(addr)?;
+    let offset = addr.as_usize() - base;
+    let read = match access.op {
+        MmioOp::Read { .. } => Some(match replayed {
+            Some(val) => val as u64,
+            None => dev.read(offset, access.width)?,
+        }),
+        MmioOp::Write { data } => {
+            dev.write(offset, access.width, data)?;
+            None
+        }
+    };
+    arch_vcpu.complete_mmio(&access, read.unwrap_or(0));
+    Ok(read)
+}
+
+fn vcpu_run(arch_vcpu: &mut RISCVVCpu) -> AxResult<AxVCpuExitReason> {
+    use axhal::arch::{local_irq_restore, local_irq_save_and_disable};
+    let flags = local_irq_save_and_disable();
+    let ret = arch_vcpu.run();
+    local_irq_restore(flags);
+    ret
+}
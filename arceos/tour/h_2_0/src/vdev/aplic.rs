@@ -0,0 +1,320 @@
+//! A virtual APLIC in MSI delivery mode, at the address of the
+//! supervisor-level one of QEMU virt.
+//!
+//! It replaces the PLIC for the wired interrupts of the devices when the VM
+//! uses the AIA: a pending and enabled source is forwarded as an MSI to the
+//! IMSIC of the vCPU in its `target` register. It is a single supervisor
+//! domain, without child domains, so sources cannot be delegated.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use axerrno::AxResult;
+use riscv_vcpu::AccessWidth;
+use std::sync::{Mutex, MutexGuard};
+
+use super::imsic::VImsic;
+use super::plic::NUM_SOURCES;
+use super::MmioDevice;
+
+/// The guest physical address of the APLIC.
+pub const APLIC_BASE: usize = 0x0d00_0000;
+/// The size of the APLIC registers, without interrupt delivery controls in
+/// MSI delivery mode.
+pub const APLIC_SIZE: usize = 0x8000;
+
+const WORDS: usize = NUM_SOURCES.div_ceil(32);
+
+const DOMAINCFG: usize = 0x0;
+const SOURCECFG_BASE: usize = 0x4;
+const SOURCECFG_END: usize = 0x1000;
+const SETIP_BASE: usize = 0x1c00;
+const SETIPNUM: usize = 0x1cdc;
+const IN_CLRIP_BASE: usize = 0x1d00;
+const CLRIPNUM: usize = 0x1ddc;
+const SETIE_BASE: usize = 0x1e00;
+const SETIENUM: usize = 0x1edc;
+const CLRIE_BASE: usize = 0x1f00;
+const CLRIENUM: usize = 0x1fdc;
+const SETIPNUM_LE: usize = 0x2000;
+const SETIPNUM_BE: usize = 0x2004;
+const GENMSI: usize = 0x3000;
+const TARGET_BASE: usize = 0x3004;
+const TARGET_END: usize = 0x4000;
+
+/// `domaincfg` reads with its top byte set, and the MSI delivery mode.
+const DOMAINCFG_FIXED: u32 = 0x8000_0000 | DOMAINCFG_DM;
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+const SOURCECFG_D: u32 = 1 << 10;
+const SM_INACTIVE: u32 = 0;
+const SM_DETACHED: u32 = 1;
+const SM_EDGE1: u32 = 4;
+const SM_EDGE0: u32 = 5;
+const SM_LEVEL1: u32 = 6;
+const SM_LEVEL0: u32 = 7;
+
+/// The hart index and the EIID of a `target` register in MSI delivery mode,
+/// the guest index being 0.
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_MASK: u32 = 0xfffc_07ff;
+const EIID_MASK: u32 = 0x7ff;
+
+type Bits = [u32; WORDS];
+
+fn test_bit(bits: &Bits, n: usize) -> bool {
+    bits[n / 32] & (1 << (n % 32)) != 0
+}
+
+fn set_bit(bits: &mut Bits, n: usize, val: bool) {
+    if val {
+        bits[n / 32] |= 1 << (n % 32);
+    } else {
+        bits[n / 32] &= !(1 << (n % 32));
+    }
+}
+
+struct AplicState {
+    domaincfg: u32,
+    sourcecfg: [u32; NUM_SOURCES],
+    target: [u32; NUM_SOURCES],
+    pending: Bits,
+    enabled: Bits,
+    /// The levels of the source wires.
+    input: Bits,
+}
+
+impl AplicState {
+    fn mode(&self, irq: usize) -> u32 {
+        self.sourcecfg[irq]
+    }
+
+    fn is_level(&self, irq: usize) -> bool {
+        matches!(self.mode(irq), SM_LEVEL1 | SM_LEVEL0)
+    }
+
+    /// The input of `irq` once inverted for the low-active modes.
+    fn rectified(&self, irq: usize) -> bool {
+        let input = test_bit(&self.input, irq);
+        match self.mode(irq) {
+            SM_EDGE1 | SM_LEVEL1 => input,
+            SM_EDGE0 | SM_LEVEL0 => !input,
+            _ => false,
+        }
+    }
+
+    /// Sets `irq` pending by a write of the guest, only if it is active, and
+    /// its rectified input is high for the level-sensitive modes.
+    fn set_pending(&mut self, irq: usize) {
+        if irq == 0 || irq >= NUM_SOURCES || self.mode(irq) == SM_INACTIVE {
+            return;
+        }
+        if !self.is_level(irq) || self.rectified(irq) {
+            set_bit(&mut self.pending, irq, true);
+        }
+    }
+
+    fn set_enabled(&mut self, irq: usize, val: bool) {
+        if irq != 0 && irq < NUM_SOURCES && self.mode(irq) != SM_INACTIVE {
+            set_bit(&mut self.enabled, irq, val);
+        }
+    }
+
+    fn clear_pending(&mut self, irq: usize) {
+        if irq < NUM_SOURCES {
+            set_bit(&mut self.pending, irq, false);
+        }
+    }
+
+    /// Applies `f` to the sources of the bits set in `val`, written to the
+    /// word `word` of a register with a bit per source.
+    fn write_bits(&mut self, word: usize, val: u32, f: impl Fn(&mut Self, usize)) {
+        for i in (0..32).filter(|i| val & 1 << i != 0) {
+            f(self, word * 32 + i);
+        }
+    }
+
+    fn write_sourcecfg(&mut self, irq: usize, val: u32) {
+        // Without child domains, sources cannot be delegated.
+        let mode = match val {
+            _ if val & SOURCECFG_D != 0 => SM_INACTIVE,
+            SM_DETACHED | SM_EDGE1 | SM_EDGE0 | SM_LEVEL1 | SM_LEVEL0 => val,
+            _ => SM_INACTIVE,
+        };
+        self.sourcecfg[irq] = mode;
+        if mode == SM_INACTIVE {
+            set_bit(&mut self.pending, irq, false);
+            set_bit(&mut self.enabled, irq, false);
+        } else if self.is_level(irq) {
+            let rectified = self.rectified(irq);
+            set_bit(&mut self.pending, irq, rectified);
+        }
+    }
+
+    /// Takes the pending and enabled sources, to be forwarded as MSIs, if
+    /// the domain has the interrupts enabled.
+    fn take_msis(&mut self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let enabled = self.domaincfg & DOMAINCFG_IE != 0;
+        (1..NUM_SOURCES).filter_map(move |irq| {
+            if !enabled || !test_bit(&self.pending, irq) || !test_bit(&self.enabled, irq) {
+                return None;
+            }
+            set_bit(&mut self.pending, irq, false);
+            let target = self.target[irq];
+            Some((
+                (target >> TARGET_HART_SHIFT) as usize,
+                (target & EIID_MASK) as usize,
+            ))
+        })
+    }
+}
+
+/// A virtual APLIC forwarding the interrupts of the devices to the IMSIC.
+pub struct VAplic {
+    imsic: Arc<VImsic>,
+    state: Mutex<AplicState>,
+}
+
+impl VAplic {
+    /// Creates an APLIC sending its MSIs to `imsic`.
+    pub fn new(imsic: Arc<VImsic>) -> Self {
+        Self {
+            imsic,
+            state: Mutex::new(AplicState {
+                domaincfg: DOMAINCFG_FIXED,
+                sourcecfg: [SM_INACTIVE; NUM_SOURCES],
+                target: [0; NUM_SOURCES],
+                pending: [0; WORDS],
+                enabled: [0; WORDS],
+                input: [0; WORDS],
+            }),
+        }
+    }
+
+    /// Sets the level of the wire of the source `irq`.
+    pub fn set_level(&self, irq: usize, level: bool) {
+        let mut state = self.state.lock();
+        if test_bit(&state.input, irq) == level {
+            return;
+        }
+        let rectified = state.rectified(irq);
+        set_bit(&mut state.input, irq, level);
+        match state.mode(irq) {
+            SM_EDGE1 | SM_EDGE0 if !rectified && state.rectified(irq) => {
+                set_bit(&mut state.pending, irq, true);
+            }
+            SM_LEVEL1 | SM_LEVEL0 => {
+                let rectified = state.rectified(irq);
+                set_bit(&mut state.pending, irq, rectified);
+            }
+            _ => {}
+        }
+        self.forward(state);
+    }
+
+    /// Raises the source `irq`, as a write of the guest to `setipnum`.
+    pub fn raise(&self, irq: usize) {
+        let mut state = self.state.lock();
+        state.set_pending(irq);
+        self.forward(state);
+    }
+
+    /// Resets the APLIC for a reboot of the guest. The levels of the devices
+    /// stay.
+    pub fn reset(&self) {
+        let mut state = self.state.lock();
+        state.domaincfg = DOMAINCFG_FIXED;
+        state.sourcecfg = [SM_INACTIVE; NUM_SOURCES];
+        state.target = [0; NUM_SOURCES];
+        state.pending = [0; WORDS];
+        state.enabled = [0; WORDS];
+    }
+
+    /// Forwards the pending and enabled sources as MSIs.
+    fn forward(&self, mut state: MutexGuard<AplicState>) {
+        let msis: Vec<_> = state.take_msis().collect();
+        drop(state);
+        for (hart, eiid) in msis {
+            self.imsic.send(hart, eiid);
+        }
+    }
+}
+
+impl MmioDevice for VAplic {
+    fn read(&self, offset: usize, _width: AccessWidth) -> AxResult<u64> {
+        let state = self.state.lock();
+        let word = |base| (offset - base) / 4;
+        let val = match offset {
+            DOMAINCFG => state.domaincfg,
+            SOURCECFG_BASE..SOURCECFG_END => state
+                .sourcecfg
+                .get(word(SOURCECFG_BASE) + 1)
+                .copied()
+                .unwrap_or(0),
+            SETIP_BASE..SETIPNUM => state.pending.get(word(SETIP_BASE)).copied().unwrap_or(0),
+            IN_CLRIP_BASE..CLRIPNUM => {
+                let base = word(IN_CLRIP_BASE) * 32;
+                (0..32)
+                    .filter(|i| base + i < NUM_SOURCES && state.rectified(base + i))
+                    .fold(0, |bits, i| bits | 1 << i)
+            }
+            SETIE_BASE..SETIENUM => state.enabled.get(word(SETIE_BASE)).copied().unwrap_or(0),
+            TARGET_BASE..TARGET_END => state
+                .target
+                .get(word(TARGET_BASE) + 1)
+                .copied()
+                .unwrap_or(0),
+            // `genmsi` is never busy, the MSI being sent at once, and the
+            // other registers read as zero.
+            _ => 0,
+        };
+        Ok(val as u64)
+    }
+
+    fn write(&self, offset: usize, _width: AccessWidth, val: u64) -> AxResult {
+        let val = val as u32;
+        let mut state = self.state.lock();
+        let word = |base| (offset - base) / 4;
+        match offset {
+            DOMAINCFG => state.domaincfg = DOMAINCFG_FIXED | val & DOMAINCFG_IE,
+            SOURCECFG_BASE..SOURCECFG_END => {
+                let irq = word(SOURCECFG_BASE) + 1;
+                if irq < NUM_SOURCES {
+                    state.write_sourcecfg(irq, val);
+                }
+            }
+            SETIP_BASE..SETIPNUM => {
+                state.write_bits(word(SETIP_BASE), val, |state, irq| state.set_pending(irq))
+            }
+            IN_CLRIP_BASE..CLRIPNUM => state.write_bits(word(IN_CLRIP_BASE), val, |state, irq| {
+                state.clear_pending(irq)
+            }),
+            SETIE_BASE..SETIENUM => state.write_bits(word(SETIE_BASE), val, |state, irq| {
+                state.set_enabled(irq, true)
+            }),
+            CLRIE_BASE..CLRIENUM => state.write_bits(word(CLRIE_BASE), val, |state, irq| {
+                state.set_enabled(irq, false)
+            }),
+            SETIPNUM | SETIPNUM_LE => state.set_pending(val as usize),
+            SETIPNUM_BE => state.set_pending(val.swap_bytes() as usize),
+            CLRIPNUM => state.clear_pending(val as usize),
+            SETIENUM => state.set_enabled(val as usize, true),
+            CLRIENUM => state.set_enabled(val as usize, false),
+            GENMSI => {
+                drop(state);
+                let hart = (val >> TARGET_HART_SHIFT) as usize;
+                self.imsic.send(hart, (val & EIID_MASK) as usize);
+                return Ok(());
+            }
+            TARGET_BASE..TARGET_END => {
+                let irq = word(TARGET_BASE) + 1;
+                if irq < NUM_SOURCES {
+                    state.target[irq] = val & TARGET_MASK;
+                }
+            }
+            _ => {}
+        }
+        self.forward(state);
+        Ok(())
+    }
+}
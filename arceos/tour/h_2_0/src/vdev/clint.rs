@@ -0,0 +1,120 @@
+//! A virtual CLINT, with the layout of the one of QEMU virt.
+//!
+//! The guest runs in VS-mode, so the machine-level registers are mapped to
+//! the virtual supervisor ones: setting `msip` sends a virtual IPI, and
+//! `mtimecmp` programs the guest timer of the hart.
+
+use alloc::sync::Weak;
+use alloc::vec::Vec;
+use axerrno::{ax_err, AxResult};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use riscv_vcpu::AccessWidth;
+
+use super::MmioDevice;
+use crate::vm::Vm;
+
+/// The guest physical address of the CLINT.
+pub const CLINT_BASE: usize = 0x0200_0000;
+/// The size of the CLINT registers.
+pub const CLINT_SIZE: usize = 0x1_0000;
+
+const MSIP_OFFSET: usize = 0x0;
+const MTIMECMP_OFFSET: usize = 0x4000;
+const MTIME_OFFSET: usize = 0xbff8;
+
+struct HartRegs {
+    msip: AtomicU32,
+    mtimecmp: AtomicU64,
+    /// `mtimecmp` was written, and the timer of the vCPU not programmed yet.
+    timer_changed: AtomicBool,
+}
+
+/// A virtual CLINT for the vCPUs of a VM.
+pub struct VClint {
+    vm: Weak<Vm>,
+    harts: Vec<HartRegs>,
+}
+
+impl VClint {
+    /// Creates a CLINT for the `num_harts` vCPUs of `vm`.
+    pub fn new(vm: Weak<Vm>, num_harts: usize) -> Self {
+        let harts = (0..num_harts)
+            .map(|_| HartRegs {
+                msip: AtomicU32::new(0),
+                mtimecmp: AtomicU64::new(u64::MAX),
+                timer_changed: AtomicBool::new(false),
+            })
+            .collect();
+        Self { vm, harts }
+    }
+
+    /// Takes the timer deadline of vCPU `hart` if the guest changed it, to
+    /// program it before running the vCPU.
+    pub fn take_timer(&self, hart: usize) -> Option<u64> {
+        let regs = self.harts.get(hart)?;
+        regs.timer_changed
+            .swap(false, Ordering::Acquire)
+            .then(|| regs.mtimecmp.load(Ordering::Relaxed))
+    }
+
+    /// Returns the registers of the hart at `offset` from `base`, with
+    /// registers of `stride` bytes, and the offset in the register.
+    fn hart_of(&self, offset: usize, base: usize, stride: usize) -> AxResult<(&HartRegs, usize)> {
+        let index = (offset - base) / stride;
+        match self.harts.get(index) {
+            Some(regs) => Ok((regs, (offset - base) % stride)),
+            None => ax_err!(InvalidInput, "no such hart"),
+        }
+    }
+}
+
+impl MmioDevice for VClint {
+    fn read(&self, offset: usize, width: AccessWidth) -> AxResult<u64> {
+        let time = axhal::time::current_ticks();
+        match offset {
+            MTIME_OFFSET..CLINT_SIZE => Ok(time >> ((offset - MTIME_OFFSET) * 8)),
+            MTIMECMP_OFFSET..MTIME_OFFSET => {
+                let (regs, shift) = self.hart_of(offset, MTIMECMP_OFFSET, 8)?;
+                Ok(regs.mtimecmp.load(Ordering::Relaxed) >> (shift * 8))
+            }
+            MSIP_OFFSET..MTIMECMP_OFFSET if width == AccessWidth::Dword => {
+                let (regs, _) = self.hart_of(offset, MSIP_OFFSET, 4)?;
+                Ok(regs.msip.load(Ordering::Relaxed) as u64)
+            }
+            _ => ax_err!(InvalidInput, "bad CLINT access"),
+        }
+    }
+
+    fn write(&self, offset: usize, width: AccessWidth, val: u64) -> AxResult {
+        match offset {
+            MTIMECMP_OFFSET..MTIME_OFFSET => {
+                let (regs, shift) = self.hart_of(offset, MTIMECMP_OFFSET, 8)?;
+                // 32-bit guests write the two halves separately.
+                let mask = width.extend(u64::MAX, false) << (shift * 8);
+                let old = regs.mtimecmp.load(Ordering::Relaxed);
+                let new = old & !mask | (val << (shift * 8)) & mask;
+                regs.mtimecmp.store(new, Ordering::Relaxed);
+                regs.timer_changed.store(true, Ordering::Release);
+                // The vCPU may wait for the old deadline.
+                if let Some(vm) = self.vm.upgrade() {
+                    vm.kick((offset - MTIMECMP_OFFSET) / 8);
+                }
+                Ok(())
+            }
+            MSIP_OFFSET..MTIMECMP_OFFSET if width == AccessWidth::Dword => {
+                let (regs, _) = self.hart_of(offset, MSIP_OFFSET, 4)?;
+                let msip = val as u32 & 1;
+                regs.msip.store(msip, Ordering::Relaxed);
+                if msip != 0 {
+                    let hart = (offset - MSIP_OFFSET) / 4;
+                    if let Some(vm) = self.vm.upgrade() {
+                        vm.send_ipi(1, hart)?;
+                    }
+                }
+                Ok(())
+            }
+            // mtime is read-only, the guest time follows the host one.
+            _ => ax_err!(InvalidInput, "bad CLINT access"),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! A virtual goldfish RTC, at the address of the one of QEMU virt.
+//!
+//! It counts the nanoseconds since the epoch with the wall time of the host,
+//! so the guest gets the real date at boot. Setting the time only moves the
+//! clock of the VM, by an offset from the host one. The alarm is checked
+//! when the interrupts of the devices are updated, so it may fire late while
+//! all the vCPUs wait.
+
+use axerrno::AxResult;
+use axhal::time::wall_time_nanos;
+use riscv_vcpu::AccessWidth;
+use std::sync::Mutex;
+
+use super::MmioDevice;
+
+/// The guest physical address of the RTC.
+pub const RTC_BASE: usize = 0x10_1000;
+/// The size of the RTC registers.
+pub const RTC_SIZE: usize = 0x1000;
+/// The interrupt of the RTC in the PLIC.
+pub const RTC_IRQ: usize = 11;
+
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+const ALARM_LOW: usize = 0x08;
+const ALARM_HIGH: usize = 0x0c;
+const IRQ_ENABLED: usize = 0x10;
+const CLEAR_ALARM: usize = 0x14;
+const ALARM_STATUS: usize = 0x18;
+const CLEAR_INTERRUPT: usize = 0x1c;
+
+#[derive(Default)]
+struct RtcRegs {
+    /// The time of the VM minus the wall time of the host.
+    offset: i64,
+    /// The high half of the time, latched when the low one is read, or
+    /// written before the low one.
+    time_high: u32,
+    alarm_high: u32,
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    /// The alarm fired, and its interrupt is not cleared yet.
+    irq_pending: bool,
+}
+
+impl RtcRegs {
+    fn now(&self) -> u64 {
+        wall_time_nanos().wrapping_add_signed(self.offset)
+    }
+
+    fn check_alarm(&mut self) {
+        if self.alarm.is_some_and(|alarm| self.now() >= alarm) {
+            self.alarm = None;
+            self.irq_pending = true;
+        }
+    }
+}
+
+/// A virtual goldfish RTC backed by the host clock.
+#[derive(Default)]
+pub struct GoldfishRtc {
+    regs: Mutex<RtcRegs>,
+}
+
+impl GoldfishRtc {
+    /// Whether the RTC raises its interrupt, once the alarm fired.
+    pub fn irq_level(&self) -> bool {
+        let mut regs = self.regs.lock();
+        regs.check_alarm();
+        regs.irq_enabled && regs.irq_pending
+    }
+}
+
+impl MmioDevice for GoldfishRtc {
+    fn read(&self, offset: usize, _width: AccessWidth) -> AxResult<u64> {
+        let mut regs = self.regs.lock();
+        let val = match offset {
+            TIME_LOW => {
+                let now = regs.now();
+                regs.time_high = (now >> 32) as u32;
+                now as u32
+            }
+            TIME_HIGH => regs.time_high,
+            ALARM_LOW => regs.alarm.map_or(0, |alarm| alarm as u32),
+            ALARM_HIGH => regs.alarm.map_or(0, |alarm| (alarm >> 32) as u32),
+            IRQ_ENABLED => regs.irq_enabled as u32,
+            ALARM_STATUS => {
+                regs.check_alarm();
+                regs.alarm.is_some() as u32
+            }
+            _ => 0,
+        };
+        Ok(val as u64)
+    }
+
+    fn write(&self, offset: usize, _width: AccessWidth, val: u64) -> AxResult {
+        let val = val as u32;
+        let mut regs = self.regs.lock();
+        match offset {
+            // The time and the alarm are set by writing their high half
+            // first, then the low one.
+            TIME_LOW => {
+                let time = (regs.time_high as u64) << 32 | val as u64;
+                regs.offset = time.wrapping_sub(wall_time_nanos()) as i64;
+            }
+            TIME_HIGH => regs.time_high = val,
+            ALARM_LOW => {
+                regs.alarm = Some((regs.alarm_high as u64) << 32 | val as u64);
+                regs.check_alarm();
+            }
+            ALARM_HIGH => regs.alarm_high = val,
+            IRQ_ENABLED => regs.irq_enabled = val & 1 != 0,
+            CLEAR_ALARM => regs.alarm = None,
+            CLEAR_INTERRUPT => regs.irq_pending = false,
+            _ => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+//! A virtual IMSIC, the MSI controller of the AIA, at the address of the
+//! supervisor-level one of QEMU virt.
+//!
+//! Each vCPU has an interrupt file of 63 identities in its own page. Devices
+//! and other vCPUs send it MSIs by writing an identity to its `seteipnum_le`
+//! register, and the guest handles them through the `vsireg` and `vstopei`
+//! CSRs, which trap to be emulated, see [`riscv_vcpu::ImsicAccess`]. A
+//! deliverable interrupt is a VS-level external interrupt for the vCPU.
+
+use alloc::sync::Weak;
+use alloc::vec::Vec;
+use axerrno::AxResult;
+use riscv_vcpu::{AccessWidth, ImsicAccess, ImsicReg};
+use std::sync::Mutex;
+
+use super::MmioDevice;
+use crate::vm::Vm;
+
+/// The guest physical address of the IMSIC.
+pub const IMSIC_BASE: usize = 0x2800_0000;
+/// The size of the interrupt file of a vCPU.
+pub const IMSIC_FILE_SIZE: usize = 0x1000;
+/// The number of identities, plus the reserved identity 0.
+pub const NUM_IDS: usize = 64;
+
+const SETEIPNUM_LE: usize = 0x0;
+const SETEIPNUM_BE: usize = 0x4;
+
+const ISELECT_EIDELIVERY: usize = 0x70;
+const ISELECT_EITHRESHOLD: usize = 0x72;
+/// `eip0` holds the pending bits of the 64 identities on RV64, the other
+/// `eip` registers are zero.
+const ISELECT_EIP0: usize = 0x80;
+const ISELECT_EIE0: usize = 0xc0;
+
+/// `eidelivery` enables the delivery of the interrupts.
+const EIDELIVERY_ENABLED: usize = 1;
+
+#[derive(Default)]
+struct InterruptFile {
+    eidelivery: usize,
+    eithreshold: usize,
+    pending: u64,
+    enabled: u64,
+}
+
+impl InterruptFile {
+    /// The pending and enabled identity with the highest priority, the
+    /// lowest one, under the threshold if any.
+    fn top(&self) -> usize {
+        let candidates = self.pending & self.enabled & !1;
+        let id = candidates.trailing_zeros() as usize;
+        if id >= NUM_IDS || (self.eithreshold != 0 && id >= self.eithreshold) {
+            0
+        } else {
+            id
+        }
+    }
+
+    fn deliverable(&self) -> bool {
+        self.eidelivery == EIDELIVERY_ENABLED && self.top() != 0
+    }
+
+    fn read(&self, reg: ImsicReg) -> usize {
+        match reg {
+            ImsicReg::Indirect(ISELECT_EIDELIVERY) => self.eidelivery,
+            ImsicReg::Indirect(ISELECT_EITHRESHOLD) => self.eithreshold,
+            ImsicReg::Indirect(ISELECT_EIP0) => self.pending as usize,
+            ImsicReg::Indirect(ISELECT_EIE0) => self.enabled as usize,
+            ImsicReg::Indirect(_) => 0,
+            // The priority of an identity is the identity itself.
+            ImsicReg::Topei => self.top() << 16 | self.top(),
+        }
+    }
+
+    fn write(&mut self, reg: ImsicReg, val: usize) {
+        match reg {
+            ImsicReg::Indirect(ISELECT_EIDELIVERY) => self.eidelivery = val & EIDELIVERY_ENABLED,
+            ImsicReg::Indirect(ISELECT_EITHRESHOLD) => self.eithreshold = val % NUM_IDS,
+            // Identity 0 does not exist.
+            ImsicReg::Indirect(ISELECT_EIP0) => self.pending = val as u64 & !1,
+            ImsicReg::Indirect(ISELECT_EIE0) => self.enabled = val as u64 & !1,
+            ImsicReg::Indirect(_) => {}
+            // A write claims the top interrupt, whatever the value.
+            ImsicReg::Topei => self.pending &= !(1 << self.top()),
+        }
+    }
+}
+
+/// A virtual IMSIC, with an interrupt file per vCPU.
+pub struct VImsic {
+    vm: Weak<Vm>,
+    files: Vec<Mutex<InterruptFile>>,
+}
+
+impl VImsic {
+    /// Creates the IMSIC of `vm`, with `num_vcpus` vCPUs.
+    pub fn new(vm: Weak<Vm>, num_vcpus: usize) -> Self {
+        let files = (0..num_vcpus).map(|_| Mutex::default()).collect();
+        Self { vm, files }
+    }
+
+    /// Returns the size of the registers, a page per vCPU.
+    pub fn size(&self) -> usize {
+        self.files.len() * IMSIC_FILE_SIZE
+    }
+
+    /// Sends the MSI `id` to vCPU `hart`, kicking it if the interrupt gets
+    /// deliverable.
+    pub fn send(&self, hart: usize, id: usize) {
+        let Some(file) = self.files.get(hart) else {
+            return;
+        };
+        if id == 0 || id >= NUM_IDS {
+            return;
+        }
+        let mut file = file.lock();
+        let was_deliverable = file.deliverable();
+        file.pending |= 1 << id;
+        let deliverable = file.deliverable();
+        drop(file);
+        if deliverable && !was_deliverable {
+            if let Some(vm) = self.vm.upgrade() {
+                vm.kick(hart);
+            }
+        }
+    }
+
+    /// Whether an interrupt is deliverable to vCPU `id`.
+    pub fn irq_pending(&self, id: usize) -> bool {
+        self.files
+            .get(id)
+            .is_some_and(|file| file.lock().deliverable())
+    }
+
+    /// Emulates `access` of vCPU `hart` to its interrupt file, returning the
+    /// value of the register before the access.
+    pub fn access(&self, hart: usize, access: &ImsicAccess) -> usize {
+        let Some(file) = self.files.get(hart) else {
+            return 0;
+        };
+        let mut file = file.lock();
+        let old = file.read(access.reg);
+        if access.write.is_some() {
+            file.write(access.reg, access.new_value(old));
+        }
+        old
+    }
+
+    /// Resets the interrupt files for a reboot of the guest.
+    pub fn reset(&self) {
+        for file in &self.files {
+            *file.lock() = InterruptFile::default();
+        }
+    }
+}
+
+impl MmioDevice for VImsic {
+    fn read(&self, _offset: usize, _width: AccessWidth) -> AxResult<u64> {
+        // The `seteipnum` registers read as zero.
+        Ok(0)
+    }
+
+    fn write(&self, offset: usize, _width: AccessWidth, val: u64) -> AxResult {
+        let hart = offset / IMSIC_FILE_SIZE;
+        let id = match offset % IMSIC_FILE_SIZE {
+            SETEIPNUM_LE => val as u32,
+            SETEIPNUM_BE => (val as u32).swap_bytes(),
+            _ => return Ok(()),
+        };
+        self.send(hart, id as usize);
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+//! Devices emulated for the guest.
+//!
+//! The guest accesses them through unmapped guest physical addresses, so each
+//! access faults and is decoded by the vCPU, then served by the device. Their
+//! interrupts go through the virtual PLIC, or with the AIA, through the
+//! virtual APLIC forwarding them as MSIs to the virtual IMSIC.
+
+pub mod aplic;
+pub mod clint;
+pub mod goldfish_rtc;
+pub mod imsic;
+pub mod passthrough;
+pub mod pflash;
+pub mod plic;
+pub mod uart;
+mod virtio;
+pub mod virtio_balloon;
+pub mod virtio_blk;
+#[cfg(feature = "vnet")]
+pub mod virtio_net;
+
+pub use aplic::VAplic;
+pub use clint::VClint;
+pub use goldfish_rtc::GoldfishRtc;
+pub use imsic::VImsic;
+pub use passthrough::PassthroughBlk;
+pub use pflash::VirtPflash;
+pub use plic::VPlic;
+pub use uart::VUart;
+pub use virtio_balloon::VirtioBalloon;
+pub use virtio_blk::VirtioBlk;
+#[cfg(feature = "vnet")]
+pub use virtio_net::VirtioNet;
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use axerrno::{ax_err, AxResult};
+use axmm::AddrSpace;
+use core::ops::Range;
+use riscv_vcpu::AccessWidth;
+
+use crate::vm::{Vm, VmConfig};
+
+/// A device emulated with MMIO registers.
+pub trait MmioDevice: Send + Sync {
+    /// Reads the register at `offset` from the base of the device.
+    fn read(&self, offset: usize, width: AccessWidth) -> AxResult<u64>;
+
+    /// Writes `val` to the register at `offset` from the base of the device.
+    fn write(&self, offset: usize, width: AccessWidth, val: u64) -> AxResult;
+
+    /// Maps the page at `offset` from the base of the device in `aspace`,
+    /// for a device backed by memory the guest reads directly. Returns
+    /// whether the page is mapped, or the read has to be emulated.
+    fn map_page(&self, _aspace: &mut AddrSpace, _offset: usize) -> AxResult<bool> {
+        Ok(false)
+    }
+}
+
+/// The guest physical addresses served by a device.
+struct MmioRegion {
+    range: Range<usize>,
+    dev: Arc<dyn MmioDevice>,
+}
+
+/// The devices of a VM.
+pub struct Devices {
+    pub clint: Arc<VClint>,
+    pub plic: Arc<VPlic>,
+    /// The IMSIC and the APLIC replacing the PLIC, with the AIA.
+    pub imsic: Option<Arc<VImsic>>,
+    pub aplic: Option<Arc<VAplic>>,
+    pub uart: Arc<VUart>,
+    pub rtc: Arc<GoldfishRtc>,
+    pub blk: Option<Arc<VirtioBlk>>,
+    #[cfg(feature = "vnet")]
+    pub net: Option<Arc<VirtioNet>>,
+    pub pflash: Option<Arc<VirtPflash>>,
+    pub balloon: Option<Arc<VirtioBalloon>>,
+    pub passthrough: Option<Arc<PassthroughBlk>>,
+    mmio: Vec<MmioRegion>,
+}
+
+impl Devices {
+    /// Creates the devices of `vm` described by `config`.
+    pub fn new(vm: Weak<Vm>, config: &VmConfig) -> AxResult<Self> {
+        let blk = match &config.disk {
+            Some(path) => {
+                info!("virtio-blk backed by {}", path);
+                Some(Arc::new(VirtioBlk::new(vm.clone(), path)?))
+            }
+            None => None,
+        };
+        let pflash = match &config.pflash {
+            Some(path) => {
+                info!("pflash at {:#x} backed by {}", config.pflash_base, path);
+                Some(Arc::new(VirtPflash::new(
+                    vm.clone(),
+                    config.pflash_base,
+                    path,
+                )?))
+            }
+            None => None,
+        };
+        let passthrough = match config.blk_passthrough {
+            Some(base) => {
+                info!("host virtio-blk at {:#x} passed through", base);
+                Some(Arc::new(PassthroughBlk::new(vm.clone(), base)?))
+            }
+            None => None,
+        };
+        let balloon = config.balloon.map(|num_pages| {
+            info!("virtio-balloon asking for {} pages", num_pages);
+            VirtioBalloon::new(vm.clone(), num_pages)
+        });
+        #[cfg(feature = "vnet")]
+        let net = match &config.net_peer {
+            Some(peer) => {
+                info!("virtio-net tunneled to {}", peer);
+                let peer = peer
+                    .parse()
+                    .map_err(|_| axerrno::ax_err_type!(InvalidInput, "bad virtio-net peer"))?;
+                Some(VirtioNet::new(vm.clone(), peer)?)
+            }
+            None => None,
+        };
+        let imsic = config
+            .aia
+            .then(|| Arc::new(VImsic::new(vm.clone(), config.max_vcpus)));
+        let aplic = imsic
+            .as_ref()
+            .map(|imsic| Arc::new(VAplic::new(imsic.clone())));
+        let mut devs = Self {
+            clint: Arc::new(VClint::new(vm.clone(), config.max_vcpus)),
+            plic: Arc::new(VPlic::new(vm, config.max_vcpus)),
+            imsic,
+            aplic,
+            uart: Arc::new(VUart::default()),
+            rtc: Arc::new(GoldfishRtc::default()),
+            blk,
+            #[cfg(feature = "vnet")]
+            net,
+            pflash,
+            balloon,
+            passthrough,
+            mmio: Vec::new(),
+        };
+        devs.register(clint::CLINT_BASE, clint::CLINT_SIZE, devs.clint.clone())?;
+        if let (Some(imsic), Some(aplic)) = (devs.imsic.clone(), devs.aplic.clone()) {
+            devs.register(imsic::IMSIC_BASE, imsic.size(), imsic)?;
+            devs.register(aplic::APLIC_BASE, aplic::APLIC_SIZE, aplic)?;
+        } else {
+            devs.register(plic::PLIC_BASE, plic::PLIC_SIZE, devs.plic.clone())?;
+        }
+        devs.register(uart::UART_BASE, uart::UART_SIZE, devs.uart.clone())?;
+        use goldfish_rtc::{RTC_BASE, RTC_SIZE};
+        devs.register(RTC_BASE, RTC_SIZE, devs.rtc.clone())?;
+        if let Some(blk) = devs.blk.clone() {
+            use virtio_blk::{VIRTIO_BLK_BASE, VIRTIO_BLK_SIZE};
+            devs.register(VIRTIO_BLK_BASE, VIRTIO_BLK_SIZE, blk)?;
+        }
+        #[cfg(feature = "vnet")]
+        if let Some(net) = devs.net.clone() {
+            use virtio_net::{VIRTIO_NET_BASE, VIRTIO_NET_SIZE};
+            devs.register(VIRTIO_NET_BASE, VIRTIO_NET_SIZE, net)?;
+        }
+        if let Some(balloon) = devs.balloon.clone() {
+            use virtio_balloon::{VIRTIO_BALLOON_BASE, VIRTIO_BALLOON_SIZE};
+            devs.register(VIRTIO_BALLOON_BASE, VIRTIO_BALLOON_SIZE, balloon)?;
+        }
+        if let Some(pflash) = devs.pflash.clone() {
+            devs.register(pflash.base(), pflash.size(), pflash)?;
+        }
+        if let Some(passthrough) = devs.passthrough.clone() {
+            devs.register(
+                passthrough.base(),
+                virtio::VIRTIO_MMIO_SIZE,
+                passthrough.clone(),
+            )?;
+            devs.plic.set_forwarded(passthrough.irq());
+        }
+        Ok(devs)
+    }
+
+    /// Serves the guest physical addresses from `base` to `base + size` with
+    /// `dev`. They must not be served by another device.
+    pub fn register(&mut self, base: usize, size: usize, dev: Arc<dyn MmioDevice>) -> AxResult {
+        let range = base..base + size;
+        if self
+            .mmio
+            .iter()
+            .any(|region| region.range.start < range.end && range.start < region.range.end)
+        {
+            return ax_err!(AlreadyExists, "MMIO region overlaps another device");
+        }
+        self.mmio.push(MmioRegion { range, dev });
+        Ok(())
+    }
+
+    /// Returns the device whose registers contain the guest physical address
+    /// `gpa`, and its base.
+    pub fn find(&self, gpa: usize) -> Option<(&dyn MmioDevice, usize)> {
+        self.mmio
+            .iter()
+            .find(|region| region.range.contains(&gpa))
+            .map(|region| (region.dev.as_ref(), region.range.start))
+    }
+
+    /// Resets the devices the guest does not reset itself when it boots again,
+    /// see [`Vm::wait`].
+    pub fn reset(&self) {
+        self.plic.reset();
+        if let (Some(imsic), Some(aplic)) = (&self.imsic, &self.aplic) {
+            imsic.reset();
+            aplic.reset();
+        }
+        if let Some(passthrough) = &self.passthrough {
+            passthrough.reset();
+        }
+    }
+
+    /// Whether an external interrupt is pending for vCPU `id`, from the PLIC
+    /// or the IMSIC.
+    pub fn irq_pending(&self, id: usize) -> bool {
+        match &self.imsic {
+            Some(imsic) => imsic.irq_pending(id),
+            None => self.plic.irq_pending(id),
+        }
+    }
+
+    /// Raises the source `irq` of the PLIC, or of the APLIC with the AIA.
+    pub fn raise_irq(&self, irq: usize) {
+        match &self.aplic {
+            Some(aplic) => aplic.raise(irq),
+            None => self.plic.raise(irq),
+        }
+    }
+
+    /// Sets the level of the source `irq` of the PLIC, or of the APLIC with
+    /// the AIA.
+    fn set_level(&self, irq: usize, level: bool) {
+        match &self.aplic {
+            Some(aplic) => aplic.set_level(irq, level),
+            None => self.plic.set_level(irq, level),
+        }
+    }
+
+    /// Updates the PLIC, or the APLIC, with the interrupts of the devices.
+    pub fn sync_irqs(&self) {
+        self.set_level(uart::UART_IRQ, self.uart.irq_level());
+        self.set_level(goldfish_rtc::RTC_IRQ, self.rtc.irq_level());
+        if let Some(blk) = &self.blk {
+            self.set_level(virtio_blk::VIRTIO_BLK_IRQ, blk.irq_level());
+        }
+        #[cfg(feature = "vnet")]
+        if let Some(net) = &self.net {
+            self.set_level(virtio_net::VIRTIO_NET_IRQ, net.irq_level());
+        }
+        if let Some(balloon) = &self.balloon {
+            self.set_level(virtio_balloon::VIRTIO_BALLOON_IRQ, balloon.irq_level());
+        }
+        if let Some(passthrough) = &self.passthrough {
+            if passthrough.take_host_irq() {
+                if let Err(err) = passthrough.complete_dma() {
+                    warn!("passthrough virtio-blk: DMA not completed: {:?}", err);
+                }
+                self.plic.raise(passthrough.irq());
+            }
+            if self.plic.take_completed(passthrough.irq()) {
+                passthrough.complete();
+            }
+        }
+    }
+}
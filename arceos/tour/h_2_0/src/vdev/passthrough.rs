@@ -0,0 +1,615 @@
+//! A virtio-blk device of the host, passed through to a guest.
+//!
+//! The guest drives the device at the same address as on the host, and its
+//! interrupt is forwarded from the host PLIC to the virtual PLIC. The host
+//! must leave the device alone: its base is listed in
+//! `AX_VIRTIO_MMIO_RESERVED` when building, so axdriver does not probe it.
+//!
+//! There is no IOMMU on QEMU virt: the device accesses host physical
+//! addresses, any of them. So it never sees the guest memory. Its registers
+//! are emulated, most of them by forwarding the access to the device, but
+//! its virtqueue is a shadow of the one of the guest, in host frames. When
+//! the guest notifies the device, its requests are copied to the shadow
+//! queue, with their buffers in a bounce region of host frames, a chunk per
+//! descriptor. When the device interrupts, the buffers it wrote are copied
+//! back to the guest, and the requests are given back in the used ring of
+//! the guest.
+//!
+//! For the buffers to fit in the chunks, the device offers the guest
+//! `VIRTIO_BLK_F_SIZE_MAX` with chunk-sized segments, and neither indirect
+//! descriptors, event indices nor multiple queues. Both the legacy and the
+//! modern virtio-mmio interfaces are handled.
+//!
+//! The host PLIC routes the interrupt to the hart running vCPU 0. It is
+//! claimed there, either when it makes the vCPU exit or in the interrupt
+//! handler of the host, and raised in the virtual PLIC by vCPU 0. It is
+//! completed on the host once the guest completes it.
+
+use alloc::sync::Weak;
+use axerrno::{ax_err, AxError, AxResult};
+use axhal::mem::{phys_to_virt, virt_to_phys};
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use memory_addr::{align_up, PhysAddr, VirtAddr, PAGE_SIZE_4K};
+use riscv_vcpu::AccessWidth;
+use std::sync::Mutex;
+
+use super::plic::NUM_SOURCES;
+use super::virtio::{Desc, VirtQueue};
+use super::MmioDevice;
+use crate::guest_mem::GuestMemory;
+use crate::vm::Vm;
+
+/// The host physical address of the PLIC of QEMU virt.
+const HOST_PLIC_BASE: usize = 0x0c00_0000;
+const PLIC_ENABLE_BASE: usize = 0x2000;
+const PLIC_ENABLE_STRIDE: usize = 0x80;
+const PLIC_CONTEXT_BASE: usize = 0x20_0000;
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+const PLIC_CONTEXT_CLAIM: usize = 0x4;
+
+/// The IRQ of the first virtio-mmio slot of QEMU virt, the next slots have
+/// the next ones.
+const VIRTIO_IRQ_BASE: usize = 1;
+
+/// The supervisor external interrupt in `scause`.
+const S_EXT: usize = (1 << (usize::BITS - 1)) | 9;
+
+const VIRTIO_MAGIC: u32 = 0x7472_6976;
+const VIRTIO_REG_MAGIC: usize = 0x000;
+const VIRTIO_REG_VERSION: usize = 0x004;
+const VIRTIO_REG_DEVICE_ID: usize = 0x008;
+const VIRTIO_REG_DEVICE_FEATURES: usize = 0x010;
+const VIRTIO_REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const VIRTIO_REG_DRIVER_FEATURES: usize = 0x020;
+const VIRTIO_REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const VIRTIO_REG_GUEST_PAGE_SIZE: usize = 0x028;
+const VIRTIO_REG_QUEUE_SEL: usize = 0x030;
+const VIRTIO_REG_QUEUE_NUM_MAX: usize = 0x034;
+const VIRTIO_REG_QUEUE_NUM: usize = 0x038;
+const VIRTIO_REG_QUEUE_ALIGN: usize = 0x03c;
+const VIRTIO_REG_QUEUE_PFN: usize = 0x040;
+const VIRTIO_REG_QUEUE_READY: usize = 0x044;
+const VIRTIO_REG_QUEUE_NOTIFY: usize = 0x050;
+const VIRTIO_REG_STATUS: usize = 0x070;
+const VIRTIO_REG_QUEUE_DESC_LOW: usize = 0x080;
+const VIRTIO_REG_QUEUE_DESC_HIGH: usize = 0x084;
+const VIRTIO_REG_QUEUE_DRIVER_LOW: usize = 0x090;
+const VIRTIO_REG_QUEUE_DRIVER_HIGH: usize = 0x094;
+const VIRTIO_REG_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const VIRTIO_REG_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const VIRTIO_CONFIG_OFFSET: usize = 0x100;
+const DEVICE_ID_BLK: u32 = 2;
+
+/// The version of the legacy virtio-mmio interface.
+const VIRTIO_VERSION_LEGACY: u32 = 1;
+
+const VIRTIO_BLK_F_SIZE_MAX: u32 = 1 << 1;
+const VIRTIO_BLK_F_MQ: u32 = 1 << 12;
+const VIRTIO_F_INDIRECT_DESC: u32 = 1 << 28;
+const VIRTIO_F_EVENT_IDX: u32 = 1 << 29;
+/// The features of the device hidden from the guest, in the first word.
+const HIDDEN_FEATURES: u32 = VIRTIO_BLK_F_MQ | VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+/// The offset of `size_max` in the virtio-blk configuration.
+const BLK_CONFIG_SIZE_MAX: usize = 8;
+
+/// The largest virtqueue of the guest.
+const MAX_QUEUE_SIZE: u16 = 64;
+/// The size of the bounce chunk of each descriptor, the largest buffer.
+const BOUNCE_CHUNK_SIZE: usize = PAGE_SIZE_4K;
+/// The shadow virtqueue is in the legacy layout: the descriptor table then
+/// the available ring in its first page, the used ring in its second page.
+const SHADOW_AVAIL_OFFSET: usize = MAX_QUEUE_SIZE as usize * 16;
+const SHADOW_USED_OFFSET: usize = PAGE_SIZE_4K;
+const SHADOW_SIZE: usize = 2 * PAGE_SIZE_4K;
+/// The host frames of the shadow virtqueue, then the bounce chunks.
+const FRAMES_SIZE: usize = SHADOW_SIZE + MAX_QUEUE_SIZE as usize * BOUNCE_CHUNK_SIZE;
+
+/// The host IRQs claimed, not yet raised in the virtual PLIC.
+static HOST_RAISED: [AtomicBool; NUM_SOURCES] = [const { AtomicBool::new(false) }; NUM_SOURCES];
+/// The host PLIC context each host IRQ was claimed from.
+static HOST_CONTEXT: [AtomicUsize; NUM_SOURCES] = [const { AtomicUsize::new(0) }; NUM_SOURCES];
+static HANDLER_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+fn plic_reg(offset: usize) -> *mut u32 {
+    phys_to_virt(PhysAddr::from(HOST_PLIC_BASE + offset)).as_mut_ptr() as *mut u32
+}
+
+/// The supervisor context of `hart` in the host PLIC.
+fn host_context(hart: usize) -> usize {
+    hart * 2 + 1
+}
+
+fn set_host_enable(context: usize, irq: usize, enable: bool) {
+    let reg = plic_reg(PLIC_ENABLE_BASE + context * PLIC_ENABLE_STRIDE + irq / 32 * 4);
+    unsafe {
+        let val = reg.read_volatile();
+        let bit = 1 << (irq % 32);
+        reg.write_volatile(if enable { val | bit } else { val & !bit });
+    }
+}
+
+/// Claims the pending interrupt of the host PLIC on the current hart, to be
+/// raised in the virtual PLIC.
+///
+/// It is the host handler of the supervisor external interrupt, and is also
+/// called when it makes a vCPU exit.
+pub fn handle_host_irq() {
+    let context = host_context(axhal::cpu::this_cpu_id());
+    let claim = plic_reg(PLIC_CONTEXT_BASE + context * PLIC_CONTEXT_STRIDE + PLIC_CONTEXT_CLAIM);
+    let irq = unsafe { claim.read_volatile() } as usize;
+    if irq == 0 {
+        return;
+    }
+    if irq < NUM_SOURCES {
+        HOST_CONTEXT[irq].store(context, Ordering::Relaxed);
+        HOST_RAISED[irq].store(true, Ordering::Release);
+    } else {
+        unsafe { claim.write_volatile(irq as u32) };
+    }
+}
+
+/// The IRQ of the virtio-mmio slot of the host at `base`, if it is one.
+pub fn slot_irq(base: usize) -> Option<usize> {
+    axconfig::VIRTIO_MMIO_REGIONS
+        .iter()
+        .position(|region| region.0 == base)
+        .map(|slot| VIRTIO_IRQ_BASE + slot)
+}
+
+/// The offset of the bounce chunk of descriptor `index` in the host frames.
+fn bounce_offset(index: u16) -> usize {
+    SHADOW_SIZE + index as usize * BOUNCE_CHUNK_SIZE
+}
+
+/// The virtqueue of the guest, and the transport registers the device does
+/// not see as written by the guest.
+#[derive(Default)]
+struct ShadowQueue {
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    guest_page_size: u32,
+    queue_align: u32,
+    queue_pfn: u32,
+    num: u16,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    /// The virtqueue of the guest, once set up.
+    guest: Option<VirtQueue>,
+    /// The number of requests made available to the device.
+    avail_idx: u16,
+    /// The next entry of the used ring of the device to process.
+    last_used: u16,
+}
+
+/// A virtio-blk device of the host, given to a guest.
+pub struct PassthroughBlk {
+    vm: Weak<Vm>,
+    base: usize,
+    irq: usize,
+    /// The host PLIC context the interrupt is enabled on, or `usize::MAX`.
+    context: AtomicUsize,
+    /// The version of the virtio-mmio interface of the device.
+    version: u32,
+    /// The first word of the features of the device.
+    host_features: u32,
+    /// The `size_max` of the device, if it has one.
+    host_size_max: Option<u32>,
+    /// The host frames of the shadow virtqueue and the bounce chunks.
+    frames: VirtAddr,
+    queue: Mutex<ShadowQueue>,
+}
+
+impl PassthroughBlk {
+    /// Takes the virtio-blk device of the host at `base`, one of the
+    /// virtio-mmio slots, for `vm`, and resets it.
+    pub fn new(vm: Weak<Vm>, base: usize) -> AxResult<Self> {
+        let Some(irq) = slot_irq(base) else {
+            return ax_err!(InvalidInput, "not a virtio-mmio slot");
+        };
+        let reg =
+            |offset: usize| phys_to_virt(PhysAddr::from(base + offset)).as_mut_ptr() as *mut u32;
+        let (version, host_features, host_size_max) = unsafe {
+            if reg(VIRTIO_REG_MAGIC).read_volatile() != VIRTIO_MAGIC
+                || reg(VIRTIO_REG_DEVICE_ID).read_volatile() != DEVICE_ID_BLK
+            {
+                return ax_err!(NotFound, "no virtio-blk device in the slot");
+            }
+            // The guest driver starts from a reset device.
+            reg(VIRTIO_REG_STATUS).write_volatile(0);
+            reg(VIRTIO_REG_DEVICE_FEATURES_SEL).write_volatile(0);
+            let features = reg(VIRTIO_REG_DEVICE_FEATURES).read_volatile();
+            let size_max = reg(VIRTIO_CONFIG_OFFSET + BLK_CONFIG_SIZE_MAX).read_volatile();
+            (
+                reg(VIRTIO_REG_VERSION).read_volatile(),
+                features,
+                (features & VIRTIO_BLK_F_SIZE_MAX != 0).then_some(size_max),
+            )
+        };
+        let frames = VirtAddr::from(
+            axalloc::global_allocator()
+                .alloc_pages(FRAMES_SIZE / PAGE_SIZE_4K, PAGE_SIZE_4K)
+                .map_err(|_| AxError::NoMemory)?,
+        );
+        if !HANDLER_REGISTERED.swap(true, Ordering::AcqRel) {
+            axhal::irq::register_handler(S_EXT, handle_host_irq);
+        }
+        info!(
+            "passthrough virtio-blk at {:#x}: DMA through bounce buffers",
+            base
+        );
+        Ok(Self {
+            vm,
+            base,
+            irq,
+            context: AtomicUsize::new(usize::MAX),
+            version,
+            host_features,
+            host_size_max,
+            frames,
+            queue: Mutex::new(ShadowQueue::default()),
+        })
+    }
+
+    /// The guest and host physical address of the device.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// The IRQ of the device, on both the host and the virtual PLIC.
+    pub fn irq(&self) -> usize {
+        self.irq
+    }
+
+    /// Routes the interrupt of the device to `hart`, the one running vCPU 0.
+    pub fn bind_hart(&self, hart: usize) {
+        let context = host_context(hart);
+        let old = self.context.swap(context, Ordering::AcqRel);
+        if old == context {
+            return;
+        }
+        if old != usize::MAX {
+            set_host_enable(old, self.irq, false);
+        }
+        unsafe {
+            plic_reg(self.irq * 4).write_volatile(1);
+            plic_reg(PLIC_CONTEXT_BASE + context * PLIC_CONTEXT_STRIDE).write_volatile(0);
+        }
+        set_host_enable(context, self.irq, true);
+    }
+
+    /// Resets the device, which stops the DMA set up by the guest.
+    pub fn reset(&self) {
+        let mut queue = self.queue.lock();
+        self.host_write(VIRTIO_REG_STATUS, 4, 0);
+        *queue = ShadowQueue::default();
+    }
+
+    /// Takes the interrupt claimed on the host, if any.
+    pub fn take_host_irq(&self) -> bool {
+        HOST_RAISED[self.irq].swap(false, Ordering::AcqRel)
+    }
+
+    /// Completes the interrupt on the host, once the guest completed it.
+    pub fn complete(&self) {
+        let context = HOST_CONTEXT[self.irq].load(Ordering::Relaxed);
+        let claim =
+            plic_reg(PLIC_CONTEXT_BASE + context * PLIC_CONTEXT_STRIDE + PLIC_CONTEXT_CLAIM);
+        unsafe { claim.write_volatile(self.irq as u32) };
+    }
+
+    /// Copies the buffers the device wrote back to the guest, and gives the
+    /// requests the device used back to it. Called when the device
+    /// interrupts, before the guest sees the interrupt.
+    pub fn complete_dma(&self) -> AxResult {
+        let mut queue = self.queue.lock();
+        let vm = self.vm.upgrade().ok_or(AxError::BadState)?;
+        let aspace = vm.aspace.lock();
+        self.pop_used(&mut queue, &GuestMemory::new(&aspace))
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == VIRTIO_VERSION_LEGACY
+    }
+
+    fn host_ptr(&self, offset: usize) -> *mut u8 {
+        phys_to_virt(PhysAddr::from(self.base + offset)).as_mut_ptr()
+    }
+
+    fn host_read(&self, offset: usize, size: usize) -> u64 {
+        let ptr = self.host_ptr(offset);
+        unsafe {
+            match size {
+                1 => (ptr as *const u8).read_volatile() as u64,
+                2 => (ptr as *const u16).read_volatile() as u64,
+                8 => (ptr as *const u64).read_volatile(),
+                _ => (ptr as *const u32).read_volatile() as u64,
+            }
+        }
+    }
+
+    fn host_write(&self, offset: usize, size: usize, val: u64) {
+        let ptr = self.host_ptr(offset);
+        unsafe {
+            match size {
+                1 => (ptr as *mut u8).write_volatile(val as u8),
+                2 => (ptr as *mut u16).write_volatile(val as u16),
+                8 => (ptr as *mut u64).write_volatile(val),
+                _ => (ptr as *mut u32).write_volatile(val as u32),
+            }
+        }
+    }
+
+    fn frame_ptr(&self, offset: usize) -> *mut u8 {
+        unsafe { self.frames.as_mut_ptr().add(offset) }
+    }
+
+    /// The host physical address the device sees at `offset` in the frames.
+    fn frame_pa(&self, offset: usize) -> u64 {
+        (virt_to_phys(self.frames).as_usize() + offset) as u64
+    }
+
+    /// Reads the device configuration, with the `size_max` of the bounce
+    /// chunks.
+    fn read_config(&self, offset: usize, size: usize) -> u64 {
+        let size_max = self
+            .host_size_max
+            .map_or(BOUNCE_CHUNK_SIZE as u32, |size_max| {
+                size_max.min(BOUNCE_CHUNK_SIZE as u32)
+            })
+            .to_le_bytes();
+        let mut val = [0u8; 8];
+        for (i, byte) in val.iter_mut().take(size).enumerate() {
+            let pos = offset + i;
+            *byte = match pos.checked_sub(BLK_CONFIG_SIZE_MAX) {
+                Some(i) if i < size_max.len() => size_max[i],
+                _ => self.host_read(VIRTIO_CONFIG_OFFSET + pos, 1) as u8,
+            };
+        }
+        u64::from_le_bytes(val)
+    }
+
+    /// Gives the shadow virtqueue to the device, for the virtqueue of the
+    /// guest set up in `queue`.
+    fn setup_queue(&self, queue: &mut ShadowQueue) {
+        if queue.num == 0 {
+            return;
+        }
+        queue.guest = Some(VirtQueue::new(
+            queue.num,
+            queue.desc,
+            queue.driver,
+            queue.device,
+        ));
+        queue.avail_idx = 0;
+        queue.last_used = 0;
+        unsafe { core::ptr::write_bytes(self.frame_ptr(0), 0, SHADOW_SIZE) };
+        if self.is_legacy() {
+            self.host_write(VIRTIO_REG_GUEST_PAGE_SIZE, 4, PAGE_SIZE_4K as u64);
+            self.host_write(VIRTIO_REG_QUEUE_ALIGN, 4, PAGE_SIZE_4K as u64);
+            self.host_write(
+                VIRTIO_REG_QUEUE_PFN,
+                4,
+                self.frame_pa(0) / PAGE_SIZE_4K as u64,
+            );
+        } else {
+            for (low, addr) in [
+                (VIRTIO_REG_QUEUE_DESC_LOW, self.frame_pa(0)),
+                (
+                    VIRTIO_REG_QUEUE_DRIVER_LOW,
+                    self.frame_pa(SHADOW_AVAIL_OFFSET),
+                ),
+                (
+                    VIRTIO_REG_QUEUE_DEVICE_LOW,
+                    self.frame_pa(SHADOW_USED_OFFSET),
+                ),
+            ] {
+                self.host_write(low, 4, addr & 0xffff_ffff);
+                self.host_write(low + 4, 4, addr >> 32);
+            }
+            self.host_write(VIRTIO_REG_QUEUE_READY, 4, 1);
+        }
+    }
+
+    /// Copies the requests made available by the guest to the shadow
+    /// virtqueue, with the buffers the device reads.
+    fn push_avail(&self, queue: &mut ShadowQueue, mem: &GuestMemory) -> AxResult {
+        let Some(guest) = &mut queue.guest else {
+            return Ok(());
+        };
+        while let Some(head) = guest.pop_avail(mem)? {
+            let mut index = head;
+            for desc in guest.chain(mem, head)? {
+                if desc.len as usize > BOUNCE_CHUNK_SIZE || desc.flags & VIRTQ_DESC_F_INDIRECT != 0
+                {
+                    return ax_err!(InvalidData, "passthrough buffer larger than size_max");
+                }
+                let offset = bounce_offset(index);
+                if !desc.is_write() {
+                    let chunk = unsafe {
+                        core::slice::from_raw_parts_mut(self.frame_ptr(offset), desc.len as usize)
+                    };
+                    mem.read(desc.gpa(), chunk)?;
+                }
+                let shadow = Desc {
+                    addr: self.frame_pa(offset),
+                    ..desc
+                };
+                unsafe {
+                    (self.frame_ptr(index as usize * 16) as *mut Desc).write_volatile(shadow)
+                };
+                index = desc.next;
+            }
+            let slot = SHADOW_AVAIL_OFFSET + 4 + (queue.avail_idx % queue.num) as usize * 2;
+            unsafe { (self.frame_ptr(slot) as *mut u16).write_volatile(head) };
+            queue.avail_idx = queue.avail_idx.wrapping_add(1);
+        }
+        // The device sees the entries before the index.
+        fence(Ordering::SeqCst);
+        unsafe {
+            (self.frame_ptr(SHADOW_AVAIL_OFFSET + 2) as *mut u16).write_volatile(queue.avail_idx)
+        };
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Copies the buffers of the requests used by the device back to the
+    /// guest, and gives the requests back to it.
+    fn pop_used(&self, queue: &mut ShadowQueue, mem: &GuestMemory) -> AxResult {
+        let Some(guest) = &mut queue.guest else {
+            return Ok(());
+        };
+        let used_idx =
+            unsafe { (self.frame_ptr(SHADOW_USED_OFFSET + 2) as *const u16).read_volatile() };
+        // The entries are read after the index.
+        fence(Ordering::SeqCst);
+        while queue.last_used != used_idx {
+            let elem = SHADOW_USED_OFFSET + 4 + (queue.last_used % queue.num) as usize * 8;
+            let [id, len] = unsafe { (self.frame_ptr(elem) as *const [u32; 2]).read_volatile() };
+            queue.last_used = queue.last_used.wrapping_add(1);
+            let head = id as u16;
+            let mut index = head;
+            let mut left = len as usize;
+            for desc in guest.chain(mem, head)? {
+                if desc.is_write() && left > 0 {
+                    let size = (desc.len as usize).min(left).min(BOUNCE_CHUNK_SIZE);
+                    let chunk = unsafe {
+                        core::slice::from_raw_parts(self.frame_ptr(bounce_offset(index)), size)
+                    };
+                    mem.write(desc.gpa(), chunk)?;
+                    left -= size;
+                }
+                index = desc.next;
+            }
+            guest.push_used(mem, head, len)?;
+        }
+        Ok(())
+    }
+}
+
+impl MmioDevice for PassthroughBlk {
+    fn read(&self, offset: usize, width: AccessWidth) -> AxResult<u64> {
+        if offset >= VIRTIO_CONFIG_OFFSET {
+            return Ok(self.read_config(offset - VIRTIO_CONFIG_OFFSET, width.size()));
+        }
+        let queue = self.queue.lock();
+        let legacy = self.is_legacy();
+        let val = match offset {
+            VIRTIO_REG_DEVICE_FEATURES if queue.device_features_sel == 0 => {
+                self.host_features & !HIDDEN_FEATURES | VIRTIO_BLK_F_SIZE_MAX
+            }
+            VIRTIO_REG_QUEUE_NUM_MAX if queue.queue_sel == 0 => {
+                (self.host_read(offset, 4) as u32).min(MAX_QUEUE_SIZE as u32)
+            }
+            VIRTIO_REG_QUEUE_PFN if legacy && queue.queue_sel == 0 => queue.queue_pfn,
+            VIRTIO_REG_QUEUE_READY if !legacy && queue.queue_sel == 0 => {
+                queue.guest.is_some() as u32
+            }
+            _ => self.host_read(offset, 4) as u32,
+        };
+        Ok(val as u64)
+    }
+
+    fn write(&self, offset: usize, width: AccessWidth, val: u64) -> AxResult {
+        if offset >= VIRTIO_CONFIG_OFFSET {
+            self.host_write(offset, width.size(), val);
+            return Ok(());
+        }
+        let mut queue = self.queue.lock();
+        let legacy = self.is_legacy();
+        let val = val as u32;
+        let set_low = |old: u64| old & !0xffff_ffff | val as u64;
+        let set_high = |old: u64| old & 0xffff_ffff | (val as u64) << 32;
+        let mut forwarded = Some(val);
+        match offset {
+            VIRTIO_REG_DEVICE_FEATURES_SEL => queue.device_features_sel = val,
+            VIRTIO_REG_DRIVER_FEATURES_SEL => queue.driver_features_sel = val,
+            // `VIRTIO_BLK_F_SIZE_MAX` may not be the one of the device.
+            VIRTIO_REG_DRIVER_FEATURES if queue.driver_features_sel == 0 => {
+                forwarded = Some(val & self.host_features & !HIDDEN_FEATURES);
+            }
+            VIRTIO_REG_QUEUE_SEL => queue.queue_sel = val,
+            VIRTIO_REG_GUEST_PAGE_SIZE if legacy => {
+                queue.guest_page_size = val;
+                forwarded = Some(PAGE_SIZE_4K as u32);
+            }
+            VIRTIO_REG_QUEUE_NOTIFY if val == 0 => {
+                let vm = self.vm.upgrade().ok_or(AxError::BadState)?;
+                let aspace = vm.aspace.lock();
+                self.push_avail(&mut queue, &GuestMemory::new(&aspace))?;
+            }
+            VIRTIO_REG_STATUS if val == 0 => *queue = ShadowQueue::default(),
+            _ if queue.queue_sel != 0 => {}
+            VIRTIO_REG_QUEUE_NUM => {
+                if val > MAX_QUEUE_SIZE as u32 {
+                    warn!("passthrough virtio-blk: queue of {} entries", val);
+                }
+                queue.num = val.min(MAX_QUEUE_SIZE as u32) as u16;
+                forwarded = Some(queue.num as u32);
+            }
+            VIRTIO_REG_QUEUE_ALIGN if legacy => {
+                queue.queue_align = val;
+                forwarded = Some(PAGE_SIZE_4K as u32);
+            }
+            VIRTIO_REG_QUEUE_PFN if legacy => {
+                queue.queue_pfn = val;
+                if val != 0 {
+                    // The layout of the legacy interface.
+                    let num = queue.num as usize;
+                    let desc = val as usize * queue.guest_page_size as usize;
+                    let driver = desc + num * 16;
+                    let device = align_up(driver + 6 + num * 2, queue.queue_align.max(1) as usize);
+                    queue.desc = desc as u64;
+                    queue.driver = driver as u64;
+                    queue.device = device as u64;
+                    self.setup_queue(&mut queue);
+                    forwarded = None;
+                } else {
+                    queue.guest = None;
+                }
+            }
+            VIRTIO_REG_QUEUE_READY if !legacy => {
+                if val & 1 != 0 {
+                    self.setup_queue(&mut queue);
+                    forwarded = None;
+                } else {
+                    queue.guest = None;
+                }
+            }
+            VIRTIO_REG_QUEUE_DESC_LOW if !legacy => queue.desc = set_low(queue.desc),
+            VIRTIO_REG_QUEUE_DESC_HIGH if !legacy => queue.desc = set_high(queue.desc),
+            VIRTIO_REG_QUEUE_DRIVER_LOW if !legacy => queue.driver = set_low(queue.driver),
+            VIRTIO_REG_QUEUE_DRIVER_HIGH if !legacy => queue.driver = set_high(queue.driver),
+            VIRTIO_REG_QUEUE_DEVICE_LOW if !legacy => queue.device = set_low(queue.device),
+            VIRTIO_REG_QUEUE_DEVICE_HIGH if !legacy => queue.device = set_high(queue.device),
+            _ => {}
+        }
+        // The addresses of the queue of the guest never reach the device.
+        if matches!(
+            offset,
+            VIRTIO_REG_QUEUE_DESC_LOW..=VIRTIO_REG_QUEUE_DEVICE_HIGH
+        ) && !legacy
+            && queue.queue_sel == 0
+        {
+            forwarded = None;
+        }
+        if let Some(val) = forwarded {
+            self.host_write(offset, 4, val as u64);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PassthroughBlk {
+    fn drop(&mut self) {
+        let context = *self.context.get_mut();
+        if context != usize::MAX {
+            set_host_enable(context, self.irq, false);
+        }
+        self.reset();
+        axalloc::global_allocator()
+            .dealloc_pages(self.frames.as_usize(), FRAMES_SIZE / PAGE_SIZE_4K);
+    }
+}
@@ -0,0 +1,149 @@
+//! A parallel flash, backed by a file of the host filesystem.
+//!
+//! The flash is memory for the guest: each page is mapped read-only, filled
+//! with the content of the file, the first time the guest reads it. The page
+//! is filled before it is mapped, so other vCPUs never see it half filled.
+//! Writes fault, and go to both the file and the mapped page, so the file
+//! always holds the content seen by the guest. The CFI command set is not
+//! emulated.
+
+use alloc::sync::Weak;
+use alloc::vec::Vec;
+use axerrno::{ax_err, AxError, AxResult};
+use axhal::mem::{virt_to_phys, VirtAddr};
+use axhal::paging::MappingFlags;
+use axmm::AddrSpace;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K};
+use riscv_vcpu::AccessWidth;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+
+use super::MmioDevice;
+use crate::vm::Vm;
+
+/// The guest physical address of the pflash#2 of QEMU virt, the default one.
+pub const PFLASH_BASE: usize = 0x2200_0000;
+
+/// An emulated flash backed by a file.
+pub struct VirtPflash {
+    vm: Weak<Vm>,
+    base: usize,
+    /// The size of the file, rounded up to pages.
+    size: usize,
+    file: Mutex<File>,
+    /// The host pages mapped to the guest.
+    frames: Mutex<Vec<VirtAddr>>,
+}
+
+impl VirtPflash {
+    /// Creates a flash for `vm` at the guest physical address `base`, backed
+    /// by the file at `path`.
+    pub fn new(vm: Weak<Vm>, base: usize, path: &str) -> AxResult<Self> {
+        if !base.is_aligned_4k() {
+            return ax_err!(InvalidInput, "pflash base not aligned");
+        }
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|_| AxError::NotFound)?;
+        let size = file.metadata().map_err(|_| AxError::Io)?.size() as usize;
+        if size == 0 {
+            return ax_err!(InvalidInput, "empty pflash file");
+        }
+        Ok(Self {
+            vm,
+            base,
+            size: size.align_up_4k(),
+            file: Mutex::new(file),
+            frames: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads the file at `offset` to `buf`. Past the end of the file, the
+    /// flash reads as erased.
+    fn read_file(&self, offset: usize, buf: &mut [u8]) -> AxResult {
+        buf.fill(0xff);
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(offset as u64))
+            .map_err(|_| AxError::Io)?;
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read(&mut buf[read..]).map_err(|_| AxError::Io)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MmioDevice for VirtPflash {
+    fn read(&self, offset: usize, width: AccessWidth) -> AxResult<u64> {
+        let mut buf = [0u8; 8];
+        self.read_file(offset, &mut buf[..width.size()])?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn write(&self, offset: usize, width: AccessWidth, val: u64) -> AxResult {
+        let data = &val.to_le_bytes()[..width.size()];
+        {
+            let mut file = self.file.lock();
+            file.seek(SeekFrom::Start(offset as u64))
+                .map_err(|_| AxError::Io)?;
+            file.write_all(data).map_err(|_| AxError::Io)?;
+        }
+        // The page is mapped read-only if the guest has read it.
+        let vm = self.vm.upgrade().ok_or(AxError::BadState)?;
+        let aspace = vm.aspace.lock();
+        if aspace
+            .page_table()
+            .query((self.base + offset).into())
+            .is_ok()
+        {
+            aspace.write((self.base + offset).into(), data)?;
+        }
+        Ok(())
+    }
+
+    fn map_page(&self, aspace: &mut AddrSpace, offset: usize) -> AxResult<bool> {
+        let gpa = (self.base + offset.align_down_4k()).into();
+        // Other vCPUs may have mapped it already.
+        if aspace.page_table().query(gpa).is_ok() {
+            return Ok(true);
+        }
+        let frame = VirtAddr::from(
+            axalloc::global_allocator()
+                .alloc_pages(1, PAGE_SIZE_4K)
+                .map_err(|_| AxError::NoMemory)?,
+        );
+        let page = unsafe { core::slice::from_raw_parts_mut(frame.as_mut_ptr(), PAGE_SIZE_4K) };
+        let flags = MappingFlags::READ | MappingFlags::EXECUTE | MappingFlags::USER;
+        let res = self
+            .read_file(offset.align_down_4k(), page)
+            .and_then(|_| aspace.map_linear(gpa, virt_to_phys(frame), PAGE_SIZE_4K, flags));
+        if let Err(err) = res {
+            axalloc::global_allocator().dealloc_pages(frame.as_usize(), 1);
+            return Err(err);
+        }
+        self.frames.lock().push(frame);
+        Ok(true)
+    }
+}
+
+impl Drop for VirtPflash {
+    fn drop(&mut self) {
+        for frame in self.frames.get_mut().drain(..) {
+            axalloc::global_allocator().dealloc_pages(frame.as_usize(), 1);
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! A virtual PLIC, the interrupt controller of the devices of the guest.
+//!
+//! It has the layout of the PLIC of QEMU virt, with two contexts per vCPU:
+//! context `2n` for machine mode, never delivered, and context `2n + 1` for
+//! supervisor mode, delivered to vCPU `n` as a VS-level external interrupt.
+//!
+//! The emulated devices drive level-triggered sources. The interrupts of
+//! passthrough devices are forwarded from the host PLIC: they are raised
+//! once claimed on the host, and completed on the host once the guest
+//! completes them.
+
+use alloc::sync::Weak;
+use alloc::vec;
+use alloc::vec::Vec;
+use axerrno::AxResult;
+use riscv_vcpu::AccessWidth;
+use std::sync::{Mutex, MutexGuard};
+
+use super::MmioDevice;
+use crate::vm::Vm;
+
+/// The guest physical address of the PLIC of QEMU virt.
+pub const PLIC_BASE: usize = 0x0c00_0000;
+/// The size of the PLIC registers.
+pub const PLIC_SIZE: usize = 0x60_0000;
+
+/// The number of interrupt sources, plus the reserved source 0.
+pub const NUM_SOURCES: usize = 96;
+const WORDS: usize = NUM_SOURCES.div_ceil(32);
+
+const PRIORITY_BASE: usize = 0x0;
+const PENDING_BASE: usize = 0x1000;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// The priority has 3 bits.
+const PRIORITY_MASK: u32 = 0x7;
+
+type Bits = [u32; WORDS];
+
+fn test_bit(bits: &Bits, n: usize) -> bool {
+    bits[n / 32] & (1 << (n % 32)) != 0
+}
+
+fn set_bit(bits: &mut Bits, n: usize, val: bool) {
+    if val {
+        bits[n / 32] |= 1 << (n % 32);
+    } else {
+        bits[n / 32] &= !(1 << (n % 32));
+    }
+}
+
+struct PlicState {
+    priority: [u32; NUM_SOURCES],
+    pending: Bits,
+    /// Claimed by a context, not completed yet.
+    claimed: Bits,
+    /// The levels of the level-triggered sources.
+    level: Bits,
+    /// The sources forwarded from the host PLIC.
+    forwarded: Bits,
+    /// The forwarded sources completed by the guest, not yet on the host.
+    completed: Bits,
+    enable: Vec<Bits>,
+    threshold: Vec<u32>,
+    /// Whether each vCPU has been told about a pending interrupt.
+    signaled: Vec<bool>,
+}
+
+impl PlicState {
+    /// The pending interrupt with the highest priority for `context`.
+    fn best(&self, context: usize) -> Option<usize> {
+        let mut best = None;
+        let mut best_priority = self.threshold[context];
+        for irq in 1..NUM_SOURCES {
+            if test_bit(&self.pending, irq)
+                && test_bit(&self.enable[context], irq)
+                && self.priority[irq] > best_priority
+            {
+                best = Some(irq);
+                best_priority = self.priority[irq];
+            }
+        }
+        best
+    }
+
+    fn claim(&mut self, context: usize) -> usize {
+        let Some(irq) = self.best(context) else {
+            return 0;
+        };
+        set_bit(&mut self.pending, irq, false);
+        set_bit(&mut self.claimed, irq, true);
+        irq
+    }
+
+    fn complete(&mut self, irq: usize) {
+        if irq == 0 || irq >= NUM_SOURCES || !test_bit(&self.claimed, irq) {
+            return;
+        }
+        set_bit(&mut self.claimed, irq, false);
+        if test_bit(&self.forwarded, irq) {
+            set_bit(&mut self.completed, irq, true);
+        } else if test_bit(&self.level, irq) {
+            set_bit(&mut self.pending, irq, true);
+        }
+    }
+}
+
+/// An emulated PLIC.
+pub struct VPlic {
+    vm: Weak<Vm>,
+    state: Mutex<PlicState>,
+}
+
+impl VPlic {
+    /// Creates the PLIC of `vm`, with `num_vcpus` vCPUs.
+    pub fn new(vm: Weak<Vm>, num_vcpus: usize) -> Self {
+        let num_contexts = num_vcpus * 2;
+        Self {
+            vm,
+            state: Mutex::new(PlicState {
+                priority: [0; NUM_SOURCES],
+                pending: [0; WORDS],
+                claimed: [0; WORDS],
+                level: [0; WORDS],
+                forwarded: [0; WORDS],
+                completed: [0; WORDS],
+                enable: vec![[0; WORDS]; num_contexts],
+                threshold: vec![0; num_contexts],
+                signaled: vec![false; num_vcpus],
+            }),
+        }
+    }
+
+    /// Sets the level of the level-triggered source `irq`.
+    pub fn set_level(&self, irq: usize, level: bool) {
+        let mut state = self.state.lock();
+        if test_bit(&state.level, irq) == level {
+            return;
+        }
+        set_bit(&mut state.level, irq, level);
+        if !test_bit(&state.claimed, irq) {
+            set_bit(&mut state.pending, irq, level);
+        }
+        self.signal(state);
+    }
+
+    /// Makes `irq` a source forwarded from the host PLIC.
+    pub fn set_forwarded(&self, irq: usize) {
+        set_bit(&mut self.state.lock().forwarded, irq, true);
+    }
+
+    /// Raises the forwarded source `irq`, claimed on the host.
+    pub fn raise(&self, irq: usize) {
+        let mut state = self.state.lock();
+        set_bit(&mut state.pending, irq, true);
+        self.signal(state);
+    }
+
+    /// Takes whether the forwarded source `irq` was completed by the guest,
+    /// so it is to be completed on the host.
+    pub fn take_completed(&self, irq: usize) -> bool {
+        let mut state = self.state.lock();
+        let completed = test_bit(&state.completed, irq);
+        set_bit(&mut state.completed, irq, false);
+        completed
+    }
+
+    /// Resets the PLIC for a reboot of the guest. The forwarded sources
+    /// pending or claimed by the guest are to be completed on the host.
+    pub fn reset(&self) {
+        let mut state = self.state.lock();
+        for word in 0..WORDS {
+            state.completed[word] |=
+                (state.pending[word] | state.claimed[word]) & state.forwarded[word];
+        }
+        state.priority = [0; NUM_SOURCES];
+        // The levels of the devices stay, pending once enabled again.
+        state.pending = state.level;
+        state.claimed = [0; WORDS];
+        state.enable.fill([0; WORDS]);
+        state.threshold.fill(0);
+        state.signaled.fill(false);
+    }
+
+    /// Whether an interrupt is pending for vCPU `id`.
+    pub fn irq_pending(&self, id: usize) -> bool {
+        self.state.lock().best(id * 2 + 1).is_some()
+    }
+
+    /// Kicks the vCPUs which got a pending interrupt, so they see it.
+    fn signal(&self, mut state: MutexGuard<PlicState>) {
+        let mut newly_pending = Vec::new();
+        for id in 0..state.signaled.len() {
+            let pending = state.best(id * 2 + 1).is_some();
+            if pending && !state.signaled[id] {
+                newly_pending.push(id);
+            }
+            state.signaled[id] = pending;
+        }
+        drop(state);
+        if let Some(vm) = self.vm.upgrade() {
+            for id in newly_pending {
+                vm.kick(id);
+            }
+        }
+    }
+}
+
+impl MmioDevice for VPlic {
+    fn read(&self, offset: usize, _width: AccessWidth) -> AxResult<u64> {
+        let mut state = self.state.lock();
+        let num_contexts = state.threshold.len();
+        let val = match offset {
+            PRIORITY_BASE..PENDING_BASE => state
+                .priority
+                .get((offset - PRIORITY_BASE) / 4)
+                .copied()
+                .unwrap_or(0),
+            PENDING_BASE..ENABLE_BASE => state
+                .pending
+                .get((offset - PENDING_BASE) / 4)
+                .copied()
+                .unwrap_or(0),
+            ENABLE_BASE..CONTEXT_BASE => {
+                let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                match state.enable.get(context) {
+                    Some(enable) if word < WORDS => enable[word],
+                    _ => 0,
+                }
+            }
+            _ => {
+                let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if context >= num_contexts {
+                    0
+                } else {
+                    match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                        0 => state.threshold[context],
+                        CONTEXT_CLAIM => {
+                            let irq = state.claim(context) as u32;
+                            self.signal(state);
+                            return Ok(irq as u64);
+                        }
+                        _ => 0,
+                    }
+                }
+            }
+        };
+        Ok(val as u64)
+    }
+
+    fn write(&self, offset: usize, _width: AccessWidth, val: u64) -> AxResult {
+        let val = val as u32;
+        let mut state = self.state.lock();
+        let num_contexts = state.threshold.len();
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => {
+                let irq = (offset - PRIORITY_BASE) / 4;
+                if (1..NUM_SOURCES).contains(&irq) {
+                    state.priority[irq] = val & PRIORITY_MASK;
+                }
+            }
+            // The pending bits are read-only.
+            PENDING_BASE..ENABLE_BASE => return Ok(()),
+            ENABLE_BASE..CONTEXT_BASE => {
+                let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                if context < num_contexts && word < WORDS {
+                    // Source 0 does not exist.
+                    let mask = if word == 0 { !1 } else { !0 };
+                    state.enable[context][word] = val & mask;
+                }
+            }
+            _ => {
+                let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if context < num_contexts {
+                    match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                        0 => state.threshold[context] = val & PRIORITY_MASK,
+                        CONTEXT_CLAIM => state.complete(val as usize),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        self.signal(state);
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! A virtual NS16550 UART, at the address of the one of QEMU virt.
+//!
+//! The bytes written by the guest go to the host console, and the bytes typed
+//! on the host console are received by the guest, with the interrupts of the
+//! 8250 family. The line settings are kept but have no effect. The escape
+//! sequences of the monitor are filtered out of the input, see the `monitor`
+//! module.
+
+use alloc::collections::VecDeque;
+use axerrno::AxResult;
+use riscv_vcpu::AccessWidth;
+use std::sync::Mutex;
+
+use super::MmioDevice;
+use crate::monitor;
+
+/// The guest physical address of the UART.
+pub const UART_BASE: usize = 0x1000_0000;
+/// The size of the UART registers.
+pub const UART_SIZE: usize = 0x100;
+/// The interrupt of the UART in the PLIC.
+pub const UART_IRQ: usize = 10;
+
+/// The size of the receive FIFO.
+const RX_FIFO_SIZE: usize = 16;
+
+const RBR_THR: usize = 0;
+const IER: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_RDI: u8 = 0x01;
+const IER_THRI: u8 = 0x02;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_ENABLE_FIFO: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+
+const LCR_DLAB: u8 = 0x80;
+const MCR_LOOP: u8 = 0x10;
+
+const LSR_DR: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+/// The modem is always ready: DCD, DSR and CTS are set.
+const MSR_READY: u8 = 0xb0;
+
+#[derive(Default)]
+struct UartRegs {
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    /// The transmitter is empty and its interrupt not acknowledged yet.
+    thr_empty: bool,
+    rx: VecDeque<u8>,
+}
+
+impl UartRegs {
+    fn iir(&self) -> u8 {
+        let fifo = if self.fcr & FCR_ENABLE_FIFO != 0 {
+            IIR_FIFO_ENABLED
+        } else {
+            0
+        };
+        let id = if self.ier & IER_RDI != 0 && !self.rx.is_empty() {
+            IIR_RDI
+        } else if self.ier & IER_THRI != 0 && self.thr_empty {
+            IIR_THRI
+        } else {
+            IIR_NO_INT
+        };
+        fifo | id
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() < RX_FIFO_SIZE {
+            self.rx.push_back(byte);
+        }
+    }
+}
+
+/// A virtual NS16550 UART connected to the host console.
+#[derive(Default)]
+pub struct VUart {
+    regs: Mutex<UartRegs>,
+}
+
+impl VUart {
+    /// Moves the input of the host console to the receive FIFO, as long as
+    /// it has room, unless the input goes to the monitor.
+    pub fn poll_input(&self) {
+        let mut regs = self.regs.lock();
+        while regs.rx.len() < RX_FIFO_SIZE && !monitor::active() {
+            match axhal::console::getchar() {
+                Some(byte) => {
+                    if let Some(byte) = monitor::filter_input(byte) {
+                        regs.receive(byte);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Whether the UART raises its interrupt.
+    pub fn irq_level(&self) -> bool {
+        self.regs.lock().iir() & IIR_NO_INT == 0
+    }
+}
+
+impl MmioDevice for VUart {
+    fn read(&self, offset: usize, _width: AccessWidth) -> AxResult<u64> {
+        let mut regs = self.regs.lock();
+        let dlab = regs.lcr & LCR_DLAB != 0;
+        let val = match offset {
+            RBR_THR if dlab => regs.dll,
+            RBR_THR => regs.rx.pop_front().unwrap_or(0),
+            IER if dlab => regs.dlm,
+            IER => regs.ier,
+            IIR_FCR => {
+                let iir = regs.iir();
+                // Reading the IIR acknowledges the transmitter interrupt.
+                if iir & 0x0f == IIR_THRI {
+                    regs.thr_empty = false;
+                }
+                iir
+            }
+            LCR => regs.lcr,
+            MCR => regs.mcr,
+            LSR => {
+                let dr = if regs.rx.is_empty() { 0 } else { LSR_DR };
+                // Bytes are sent as soon as they are written.
+                dr | LSR_THRE | LSR_TEMT
+            }
+            MSR => MSR_READY,
+            SCR => regs.scr,
+            _ => 0,
+        };
+        Ok(val as u64)
+    }
+
+    fn write(&self, offset: usize, _width: AccessWidth, val: u64) -> AxResult {
+        let val = val as u8;
+        let mut regs = self.regs.lock();
+        let dlab = regs.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => regs.dll = val,
+            RBR_THR => {
+                if regs.mcr & MCR_LOOP != 0 {
+                    regs.receive(val);
+                } else {
+                    axhal::console::putchar(val);
+                }
+                regs.thr_empty = true;
+            }
+            IER if dlab => regs.dlm = val,
+            IER => {
+                // Enabling the transmitter interrupt raises it at once, the
+                // transmitter being always empty.
+                if val & IER_THRI != 0 && regs.ier & IER_THRI == 0 {
+                    regs.thr_empty = true;
+                }
+                regs.ier = val & 0x0f;
+            }
+            IIR_FCR => {
+                if val & FCR_CLEAR_RX != 0 {
+                    regs.rx.clear();
+                }
+                regs.fcr = val;
+            }
+            LCR => regs.lcr = val,
+            MCR => regs.mcr = val,
+            SCR => regs.scr = val,
+            // LSR and MSR are read-only.
+            _ => {}
+        }
+        Ok(())
+    }
+}
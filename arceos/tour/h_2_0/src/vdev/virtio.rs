@@ -0,0 +1,291 @@
+//! The virtio-mmio transport (virtio 1.x) and split virtqueues shared by the
+//! virtio device models.
+
+use alloc::vec::Vec;
+use axerrno::{ax_err, AxResult};
+use memory_addr::VirtAddr;
+
+use crate::guest_mem::{GuestMemory, Pod};
+
+/// The size of the registers of a virtio-mmio device.
+pub const VIRTIO_MMIO_SIZE: usize = 0x1000;
+
+/// The offset of the device specific configuration.
+pub const CONFIG_OFFSET: usize = 0x100;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+pub const STATUS_DRIVER_OK: u32 = 4;
+pub const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+pub const INT_USED_BUFFER: u32 = 1;
+pub const INT_CONFIG_CHANGE: u32 = 2;
+
+const MAGIC: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x554d_4551;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+mod reg {
+    pub const MAGIC_VALUE: usize = 0x000;
+    pub const VERSION: usize = 0x004;
+    pub const DEVICE_ID: usize = 0x008;
+    pub const VENDOR_ID: usize = 0x00c;
+    pub const DEVICE_FEATURES: usize = 0x010;
+    pub const DEVICE_FEATURES_SEL: usize = 0x014;
+    pub const DRIVER_FEATURES: usize = 0x020;
+    pub const DRIVER_FEATURES_SEL: usize = 0x024;
+    pub const QUEUE_SEL: usize = 0x030;
+    pub const QUEUE_NUM_MAX: usize = 0x034;
+    pub const QUEUE_NUM: usize = 0x038;
+    pub const QUEUE_READY: usize = 0x044;
+    pub const QUEUE_NOTIFY: usize = 0x050;
+    pub const INTERRUPT_STATUS: usize = 0x060;
+    pub const INTERRUPT_ACK: usize = 0x064;
+    pub const STATUS: usize = 0x070;
+    pub const QUEUE_DESC_LOW: usize = 0x080;
+    pub const QUEUE_DESC_HIGH: usize = 0x084;
+    pub const QUEUE_DRIVER_LOW: usize = 0x090;
+    pub const QUEUE_DRIVER_HIGH: usize = 0x094;
+    pub const QUEUE_DEVICE_LOW: usize = 0x0a0;
+    pub const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+    pub const CONFIG_GENERATION: usize = 0x0fc;
+}
+
+/// A descriptor of a virtqueue.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Desc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+// SAFETY: `repr(C)` without padding.
+unsafe impl Pod for Desc {}
+
+impl Desc {
+    /// Whether the device writes the buffer, otherwise it reads it.
+    pub fn is_write(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_WRITE != 0
+    }
+
+    /// The guest physical address of the buffer.
+    pub fn gpa(&self) -> VirtAddr {
+        VirtAddr::from(self.addr as usize)
+    }
+}
+
+/// A split virtqueue.
+#[derive(Default)]
+pub struct VirtQueue {
+    num: u16,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    /// The next entry of the available ring to process.
+    last_avail: u16,
+}
+
+impl VirtQueue {
+    /// Creates a queue of `num` entries set up by the driver, whose
+    /// descriptor table, driver area and device area are at the given guest
+    /// physical addresses.
+    pub fn new(num: u16, desc: u64, driver: u64, device: u64) -> Self {
+        Self {
+            num,
+            ready: true,
+            desc,
+            driver,
+            device,
+            last_avail: 0,
+        }
+    }
+
+    /// Whether the driver has set up the queue.
+    pub fn is_ready(&self) -> bool {
+        self.ready && self.num != 0
+    }
+
+    /// Takes the next descriptor chain made available by the driver, and
+    /// returns its head.
+    pub fn pop_avail(&mut self, mem: &GuestMemory) -> AxResult<Option<u16>> {
+        if !self.is_ready() {
+            return ax_err!(BadState, "virtqueue not ready");
+        }
+        if self.last_avail == mem.read_obj::<u16>(gpa(self.driver + 2))? {
+            return Ok(None);
+        }
+        let slot = (self.last_avail % self.num) as u64;
+        let head = mem.read_obj::<u16>(gpa(self.driver + 4 + slot * 2))?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        Ok(Some(head))
+    }
+
+    /// Returns the descriptors of the chain at `head`.
+    pub fn chain(&self, mem: &GuestMemory, head: u16) -> AxResult<Vec<Desc>> {
+        let mut chain = Vec::new();
+        let mut desc = self.read_desc(mem, head)?;
+        chain.push(desc);
+        while desc.flags & VIRTQ_DESC_F_NEXT != 0 {
+            if chain.len() >= self.num as usize {
+                return ax_err!(InvalidData, "descriptor chain loop");
+            }
+            desc = self.read_desc(mem, desc.next)?;
+            chain.push(desc);
+        }
+        Ok(chain)
+    }
+
+    /// Gives the chain at `head` back to the driver, with `len` bytes written.
+    pub fn push_used(&mut self, mem: &GuestMemory, head: u16, len: u32) -> AxResult {
+        let used_idx = mem.read_obj::<u16>(gpa(self.device + 2))?;
+        let elem = self.device + 4 + (used_idx % self.num) as u64 * 8;
+        mem.write_obj(gpa(elem), &[head as u32, len])?;
+        mem.write_obj(gpa(self.device + 2), &used_idx.wrapping_add(1))
+    }
+
+    fn read_desc(&self, mem: &GuestMemory, index: u16) -> AxResult<Desc> {
+        if index >= self.num {
+            return ax_err!(InvalidData, "bad descriptor index");
+        }
+        mem.read_obj(gpa(self.desc + index as u64 * 16))
+    }
+}
+
+/// The state of the virtio-mmio registers of a device with `N` queues.
+pub struct VirtioMmio<const N: usize> {
+    device_id: u32,
+    features: u64,
+    queue_size: u16,
+    pub status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    pub driver_features: u64,
+    pub interrupt_status: u32,
+    queue_sel: u32,
+    pub queues: [VirtQueue; N],
+}
+
+impl<const N: usize> VirtioMmio<N> {
+    /// Creates the registers of a device of `device_id` offering `features`,
+    /// whose queues have up to `queue_size` entries.
+    pub fn new(device_id: u32, features: u64, queue_size: u16) -> Self {
+        Self {
+            device_id,
+            features: features | VIRTIO_F_VERSION_1,
+            queue_size,
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            interrupt_status: 0,
+            queue_sel: 0,
+            queues: core::array::from_fn(|_| VirtQueue::default()),
+        }
+    }
+
+    /// Whether the driver is ready, and the device does not wait for a
+    /// reset.
+    pub fn driver_ok(&self) -> bool {
+        self.status & (STATUS_DRIVER_OK | STATUS_DEVICE_NEEDS_RESET) == STATUS_DRIVER_OK
+    }
+
+    /// Stops the device after an error of the driver, e.g. a malformed
+    /// queue, until the driver resets it, and notifies the driver.
+    pub fn set_needs_reset(&mut self) {
+        self.status |= STATUS_DEVICE_NEEDS_RESET;
+        self.interrupt_status |= INT_CONFIG_CHANGE;
+    }
+
+    fn selected(&mut self) -> Option<&mut VirtQueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Reads the transport register at `offset`, below [`CONFIG_OFFSET`].
+    pub fn read(&mut self, offset: usize) -> u32 {
+        match offset {
+            reg::MAGIC_VALUE => MAGIC,
+            reg::VERSION => VERSION,
+            reg::DEVICE_ID => self.device_id,
+            reg::VENDOR_ID => VENDOR_ID,
+            reg::DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features as u32,
+                1 => (self.features >> 32) as u32,
+                _ => 0,
+            },
+            reg::QUEUE_NUM_MAX => match self.selected() {
+                Some(_) => self.queue_size as u32,
+                None => 0,
+            },
+            reg::QUEUE_READY => self.selected().map_or(0, |q| q.ready as u32),
+            reg::INTERRUPT_STATUS => self.interrupt_status,
+            reg::STATUS => self.status,
+            reg::CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    /// Writes the transport register at `offset`, below [`CONFIG_OFFSET`].
+    ///
+    /// Returns the queue notified by the driver, if any.
+    pub fn write(&mut self, offset: usize, val: u32) -> Option<usize> {
+        let set_low = |old: u64| old & !0xffff_ffff | val as u64;
+        let set_high = |old: u64| old & 0xffff_ffff | (val as u64) << 32;
+        let queue_size = self.queue_size;
+        match offset {
+            reg::DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            reg::DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            reg::DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = set_low(self.driver_features),
+                1 => self.driver_features = set_high(self.driver_features),
+                _ => {}
+            },
+            reg::QUEUE_SEL => self.queue_sel = val,
+            reg::QUEUE_NOTIFY => return ((val as usize) < N).then_some(val as usize),
+            reg::INTERRUPT_ACK => self.interrupt_status &= !val,
+            reg::STATUS => {
+                // Writing 0 resets the device.
+                if val == 0 {
+                    *self = Self::new(self.device_id, self.features, queue_size);
+                } else {
+                    // Only a reset clears `DEVICE_NEEDS_RESET`.
+                    self.status = val | self.status & STATUS_DEVICE_NEEDS_RESET;
+                }
+            }
+            _ => {
+                let Some(q) = self.selected() else {
+                    return None;
+                };
+                match offset {
+                    reg::QUEUE_NUM => q.num = (val as u16).min(queue_size),
+                    reg::QUEUE_READY => q.ready = val & 1 != 0,
+                    reg::QUEUE_DESC_LOW => q.desc = set_low(q.desc),
+                    reg::QUEUE_DESC_HIGH => q.desc = set_high(q.desc),
+                    reg::QUEUE_DRIVER_LOW => q.driver = set_low(q.driver),
+                    reg::QUEUE_DRIVER_HIGH => q.driver = set_high(q.driver),
+                    reg::QUEUE_DEVICE_LOW => q.device = set_low(q.device),
+                    reg::QUEUE_DEVICE_HIGH => q.device = set_high(q.device),
+                    _ => warn!("virtio: write to read-only register {:#x}", offset),
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Reads `width` bytes at `offset` in the device configuration `config`.
+pub fn read_config(config: &[u8], offset: usize, width: usize) -> u64 {
+    let mut val = [0u8; 8];
+    for (i, byte) in val.iter_mut().take(width).enumerate() {
+        *byte = config.get(offset + i).copied().unwrap_or(0);
+    }
+    u64::from_le_bytes(val)
+}
+
+fn gpa(addr: u64) -> VirtAddr {
+    VirtAddr::from(addr as usize)
+}
@@ -0,0 +1,247 @@
+//! A virtio-balloon device, through which the hypervisor reclaims guest RAM.
+//!
+//! The hypervisor sets the number of pages it wants, and the guest gives
+//! them in the inflate queue. Their frames are unmapped from the guest and
+//! returned to the host allocator. The guest may use the pages again anytime
+//! (`VIRTIO_BALLOON_F_MUST_TELL_HOST` is not offered), they are allocated on
+//! demand again, like the guest RAM never touched.
+//!
+//! The queues are processed by a task of the device, as the frames are freed
+//! only once all the vCPUs flushed their TLBs, which the vCPUs can not wait
+//! for themselves.
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use axerrno::AxResult;
+use axhal::mem::phys_to_virt;
+use axhal::paging::MappingFlags;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use memory_addr::{PhysAddr, VirtAddr, PAGE_SIZE_4K};
+use riscv_vcpu::AccessWidth;
+use std::sync::Mutex;
+use std::thread;
+
+use super::virtio::{self, VirtioMmio, CONFIG_OFFSET, INT_USED_BUFFER};
+use super::MmioDevice;
+use crate::guest_mem::GuestMemory;
+use crate::log_ctx;
+use crate::vm::Vm;
+
+/// The guest physical address of the device, the third virtio-mmio slot of
+/// QEMU virt.
+pub const VIRTIO_BALLOON_BASE: usize = 0x1000_3000;
+/// The size of the device registers.
+pub const VIRTIO_BALLOON_SIZE: usize = virtio::VIRTIO_MMIO_SIZE;
+/// The interrupt of the device in the PLIC.
+pub const VIRTIO_BALLOON_IRQ: usize = 3;
+
+const DEVICE_ID_BALLOON: u32 = 5;
+
+const QUEUE_SIZE: u16 = 128;
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+
+const INT_CONFIG_CHANGE: u32 = 2;
+
+/// The offsets of `num_pages` and `actual` in the configuration.
+const CONFIG_NUM_PAGES: usize = 0;
+const CONFIG_ACTUAL: usize = 4;
+
+/// The page size of the balloon protocol, whatever the guest page size.
+const BALLOON_PFN_SHIFT: usize = 12;
+
+/// The period the task checks the notifications.
+const POLL_PERIOD: Duration = Duration::from_millis(10);
+
+/// An emulated virtio-balloon device.
+pub struct VirtioBalloon {
+    vm: Weak<Vm>,
+    regs: Mutex<VirtioMmio<2>>,
+    /// The number of pages the hypervisor wants.
+    num_pages: AtomicUsize,
+    /// The number of pages in the balloon, as told by the guest.
+    actual: AtomicUsize,
+    /// The number of frames returned to the host allocator.
+    reclaimed: AtomicUsize,
+    /// The guest notified a queue.
+    notified: AtomicBool,
+}
+
+impl VirtioBalloon {
+    /// Creates a device for `vm` asking for `num_pages` pages, and starts
+    /// the task processing its queues.
+    pub fn new(vm: Weak<Vm>, num_pages: usize) -> Arc<Self> {
+        let balloon = Arc::new(Self {
+            vm,
+            regs: Mutex::new(VirtioMmio::new(DEVICE_ID_BALLOON, 0, QUEUE_SIZE)),
+            num_pages: AtomicUsize::new(num_pages),
+            actual: AtomicUsize::new(0),
+            reclaimed: AtomicUsize::new(0),
+            notified: AtomicBool::new(false),
+        });
+        let dev = Arc::downgrade(&balloon);
+        // The VM exists already, its devices being created last.
+        let vm_id = balloon.vm.upgrade().map_or(0, |vm| vm.id);
+        thread::Builder::new()
+            .name(log_ctx::task_name(vm_id, format_args!("balloon")))
+            .spawn(move || balloon_task(dev))
+            .expect("Failed to spawn the virtio-balloon task");
+        balloon
+    }
+
+    /// Whether the device raises its interrupt.
+    pub fn irq_level(&self) -> bool {
+        self.regs.lock().interrupt_status != 0
+    }
+
+    /// Asks the guest to have `num_pages` pages in the balloon.
+    pub fn set_target(&self, num_pages: usize) {
+        self.num_pages.store(num_pages, Ordering::Release);
+        self.regs.lock().interrupt_status |= INT_CONFIG_CHANGE;
+        if let Some(vm) = self.vm.upgrade() {
+            // vCPU 0 updates the PLIC, make it see this one.
+            vm.kick(0);
+        }
+    }
+
+    /// The number of pages in the balloon, as told by the guest.
+    pub fn actual(&self) -> usize {
+        self.actual.load(Ordering::Acquire)
+    }
+
+    /// The number of frames returned to the host allocator so far.
+    pub fn reclaimed(&self) -> usize {
+        self.reclaimed.load(Ordering::Acquire)
+    }
+
+    /// Returns the guest physical addresses of the pages of the buffers
+    /// made available in `queue`, giving the buffers back.
+    fn pop_pages(&self, vm: &Vm, queue: usize) -> AxResult<Vec<VirtAddr>> {
+        let mut regs = self.regs.lock();
+        if !regs.driver_ok() || !regs.queues[queue].is_ready() {
+            return Ok(Vec::new());
+        }
+        let aspace = vm.aspace.lock();
+        let mem = GuestMemory::new(&aspace);
+        let queue = &mut regs.queues[queue];
+        let mut pages = Vec::new();
+        let mut used = false;
+        while let Some(head) = queue.pop_avail(&mem)? {
+            for desc in queue.chain(&mem, head)?.iter().filter(|d| !d.is_write()) {
+                for i in 0..desc.len as usize / 4 {
+                    let pfn = mem.read_obj::<u32>(desc.gpa() + i * 4)? as usize;
+                    pages.push(VirtAddr::from(pfn << BALLOON_PFN_SHIFT));
+                }
+            }
+            queue.push_used(&mem, head, 0)?;
+            used = true;
+        }
+        drop(aspace);
+        if used {
+            regs.interrupt_status |= INT_USED_BUFFER;
+            vm.kick(0);
+        }
+        Ok(pages)
+    }
+
+    /// Unmaps the pages of the guest RAM given by the guest, and frees their
+    /// frames.
+    fn reclaim(&self, vm: &Vm, pages: &[VirtAddr]) -> AxResult {
+        // First makes the pages inaccessible, keeping their frames, which are
+        // freed once no TLB holds them.
+        let mut frames: Vec<(VirtAddr, PhysAddr)> = Vec::new();
+        {
+            let mut aspace = vm.aspace.lock();
+            for &page in pages.iter().filter(|&&page| vm.is_ram(page)) {
+                match aspace.page_table().query(page) {
+                    // Allocated, otherwise there is nothing to free.
+                    Ok((frame, flags, _)) if !flags.is_empty() => {
+                        aspace.protect(page, PAGE_SIZE_4K, MappingFlags::empty())?;
+                        frames.push((page, frame));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if frames.is_empty() {
+            return Ok(());
+        }
+        vm.flush_ept_all();
+
+        let mut aspace = vm.aspace.lock();
+        let flags = MappingFlags::from_bits(0xf).unwrap();
+        for (page, frame) in frames {
+            // If the guest touched the page meanwhile, it got a new frame.
+            if matches!(aspace.page_table().query(page), Ok((pa, f, _)) if pa == frame && f.is_empty())
+            {
+                // Back to a page of the guest RAM never touched.
+                aspace.unmap(page, PAGE_SIZE_4K)?;
+                aspace.map_alloc(page, PAGE_SIZE_4K, flags, false)?;
+            }
+            axalloc::global_allocator().dealloc_pages(phys_to_virt(frame).as_usize(), 1);
+            self.reclaimed.fetch_add(1, Ordering::AcqRel);
+        }
+        Ok(())
+    }
+
+    fn config(&self) -> [u8; 8] {
+        let mut config = [0u8; 8];
+        let num_pages = self.num_pages.load(Ordering::Acquire) as u32;
+        let actual = self.actual.load(Ordering::Acquire) as u32;
+        config[CONFIG_NUM_PAGES..CONFIG_NUM_PAGES + 4].copy_from_slice(&num_pages.to_le_bytes());
+        config[CONFIG_ACTUAL..CONFIG_ACTUAL + 4].copy_from_slice(&actual.to_le_bytes());
+        config
+    }
+}
+
+impl MmioDevice for VirtioBalloon {
+    fn read(&self, offset: usize, width: AccessWidth) -> AxResult<u64> {
+        if offset >= CONFIG_OFFSET {
+            return Ok(virtio::read_config(
+                &self.config(),
+                offset - CONFIG_OFFSET,
+                width.size(),
+            ));
+        }
+        Ok(self.regs.lock().read(offset) as u64)
+    }
+
+    fn write(&self, offset: usize, _width: AccessWidth, val: u64) -> AxResult {
+        if offset >= CONFIG_OFFSET {
+            // Only `actual` is written by the driver.
+            if offset - CONFIG_OFFSET == CONFIG_ACTUAL {
+                self.actual.store(val as u32 as usize, Ordering::Release);
+            }
+            return Ok(());
+        }
+        if self.regs.lock().write(offset, val as u32).is_some() {
+            self.notified.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
+/// Processes the queues notified by the guest until the VM is dropped.
+fn balloon_task(balloon: Weak<VirtioBalloon>) {
+    loop {
+        thread::sleep(POLL_PERIOD);
+        let Some(dev) = balloon.upgrade() else {
+            return;
+        };
+        let Some(vm) = dev.vm.upgrade() else {
+            return;
+        };
+        if !dev.notified.swap(false, Ordering::AcqRel) {
+            continue;
+        }
+        let res = dev
+            .pop_pages(&vm, INFLATE_QUEUE)
+            .and_then(|pages| dev.reclaim(&vm, &pages))
+            // The deflated pages are allocated again when the guest uses them.
+            .and_then(|_| dev.pop_pages(&vm, DEFLATE_QUEUE).map(|_| ()));
+        if let Err(err) = res {
+            warn!("[VM {}] virtio-balloon: {:?}", vm.id, err);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! A virtio-blk device, backed by a file of the host filesystem.
+//!
+//! Requests are processed synchronously when the guest notifies the queue,
+//! so the used buffer interrupt is pending when the guest resumes.
+
+use alloc::sync::Weak;
+use alloc::vec;
+use axerrno::{ax_err, AxError, AxResult};
+use riscv_vcpu::AccessWidth;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+
+use super::virtio::{self, Desc, VirtioMmio, CONFIG_OFFSET, INT_USED_BUFFER};
+use super::MmioDevice;
+use crate::guest_mem::{GuestMemory, Pod};
+use crate::vm::Vm;
+
+/// The guest physical address of the device, the first virtio-mmio slot of
+/// QEMU virt.
+pub const VIRTIO_BLK_BASE: usize = 0x1000_1000;
+/// The size of the device registers.
+pub const VIRTIO_BLK_SIZE: usize = virtio::VIRTIO_MMIO_SIZE;
+/// The interrupt of the device in the PLIC.
+pub const VIRTIO_BLK_IRQ: usize = 1;
+
+const DEVICE_ID_BLK: u32 = 2;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// The header of a request.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BlkReqHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// SAFETY: `repr(C)` without padding.
+unsafe impl Pod for BlkReqHeader {}
+const QUEUE_SIZE: u16 = 128;
+/// The max length of a data buffer, to bound the host memory used.
+const MAX_BUF_LEN: u32 = 0x10_0000;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// An emulated virtio-blk device.
+pub struct VirtioBlk {
+    vm: Weak<Vm>,
+    file: Mutex<File>,
+    /// The size of the disk in sectors.
+    capacity: u64,
+    regs: Mutex<VirtioMmio<1>>,
+}
+
+impl VirtioBlk {
+    /// Creates a device for `vm` backed by the file at `path`.
+    pub fn new(vm: Weak<Vm>, path: &str) -> AxResult<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|_| AxError::NotFound)?;
+        let size = file.metadata().map_err(|_| AxError::Io)?.size();
+        Ok(Self {
+            vm,
+            file: Mutex::new(file),
+            capacity: size / SECTOR_SIZE,
+            regs: Mutex::new(VirtioMmio::new(
+                DEVICE_ID_BLK,
+                VIRTIO_BLK_F_FLUSH,
+                QUEUE_SIZE,
+            )),
+        })
+    }
+
+    /// Whether the device raises its interrupt.
+    pub fn irq_level(&self) -> bool {
+        self.regs.lock().interrupt_status != 0
+    }
+
+    /// Processes the requests made available by the guest.
+    fn process_queue(&self, regs: &mut VirtioMmio<1>) -> AxResult {
+        let vm = self.vm.upgrade().ok_or(AxError::BadState)?;
+        let aspace = vm.aspace.lock();
+        let mem = GuestMemory::new(&aspace);
+        let queue = &mut regs.queues[0];
+        while let Some(head) = queue.pop_avail(&mem)? {
+            let chain = queue.chain(&mem, head)?;
+            let written = self.process_request(&mem, &chain)?;
+            queue.push_used(&mem, head, written)?;
+            regs.interrupt_status |= INT_USED_BUFFER;
+        }
+        Ok(())
+    }
+
+    /// Processes the request of the descriptor `chain`, and returns the
+    /// number of bytes written to the guest.
+    fn process_request(&self, mem: &GuestMemory, chain: &[Desc]) -> AxResult<u32> {
+        // The header, the data buffers, then the status byte.
+        let [header, data_descs @ .., status_desc] = chain else {
+            return ax_err!(InvalidData, "bad virtio-blk descriptor chain");
+        };
+        let BlkReqHeader {
+            req_type, sector, ..
+        } = mem.read_obj(header.gpa())?;
+
+        let mut file = self.file.lock();
+        let mut status = VIRTIO_BLK_S_OK;
+        let mut written = 0;
+        let disk_size = self.capacity * SECTOR_SIZE;
+        // `None` once the offset no longer fits in a `u64`.
+        let mut pos = sector.checked_mul(SECTOR_SIZE);
+        for desc in data_descs {
+            if desc.len > MAX_BUF_LEN {
+                status = VIRTIO_BLK_S_IOERR;
+                continue;
+            }
+            // The offset of the buffer on the disk, if it lies within it.
+            let start = pos.filter(|&pos| {
+                pos.checked_add(desc.len as u64)
+                    .is_some_and(|end| end <= disk_size)
+            });
+            let mut data = vec![0u8; desc.len as usize];
+            let res = match (req_type, start) {
+                (VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT, None) => Err(AxError::InvalidInput),
+                (VIRTIO_BLK_T_IN, Some(start)) if desc.is_write() => file
+                    .seek(SeekFrom::Start(start))
+                    .and_then(|_| file.read_exact(&mut data))
+                    .map_err(|_| AxError::Io)
+                    .and_then(|_| mem.write(desc.gpa(), &data))
+                    .map(|_| written += desc.len),
+                (VIRTIO_BLK_T_OUT, Some(start)) if !desc.is_write() => {
+                    mem.read(desc.gpa(), &mut data).and_then(|_| {
+                        file.seek(SeekFrom::Start(start))
+                            .and_then(|_| file.write_all(&data))
+                            .map_err(|_| AxError::Io)
+                    })
+                }
+                (VIRTIO_BLK_T_GET_ID, _) => {
+                    let id = b"arceos-virtio-blk";
+                    let len = id.len().min(data.len());
+                    data[..len].copy_from_slice(&id[..len]);
+                    mem.write(desc.gpa(), &data).map(|_| written += desc.len)
+                }
+                _ => Err(AxError::Unsupported),
+            };
+            match res {
+                Ok(()) => pos = pos.and_then(|pos| pos.checked_add(desc.len as u64)),
+                Err(AxError::Unsupported) => status = VIRTIO_BLK_S_UNSUPP,
+                Err(_) => status = VIRTIO_BLK_S_IOERR,
+            }
+        }
+        match req_type {
+            VIRTIO_BLK_T_FLUSH => {
+                if file.flush().is_err() {
+                    status = VIRTIO_BLK_S_IOERR;
+                }
+            }
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_GET_ID => {}
+            _ => status = VIRTIO_BLK_S_UNSUPP,
+        }
+        mem.write_obj(status_desc.gpa(), &status)?;
+        Ok(written + 1)
+    }
+}
+
+impl MmioDevice for VirtioBlk {
+    fn read(&self, offset: usize, width: AccessWidth) -> AxResult<u64> {
+        if offset >= CONFIG_OFFSET {
+            // The capacity is the first field of the configuration.
+            let config = self.capacity.to_le_bytes();
+            return Ok(virtio::read_config(
+                &config,
+                offset - CONFIG_OFFSET,
+                width.size(),
+            ));
+        }
+        Ok(self.regs.lock().read(offset) as u64)
+    }
+
+    fn write(&self, offset: usize, _width: AccessWidth, val: u64) -> AxResult {
+        let mut regs = self.regs.lock();
+        if regs.write(offset, val as u32).is_some() && regs.driver_ok() {
+            if let Err(err) = self.process_queue(&mut regs) {
+                warn!("virtio-blk: bad request queue: {:?}", err);
+                regs.set_needs_reset();
+            }
+        }
+        Ok(())
+    }
+}
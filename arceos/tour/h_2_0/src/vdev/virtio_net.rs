@@ -0,0 +1,218 @@
+//! A virtio-net device bridged to the host network stack.
+//!
+//! The Ethernet frames of the guest are tunneled in UDP datagrams through
+//! axnet, as with the `-netdev dgram` backend of QEMU: each frame sent by the
+//! guest goes to the peer address in one datagram, and each datagram
+//! received from the peer is given to the guest as a frame.
+
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use axerrno::{AxError, AxResult};
+use riscv_vcpu::AccessWidth;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+
+use super::virtio::{self, VirtioMmio, CONFIG_OFFSET, INT_USED_BUFFER};
+use super::MmioDevice;
+use crate::guest_mem::GuestMemory;
+use crate::log_ctx;
+use crate::vm::Vm;
+
+/// The guest physical address of the device, the second virtio-mmio slot of
+/// QEMU virt.
+pub const VIRTIO_NET_BASE: usize = 0x1000_2000;
+/// The size of the device registers.
+pub const VIRTIO_NET_SIZE: usize = virtio::VIRTIO_MMIO_SIZE;
+/// The interrupt of the device in the PLIC.
+pub const VIRTIO_NET_IRQ: usize = 2;
+
+const DEVICE_ID_NET: u32 = 1;
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const QUEUE_SIZE: u16 = 256;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// The size of `virtio_net_hdr` with `VIRTIO_F_VERSION_1`.
+const NET_HDR_SIZE: usize = 12;
+/// The max size of a frame, without the FCS.
+const MAX_FRAME_SIZE: usize = 1514;
+/// The max number of received frames waiting for guest buffers.
+const MAX_RX_PENDING: usize = 64;
+
+/// The locally administered MAC address of the guest.
+const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x57];
+
+/// An emulated virtio-net device.
+pub struct VirtioNet {
+    vm: Weak<Vm>,
+    socket: UdpSocket,
+    peer: SocketAddr,
+    regs: Mutex<VirtioMmio<2>>,
+    rx_pending: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl VirtioNet {
+    /// Creates a device for `vm` exchanging frames with `peer`, and starts
+    /// the task receiving them. The datagrams are received on the port of
+    /// `peer`.
+    pub fn new(vm: Weak<Vm>, peer: SocketAddr) -> AxResult<Arc<Self>> {
+        let local = SocketAddr::from(([0, 0, 0, 0], peer.port()));
+        let socket = UdpSocket::bind(local).map_err(|_| AxError::AddrInUse)?;
+        let net = Arc::new(Self {
+            vm,
+            socket,
+            peer,
+            regs: Mutex::new(VirtioMmio::new(DEVICE_ID_NET, VIRTIO_NET_F_MAC, QUEUE_SIZE)),
+            rx_pending: Mutex::new(VecDeque::new()),
+        });
+        let rx = Arc::downgrade(&net);
+        // The VM exists already, its devices being created last.
+        let vm_id = net.vm.upgrade().map_or(0, |vm| vm.id);
+        thread::Builder::new()
+            .name(log_ctx::task_name(vm_id, format_args!("net")))
+            .spawn(move || rx_task(rx))?;
+        Ok(net)
+    }
+
+    /// Whether the device raises its interrupt.
+    pub fn irq_level(&self) -> bool {
+        self.regs.lock().interrupt_status != 0
+    }
+
+    /// Sends the frames queued by the guest to the peer.
+    fn process_tx(&self, regs: &mut VirtioMmio<2>, mem: &GuestMemory) -> AxResult {
+        let queue = &mut regs.queues[TX_QUEUE];
+        while let Some(head) = queue.pop_avail(mem)? {
+            let mut packet = Vec::new();
+            for desc in queue.chain(mem, head)?.iter().filter(|d| !d.is_write()) {
+                let len = desc.len as usize;
+                if packet.len() + len > NET_HDR_SIZE + MAX_FRAME_SIZE {
+                    break;
+                }
+                let start = packet.len();
+                packet.resize(start + len, 0);
+                mem.read(desc.gpa(), &mut packet[start..])?;
+            }
+            if let Some(frame) = packet.get(NET_HDR_SIZE..) {
+                if self.socket.send_to(frame, self.peer).is_err() {
+                    warn!("virtio-net: failed to send a frame");
+                }
+            }
+            queue.push_used(mem, head, 0)?;
+            regs.interrupt_status |= INT_USED_BUFFER;
+        }
+        Ok(())
+    }
+
+    /// Gives the received frames to the guest, as long as it has buffers.
+    fn process_rx(&self, regs: &mut VirtioMmio<2>, mem: &GuestMemory) -> AxResult {
+        if !regs.driver_ok() || !regs.queues[RX_QUEUE].is_ready() {
+            return Ok(());
+        }
+        let mut pending = self.rx_pending.lock();
+        let queue = &mut regs.queues[RX_QUEUE];
+        while let Some(frame) = pending.front() {
+            let Some(head) = queue.pop_avail(mem)? else {
+                break;
+            };
+            // The header has no offload, and the frame fits in one buffer
+            // (`num_buffers` is 1).
+            let mut packet = vec![0u8; NET_HDR_SIZE];
+            packet[10] = 1;
+            packet.extend_from_slice(frame);
+            let mut written = 0;
+            for desc in queue.chain(mem, head)?.iter().filter(|d| d.is_write()) {
+                let len = (desc.len as usize).min(packet.len() - written);
+                mem.write(desc.gpa(), &packet[written..written + len])?;
+                written += len;
+                if written == packet.len() {
+                    break;
+                }
+            }
+            queue.push_used(mem, head, written as u32)?;
+            regs.interrupt_status |= INT_USED_BUFFER;
+            pending.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Processes the queue notified by the guest.
+    fn notify(&self, regs: &mut VirtioMmio<2>, queue: usize) -> AxResult {
+        let vm = self.vm.upgrade().ok_or(AxError::BadState)?;
+        let aspace = vm.aspace.lock();
+        let mem = GuestMemory::new(&aspace);
+        match queue {
+            TX_QUEUE => self.process_tx(regs, &mem),
+            _ => self.process_rx(regs, &mem),
+        }
+    }
+}
+
+impl MmioDevice for VirtioNet {
+    fn read(&self, offset: usize, width: AccessWidth) -> AxResult<u64> {
+        if offset >= CONFIG_OFFSET {
+            // The MAC address is the first field of the configuration.
+            return Ok(virtio::read_config(
+                &GUEST_MAC,
+                offset - CONFIG_OFFSET,
+                width.size(),
+            ));
+        }
+        Ok(self.regs.lock().read(offset) as u64)
+    }
+
+    fn write(&self, offset: usize, _width: AccessWidth, val: u64) -> AxResult {
+        let mut regs = self.regs.lock();
+        if let Some(queue) = regs.write(offset, val as u32) {
+            if regs.driver_ok() {
+                if let Err(err) = self.notify(&mut regs, queue) {
+                    warn!("virtio-net: bad queue {}: {:?}", queue, err);
+                    regs.set_needs_reset();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Receives the frames from the peer until the VM is dropped.
+fn rx_task(net: Weak<VirtioNet>) {
+    let mut buf = vec![0u8; MAX_FRAME_SIZE];
+    loop {
+        let Some(dev) = net.upgrade() else {
+            return;
+        };
+        let len = match dev.socket.recv_from(&mut buf) {
+            Ok((len, from)) if from == dev.peer => len,
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("virtio-net: receive error {:?}", err);
+                continue;
+            }
+        };
+        let Some(vm) = dev.vm.upgrade() else {
+            return;
+        };
+        {
+            let mut pending = dev.rx_pending.lock();
+            if pending.len() >= MAX_RX_PENDING {
+                pending.pop_front();
+            }
+            pending.push_back(buf[..len].to_vec());
+        }
+        let mut regs = dev.regs.lock();
+        let raised = regs.interrupt_status;
+        let res = dev.process_rx(&mut regs, &GuestMemory::new(&vm.aspace.lock()));
+        if res.is_err() {
+            warn!("virtio-net: failed to give a frame to the guest");
+        }
+        if regs.interrupt_status != raised {
+            // vCPU 0 updates the PLIC, make it see this one.
+            vm.kick(0);
+        }
+    }
+}
@@ -0,0 +1,1022 @@
+//! A VM: its guest memory, its vCPUs and its devices.
+//!
+//! A VM is created from a [`VmConfig`], then goes through the states of
+//! [`VmState`]: [`Vm::boot`] spawns the tasks of its vCPUs, [`Vm::pause`] and
+//! [`Vm::resume`] hold them out of the guest, and [`Vm::shutdown`] stops them.
+//! The guest stops them too when it powers the VM off or reboots it, see
+//! [`Vm::wait`].
+//! The host can add vCPUs to a VM and remove them at runtime, up to
+//! [`VmConfig::max_vcpus`], see [`Vm::add_vcpu`].
+//! A paused VM can be saved to a file, and restored in a new VM, see the
+//! `snapshot` module, or migrated to another host, see the `migrate`
+//! module. Host tasks can share memory with the guest, see the
+//! `shared` module.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use axerrno::{ax_err, ax_err_type, AxError, AxResult};
+use axhal::paging::MappingFlags;
+use axmm::AddrSpace;
+use axtask::WaitQueue;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use lazyinit::LazyInit;
+use memory_addr::{MemoryAddr, VirtAddr, PAGE_SIZE_4K};
+use riscv_vcpu::{PmuEvents, RISCVVCpu, VCpuState};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+use crate::cpu_quota::CpuQuota;
+use crate::dirty_log::DirtyLog;
+use crate::exit_log::ExitLog;
+use crate::exit_stats::ExitStats;
+use crate::loader::{load_initrd, load_vm_image};
+use crate::log_ctx;
+use crate::vdev::Devices;
+use crate::vm_fdt;
+
+#[cfg(feature = "migrate")]
+mod migrate;
+mod shared;
+mod snapshot;
+
+const VM_ASPACE_BASE: usize = 0x0;
+const VM_ASPACE_SIZE: usize = 0x7fff_ffff_f000;
+
+/// The window of host physical addresses passed through to the guest, the
+/// pflash#2 of QEMU virt, unless the VM has its own pflash there.
+const PASSTHROUGH_BASE: usize = 0x2200_0000;
+const PASSTHROUGH_SIZE: usize = 0x200_0000;
+
+/// The max number of vCPUs of a VM.
+pub const MAX_VCPUS: usize = 8;
+
+/// The vCPU is not started, or stopped by itself.
+const VCPU_STOPPED: u8 = 0;
+/// Someone asked to start the vCPU, its task has not picked it up yet.
+const VCPU_START_PENDING: u8 = 1;
+/// The vCPU is running on a physical hart.
+const VCPU_RUNNING: u8 = 2;
+/// The vCPU waits for an interrupt, its task is blocked.
+const VCPU_WAITING: u8 = 3;
+/// The vCPU is not plugged in, it cannot be started until added.
+const VCPU_ABSENT: u8 = 4;
+
+/// The HSM states of SBI `hart_get_status`.
+const HART_STARTED: usize = 0;
+const HART_STOPPED: usize = 1;
+const HART_START_PENDING: usize = 2;
+
+/// The description of a VM.
+pub struct VmConfig {
+    /// The guest physical address of the guest RAM.
+    pub mem_base: usize,
+    /// The size of the guest RAM.
+    pub mem_size: usize,
+    /// The path of the kernel image.
+    pub image: String,
+    /// The path of the initrd, if any.
+    pub initrd: Option<String>,
+    /// The guest physical address a flat image is loaded at, and the boot
+    /// vCPU starts at. ELF images are loaded at the physical addresses of
+    /// their segments, and start at their entry point.
+    pub entry: usize,
+    /// The number of vCPUs present at boot.
+    pub num_vcpus: usize,
+    /// The number of vCPU slots, with the absent ones the host may add.
+    pub max_vcpus: usize,
+    /// The path of the disk image of the virtio-blk device, if any.
+    pub disk: Option<String>,
+    /// The path of the file backing the pflash, if any.
+    pub pflash: Option<String>,
+    /// The guest physical address of the pflash.
+    pub pflash_base: usize,
+    /// The number of pages the virtio-balloon device asks for, if any.
+    pub balloon: Option<usize>,
+    /// The host virtio-blk device passed through, by its virtio-mmio slot,
+    /// if any.
+    pub blk_passthrough: Option<usize>,
+    /// The UDP peer of the virtio-net device, if any.
+    #[cfg(feature = "vnet")]
+    pub net_peer: Option<String>,
+    /// Whether the interrupts go through a virtual APLIC and IMSIC, the AIA,
+    /// instead of the PLIC.
+    pub aia: bool,
+    /// The events the guest may count with the SBI PMU extension.
+    pub pmu_events: PmuEvents,
+    /// The path of the file the VM exits are recorded to, if any, see the
+    /// `exit_log` module.
+    pub record: Option<String>,
+    /// The path of the file of recorded VM exits to replay, if any.
+    pub replay: Option<String>,
+    /// The path of the file the crash dump of the guest is written to, if
+    /// any, see the `crash` module.
+    pub crash_dump: Option<String>,
+    /// The CPU time each vCPU may use, if limited, see the `cpu_quota`
+    /// module.
+    pub cpu_quota: Option<CpuQuota>,
+}
+
+/// The state of a VM.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    /// Created, but not booted yet.
+    Created = 0,
+    /// The vCPUs run the guest.
+    Running = 1,
+    /// The vCPUs are held out of the guest.
+    Paused = 2,
+    /// The vCPUs are stopped for good.
+    Shutdown = 3,
+}
+
+/// Why a VM stopped, see [`Vm::wait`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExit {
+    /// The host shut the VM down with [`Vm::shutdown`].
+    Stopped,
+    /// The guest powered the VM off, with SBI SRST `SHUTDOWN`.
+    Shutdown,
+    /// The guest crashed, see [`Vm::guest_crash`].
+    Crashed,
+}
+
+impl VmState {
+    fn from_u8(val: u8) -> Self {
+        match val {
+            0 => Self::Created,
+            1 => Self::Running,
+            2 => Self::Paused,
+            _ => Self::Shutdown,
+        }
+    }
+}
+
+struct VCpuSlot {
+    state: AtomicU8,
+    entry: AtomicUsize,
+    arg: AtomicUsize,
+    /// The physical hart the vCPU is running on.
+    hart: AtomicUsize,
+    /// A virtual IPI is waiting to be injected.
+    ipi_pending: AtomicBool,
+    /// The vCPU was kicked since it last checked its interrupts.
+    kicked: AtomicBool,
+    /// The task of the vCPU while it waits for an interrupt.
+    wfi_queue: WaitQueue,
+    /// The generation of the G-stage mappings flushed from the TLB of the
+    /// hart running the vCPU.
+    ept_gen: AtomicUsize,
+    /// The state of the vCPU while the VM is paused, if started.
+    paused_state: Mutex<Option<VCpuState>>,
+    /// The paused state was changed, and is restored on resume.
+    paused_state_changed: AtomicBool,
+    /// The state to start the vCPU with, restored from a snapshot.
+    restored_state: Mutex<Option<VCpuState>>,
+    exit_stats: Mutex<ExitStats>,
+}
+
+pub struct Vm {
+    /// The ID of the VM in the logs, from 1.
+    pub id: usize,
+    /// The VMID tagging the G-stage translations of the VM, or 0 if the
+    /// harts do not support VMIDs.
+    pub vmid: usize,
+    pub config: VmConfig,
+    /// The entry point of the boot vCPU.
+    pub entry: usize,
+    /// The guest physical address of the device tree.
+    pub fdt_addr: usize,
+    /// The host time, in ticks, at which the guest `cycle` and `instret`
+    /// counters start.
+    pub counter_base: usize,
+    /// The offset of the guest `time` from the host one, the `htimedelta` of
+    /// all the vCPUs.
+    time_delta: AtomicUsize,
+    /// The host time, in ticks, the VM was last paused at.
+    paused_at: AtomicUsize,
+    pub aspace: Mutex<AddrSpace>,
+    state: AtomicU8,
+    vcpus: Vec<VCpuSlot>,
+    devices: LazyInit<Arc<Devices>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    dirty_log: DirtyLog,
+    /// The guest physical ranges of the shared regions, sorted.
+    shared_regions: Mutex<Vec<Range<usize>>>,
+    /// Restored from a snapshot.
+    restored: AtomicBool,
+    /// The guest powered the VM off.
+    powered_off: AtomicBool,
+    /// The guest asked for a reboot, done once the vCPU tasks exit.
+    reboot: AtomicBool,
+    /// The guest crashed.
+    crashed: AtomicBool,
+    /// The VM exits recorded or replayed, if any.
+    exit_log: Option<ExitLog>,
+    /// A debugger is attached, so breakpoints in the guest exit to it.
+    debugging: AtomicBool,
+    /// The vCPU which stopped the VM at a breakpoint, plus one, or 0.
+    breakpoint_stop: AtomicUsize,
+    /// The vCPU stepping an instruction of the paused VM, plus one, or 0.
+    step_request: AtomicUsize,
+    /// Each VM exit is logged, see the `log_ctx` module.
+    trace_exits: AtomicBool,
+    /// The generation of the G-stage mappings, bumped each time mappings
+    /// are downgraded so the TLBs of all the vCPUs need a flush.
+    ept_gen: AtomicUsize,
+}
+
+impl Vm {
+    /// Creates VM `id` from `config`: maps its RAM, loads its image and
+    /// creates its devices.
+    ///
+    /// Each VM has its own guest memory, so VMs with different IDs are
+    /// isolated from each other.
+    pub fn create(id: usize, config: VmConfig) -> AxResult<Arc<Self>> {
+        if config.num_vcpus == 0
+            || config.num_vcpus > config.max_vcpus
+            || config.max_vcpus > MAX_VCPUS
+        {
+            return ax_err!(InvalidInput, "bad number of vCPUs");
+        }
+        if config.aia && !riscv_vcpu::has_aia_support() {
+            return ax_err!(Unsupported, "the AIA needs Ssaia on the host");
+        }
+        if config.aia && config.blk_passthrough.is_some() {
+            return ax_err!(Unsupported, "passthrough devices need the PLIC");
+        }
+        let mut aspace = AddrSpace::new_empty(VirtAddr::from(VM_ASPACE_BASE), VM_ASPACE_SIZE)?;
+        // Guest RAM, with full access flags, allocated on demand.
+        let mapping_flags = MappingFlags::from_bits(0xf).unwrap();
+        aspace.map_alloc(
+            config.mem_base.into(),
+            config.mem_size,
+            mapping_flags,
+            false,
+        )?;
+        let (entry, fdt_addr) = load_guest(&config, &mut aspace)?;
+        let exit_log = match (&config.record, &config.replay) {
+            (Some(_), Some(_)) => return ax_err!(InvalidInput, "both recording and replaying"),
+            (Some(path), None) => Some(ExitLog::record(path, config.max_vcpus)?),
+            (None, Some(path)) => Some(ExitLog::replay(path, config.max_vcpus)?),
+            (None, None) => None,
+        };
+
+        let vcpus = (0..config.max_vcpus)
+            .map(|id| VCpuSlot {
+                state: AtomicU8::new(if id < config.num_vcpus {
+                    VCPU_STOPPED
+                } else {
+                    VCPU_ABSENT
+                }),
+                entry: AtomicUsize::new(0),
+                arg: AtomicUsize::new(0),
+                hart: AtomicUsize::new(usize::MAX),
+                ipi_pending: AtomicBool::new(false),
+                kicked: AtomicBool::new(false),
+                wfi_queue: WaitQueue::new(),
+                ept_gen: AtomicUsize::new(0),
+                paused_state: Mutex::new(None),
+                paused_state_changed: AtomicBool::new(false),
+                restored_state: Mutex::new(None),
+                exit_stats: Mutex::new(ExitStats::default()),
+            })
+            .collect();
+        // VMID 0 is left for hosts without VMID support.
+        let vmid = if riscv_vcpu::vmid_bits() > 0 { id } else { 0 };
+        let now = axhal::time::current_ticks() as usize;
+        let vm = Arc::new(Self {
+            id,
+            vmid,
+            entry,
+            fdt_addr,
+            counter_base: now,
+            // The guest time starts from 0.
+            time_delta: AtomicUsize::new(now.wrapping_neg()),
+            paused_at: AtomicUsize::new(0),
+            dirty_log: DirtyLog::new(config.mem_base, config.mem_size),
+            config,
+            aspace: Mutex::new(aspace),
+            state: AtomicU8::new(VmState::Created as u8),
+            vcpus,
+            devices: LazyInit::new(),
+            tasks: Mutex::new(Vec::new()),
+            shared_regions: Mutex::new(Vec::new()),
+            restored: AtomicBool::new(false),
+            powered_off: AtomicBool::new(false),
+            reboot: AtomicBool::new(false),
+            crashed: AtomicBool::new(false),
+            exit_log,
+            debugging: AtomicBool::new(false),
+            breakpoint_stop: AtomicUsize::new(0),
+            step_request: AtomicUsize::new(0),
+            trace_exits: AtomicBool::new(false),
+            ept_gen: AtomicUsize::new(0),
+        });
+        // The devices refer to the VM, so they come once it exists.
+        let devices = Devices::new(Arc::downgrade(&vm), &vm.config)?;
+        vm.devices.init_once(Arc::new(devices));
+        Ok(vm)
+    }
+
+    /// The number of vCPU slots, present or not.
+    pub fn num_vcpus(&self) -> usize {
+        self.vcpus.len()
+    }
+
+    /// Whether vCPU `id` is present, i.e. not removed or not added yet.
+    pub fn vcpu_present(&self, id: usize) -> bool {
+        self.vcpus
+            .get(id)
+            .is_some_and(|vcpu| vcpu.state.load(Ordering::Acquire) != VCPU_ABSENT)
+    }
+
+    /// Adds the absent vCPU `hart_id` to the VM. It is stopped, and the guest
+    /// starts it with `hart_start`, e.g. when onlining the CPU.
+    pub fn add_vcpu(&self, hart_id: usize) -> AxResult {
+        let Some(vcpu) = self.vcpus.get(hart_id) else {
+            return ax_err!(InvalidInput, "no such vCPU slot");
+        };
+        vcpu.state
+            .compare_exchange(
+                VCPU_ABSENT,
+                VCPU_STOPPED,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .or_else(|_| ax_err!(AlreadyExists, "vCPU already present"))?;
+        info!("[VM {}] vCPU {} added", self.id, hart_id);
+        Ok(())
+    }
+
+    /// Removes vCPU `hart_id` from the VM. The guest must have stopped it
+    /// with `hart_stop` first, e.g. when offlining the CPU, as a started vCPU
+    /// cannot be taken away from it. The boot vCPU cannot be removed.
+    pub fn remove_vcpu(&self, hart_id: usize) -> AxResult {
+        if hart_id == 0 {
+            return ax_err!(InvalidInput, "the boot vCPU cannot be removed");
+        }
+        let Some(vcpu) = self.vcpus.get(hart_id) else {
+            return ax_err!(InvalidInput, "no such vCPU slot");
+        };
+        match vcpu.state.compare_exchange(
+            VCPU_STOPPED,
+            VCPU_ABSENT,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                info!("[VM {}] vCPU {} removed", self.id, hart_id);
+                Ok(())
+            }
+            Err(VCPU_ABSENT) => ax_err!(NotFound, "vCPU not present"),
+            Err(_) => ax_err!(BadState, "vCPU started, the guest has to stop it first"),
+        }
+    }
+
+    pub fn devices(&self) -> &Arc<Devices> {
+        &self.devices
+    }
+
+    pub fn exit_log(&self) -> Option<&ExitLog> {
+        self.exit_log.as_ref()
+    }
+
+    pub fn state(&self) -> VmState {
+        VmState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn transition(&self, from: VmState, to: VmState) -> AxResult {
+        self.state
+            .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .or_else(|_| ax_err!(BadState, "bad VM state"))
+    }
+
+    /// Boots the VM: spawns the tasks of its vCPUs, and starts the boot vCPU
+    /// at the entry, with the device tree in `a1`. The others wait for
+    /// `hart_start`.
+    pub fn boot(self: &Arc<Self>) -> AxResult {
+        self.transition(VmState::Created, VmState::Running)?;
+        // A restored VM has its vCPUs started already.
+        if !self.restored.load(Ordering::Acquire) {
+            self.start_vcpu(0, self.entry, self.fdt_addr)?;
+        }
+        let mut tasks = self.tasks.lock();
+        for vcpu_id in 0..self.num_vcpus() {
+            let vm = self.clone();
+            let task = thread::Builder::new()
+                .name(log_ctx::task_name(self.id, format_args!("cpu{}", vcpu_id)))
+                .spawn(move || crate::vcpu::vcpu_task(vm, vcpu_id))?;
+            tasks.push(task);
+        }
+        Ok(())
+    }
+
+    /// Pauses the VM: its vCPUs are kicked out of the guest, and wait until
+    /// it is resumed.
+    ///
+    /// The guest time stops meanwhile, see [`Vm::time_delta`].
+    pub fn pause(&self) -> AxResult {
+        self.paused_at
+            .store(axhal::time::current_ticks() as usize, Ordering::Release);
+        self.transition(VmState::Running, VmState::Paused)?;
+        self.kick_all();
+        Ok(())
+    }
+
+    /// Resumes the paused VM. The guest time goes on from where it was
+    /// paused.
+    pub fn resume(&self) -> AxResult {
+        let paused = (axhal::time::current_ticks() as usize)
+            .wrapping_sub(self.paused_at.load(Ordering::Acquire));
+        // Before the vCPUs see the VM running, so they take the new delta.
+        self.time_delta.fetch_sub(paused, Ordering::AcqRel);
+        self.transition(VmState::Paused, VmState::Running)
+            .inspect_err(|_| {
+                self.time_delta.fetch_add(paused, Ordering::AcqRel);
+            })
+    }
+
+    /// The offset of the guest `time` from the host one, the `htimedelta` of
+    /// all the vCPUs. It hides the time the VM was paused from the guest.
+    pub fn time_delta(&self) -> usize {
+        self.time_delta.load(Ordering::Acquire)
+    }
+
+    /// Sets the guest time to `time`, in ticks, for the restored VM.
+    fn set_guest_time(&self, time: usize) {
+        let now = axhal::time::current_ticks() as usize;
+        self.time_delta
+            .store(time.wrapping_sub(now), Ordering::Release);
+    }
+
+    /// Shuts the VM down: its vCPUs are kicked out of the guest, and their
+    /// tasks exit.
+    pub fn shutdown(&self) -> AxResult {
+        // Overrides a reboot asked by the guest meanwhile.
+        self.reboot.store(false, Ordering::Release);
+        self.stop()
+    }
+
+    /// Called by the task of a vCPU whose guest powers the VM off, or reboots
+    /// it if `reboot` is set: the vCPUs are stopped like with
+    /// [`Vm::shutdown`], then [`Vm::wait`] handles the request.
+    pub fn guest_reset(&self, reboot: bool) {
+        if reboot {
+            self.reboot.store(true, Ordering::Release);
+        } else {
+            self.powered_off.store(true, Ordering::Release);
+        }
+        let _ = self.stop();
+    }
+
+    /// Called by the task of a vCPU whose guest cannot run any further, once
+    /// it dumped its state: the vCPUs are stopped like with
+    /// [`Vm::shutdown`], and the VM is not rebooted.
+    pub fn guest_crash(&self) {
+        self.crashed.store(true, Ordering::Release);
+        let _ = self.stop();
+    }
+
+    fn stop(&self) -> AxResult {
+        let old = self.state.swap(VmState::Shutdown as u8, Ordering::AcqRel);
+        if VmState::from_u8(old) == VmState::Shutdown {
+            return ax_err!(BadState, "VM already shut down");
+        }
+        self.kick_all();
+        Ok(())
+    }
+
+    /// Waits until the VM stops for good, and frees its guest memory.
+    ///
+    /// When the guest reboots the VM, the VM is reset once the tasks of the
+    /// vCPUs exit, and boots again from its image.
+    pub fn wait(self: &Arc<Self>) -> AxResult<VmExit> {
+        loop {
+            let tasks = core::mem::take(&mut *self.tasks.lock());
+            for task in tasks {
+                task.join()
+                    .map_err(|_| ax_err_type!(BadState, "vCPU task failed"))?;
+            }
+            if let Some(log) = &self.exit_log {
+                log.flush()?;
+            }
+            if self.powered_off.load(Ordering::Acquire)
+                || self.crashed.load(Ordering::Acquire)
+                || !self.reboot.swap(false, Ordering::AcqRel)
+            {
+                break;
+            }
+            info!("[VM {}] rebooting", self.id);
+            self.reset()?;
+            self.boot()?;
+        }
+        self.aspace.lock().clear();
+        Ok(if self.crashed.load(Ordering::Acquire) {
+            VmExit::Crashed
+        } else if self.powered_off.load(Ordering::Acquire) {
+            VmExit::Shutdown
+        } else {
+            VmExit::Stopped
+        })
+    }
+
+    /// Resets the VM whose vCPU tasks exited, as it was created: its guest
+    /// memory is freed, and its image and device tree are loaded again.
+    ///
+    /// The shared regions stay mapped. The devices keep their state but the
+    /// PLIC and the passthrough device, as the guest resets them when it
+    /// probes them.
+    fn reset(&self) -> AxResult {
+        {
+            let shared_regions = self.shared_regions.lock();
+            let mut aspace = self.aspace.lock();
+            let shared: Vec<_> = shared_regions
+                .iter()
+                .filter_map(|range| {
+                    let (frame, flags, _) = aspace.page_table().query(range.start.into()).ok()?;
+                    Some((range.clone(), frame, flags))
+                })
+                .collect();
+            aspace.clear();
+            for (range, frame, flags) in shared {
+                aspace.map_linear(range.start.into(), frame, range.len(), flags)?;
+            }
+            let mapping_flags = MappingFlags::from_bits(0xf).unwrap();
+            aspace.map_alloc(
+                self.config.mem_base.into(),
+                self.config.mem_size,
+                mapping_flags,
+                false,
+            )?;
+            load_guest(&self.config, &mut aspace)?;
+        }
+        for vcpu in &self.vcpus {
+            vcpu.kicked.store(false, Ordering::Relaxed);
+            *vcpu.paused_state.lock() = None;
+            vcpu.paused_state_changed.store(false, Ordering::Relaxed);
+            *vcpu.restored_state.lock() = None;
+        }
+        // The TLBs may hold the old mappings.
+        self.ept_gen.fetch_add(1, Ordering::AcqRel);
+        self.restored.store(false, Ordering::Release);
+        self.breakpoint_stop.store(0, Ordering::Release);
+        self.step_request.store(0, Ordering::Release);
+        self.devices().reset();
+        self.state.store(VmState::Created as u8, Ordering::Release);
+        Ok(())
+    }
+
+    /// Called by the task of vCPU `id` before entering the guest: waits while
+    /// the VM is paused, and returns whether the vCPU may run.
+    ///
+    /// While paused, the state of `arch_vcpu` is published for snapshots and
+    /// debuggers, and it is restored on resume if they changed it.
+    pub fn vcpu_may_run(&self, id: usize, arch_vcpu: &mut RISCVVCpu) -> bool {
+        let vcpu = &self.vcpus[id];
+        let paused_state = &vcpu.paused_state;
+        loop {
+            match self.state() {
+                VmState::Running => {
+                    // Pairs with `kick_vcpu`: the interrupts raised before are
+                    // seen by the caller, the ones raised after kick it again.
+                    vcpu.kicked.store(false, Ordering::SeqCst);
+                    let state = paused_state.lock().take();
+                    if let Some(state) = state {
+                        if vcpu.paused_state_changed.swap(false, Ordering::AcqRel) {
+                            arch_vcpu.restore_state(&state);
+                        }
+                        // Resumed, the time paused is hidden from the guest.
+                        arch_vcpu.set_time_delta(self.time_delta());
+                    }
+                    return true;
+                }
+                VmState::Paused => {
+                    let mut state = paused_state.lock();
+                    if self.step_request.load(Ordering::Acquire) == id + 1 {
+                        // The step starts from the state the debugger may have
+                        // changed, and the state is published again after it.
+                        if let Some(state) = state.take() {
+                            if vcpu.paused_state_changed.swap(false, Ordering::AcqRel) {
+                                arch_vcpu.restore_state(&state);
+                            }
+                        }
+                        arch_vcpu.set_single_step(true);
+                        return true;
+                    }
+                    if state.is_none() {
+                        *state = Some(arch_vcpu.save_state());
+                    }
+                    drop(state);
+                    // Spin, as yielding may move the task to another hart.
+                    core::hint::spin_loop()
+                }
+                VmState::Created | VmState::Shutdown => return false,
+            }
+        }
+    }
+
+    /// Returns the state of vCPU `id` of the paused VM, once it is out of the
+    /// guest, or `None` if it is not started.
+    pub fn paused_vcpu_state(&self, id: usize) -> Option<VCpuState> {
+        let vcpu = self.vcpus.get(id)?;
+        while matches!(
+            vcpu.state.load(Ordering::Acquire),
+            VCPU_RUNNING | VCPU_WAITING
+        ) {
+            let state = vcpu.paused_state.lock().clone();
+            if state.is_some() {
+                return state;
+            }
+            thread::yield_now();
+        }
+        None
+    }
+
+    /// Changes the state of vCPU `id` of the paused VM, once it is out of the
+    /// guest. The vCPU resumes with `state`.
+    pub fn set_paused_vcpu_state(&self, id: usize, state: VCpuState) -> AxResult {
+        if self.state() != VmState::Paused || self.paused_vcpu_state(id).is_none() {
+            return ax_err!(BadState, "vCPU not paused");
+        }
+        let vcpu = &self.vcpus[id];
+        *vcpu.paused_state.lock() = Some(state);
+        vcpu.paused_state_changed.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Takes the state vCPU `id` has to start with, if the VM was restored
+    /// from a snapshot.
+    pub fn take_restored_state(&self, id: usize) -> Option<VCpuState> {
+        self.vcpus[id].restored_state.lock().take()
+    }
+
+    fn kick_all(&self) {
+        let this_hart = axhal::cpu::this_cpu_id();
+        for vcpu in &self.vcpus {
+            self.kick_vcpu(vcpu, this_hart);
+        }
+    }
+
+    /// Asks the task of vCPU `id` to start it at `entry`, with `arg` in `a1`.
+    pub fn start_vcpu(&self, id: usize, entry: usize, arg: usize) -> AxResult {
+        let Some(vcpu) = self.vcpus.get(id) else {
+            return ax_err!(InvalidInput, "no such vCPU");
+        };
+        let not_stopped = |state| match state {
+            VCPU_ABSENT => ax_err!(InvalidInput, "vCPU not present"),
+            _ => ax_err!(AlreadyExists, "vCPU already started"),
+        };
+        let state = vcpu.state.load(Ordering::Acquire);
+        if state != VCPU_STOPPED {
+            return not_stopped(state);
+        }
+        vcpu.entry.store(entry, Ordering::Relaxed);
+        vcpu.arg.store(arg, Ordering::Relaxed);
+        vcpu.state
+            .compare_exchange(
+                VCPU_STOPPED,
+                VCPU_START_PENDING,
+                Ordering::Release,
+                Ordering::Relaxed,
+            )
+            .map(|_| ())
+            .or_else(not_stopped)
+    }
+
+    /// Waits until vCPU `id` is asked to start, and returns its entry point
+    /// and argument, or `None` if the VM is shut down.
+    ///
+    /// It yields while waiting, so the calling task may move to another hart.
+    pub fn wait_for_start(&self, id: usize) -> Option<(usize, usize)> {
+        let vcpu = &self.vcpus[id];
+        while vcpu.state.load(Ordering::Acquire) != VCPU_START_PENDING {
+            if self.state() == VmState::Shutdown {
+                return None;
+            }
+            thread::yield_now();
+        }
+        Some((
+            vcpu.entry.load(Ordering::Relaxed),
+            vcpu.arg.load(Ordering::Relaxed),
+        ))
+    }
+
+    /// Records that vCPU `id` is running on the physical hart `hart`.
+    pub fn set_running(&self, id: usize, hart: usize) {
+        let vcpu = &self.vcpus[id];
+        vcpu.hart.store(hart, Ordering::Relaxed);
+        // Pairs with `send_ipi`: either the sender sees the vCPU running and
+        // kicks it, or the vCPU sees the pending IPI before entering the guest.
+        vcpu.state.store(VCPU_RUNNING, Ordering::SeqCst);
+    }
+
+    /// Called by the task of vCPU `id`, which executed `wfi`: blocks it until
+    /// the vCPU is kicked, or for `timeout` at most. The task may then resume
+    /// on another hart, see [`Vm::set_running`].
+    pub fn wait_for_kick(&self, id: usize, timeout: Option<Duration>) {
+        let vcpu = &self.vcpus[id];
+        // Pairs with `kick_vcpu`, which notifies the waiting vCPUs.
+        vcpu.state.store(VCPU_WAITING, Ordering::SeqCst);
+        let kicked = || vcpu.kicked.load(Ordering::SeqCst);
+        match timeout {
+            Some(timeout) => {
+                vcpu.wfi_queue.wait_timeout_until(timeout, kicked);
+            }
+            None => vcpu.wfi_queue.wait_until(kicked),
+        }
+    }
+
+    /// Records that vCPU `id` has stopped itself.
+    pub fn set_stopped(&self, id: usize) {
+        let vcpu = &self.vcpus[id];
+        vcpu.ipi_pending.store(false, Ordering::Relaxed);
+        vcpu.hart.store(usize::MAX, Ordering::Relaxed);
+        vcpu.state.store(VCPU_STOPPED, Ordering::Release);
+    }
+
+    /// Records an exit of vCPU `id` in its statistics, see
+    /// [`ExitStats::record`].
+    pub fn record_exit(
+        &self,
+        id: usize,
+        reason: &'static str,
+        csr: Option<u16>,
+        guest_time_ns: u64,
+        time_ns: u64,
+    ) {
+        self.vcpus[id]
+            .exit_stats
+            .lock()
+            .record(reason, csr, guest_time_ns, time_ns);
+    }
+
+    /// Returns the exit statistics of each vCPU.
+    pub fn exit_stats(&self) -> Vec<ExitStats> {
+        self.vcpus
+            .iter()
+            .map(|vcpu| vcpu.exit_stats.lock().clone())
+            .collect()
+    }
+
+    /// Sends a virtual IPI to the vCPUs in `hart_mask`, as the SBI `send_ipi`
+    /// call.
+    ///
+    /// Running vCPUs on other harts are kicked out of the guest with a
+    /// physical IPI, so that their tasks inject the virtual one. Nothing is
+    /// sent if the mask selects a vCPU that does not exist, or is absent.
+    pub fn send_ipi(&self, hart_mask: usize, hart_mask_base: usize) -> AxResult {
+        let selected = |id: usize| {
+            self.vcpu_present(id)
+                && (hart_mask_base == usize::MAX
+                    || (id >= hart_mask_base
+                        && id - hart_mask_base < usize::BITS as usize
+                        && hart_mask & (1 << (id - hart_mask_base)) != 0))
+        };
+        if hart_mask_base != usize::MAX {
+            let num_selected = (0..self.vcpus.len()).filter(|&id| selected(id)).count();
+            if num_selected != hart_mask.count_ones() as usize {
+                return ax_err!(InvalidInput, "no such vCPU in the hart mask");
+            }
+        }
+        let this_hart = axhal::cpu::this_cpu_id();
+        for (id, vcpu) in self.vcpus.iter().enumerate() {
+            if selected(id) {
+                vcpu.ipi_pending.store(true, Ordering::SeqCst);
+                self.kick_vcpu(vcpu, this_hart);
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes vCPU `id` exit the guest if it is running on another hart, or
+    /// wakes it up if it waits for an interrupt, so that its task sees a new
+    /// interrupt before entering the guest again.
+    pub fn kick(&self, id: usize) {
+        if let Some(vcpu) = self.vcpus.get(id) {
+            self.kick_vcpu(vcpu, axhal::cpu::this_cpu_id());
+        }
+    }
+
+    fn kick_vcpu(&self, vcpu: &VCpuSlot, this_hart: usize) {
+        vcpu.kicked.store(true, Ordering::SeqCst);
+        match vcpu.state.load(Ordering::SeqCst) {
+            VCPU_RUNNING => {
+                let hart = vcpu.hart.load(Ordering::Relaxed);
+                if hart != this_hart {
+                    let _ = sbi_rt::send_ipi(1, hart);
+                }
+            }
+            VCPU_WAITING => {
+                vcpu.wfi_queue.notify_one(false);
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the HSM state of vCPU `id`, as the SBI `hart_get_status` call.
+    /// An absent vCPU does not exist for the guest.
+    pub fn vcpu_status(&self, id: usize) -> AxResult<usize> {
+        let Some(vcpu) = self.vcpus.get(id) else {
+            return ax_err!(InvalidInput, "no such vCPU");
+        };
+        Ok(match vcpu.state.load(Ordering::Acquire) {
+            VCPU_ABSENT => return ax_err!(InvalidInput, "vCPU not present"),
+            VCPU_RUNNING | VCPU_WAITING => HART_STARTED,
+            VCPU_START_PENDING => HART_START_PENDING,
+            _ => HART_STOPPED,
+        })
+    }
+
+    /// Takes the pending virtual IPI of vCPU `id`.
+    pub fn take_ipi(&self, id: usize) -> bool {
+        self.vcpus[id].ipi_pending.swap(false, Ordering::SeqCst)
+    }
+
+    /// Whether `gpa` is in the guest RAM.
+    pub fn is_ram(&self, gpa: VirtAddr) -> bool {
+        (self.config.mem_base..self.config.mem_base + self.config.mem_size)
+            .contains(&gpa.as_usize())
+    }
+
+    /// Maps the page of `gpa`, whose `access` faulted outside the registered
+    /// MMIO regions: a zeroed page in the guest RAM, allocated on demand, or
+    /// the host page of a passthrough device. Other addresses, and accesses
+    /// the guest RAM does not allow, are an error.
+    pub fn map_on_fault(&self, gpa: VirtAddr, access: MappingFlags) -> AxResult {
+        let page = gpa.align_down_4k();
+        let mapping_flags = MappingFlags::from_bits(0xf).unwrap();
+        let mut aspace = self.aspace.lock();
+        let res = if self.is_ram(gpa) {
+            match aspace.page_table().query(page) {
+                // Other vCPUs may have mapped it already.
+                Ok((_, flags, _)) if flags.contains(access) => Ok(()),
+                // Never allocated, or being reclaimed by the balloon.
+                query
+                    if query.map_or(true, |(_, flags, _)| flags.is_empty())
+                        && aspace.handle_page_fault(page, access) =>
+                {
+                    self.dirty_log.mark_dirty(gpa);
+                    Ok(())
+                }
+                Ok(_)
+                    if access.contains(MappingFlags::WRITE)
+                        && self.dirty_log.handle_write_fault(&mut aspace, gpa)? =>
+                {
+                    Ok(())
+                }
+                _ => ax_err!(BadAddress, "access not allowed in the guest RAM"),
+            }
+        } else if (PASSTHROUGH_BASE..PASSTHROUGH_BASE + PASSTHROUGH_SIZE).contains(&gpa.as_usize())
+        {
+            aspace.map_linear(page, page.as_usize().into(), PAGE_SIZE_4K, mapping_flags)
+        } else {
+            return ax_err!(BadAddress, "no RAM nor device at the address");
+        };
+        match res {
+            // Other vCPUs may have mapped it already.
+            Err(AxError::AlreadyExists) => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Starts logging the pages of the guest RAM written by the guest, see
+    /// [`Vm::take_dirty_log`].
+    ///
+    /// Like the other dirty log methods, it must not be called by the task of
+    /// a vCPU of the VM, as it waits for them to flush their TLBs.
+    pub fn start_dirty_log(&self) -> AxResult {
+        self.dirty_log.start(&mut self.aspace.lock())?;
+        self.flush_ept_all();
+        Ok(())
+    }
+
+    /// Stops logging the pages written by the guest.
+    pub fn stop_dirty_log(&self) -> AxResult {
+        self.dirty_log.stop(&mut self.aspace.lock())
+    }
+
+    /// Returns the bitmap of the pages of the guest RAM written by the guest
+    /// since the log was started or last taken, bit `n` for the page at
+    /// `mem_base + n * 4K`, and starts a new log.
+    pub fn take_dirty_log(&self) -> AxResult<Vec<u64>> {
+        let dirty = self.dirty_log.collect(&mut self.aspace.lock())?;
+        self.flush_ept_all();
+        self.dirty_log.clear(&dirty);
+        Ok(dirty)
+    }
+
+    /// Makes all the vCPUs flush their G-stage TLBs, and waits until the ones
+    /// in the guest have.
+    ///
+    /// It must not be called by the task of a vCPU of the VM, which would
+    /// wait for itself.
+    pub fn flush_ept_all(&self) {
+        let gen = self.ept_gen.fetch_add(1, Ordering::AcqRel) + 1;
+        self.kick_all();
+        for vcpu in &self.vcpus {
+            // The others flush before entering the guest again.
+            while vcpu.state.load(Ordering::SeqCst) == VCPU_RUNNING
+                && self.state() == VmState::Running
+                && vcpu.ept_gen.load(Ordering::Acquire) < gen
+            {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Called by the task of vCPU `id` before entering the guest: calls
+    /// `flush` if the G-stage mappings changed since the last call.
+    pub fn sync_ept(&self, id: usize, flush: impl FnOnce()) {
+        let gen = self.ept_gen.load(Ordering::Acquire);
+        let vcpu = &self.vcpus[id];
+        if vcpu.ept_gen.load(Ordering::Relaxed) != gen {
+            flush();
+            vcpu.ept_gen.store(gen, Ordering::Release);
+        }
+    }
+
+    /// Whether a debugger is attached, so breakpoints in the guest exit to
+    /// it.
+    pub fn debugging(&self) -> bool {
+        self.debugging.load(Ordering::Acquire)
+    }
+
+    pub fn set_debugging(&self, debugging: bool) {
+        self.debugging.store(debugging, Ordering::Release);
+        self.breakpoint_stop.store(0, Ordering::Release);
+    }
+
+    /// Called by the task of vCPU `id` when it hits a breakpoint: pauses the
+    /// VM for the debugger.
+    pub fn stop_at_breakpoint(&self, id: usize) {
+        // Only the first of the vCPUs hitting breakpoints at once is reported.
+        let _ =
+            self.breakpoint_stop
+                .compare_exchange(0, id + 1, Ordering::AcqRel, Ordering::Acquire);
+        let _ = self.pause();
+    }
+
+    /// Runs vCPU `id` of the paused VM for an instruction, the other vCPUs
+    /// staying paused, and returns once it is out of the guest again.
+    pub fn step_vcpu(&self, id: usize) -> AxResult {
+        if self.state() != VmState::Paused || self.paused_vcpu_state(id).is_none() {
+            return ax_err!(BadState, "vCPU not paused");
+        }
+        self.step_request.store(id + 1, Ordering::Release);
+        // The vCPU may stop, or the VM shut down, during the step.
+        while self.step_request.load(Ordering::Acquire) == id + 1
+            && self.state() == VmState::Paused
+            && matches!(
+                self.vcpus[id].state.load(Ordering::Acquire),
+                VCPU_RUNNING | VCPU_WAITING
+            )
+        {
+            thread::yield_now();
+        }
+        self.end_step(id);
+        Ok(())
+    }
+
+    /// Called by the task of vCPU `id` once its step is done.
+    pub fn end_step(&self, id: usize) {
+        let _ = self
+            .step_request
+            .compare_exchange(id + 1, 0, Ordering::AcqRel, Ordering::Acquire);
+    }
+
+    /// Whether each VM exit is logged.
+    pub fn trace_exits(&self) -> bool {
+        self.trace_exits.load(Ordering::Relaxed)
+    }
+
+    /// Logs each VM exit at the trace level, or stops.
+    pub fn set_trace_exits(&self, enable: bool) {
+        self.trace_exits.store(enable, Ordering::Relaxed);
+    }
+
+    /// Takes the vCPU which stopped the VM at a breakpoint, if any.
+    pub fn take_breakpoint_stop(&self) -> Option<usize> {
+        self.breakpoint_stop
+            .swap(0, Ordering::AcqRel)
+            .checked_sub(1)
+    }
+}
+
+/// Loads the image, the initrd and the device tree of the VM described by
+/// `config` into its guest memory, and returns the entry point and the
+/// address of the device tree.
+fn load_guest(config: &VmConfig, aspace: &mut AddrSpace) -> AxResult<(usize, usize)> {
+    let entry = load_vm_image(config, aspace)?;
+    let initrd = load_initrd(config, aspace)?;
+    let fdt = vm_fdt::build_fdt(config, initrd.as_ref());
+    let fdt_addr = vm_fdt::load_fdt(&fdt, config, aspace)?;
+    if initrd.is_some_and(|initrd| initrd.end > fdt_addr) {
+        return ax_err!(InvalidInput, "initrd overlaps the device tree");
+    }
+    Ok((entry, fdt_addr))
+}
@@ -0,0 +1,147 @@
+//! Live migration of VMs to another host, over TCP.
+//!
+//! The source sends the guest RAM while the guest runs, with the dirty log
+//! on: first the pages allocated so far, then in rounds the pages written
+//! during the previous one. Once few pages are left, or after
+//! [`MAX_ROUNDS`], it pauses the VM, sends the last pages and the state of
+//! the vCPUs, and shuts the VM down once the destination acknowledged it.
+//! Like with snapshots, the state of the devices is not sent.
+//!
+//! The stream reuses the format of snapshots: the magic, the version and the
+//! layout of the VM, then tagged parts, each batch of pages after
+//! [`TAG_PAGES`], and the state of the vCPUs after [`TAG_VCPUS`], which ends
+//! the stream. The destination answers with [`MIGRATION_ACK`].
+
+use alloc::vec::Vec;
+use axerrno::{ax_err, AxResult};
+use core::sync::atomic::Ordering;
+use memory_addr::{VirtAddr, PAGE_SIZE_4K};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+
+use super::snapshot::{read_word, write_word};
+use super::{Vm, VmState};
+
+const MIGRATION_MAGIC: u64 = u64::from_le_bytes(*b"AXVMMIGR");
+const MIGRATION_VERSION: u64 = 1;
+const MIGRATION_ACK: u64 = u64::from_le_bytes(*b"AXVMDONE");
+
+/// Followed by a batch of pages.
+const TAG_PAGES: usize = 1;
+/// Followed by the state of the vCPUs, the last part.
+const TAG_VCPUS: usize = 2;
+
+/// The rounds of pages sent while the guest runs, at most.
+const MAX_ROUNDS: usize = 8;
+/// The VM is paused once at most this many pages are left to send.
+const MAX_FINAL_PAGES: usize = 256;
+
+impl Vm {
+    /// Migrates the running VM to the host receiving it at `addr`, see
+    /// [`Vm::receive_migration`]. The VM is shut down once migrated, or goes
+    /// on running if the migration fails.
+    ///
+    /// It must not be called by the task of a vCPU of the VM.
+    pub fn migrate_to(&self, addr: SocketAddr) -> AxResult {
+        if self.state() != VmState::Running {
+            return ax_err!(BadState, "VM not running");
+        }
+        let mut stream = TcpStream::connect(addr)?;
+        info!("[VM {}] migrating to {}", self.id, addr);
+        self.start_dirty_log()?;
+        let res = self.send_migration(&mut stream);
+        let _ = self.stop_dirty_log();
+        match res {
+            Ok(()) => {
+                info!("[VM {}] migrated to {}", self.id, addr);
+                self.shutdown()
+            }
+            Err(err) => {
+                if self.state() == VmState::Paused {
+                    self.resume()?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn send_migration(&self, stream: &mut TcpStream) -> AxResult {
+        write_word(stream, MIGRATION_MAGIC as usize)?;
+        write_word(stream, MIGRATION_VERSION as usize)?;
+        self.write_layout(stream)?;
+
+        let mut pages = self.allocated_pages();
+        for round in 0..MAX_ROUNDS {
+            if pages.len() <= MAX_FINAL_PAGES {
+                break;
+            }
+            debug!(
+                "[VM {}] migration round {}: {} pages",
+                self.id,
+                round,
+                pages.len()
+            );
+            write_word(stream, TAG_PAGES)?;
+            self.write_pages(stream, &pages)?;
+            pages = self.dirty_pages()?;
+        }
+
+        self.pause()?;
+        // The pages left, and the ones written since the dirty log was taken.
+        pages.extend(self.dirty_pages()?);
+        pages.sort_unstable();
+        pages.dedup();
+        debug!(
+            "[VM {}] migration last round: {} pages",
+            self.id,
+            pages.len()
+        );
+        write_word(stream, TAG_PAGES)?;
+        self.write_pages(stream, &pages)?;
+        write_word(stream, TAG_VCPUS)?;
+        self.write_vcpus(stream)?;
+        if read_word(stream)? != MIGRATION_ACK as usize {
+            return ax_err!(InvalidData, "migration not acknowledged");
+        }
+        Ok(())
+    }
+
+    /// Takes the dirty log, as the list of the pages written.
+    fn dirty_pages(&self) -> AxResult<Vec<VirtAddr>> {
+        let bitmap = self.take_dirty_log()?;
+        let num_pages = self.config.mem_size / PAGE_SIZE_4K;
+        Ok((0..num_pages)
+            .filter(|&n| bitmap[n / 64] & (1 << (n % 64)) != 0)
+            .map(|n| VirtAddr::from(self.config.mem_base + n * PAGE_SIZE_4K))
+            .collect())
+    }
+
+    /// Waits on the TCP port `port` for a VM migrated with
+    /// [`Vm::migrate_to`], and receives it into this VM, which is created
+    /// from the same configuration but not booted yet. Booting it resumes the
+    /// migrated one.
+    pub fn receive_migration(&self, port: u16) -> AxResult {
+        self.check_restorable()?;
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))?;
+        info!("[VM {}] waiting for a migration on port {}", self.id, port);
+        let (mut stream, peer) = listener.accept()?;
+        info!("[VM {}] receiving a migration from {}", self.id, peer);
+        if read_word(&mut stream)? != MIGRATION_MAGIC as usize
+            || read_word(&mut stream)? != MIGRATION_VERSION as usize
+        {
+            return ax_err!(InvalidData, "bad migration stream");
+        }
+        self.check_layout(&mut stream)?;
+        loop {
+            match read_word(&mut stream)? {
+                TAG_PAGES => self.read_pages(&mut stream)?,
+                TAG_VCPUS => break,
+                _ => return ax_err!(InvalidData, "bad migration stream"),
+            }
+        }
+        self.read_vcpus(&mut stream)?;
+        write_word(&mut stream, MIGRATION_ACK as usize)?;
+        self.restored.store(true, Ordering::Release);
+        info!("[VM {}] migrated from {}", self.id, peer);
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! Memory shared between host tasks and the guest.
+//!
+//! A shared region is made of host pages mapped into the guest at a guest
+//! physical address of the shared window, which the host task tells the
+//! guest, e.g. in a register of its device. Both sides then exchange data in
+//! place, without copies through the hypervisor.
+
+use alloc::sync::{Arc, Weak};
+use axerrno::{ax_err, AxError, AxResult};
+use axhal::mem::{virt_to_phys, VirtAddr};
+use axhal::paging::MappingFlags;
+use core::ops::Range;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K};
+
+use super::Vm;
+
+/// The window of guest physical addresses of the shared regions, between the
+/// devices and the guest RAM.
+const SHARED_BASE: usize = 0x4000_0000;
+const SHARED_SIZE: usize = 0x4000_0000;
+
+/// Host pages mapped into a guest. They are unmapped from the guest and
+/// freed when dropped.
+pub struct SharedRegion {
+    vm: Weak<Vm>,
+    gpa: usize,
+    size: usize,
+    vaddr: VirtAddr,
+}
+
+impl SharedRegion {
+    /// The guest physical address of the region.
+    pub fn gpa(&self) -> usize {
+        self.gpa
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The region, as the guest sees it. The guest may change it anytime.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.vaddr.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.vaddr.as_mut_ptr(), self.size) }
+    }
+}
+
+impl Drop for SharedRegion {
+    fn drop(&mut self) {
+        if let Some(vm) = self.vm.upgrade() {
+            let res = vm.aspace.lock().unmap(self.gpa.into(), self.size);
+            if let Err(err) = res {
+                warn!(
+                    "[VM {}] failed to unmap shared region {:#x}: {:?}",
+                    vm.id, self.gpa, err
+                );
+            }
+            vm.flush_ept_all();
+            vm.shared_regions
+                .lock()
+                .retain(|range| range.start != self.gpa);
+        }
+        axalloc::global_allocator().dealloc_pages(self.vaddr.as_usize(), self.size / PAGE_SIZE_4K);
+    }
+}
+
+/// Returns the lowest free range of `size` bytes in the shared window.
+fn find_free(used: &[Range<usize>], size: usize) -> Option<usize> {
+    let mut gpa = SHARED_BASE;
+    for range in used {
+        if gpa + size <= range.start {
+            break;
+        }
+        gpa = gpa.max(range.end);
+    }
+    (gpa + size <= SHARED_BASE + SHARED_SIZE).then_some(gpa)
+}
+
+impl Vm {
+    /// Creates a region of `size` bytes, zeroed, shared with the guest of the
+    /// VM.
+    ///
+    /// It must not be dropped by the task of a vCPU of the VM, as unmapping
+    /// waits for the vCPUs to flush their TLBs.
+    pub fn create_shared_region(self: &Arc<Self>, size: usize) -> AxResult<SharedRegion> {
+        if size == 0 {
+            return ax_err!(InvalidInput, "empty shared region");
+        }
+        let size = size.align_up_4k();
+        let num_pages = size / PAGE_SIZE_4K;
+
+        let mut used = self.shared_regions.lock();
+        let Some(gpa) = find_free(&used, size) else {
+            return ax_err!(NoMemory, "shared window full");
+        };
+        let vaddr = VirtAddr::from(
+            axalloc::global_allocator()
+                .alloc_pages(num_pages, PAGE_SIZE_4K)
+                .map_err(|_| AxError::NoMemory)?,
+        );
+        unsafe { core::ptr::write_bytes(vaddr.as_mut_ptr(), 0, size) };
+        let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER;
+        let res = self
+            .aspace
+            .lock()
+            .map_linear(gpa.into(), virt_to_phys(vaddr), size, flags);
+        if let Err(err) = res {
+            axalloc::global_allocator().dealloc_pages(vaddr.as_usize(), num_pages);
+            return Err(err);
+        }
+        let pos = used.partition_point(|range| range.start < gpa);
+        used.insert(pos, gpa..gpa + size);
+        debug!(
+            "[VM {}] shared region at {:#x}, size {:#x}",
+            self.id, gpa, size
+        );
+
+        Ok(SharedRegion {
+            vm: Arc::downgrade(self),
+            gpa,
+            size,
+            vaddr,
+        })
+    }
+}
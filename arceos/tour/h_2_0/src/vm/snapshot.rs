@@ -0,0 +1,212 @@
+//! Snapshots of VMs, in files of the host filesystem.
+//!
+//! A snapshot holds the state of the vCPUs and the pages of the guest RAM
+//! allocated so far. The state of the devices is not saved, they are reset
+//! on restore, so the guest should not have I/O in flight when paused.
+//!
+//! The file is a sequence of little-endian 64-bit words: the magic, the
+//! version, the base and size of the guest RAM and the number of vCPUs. Then
+//! for each vCPU slot, its HSM state, entry and argument, followed by its
+//! [`VCpuState`] if started. Then the number of pages, and for each page, its
+//! guest physical address followed by its content.
+//!
+//! The parts of the format are shared with the migration of VMs, see the
+//! `migrate` module.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use axerrno::{ax_err, ax_err_type, AxError, AxResult};
+use core::sync::atomic::Ordering;
+use memory_addr::{VirtAddr, PAGE_SIZE_4K};
+use riscv_vcpu::VCpuState;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use super::{Vm, VmState, VCPU_ABSENT, VCPU_RUNNING, VCPU_START_PENDING, VCPU_STOPPED};
+use crate::loader::populate_ram;
+
+const SNAPSHOT_MAGIC: u64 = u64::from_le_bytes(*b"AXVMSNAP");
+const SNAPSHOT_VERSION: u64 = 2;
+
+pub(super) fn io_err(err: std::io::Error) -> AxError {
+    ax_err_type!(
+        Io,
+        format!("Failed to transfer the VM state, err {:?}", err)
+    )
+}
+
+pub(super) fn write_word(w: &mut impl Write, word: usize) -> AxResult {
+    w.write_all(&(word as u64).to_le_bytes()).map_err(io_err)
+}
+
+pub(super) fn read_word(r: &mut impl Read) -> AxResult<usize> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf).map_err(io_err)?;
+    Ok(u64::from_le_bytes(buf) as usize)
+}
+
+fn bad_state() -> AxError {
+    ax_err_type!(InvalidData, "bad VM state")
+}
+
+impl Vm {
+    /// Saves the paused VM to the file at `path`.
+    pub fn snapshot(&self, path: &str) -> AxResult {
+        if self.state() != VmState::Paused {
+            return ax_err!(BadState, "VM not paused");
+        }
+        let mut file = File::create(path).map_err(io_err)?;
+        write_word(&mut file, SNAPSHOT_MAGIC as usize)?;
+        write_word(&mut file, SNAPSHOT_VERSION as usize)?;
+        self.write_layout(&mut file)?;
+        self.write_vcpus(&mut file)?;
+        self.write_pages(&mut file, &self.allocated_pages())?;
+        info!("[VM {}] saved to {}", self.id, path);
+        Ok(())
+    }
+
+    /// Restores the VM saved to the file at `path` into this VM, which is
+    /// created from the same configuration but not booted yet. Booting it
+    /// resumes the saved one.
+    pub fn restore(&self, path: &str) -> AxResult {
+        self.check_restorable()?;
+        let mut file = File::open(path).map_err(io_err)?;
+        if read_word(&mut file)? != SNAPSHOT_MAGIC as usize
+            || read_word(&mut file)? != SNAPSHOT_VERSION as usize
+        {
+            return ax_err!(InvalidData, "bad snapshot");
+        }
+        self.check_layout(&mut file)?;
+        self.read_vcpus(&mut file)?;
+        self.read_pages(&mut file)?;
+        self.restored.store(true, Ordering::Release);
+        info!("[VM {}] restored from {}", self.id, path);
+        Ok(())
+    }
+
+    /// Fails unless the VM can be restored: created, and not booted yet.
+    pub(super) fn check_restorable(&self) -> AxResult {
+        if self.state() != VmState::Created || self.restored.load(Ordering::Acquire) {
+            return ax_err!(BadState, "VM already booted");
+        }
+        Ok(())
+    }
+
+    /// Writes the base and size of the guest RAM, and the number of vCPUs.
+    pub(super) fn write_layout(&self, w: &mut impl Write) -> AxResult {
+        write_word(w, self.config.mem_base)?;
+        write_word(w, self.config.mem_size)?;
+        write_word(w, self.num_vcpus())
+    }
+
+    /// Reads the layout written by [`Vm::write_layout`], and fails if it is
+    /// not the one of this VM.
+    pub(super) fn check_layout(&self, r: &mut impl Read) -> AxResult {
+        if read_word(r)? != self.config.mem_base
+            || read_word(r)? != self.config.mem_size
+            || read_word(r)? != self.num_vcpus()
+        {
+            return ax_err!(InvalidInput, "state of another VM configuration");
+        }
+        Ok(())
+    }
+
+    /// Writes the state of the vCPUs of the paused VM.
+    pub(super) fn write_vcpus(&self, w: &mut impl Write) -> AxResult {
+        for (id, vcpu) in self.vcpus.iter().enumerate() {
+            let state = self.paused_vcpu_state(id);
+            let hsm_state = match state {
+                Some(_) => VCPU_RUNNING,
+                None => vcpu.state.load(Ordering::Acquire),
+            };
+            write_word(w, hsm_state as usize)?;
+            write_word(w, vcpu.entry.load(Ordering::Relaxed))?;
+            write_word(w, vcpu.arg.load(Ordering::Relaxed))?;
+            if let Some(state) = state {
+                for &word in state.as_words() {
+                    write_word(w, word)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the state of the vCPUs written by [`Vm::write_vcpus`], and
+    /// starts the vCPUs with it on boot.
+    pub(super) fn read_vcpus(&self, r: &mut impl Read) -> AxResult {
+        for (id, vcpu) in self.vcpus.iter().enumerate() {
+            let hsm_state = read_word(r)? as u8;
+            let entry = read_word(r)?;
+            let arg = read_word(r)?;
+            // The vCPU may have been added or removed since the VM was created.
+            let present = if hsm_state == VCPU_ABSENT {
+                VCPU_ABSENT
+            } else {
+                VCPU_STOPPED
+            };
+            vcpu.state.store(present, Ordering::Release);
+            match hsm_state {
+                VCPU_RUNNING => {
+                    let mut words = [0; VCpuState::NUM_WORDS];
+                    for word in &mut words {
+                        *word = read_word(r)?;
+                    }
+                    let state = VCpuState::from_words(&words).ok_or_else(bad_state)?;
+                    // The guest time goes on from the one saved.
+                    self.set_guest_time(state.time);
+                    self.start_vcpu(id, state.sepc, 0)?;
+                    *vcpu.restored_state.lock() = Some(state);
+                }
+                VCPU_START_PENDING => self.start_vcpu(id, entry, arg)?,
+                VCPU_STOPPED | VCPU_ABSENT => {}
+                _ => return Err(bad_state()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the pages of the guest RAM allocated so far.
+    pub(super) fn allocated_pages(&self) -> Vec<VirtAddr> {
+        let aspace = self.aspace.lock();
+        (self.config.mem_base..self.config.mem_base + self.config.mem_size)
+            .step_by(PAGE_SIZE_4K)
+            .map(VirtAddr::from)
+            .filter(|&page| {
+                matches!(aspace.page_table().query(page), Ok((_, flags, _)) if !flags.is_empty())
+            })
+            .collect()
+    }
+
+    /// Writes the number of `pages`, then each of them with its content.
+    pub(super) fn write_pages(&self, w: &mut impl Write, pages: &[VirtAddr]) -> AxResult {
+        write_word(w, pages.len())?;
+        let mut buf = vec![0; PAGE_SIZE_4K];
+        for &page in pages {
+            // Not locked while writing, for the vCPUs of a running VM. A page
+            // reclaimed by the balloon meanwhile is sent as zeros.
+            if self.aspace.lock().read(page, &mut buf).is_err() {
+                buf.fill(0);
+            }
+            write_word(w, page.as_usize())?;
+            w.write_all(&buf).map_err(io_err)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the pages written by [`Vm::write_pages`] into the guest RAM.
+    pub(super) fn read_pages(&self, r: &mut impl Read) -> AxResult {
+        let num_pages = read_word(r)?;
+        let mut buf = vec![0; PAGE_SIZE_4K];
+        for _ in 0..num_pages {
+            let page = VirtAddr::from(read_word(r)?);
+            if !self.is_ram(page) {
+                return Err(bad_state());
+            }
+            r.read_exact(&mut buf).map_err(io_err)?;
+            let mut aspace = self.aspace.lock();
+            populate_ram(&mut aspace, page, PAGE_SIZE_4K)?;
+            aspace.write(page, &buf)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+//! The device tree of the guest.
+//!
+//! It describes the guest RAM, the vCPUs and the emulated devices, so stock
+//! kernels can discover them. The blob is loaded at the end of the guest RAM
+//! and its address is passed in `a1` to the boot vCPU. The `/chosen` node
+//! gives the location of the initrd, if any.
+//!
+//! The interrupts of the devices go through the virtual PLIC, which sends
+//! them to the supervisor external interrupt of the vCPUs.
+
+use alloc::vec::Vec;
+use axerrno::{ax_err, AxResult};
+use axmm::AddrSpace;
+use core::ops::Range;
+use memory_addr::MemoryAddr;
+
+use crate::guest_mem::GuestMemory;
+use crate::loader::populate_ram;
+use crate::vdev::aplic::{APLIC_BASE, APLIC_SIZE};
+use crate::vdev::clint::{CLINT_BASE, CLINT_SIZE};
+use crate::vdev::goldfish_rtc::{RTC_BASE, RTC_IRQ, RTC_SIZE};
+use crate::vdev::imsic::{IMSIC_BASE, IMSIC_FILE_SIZE, NUM_IDS};
+use crate::vdev::passthrough;
+use crate::vdev::plic::{NUM_SOURCES, PLIC_BASE, PLIC_SIZE};
+use crate::vdev::uart::{UART_BASE, UART_IRQ, UART_SIZE};
+use crate::vdev::virtio_balloon::{VIRTIO_BALLOON_BASE, VIRTIO_BALLOON_IRQ, VIRTIO_BALLOON_SIZE};
+use crate::vdev::virtio_blk::{VIRTIO_BLK_BASE, VIRTIO_BLK_IRQ, VIRTIO_BLK_SIZE};
+use crate::vm::{VmConfig, MAX_VCPUS};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// The clock of the UART of QEMU virt.
+const UART_CLOCK_FREQ: u32 = 3_686_400;
+
+/// The phandle of the interrupt controller of vCPU `n` is `CPU_INTC_PHANDLE + n`.
+const CPU_INTC_PHANDLE: u32 = 1;
+/// The phandle of the PLIC, after the ones of the vCPUs.
+const PLIC_PHANDLE: u32 = CPU_INTC_PHANDLE + MAX_VCPUS as u32;
+/// The phandles of the IMSIC and of the APLIC, replacing the PLIC with the
+/// AIA.
+const IMSIC_PHANDLE: u32 = PLIC_PHANDLE + 1;
+const APLIC_PHANDLE: u32 = PLIC_PHANDLE + 2;
+
+/// The trigger type of the interrupts of the devices in the specifiers of
+/// the APLIC.
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+/// The local interrupts of the CLINT, machine-level software and timer.
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+/// The local interrupts of the PLIC, machine-level and supervisor-level
+/// external.
+const IRQ_M_EXT: u32 = 11;
+const IRQ_S_EXT: u32 = 9;
+
+/// Writer of a flattened device tree, with 2 address and size cells.
+struct FdtWriter {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtWriter {
+    fn new() -> Self {
+        Self {
+            structs: Vec::new(),
+            strings: Vec::new(),
+        }
+    }
+
+    fn push_u32(&mut self, val: u32) {
+        self.structs.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.structs.len() % 4 != 0 {
+            self.structs.push(0);
+        }
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structs.extend_from_slice(name.as_bytes());
+        self.structs.push(0);
+        self.align();
+    }
+
+    fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+    }
+
+    /// Returns the offset of `name` in the strings block, adding it if needed.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&b| b == 0) {
+            if s == name.as_bytes() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+
+    fn prop(&mut self, name: &str, data: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(data.len() as u32);
+        self.push_u32(name_offset);
+        self.structs.extend_from_slice(data);
+        self.align();
+    }
+
+    fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    fn prop_u32(&mut self, name: &str, val: u32) {
+        self.prop(name, &val.to_be_bytes());
+    }
+
+    fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let data: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.prop(name, &data);
+    }
+
+    fn prop_str(&mut self, name: &str, val: &str) {
+        let mut data = Vec::from(val.as_bytes());
+        data.push(0);
+        self.prop(name, &data);
+    }
+
+    fn prop_u64(&mut self, name: &str, val: u64) {
+        self.prop_cells(name, &[(val >> 32) as u32, val as u32]);
+    }
+
+    /// A `reg` property of one region.
+    fn prop_reg(&mut self, base: usize, size: usize) {
+        let (base, size) = (base as u64, size as u64);
+        self.prop_cells(
+            "reg",
+            &[
+                (base >> 32) as u32,
+                base as u32,
+                (size >> 32) as u32,
+                size as u32,
+            ],
+        );
+    }
+
+    /// Returns the blob, without reserved memory entries.
+    fn finish(mut self) -> Vec<u8> {
+        self.push_u32(FDT_END);
+        // The memory reservation block, with its terminating entry only.
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.structs.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            // The boot CPU.
+            0,
+            self.strings.len() as u32,
+            self.structs.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structs);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// Writes the `interrupts` of a device wired to the source `irq` of the
+/// PLIC, or of the APLIC if `aia`, whose specifiers have the trigger type.
+fn interrupts_prop(fdt: &mut FdtWriter, aia: bool, irq: usize) {
+    if aia {
+        fdt.prop_cells("interrupts", &[irq as u32, IRQ_TYPE_LEVEL_HIGH]);
+    } else {
+        fdt.prop_u32("interrupts", irq as u32);
+    }
+}
+
+fn virtio_node(fdt: &mut FdtWriter, aia: bool, base: usize, size: usize, irq: usize) {
+    fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+    fdt.prop_str("compatible", "virtio,mmio");
+    fdt.prop_reg(base, size);
+    interrupts_prop(fdt, aia, irq);
+    fdt.end_node();
+}
+
+/// Builds the device tree of the VM described by `config`, whose initrd, if
+/// any, is at `initrd`.
+pub fn build_fdt(config: &VmConfig, initrd: Option<&Range<usize>>) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.prop_u32("#address-cells", 2);
+    fdt.prop_u32("#size-cells", 2);
+    fdt.prop_str("compatible", "riscv-virtio");
+    fdt.prop_str("model", "arceos,h_2_0");
+
+    let aia = config.aia;
+    fdt.begin_node("chosen");
+    fdt.prop_str("stdout-path", &format!("/soc/serial@{:x}", UART_BASE));
+    if let Some(initrd) = initrd {
+        fdt.prop_u64("linux,initrd-start", initrd.start as u64);
+        fdt.prop_u64("linux,initrd-end", initrd.end as u64);
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", config.mem_base));
+    fdt.prop_str("device_type", "memory");
+    fdt.prop_reg(config.mem_base, config.mem_size);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 0);
+    fdt.prop_u32("timebase-frequency", axconfig::TIMER_FREQUENCY as u32);
+    // All the vCPU slots: the absent vCPUs fail `hart_start` until added.
+    for id in 0..config.max_vcpus {
+        fdt.begin_node(&format!("cpu@{}", id));
+        fdt.prop_str("device_type", "cpu");
+        fdt.prop_u32("reg", id as u32);
+        fdt.prop_str("status", "okay");
+        fdt.prop_str("compatible", "riscv");
+        fdt.prop_str(
+            "riscv,isa",
+            if aia {
+                "rv64imafdc_ssaia"
+            } else {
+                "rv64imafdc"
+            },
+        );
+        fdt.prop_str("mmu-type", "riscv,sv39");
+        fdt.begin_node("interrupt-controller");
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_str("compatible", "riscv,cpu-intc");
+        fdt.prop_u32("phandle", CPU_INTC_PHANDLE + id as u32);
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.prop_u32("#address-cells", 2);
+    fdt.prop_u32("#size-cells", 2);
+    fdt.prop_str("compatible", "simple-bus");
+    fdt.prop_empty("ranges");
+    fdt.prop_u32(
+        "interrupt-parent",
+        if aia { APLIC_PHANDLE } else { PLIC_PHANDLE },
+    );
+
+    fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+    fdt.prop_str("compatible", "riscv,clint0");
+    fdt.prop_reg(CLINT_BASE, CLINT_SIZE);
+    let irqs: Vec<u32> = (0..config.max_vcpus as u32)
+        .flat_map(|id| {
+            let intc = CPU_INTC_PHANDLE + id;
+            [intc, IRQ_M_SOFT, intc, IRQ_M_TIMER]
+        })
+        .collect();
+    fdt.prop_cells("interrupts-extended", &irqs);
+    fdt.end_node();
+
+    if !aia {
+        fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+        fdt.prop("compatible", b"sifive,plic-1.0.0\0riscv,plic0\0");
+        fdt.prop_reg(PLIC_BASE, PLIC_SIZE);
+        fdt.prop_u32("#address-cells", 0);
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_u32("riscv,ndev", NUM_SOURCES as u32 - 1);
+        let irqs: Vec<u32> = (0..config.max_vcpus as u32)
+            .flat_map(|id| {
+                let intc = CPU_INTC_PHANDLE + id;
+                [intc, IRQ_M_EXT, intc, IRQ_S_EXT]
+            })
+            .collect();
+        fdt.prop_cells("interrupts-extended", &irqs);
+        fdt.prop_u32("phandle", PLIC_PHANDLE);
+        fdt.end_node();
+    } else {
+        fdt.begin_node(&format!("imsics@{:x}", IMSIC_BASE));
+        fdt.prop("compatible", b"qemu,imsics\0riscv,imsics\0");
+        fdt.prop_reg(IMSIC_BASE, config.max_vcpus * IMSIC_FILE_SIZE);
+        let irqs: Vec<u32> = (0..config.max_vcpus as u32)
+            .flat_map(|id| [CPU_INTC_PHANDLE + id, IRQ_S_EXT])
+            .collect();
+        fdt.prop_cells("interrupts-extended", &irqs);
+        fdt.prop_u32("#interrupt-cells", 0);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_empty("msi-controller");
+        fdt.prop_u32("#msi-cells", 0);
+        fdt.prop_u32("riscv,num-ids", NUM_IDS as u32 - 1);
+        fdt.prop_u32("phandle", IMSIC_PHANDLE);
+        fdt.end_node();
+
+        fdt.begin_node(&format!("aplic@{:x}", APLIC_BASE));
+        fdt.prop("compatible", b"qemu,aplic\0riscv,aplic\0");
+        fdt.prop_reg(APLIC_BASE, APLIC_SIZE);
+        fdt.prop_u32("#interrupt-cells", 2);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_u32("msi-parent", IMSIC_PHANDLE);
+        fdt.prop_u32("riscv,num-sources", NUM_SOURCES as u32 - 1);
+        fdt.prop_u32("phandle", APLIC_PHANDLE);
+        fdt.end_node();
+    }
+
+    fdt.begin_node(&format!("serial@{:x}", UART_BASE));
+    fdt.prop_str("compatible", "ns16550a");
+    fdt.prop_reg(UART_BASE, UART_SIZE);
+    fdt.prop_u32("clock-frequency", UART_CLOCK_FREQ);
+    interrupts_prop(&mut fdt, aia, UART_IRQ);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("rtc@{:x}", RTC_BASE));
+    fdt.prop_str("compatible", "google,goldfish-rtc");
+    fdt.prop_reg(RTC_BASE, RTC_SIZE);
+    interrupts_prop(&mut fdt, aia, RTC_IRQ);
+    fdt.end_node();
+
+    if config.disk.is_some() {
+        virtio_node(
+            &mut fdt,
+            aia,
+            VIRTIO_BLK_BASE,
+            VIRTIO_BLK_SIZE,
+            VIRTIO_BLK_IRQ,
+        );
+    }
+    if config.balloon.is_some() {
+        virtio_node(
+            &mut fdt,
+            aia,
+            VIRTIO_BALLOON_BASE,
+            VIRTIO_BALLOON_SIZE,
+            VIRTIO_BALLOON_IRQ,
+        );
+    }
+    #[cfg(feature = "vnet")]
+    if config.net_peer.is_some() {
+        use crate::vdev::virtio_net::{VIRTIO_NET_BASE, VIRTIO_NET_IRQ, VIRTIO_NET_SIZE};
+        virtio_node(
+            &mut fdt,
+            aia,
+            VIRTIO_NET_BASE,
+            VIRTIO_NET_SIZE,
+            VIRTIO_NET_IRQ,
+        );
+    }
+    if let Some(base) = config.blk_passthrough {
+        if let Some(irq) = passthrough::slot_irq(base) {
+            virtio_node(&mut fdt, aia, base, VIRTIO_BLK_SIZE, irq);
+        }
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+/// Loads the device tree `fdt` at the end of the guest RAM of `config`, and
+/// returns its guest physical address.
+pub fn load_fdt(fdt: &[u8], config: &VmConfig, aspace: &mut AddrSpace) -> AxResult<usize> {
+    let ram_end = config.mem_base + config.mem_size;
+    let addr = match ram_end.checked_sub(fdt.len()) {
+        Some(addr) if addr.align_down_4k() >= config.mem_base => addr.align_down_4k(),
+        _ => return ax_err!(InvalidInput, "no room for the device tree"),
+    };
+    populate_ram(aspace, addr.into(), fdt.len())?;
+    GuestMemory::new(aspace).write(addr.into(), fdt)?;
+    Ok(addr)
+}
@@ -3,8 +3,9 @@ extern crate alloc;
 use alloc::string::String;
 use core::fmt;
 
-use super::FileType;
+use super::{FileType, Metadata};
 use crate::io::Result;
+use axerrno::AxError;
 
 use arceos_api::fs as api;
 
@@ -112,6 +113,17 @@ impl<'a> DirEntry<'a> {
     pub fn file_type(&self) -> FileType {
         self.entry_type
     }
+
+    /// Returns the metadata for the file that this entry points at.
+    pub fn metadata(&self) -> Result<Metadata> {
+        super::metadata(&self.path())
+    }
+}
+
+impl fmt::Debug for ReadDir<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReadDir").field(&self.path).finish()
+    }
 }
 
 impl fmt::Debug for DirEntry<'_> {
@@ -145,10 +157,20 @@ impl DirBuilder {
         }
     }
 
-    fn create_dir_all(&self, _path: &str) -> Result<()> {
-        axerrno::ax_err!(
-            Unsupported,
-            "Recursive directory creation is not supported yet"
-        )
+    fn create_dir_all(&self, path: &str) -> Result<()> {
+        let path = path.trim_end_matches('/');
+        // The ancestors first, from the root.
+        let ancestors = path.match_indices('/').map(|(i, _)| &path[..i]);
+        for dir in ancestors.chain(Some(path)) {
+            if dir.is_empty() || dir.ends_with('/') {
+                continue;
+            }
+            match api::ax_create_dir(dir) {
+                Ok(()) => {}
+                Err(AxError::AlreadyExists) if super::metadata(dir)?.is_dir() => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
     }
 }
@@ -1,4 +1,5 @@
 use crate::io::{prelude::*, Result, SeekFrom};
+use crate::time::SystemTime;
 use core::fmt;
 
 use arceos_api::fs as api;
@@ -16,6 +17,10 @@ pub struct File {
 }
 
 /// Metadata information about a file.
+///
+/// It comes from the attributes of the file node, which have no timestamps:
+/// [`Metadata::modified`] and the like return an `Unsupported` error, as
+/// `std` does on platforms without them.
 pub struct Metadata(api::AxFileAttr);
 
 /// Options and flags which can be used to configure how a file is opened.
@@ -40,7 +45,8 @@ impl OpenOptions {
         self
     }
 
-    /// Sets the option for the append mode.
+    /// Sets the option for the append mode: each write goes to the end of
+    /// the file, whatever the cursor.
     pub fn append(&mut self, append: bool) -> &mut Self {
         self.0.append(append);
         self
@@ -70,6 +76,12 @@ impl OpenOptions {
     }
 }
 
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Metadata {
     /// Returns the file type for this metadata.
     pub const fn file_type(&self) -> FileType {
@@ -90,6 +102,32 @@ impl Metadata {
         self.0.is_file()
     }
 
+    /// Returns `true` if this metadata is for a symbolic link.
+    pub fn is_symlink(&self) -> bool {
+        self.0.file_type().is_symlink()
+    }
+
+    /// Returns the unix-style mode of the file: its type in the `S_IFMT` bits,
+    /// and its permissions in the low 9 bits.
+    pub fn mode(&self) -> u32 {
+        (self.0.file_type() as u32) << 12 | self.0.perm().bits() as u32
+    }
+
+    /// Returns the last modification time of the file.
+    pub fn modified(&self) -> Result<SystemTime> {
+        axerrno::ax_err!(Unsupported, "no file timestamps")
+    }
+
+    /// Returns the last access time of the file.
+    pub fn accessed(&self) -> Result<SystemTime> {
+        axerrno::ax_err!(Unsupported, "no file timestamps")
+    }
+
+    /// Returns the creation time of the file.
+    pub fn created(&self) -> Result<SystemTime> {
+        axerrno::ax_err!(Unsupported, "no file timestamps")
+    }
+
     /// Returns the size of the file, in bytes, this metadata is for.
     #[allow(clippy::len_without_is_empty)]
     pub const fn len(&self) -> u64 {
@@ -118,6 +156,8 @@ impl fmt::Debug for Metadata {
             .field("file_type", &self.file_type())
             .field("is_dir", &self.is_dir())
             .field("is_file", &self.is_file())
+            .field("len", &self.len())
+            .field("mode", &format_args!("{:#o}", self.mode()))
             .field("permissions", &self.permissions())
             .finish_non_exhaustive()
     }
@@ -153,7 +193,8 @@ impl File {
     }
 
     /// Truncates or extends the underlying file, updating the size of
-    /// this file to become `size`.
+    /// this file to become `size`. An extended file is filled with zeros, so
+    /// it can be used to preallocate a file.
     pub fn set_len(&self, size: u64) -> Result<()> {
         api::ax_truncate_file(&self.inner, size)
     }
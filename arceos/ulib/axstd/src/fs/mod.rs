@@ -36,6 +36,14 @@ pub fn write<C: AsRef<[u8]>>(path: &str, contents: C) -> io::Result<()> {
     File::create(path)?.write_all(contents.as_ref())
 }
 
+/// Copies the contents of a file to another, which is created or truncated,
+/// and returns the number of bytes copied.
+pub fn copy(from: &str, to: &str) -> io::Result<u64> {
+    let mut reader = File::open(from)?;
+    let mut writer = File::create(to)?;
+    io::copy(&mut reader, &mut writer)
+}
+
 /// Given a path, query the file system to get information about a file,
 /// directory, etc.
 pub fn metadata(path: &str) -> io::Result<Metadata> {
@@ -63,6 +71,25 @@ pub fn remove_dir(path: &str) -> io::Result<()> {
     arceos_api::fs::ax_remove_dir(path)
 }
 
+/// Removes a directory at this path, after removing all its contents.
+///
+/// Symbolic links are removed, not followed.
+#[cfg(feature = "alloc")]
+pub fn remove_dir_all(path: &str) -> io::Result<()> {
+    // Listed first, as the directory must not change while it is read.
+    let entries = read_dir(path)?
+        .map(|entry| entry.map(|entry| (entry.path(), entry.file_type().is_dir())))
+        .collect::<io::Result<Vec<_>>>()?;
+    for (entry, is_dir) in entries {
+        if is_dir {
+            remove_dir_all(&entry)?;
+        } else {
+            remove_file(&entry)?;
+        }
+    }
+    remove_dir(path)
+}
+
 /// Removes a file from the filesystem.
 pub fn remove_file(path: &str) -> io::Result<()> {
     arceos_api::fs::ax_remove_file(path)
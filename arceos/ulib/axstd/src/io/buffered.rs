@@ -0,0 +1,345 @@
+//! Buffered readers and writers.
+
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ptr;
+
+use crate::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+
+/// The size of the buffer of a [`BufReader`] or a [`BufWriter`].
+const DEFAULT_BUF_SIZE: usize = 1024;
+
+/// Adds buffering to a reader, similar to
+/// [`std::io::BufReader`](https://doc.rust-lang.org/std/io/struct.BufReader.html).
+///
+/// Seeking discards the buffered data, except with
+/// [`seek_relative`](BufReader::seek_relative) within the buffer. The buffer
+/// is inline, so it can be used without `alloc`.
+pub struct BufReader<R> {
+    inner: R,
+    /// The next byte to read in the buffer.
+    pos: usize,
+    /// The end of the data read in the buffer.
+    filled: usize,
+    buf: [u8; DEFAULT_BUF_SIZE],
+}
+
+impl<R> BufReader<R> {
+    /// Creates a new `BufReader` with a default buffer capacity (1 KB).
+    pub const fn new(inner: R) -> BufReader<R> {
+        Self {
+            inner,
+            pos: 0,
+            filled: 0,
+            buf: [0; DEFAULT_BUF_SIZE],
+        }
+    }
+
+    /// Gets a reference to the underlying reader.
+    pub const fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying reader.
+    ///
+    /// It is inadvisable to directly read from the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns a reference to the internally buffered data.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+
+    /// Returns the number of bytes the internal buffer can hold at once.
+    pub const fn capacity(&self) -> usize {
+        DEFAULT_BUF_SIZE
+    }
+
+    /// Unwraps this `BufReader`, returning the underlying reader.
+    ///
+    /// The buffered data is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn discard_buffer(&mut self) {
+        self.pos = 0;
+        self.filled = 0;
+    }
+}
+
+impl<R: Seek> BufReader<R> {
+    /// Seeks relative to the current position, keeping the buffer if the new
+    /// position is in it.
+    pub fn seek_relative(&mut self, offset: i64) -> io::Result<()> {
+        let pos = self.pos as i64 + offset;
+        if (0..=self.filled as i64).contains(&pos) {
+            self.pos = pos as usize;
+            Ok(())
+        } else {
+            self.seek(SeekFrom::Current(offset)).map(drop)
+        }
+    }
+}
+
+impl<R: Read> Read for BufReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Large reads bypass the empty buffer.
+        if self.pos == self.filled && buf.len() >= DEFAULT_BUF_SIZE {
+            self.discard_buffer();
+            return self.inner.read(buf);
+        }
+        let n = {
+            let data = self.fill_buf()?;
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for BufReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.filled {
+            self.filled = self.inner.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
+    }
+}
+
+impl<R: Seek> Seek for BufReader<R> {
+    /// Seeks in the underlying reader, and discards the buffer.
+    ///
+    /// With [`SeekFrom::Current`], the offset is from the position of the
+    /// `BufReader`, not of the underlying reader, which is ahead by the
+    /// buffered data.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let result = match pos {
+            SeekFrom::Current(n) => {
+                let remainder = (self.filled - self.pos) as i64;
+                match n.checked_sub(remainder) {
+                    Some(n) => self.inner.seek(SeekFrom::Current(n))?,
+                    None => {
+                        self.inner.seek(SeekFrom::Current(-remainder))?;
+                        self.inner.seek(SeekFrom::Current(n))?
+                    }
+                }
+            }
+            _ => self.inner.seek(pos)?,
+        };
+        self.discard_buffer();
+        Ok(result)
+    }
+}
+
+impl<R: fmt::Debug> fmt::Debug for BufReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufReader")
+            .field("reader", &self.inner)
+            .field(
+                "buffer",
+                &format_args!("{}/{}", self.filled - self.pos, DEFAULT_BUF_SIZE),
+            )
+            .finish()
+    }
+}
+
+/// Wraps a writer and buffers its output, similar to
+/// [`std::io::BufWriter`](https://doc.rust-lang.org/std/io/struct.BufWriter.html).
+///
+/// The data is written to the inner writer once the buffer is full, on
+/// [`flush`](Write::flush), or when the [`BufWriter`] is dropped, ignoring
+/// the errors then. Writes larger than the buffer go to the inner writer at
+/// once. The buffer is inline, so it can be used without `alloc`.
+pub struct BufWriter<W: Write> {
+    inner: W,
+    buf: [u8; DEFAULT_BUF_SIZE],
+    len: usize,
+}
+
+impl<W: Write> BufWriter<W> {
+    /// Creates a new `BufWriter` with a default buffer capacity (1 KB).
+    pub const fn new(inner: W) -> BufWriter<W> {
+        Self {
+            inner,
+            buf: [0; DEFAULT_BUF_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub const fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying writer.
+    ///
+    /// It is inadvisable to directly write to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns a reference to the internally buffered data.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Returns the number of bytes the internal buffer can hold without
+    /// flushing.
+    pub const fn capacity(&self) -> usize {
+        DEFAULT_BUF_SIZE
+    }
+
+    /// Writes the buffered data, and returns the underlying writer.
+    ///
+    /// The data is lost if it cannot be written.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.flush_buf()?;
+        let this = ManuallyDrop::new(self);
+        // SAFETY: `this` is not dropped, so the writer is moved out once.
+        Ok(unsafe { ptr::read(&this.inner) })
+    }
+
+    /// Writes the buffered data to the underlying writer, keeping what could
+    /// not be written on error.
+    fn flush_buf(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let mut res = Ok(());
+        while written < self.len {
+            match self.inner.write(&self.buf[written..self.len]) {
+                Ok(0) => {
+                    res = axerrno::ax_err!(WriteZero, "failed to write the buffered data");
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            }
+        }
+        self.buf.copy_within(written..self.len, 0);
+        self.len -= written;
+        res
+    }
+}
+
+impl<W: Write> Write for BufWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.len + buf.len() > DEFAULT_BUF_SIZE {
+            self.flush_buf()?;
+        }
+        if buf.len() >= DEFAULT_BUF_SIZE {
+            self.inner.write(buf)
+        } else {
+            self.buf[self.len..self.len + buf.len()].copy_from_slice(buf);
+            self.len += buf.len();
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriter<W> {
+    /// Writes the buffered data, then seeks in the underlying writer.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush_buf()?;
+        self.inner.seek(pos)
+    }
+}
+
+impl<W: Write> Drop for BufWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush_buf();
+    }
+}
+
+impl<W: Write + fmt::Debug> fmt::Debug for BufWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufWriter")
+            .field("writer", &self.inner)
+            .field("buffer", &format_args!("{}/{}", self.len, DEFAULT_BUF_SIZE))
+            .finish()
+    }
+}
+
+/// Wraps a writer and buffers its output until a newline, similar to
+/// [`std::io::LineWriter`](https://doc.rust-lang.org/std/io/struct.LineWriter.html).
+///
+/// A write ending lines flushes them to the inner writer, the rest of the
+/// line stays buffered until the next newline or flush, like with a
+/// [`BufWriter`].
+pub struct LineWriter<W: Write> {
+    inner: BufWriter<W>,
+}
+
+impl<W: Write> LineWriter<W> {
+    /// Creates a new `LineWriter`.
+    pub const fn new(inner: W) -> LineWriter<W> {
+        Self {
+            inner: BufWriter::new(inner),
+        }
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub const fn get_ref(&self) -> &W {
+        self.inner.get_ref()
+    }
+
+    /// Gets a mutable reference to the underlying writer.
+    ///
+    /// It is inadvisable to directly write to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.get_mut()
+    }
+
+    /// Writes the buffered data, and returns the underlying writer.
+    pub fn into_inner(self) -> io::Result<W> {
+        self.inner.into_inner()
+    }
+}
+
+impl<W: Write> Write for LineWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(last_newline) = buf.iter().rposition(|&b| b == b'\n') else {
+            return self.inner.write(buf);
+        };
+        let (lines, tail) = buf.split_at(last_newline + 1);
+        let written = self.inner.write(lines)?;
+        self.inner.flush_buf()?;
+        if written < lines.len() || tail.is_empty() {
+            return Ok(written);
+        }
+        // The lines are written, so the tail is not reported as an error.
+        Ok(written + self.inner.write(tail).unwrap_or(0))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + fmt::Debug> fmt::Debug for LineWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LineWriter")
+            .field("writer", self.get_ref())
+            .field(
+                "buffer",
+                &format_args!("{}/{}", self.inner.len, DEFAULT_BUF_SIZE),
+            )
+            .finish()
+    }
+}
@@ -0,0 +1,39 @@
+//! Copying between readers and writers.
+
+use crate::io::{self, Read, Write};
+
+/// The size of the chunks [`copy`] transfers.
+#[cfg(feature = "alloc")]
+const COPY_BUF_SIZE: usize = 64 * 1024;
+/// Without `alloc`, the chunk is on the stack, so it is kept small.
+#[cfg(not(feature = "alloc"))]
+const COPY_BUF_SIZE: usize = 4 * 1024;
+
+/// Copies the entire contents of a reader into a writer, similar to
+/// [`std::io::copy`](https://doc.rust-lang.org/std/io/fn.copy.html).
+///
+/// It reads until the end of `reader` and writes all the data to `writer`,
+/// returning the number of bytes copied. The data goes through a 64 KiB
+/// buffer on the heap (4 KiB on the stack without `alloc`), so large copies,
+/// like of files, take few reads and writes. The [`Read`] and [`Write`]
+/// traits have no vectored I/O, so each chunk is a single write.
+pub fn copy<R, W>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    #[cfg(feature = "alloc")]
+    let mut buf = alloc::vec![0u8; COPY_BUF_SIZE];
+    #[cfg(not(feature = "alloc"))]
+    let mut buf = [0u8; COPY_BUF_SIZE];
+
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(copied);
+        }
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+}
@@ -1,10 +1,16 @@
 //! Traits, helpers, and type definitions for core I/O functionality.
 
+mod buffered;
+mod copy;
 mod stdio;
 
 pub use axio::prelude;
-pub use axio::{BufRead, BufReader, Error, Read, Seek, SeekFrom, Write};
+pub use axio::{BufRead, Error, Read, Seek, SeekFrom, Write};
 
+pub use self::buffered::{BufReader, BufWriter, LineWriter};
+pub use self::copy::copy;
+#[cfg(feature = "alloc")]
+pub use self::stdio::Lines;
 #[doc(hidden)]
 pub use self::stdio::__print_impl;
 pub use self::stdio::{stdin, stdout, Stdin, StdinLock, Stdout, StdoutLock};
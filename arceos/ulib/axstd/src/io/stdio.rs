@@ -1,8 +1,24 @@
-use crate::io::{self, prelude::*, BufReader};
+use crate::io::{self, prelude::*, BufReader, LineWriter};
 use crate::sync::{Mutex, MutexGuard};
 
 #[cfg(feature = "alloc")]
 use alloc::{string::String, vec::Vec};
+#[cfg(feature = "alloc")]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "alloc")]
+const LF: u8 = b'\n';
+#[cfg(feature = "alloc")]
+const CR: u8 = b'\r';
+#[cfg(feature = "alloc")]
+const BS: u8 = b'\x08';
+#[cfg(feature = "alloc")]
+const DEL: u8 = b'\x7f';
+
+/// Whether the last line read by [`Stdin::read_line`] ended with `\r`, so that
+/// the `\n` of a `\r\n` does not end another, empty line.
+#[cfg(feature = "alloc")]
+static AFTER_CR: AtomicBool = AtomicBool::new(false);
 
 struct StdinRaw;
 struct StdoutRaw;
@@ -58,9 +74,97 @@ impl Stdin {
     }
 
     /// Locks this handle and reads a line of input, appending it to the specified buffer.
+    ///
+    /// It blocks until a line is entered, and edits it like a terminal: the
+    /// typed characters are echoed, backspace erases the last one, and other
+    /// control characters are ignored. The line ends with a newline, for
+    /// `\r`, `\n` or `\r\n`.
     #[cfg(feature = "alloc")]
     pub fn read_line(&self, buf: &mut String) -> io::Result<usize> {
-        self.inner.lock().read_line(buf)
+        let mut stdin = self.inner.lock();
+        let mut line = Vec::new();
+        loop {
+            let byte = read_byte(&mut stdin)?;
+            if AFTER_CR.swap(false, Ordering::Relaxed) && byte == LF {
+                continue;
+            }
+            match byte {
+                CR | LF => {
+                    AFTER_CR.store(byte == CR, Ordering::Relaxed);
+                    line.push(LF);
+                    echo(b"\n")?;
+                    break;
+                }
+                BS | DEL => {
+                    // Erase a whole UTF-8 character.
+                    while let Some(byte) = line.pop() {
+                        if byte & 0xc0 != 0x80 {
+                            echo(&[BS, b' ', BS])?;
+                            break;
+                        }
+                    }
+                }
+                0..=31 => {}
+                byte => {
+                    line.push(byte);
+                    echo(&[byte])?;
+                }
+            }
+        }
+        let line = String::from_utf8(line).map_err(|_| {
+            axerrno::ax_err_type!(InvalidData, "stream did not contain valid UTF-8")
+        })?;
+        buf.push_str(&line);
+        Ok(line.len())
+    }
+
+    /// Consumes this handle and returns an iterator over the input lines, read
+    /// with [`read_line`](Stdin::read_line).
+    ///
+    /// The console has no end of input, so the iterator never ends.
+    #[cfg(feature = "alloc")]
+    pub fn lines(self) -> Lines {
+        Lines { stdin: self }
+    }
+}
+
+/// Reads a byte, blocking until one is available.
+#[cfg(feature = "alloc")]
+fn read_byte(stdin: &mut BufReader<StdinRaw>) -> io::Result<u8> {
+    let mut byte = [0];
+    while stdin.read(&mut byte)? == 0 {
+        crate::thread::yield_now();
+    }
+    Ok(byte[0])
+}
+
+/// Echoes the typed input to the console, at once.
+#[cfg(feature = "alloc")]
+fn echo(bytes: &[u8]) -> io::Result<()> {
+    let mut stdout = stdout().lock();
+    stdout.write_all(bytes)?;
+    stdout.flush()
+}
+
+/// An iterator over the lines of the standard input.
+///
+/// It is created by [`Stdin::lines`], and yields the lines without their
+/// newline.
+#[cfg(feature = "alloc")]
+pub struct Lines {
+    stdin: Stdin,
+}
+
+#[cfg(feature = "alloc")]
+impl Iterator for Lines {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        let mut line = String::new();
+        Some(self.stdin.read_line(&mut line).map(|_| {
+            line.pop();
+            line
+        }))
     }
 }
 
@@ -109,13 +213,17 @@ impl BufRead for StdinLock<'_> {
 }
 
 /// A handle to the global standard output stream of the current process.
+///
+/// The output is line-buffered: it is written to the console once a line is
+/// complete, the rest is kept until the next newline or
+/// [`flush`](Write::flush).
 pub struct Stdout {
-    inner: &'static Mutex<StdoutRaw>,
+    inner: &'static Mutex<LineWriter<StdoutRaw>>,
 }
 
 /// A locked reference to the [`Stdout`] handle.
 pub struct StdoutLock<'a> {
-    inner: MutexGuard<'a, StdoutRaw>,
+    inner: MutexGuard<'a, LineWriter<StdoutRaw>>,
 }
 
 impl Stdout {
@@ -157,7 +265,7 @@ pub fn stdin() -> Stdin {
 
 /// Constructs a new handle to the standard output of the current process.
 pub fn stdout() -> Stdout {
-    static INSTANCE: Mutex<StdoutRaw> = Mutex::new(StdoutRaw);
+    static INSTANCE: Mutex<LineWriter<StdoutRaw>> = Mutex::new(LineWriter::new(StdoutRaw));
     Stdout { inner: &INSTANCE }
 }
 
@@ -168,6 +276,9 @@ pub fn __print_impl(args: core::fmt::Arguments) {
         // with kernel logs
         arceos_api::stdio::ax_console_write_fmt(args).unwrap();
     } else {
-        stdout().lock().write_fmt(args).unwrap();
+        // One console write per call, even without a newline, like a prompt.
+        let mut stdout = stdout().lock();
+        stdout.write_fmt(args).unwrap();
+        stdout.flush().unwrap();
     }
 }
@@ -20,11 +20,23 @@ mod udp;
 
 pub use self::socket_addr::{IpAddr, Ipv4Addr, Ipv6Addr};
 pub use self::socket_addr::{SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
-pub use self::tcp::{TcpListener, TcpStream};
+pub use self::tcp::{Incoming, TcpListener, TcpStream};
 pub use self::udp::UdpSocket;
 
 use crate::io;
 
+/// Possible values which can be passed to [`TcpStream::shutdown`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shutdown {
+    /// The reading portion of the [`TcpStream`] should be shut down.
+    Read,
+    /// The writing portion of the [`TcpStream`] should be shut down.
+    Write,
+    /// Both the reading and the writing portions of the [`TcpStream`] should
+    /// be shut down.
+    Both,
+}
+
 fn each_addr<A: ToSocketAddrs, F, T>(addr: A, mut f: F) -> io::Result<T>
 where
     F: FnMut(io::Result<&SocketAddr>) -> io::Result<T>,
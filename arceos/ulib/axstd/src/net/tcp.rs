@@ -1,4 +1,6 @@
-use super::{SocketAddr, ToSocketAddrs};
+use core::fmt;
+
+use super::{Shutdown, SocketAddr, ToSocketAddrs};
 use crate::io::{self, prelude::*};
 
 use arceos_api::net::{self as api, AxTcpSocketHandle};
@@ -39,9 +41,28 @@ impl TcpStream {
         api::ax_tcp_peer_addr(&self.0)
     }
 
-    /// Shuts down the connection.
-    pub fn shutdown(&self) -> io::Result<()> {
-        api::ax_tcp_shutdown(&self.0)
+    /// Shuts down the read, write, or both halves of this connection.
+    ///
+    /// Shutting down the write half sends a FIN to the peer, and the stream
+    /// stays readable. The stack cannot stop receiving alone, so
+    /// [`Shutdown::Read`] is unsupported.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match how {
+            Shutdown::Write => api::ax_tcp_shutdown_write(&self.0),
+            Shutdown::Both => api::ax_tcp_shutdown(&self.0),
+            Shutdown::Read => axerrno::ax_err!(
+                Unsupported,
+                "cannot shut down the read half of a TCP stream"
+            ),
+        }
+    }
+
+    /// Moves this TCP stream into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, reads and writes return [`io::Error::WouldBlock`]
+    /// instead of waiting.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        api::ax_tcp_set_nonblocking(&self.0, nonblocking)
     }
 }
 
@@ -61,6 +82,35 @@ impl Write for TcpStream {
     }
 }
 
+impl Read for &TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        api::ax_tcp_recv(&self.0, buf)
+    }
+}
+
+impl Write for &TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        api::ax_tcp_send(&self.0, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for TcpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut res = f.debug_struct("TcpStream");
+        if let Ok(addr) = self.local_addr() {
+            res.field("addr", &addr);
+        }
+        if let Ok(peer) = self.peer_addr() {
+            res.field("peer", &peer);
+        }
+        res.finish()
+    }
+}
+
 impl TcpListener {
     /// Creates a new `TcpListener` which will be bound to the specified
     /// address.
@@ -102,4 +152,49 @@ impl TcpListener {
     pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
         api::ax_tcp_accept(&self.0).map(|(a, b)| (TcpStream(a), b))
     }
+
+    /// Returns an iterator over the connections being received on this
+    /// listener.
+    ///
+    /// It is equivalent to calling [`TcpListener::accept`] in a loop, and
+    /// never returns `None`.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    /// Moves this TCP listener into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, [`accept`](TcpListener::accept) returns
+    /// [`io::Error::WouldBlock`] instead of waiting.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        api::ax_tcp_set_nonblocking(&self.0, nonblocking)
+    }
+}
+
+impl fmt::Debug for TcpListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut res = f.debug_struct("TcpListener");
+        if let Ok(addr) = self.local_addr() {
+            res.field("addr", &addr);
+        }
+        res.finish()
+    }
+}
+
+/// An iterator that infinitely [`accept`]s connections on a [`TcpListener`].
+///
+/// It is created by [`TcpListener::incoming`].
+///
+/// [`accept`]: TcpListener::accept
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<io::Result<TcpStream>> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
 }
@@ -1,4 +1,6 @@
-use super::{SocketAddr, ToSocketAddrs};
+use core::fmt;
+
+use super::{Ipv4Addr, SocketAddr, ToSocketAddrs};
 use crate::io;
 
 use arceos_api::net::{self as api, AxUdpSocketHandle};
@@ -73,7 +75,7 @@ impl UdpSocket {
     /// error would only be detected after the first send. If the OS returns an
     /// error for each of the specified addresses, the error returned from the
     /// last connection attempt (the last address) is returned.
-    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
         super::each_addr(addr, |addr: io::Result<&SocketAddr>| {
             let addr = addr?;
             api::ax_udp_connect(&self.0, *addr)
@@ -93,4 +95,63 @@ impl UdpSocket {
     pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
         api::ax_udp_recv(&self.0, buf)
     }
+
+    /// Moves this UDP socket into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, sends and receives return
+    /// [`io::Error::WouldBlock`] instead of waiting.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        api::ax_udp_set_nonblocking(&self.0, nonblocking)
+    }
+
+    /// Sets the value of the `SO_BROADCAST` option for this socket.
+    ///
+    /// When enabled, this socket is allowed to send packets to the broadcast
+    /// address, otherwise it fails with [`io::Error::PermissionDenied`].
+    pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        api::ax_udp_set_broadcast(&self.0, broadcast)
+    }
+
+    /// Gets the value of the `SO_BROADCAST` option for this socket.
+    pub fn broadcast(&self) -> io::Result<bool> {
+        api::ax_udp_broadcast(&self.0)
+    }
+
+    /// Sets the value for the `IP_TTL` option on this socket, the time-to-live
+    /// of the datagrams sent, from 1 to 255.
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        api::ax_udp_set_ttl(&self.0, ttl)
+    }
+
+    /// Gets the value of the `IP_TTL` option for this socket.
+    pub fn ttl(&self) -> io::Result<u32> {
+        api::ax_udp_ttl(&self.0)
+    }
+
+    /// Executes an operation of the `IP_ADD_MEMBERSHIP` type.
+    ///
+    /// This joins the multicast group `multiaddr`. Only the default network
+    /// interface is supported, so `interface` must be
+    /// [`Ipv4Addr::UNSPECIFIED`].
+    pub fn join_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        api::ax_udp_join_multicast_v4(&self.0, *multiaddr, *interface)
+    }
+
+    /// Executes an operation of the `IP_DROP_MEMBERSHIP` type.
+    ///
+    /// For more information about this option, see
+    /// [`join_multicast_v4`](UdpSocket::join_multicast_v4).
+    pub fn leave_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        api::ax_udp_leave_multicast_v4(&self.0, *multiaddr, *interface)
+    }
+}
+
+impl fmt::Debug for UdpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut res = f.debug_struct("UdpSocket");
+        if let Ok(addr) = self.local_addr() {
+            res.field("addr", &addr);
+        }
+        res.finish()
+    }
 }
@@ -5,4 +5,11 @@ pub mod arceos {
     pub use arceos_api as api;
     #[doc(no_inline)]
     pub use arceos_api::modules;
+
+    /// Returns the free memory of the global allocator, with the size of its
+    /// largest free block, the number of free blocks and the fragmentation.
+    #[cfg(feature = "alloc")]
+    pub fn memory_stats() -> api::mem::AxMemoryStats {
+        api::mem::ax_memory_stats()
+    }
 }
@@ -0,0 +1,151 @@
+//! A condition variable on wait queues.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use arceos_api::task::{self as api, AxWaitQueueHandle};
+
+use super::MutexGuard;
+
+/// A type indicating whether a timed wait on a condition variable returned
+/// due to a time out or not.
+///
+/// It is returned by the [`wait_timeout`] method.
+///
+/// [`wait_timeout`]: Condvar::wait_timeout
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Returns `true` if the wait was known to have timed out.
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+/// A condition variable, similar to
+/// [`std::sync::Condvar`](https://doc.rust-lang.org/std/sync/struct.Condvar.html).
+///
+/// The waiting tasks are blocked in a wait queue, after releasing the
+/// [`Mutex`](super::Mutex) of the guard they pass, and lock it again when
+/// they are woken up. Like with `std`, a task may wake up without being
+/// notified, so the condition should be checked in a loop, or with
+/// [`wait_while`](Condvar::wait_while).
+pub struct Condvar {
+    wq: AxWaitQueueHandle,
+    /// Incremented by each notification, so the waiters can tell they were
+    /// notified since they released the mutex.
+    seq: AtomicU64,
+}
+
+impl Condvar {
+    /// Creates a new condition variable which is ready to be waited on and
+    /// notified.
+    pub const fn new() -> Self {
+        Self {
+            wq: AxWaitQueueHandle::new(),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Blocks the current task until this condition variable receives a
+    /// notification, and returns the guard of the mutex locked again.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_inner(guard, None).0
+    }
+
+    /// Blocks the current task while `condition` returns `true` for the data
+    /// protected by the mutex.
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Blocks the current task until this condition variable receives a
+    /// notification, or `dur` has elapsed.
+    ///
+    /// Without the `irq` feature, the timeout is ignored.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        dur: Duration,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        self.wait_inner(guard, Some(dur))
+    }
+
+    /// Blocks the current task while `condition` returns `true` for the data
+    /// protected by the mutex, or until `dur` has elapsed.
+    ///
+    /// Without the `irq` feature, the timeout is ignored.
+    pub fn wait_timeout_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        dur: Duration,
+        mut condition: F,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult)
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let deadline = arceos_api::time::ax_monotonic_time() + dur;
+        while condition(&mut *guard) {
+            let now = arceos_api::time::ax_monotonic_time();
+            if now >= deadline {
+                return (guard, WaitTimeoutResult(true));
+            }
+            guard = self.wait_timeout(guard, deadline - now).0;
+        }
+        (guard, WaitTimeoutResult(false))
+    }
+
+    /// Wakes up one task blocked on this condition variable.
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        api::ax_wait_queue_wake(&self.wq, 1);
+    }
+
+    /// Wakes up all the tasks blocked on this condition variable.
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        api::ax_wait_queue_wake(&self.wq, u32::MAX);
+    }
+
+    fn wait_inner<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        // Read with the mutex locked, so a notification after it is released
+        // is not missed.
+        let seq = self.seq.load(Ordering::Acquire);
+        let mutex = MutexGuard::mutex(&guard);
+        drop(guard);
+        let timed_out = api::ax_wait_queue_wait(
+            &self.wq,
+            || self.seq.load(Ordering::Acquire) != seq,
+            timeout,
+        );
+        (mutex.lock(), WaitTimeoutResult(timed_out))
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Condvar { .. }")
+    }
+}
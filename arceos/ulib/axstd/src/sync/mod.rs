@@ -1,4 +1,10 @@
 //! Useful synchronization primitives.
+//!
+//! With the `multitask` feature, [`Mutex`] and [`RwLock`] block the current
+//! task in a wait queue until they can be acquired. Unlike the ones of `std`,
+//! they are not poisoned by a panicking thread, so locking returns the guard
+//! itself rather than a `LockResult`. [`Condvar`] likewise returns the guard
+//! of the mutex its waits release.
 
 #[doc(no_inline)]
 pub use core::sync::atomic;
@@ -7,13 +13,28 @@ pub use core::sync::atomic;
 #[doc(no_inline)]
 pub use alloc::sync::{Arc, Weak};
 
+#[cfg(feature = "multitask")]
+mod condvar;
+#[cfg(feature = "multitask")]
+#[doc(cfg(feature = "multitask"))]
+pub mod mpsc;
 #[cfg(feature = "multitask")]
 mod mutex;
+#[cfg(feature = "multitask")]
+mod rwlock;
+
+#[cfg(feature = "multitask")]
+#[doc(cfg(feature = "multitask"))]
+pub use self::condvar::{Condvar, WaitTimeoutResult};
 
 #[cfg(feature = "multitask")]
 #[doc(cfg(feature = "multitask"))]
 pub use self::mutex::{Mutex, MutexGuard};
 
+#[cfg(feature = "multitask")]
+#[doc(cfg(feature = "multitask"))]
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard};
+
 #[cfg(not(feature = "multitask"))]
 #[doc(cfg(not(feature = "multitask")))]
 pub use kspin::{SpinRaw as Mutex, SpinRawGuard as MutexGuard}; // never used in IRQ context
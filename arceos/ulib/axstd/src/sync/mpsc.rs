@@ -0,0 +1,470 @@
+//! Multi-producer, single-consumer FIFO queue communication primitives,
+//! similar to [`std::sync::mpsc`](https://doc.rust-lang.org/std/sync/mpsc/index.html).
+//!
+//! A channel is a queue behind a [`Mutex`], with a [`Condvar`] for the
+//! receiver waiting for messages, and another for the senders of a bounded
+//! channel waiting for room. [`channel`] creates an unbounded channel, whose
+//! [`Sender`] never blocks, and [`sync_channel`] a bounded one, whose
+//! [`SyncSender`] blocks while the channel is full.
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::fmt;
+use core::time::Duration;
+
+use super::{Condvar, Mutex};
+
+/// An error returned from [`Sender::send`] or [`SyncSender::send`], when the
+/// receiver is gone. It holds the message that could not be sent.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct SendError<T>(pub T);
+
+/// An error returned from [`Receiver::recv`], when all the senders are gone
+/// and the channel is empty.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct RecvError;
+
+/// The reasons [`Receiver::try_recv`] could not return a message.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TryRecvError {
+    /// The channel is empty, but the senders are still there.
+    Empty,
+    /// All the senders are gone and the channel is empty.
+    Disconnected,
+}
+
+/// The reasons [`Receiver::recv_timeout`] could not return a message.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum RecvTimeoutError {
+    /// No message arrived before the timeout.
+    Timeout,
+    /// All the senders are gone and the channel is empty.
+    Disconnected,
+}
+
+/// The reasons [`SyncSender::try_send`] could not send a message, with the
+/// message.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum TrySendError<T> {
+    /// The channel is full.
+    Full(T),
+    /// The receiver is gone.
+    Disconnected(T),
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    /// The capacity of a bounded channel.
+    bound: Option<usize>,
+    senders: usize,
+    receiver: bool,
+    /// The messages sent and received so far, for the senders of a
+    /// zero-capacity channel waiting for theirs to be received.
+    sent: u64,
+    received: u64,
+}
+
+impl<T> State<T> {
+    fn is_full(&self) -> bool {
+        // A zero-capacity channel holds the message of a sender until it is
+        // received.
+        self.bound
+            .is_some_and(|bound| self.queue.len() >= bound.max(1))
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Notified when a message is queued, or the last sender is gone.
+    not_empty: Condvar,
+    /// Notified when a message is received, or the receiver is gone.
+    not_full: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn new(bound: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                bound,
+                senders: 1,
+                receiver: true,
+                sent: 0,
+                received: 0,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        })
+    }
+
+    /// Queues `msg`, waiting for room in a bounded channel if `block` is set.
+    fn send(&self, msg: T, block: bool) -> Result<(), TrySendError<T>> {
+        let mut state = self.state.lock();
+        if block {
+            state = self
+                .not_full
+                .wait_while(state, |state| state.receiver && state.is_full());
+        }
+        if !state.receiver {
+            return Err(TrySendError::Disconnected(msg));
+        }
+        if state.is_full() {
+            return Err(TrySendError::Full(msg));
+        }
+        state.queue.push_back(msg);
+        state.sent += 1;
+        let ticket = state.sent;
+        self.not_empty.notify_one();
+        if block && state.bound == Some(0) {
+            drop(
+                self.not_full
+                    .wait_while(state, |state| state.receiver && state.received < ticket),
+            );
+        }
+        Ok(())
+    }
+
+    fn add_sender(&self) {
+        self.state.lock().senders += 1;
+    }
+
+    fn drop_sender(&self) {
+        let mut state = self.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.not_empty.notify_all();
+        }
+    }
+
+    /// Takes the first message, if any, and tells the senders.
+    fn take(&self, state: &mut State<T>) -> Option<T> {
+        let msg = state.queue.pop_front()?;
+        state.received += 1;
+        self.not_full.notify_all();
+        Some(msg)
+    }
+}
+
+/// The sending side of an unbounded channel, see [`channel`].
+///
+/// It can be cloned to send from several threads.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The sending side of a bounded channel, see [`sync_channel`].
+///
+/// It can be cloned to send from several threads.
+pub struct SyncSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The receiving side of a channel, see [`channel`] and [`sync_channel`].
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates an unbounded channel, returning its sending and receiving sides.
+///
+/// The messages are received in the order they were sent, and sending never
+/// blocks.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Shared::new(None);
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Creates a channel holding up to `bound` messages, returning its sending
+/// and receiving sides.
+///
+/// Sending blocks while the channel is full. With a `bound` of 0, sending
+/// blocks until the message is received.
+pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+    let shared = Shared::new(Some(bound));
+    (
+        SyncSender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Sends `msg` to the receiver, or returns it back in the error if the
+    /// receiver is gone.
+    pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        self.shared.send(msg, false).map_err(|err| match err {
+            TrySendError::Full(msg) | TrySendError::Disconnected(msg) => SendError(msg),
+        })
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.add_sender();
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Sender { .. }")
+    }
+}
+
+impl<T> SyncSender<T> {
+    /// Sends `msg` to the receiver, blocking while the channel is full, or
+    /// returns it back in the error if the receiver is gone.
+    pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        self.shared.send(msg, true).map_err(|err| match err {
+            TrySendError::Full(msg) | TrySendError::Disconnected(msg) => SendError(msg),
+        })
+    }
+
+    /// Sends `msg` to the receiver if the channel has room for it, without
+    /// blocking.
+    pub fn try_send(&self, msg: T) -> Result<(), TrySendError<T>> {
+        self.shared.send(msg, false)
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.add_sender();
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for SyncSender<T> {
+    fn drop(&mut self) {
+        self.shared.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for SyncSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SyncSender { .. }")
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next message, blocking until one is sent. Fails once
+    /// all the senders are gone and the channel is empty.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let state = self.shared.state.lock();
+        let mut state = self
+            .shared
+            .not_empty
+            .wait_while(state, |state| state.queue.is_empty() && state.senders != 0);
+        self.shared.take(&mut state).ok_or(RecvError)
+    }
+
+    /// Receives the next message if there is one, without blocking.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.state.lock();
+        match self.shared.take(&mut state) {
+            Some(msg) => Ok(msg),
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Receives the next message, blocking until one is sent or `timeout`
+    /// has elapsed.
+    ///
+    /// Without the `irq` feature, the timeout is ignored.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let state = self.shared.state.lock();
+        let (mut state, _) = self
+            .shared
+            .not_empty
+            .wait_timeout_while(state, timeout, |state| {
+                state.queue.is_empty() && state.senders != 0
+            });
+        match self.shared.take(&mut state) {
+            Some(msg) => Ok(msg),
+            None if state.senders == 0 => Err(RecvTimeoutError::Disconnected),
+            None => Err(RecvTimeoutError::Timeout),
+        }
+    }
+
+    /// Returns an iterator blocking for the messages, which ends once all
+    /// the senders are gone.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { rx: self }
+    }
+
+    /// Returns an iterator over the messages already sent, without blocking.
+    pub fn try_iter(&self) -> TryIter<'_, T> {
+        TryIter { rx: self }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.receiver = false;
+        // Drops the messages left, which nobody can receive anymore.
+        state.queue.clear();
+        self.shared.not_full.notify_all();
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Receiver { .. }")
+    }
+}
+
+/// An iterator over the messages of a [`Receiver`], blocking for each, see
+/// [`Receiver::iter`].
+#[derive(Debug)]
+pub struct Iter<'a, T: 'a> {
+    rx: &'a Receiver<T>,
+}
+
+/// An iterator over the messages already sent to a [`Receiver`], see
+/// [`Receiver::try_iter`].
+#[derive(Debug)]
+pub struct TryIter<'a, T: 'a> {
+    rx: &'a Receiver<T>,
+}
+
+/// An owning iterator over the messages of a [`Receiver`], blocking for
+/// each.
+#[derive(Debug)]
+pub struct IntoIter<T> {
+    rx: Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<T> Iterator for TryIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { rx: self }
+    }
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SendError { .. }")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("sending on a closed channel")
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("sending on a full channel"),
+            TrySendError::Disconnected(_) => f.write_str("sending on a closed channel"),
+        }
+    }
+}
+
+impl<T> From<SendError<T>> for TrySendError<T> {
+    fn from(err: SendError<T>) -> Self {
+        TrySendError::Disconnected(err.0)
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("receiving on a closed channel")
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Disconnected => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => f.write_str("timed out waiting on channel"),
+            RecvTimeoutError::Disconnected => {
+                f.write_str("channel is empty and sending half is closed")
+            }
+        }
+    }
+}
+
+impl From<RecvError> for TryRecvError {
+    fn from(_: RecvError) -> Self {
+        TryRecvError::Disconnected
+    }
+}
+
+impl From<RecvError> for RecvTimeoutError {
+    fn from(_: RecvError) -> Self {
+        RecvTimeoutError::Disconnected
+    }
+}
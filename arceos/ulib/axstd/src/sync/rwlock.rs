@@ -0,0 +1,401 @@
+//! A naïve sleeping reader-writer lock.
+//!
+//! It is the writer-preferred lock of `axsync`, rebuilt on the wait queues of
+//! `arceos_api`: like the [`Mutex`](super::Mutex), `axstd` reaches the kernel
+//! only through `arceos_api`, whose opaque handles cannot carry the generic
+//! data of an `axsync::RwLock`. The `RwLockPolicy` of `axsync` is not
+//! provided, readers never starve writers here.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arceos_api::task::{self as api, AxWaitQueueHandle};
+
+const WRITER: usize = 1;
+const UPGRADABLE: usize = 1 << 1;
+const READER: usize = 1 << 2;
+
+/// A reader-writer lock, similar to
+/// [`std::sync::RwLock`](https://doc.rust-lang.org/std/sync/struct.RwLock.html).
+///
+/// It allows any number of readers or at most one writer at a time. When the
+/// lock cannot be acquired, the current task will block and be put into the
+/// wait queue. New readers are refused while a writer is waiting, so writers
+/// are not starved by readers.
+///
+/// Besides the usual read and write locks, it also provides an upgradable
+/// read lock, as `axsync` does: it can coexist with plain readers, but only
+/// one upgradable reader (and no writer) may hold the lock at a time, and it
+/// can later be upgraded into a write lock without releasing it first.
+pub struct RwLock<T: ?Sized> {
+    wq: AxWaitQueueHandle,
+    state: AtomicUsize,
+    waiting_writers: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+/// A guard that provides immutable data access.
+///
+/// When the guard falls out of scope it will release the read lock.
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    data: *const T,
+}
+
+/// A guard that provides mutable data access.
+///
+/// When the guard falls out of scope it will release the write lock.
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    data: *mut T,
+}
+
+/// A guard that provides immutable data access and can be upgraded into a
+/// [`RwLockWriteGuard`].
+///
+/// When the guard falls out of scope it will release the upgradable lock.
+pub struct RwLockUpgradableGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    data: *const T,
+}
+
+// Same unsafe impls as `std::sync::RwLock`
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Creates a new [`RwLock`] wrapping the supplied data.
+    #[inline(always)]
+    pub const fn new(data: T) -> Self {
+        Self {
+            wq: AxWaitQueueHandle::new(),
+            state: AtomicUsize::new(0),
+            waiting_writers: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this [`RwLock`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        let RwLock { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Returns `true` if the lock is currently held by a writer.
+    ///
+    /// The result may be out of date the instant it is returned, so it should
+    /// only be used as a heuristic.
+    #[inline(always)]
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0
+    }
+
+    fn try_lock_shared(&self, extra: usize) -> bool {
+        if self.waiting_writers.load(Ordering::Relaxed) != 0 {
+            return false;
+        }
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & WRITER != 0 || state & extra != 0 {
+                return false;
+            }
+            let new = if extra == 0 {
+                state + READER
+            } else {
+                state | extra
+            };
+            match self
+                .state
+                .compare_exchange_weak(state, new, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(s) => state = s,
+            }
+        }
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn try_upgrade_inner(&self) -> bool {
+        self.state
+            .compare_exchange(UPGRADABLE, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Blocks until `acquire` succeeds, registering the current task as a
+    /// waiting writer meanwhile.
+    fn wait_as_writer(&self, acquire: impl Fn() -> bool) {
+        if acquire() {
+            return;
+        }
+        self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+        api::ax_wait_queue_wait(&self.wq, acquire, None);
+        self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Locks this [`RwLock`] with shared read access, blocking the current
+    /// task until it can be acquired.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        if !self.try_lock_shared(0) {
+            api::ax_wait_queue_wait(&self.wq, || self.try_lock_shared(0), None);
+        }
+        RwLockReadGuard {
+            lock: self,
+            data: self.data.get(),
+        }
+    }
+
+    /// Attempts to lock this [`RwLock`] with shared read access, returning a
+    /// guard if successful.
+    #[inline(always)]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        if self.try_lock_shared(0) {
+            Some(RwLockReadGuard {
+                lock: self,
+                data: self.data.get(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Locks this [`RwLock`] with upgradable read access, blocking the
+    /// current task until it can be acquired.
+    pub fn upgradable_read(&self) -> RwLockUpgradableGuard<T> {
+        if !self.try_lock_shared(UPGRADABLE) {
+            api::ax_wait_queue_wait(&self.wq, || self.try_lock_shared(UPGRADABLE), None);
+        }
+        RwLockUpgradableGuard {
+            lock: self,
+            data: self.data.get(),
+        }
+    }
+
+    /// Attempts to lock this [`RwLock`] with upgradable read access,
+    /// returning a guard if successful.
+    #[inline(always)]
+    pub fn try_upgradable_read(&self) -> Option<RwLockUpgradableGuard<T>> {
+        if self.try_lock_shared(UPGRADABLE) {
+            Some(RwLockUpgradableGuard {
+                lock: self,
+                data: self.data.get(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Locks this [`RwLock`] with exclusive write access, blocking the
+    /// current task until it can be acquired.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.wait_as_writer(|| self.try_lock_exclusive());
+        RwLockWriteGuard {
+            lock: self,
+            data: self.data.get(),
+        }
+    }
+
+    /// Attempts to lock this [`RwLock`] with exclusive write access,
+    /// returning a guard if successful.
+    #[inline(always)]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if self.try_lock_exclusive() {
+            Some(RwLockWriteGuard {
+                lock: self,
+                data: self.data.get(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`RwLock`] mutably, no actual locking
+    /// needs to take place.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn unlock_shared(&self) {
+        let prev = self.state.fetch_sub(READER, Ordering::Release);
+        if prev / READER == 1 {
+            // The last reader is gone, a writer or an upgrader may proceed.
+            api::ax_wait_queue_wake(&self.wq, u32::MAX);
+        }
+    }
+
+    fn unlock_upgradable(&self) {
+        self.state.fetch_and(!UPGRADABLE, Ordering::Release);
+        api::ax_wait_queue_wake(&self.wq, u32::MAX);
+    }
+
+    fn unlock_exclusive(&self) {
+        self.state.fetch_and(!WRITER, Ordering::Release);
+        // Wake up the readers too, a single writer could leave them waiting.
+        api::ax_wait_queue_wake(&self.wq, u32::MAX);
+    }
+}
+
+impl<T: ?Sized + Default> Default for RwLock<T> {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => write!(f, "RwLock {{ data: ")
+                .and_then(|()| (*guard).fmt(f))
+                .and_then(|()| write!(f, "}}")),
+            None => write!(f, "RwLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> RwLockUpgradableGuard<'a, T> {
+    /// Upgrades into a write lock, blocking the current task until all other
+    /// readers have released the lock.
+    pub fn upgrade(self) -> RwLockWriteGuard<'a, T> {
+        let this = ManuallyDrop::new(self);
+        let lock = this.lock;
+        lock.wait_as_writer(|| lock.try_upgrade_inner());
+        RwLockWriteGuard {
+            lock,
+            data: lock.data.get(),
+        }
+    }
+
+    /// Tries to upgrade into a write lock without blocking.
+    ///
+    /// Returns the original guard on failure.
+    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'a, T>, Self> {
+        if self.lock.try_upgrade_inner() {
+            let this = ManuallyDrop::new(self);
+            Ok(RwLockWriteGuard {
+                lock: this.lock,
+                data: this.lock.data.get(),
+            })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Downgrades into a plain read lock, allowing another task to take the
+    /// upgradable lock.
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
+        let this = ManuallyDrop::new(self);
+        let lock = this.lock;
+        lock.state.fetch_add(READER, Ordering::Acquire);
+        lock.unlock_upgradable();
+        RwLockReadGuard {
+            lock,
+            data: lock.data.get(),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
+    /// Atomically downgrades into a read lock, without letting any writer in
+    /// between.
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
+        let this = ManuallyDrop::new(self);
+        let lock = this.lock;
+        lock.state.store(READER, Ordering::Release);
+        api::ax_wait_queue_wake(&lock.wq, u32::MAX);
+        RwLockReadGuard {
+            lock,
+            data: lock.data.get(),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        // We know statically that no writer is referencing data
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    /// The dropping of the [`RwLockReadGuard`] will release the read lock it
+    /// was created from.
+    fn drop(&mut self) {
+        self.lock.unlock_shared();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockUpgradableGuard<'a, T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        // We know statically that no writer is referencing data
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for RwLockUpgradableGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockUpgradableGuard<'a, T> {
+    /// The dropping of the [`RwLockUpgradableGuard`] will release the
+    /// upgradable lock it was created from.
+    fn drop(&mut self) {
+        self.lock.unlock_upgradable();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        // We know statically that only we are referencing data
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        // We know statically that only we are referencing data
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    /// The dropping of the [`RwLockWriteGuard`] will release the write lock it
+    /// was created from.
+    fn drop(&mut self) {
+        self.lock.unlock_exclusive();
+    }
+}
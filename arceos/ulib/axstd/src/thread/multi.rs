@@ -3,19 +3,69 @@
 extern crate alloc;
 
 use crate::io;
-use alloc::{string::String, sync::Arc};
-use core::{cell::UnsafeCell, num::NonZeroU64};
+use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{cell::UnsafeCell, num::NonZeroU64, time::Duration};
 
-use arceos_api::task::{self as api, AxTaskHandle};
+use arceos_api::task::{self as api, AxTaskHandle, AxWaitQueueHandle};
 use axerrno::ax_err_type;
+use kspin::SpinNoIrq;
+
+/// The park states of the running threads, by their IDs.
+///
+/// A thread spawned by [`Builder::spawn`] removes its own once its closure
+/// returned, the other tasks get one on their first [`current`].
+static PARKERS: SpinNoIrq<BTreeMap<u64, Arc<Parker>>> = SpinNoIrq::new(BTreeMap::new());
+
+/// The park token of a thread, and the queue it is parked in.
+struct Parker {
+    token: AtomicBool,
+    wq: AxWaitQueueHandle,
+}
+
+impl Parker {
+    const fn new() -> Self {
+        Self {
+            token: AtomicBool::new(false),
+            wq: AxWaitQueueHandle::new(),
+        }
+    }
+
+    /// Returns the park state of the current thread.
+    fn current() -> Arc<Parker> {
+        PARKERS
+            .lock()
+            .entry(api::ax_current_task_id())
+            .or_insert_with(|| Arc::new(Parker::new()))
+            .clone()
+    }
+
+    fn park(&self, timeout: Option<Duration>) {
+        api::ax_wait_queue_wait(
+            &self.wq,
+            || self.token.swap(false, Ordering::Acquire),
+            timeout,
+        );
+    }
+
+    fn unpark(&self) {
+        self.token.store(true, Ordering::Release);
+        // Only the thread owning the token waits in the queue.
+        api::ax_wait_queue_wake(&self.wq, 1);
+    }
+}
 
 /// A unique identifier for a running thread.
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
 pub struct ThreadId(NonZeroU64);
 
 /// A handle to a thread.
+#[derive(Clone)]
 pub struct Thread {
     id: ThreadId,
+    name: Option<String>,
+    parker: Arc<Parker>,
 }
 
 impl ThreadId {
@@ -27,9 +77,11 @@ impl ThreadId {
 }
 
 impl Thread {
-    fn from_id(id: u64) -> Self {
+    fn new(id: u64, name: String, parker: Arc<Parker>) -> Self {
         Self {
             id: ThreadId(NonZeroU64::new(id).unwrap()),
+            name: Some(name).filter(|name| !name.is_empty()),
+            parker,
         }
     }
 
@@ -37,6 +89,26 @@ impl Thread {
     pub fn id(&self) -> ThreadId {
         self.id
     }
+
+    /// Gets the thread's name, if it was given one with [`Builder::name`].
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Makes the token of the thread available, waking it up if it is
+    /// parked, see [`park`].
+    pub fn unpark(&self) {
+        self.parker.unpark();
+    }
+}
+
+impl fmt::Debug for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Thread")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
 }
 
 /// Thread factory, which can be used in order to configure the properties of
@@ -61,7 +133,8 @@ impl Builder {
         }
     }
 
-    /// Names the thread-to-be.
+    /// Names the thread-to-be. It is the name of the underlying task too,
+    /// e.g. in the logs.
     pub fn name(mut self, name: String) -> Builder {
         self.name = Some(name);
         self
@@ -104,9 +177,14 @@ impl Builder {
             result: UnsafeCell::new(None),
         });
         let their_packet = my_packet.clone();
+        let my_parker = Arc::new(Parker::new());
+        let their_parker = my_parker.clone();
 
         let main = move || {
+            let id = api::ax_current_task_id();
+            PARKERS.lock().insert(id, their_parker);
             let ret = f();
+            PARKERS.lock().remove(&id);
             // SAFETY: `their_packet` as been built just above and moved by the
             // closure (it is an Arc<...>) and `my_packet` will be stored in the
             // same `JoinHandle` as this closure meaning the mutation will be
@@ -115,19 +193,45 @@ impl Builder {
             drop(their_packet);
         };
 
-        let task = api::ax_spawn(main, name, stack_size);
+        let task = api::ax_spawn(main, name.clone(), stack_size);
         Ok(JoinHandle {
-            thread: Thread::from_id(task.id()),
+            thread: Thread::new(task.id(), name, my_parker),
             native: task,
             packet: my_packet,
         })
     }
 }
 
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Gets a handle to the thread that invokes it.
 pub fn current() -> Thread {
-    let id = api::ax_current_task_id();
-    Thread::from_id(id)
+    Thread::new(
+        api::ax_current_task_id(),
+        api::ax_current_task_name(),
+        Parker::current(),
+    )
+}
+
+/// Blocks the current thread until its token is made available by
+/// [`Thread::unpark`], and consumes the token.
+///
+/// Each thread has a token, initially unavailable. If it is available
+/// already, `park` consumes it and returns at once.
+pub fn park() {
+    Parker::current().park(None);
+}
+
+/// Blocks the current thread until its token is made available, or `dur`
+/// has elapsed, see [`park`].
+///
+/// Without the `irq` feature, the timeout is ignored.
+pub fn park_timeout(dur: Duration) {
+    Parker::current().park(Some(dur));
 }
 
 /// Spawns a new thread, returning a [`JoinHandle`] for it.
@@ -173,6 +277,14 @@ impl<T> JoinHandle<T> {
         &self.thread
     }
 
+    /// Checks if the associated thread has finished running its closure,
+    /// without blocking.
+    pub fn is_finished(&self) -> bool {
+        // The thread drops its reference to the packet once the closure
+        // returned.
+        Arc::strong_count(&self.packet) == 1
+    }
+
     /// Waits for the associated thread to finish.
     ///
     /// This function will return immediately if the associated thread has
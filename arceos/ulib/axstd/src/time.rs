@@ -1,19 +1,40 @@
 //! Temporal quantification.
+//!
+//! [`Instant`] measures the time elapsed since boot. [`SystemTime`] is the
+//! time elapsed since [`UNIX_EPOCH`], read from the RTC at boot with the `rtc`
+//! feature, otherwise it starts from the epoch at boot.
 
 use arceos_api::time::AxTimeValue;
+use core::fmt;
 use core::ops::{Add, AddAssign, Sub, SubAssign};
 
 pub use core::time::Duration;
 
 /// A measurement of a monotonically nondecreasing clock.
 /// Opaque and useful only with [`Duration`].
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct Instant(AxTimeValue);
 
+/// A measurement of the system clock, similar to
+/// [`std::time::SystemTime`](https://doc.rust-lang.org/std/time/struct.SystemTime.html).
+///
+/// Unlike [`Instant`], it may go backwards, e.g. if the clock is adjusted.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct SystemTime(AxTimeValue);
+
+/// An anchor in time, "1970-01-01 00:00:00 UTC", from which [`SystemTime`]
+/// values are measured.
+pub const UNIX_EPOCH: SystemTime = SystemTime(AxTimeValue::ZERO);
+
+/// An error returned from [`SystemTime::duration_since`] and
+/// [`SystemTime::elapsed`], when the other time is later.
+#[derive(Clone, Debug)]
+pub struct SystemTimeError(Duration);
+
 impl Instant {
     /// Returns an instant corresponding to "now".
     pub fn now() -> Instant {
-        Instant(arceos_api::time::ax_wall_time())
+        Instant(arceos_api::time::ax_monotonic_time())
     }
 
     /// Returns the amount of time elapsed from another instant to this one,
@@ -95,3 +116,85 @@ impl Sub<Instant> for Instant {
         self.duration_since(other)
     }
 }
+
+impl SystemTime {
+    /// An anchor in time, see [`UNIX_EPOCH`].
+    pub const UNIX_EPOCH: SystemTime = UNIX_EPOCH;
+
+    /// Returns the system time corresponding to "now".
+    pub fn now() -> SystemTime {
+        SystemTime(arceos_api::time::ax_wall_time())
+    }
+
+    /// Returns the amount of time elapsed from an earlier point in time, or
+    /// an error holding how much later it is.
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, SystemTimeError> {
+        self.0
+            .checked_sub(earlier.0)
+            .ok_or_else(|| SystemTimeError(earlier.0 - self.0))
+    }
+
+    /// Returns the amount of time elapsed since this system time was created.
+    pub fn elapsed(&self) -> Result<Duration, SystemTimeError> {
+        SystemTime::now().duration_since(*self)
+    }
+
+    /// Returns `Some(t)` where `t` is the time `self + duration` if `t` can be represented as
+    /// `SystemTime`, `None` otherwise.
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_add(duration).map(SystemTime)
+    }
+
+    /// Returns `Some(t)` where `t` is the time `self - duration` if `t` can be represented as
+    /// `SystemTime`, `None` otherwise.
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_sub(duration).map(SystemTime)
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    /// # Panics
+    ///
+    /// This function may panic if the resulting point in time cannot be represented by the
+    /// underlying data structure.
+    fn add(self, dur: Duration) -> SystemTime {
+        self.checked_add(dur)
+            .expect("overflow when adding duration to system time")
+    }
+}
+
+impl AddAssign<Duration> for SystemTime {
+    fn add_assign(&mut self, other: Duration) {
+        *self = *self + other;
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, dur: Duration) -> SystemTime {
+        self.checked_sub(dur)
+            .expect("overflow when subtracting duration from system time")
+    }
+}
+
+impl SubAssign<Duration> for SystemTime {
+    fn sub_assign(&mut self, other: Duration) {
+        *self = *self - other;
+    }
+}
+
+impl SystemTimeError {
+    /// Returns how much later the other system time was.
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl fmt::Display for SystemTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "second time provided was later than self")
+    }
+}